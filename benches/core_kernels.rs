@@ -0,0 +1,242 @@
+//! Benchmarks of the core kernels on synthetic data.
+//!
+//! Sizes mirror real runs: a 5 Mbp bacterial genome for sketching, a batch of
+//! 150 bp reads for k-mer hashing, 1000-hash sketches, a 500-reference
+//! database and a 2000 x 24 count table. All inputs come from a fixed seed so
+//! numbers are comparable across machines and commits.
+//!
+//! To check a release for regressions, save a baseline on the previous tag and
+//! compare the candidate against it:
+//!
+//! ```text
+//! git checkout v0.1.0 && cargo bench --bench core_kernels -- --save-baseline v0.1.0
+//! git checkout main   && cargo bench --bench core_kernels -- --baseline v0.1.0
+//! ```
+//!
+//! Criterion flags any kernel whose time changed beyond its noise threshold.
+
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+
+use strain_ahsp::bio::kmers::{CanonicalKmerIter, KmerExtractor, PackedKmerIter};
+use strain_ahsp::bio::{is_valid_base, reverse_complement};
+use strain_ahsp::count_table::CountTable;
+use strain_ahsp::database::downloader::SignatureDatabase;
+use strain_ahsp::normalization::normalize;
+use strain_ahsp::sketch::signature::{KmerSignature, KmerSignatureBuilder};
+use strain_ahsp::sketch::{Comparable, MultiResolutionSignature};
+
+const SEED: u64 = 42;
+const GENOME_LENGTH: usize = 5_000_000;
+const READ_LENGTH: usize = 150;
+const READ_COUNT: usize = 10_000;
+const SKETCH_SIZE: usize = 1000;
+const KMER_SIZE: usize = 31;
+const DATABASE_SIZE: usize = 500;
+const FEATURE_COUNT: usize = 2000;
+const SAMPLE_COUNT: usize = 24;
+
+fn random_dna(rng: &mut StdRng, length: usize) -> Vec<u8> {
+    (0..length)
+        .map(|_| b"ACGT"[rng.random_range(0..4)])
+        .collect()
+}
+
+/// A genome and a relative of it with about 1% point mutations
+fn genome_pair(rng: &mut StdRng) -> (Vec<u8>, Vec<u8>) {
+    let genome = random_dna(rng, GENOME_LENGTH);
+    let mut relative = genome.clone();
+    for base in relative.iter_mut() {
+        if rng.random_bool(0.01) {
+            *base = b"ACGT"[rng.random_range(0..4)];
+        }
+    }
+    (genome, relative)
+}
+
+fn sketch(sequence: &[u8], kmer_size: usize) -> KmerSignature {
+    let mut signature =
+        KmerSignatureBuilder::new(kmer_size, "DNA", "minhash", SKETCH_SIZE, 0).build();
+    signature.add_sequence(sequence).unwrap();
+    signature
+}
+
+fn bench_kmer_hashing(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let reads: Vec<Vec<u8>> = (0..READ_COUNT)
+        .map(|_| random_dna(&mut rng, READ_LENGTH))
+        .collect();
+    let bases = (READ_COUNT * READ_LENGTH) as u64;
+
+    let mut group = c.benchmark_group("kmer_hashing");
+    group.throughput(Throughput::Bytes(bases));
+    group.bench_function("canonical_iter", |b| {
+        b.iter(|| {
+            reads
+                .iter()
+                .map(|read| CanonicalKmerIter::new(read, KMER_SIZE).count())
+                .sum::<usize>()
+        })
+    });
+    // Canonicalizing by allocating each k-mer's reverse complement, as
+    // sketching did before k-mers were packed
+    group.bench_function("canonical_bytes", |b| {
+        b.iter(|| {
+            let mut canonical = 0;
+            for read in &reads {
+                for kmer in read.windows(KMER_SIZE) {
+                    if kmer.iter().all(|&base| is_valid_base(base)) {
+                        let rc = reverse_complement(kmer);
+                        canonical += black_box(if kmer < &rc[..] { kmer } else { &rc[..] }).len();
+                    }
+                }
+            }
+            canonical
+        })
+    });
+    group.bench_function("canonical_packed_u64", |b| {
+        b.iter(|| {
+            reads
+                .iter()
+                .map(|read| {
+                    PackedKmerIter::<u64>::new(read, KMER_SIZE)
+                        .unwrap()
+                        .fold(0u64, |acc, kmer| acc ^ kmer)
+                })
+                .fold(0, |acc, x| acc ^ x)
+        })
+    });
+    group.bench_function("canonical_packed_u128", |b| {
+        b.iter(|| {
+            reads
+                .iter()
+                .map(|read| {
+                    PackedKmerIter::<u128>::new(read, KMER_SIZE)
+                        .unwrap()
+                        .fold(0u128, |acc, kmer| acc ^ kmer)
+                })
+                .fold(0, |acc, x| acc ^ x)
+        })
+    });
+    group.bench_function("count_kmers", |b| {
+        let extractor = KmerExtractor::new(KMER_SIZE);
+        b.iter(|| {
+            reads
+                .iter()
+                .map(|read| extractor.count_kmers(read).len())
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+fn bench_sketch_construction(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let genome = random_dna(&mut rng, GENOME_LENGTH);
+
+    let mut group = c.benchmark_group("sketch_construction");
+    group.throughput(Throughput::Bytes(GENOME_LENGTH as u64));
+    group.sample_size(10);
+    for kmer_size in [21, 31] {
+        group.bench_function(format!("genome_k{}", kmer_size), |b| {
+            b.iter(|| sketch(black_box(&genome), kmer_size))
+        });
+    }
+    group.finish();
+}
+
+fn bench_similarity(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let (genome, relative) = genome_pair(&mut rng);
+    let a = sketch(&genome, KMER_SIZE);
+    let b = sketch(&relative, KMER_SIZE);
+
+    let mut group = c.benchmark_group("similarity");
+    group.bench_function("jaccard", |bench| {
+        bench.iter(|| black_box(&a).jaccard(black_box(&b)))
+    });
+    group.bench_function("containment", |bench| {
+        bench.iter(|| black_box(&a).containment_ani(black_box(&b)))
+    });
+    group.finish();
+}
+
+fn bench_database_lookup(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let dir = TempDir::new().unwrap();
+    let mut database = SignatureDatabase::open(dir.path().join("db")).unwrap();
+    let ids: Vec<String> = (0..DATABASE_SIZE)
+        .map(|i| format!("GCF_{:09}", i))
+        .collect();
+    for id in &ids {
+        let mut level = KmerSignatureBuilder::new(KMER_SIZE, "DNA", "minhash", SKETCH_SIZE, 0)
+            .name(id)
+            .build();
+        level.sketch.hashes = (0..SKETCH_SIZE).map(|_| rng.random()).collect();
+        level.sketch.hashes.sort_unstable();
+        let mut signature = MultiResolutionSignature::new(id.clone(), vec!["Bacteria".into()]);
+        signature.add_level(level);
+        database.add_signature(&signature).unwrap();
+    }
+
+    let mut group = c.benchmark_group("database");
+    group.bench_function("get_signature", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 1) % ids.len();
+            database.get_signature(black_box(&ids[i])).unwrap()
+        })
+    });
+    group.sample_size(10);
+    group.bench_function("get_all_signatures", |b| {
+        b.iter(|| database.get_all_signatures().unwrap().len())
+    });
+    group.finish();
+}
+
+fn bench_normalization(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let data: HashMap<String, HashMap<String, f64>> = (0..SAMPLE_COUNT)
+        .map(|s| {
+            let counts = (0..FEATURE_COUNT)
+                .map(|f| {
+                    // Sparse, skewed counts as in real taxon tables
+                    let count = if rng.random_bool(0.3) {
+                        0.0
+                    } else {
+                        (rng.random::<f64>().powi(3) * 10_000.0).round()
+                    };
+                    (format!("taxon_{}", f), count)
+                })
+                .collect();
+            (format!("S{}", s), counts)
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("normalization");
+    for method in ["median-of-ratios", "cpm"] {
+        group.bench_function(method, |b| {
+            b.iter_batched(
+                || CountTable::build_from_data(&data).unwrap(),
+                |mut table| normalize(&mut table, method).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_kmer_hashing,
+    bench_sketch_construction,
+    bench_similarity,
+    bench_database_lookup,
+    bench_normalization
+);
+criterion_main!(benches);
@@ -1,15 +1,16 @@
 use std::error::Error;
-use strain_ahsp::adaptive::AdaptiveClassifier;
+use strain_ahsp::adaptive::classifier::TaxonomicLevel;
+use strain_ahsp::adaptive::ClassifierBuilder;
 use strain_ahsp::database::DatabaseManager;
+use strain_ahsp::sketch::SignatureLayout;
 
 fn main() -> Result<(), Box<dyn Error>> {
     // Initialize database manager
     let mut manager = DatabaseManager::new(
         "/path/to/something",
         "genome_cache",
-        31,                       // macro_k
-        21,                       // meso_k
-        Some("1000".to_string()), // sketch_size as Option<String>
+        SignatureLayout::minhash(&[31, 21], 1000), // macro_k, meso_k, sketch size
+        None,                                      // NCBI API key
     )?;
 
     // Check if database is empty
@@ -21,12 +22,15 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("Added {} references", added.len());
     }
 
-    // Get all signatures for classification
-    let references = manager.database.get_all_signatures()?;
-
-    // Create adaptive classifier
-    let classifier = AdaptiveClassifier::new(references, None, None)
-        .map_err(|e| Box::new(e) as Box<dyn Error>)?;
+    // Create adaptive classifier over every signature in the database
+    let classifier = ClassifierBuilder::from_database(&manager.database)?
+        .min_similarity(0.05)
+        .per_rank_thresholds([(TaxonomicLevel::Species, 0.8)])
+        .build()?;
+    println!(
+        "Classifying against {} references",
+        classifier.references.len()
+    );
 
     // Now you can classify metagenomic samples
     println!("Ready to classify samples");
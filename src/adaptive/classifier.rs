@@ -1,7 +1,11 @@
+use log::debug;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use thiserror::Error;
 
+use crate::database::downloader::SignatureDatabase;
+use crate::sketch::compare::WeightedSimilarity;
+use crate::sketch::masking::{CommonHashes, HashFrequencies};
 use crate::sketch::signature::{MultiResolutionSignature, ResolutionLevel};
 
 #[derive(Error, Debug)]
@@ -10,6 +14,12 @@ pub enum ClassificationError {
     NoReferences,
     #[error("Insufficient coverage for classification")]
     InsufficientCoverage,
+    #[error("Cannot read reference signatures: {0}")]
+    Database(String),
+    #[error("Invalid classifier setting: {0}")]
+    InvalidParameter(String),
+    #[error("Best match scores {score:.3}, below the minimum similarity of {min_similarity}")]
+    BelowMinSimilarity { score: f64, min_similarity: f64 },
 }
 
 /// Taxonomic levels from domain to strain
@@ -104,9 +114,18 @@ pub struct Classification {
 
     /// Similarity scores at each resolution level
     pub similarity_scores: HashMap<ResolutionLevel, f64>,
+
+    /// Weight of each resolution level in the match score; levels whose
+    /// estimate rests on more shared hashes weigh more
+    #[serde(default)]
+    pub level_weights: HashMap<ResolutionLevel, f64>,
 }
 
-/// Adaptive resolution classifier
+/// Adaptive resolution classifier.
+///
+/// Calls each query at the finest taxonomic level whose similarity to the
+/// best-matching reference meets that level's threshold. Build one with
+/// `ClassifierBuilder`; `new` covers the common case.
 pub struct AdaptiveClassifier {
     /// Reference signatures
     pub references: Vec<MultiResolutionSignature>,
@@ -119,32 +138,207 @@ pub struct AdaptiveClassifier {
 
     /// Minimum coverage required for classification
     min_coverage: usize,
+
+    /// Weighted similarity below which a query has no match
+    min_similarity: f64,
+
+    /// Hashes in too many references to say which one a query matches,
+    /// left out of queries before comparison
+    promiscuous: Option<CommonHashes>,
+
+    /// How many references contain each hash, when genus-level calls weigh
+    /// hashes by their rarity
+    hash_frequencies: Option<HashFrequencies>,
 }
 
-impl AdaptiveClassifier {
-    /// Create a new adaptive classifier
-    pub fn new(
-        references: Vec<MultiResolutionSignature>,
-        thresholds: Option<ConfidenceThresholds>,
-        min_coverage: Option<usize>,
-    ) -> Result<Self, ClassificationError> {
-        if references.is_empty() {
+/// Settings of an `AdaptiveClassifier`, set one at a time.
+///
+/// Start from the references, either given directly (`new`) or read from a
+/// signature database (`from_database`); every setting left alone keeps its
+/// default, and `build` checks them all.
+#[derive(Debug, Clone)]
+pub struct ClassifierBuilder {
+    references: Vec<MultiResolutionSignature>,
+    thresholds: ConfidenceThresholds,
+    min_coverage: usize,
+    min_similarity: f64,
+    max_hash_frequency: Option<f64>,
+    idf_weighting: bool,
+}
+
+impl ClassifierBuilder {
+    /// Classify against `references`, with default settings
+    pub fn new(references: Vec<MultiResolutionSignature>) -> Self {
+        ClassifierBuilder {
+            references,
+            thresholds: ConfidenceThresholds::default(),
+            min_coverage: 100,
+            min_similarity: 0.0,
+            max_hash_frequency: None,
+            idf_weighting: false,
+        }
+    }
+
+    /// Classify against every signature in `database`
+    pub fn from_database(database: &SignatureDatabase) -> Result<Self, ClassificationError> {
+        let references = database
+            .get_all_signatures()
+            .map_err(|e| ClassificationError::Database(e.to_string()))?;
+        Ok(Self::new(references))
+    }
+
+    /// Report no match when the best reference scores below `min_similarity`
+    /// (weighted Jaccard index over the resolution levels); 0 by default
+    pub fn min_similarity(mut self, min_similarity: f64) -> Self {
+        self.min_similarity = min_similarity;
+        self
+    }
+
+    /// Replace the confidence thresholds of the given ranks, keeping the
+    /// defaults of the others
+    pub fn per_rank_thresholds(
+        mut self,
+        thresholds: impl IntoIterator<Item = (TaxonomicLevel, f64)>,
+    ) -> Self {
+        self.thresholds.thresholds.extend(thresholds);
+        self
+    }
+
+    /// Replace all confidence thresholds
+    pub fn thresholds(mut self, thresholds: ConfidenceThresholds) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Minimum coverage required for classification; 100 by default
+    pub fn min_coverage(mut self, min_coverage: usize) -> Self {
+        self.min_coverage = min_coverage;
+        self
+    }
+
+    /// Leave hashes found in more than `max_frequency` of the references
+    /// (conserved genes and the like) out of every query. Has no effect with
+    /// fewer than `MIN_REFERENCES_FOR_COMMON_HASHES` references.
+    pub fn max_hash_frequency(mut self, max_frequency: f64) -> Self {
+        self.max_hash_frequency = Some(max_frequency);
+        self
+    }
+
+    /// Make genus-level calls (and coarser) with an IDF-weighted Jaccard
+    /// index of the coarsest level, so hashes from genes conserved across
+    /// the database count for little and the reference sharing the rarest
+    /// hashes with the query is chosen
+    pub fn idf_weighting(mut self, idf_weighting: bool) -> Self {
+        self.idf_weighting = idf_weighting;
+        self
+    }
+
+    /// Check the settings and index the references
+    pub fn build(self) -> Result<AdaptiveClassifier, ClassificationError> {
+        if self.references.is_empty() {
             return Err(ClassificationError::NoReferences);
         }
+        if !(0.0..=1.0).contains(&self.min_similarity) {
+            return Err(ClassificationError::InvalidParameter(format!(
+                "minimum similarity must be between 0 and 1, got {}",
+                self.min_similarity
+            )));
+        }
+        if let Some((level, threshold)) = self
+            .thresholds
+            .thresholds
+            .iter()
+            .find(|(_, threshold)| !(0.0..=1.0).contains(*threshold))
+        {
+            return Err(ClassificationError::InvalidParameter(format!(
+                "{:?} threshold must be between 0 and 1, got {}",
+                level, threshold
+            )));
+        }
+
+        if let Some(max_frequency) = self.max_hash_frequency {
+            if !(max_frequency > 0.0 && max_frequency < 1.0) {
+                return Err(ClassificationError::InvalidParameter(format!(
+                    "maximum hash frequency must be between 0 and 1, got {}",
+                    max_frequency
+                )));
+            }
+        }
+
+        let frequencies = (self.max_hash_frequency.is_some() || self.idf_weighting)
+            .then(|| HashFrequencies::from_signatures(&self.references));
+        let promiscuous = frequencies
+            .as_ref()
+            .zip(self.max_hash_frequency)
+            .map(|(frequencies, max_frequency)| frequencies.common(max_frequency))
+            .filter(|common| !common.is_empty());
 
         // Build reference index
         let mut reference_index = HashMap::new();
-        for (i, ref_sig) in references.iter().enumerate() {
+        for (i, ref_sig) in self.references.iter().enumerate() {
             reference_index.insert(ref_sig.taxon_id.clone(), i);
         }
 
         Ok(AdaptiveClassifier {
-            references,
-            thresholds: thresholds.unwrap_or_default(),
+            references: self.references,
+            thresholds: self.thresholds,
             reference_index,
-            min_coverage: min_coverage.unwrap_or(100),
+            min_coverage: self.min_coverage,
+            min_similarity: self.min_similarity,
+            promiscuous,
+            hash_frequencies: if self.idf_weighting {
+                frequencies
+            } else {
+                None
+            },
         })
     }
+}
+
+impl AdaptiveClassifier {
+    /// Create a new adaptive classifier; `ClassifierBuilder` offers every
+    /// other setting
+    pub fn new(
+        references: Vec<MultiResolutionSignature>,
+        thresholds: Option<ConfidenceThresholds>,
+        min_coverage: Option<usize>,
+    ) -> Result<Self, ClassificationError> {
+        let mut builder = ClassifierBuilder::new(references);
+        if let Some(thresholds) = thresholds {
+            builder = builder.thresholds(thresholds);
+        }
+        if let Some(min_coverage) = min_coverage {
+            builder = builder.min_coverage(min_coverage);
+        }
+        builder.build()
+    }
+
+    /// Number of hashes left out of queries for being in too many references
+    pub fn promiscuous_hash_count(&self) -> usize {
+        self.promiscuous.as_ref().map_or(0, CommonHashes::len)
+    }
+
+    /// Weighted similarity below which a query has no match
+    pub fn min_similarity(&self) -> f64 {
+        self.min_similarity
+    }
+
+    /// References scoring at least the minimum similarity against `query`,
+    /// with their weighted similarity, most similar first
+    pub fn rank(&self, query: &MultiResolutionSignature) -> Vec<(String, f64)> {
+        let filtered = self.promiscuous.as_ref().map(|p| p.remove_from(query));
+        let query = filtered.as_ref().unwrap_or(query);
+        let mut ranked: Vec<(String, f64)> = self
+            .references
+            .iter()
+            .filter_map(|reference| {
+                let score = query.weighted_similarity(reference).ok()?.score;
+                (score >= self.min_similarity).then(|| (reference.taxon_id.clone(), score))
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+    }
 
     /// Classify a query signature at the appropriate resolution level
     pub fn classify(
@@ -158,7 +352,22 @@ impl AdaptiveClassifier {
         }
 
         // Find best matching reference at each level
-        let (best_match_id, best_match_idx, best_similarities) = self.find_best_match(query);
+        let filtered = self.promiscuous.as_ref().map(|p| p.remove_from(query));
+        let query = filtered.as_ref().unwrap_or(query);
+        let (mut best_match_id, mut best_match_idx, weighted) = self.find_best_match(query);
+        let score = weighted.as_ref().map_or(0.0, |w| w.score);
+        if score < self.min_similarity {
+            return Err(ClassificationError::BelowMinSimilarity {
+                score,
+                min_similarity: self.min_similarity,
+            });
+        }
+        let mut best_similarities = HashMap::new();
+        let mut level_weights = HashMap::new();
+        for (idx, level) in weighted.iter().flat_map(|w| w.levels.iter()).enumerate() {
+            best_similarities.insert(resolution_level_at(idx), level.jaccard);
+            level_weights.insert(resolution_level_at(idx), level.weight);
+        }
 
         // Find best confidence and corresponding taxonomic level
         let mut best_level = TaxonomicLevel::Strain;
@@ -166,11 +375,8 @@ impl AdaptiveClassifier {
 
         // Map resolution levels to taxonomic levels and check thresholds
         for (resolution_level, confidence) in best_similarities.iter() {
-            let taxonomic_level = match resolution_level {
-                ResolutionLevel::Micro => TaxonomicLevel::Strain,
-                ResolutionLevel::Meso => TaxonomicLevel::StrainGroup,
-                ResolutionLevel::Macro => TaxonomicLevel::Species,
-                ResolutionLevel::Custom(_) => continue, // Skip custom levels
+            let Some(taxonomic_level) = taxonomic_level_for(resolution_level) else {
+                continue; // Skip custom levels
             };
 
             if let Some(threshold) = self.thresholds.thresholds.get(&taxonomic_level) {
@@ -188,6 +394,15 @@ impl AdaptiveClassifier {
             best_confidence += 0.05;
         }
 
+        // Conserved k-mers dominate coarse comparisons, so weigh them down
+        if best_level <= TaxonomicLevel::Genus {
+            if let Some((idx, similarity)) = self.find_best_idf_match(query) {
+                best_match_idx = idx;
+                best_match_id = self.references[idx].taxon_id.clone();
+                best_confidence = similarity;
+            }
+        }
+
         // Build classification result
         let reference = &self.references[best_match_idx];
         let mut result_lineage = Vec::new();
@@ -212,51 +427,134 @@ impl AdaptiveClassifier {
             confidence: best_confidence,
             best_match: best_match_id,
             similarity_scores: best_similarities,
+            level_weights,
         })
     }
 
-    /// Find the best matching reference signature
+    /// Find the reference with the highest weighted similarity and its per-level breakdown
     fn find_best_match(
         &self,
         query: &MultiResolutionSignature,
-    ) -> (String, usize, HashMap<ResolutionLevel, f64>) {
+    ) -> (String, usize, Option<WeightedSimilarity>) {
         let mut best_match_idx = 0;
-        let mut best_overall_similarity = 0.0;
-        let mut best_similarities = HashMap::new();
+        let mut best: Option<WeightedSimilarity> = None;
 
         // Compare with each reference
         for (i, reference) in self.references.iter().enumerate() {
-            // Calculate weighted similarity between signatures
-            if let Some(weighted_sim) = query.similarity(reference, None) {
-                if weighted_sim > best_overall_similarity {
-                    best_overall_similarity = weighted_sim;
-                    best_match_idx = i;
-
-                    // Record similarities at each resolution level
-                    best_similarities = HashMap::new();
-                    for (idx, level) in query.levels.iter().enumerate() {
-                        if idx < reference.levels.len() {
-                            if let Some(sim) = level.jaccard_similarity(&reference.levels[idx]) {
-                                best_similarities.insert(
-                                    match idx {
-                                        0 => ResolutionLevel::Macro,
-                                        1 => ResolutionLevel::Meso,
-                                        2 => ResolutionLevel::Micro,
-                                        _ => ResolutionLevel::Custom(idx as u8),
-                                    },
-                                    sim,
-                                );
-                            }
-                        }
-                    }
+            let weighted = match query.weighted_similarity(reference) {
+                Ok(weighted) => weighted,
+                Err(reason) => {
+                    debug!("Cannot compare with {}: {}", reference.taxon_id, reason);
+                    continue;
                 }
+            };
+            if weighted.score > best.as_ref().map_or(0.0, |b| b.score) {
+                best_match_idx = i;
+                best = Some(weighted);
             }
         }
 
         (
             self.references[best_match_idx].taxon_id.clone(),
             best_match_idx,
-            best_similarities,
+            best,
         )
     }
+
+    /// Index of the reference with the highest IDF-weighted Jaccard index at
+    /// the coarsest level, and that similarity; `None` unless IDF weights are in use
+    fn find_best_idf_match(&self, query: &MultiResolutionSignature) -> Option<(usize, f64)> {
+        let frequencies = self.hash_frequencies.as_ref()?;
+        let query_level = query.levels.first()?;
+        let idf = |hash| frequencies.idf(query_level.kmer_size, hash);
+        self.references
+            .iter()
+            .enumerate()
+            .filter_map(|(i, reference)| {
+                let similarity = query_level.weighted_jaccard(reference.levels.first()?, idf)?;
+                Some((i, similarity))
+            })
+            .filter(|(_, similarity)| *similarity > 0.0)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+}
+
+/// Resolution level of the signature level at `index`
+pub(crate) fn resolution_level_at(index: usize) -> ResolutionLevel {
+    match index {
+        0 => ResolutionLevel::Macro,
+        1 => ResolutionLevel::Meso,
+        2 => ResolutionLevel::Micro,
+        _ => ResolutionLevel::Custom(index as u8),
+    }
+}
+
+/// Taxonomic level a resolution level can call (none for custom levels)
+pub(crate) fn taxonomic_level_for(level: &ResolutionLevel) -> Option<TaxonomicLevel> {
+    match level {
+        ResolutionLevel::Micro => Some(TaxonomicLevel::Strain),
+        ResolutionLevel::Meso => Some(TaxonomicLevel::StrainGroup),
+        ResolutionLevel::Macro => Some(TaxonomicLevel::Species),
+        ResolutionLevel::Custom(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_builder_applies_settings() {
+        let references = vec![
+            signature("a", &[], [level(21, 4, [1, 2, 3, 10])]),
+            signature("b", &[], [level(21, 4, [1, 2, 3, 20])]),
+            signature("c", &[], [level(21, 4, [1, 2, 3, 30])]),
+        ];
+        assert!(matches!(
+            ClassifierBuilder::new(Vec::new()).build(),
+            Err(ClassificationError::NoReferences)
+        ));
+        assert!(matches!(
+            ClassifierBuilder::new(references.clone())
+                .min_similarity(1.5)
+                .build(),
+            Err(ClassificationError::InvalidParameter(_))
+        ));
+        assert!(matches!(
+            ClassifierBuilder::new(references.clone())
+                .max_hash_frequency(1.0)
+                .build(),
+            Err(ClassificationError::InvalidParameter(_))
+        ));
+
+        let classifier = ClassifierBuilder::new(references.clone())
+            .per_rank_thresholds([(TaxonomicLevel::Strain, 0.5)])
+            .max_hash_frequency(0.5)
+            .build()
+            .unwrap();
+        assert_eq!(
+            classifier.thresholds.thresholds[&TaxonomicLevel::Strain],
+            0.5
+        );
+        assert_eq!(
+            classifier.thresholds.thresholds[&TaxonomicLevel::Species],
+            0.75
+        );
+        // Hashes 1-3 are in every reference, so only 20 tells the query apart
+        assert_eq!(classifier.promiscuous_hash_count(), 3);
+        let query = signature("query", &[], [level(21, 4, [1, 2, 3, 20])]);
+        assert_eq!(classifier.rank(&query)[0].0, "b");
+        assert_eq!(classifier.classify(&query).unwrap().best_match, "b");
+
+        let strict = ClassifierBuilder::new(references)
+            .min_similarity(0.9)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            strict.classify(&signature("query", &[], [level(21, 4, [40, 50, 60, 70])])),
+            Err(ClassificationError::BelowMinSimilarity { .. })
+        ));
+        assert!(strict.rank(&query).iter().all(|(_, score)| *score >= 0.9));
+    }
 }
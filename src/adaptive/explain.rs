@@ -0,0 +1,255 @@
+//! Details behind a classification, for `--debug-classification`.
+//!
+//! A call is the outcome of one best-matching reference and a threshold per
+//! resolution level, which is hard to argue with from the call alone. This
+//! lists, for every resolution level, the closest references to the query
+//! with their shared hashes, Jaccard similarity, containment and implied
+//! ANI, next to the threshold that level has to meet and whether it did.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::adaptive::classifier::{
+    resolution_level_at, taxonomic_level_for, AdaptiveClassifier, Classification, TaxonomicLevel,
+};
+use crate::sketch::signature::{MultiResolutionSignature, ResolutionLevel};
+use crate::sketch::Comparable;
+
+/// How a reference fared against the threshold of a resolution level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdDecision {
+    /// The best match at the level that made the call
+    Selected,
+    /// Meets the threshold
+    Pass,
+    /// Similarity is below the threshold
+    BelowThreshold,
+    /// The level has no threshold (custom levels)
+    NoThreshold,
+}
+
+impl fmt::Display for ThresholdDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ThresholdDecision::Selected => "selected",
+            ThresholdDecision::Pass => "pass",
+            ThresholdDecision::BelowThreshold => "below_threshold",
+            ThresholdDecision::NoThreshold => "no_threshold",
+        })
+    }
+}
+
+/// Comparison of the query with one reference at one resolution level
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CandidateDetail {
+    pub level: ResolutionLevel,
+    pub kmer_size: usize,
+    /// 1-based rank of the reference at this level, by Jaccard similarity
+    pub rank: usize,
+    pub reference_id: String,
+    /// Most specific name in the reference lineage
+    pub reference_name: String,
+    pub shared_hashes: usize,
+    pub query_hashes: usize,
+    pub reference_hashes: usize,
+    pub jaccard: f64,
+    /// Fraction of the reference's hashes found in the query
+    pub containment: f64,
+    /// `containment^(1/k)`
+    pub ani: f64,
+    /// Taxonomic level this resolution level calls, and its threshold
+    pub taxonomic_level: Option<TaxonomicLevel>,
+    pub threshold: Option<f64>,
+    pub decision: ThresholdDecision,
+    /// Whether this is the best match overall (weighted over all levels)
+    pub best_match: bool,
+}
+
+/// Compare `query` with every reference and keep the `top_k` closest per
+/// resolution level. The overall best match is always kept, whatever its rank.
+pub fn explain_classification(
+    classifier: &AdaptiveClassifier,
+    query: &MultiResolutionSignature,
+    classification: &Classification,
+    top_k: usize,
+) -> Vec<CandidateDetail> {
+    let mut details = Vec::new();
+    for (index, query_level) in query.levels.iter().enumerate() {
+        let level = resolution_level_at(index);
+        let taxonomic_level = taxonomic_level_for(&level);
+        let threshold = taxonomic_level
+            .and_then(|t| classifier.thresholds.thresholds.get(&t))
+            .copied();
+
+        let mut candidates: Vec<CandidateDetail> = classifier
+            .references
+            .iter()
+            .filter_map(|reference| {
+                let reference_level = reference.levels.get(index)?;
+                let jaccard = query_level.jaccard(reference_level)?;
+                let shared_hashes = reference_level.sketch.shared_hashes(&query_level.sketch);
+                let containment = reference_level.containment(query_level).unwrap_or(0.0);
+                let best_match = reference.taxon_id == classification.best_match;
+                let decision = match threshold {
+                    None => ThresholdDecision::NoThreshold,
+                    Some(t) if jaccard < t => ThresholdDecision::BelowThreshold,
+                    Some(_) if best_match && taxonomic_level == Some(classification.level) => {
+                        ThresholdDecision::Selected
+                    }
+                    Some(_) => ThresholdDecision::Pass,
+                };
+                Some(CandidateDetail {
+                    level: level.clone(),
+                    kmer_size: query_level.kmer_size,
+                    rank: 0,
+                    reference_id: reference.taxon_id.clone(),
+                    reference_name: reference.lineage.last().cloned().unwrap_or_default(),
+                    shared_hashes,
+                    query_hashes: query_level.sketch.size(),
+                    reference_hashes: reference_level.sketch.size(),
+                    jaccard,
+                    containment,
+                    ani: reference_level.containment_ani(query_level).unwrap_or(0.0),
+                    taxonomic_level,
+                    threshold,
+                    decision,
+                    best_match,
+                })
+            })
+            .collect();
+
+        candidates.sort_by(|a, b| {
+            b.jaccard
+                .total_cmp(&a.jaccard)
+                .then(b.containment.total_cmp(&a.containment))
+                .then(a.reference_id.cmp(&b.reference_id))
+        });
+        for (rank, mut candidate) in candidates.into_iter().enumerate() {
+            candidate.rank = rank + 1;
+            if candidate.rank <= top_k || candidate.best_match {
+                details.push(candidate);
+            }
+        }
+    }
+    details
+}
+
+/// Write the details as a TSV, preceded by `#` lines stating the call
+pub fn write_classification_debug(
+    classification: &Classification,
+    details: &[CandidateDetail],
+    path: &Path,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(
+        file,
+        "# call: {} at {:?} (confidence {:.4}, best match {})",
+        classification.taxon_id,
+        classification.level,
+        classification.confidence,
+        classification.best_match
+    )?;
+    if !details
+        .iter()
+        .any(|d| d.decision == ThresholdDecision::Selected)
+    {
+        writeln!(
+            file,
+            "# no resolution level met its threshold; the call fell back to a higher rank"
+        )?;
+    }
+    writeln!(
+        file,
+        "level\tk\trank\treference_id\treference_name\tshared_hashes\tquery_hashes\t\
+         reference_hashes\tjaccard\tcontainment\tani\ttaxonomic_level\tthreshold\tdecision\tbest_match"
+    )?;
+    for d in details {
+        writeln!(
+            file,
+            "{:?}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{}\t{}\t{}\t{}",
+            d.level,
+            d.kmer_size,
+            d.rank,
+            d.reference_id,
+            d.reference_name,
+            d.shared_hashes,
+            d.query_hashes,
+            d.reference_hashes,
+            d.jaccard,
+            d.containment,
+            d.ani,
+            d.taxonomic_level
+                .map_or("NA".to_string(), |level| format!("{:?}", level)),
+            d.threshold.map_or("NA".to_string(), |t| t.to_string()),
+            d.decision,
+            d.best_match
+        )?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_explain_lists_top_references_and_decisions() {
+        let query = signature(
+            "query",
+            &["Bacteria", "query species"],
+            [level(21, 100, 0..100), level(21, 100, 0..100)],
+        );
+        let references = vec![
+            signature(
+                "close",
+                &["Bacteria", "close species"],
+                [level(21, 100, 0..100), level(21, 100, 10..110)],
+            ),
+            signature(
+                "mid",
+                &["Bacteria", "mid species"],
+                [level(21, 100, 30..130), level(21, 100, 30..130)],
+            ),
+            signature(
+                "far",
+                &["Bacteria", "far species"],
+                [level(21, 100, 90..190), level(21, 100, 90..190)],
+            ),
+        ];
+        let classifier = AdaptiveClassifier::new(references, None, None).unwrap();
+        let classification = classifier.classify(&query).unwrap();
+        assert_eq!(classification.best_match, "close");
+
+        let details = explain_classification(&classifier, &query, &classification, 2);
+        // Two per level; "far" is cut off
+        assert_eq!(details.len(), 4);
+        assert!(details.iter().all(|d| d.reference_id != "far"));
+        let macro_best = &details[0];
+        assert_eq!(macro_best.level, ResolutionLevel::Macro);
+        assert_eq!(macro_best.reference_id, "close");
+        assert_eq!(macro_best.shared_hashes, 100);
+        assert_eq!(macro_best.ani, 1.0);
+        assert_eq!(macro_best.decision, ThresholdDecision::Selected);
+        assert_eq!(details[1].reference_id, "mid");
+        assert_eq!(details[1].rank, 2);
+        assert_eq!(details[1].shared_hashes, 70);
+        assert_eq!(details[1].decision, ThresholdDecision::BelowThreshold);
+
+        // The best match stays listed even outside the top K
+        let details = explain_classification(&classifier, &query, &classification, 0);
+        assert!(details.iter().all(|d| d.best_match));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("debug.tsv");
+        write_classification_debug(&classification, &details, &path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# call: "));
+        assert_eq!(text.lines().filter(|l| !l.starts_with('#')).count(), 3);
+    }
+}
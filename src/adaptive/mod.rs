@@ -1,3 +1,8 @@
 pub mod classifier;
+pub mod explain;
+pub mod scores;
 
-pub use classifier::{AdaptiveClassifier, Classification, ConfidenceThresholds};
+pub use classifier::{
+    AdaptiveClassifier, Classification, ClassificationError, ClassifierBuilder,
+    ConfidenceThresholds,
+};
@@ -0,0 +1,169 @@
+//! Raw similarity evidence behind a sample's classification, for `--export-scores`.
+//!
+//! A call condenses the comparison of the sample with every reference at
+//! every resolution level into one taxon. Users who want to call taxa their
+//! own way, or train models on the evidence, need the comparisons
+//! themselves: the score matrix has one row per reference and resolution
+//! level, with the shared hashes, Jaccard similarity, containment and ANI of
+//! the sample against that reference, for all references rather than the
+//! closest few that `--debug-classification` lists. Rows carry the sample
+//! ID, so the matrices of several samples can be concatenated into one
+//! sample × reference × level table.
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::adaptive::classifier::{resolution_level_at, AdaptiveClassifier};
+use crate::io::escape::csv_writer_builder;
+use crate::sketch::signature::{MultiResolutionSignature, ResolutionLevel};
+use crate::sketch::Comparable;
+
+/// Comparison of a sample with one reference at one resolution level
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScoreRow {
+    pub reference_id: String,
+    pub level: ResolutionLevel,
+    pub kmer_size: usize,
+    pub shared_hashes: usize,
+    pub query_hashes: usize,
+    pub reference_hashes: usize,
+    pub jaccard: f64,
+    /// Fraction of the reference's hashes found in the sample
+    pub containment: f64,
+    /// `containment^(1/k)`
+    pub ani: f64,
+}
+
+/// Compare `query` with every reference at every level both have, in
+/// reference order, levels coarsest first. Levels whose sketches cannot be
+/// compared are left out.
+pub fn score_matrix(
+    classifier: &AdaptiveClassifier,
+    query: &MultiResolutionSignature,
+) -> Vec<ScoreRow> {
+    classifier
+        .references
+        .par_iter()
+        .flat_map_iter(|reference| {
+            query
+                .levels
+                .iter()
+                .zip(&reference.levels)
+                .enumerate()
+                .filter_map(|(index, (query_level, reference_level))| {
+                    Some(ScoreRow {
+                        reference_id: reference.taxon_id.clone(),
+                        level: resolution_level_at(index),
+                        kmer_size: query_level.kmer_size,
+                        shared_hashes: reference_level.sketch.shared_hashes(&query_level.sketch),
+                        query_hashes: query_level.sketch.size(),
+                        reference_hashes: reference_level.sketch.size(),
+                        jaccard: query_level.jaccard(reference_level)?,
+                        containment: reference_level.containment(query_level).unwrap_or(0.0),
+                        ani: reference_level.containment_ani(query_level).unwrap_or(0.0),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Write the score matrix of `sample_id` as a TSV
+pub fn write_score_matrix(sample_id: &str, rows: &[ScoreRow], path: &Path) -> io::Result<()> {
+    let mut writer = csv_writer_builder()
+        .delimiter(b'\t')
+        .from_writer(BufWriter::new(File::create(path)?));
+    writer.write_record([
+        "sample_id",
+        "reference_id",
+        "level",
+        "k",
+        "shared_hashes",
+        "query_hashes",
+        "reference_hashes",
+        "jaccard",
+        "containment",
+        "ani",
+    ])?;
+    for row in rows {
+        writer.write_record([
+            sample_id,
+            &row.reference_id,
+            &format!("{:?}", row.level),
+            &row.kmer_size.to_string(),
+            &row.shared_hashes.to_string(),
+            &row.query_hashes.to_string(),
+            &row.reference_hashes.to_string(),
+            &format!("{:.6}", row.jaccard),
+            &format!("{:.6}", row.containment),
+            &format!("{:.6}", row.ani),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_score_matrix_covers_every_reference_and_level() {
+        let query = signature(
+            "query",
+            &["Bacteria", "query"],
+            [level(31, 100, 0..100), level(21, 100, 0..100)],
+        );
+        let references = vec![
+            signature(
+                "close",
+                &["Bacteria", "close"],
+                [level(31, 100, 0..100), level(21, 100, 10..110)],
+            ),
+            signature(
+                "mid",
+                &["Bacteria", "mid"],
+                [level(31, 100, 30..130), level(21, 100, 30..130)],
+            ),
+            signature(
+                "far",
+                &["Bacteria", "far"],
+                [level(31, 100, 90..190), level(21, 100, 90..190)],
+            ),
+        ];
+        let classifier = AdaptiveClassifier::new(references, None, None).unwrap();
+        let rows = score_matrix(&classifier, &query);
+        assert_eq!(rows.len(), 6);
+        assert_eq!(rows[0].reference_id, "close");
+        assert_eq!(rows[0].level, ResolutionLevel::Macro);
+        assert_eq!(rows[0].shared_hashes, 100);
+        assert_eq!(rows[0].jaccard, 1.0);
+        assert_eq!(rows[1].level, ResolutionLevel::Meso);
+        assert_eq!(rows[1].kmer_size, 21);
+        assert_eq!(rows[1].shared_hashes, 90);
+        assert_eq!(rows[5].reference_id, "far");
+        assert_eq!(rows[5].shared_hashes, 10);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("S1_scores.tsv");
+        write_score_matrix("S1", &rows, &path).unwrap();
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_path(&path)
+            .unwrap();
+        assert_eq!(&reader.headers().unwrap()[3], "k");
+        let records: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 6);
+        assert_eq!(
+            records[1].iter().collect::<Vec<_>>(),
+            vec![
+                "S1", "close", "Meso", "21", "90", "100", "100", "0.900000", "0.900000", "0.994995"
+            ]
+        );
+    }
+}
@@ -5,13 +5,18 @@
 //! - K-mer iterators over sequences.
 //! - Canonical k-mer generation (lexicographically smaller of k-mer and its reverse complement).
 //! - K-mer counting functions.
+//! - Canonical k-mers packed two bits per base into a `u64` (k <= 32) or a
+//!   `u128` (k <= 64), rolled along the sequence without allocating.
 
 use crate::bio; // Access functions like reverse_complement from parent bio module
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::warn;
 use needletail::parser::SequenceRecord; // Using needletail for sequence handling
 use needletail::Sequence;
 use std::collections::HashMap; // For k-mer counting
+use std::fmt;
+use std::hash::Hash;
+use std::ops::{BitAnd, BitOr, Not, Shl, Shr};
 
 /// Represents a k-mer. Could be stored as bytes, string, or a packed integer format.
 // Example using bytes:
@@ -80,6 +85,141 @@ impl<'a> Iterator for CanonicalKmerIter<'a> {
     }
 }
 
+/// Unsigned integer a k-mer is packed into, two bits per base
+/// (A = 0, C = 1, G = 2, T = 3, first base in the highest bits).
+///
+/// With this encoding, packed k-mers compare in the same order as their
+/// bases, so the smaller of a k-mer and its reverse complement is the
+/// canonical k-mer.
+pub trait KmerWord:
+    Copy
+    + Ord
+    + Hash
+    + fmt::Debug
+    + From<u8>
+    + Shl<usize, Output = Self>
+    + Shr<usize, Output = Self>
+    + BitOr<Output = Self>
+    + BitAnd<Output = Self>
+    + Not<Output = Self>
+{
+    /// Longest k-mer the word holds
+    const MAX_K: usize;
+}
+
+impl KmerWord for u64 {
+    const MAX_K: usize = 32;
+}
+
+impl KmerWord for u128 {
+    const MAX_K: usize = 64;
+}
+
+/// Two-bit code of a base, `None` for anything but A, C, G or T (either case)
+pub fn base_code(base: u8) -> Option<u8> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// A k-mer and its reverse complement, rolled one base code at a time
+#[derive(Debug, Clone)]
+pub(crate) struct KmerRoller<W: KmerWord> {
+    k: usize,
+    /// Bases pushed since the last reset
+    valid: usize,
+    forward: W,
+    reverse: W,
+    mask: W,
+}
+
+impl<W: KmerWord> KmerRoller<W> {
+    /// Fails unless `k` is between 1 and `W::MAX_K`
+    pub(crate) fn new(k: usize) -> Result<Self> {
+        if k == 0 || k > W::MAX_K {
+            return Err(anyhow!(
+                "k-mer size {} cannot be packed: must be between 1 and {}",
+                k,
+                W::MAX_K
+            ));
+        }
+        let zero = W::from(0);
+        Ok(KmerRoller {
+            k,
+            valid: 0,
+            forward: zero,
+            reverse: zero,
+            mask: !zero >> (2 * (W::MAX_K - k)),
+        })
+    }
+
+    /// Append a base code; the canonical k-mer ending at it, once k bases
+    /// have been pushed since the last reset
+    pub(crate) fn push(&mut self, code: u8) -> Option<W> {
+        // Bases older than k are shifted out of both words
+        self.forward = ((self.forward << 2) | W::from(code)) & self.mask;
+        self.reverse = (self.reverse >> 2) | (W::from(3 - code) << (2 * (self.k - 1)));
+        self.valid += 1;
+        (self.valid >= self.k).then(|| self.forward.min(self.reverse))
+    }
+
+    /// Start over, as after a base that cannot be part of a k-mer
+    pub(crate) fn reset(&mut self) {
+        self.valid = 0;
+    }
+}
+
+/// An iterator over the canonical k-mers of a sequence, packed into `W`.
+///
+/// The k-mer and its reverse complement are updated by one base per step,
+/// so nothing is allocated or rescanned. K-mers containing a base other than
+/// A, C, G or T are skipped; lowercase bases are treated as uppercase.
+pub struct PackedKmerIter<'a, W: KmerWord> {
+    sequence: &'a [u8],
+    pos: usize,
+    roller: KmerRoller<W>,
+}
+
+impl<'a, W: KmerWord> PackedKmerIter<'a, W> {
+    /// Iterate over the canonical k-mers of `sequence`; fails unless `k` is
+    /// between 1 and `W::MAX_K`
+    pub fn new(sequence: &'a [u8], k: usize) -> Result<Self> {
+        Ok(PackedKmerIter {
+            sequence,
+            pos: 0,
+            roller: KmerRoller::new(k)?,
+        })
+    }
+}
+
+impl<'a, W: KmerWord> Iterator for PackedKmerIter<'a, W> {
+    type Item = W;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.sequence.len() {
+            let base = self.sequence[self.pos];
+            self.pos += 1;
+            match base_code(base) {
+                Some(code) => {
+                    if let Some(kmer) = self.roller.push(code) {
+                        return Some(kmer);
+                    }
+                }
+                None => self.roller.reset(),
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.sequence.len() - self.pos))
+    }
+}
+
 /// Counts canonical k-mers in a single sequence record.
 ///
 /// # Arguments
@@ -271,4 +411,45 @@ mod tests {
     }
 
     // TODO: Add tests for the CanonicalKmerIter once it's fully implemented.
+
+    #[test]
+    fn test_packed_kmers_match_canonical_bytes() {
+        fn pack(kmer: &[u8]) -> u128 {
+            kmer.iter().fold(0, |word, &base| {
+                (word << 2) | base_code(base).unwrap() as u128
+            })
+        }
+        fn expected(seq: &[u8], k: usize) -> Vec<u128> {
+            seq.windows(k)
+                .filter(|kmer| kmer.iter().all(|&b| bio::is_valid_base(b)))
+                .map(|kmer| {
+                    let kmer = kmer.to_ascii_uppercase();
+                    let rc = bio::reverse_complement(&kmer);
+                    pack(if kmer <= rc { &kmer } else { &rc })
+                })
+                .collect()
+        }
+
+        let seq = b"ACGTTGCAacgtNNGATTACAGATTACAGGGCCCTTTAAANACGTACGTAGCTAGCTAGGATCGATCGGCTAGCTAGCATCGATCGATCGTAGCTAGTCGA";
+        for k in [1, 2, 7, 31, 32] {
+            let packed: Vec<u128> = PackedKmerIter::<u64>::new(seq, k)
+                .unwrap()
+                .map(u128::from)
+                .collect();
+            assert_eq!(packed, expected(seq, k), "k = {}", k);
+        }
+        for k in [33, 50, 64] {
+            let packed: Vec<u128> = PackedKmerIter::<u128>::new(seq, k).unwrap().collect();
+            assert_eq!(packed, expected(seq, k), "k = {}", k);
+        }
+
+        // A k-mer and its reverse complement pack to the same word
+        let forward: Vec<u64> = PackedKmerIter::new(b"GATTACA", 7).unwrap().collect();
+        let reverse: Vec<u64> = PackedKmerIter::new(b"TGTAATC", 7).unwrap().collect();
+        assert_eq!(forward, reverse);
+
+        assert!(PackedKmerIter::<u64>::new(seq, 33).is_err());
+        assert!(PackedKmerIter::<u128>::new(seq, 0).is_err());
+        assert_eq!(PackedKmerIter::<u64>::new(b"ACG", 4).unwrap().count(), 0);
+    }
 }
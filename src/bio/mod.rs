@@ -6,7 +6,10 @@
 
 // Declare sub-modules within the 'bio' directory
 pub mod kmers;
+pub mod names;
+pub mod packed; // 2-bit packed sequences
 pub mod signature; // Module for handling sequence signatures (e.g., from sketching)
+pub mod taxdump;
 pub mod taxonomy;
 
 pub use kmers::KmerExtractor;
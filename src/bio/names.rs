@@ -0,0 +1,196 @@
+//! Taxa named in user input.
+//!
+//! Watchlists, taxon filters and lineage subsets all name taxa, and users
+//! know them by name (`Klebsiella pneumoniae`) as often as by NCBI taxid
+//! (`573`). With an offline taxonomy either form is accepted: a name is
+//! resolved to its taxid by scientific name, ignoring case. A name shared by
+//! several taxa (`Bacillus`, both a bacterial and an insect genus) is refused
+//! with the lineage of each candidate, so the user can pick the taxid, and a
+//! name matching nothing lists the closest names by edit distance, which
+//! catches most typos (`Klebsiela pneumonia`).
+
+use thiserror::Error;
+
+use crate::bio::taxdump::Taxonomy;
+
+/// Most names suggested for a name that matches no taxon
+const MAX_SUGGESTIONS: usize = 5;
+
+/// Why a taxon named by the user cannot be resolved
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TaxonLookupError {
+    #[error("Unknown taxid '{0}'")]
+    UnknownTaxid(String),
+
+    #[error("Unknown taxon '{query}'{}", did_you_mean(suggestions))]
+    UnknownName {
+        query: String,
+        /// Closest known names, the closest first
+        suggestions: Vec<String>,
+    },
+
+    #[error("Taxon name '{query}' is ambiguous, give one of these taxids instead: {}", candidates.join("; "))]
+    Ambiguous {
+        query: String,
+        /// Each taxon with the name, as `taxid (rank in lineage)`
+        candidates: Vec<String>,
+    },
+
+    #[error("Taxon '{0}' can only be resolved with --taxonomy (an NCBI taxdump directory or lineage map)")]
+    NoTaxonomy(String),
+}
+
+/// `"; did you mean ...?"` for the suggestions, if any
+fn did_you_mean(suggestions: &[String]) -> String {
+    if suggestions.is_empty() {
+        String::new()
+    } else {
+        format!("; did you mean {}?", suggestions.join(", "))
+    }
+}
+
+/// Whether `query` is a numeric taxid rather than a name
+pub fn is_taxid(query: &str) -> bool {
+    !query.is_empty() && query.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Levenshtein distance between `a` and `b`, ignoring case
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().flat_map(char::to_lowercase).collect();
+    let b: Vec<char> = b.chars().flat_map(char::to_lowercase).collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// The `candidates` closest to `query`, the closest first. Only names within
+/// a quarter of the query's length in edits (at least 2) are suggested.
+pub fn suggestions<'a>(query: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let length = query.chars().count();
+    let max_distance = (length / 4).max(2);
+    let mut close: Vec<(usize, &str)> = candidates
+        .into_iter()
+        // The length difference bounds the distance, so most names are skipped cheaply
+        .filter(|candidate| candidate.chars().count().abs_diff(length) <= max_distance)
+        .map(|candidate| (edit_distance(query, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .collect();
+    close.sort_unstable();
+    close.dedup_by(|a, b| a.1 == b.1);
+    close
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate.to_string())
+        .collect()
+}
+
+impl Taxonomy {
+    /// The taxid `query` stands for: `query` itself if it is a known taxid,
+    /// otherwise the only taxon with that scientific name, ignoring case
+    pub fn resolve(&self, query: &str) -> Result<String, TaxonLookupError> {
+        let query = query.trim();
+        if self.node(query).is_some() {
+            return Ok(query.to_string());
+        }
+        if is_taxid(query) {
+            return Err(TaxonLookupError::UnknownTaxid(query.to_string()));
+        }
+
+        let mut matches: Vec<&str> = self
+            .taxa()
+            .filter(|(_, node)| node.name.eq_ignore_ascii_case(query))
+            .map(|(taxid, _)| taxid)
+            .collect();
+        match matches.len() {
+            1 => Ok(matches[0].to_string()),
+            0 => Err(TaxonLookupError::UnknownName {
+                query: query.to_string(),
+                suggestions: suggestions(query, self.taxa().map(|(_, node)| node.name.as_str())),
+            }),
+            _ => {
+                matches.sort_unstable();
+                let candidates = matches
+                    .into_iter()
+                    .map(|taxid| {
+                        let mut lineage = self.lineage(taxid);
+                        lineage.pop();
+                        let rank = self
+                            .node(taxid)
+                            .map_or("no rank", |node| node.rank.as_str());
+                        format!("{} ({} in {})", taxid, rank, lineage.join(" > "))
+                    })
+                    .collect();
+                Err(TaxonLookupError::Ambiguous {
+                    query: query.to_string(),
+                    candidates,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_names_and_taxids_resolve_interchangeably() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = "1\t|\t1\t|\tno rank\t|\n\
+                     2\t|\t1\t|\tsuperkingdom\t|\n\
+                     2759\t|\t1\t|\tsuperkingdom\t|\n\
+                     570\t|\t2\t|\tgenus\t|\n\
+                     573\t|\t570\t|\tspecies\t|\n\
+                     1386\t|\t2\t|\tgenus\t|\n\
+                     55087\t|\t2759\t|\tgenus\t|\n";
+        let names = "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+                     2\t|\tBacteria\t|\t\t|\tscientific name\t|\n\
+                     2759\t|\tEukaryota\t|\t\t|\tscientific name\t|\n\
+                     570\t|\tKlebsiella\t|\t\t|\tscientific name\t|\n\
+                     573\t|\tKlebsiella pneumoniae\t|\t\t|\tscientific name\t|\n\
+                     1386\t|\tBacillus\t|\t\t|\tscientific name\t|\n\
+                     55087\t|\tBacillus\t|\t\t|\tscientific name\t|\n";
+        fs::write(dir.path().join("nodes.dmp"), nodes).unwrap();
+        fs::write(dir.path().join("names.dmp"), names).unwrap();
+        let taxonomy = Taxonomy::from_taxdump(dir.path()).unwrap();
+
+        assert_eq!(taxonomy.resolve("573").unwrap(), "573");
+        assert_eq!(taxonomy.resolve(" klebsiella PNEUMONIAE ").unwrap(), "573");
+        assert_eq!(
+            taxonomy.resolve("999999"),
+            Err(TaxonLookupError::UnknownTaxid("999999".to_string()))
+        );
+
+        let ambiguous = taxonomy.resolve("Bacillus").unwrap_err();
+        assert_eq!(
+            ambiguous.to_string(),
+            "Taxon name 'Bacillus' is ambiguous, give one of these taxids instead: \
+             1386 (genus in Bacteria); 55087 (genus in Eukaryota)"
+        );
+
+        let misspelled = taxonomy.resolve("Klebsiela pneumonia").unwrap_err();
+        assert_eq!(
+            misspelled.to_string(),
+            "Unknown taxon 'Klebsiela pneumonia'; did you mean Klebsiella pneumoniae?"
+        );
+        assert_eq!(
+            taxonomy.resolve("Pseudomonas").unwrap_err().to_string(),
+            "Unknown taxon 'Pseudomonas'"
+        );
+
+        assert_eq!(edit_distance("kitten", "Sitting"), 3);
+        assert_eq!(
+            suggestions("Bacilus", ["Bacillus", "Bacteria", "Bacillus"]),
+            vec!["Bacillus"]
+        );
+    }
+}
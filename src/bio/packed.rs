@@ -0,0 +1,262 @@
+//! DNA packed two bits per base.
+//!
+//! A reference genome held as bytes takes one byte per base, four times what
+//! its four letters need; across a few thousand bacterial genomes, or one
+//! large eukaryotic assembly, that is the bulk of the memory a database build
+//! uses. `PackedSequence` stores 32 bases per `u64`, with the same encoding
+//! as the packed k-mers of `bio::kmers`, so k-mers are read straight from the
+//! packed words.
+//!
+//! Ambiguous bases (N and other IUPAC codes) have no two-bit code, and what
+//! to do with them depends on the use:
+//!
+//! * `skip` keeps their positions, and no k-mer spans them
+//! * `split` cuts the sequence into fragments at each run of them
+//! * `randomize` replaces each with a base drawn from a fixed seed, as some
+//!   aligners do, so the same input always packs the same way
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::bio::kmers::{base_code, KmerRoller, KmerWord};
+
+/// Bases per packed word
+const BASES_PER_WORD: usize = 32;
+
+/// Seed of the bases that replace ambiguous ones with `NPolicy::Randomize`
+const RANDOMIZE_SEED: u64 = 0x2b17_5eed;
+
+/// Letters of the two-bit codes
+const BASES: &[u8; 4] = b"ACGT";
+
+/// What to do with bases other than A, C, G and T when packing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NPolicy {
+    /// Keep their positions; k-mers spanning them are skipped
+    #[default]
+    Skip,
+    /// Cut the sequence into fragments around them
+    Split,
+    /// Replace them with seeded random bases
+    Randomize,
+}
+
+impl fmt::Display for NPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NPolicy::Skip => write!(f, "skip"),
+            NPolicy::Split => write!(f, "split"),
+            NPolicy::Randomize => write!(f, "randomize"),
+        }
+    }
+}
+
+/// A DNA sequence packed two bits per base
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackedSequence {
+    words: Vec<u64>,
+    len: usize,
+    /// Runs of ambiguous bases (stored as A in `words`), in order
+    ambiguous: Vec<Range<usize>>,
+}
+
+impl PackedSequence {
+    /// Pack `sequence`, which may mix upper and lower case. Gives one
+    /// sequence, except with `NPolicy::Split`, which gives one per stretch
+    /// of unambiguous bases (none for a sequence without any).
+    pub fn encode(sequence: &[u8], policy: NPolicy) -> Vec<PackedSequence> {
+        match policy {
+            NPolicy::Split => sequence
+                .split(|&base| base_code(base).is_none())
+                .filter(|fragment| !fragment.is_empty())
+                .map(|fragment| Self::pack(fragment, |_| 0))
+                .collect(),
+            NPolicy::Skip => vec![Self::pack(sequence, |_| 0)],
+            NPolicy::Randomize => {
+                let mut rng = StdRng::seed_from_u64(RANDOMIZE_SEED);
+                let mut packed = Self::pack(sequence, |_| rng.random_range(0..4));
+                packed.ambiguous.clear();
+                packed
+            }
+        }
+    }
+
+    /// Pack with `replace` giving the code of each ambiguous base
+    fn pack(sequence: &[u8], mut replace: impl FnMut(usize) -> u8) -> PackedSequence {
+        let mut words = vec![0u64; sequence.len().div_ceil(BASES_PER_WORD)];
+        let mut ambiguous: Vec<Range<usize>> = Vec::new();
+        for (i, &base) in sequence.iter().enumerate() {
+            let code = match base_code(base) {
+                Some(code) => code,
+                None => {
+                    match ambiguous.last_mut() {
+                        Some(run) if run.end == i => run.end += 1,
+                        _ => ambiguous.push(i..i + 1),
+                    }
+                    replace(i)
+                }
+            };
+            words[i / BASES_PER_WORD] |= (code as u64) << shift(i);
+        }
+        PackedSequence {
+            words,
+            len: sequence.len(),
+            ambiguous,
+        }
+    }
+
+    /// Number of bases
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes used by the packed bases
+    pub fn packed_bytes(&self) -> usize {
+        self.words.len() * std::mem::size_of::<u64>()
+    }
+
+    /// Two-bit code of the base at `i`, `None` for an ambiguous base
+    pub fn code(&self, i: usize) -> Option<u8> {
+        if self.is_ambiguous(i) {
+            return None;
+        }
+        Some(((self.words[i / BASES_PER_WORD] >> shift(i)) & 0b11) as u8)
+    }
+
+    fn is_ambiguous(&self, i: usize) -> bool {
+        // Runs are sorted and disjoint
+        let run = self.ambiguous.partition_point(|run| run.end <= i);
+        self.ambiguous.get(run).is_some_and(|run| run.contains(&i))
+    }
+
+    /// The bases as uppercase letters, with N for ambiguous ones
+    pub fn decode(&self) -> Vec<u8> {
+        (0..self.len)
+            .map(|i| self.code(i).map_or(b'N', |code| BASES[code as usize]))
+            .collect()
+    }
+
+    /// Canonical k-mers packed into `W`, as `bio::kmers::PackedKmerIter`
+    /// gives for the decoded sequence; fails unless `k` is between 1 and
+    /// `W::MAX_K`
+    pub fn canonical_kmers<W: KmerWord>(&self, k: usize) -> Result<PackedSequenceKmers<'_, W>> {
+        Ok(PackedSequenceKmers {
+            sequence: self,
+            pos: 0,
+            run: 0,
+            roller: KmerRoller::new(k)?,
+        })
+    }
+
+    /// How often each canonical k-mer (k <= 32) occurs
+    pub fn count_canonical_kmers(&self, k: usize) -> Result<HashMap<u64, u32>> {
+        let mut counts = HashMap::new();
+        for kmer in self.canonical_kmers::<u64>(k)? {
+            *counts.entry(kmer).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+}
+
+/// Shift of base `i` within its word; the first base is in the highest bits
+fn shift(i: usize) -> usize {
+    2 * (BASES_PER_WORD - 1 - i % BASES_PER_WORD)
+}
+
+/// Iterator over the canonical k-mers of a `PackedSequence`
+pub struct PackedSequenceKmers<'a, W: KmerWord> {
+    sequence: &'a PackedSequence,
+    pos: usize,
+    /// First ambiguous run not yet passed
+    run: usize,
+    roller: KmerRoller<W>,
+}
+
+impl<'a, W: KmerWord> Iterator for PackedSequenceKmers<'a, W> {
+    type Item = W;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sequence = self.sequence;
+        while self.pos < sequence.len {
+            if let Some(run) = sequence.ambiguous.get(self.run) {
+                if run.start == self.pos {
+                    self.pos = run.end;
+                    self.run += 1;
+                    self.roller.reset();
+                    continue;
+                }
+            }
+            let i = self.pos;
+            self.pos += 1;
+            let code = ((sequence.words[i / BASES_PER_WORD] >> shift(i)) & 0b11) as u8;
+            if let Some(kmer) = self.roller.push(code) {
+                return Some(kmer);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bio::kmers::PackedKmerIter;
+
+    #[test]
+    fn test_pack_round_trip_and_n_policies() {
+        let sequence = b"ACGTacgtNNGATTACAGATTACAGGGCCCTTTAAACCGGTTAACCGGTTNAC";
+        let packed = PackedSequence::encode(sequence, NPolicy::Skip);
+        assert_eq!(packed.len(), 1);
+        let packed = &packed[0];
+        assert_eq!(packed.len(), sequence.len());
+        assert_eq!(packed.packed_bytes(), 16);
+        assert_eq!(packed.decode(), sequence.to_ascii_uppercase());
+        assert_eq!(packed.code(8), None);
+        assert_eq!(packed.code(3), Some(3));
+
+        for k in [1, 5, 21, 32] {
+            let from_bytes: Vec<u64> = PackedKmerIter::new(sequence, k).unwrap().collect();
+            let from_packed: Vec<u64> = packed.canonical_kmers(k).unwrap().collect();
+            assert_eq!(from_packed, from_bytes, "k = {}", k);
+        }
+        assert!(packed.canonical_kmers::<u64>(33).is_err());
+        // GATTACA occurs twice, and is canonical
+        let counts = packed.count_canonical_kmers(7).unwrap();
+        let gattaca: u64 = PackedKmerIter::new(b"GATTACA", 7).unwrap().next().unwrap();
+        assert_eq!(counts[&gattaca], 2);
+
+        let fragments = PackedSequence::encode(sequence, NPolicy::Split);
+        let decoded: Vec<Vec<u8>> = fragments.iter().map(PackedSequence::decode).collect();
+        assert_eq!(
+            decoded,
+            vec![
+                b"ACGTACGT".to_vec(),
+                b"GATTACAGATTACAGGGCCCTTTAAACCGGTTAACCGGTT".to_vec(),
+                b"AC".to_vec(),
+            ]
+        );
+        assert!(PackedSequence::encode(b"NNN", NPolicy::Split).is_empty());
+
+        let randomized = PackedSequence::encode(sequence, NPolicy::Randomize);
+        let decoded = randomized[0].decode();
+        assert_eq!(decoded.len(), sequence.len());
+        assert!(decoded.iter().all(|base| BASES.contains(base)));
+        assert_eq!(decoded[..8], sequence[..8].to_ascii_uppercase());
+        // The same input always gets the same bases
+        assert_eq!(
+            PackedSequence::encode(sequence, NPolicy::Randomize),
+            randomized
+        );
+    }
+}
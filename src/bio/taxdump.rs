@@ -0,0 +1,379 @@
+//! Offline taxonomy from NCBI taxdump files or lineage maps, with a binary cache.
+//!
+//! The NCBI `nodes.dmp` and `names.dmp` run to hundreds of megabytes, and a
+//! workflow that calls the CLI once per sample would parse them once per
+//! sample. The parsed taxonomy is therefore kept in a bincode cache file next
+//! to the other downloads. The cache records the size and modification time of
+//! every source file and is rebuilt as soon as any of them changes, so an
+//! updated dump is never shadowed by a stale cache.
+//!
+//! A lineage map (one `id<TAB>Domain; Phylum; ...; Species` line per taxon) is
+//! turned into the same tree: each prefix of a lineage becomes a node whose
+//! ID is the prefix itself (names joined by `;`), ranked by position as in
+//! `parse_lineage`, and each mapped ID hangs below its lineage.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+use bincode::config::standard;
+use bincode::{decode_from_slice, encode_to_vec, Decode, Encode};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::bio::taxonomy::TaxonomicLevel;
+use crate::utils::paths::long_path;
+
+/// Version of the cache layout; caches of other versions are rebuilt
+const CACHE_VERSION: u32 = 1;
+
+/// ID of the NCBI root node, which is its own parent
+pub const NCBI_ROOT: &str = "1";
+
+/// One taxon
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct TaxNode {
+    /// Parent taxon (`None` for the root)
+    pub parent: Option<String>,
+    /// Rank as named by the source ("species", "genus", "no rank", ...)
+    pub rank: String,
+    /// Scientific name
+    pub name: String,
+}
+
+/// Taxonomy tree keyed by taxon ID
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct Taxonomy {
+    nodes: HashMap<String, TaxNode>,
+}
+
+/// Size and modification time of a source file when the cache was built
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+struct SourceStamp {
+    path: String,
+    len: u64,
+    modified_nanos: u128,
+}
+
+impl SourceStamp {
+    fn of(path: &Path) -> Result<Self> {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("Cannot read {}", path.display()))?;
+        let modified_nanos = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_nanos());
+        Ok(SourceStamp {
+            path: path.display().to_string(),
+            len: metadata.len(),
+            modified_nanos,
+        })
+    }
+}
+
+impl Taxonomy {
+    /// Number of taxa
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The taxon with ID `taxid`
+    pub fn node(&self, taxid: &str) -> Option<&TaxNode> {
+        self.nodes.get(taxid)
+    }
+
+    /// Every taxon with its ID, in no particular order
+    pub fn taxa(&self) -> impl Iterator<Item = (&str, &TaxNode)> {
+        self.nodes
+            .iter()
+            .map(|(taxid, node)| (taxid.as_str(), node))
+    }
+
+    /// Scientific names from the top of the tree down to `taxid`, without the
+    /// NCBI root; empty for an unknown taxon
+    pub fn lineage(&self, taxid: &str) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut current = Some(taxid);
+        // A malformed dump could contain a cycle; no lineage is longer than the tree
+        while let Some(id) = current.filter(|_| names.len() <= self.nodes.len()) {
+            let Some(node) = self.nodes.get(id) else {
+                break;
+            };
+            if id == NCBI_ROOT {
+                break;
+            }
+            names.push(node.name.clone());
+            current = node.parent.as_deref();
+        }
+        names.reverse();
+        names
+    }
+
+    /// The taxon of rank `rank` ("genus", "family", ...) that is `taxid` or one
+    /// of its ancestors; `None` for an unknown taxon or one without such an ancestor
+    pub fn ancestor_at_rank(&self, taxid: &str, rank: &str) -> Option<&TaxNode> {
+        let mut current = Some(taxid);
+        let mut steps = 0;
+        // A malformed dump could contain a cycle; no path is longer than the tree
+        while let Some(id) = current.filter(|_| steps <= self.nodes.len()) {
+            let node = self.nodes.get(id)?;
+            if node.rank == rank {
+                return Some(node);
+            }
+            current = node.parent.as_deref();
+            steps += 1;
+        }
+        None
+    }
+
+    /// Parse `nodes.dmp` and `names.dmp` (scientific names) from a taxdump directory
+    pub fn from_taxdump(dir: &Path) -> Result<Self> {
+        let mut names: HashMap<String, String> = HashMap::new();
+        for fields in dmp_records(&dir.join("names.dmp"))? {
+            if fields.len() >= 4 && fields[3] == "scientific name" {
+                names.insert(fields[0].clone(), fields[1].clone());
+            }
+        }
+        let mut nodes = HashMap::new();
+        for fields in dmp_records(&dir.join("nodes.dmp"))? {
+            if fields.len() < 3 {
+                continue;
+            }
+            let (taxid, parent) = (&fields[0], &fields[1]);
+            let name = names.remove(taxid).unwrap_or_else(|| taxid.clone());
+            nodes.insert(
+                taxid.clone(),
+                TaxNode {
+                    parent: (parent != taxid).then(|| parent.clone()),
+                    rank: fields[2].clone(),
+                    name,
+                },
+            );
+        }
+        if nodes.is_empty() {
+            bail!("{} contains no taxa", dir.join("nodes.dmp").display());
+        }
+        Ok(Taxonomy { nodes })
+    }
+
+    /// Parse a lineage map: `id<TAB>Domain; Phylum; ...` per line, `#` comments allowed
+    pub fn from_lineage_map(path: &Path) -> Result<Self> {
+        let reader = BufReader::new(
+            File::open(path).with_context(|| format!("Cannot open {}", path.display()))?,
+        );
+        let levels = TaxonomicLevel::all_levels();
+        let mut nodes: HashMap<String, TaxNode> = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((id, lineage)) = line.split_once('\t') else {
+                warn!("Skipping lineage map line without a tab: {}", line);
+                continue;
+            };
+            let mut parent: Option<String> = None;
+            let mut prefix: Vec<&str> = Vec::new();
+            for (depth, name) in lineage.split(';').map(str::trim).enumerate() {
+                if name.is_empty() {
+                    continue;
+                }
+                prefix.push(name);
+                let node_id = prefix.join(";");
+                let rank = levels.get(depth).map_or("no rank", |level| level.as_str());
+                nodes.entry(node_id.clone()).or_insert_with(|| TaxNode {
+                    parent: parent.clone(),
+                    rank: rank.to_string(),
+                    name: name.to_string(),
+                });
+                parent = Some(node_id);
+            }
+            nodes.insert(
+                id.trim().to_string(),
+                TaxNode {
+                    parent,
+                    rank: "no rank".to_string(),
+                    name: id.trim().to_string(),
+                },
+            );
+        }
+        Ok(Taxonomy { nodes })
+    }
+
+    /// Load a taxonomy from a taxdump directory or a lineage map file, reusing
+    /// the cache in `cache_dir` while the source files are unchanged
+    pub fn load_cached(source: &Path, cache_dir: &Path) -> Result<Self> {
+        let files = if source.is_dir() {
+            vec![source.join("nodes.dmp"), source.join("names.dmp")]
+        } else {
+            vec![source.to_path_buf()]
+        };
+        let stamps = files
+            .iter()
+            .map(|file| SourceStamp::of(file))
+            .collect::<Result<Vec<_>>>()?;
+        let cache_path = cache_file(source, cache_dir);
+
+        match read_cache(&cache_path, &stamps) {
+            Ok(Some(taxonomy)) => {
+                info!(
+                    "Loaded {} taxa from cache {}",
+                    taxonomy.len(),
+                    cache_path.display()
+                );
+                return Ok(taxonomy);
+            }
+            Ok(None) => debug!(
+                "Taxonomy cache {} is stale or missing",
+                cache_path.display()
+            ),
+            Err(e) => warn!(
+                "Ignoring unreadable taxonomy cache {}: {}",
+                cache_path.display(),
+                e
+            ),
+        }
+
+        let taxonomy = if source.is_dir() {
+            Taxonomy::from_taxdump(source)?
+        } else {
+            Taxonomy::from_lineage_map(source)?
+        };
+        info!("Parsed {} taxa from {}", taxonomy.len(), source.display());
+        if let Err(e) = write_cache(&cache_path, &stamps, &taxonomy) {
+            warn!(
+                "Cannot write taxonomy cache {}: {}",
+                cache_path.display(),
+                e
+            );
+        }
+        Ok(taxonomy)
+    }
+}
+
+/// Fields of each `\t|\t`-separated record of a taxdump file
+fn dmp_records(path: &Path) -> Result<impl Iterator<Item = Vec<String>>> {
+    let reader = BufReader::new(
+        File::open(path).with_context(|| format!("Cannot open {}", path.display()))?,
+    );
+    Ok(reader.lines().map_while(|line| line.ok()).map(|line| {
+        line.trim_end_matches(['\t', '|'])
+            .split("\t|\t")
+            .map(|field| field.trim().to_string())
+            .collect()
+    }))
+}
+
+/// Cache file for a source, named after a digest of its path
+fn cache_file(source: &Path, cache_dir: &Path) -> PathBuf {
+    let canonical = source
+        .canonicalize()
+        .unwrap_or_else(|_| source.to_path_buf());
+    let digest = hex::encode(Sha256::digest(canonical.display().to_string().as_bytes()));
+    long_path(&cache_dir.join(format!("taxonomy-{}.bin", &digest[..16])))
+}
+
+/// The cached taxonomy, `None` if there is no cache or it was built from
+/// other versions of the source files
+fn read_cache(path: &Path, stamps: &[SourceStamp]) -> Result<Option<Taxonomy>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = fs::read(path)?;
+    // The header is decoded first, so a stale cache is rejected without
+    // decoding the whole tree
+    let ((version, cached_stamps), header_len): ((u32, Vec<SourceStamp>), usize) =
+        decode_from_slice(&bytes, standard())?;
+    if version != CACHE_VERSION || cached_stamps != stamps {
+        return Ok(None);
+    }
+    let (taxonomy, _): (Taxonomy, usize) = decode_from_slice(&bytes[header_len..], standard())?;
+    Ok(Some(taxonomy))
+}
+
+/// Write the cache through a temporary file, so concurrent runs never read a
+/// partly written cache
+fn write_cache(path: &Path, stamps: &[SourceStamp], taxonomy: &Taxonomy) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut bytes = encode_to_vec((CACHE_VERSION, stamps), standard())?;
+    bytes.extend(encode_to_vec(taxonomy, standard())?);
+    let partial = path.with_extension(format!("bin.{}.partial", std::process::id()));
+    fs::write(&partial, bytes)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_taxdump_cached_until_source_changes() {
+        let dir = tempdir().unwrap();
+        let dump = dir.path().join("taxdump");
+        let cache = dir.path().join("cache");
+        fs::create_dir_all(&dump).unwrap();
+        let nodes = "1\t|\t1\t|\tno rank\t|\n\
+                     2\t|\t1\t|\tsuperkingdom\t|\n\
+                     561\t|\t2\t|\tgenus\t|\n\
+                     562\t|\t561\t|\tspecies\t|\n";
+        let names = "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+                     2\t|\tBacteria\t|\tBacteria <bacteria>\t|\tscientific name\t|\n\
+                     561\t|\tEscherichia\t|\t\t|\tscientific name\t|\n\
+                     562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|\n\
+                     562\t|\tBacillus coli\t|\t\t|\tsynonym\t|\n";
+        fs::write(dump.join("nodes.dmp"), nodes).unwrap();
+        fs::write(dump.join("names.dmp"), names).unwrap();
+
+        let parsed = Taxonomy::load_cached(&dump, &cache).unwrap();
+        assert_eq!(parsed.len(), 4);
+        assert_eq!(
+            parsed.lineage("562"),
+            vec!["Bacteria", "Escherichia", "Escherichia coli"]
+        );
+        assert_eq!(parsed.node("562").unwrap().rank, "species");
+        assert_eq!(
+            parsed.ancestor_at_rank("562", "genus").unwrap().name,
+            "Escherichia"
+        );
+        assert!(parsed.ancestor_at_rank("562", "family").is_none());
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+        assert_eq!(Taxonomy::load_cached(&dump, &cache).unwrap(), parsed);
+
+        // A new dump (different size, so the stamp differs whatever the mtime resolution)
+        fs::write(
+            dump.join("nodes.dmp"),
+            format!("{}620\t|\t2\t|\tgenus\t|\n", nodes),
+        )
+        .unwrap();
+        let updated = Taxonomy::load_cached(&dump, &cache).unwrap();
+        assert_eq!(updated.len(), 5);
+        assert_eq!(updated.lineage("620"), vec!["Bacteria", "620"]);
+
+        let map = dir.path().join("lineages.tsv");
+        fs::write(
+            &map,
+            "# accession\tlineage\nGCF_1\tBacteria; Pseudomonadota; ; \nGCF_2\tBacteria; Bacillota\n",
+        )
+        .unwrap();
+        let mapped = Taxonomy::load_cached(&map, &cache).unwrap();
+        assert_eq!(
+            mapped.lineage("GCF_1"),
+            vec!["Bacteria", "Pseudomonadota", "GCF_1"]
+        );
+        assert_eq!(mapped.node("Bacteria;Bacillota").unwrap().rank, "phylum");
+        assert_eq!(mapped.len(), 5);
+    }
+}
@@ -0,0 +1,624 @@
+//! Configuration for strain_ahsp.
+//!
+//! Settings are read from a JSON file; any section or field that is omitted
+//! falls back to its default.
+//!
+//! A config file is checked as a whole before a run starts: unknown keys
+//! (usually typos, which serde would otherwise ignore), values of the wrong
+//! type, values out of range and settings that contradict each other are all
+//! collected and reported together, so one edit fixes them all instead of
+//! one failed run per mistake.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::database::storage::StorageBackend;
+use crate::pipeline::locale::{ReportLanguage, ReportLocale};
+use crate::pipeline::strict::StrictConfig;
+use crate::pipeline::telemetry::TelemetryConfig;
+use crate::sketch::signature::{
+    LevelParameters, SignatureLayout, DEFAULT_MACRO_K, DEFAULT_MESO_K, DEFAULT_SKETCH_SIZE,
+};
+use crate::utils::workspace::WorkspaceConfig;
+
+/// Top-level configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Signature database storage settings
+    pub database: DatabaseConfig,
+
+    /// Sketch parameters of new databases
+    pub sketch: SketchConfig,
+
+    /// Report language and number formatting
+    pub report: ReportConfig,
+
+    /// Scratch directory for intermediate files
+    pub workspace: WorkspaceConfig,
+
+    /// Database and environment pins checked by `--strict`
+    pub strict: StrictConfig,
+
+    /// OpenTelemetry export of traces and metrics
+    pub telemetry: TelemetryConfig,
+}
+
+impl Config {
+    /// Load configuration from a JSON file, failing with every problem found
+    /// in it (as `ConfigErrors`) if there are any
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open config file {}", path.display()))?;
+        let value: Value = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        Self::from_value(value).map_err(|errors| {
+            ConfigErrors {
+                path: path.to_path_buf(),
+                errors,
+            }
+            .into()
+        })
+    }
+
+    /// Configuration from parsed JSON, or every problem found in it
+    pub fn from_value(value: Value) -> Result<Self, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let defaults = serde_json::to_value(Config::default()).expect("config serializes");
+        check_keys(&value, &defaults, "", &mut errors);
+        match &value {
+            Value::Object(sections) => {
+                for (name, section) in sections {
+                    match name.as_str() {
+                        "database" => check_fields::<DatabaseConfig>(name, section, &mut errors),
+                        "sketch" => check_fields::<SketchConfig>(name, section, &mut errors),
+                        "report" => check_fields::<ReportConfig>(name, section, &mut errors),
+                        "workspace" => check_fields::<WorkspaceConfig>(name, section, &mut errors),
+                        "strict" => check_fields::<StrictConfig>(name, section, &mut errors),
+                        "telemetry" => check_fields::<TelemetryConfig>(name, section, &mut errors),
+                        _ => {}
+                    }
+                }
+            }
+            _ => errors.push(ConfigError::InvalidValue {
+                key: "(top level)".to_string(),
+                message: "expected an object of sections".to_string(),
+            }),
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let config: Config = serde_json::from_value(value).map_err(|e| {
+            vec![ConfigError::InvalidValue {
+                key: "(top level)".to_string(),
+                message: e.to_string(),
+            }]
+        })?;
+        let errors = config.validate();
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Values out of range and settings that contradict each other
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let database = &self.database;
+        if database.cache_capacity_bytes == 0 {
+            errors.push(ConfigError::out_of_range(
+                "database.cache_capacity_bytes",
+                database.cache_capacity_bytes,
+                "more than 0",
+            ));
+        }
+        if !zstd::compression_level_range().contains(&database.signature_zstd_level) {
+            errors.push(ConfigError::out_of_range(
+                "database.signature_zstd_level",
+                database.signature_zstd_level,
+                "a zstd level, at most 22",
+            ));
+        }
+        for (i, key) in database.trusted_bundle_keys.iter().enumerate() {
+            if !is_hex(key, 32) {
+                errors.push(ConfigError::out_of_range(
+                    &format!("database.trusted_bundle_keys[{}]", i),
+                    key,
+                    "an Ed25519 public key as 64 hex digits",
+                ));
+            }
+        }
+
+        errors.extend(self.sketch.validate());
+
+        let report = &self.report;
+        if let Some(separator) = report.decimal_separator {
+            if separator.is_ascii_digit() || separator.is_whitespace() {
+                errors.push(ConfigError::out_of_range(
+                    "report.decimal_separator",
+                    separator,
+                    "a character other than a digit or space",
+                ));
+            }
+        }
+        if let Some(separator) = &report.thousands_separator {
+            if separator.chars().count() > 1 {
+                errors.push(ConfigError::out_of_range(
+                    "report.thousands_separator",
+                    format!("{:?}", separator),
+                    "one character, or \"\" for no grouping",
+                ));
+            }
+        }
+        // Only a separator set here can clash with the language's other one
+        if report.decimal_separator.is_some() || report.thousands_separator.is_some() {
+            let locale = ReportLocale::from_config(report);
+            if locale.thousands_separator == Some(locale.decimal_separator) {
+                errors.push(ConfigError::Incompatible {
+                    first: "report.decimal_separator",
+                    second: "report.thousands_separator",
+                    reason: "numbers would be ambiguous with the same separator for both",
+                });
+            }
+        }
+
+        if let Some(digest) = &self.strict.database_sha256 {
+            if !is_hex(digest, 32) {
+                errors.push(ConfigError::out_of_range(
+                    "strict.database_sha256",
+                    digest,
+                    "a SHA-256 digest as 64 hex digits",
+                ));
+            }
+        }
+
+        let telemetry = &self.telemetry;
+        if telemetry.enabled
+            && !(telemetry.endpoint.starts_with("http://")
+                || telemetry.endpoint.starts_with("https://"))
+        {
+            errors.push(ConfigError::out_of_range(
+                "telemetry.endpoint",
+                &telemetry.endpoint,
+                "an http:// or https:// URL",
+            ));
+        }
+        if telemetry.export_interval_secs == 0 {
+            errors.push(ConfigError::out_of_range(
+                "telemetry.export_interval_secs",
+                telemetry.export_interval_secs,
+                "at least 1",
+            ));
+        }
+        errors
+    }
+
+    /// Load configuration from an optional path, using defaults when none is given
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::from_file(path),
+            None => Ok(Config::default()),
+        }
+    }
+}
+
+/// A problem with one setting of a config file
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConfigError {
+    #[error("{key}: unknown key (expected one of {expected})")]
+    UnknownKey { key: String, expected: String },
+
+    #[error("{key}: {message}")]
+    InvalidValue { key: String, message: String },
+
+    #[error("{key}: {value} is out of range (expected {expected})")]
+    OutOfRange {
+        key: String,
+        value: String,
+        expected: &'static str,
+    },
+
+    #[error("{first} and {second} cannot be combined: {reason}")]
+    Incompatible {
+        first: &'static str,
+        second: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl ConfigError {
+    fn out_of_range(key: &str, value: impl fmt::Display, expected: &'static str) -> Self {
+        ConfigError::OutOfRange {
+            key: key.to_string(),
+            value: value.to_string(),
+            expected,
+        }
+    }
+}
+
+/// Every problem found in a config file
+#[derive(Debug, Clone)]
+pub struct ConfigErrors {
+    pub path: PathBuf,
+    pub errors: Vec<ConfigError>,
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Config file {} has {} problem{}:",
+            self.path.display(),
+            self.errors.len(),
+            if self.errors.len() == 1 { "" } else { "s" }
+        )?;
+        for error in &self.errors {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Report the keys of `value` that `defaults`, the same section serialized
+/// from its defaults, does not have, descending into nested sections
+fn check_keys(value: &Value, defaults: &Value, prefix: &str, errors: &mut Vec<ConfigError>) {
+    let (Value::Object(fields), Value::Object(known)) = (value, defaults) else {
+        return;
+    };
+    for (key, field) in fields {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match known.get(key) {
+            Some(default) => check_keys(field, default, &path, errors),
+            None => errors.push(ConfigError::UnknownKey {
+                key: path,
+                expected: known.keys().cloned().collect::<Vec<_>>().join(", "),
+            }),
+        }
+    }
+}
+
+/// Deserialize each field of a section on its own, with defaults for the
+/// rest, so a value of the wrong type is reported for every field that has one
+fn check_fields<T: DeserializeOwned>(name: &str, section: &Value, errors: &mut Vec<ConfigError>) {
+    let Value::Object(fields) = section else {
+        if let Err(e) = serde_json::from_value::<T>(section.clone()) {
+            errors.push(ConfigError::InvalidValue {
+                key: name.to_string(),
+                message: e.to_string(),
+            });
+        }
+        return;
+    };
+    for (key, field) in fields {
+        let single = Value::Object(Map::from_iter([(key.clone(), field.clone())]));
+        if let Err(e) = serde_json::from_value::<T>(single) {
+            errors.push(ConfigError::InvalidValue {
+                key: format!("{}.{}", name, key),
+                message: e.to_string(),
+            });
+        }
+    }
+}
+
+/// Whether `text` is `bytes` bytes written as hex digits
+fn is_hex(text: &str, bytes: usize) -> bool {
+    text.len() == bytes * 2 && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Sketch parameters of new databases, which `db init` flags override
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SketchConfig {
+    /// K-mer size of each resolution level, coarsest (largest) first
+    pub kmer_sizes: Vec<usize>,
+
+    /// Hashes kept per level in fixed-size MinHash sketches (0 for scaled sketches)
+    pub num_hashes: usize,
+
+    /// Keep hashes below 2^64 / `scaled` in scaled MinHash sketches (0 for fixed-size sketches)
+    pub scaled: u64,
+}
+
+impl Default for SketchConfig {
+    fn default() -> Self {
+        SketchConfig {
+            kmer_sizes: vec![DEFAULT_MACRO_K, DEFAULT_MESO_K],
+            num_hashes: DEFAULT_SKETCH_SIZE,
+            scaled: 0,
+        }
+    }
+}
+
+impl SketchConfig {
+    /// Signature layout with these parameters
+    pub fn layout(&self) -> SignatureLayout {
+        SignatureLayout {
+            levels: self
+                .kmer_sizes
+                .iter()
+                .map(|&kmer_size| LevelParameters {
+                    kmer_size,
+                    algorithm: "minhash".to_string(),
+                    num_hashes: self.num_hashes,
+                    scaled: self.scaled,
+                    molecule_type: "DNA".to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Problems with these parameters
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if self.kmer_sizes.is_empty() {
+            errors.push(ConfigError::out_of_range(
+                "sketch.kmer_sizes",
+                "[]",
+                "at least one k-mer size",
+            ));
+        }
+        for (i, &kmer_size) in self.kmer_sizes.iter().enumerate() {
+            if kmer_size == 0 || kmer_size > 63 {
+                errors.push(ConfigError::out_of_range(
+                    &format!("sketch.kmer_sizes[{}]", i),
+                    kmer_size,
+                    "1 to 63",
+                ));
+            }
+        }
+        if self.kmer_sizes.windows(2).any(|pair| pair[0] <= pair[1]) {
+            errors.push(ConfigError::out_of_range(
+                "sketch.kmer_sizes",
+                format!("{:?}", self.kmer_sizes),
+                "decreasing k-mer sizes, coarsest first",
+            ));
+        }
+        match (self.num_hashes, self.scaled) {
+            (0, 0) => errors.push(ConfigError::out_of_range(
+                "sketch.num_hashes",
+                0,
+                "more than 0 unless sketch.scaled is set",
+            )),
+            (n, s) if n > 0 && s > 0 => errors.push(ConfigError::Incompatible {
+                first: "sketch.num_hashes",
+                second: "sketch.scaled",
+                reason: "a sketch is either fixed-size or scaled; set the other to 0",
+            }),
+            _ => {}
+        }
+        errors
+    }
+}
+
+/// Storage settings for the signature database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    /// Embedded store used for signatures ("sled" or "redb")
+    pub backend: StorageBackend,
+
+    /// Size of sled's page cache in bytes
+    pub cache_capacity_bytes: u64,
+
+    /// Background flush interval in milliseconds (`None` disables periodic flushes)
+    pub flush_every_ms: Option<u64>,
+
+    /// Compress individual signature blobs with zstd before insert
+    pub compress_signatures: bool,
+
+    /// zstd level for signature blobs
+    pub signature_zstd_level: i32,
+
+    /// Ed25519 public keys (hex) of trusted bundle publishers; when set,
+    /// `db install` only accepts bundles signed with one of them
+    pub trusted_bundle_keys: Vec<String>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: StorageBackend::Sled,
+            cache_capacity_bytes: 1024 * 1024 * 1024,
+            flush_every_ms: Some(500),
+            compress_signatures: true,
+            signature_zstd_level: 3,
+            trusted_bundle_keys: Vec::new(),
+        }
+    }
+}
+
+/// Language, number formatting and contents of reports
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReportConfig {
+    /// Language of section headers ("en", "de", "fr", "es" or "auto" for the
+    /// system locale)
+    pub language: ReportLanguage,
+
+    /// Decimal separator, if not the language's usual one
+    pub decimal_separator: Option<char>,
+
+    /// Thousands separator, if not the language's usual one ("" for none)
+    pub thousands_separator: Option<String>,
+
+    /// Sections included in sample reports
+    pub sections: ReportSections,
+
+    /// Leave file paths and the host name out of reports, for sharing them
+    /// outside the lab
+    pub redact: bool,
+}
+
+/// Sections of a sample report. Watchlist alerts and warnings are always
+/// included, since the rest of the report cannot be read safely without them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReportSections {
+    /// Read counts, QC, contamination and pairing metrics
+    pub qc: bool,
+
+    /// Top classification, user MAG hits and suggested missing references
+    pub taxonomy: bool,
+
+    /// Strain abundances, alignment confirmation, estimator comparison and strain clusters
+    pub strains: bool,
+
+    /// Antimicrobial resistance findings, for results that carry them
+    pub amr: bool,
+
+    /// Within-sample strain diversity
+    pub diversity: bool,
+
+    /// Methods text describing how the results were produced
+    pub methods: bool,
+}
+
+impl Default for ReportSections {
+    fn default() -> Self {
+        ReportSections {
+            qc: true,
+            taxonomy: true,
+            strains: true,
+            amr: true,
+            diversity: true,
+            methods: true,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    /// Build the sled configuration for a database at `path`
+    pub fn sled_config(&self, path: impl AsRef<Path>) -> sled::Config {
+        sled::Config::new()
+            .path(path)
+            .cache_capacity(self.cache_capacity_bytes)
+            .flush_every_ms(self.flush_every_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_partial_config_uses_defaults() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            r#"{{"database": {{"cache_capacity_bytes": 268435456, "compress_signatures": false}},
+                "report": {{"language": "de", "thousands_separator": "",
+                            "sections": {{"strains": false}}, "redact": true}}}}"#
+        )
+        .unwrap();
+
+        let config = Config::from_file(file.path()).unwrap();
+        assert_eq!(config.database.cache_capacity_bytes, 256 * 1024 * 1024);
+        assert!(!config.database.compress_signatures);
+        assert_eq!(config.database.flush_every_ms, Some(500));
+        assert_eq!(config.database.signature_zstd_level, 3);
+        assert_eq!(config.database.backend, StorageBackend::Sled);
+        assert_eq!(config.report.language, ReportLanguage::De);
+        assert_eq!(config.report.decimal_separator, None);
+        assert!(!config.report.sections.strains);
+        assert!(config.report.sections.qc);
+        assert!(config.report.redact);
+        assert!(!Config::default().report.redact);
+        assert!(!config.telemetry.enabled);
+    }
+
+    #[test]
+    fn test_config_errors_are_collected() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            r#"{{"database": {{"cache_capacity": 1, "signature_zstd_level": 40,
+                              "compress_signatures": "yes"}},
+                "sketch": {{"kmer_sizes": [21, 31], "num_hashes": 1000, "scaled": 100}},
+                "report": {{"language": "de", "thousands_separator": ",",
+                            "sections": {{"strain": false}}}},
+                "telemtry": {{}}}}"#
+        )
+        .unwrap();
+
+        let error = Config::from_file(file.path()).unwrap_err();
+        let errors = &error.downcast_ref::<ConfigErrors>().unwrap().errors;
+        // Unknown keys and wrong types come first; ranges are only checked
+        // once every value could be read
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert!(messages[0].starts_with("database.cache_capacity: unknown key"));
+        assert!(messages[1].starts_with("report.sections.strain: unknown key"));
+        assert!(
+            messages[2].starts_with("telemtry: unknown key") && messages[2].contains("telemetry")
+        );
+        assert!(messages[3].starts_with("database.compress_signatures: invalid type"));
+        assert!(error.to_string().contains("has 4 problems"));
+
+        let value = serde_json::json!({
+            "database": {"signature_zstd_level": 40},
+            "sketch": {"kmer_sizes": [21, 31], "num_hashes": 1000, "scaled": 100},
+            "report": {"language": "de", "thousands_separator": ","},
+        });
+        let errors = Config::from_value(value).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                ConfigError::OutOfRange {
+                    key: "database.signature_zstd_level".to_string(),
+                    value: "40".to_string(),
+                    expected: "a zstd level, at most 22",
+                },
+                ConfigError::OutOfRange {
+                    key: "sketch.kmer_sizes".to_string(),
+                    value: "[21, 31]".to_string(),
+                    expected: "decreasing k-mer sizes, coarsest first",
+                },
+                ConfigError::Incompatible {
+                    first: "sketch.num_hashes",
+                    second: "sketch.scaled",
+                    reason: "a sketch is either fixed-size or scaled; set the other to 0",
+                },
+                ConfigError::Incompatible {
+                    first: "report.decimal_separator",
+                    second: "report.thousands_separator",
+                    reason: "numbers would be ambiguous with the same separator for both",
+                },
+            ]
+        );
+
+        let scaled = SketchConfig {
+            num_hashes: 0,
+            scaled: 1000,
+            ..SketchConfig::default()
+        };
+        assert!(scaled.validate().is_empty());
+        assert_eq!(scaled.layout().validate(), Ok(()));
+        assert_eq!(SketchConfig::default().layout(), SignatureLayout::default());
+        assert!(Config::default().validate().is_empty());
+    }
+
+    #[test]
+    fn test_missing_config_file_errors() {
+        assert!(Config::from_file("/nonexistent/ahsp_config.json").is_err());
+        assert!(Config::load(None).is_ok());
+    }
+}
@@ -4,10 +4,11 @@
 //! where rows might be features (genes, k-mers, taxa) and columns
 //! are samples.
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use log::warn;
 use ndarray::{Array, Array2, Axis}; // Using ndarray for matrix operations
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap; // Or indexmap::IndexMap for ordered keys // For potential serialization
+use std::collections::{BTreeSet, HashMap, HashSet}; // Or indexmap::IndexMap for ordered keys // For potential serialization
 
 /// Represents a count table.
 ///
@@ -40,25 +41,54 @@ impl CountTable {
         }
     }
 
-    /// Builds a CountTable from processed data (e.g., k-mer counts per sample).
+    /// Builds a CountTable from per-sample counts.
     ///
     /// # Arguments
     ///
-    /// * `data` - A structure representing the counts per sample. This needs definition.
-    ///   For example, it could be `HashMap<String, HashMap<String, u32>>` where
-    ///   outer key is sample name, inner key is feature name, value is count.
+    /// * `data` - Counts keyed by sample name, then feature name. Features missing
+    ///   from a sample are counted as zero.
     ///
     /// # Returns
     ///
-    /// * `Result<Self>` - The constructed CountTable or an error.
-    pub fn build_from_data(/* data: AppropriateDataStructure */) -> Result<Self> {
-        // TODO: Implement the logic to:
-        // 1. Collect all unique feature names and sample names.
-        // 2. Create the feature_names, feature_map, sample_names, sample_map.
-        // 3. Initialize the ndarray::Array2 with zeros based on dimensions.
-        // 4. Populate the Array2 with counts from the input data structure.
-        // 5. Handle potential errors (e.g., inconsistent data).
-        unimplemented!("CountTable::build_from_data needs implementation");
+    /// * `Result<Self>` - The constructed CountTable, with samples and features sorted by name.
+    pub fn build_from_data(data: &HashMap<String, HashMap<String, f64>>) -> Result<Self> {
+        let index = DataIndex::new(data);
+        let mut counts = Array2::zeros((index.feature_names.len(), index.sample_names.len()));
+        index.fill(data, CountPrecision::F64, |cell, count| {
+            counts[cell] = count;
+            true
+        })?;
+
+        Ok(CountTable {
+            counts,
+            feature_names: index.feature_names,
+            feature_map: index.feature_map,
+            sample_names: index.sample_names,
+            sample_map: index.sample_map,
+        })
+    }
+
+    /// Copies the counts into a `CompactCountTable` at the given precision.
+    ///
+    /// Fails if a count cannot be stored at that precision, e.g. a fractional
+    /// normalized value in `CountPrecision::U32`.
+    pub fn to_compact(&self, precision: CountPrecision) -> Result<CompactCountTable> {
+        let mut counts = CountStorage::zeros(precision, self.counts.dim());
+        for ((feature, sample), &count) in self.counts.indexed_iter() {
+            if !counts.set([feature, sample], count) {
+                return Err(unrepresentable(
+                    count,
+                    &self.feature_names[feature],
+                    &self.sample_names[sample],
+                    precision,
+                ));
+            }
+        }
+        Ok(CompactCountTable {
+            counts,
+            feature_names: self.feature_names.clone(),
+            sample_names: self.sample_names.clone(),
+        })
     }
 
     /// Adds a sample column to the table.
@@ -131,6 +161,329 @@ impl Default for CountTable {
     }
 }
 
+/// How a sample ID given more than once in a count table, metadata file or
+/// results directory is handled, e.g. for a re-sequenced sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Refuse the input, naming the duplicated IDs
+    #[default]
+    Error,
+    /// Keep every occurrence, renaming the second `<id>_2`, the third `<id>_3`, ...
+    Suffix,
+    /// Combine the occurrences into one sample: counts are summed, metadata
+    /// rows must agree
+    Merge,
+}
+
+impl DuplicatePolicy {
+    /// The ID each of `ids` is stored under: the ID itself, except with
+    /// `Suffix` for repeats, which get the first free `_<n>` suffix. With
+    /// `Merge` repeats keep their ID, and the caller combines them. `what`
+    /// names the input in errors and warnings.
+    pub fn sample_ids(self, ids: &[String], what: &str) -> Result<Vec<String>> {
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for id in ids {
+            *seen.entry(id.as_str()).or_default() += 1;
+        }
+        let mut duplicated: Vec<&str> = seen
+            .iter()
+            .filter(|&(_, &count)| count > 1)
+            .map(|(&id, _)| id)
+            .collect();
+        if duplicated.is_empty() {
+            return Ok(ids.to_vec());
+        }
+        duplicated.sort_unstable();
+
+        match self {
+            DuplicatePolicy::Error => bail!(
+                "{} lists sample IDs more than once: {} (choose --duplicate-samples suffix or merge)",
+                what,
+                duplicated.join(", ")
+            ),
+            DuplicatePolicy::Merge => {
+                warn!(
+                    "{}: merging repeated sample IDs {}",
+                    what,
+                    duplicated.join(", ")
+                );
+                Ok(ids.to_vec())
+            }
+            DuplicatePolicy::Suffix => {
+                let mut taken: HashSet<String> = ids.iter().cloned().collect();
+                let mut occurrences: HashMap<&str, usize> = HashMap::new();
+                let mut renamed = Vec::with_capacity(ids.len());
+                for id in ids {
+                    let occurrence = occurrences.entry(id.as_str()).or_default();
+                    *occurrence += 1;
+                    if *occurrence == 1 {
+                        renamed.push(id.clone());
+                        continue;
+                    }
+                    let mut suffix = *occurrence;
+                    let mut candidate = format!("{}_{}", id, suffix);
+                    while taken.contains(&candidate) {
+                        suffix += 1;
+                        candidate = format!("{}_{}", id, suffix);
+                    }
+                    warn!("{}: repeated sample {} renamed {}", what, id, candidate);
+                    taken.insert(candidate.clone());
+                    renamed.push(candidate);
+                }
+                Ok(renamed)
+            }
+        }
+    }
+}
+
+/// Numeric type used to store the counts of a `CompactCountTable`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CountPrecision {
+    /// Double precision, as used by `CountTable`
+    #[default]
+    F64,
+    /// Single precision. Whole counts up to 2^24 (about 16.7 million) are
+    /// exact; larger ones are rounded to within one part in 10^7.
+    F32,
+    /// Unsigned 32-bit integers; only whole counts can be stored
+    U32,
+}
+
+impl CountPrecision {
+    /// Bytes taken by one stored count
+    pub fn bytes_per_count(self) -> usize {
+        match self {
+            CountPrecision::F64 => 8,
+            CountPrecision::F32 | CountPrecision::U32 => 4,
+        }
+    }
+}
+
+/// A features x samples count matrix at one of the `CountPrecision`s
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CountStorage {
+    F64(Array2<f64>),
+    F32(Array2<f32>),
+    U32(Array2<u32>),
+}
+
+impl CountStorage {
+    /// A zero matrix of the given shape and precision
+    pub fn zeros(precision: CountPrecision, shape: (usize, usize)) -> Self {
+        match precision {
+            CountPrecision::F64 => CountStorage::F64(Array2::zeros(shape)),
+            CountPrecision::F32 => CountStorage::F32(Array2::zeros(shape)),
+            CountPrecision::U32 => CountStorage::U32(Array2::zeros(shape)),
+        }
+    }
+
+    /// Precision of the stored counts
+    pub fn precision(&self) -> CountPrecision {
+        match self {
+            CountStorage::F64(_) => CountPrecision::F64,
+            CountStorage::F32(_) => CountPrecision::F32,
+            CountStorage::U32(_) => CountPrecision::U32,
+        }
+    }
+
+    /// Shape of the matrix (features, samples)
+    pub fn dim(&self) -> (usize, usize) {
+        match self {
+            CountStorage::F64(counts) => counts.dim(),
+            CountStorage::F32(counts) => counts.dim(),
+            CountStorage::U32(counts) => counts.dim(),
+        }
+    }
+
+    /// Count at `[feature, sample]`, widened to f64
+    pub fn get(&self, feature: usize, sample: usize) -> Option<f64> {
+        match self {
+            CountStorage::F64(counts) => counts.get([feature, sample]).copied(),
+            CountStorage::F32(counts) => counts.get([feature, sample]).map(|&c| c as f64),
+            CountStorage::U32(counts) => counts.get([feature, sample]).map(|&c| c as f64),
+        }
+    }
+
+    /// Store `count` at `cell`, returning false if this precision cannot hold it
+    fn set(&mut self, cell: [usize; 2], count: f64) -> bool {
+        match self {
+            CountStorage::F64(counts) => counts[cell] = count,
+            CountStorage::F32(counts) => counts[cell] = count as f32,
+            CountStorage::U32(counts) => {
+                if count.fract() != 0.0 || count > u32::MAX as f64 {
+                    return false;
+                }
+                counts[cell] = count as u32;
+            }
+        }
+        true
+    }
+
+    /// The counts as an f64 matrix, for analysis
+    pub fn to_f64(&self) -> Array2<f64> {
+        match self {
+            CountStorage::F64(counts) => counts.clone(),
+            CountStorage::F32(counts) => counts.mapv(|c| c as f64),
+            CountStorage::U32(counts) => counts.mapv(|c| c as f64),
+        }
+    }
+}
+
+/// A count table held at reduced precision.
+///
+/// Raw counts are whole numbers, so storing them as f32 or u32 halves the
+/// memory of a large study without changing any value below 2^24. Normalization
+/// and statistics work on `CountTable`; convert with `to_count_table` when the
+/// analysis starts and `CountTable::to_compact` to hold results again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompactCountTable {
+    /// The count matrix (features x samples)
+    pub counts: CountStorage,
+    pub feature_names: Vec<String>,
+    pub sample_names: Vec<String>,
+}
+
+impl CompactCountTable {
+    /// Builds a table from per-sample counts, like `CountTable::build_from_data`,
+    /// without ever holding the full matrix in f64.
+    pub fn build_from_data(
+        data: &HashMap<String, HashMap<String, f64>>,
+        precision: CountPrecision,
+    ) -> Result<Self> {
+        let index = DataIndex::new(data);
+        let mut counts = CountStorage::zeros(
+            precision,
+            (index.feature_names.len(), index.sample_names.len()),
+        );
+        index.fill(data, precision, |cell, count| counts.set(cell, count))?;
+
+        Ok(CompactCountTable {
+            counts,
+            feature_names: index.feature_names,
+            sample_names: index.sample_names,
+        })
+    }
+
+    /// Precision of the stored counts
+    pub fn precision(&self) -> CountPrecision {
+        self.counts.precision()
+    }
+
+    /// Returns the dimensions of the count table (features, samples).
+    pub fn dimensions(&self) -> (usize, usize) {
+        self.counts.dim()
+    }
+
+    /// Bytes taken by the count matrix
+    pub fn matrix_bytes(&self) -> usize {
+        let (features, samples) = self.dimensions();
+        features * samples * self.precision().bytes_per_count()
+    }
+
+    /// Widens the counts to a `CountTable` for normalization and statistics
+    pub fn to_count_table(&self) -> CountTable {
+        let index = |names: &[String]| -> HashMap<String, usize> {
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.clone(), i))
+                .collect()
+        };
+        CountTable {
+            counts: self.counts.to_f64(),
+            feature_map: index(&self.feature_names),
+            feature_names: self.feature_names.clone(),
+            sample_map: index(&self.sample_names),
+            sample_names: self.sample_names.clone(),
+        }
+    }
+}
+
+/// Sorted sample and feature names of per-sample count data
+struct DataIndex {
+    feature_names: Vec<String>,
+    feature_map: HashMap<String, usize>,
+    sample_names: Vec<String>,
+    sample_map: HashMap<String, usize>,
+}
+
+impl DataIndex {
+    fn new(data: &HashMap<String, HashMap<String, f64>>) -> Self {
+        let mut sample_names: Vec<String> = data.keys().cloned().collect();
+        sample_names.sort();
+
+        let feature_names: Vec<String> = data
+            .values()
+            .flat_map(|counts| counts.keys().cloned())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        let feature_map: HashMap<String, usize> = feature_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), i))
+            .collect();
+        let sample_map: HashMap<String, usize> = sample_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), i))
+            .collect();
+
+        DataIndex {
+            feature_names,
+            feature_map,
+            sample_names,
+            sample_map,
+        }
+    }
+
+    /// Validate every count and pass it to `store` with its `[feature, sample]`
+    /// cell; `store` returns false when `precision` cannot hold the count.
+    fn fill(
+        &self,
+        data: &HashMap<String, HashMap<String, f64>>,
+        precision: CountPrecision,
+        mut store: impl FnMut([usize; 2], f64) -> bool,
+    ) -> Result<()> {
+        for (sample, sample_counts) in data {
+            let col = self.sample_map[sample];
+            for (feature, &count) in sample_counts {
+                if !count.is_finite() || count < 0.0 {
+                    return Err(anyhow::anyhow!(
+                        "Invalid count {} for feature '{}' in sample '{}'",
+                        count,
+                        feature,
+                        sample
+                    ));
+                }
+                if !store([self.feature_map[feature], col], count) {
+                    return Err(unrepresentable(count, feature, sample, precision));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn unrepresentable(
+    count: f64,
+    feature: &str,
+    sample: &str,
+    precision: CountPrecision,
+) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Count {} for feature '{}' in sample '{}' cannot be stored as {:?}; \
+         use f32 or f64 precision for fractional or normalized values",
+        count,
+        feature,
+        sample,
+        precision
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,15 +497,103 @@ mod tests {
         assert!(table.sample_names.is_empty());
     }
 
-    // TODO: Add more comprehensive tests once build_from_data and other methods are implemented.
-    // Example structure for a test:
-    // #[test]
-    // fn test_build_simple_table() {
-    //     // 1. Create mock input data (e.g., HashMap)
-    //     // 2. Call CountTable::build_from_data
-    //     // 3. Assert dimensions are correct
-    //     // 4. Assert feature/sample names are correct
-    //     // 5. Assert specific count values are correct
-    //     // assert!(false, "Test not implemented");
-    // }
+    #[test]
+    fn test_build_simple_table() {
+        let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+        data.insert(
+            "S2".to_string(),
+            HashMap::from([("GCF_B".to_string(), 3.0)]),
+        );
+        data.insert(
+            "S1".to_string(),
+            HashMap::from([("GCF_A".to_string(), 10.0), ("GCF_B".to_string(), 1.0)]),
+        );
+
+        let table = CountTable::build_from_data(&data).unwrap();
+        assert_eq!(table.dimensions(), (2, 2));
+        assert_eq!(
+            table.sample_names(),
+            &vec!["S1".to_string(), "S2".to_string()]
+        );
+        assert_eq!(
+            table.feature_names(),
+            &vec!["GCF_A".to_string(), "GCF_B".to_string()]
+        );
+        assert_eq!(table.counts_matrix(), &arr2(&[[10.0, 0.0], [1.0, 3.0]]));
+
+        data.get_mut("S2")
+            .unwrap()
+            .insert("GCF_C".to_string(), -1.0);
+        assert!(CountTable::build_from_data(&data).is_err());
+    }
+
+    #[test]
+    fn test_compact_storage_round_trip() {
+        let data: HashMap<String, HashMap<String, f64>> = HashMap::from([
+            (
+                "S1".to_string(),
+                HashMap::from([
+                    ("GCF_A".to_string(), 16_777_216.0),
+                    ("GCF_B".to_string(), 7.0),
+                ]),
+            ),
+            (
+                "S2".to_string(),
+                HashMap::from([("GCF_B".to_string(), 3.0)]),
+            ),
+        ]);
+        let table = CountTable::build_from_data(&data).unwrap();
+
+        for precision in [CountPrecision::F32, CountPrecision::U32] {
+            let compact = CompactCountTable::build_from_data(&data, precision).unwrap();
+            assert_eq!(compact.precision(), precision);
+            assert_eq!(compact.matrix_bytes(), 2 * 2 * 4);
+            assert_eq!(compact, table.to_compact(precision).unwrap());
+
+            let widened = compact.to_count_table();
+            assert_eq!(widened.counts_matrix(), table.counts_matrix());
+            assert_eq!(widened.feature_map, table.feature_map);
+            assert_eq!(widened.sample_map, table.sample_map);
+        }
+
+        // Normalized values need a floating-point precision
+        let mut normalized = table;
+        normalized.counts_matrix_mut()[[1, 1]] = 0.5;
+        assert!(normalized.to_compact(CountPrecision::U32).is_err());
+        let compact = normalized.to_compact(CountPrecision::F32).unwrap();
+        assert_eq!(compact.counts.get(1, 1), Some(0.5));
+    }
+
+    #[test]
+    fn test_duplicate_sample_policies() {
+        let ids: Vec<String> = ["S1", "S2", "S1", "S1_2", "S1"]
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+
+        let error = DuplicatePolicy::Error
+            .sample_ids(&ids, "table.csv")
+            .unwrap_err();
+        assert!(error.to_string().contains("more than once: S1"));
+        assert_eq!(
+            DuplicatePolicy::Suffix
+                .sample_ids(&ids, "table.csv")
+                .unwrap(),
+            vec!["S1", "S2", "S1_3", "S1_2", "S1_4"]
+        );
+        assert_eq!(
+            DuplicatePolicy::Merge
+                .sample_ids(&ids, "table.csv")
+                .unwrap(),
+            ids
+        );
+
+        let unique = vec!["S1".to_string(), "S2".to_string()];
+        assert_eq!(
+            DuplicatePolicy::Error
+                .sample_ids(&unique, "table.csv")
+                .unwrap(),
+            unique
+        );
+    }
 }
@@ -0,0 +1,440 @@
+//! Prebuilt reference panels, packed as bundles.
+//!
+//! Building a database means searching NCBI, downloading hundreds or thousands
+//! of genomes and sketching each one: hours of work that ends in the same
+//! signatures for every lab using the same panel. A bundle carries a finished
+//! panel under a versioned name such as `standard-bacteria-v1`: its
+//! signatures with their lineages, zstd-compressed, and the digest of their
+//! contents.
+//!
+//! `db bundle` packs a database into a bundle and writes its SHA-256 next to
+//! it in `sha256sum` format (`<file>.sha256`), so both can be published side
+//! by side. `db install` fetches a bundle from a URL or path, checks the file
+//! against that checksum (or one given on the command line) and the
+//! signatures against the recorded digest, and only then loads them into the
+//! configured database, whichever storage backend it uses.
+//!
+//! A checksum fetched from the same server as the bundle only shows that the
+//! download is intact, not who made it. Publishers can therefore sign bundles
+//! with an Ed25519 key (`db keygen`, `db sign`): the detached signature, over
+//! the bundle's SHA-256, is published as `<file>.sig`. When the config lists
+//! trusted publisher keys, `db install` refuses bundles without a valid
+//! signature from one of them.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::{info, warn};
+use rand::Rng;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::database::downloader::DatabaseError;
+use crate::io::format::{read_versioned_json, Artifact, ArtifactError};
+use crate::io::json::{write_json, JsonCompression};
+use crate::provenance::database_digest;
+use crate::sketch::MultiResolutionSignature;
+use crate::utils::paths::{long_path, safe_file_name};
+
+/// Version of the bundle format written by this build
+pub const BUNDLE_FORMAT_VERSION: u32 = 1;
+
+/// Extension of bundle files
+pub const BUNDLE_EXTENSION: &str = "ahspdb";
+
+/// Suffix of the checksum file published next to a bundle
+pub const CHECKSUM_SUFFIX: &str = ".sha256";
+
+/// Suffix of the detached signature published next to a bundle
+pub const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// A reference panel ready to be installed into an empty database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceBundle {
+    /// Versioned panel name, e.g. `standard-bacteria-v1`
+    pub name: String,
+    pub format_version: u32,
+    /// Digest of the signatures, as recorded in the provenance of results
+    pub database_sha256: String,
+    pub signatures: Vec<MultiResolutionSignature>,
+}
+
+impl ReferenceBundle {
+    /// Bundle `signatures` under `name`
+    pub fn new(
+        name: &str,
+        signatures: Vec<MultiResolutionSignature>,
+    ) -> Result<Self, DatabaseError> {
+        check_name(name)?;
+        Ok(ReferenceBundle {
+            name: name.to_string(),
+            format_version: BUNDLE_FORMAT_VERSION,
+            database_sha256: database_digest(&signatures),
+            signatures,
+        })
+    }
+
+    /// Write the bundle to `path` and its checksum to `<path>.sha256`,
+    /// returning the checksum
+    pub fn write(&self, path: &Path) -> Result<String, DatabaseError> {
+        write_json(path, self, JsonCompression::Zstd)?;
+        let checksum = file_sha256(path)?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        fs::write(
+            checksum_path(path),
+            format!("{}  {}\n", checksum, file_name),
+        )?;
+        Ok(checksum)
+    }
+
+    /// Read a bundle, checking its format version and that its signatures
+    /// match the digest it records
+    pub fn read(path: &Path) -> Result<Self, DatabaseError> {
+        let bundle: ReferenceBundle =
+            read_versioned_json(Artifact::Bundle, path).map_err(|e| match e {
+                ArtifactError::UnsupportedVersion(e) => DatabaseError::UnsupportedVersion(e),
+                e => {
+                    DatabaseError::BundleError(format!("{} is not a bundle: {}", path.display(), e))
+                }
+            })?;
+        let digest = database_digest(&bundle.signatures);
+        if digest != bundle.database_sha256 {
+            return Err(DatabaseError::BundleError(format!(
+                "signatures of {} do not match its recorded digest ({} != {})",
+                path.display(),
+                digest,
+                bundle.database_sha256
+            )));
+        }
+        Ok(bundle)
+    }
+}
+
+/// Where the checksum of the bundle at `path` is written
+pub fn checksum_path(path: &Path) -> PathBuf {
+    with_suffix(path, CHECKSUM_SUFFIX)
+}
+
+/// Where the signature of the bundle at `path` is written
+pub fn signature_path(path: &Path) -> PathBuf {
+    with_suffix(path, SIGNATURE_SUFFIX)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut with_suffix = path.as_os_str().to_owned();
+    with_suffix.push(suffix);
+    PathBuf::from(with_suffix)
+}
+
+/// Create a publisher key pair: the secret key, hex-encoded, in `secret_path`
+/// (readable by the owner only) and the public key in `<secret_path>.pub`.
+/// Returns the public key.
+pub fn generate_key_pair(secret_path: &Path) -> Result<String, DatabaseError> {
+    // Created exclusively and owner-only from the start, so the key is never
+    // readable by others and an existing key is never replaced
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(secret_path).map_err(|e| {
+        if e.kind() == io::ErrorKind::AlreadyExists {
+            DatabaseError::BundleError(format!(
+                "{} already exists; refusing to overwrite a signing key",
+                secret_path.display()
+            ))
+        } else {
+            e.into()
+        }
+    })?;
+    let mut seed = [0u8; 32];
+    rand::rng().fill(&mut seed);
+    let key = SigningKey::from_bytes(&seed);
+    writeln!(file, "{}", hex::encode(key.to_bytes()))?;
+    file.sync_all()?;
+    let public_key = hex::encode(key.verifying_key().to_bytes());
+    fs::write(
+        with_suffix(secret_path, ".pub"),
+        format!("{}\n", public_key),
+    )?;
+    Ok(public_key)
+}
+
+/// Sign the bundle at `path` with the secret key in `secret_path`, writing
+/// the signature to `<path>.sig`
+pub fn sign_bundle(path: &Path, secret_path: &Path) -> Result<PathBuf, DatabaseError> {
+    let seed: [u8; 32] = decode_hex(&fs::read_to_string(secret_path)?, "secret key")?;
+    let key = SigningKey::from_bytes(&seed);
+    let digest = hex::decode(file_sha256(path)?).expect("SHA-256 is hex");
+    let signature = key.sign(&digest);
+    let signature_path = signature_path(path);
+    fs::write(
+        &signature_path,
+        format!("{}\n", hex::encode(signature.to_bytes())),
+    )?;
+    Ok(signature_path)
+}
+
+/// Check `signature` (hex) of a bundle with SHA-256 `checksum` against the
+/// trusted public keys, returning the key that made it
+pub fn verify_signature<'a>(
+    checksum: &str,
+    signature: &str,
+    trusted_keys: &'a [String],
+) -> Result<&'a str, DatabaseError> {
+    let signature = Signature::from_bytes(&decode_hex(signature, "signature")?);
+    let digest = hex::decode(checksum)
+        .map_err(|e| DatabaseError::BundleError(format!("invalid checksum: {}", e)))?;
+    for key in trusted_keys {
+        let key_bytes: [u8; 32] = decode_hex(key, "public key")?;
+        let public_key = VerifyingKey::from_bytes(&key_bytes).map_err(|e| {
+            DatabaseError::BundleError(format!("invalid public key {}: {}", key, e))
+        })?;
+        if public_key.verify(&digest, &signature).is_ok() {
+            return Ok(key.as_str());
+        }
+    }
+    Err(DatabaseError::BundleError(
+        "the bundle is not signed by any trusted key".to_string(),
+    ))
+}
+
+/// Decode `N` bytes from hex text, ignoring surrounding whitespace
+fn decode_hex<const N: usize>(text: &str, what: &str) -> Result<[u8; N], DatabaseError> {
+    let bytes = hex::decode(text.trim())
+        .map_err(|e| DatabaseError::BundleError(format!("invalid {}: {}", what, e)))?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        DatabaseError::BundleError(format!(
+            "invalid {}: {} bytes, expected {}",
+            what,
+            bytes.len(),
+            N
+        ))
+    })
+}
+
+/// SHA-256 of a file, hex-encoded
+pub fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => break,
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Download the bundle `name` from `source` (an `http(s)://` or `file://`
+/// URL, or a path) into `cache_dir`, verify it, and read it.
+///
+/// The file must have the SHA-256 `expected_sha256`, or when none is given,
+/// the one published at `<source>.sha256`. With `trusted_keys`, it must also
+/// carry a signature (`<source>.sig`) from one of them, and be named `name`.
+/// The download goes to a temporary file next to the cached bundle and only
+/// replaces it once every check has passed, so a corrupt or tampered download
+/// never touches a bundle already in the cache.
+pub fn fetch_bundle(
+    name: &str,
+    source: &str,
+    expected_sha256: Option<&str>,
+    trusted_keys: &[String],
+    cache_dir: &Path,
+) -> Result<(PathBuf, ReferenceBundle), DatabaseError> {
+    check_name(name)?;
+    let expected = match expected_sha256 {
+        Some(checksum) => checksum.trim().to_lowercase(),
+        None => {
+            let checksum_source = format!("{}{}", source, CHECKSUM_SUFFIX);
+            let text = fetch_text(&checksum_source).map_err(|e| {
+                DatabaseError::BundleError(format!(
+                    "cannot read the checksum at {} ({}); pass it with --sha256",
+                    checksum_source, e
+                ))
+            })?;
+            text.split_whitespace()
+                .next()
+                .map(str::to_lowercase)
+                .ok_or_else(|| {
+                    DatabaseError::BundleError(format!("{} is empty", checksum_source))
+                })?
+        }
+    };
+
+    let dir = long_path(&cache_dir.join("bundles"));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.{}", safe_file_name(name), BUNDLE_EXTENSION));
+    info!("Fetching bundle {} from {}", name, source);
+    // Removed when dropped, on every failed check below
+    let mut download = tempfile::Builder::new()
+        .prefix(".download-")
+        .suffix(".part")
+        .tempfile_in(&dir)?;
+    fetch_file(source, download.as_file_mut())?;
+
+    let checksum = file_sha256(download.path())?;
+    if checksum != expected {
+        return Err(DatabaseError::BundleError(format!(
+            "checksum of {} is {}, expected {}; the download is corrupt or was tampered with",
+            source, checksum, expected
+        )));
+    }
+    if trusted_keys.is_empty() {
+        warn!(
+            "No trusted bundle keys configured; {} is checked for corruption, not for who published it",
+            name
+        );
+    } else {
+        let signature_source = format!("{}{}", source, SIGNATURE_SUFFIX);
+        let signature = fetch_text(&signature_source).map_err(|e| {
+            DatabaseError::BundleError(format!(
+                "cannot read the signature at {}: {}",
+                signature_source, e
+            ))
+        })?;
+        let key = verify_signature(&checksum, &signature, trusted_keys)?;
+        info!("{} is signed by trusted key {}", name, key);
+    }
+    let bundle = ReferenceBundle::read(download.path())?;
+    if bundle.name != name {
+        return Err(DatabaseError::BundleError(format!(
+            "{} holds bundle {}, not {}",
+            source, bundle.name, name
+        )));
+    }
+    download.persist(&path).map_err(|e| e.error)?;
+    Ok((path, bundle))
+}
+
+/// Bundle names become file names, so they are limited to letters, digits,
+/// `.`, `_` and `-`
+fn check_name(name: &str) -> Result<(), DatabaseError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err(DatabaseError::BundleError(format!(
+            "invalid bundle name '{}': use letters, digits, '.', '_' and '-'",
+            name
+        )))
+    }
+}
+
+/// Local path of `source`, if it is not an HTTP(S) URL
+fn local_path(source: &str) -> Option<&Path> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        None
+    } else {
+        Some(Path::new(source.strip_prefix("file://").unwrap_or(source)))
+    }
+}
+
+fn fetch_file(source: &str, dest: &mut File) -> Result<(), DatabaseError> {
+    if let Some(path) = local_path(source) {
+        io::copy(&mut File::open(path)?, dest)?;
+    } else {
+        let mut response = Client::new().get(source).send()?.error_for_status()?;
+        response.copy_to(dest)?;
+    }
+    dest.sync_all()?;
+    Ok(())
+}
+
+fn fetch_text(source: &str) -> Result<String, DatabaseError> {
+    match local_path(source) {
+        Some(path) => Ok(fs::read_to_string(path)?),
+        None => Ok(Client::new()
+            .get(source)
+            .send()?
+            .error_for_status()?
+            .text()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_bundles_install_only_when_verified() {
+        let signatures: Vec<MultiResolutionSignature> = ["a", "b"]
+            .iter()
+            .zip(0..)
+            .map(|(id, i)| signature(id, &["Bacteria"], [level(21, 4, [i, 10 + i])]))
+            .collect();
+        let bundle = ReferenceBundle::new("panel-v1", signatures.clone()).unwrap();
+        assert_eq!(bundle.database_sha256, database_digest(&signatures));
+        assert!(ReferenceBundle::new("../panel", signatures).is_err());
+
+        let published = tempfile::tempdir().unwrap();
+        let path = published.path().join("panel-v1.ahspdb");
+        let checksum = bundle.write(&path).unwrap();
+        assert!(fs::read_to_string(checksum_path(&path))
+            .unwrap()
+            .starts_with(&checksum));
+
+        let cache = tempfile::tempdir().unwrap();
+        let source = format!("file://{}", path.display());
+        let (installed, fetched) =
+            fetch_bundle("panel-v1", &source, None, &[], cache.path()).unwrap();
+        assert_eq!(installed, cache.path().join("bundles/panel-v1.ahspdb"));
+        assert_eq!(fetched.signatures.len(), 2);
+        assert_eq!(fetched.database_sha256, bundle.database_sha256);
+
+        // Wrong checksum or name: refused, leaving the cached bundle as it was
+        // and no partial download behind
+        let wrong = "0".repeat(64);
+        let source = path.display().to_string();
+        assert!(fetch_bundle("panel-v1", &source, Some(&wrong), &[], cache.path()).is_err());
+        assert!(fetch_bundle("panel-v2", &source, Some(&checksum), &[], cache.path()).is_err());
+        assert_eq!(file_sha256(&installed).unwrap(), checksum);
+        let cached: Vec<_> = fs::read_dir(cache.path().join("bundles"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(cached, vec!["panel-v1.ahspdb"]);
+
+        // With trusted keys, only a bundle signed by one of them installs
+        let keys = tempfile::tempdir().unwrap();
+        let publisher = keys.path().join("publisher.key");
+        let public_key = generate_key_pair(&publisher).unwrap();
+        let secret = fs::read_to_string(&publisher).unwrap();
+        assert!(generate_key_pair(&publisher).is_err());
+        assert_eq!(fs::read_to_string(&publisher).unwrap(), secret);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&publisher).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let other_key = generate_key_pair(&keys.path().join("other.key")).unwrap();
+        let trusted = vec![public_key.clone()];
+        assert!(fetch_bundle("panel-v1", &source, None, &trusted, cache.path()).is_err());
+        sign_bundle(&path, &publisher).unwrap();
+        fetch_bundle("panel-v1", &source, None, &trusted, cache.path()).unwrap();
+        assert!(fetch_bundle("panel-v1", &source, None, &[other_key], cache.path()).is_err());
+        let signature = fs::read_to_string(signature_path(&path)).unwrap();
+        assert!(verify_signature(&wrong, &signature, &trusted).is_err());
+
+        // Signatures edited after the digest was recorded
+        let mut tampered = bundle.clone();
+        tampered.signatures[0].levels[0].sketch.hashes.push(99);
+        tampered.write(&path).unwrap();
+        assert!(ReferenceBundle::read(&path).is_err());
+    }
+}
@@ -1,20 +1,34 @@
 use std::collections::{HashMap, HashSet}; // Added HashSet
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, Write}; // Added BufReader
+use std::io::{self, BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 
-use crate::sketch::signature::MultiResolutionSignature; // Add MultiResolutionSignature from qc
+use crate::bio::names::{is_taxid, suggestions, TaxonLookupError};
+use crate::bio::taxdump::Taxonomy;
+use crate::bio::taxonomy::TaxonomicLevel;
+use crate::config::DatabaseConfig;
+use crate::database::mag::MagMetadata;
+use crate::database::storage::{open_store, SignatureStore, SIGNATURE_TABLE};
+use crate::io::format::{Artifact, UnsupportedVersion};
+use crate::sketch::cache::SimilarityCache;
+use crate::sketch::masking::CommonHashes;
+use crate::sketch::signature::{
+    LegacyMultiResolutionSignature, MultiResolutionSignature, SignatureLayout,
+};
 use crate::sketch::SignatureBuilder;
+use crate::utils::paths::{long_path, safe_file_name};
+use crate::utils::workspace::Workspace;
 use bincode::config::standard;
 use bincode::{decode_from_slice, encode_to_vec};
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use quick_xml::events::{BytesStart, Event}; // Added BytesStart, Event
 use quick_xml::Reader; // Added Reader
 use rayon::prelude::*;
 use reqwest::{blocking::Client, header};
 use serde::{Deserialize, Serialize};
-use sled::Db;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -51,6 +65,21 @@ pub enum DatabaseError {
 
     #[error("Invalid signature: {0}")]
     InvalidSignature(String), // Added InvalidSignature error variant
+
+    #[error("Invalid search pattern: {0}")]
+    InvalidPattern(String),
+
+    #[error("Storage backend error: {0}")]
+    StorageError(String),
+
+    #[error("Parse error: {0}")]
+    ParseError(String),
+
+    #[error("Bundle error: {0}")]
+    BundleError(String),
+
+    #[error("{0}")]
+    UnsupportedVersion(#[from] UnsupportedVersion),
 }
 
 // Add conversion from bincode errors
@@ -66,6 +95,76 @@ impl From<bincode::error::DecodeError> for DatabaseError {
     }
 }
 
+/// Base URL of the NCBI E-utilities API
+pub const NCBI_EUTILS_URL: &str = "https://eutils.ncbi.nlm.nih.gov/entrez/eutils";
+
+/// Table holding signature addition timestamps
+const ADDED_AT_TREE: &str = "added_at";
+
+/// Table mapping signature content hashes to signature IDs
+const CONTENT_HASH_TREE: &str = "content_hashes";
+
+/// Table mapping user MAG signature IDs to their JSON-encoded `MagMetadata`
+const MAG_METADATA_TABLE: &str = "mag_metadata";
+
+/// Table mapping `measure \0 content hash \0 content hash` to a cached similarity
+const SIMILARITY_CACHE_TABLE: &str = "similarity_cache";
+
+/// Table holding the bincode-encoded `CommonHashes` under `COMMON_HASHES_KEY`
+const REFERENCE_MASKING_TABLE: &str = "reference_masking";
+
+const COMMON_HASHES_KEY: &[u8] = b"common_hashes";
+
+/// Table of `signature ID \0 signature ID` keys (taxonomy index)
+const TAXONOMY_INDEX_TABLE: &str = "taxonomy_index";
+
+/// Table of `lineage term \0 signature ID` keys (lineage index)
+const LINEAGE_INDEX_TABLE: &str = "lineage_index";
+
+/// Key of an index entry: the term and signature ID separated by a NUL byte
+fn index_key(term: &str, id: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(term.len() + id.len() + 1);
+    key.extend_from_slice(term.as_bytes());
+    key.push(0);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+/// Result of inserting a signature into the database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The signature was stored
+    Added,
+    /// An identical signature is already stored under the given ID
+    Duplicate(String),
+}
+
+/// Number of signatures written per storage batch by `add_signatures_bulk`
+const BULK_BATCH_SIZE: usize = 1000;
+
+/// Outcome of `SignatureDatabase::add_signatures_bulk`
+#[derive(Debug, Clone, Default)]
+pub struct BulkInsertReport {
+    /// IDs of the signatures that were stored
+    pub added: Vec<String>,
+
+    /// Skipped duplicates as (signature ID, existing ID)
+    pub duplicates: Vec<(String, String)>,
+
+    /// Rejected signatures as (signature ID, reason)
+    pub invalid: Vec<(String, String)>,
+}
+
+/// Duplicate signatures found (and optionally removed) by `SignatureDatabase::dedupe`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DedupeReport {
+    /// Groups of identical signatures as (kept ID, duplicate IDs)
+    pub groups: Vec<(String, Vec<String>)>,
+
+    /// Total size of the duplicate signature records in bytes
+    pub bytes_reclaimed: u64,
+}
+
 /// NCBI genome metadata
 use bincode::{Decode, Encode};
 #[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)] // <-- Add Encode, Decode
@@ -96,6 +195,410 @@ pub struct GenomeMetadata {
 
     /// Full taxonomic lineage as (taxid, name) pairs, root first
     pub lineage: Vec<(String, String)>, // (taxid, name) pairs
+
+    /// Contig N50 in base pairs (0 if not reported)
+    pub contig_n50: usize,
+
+    /// Number of contigs in the assembly (0 if not reported)
+    pub contig_count: usize,
+
+    /// NCBI quality flags (anomalous, suppressed, excluded from RefSeq)
+    pub quality_flags: Vec<String>,
+
+    /// Species-level taxonomy ID (the assembly taxid may be a strain)
+    pub species_taxid: String,
+
+    /// RefSeq category ("reference genome", "representative genome" or "na")
+    pub refseq_category: String,
+
+    /// Whether the assembly was built from type material
+    pub from_type: bool,
+}
+
+impl GenomeMetadata {
+    /// Assembly level, if NCBI reported one of the four levels
+    pub fn level(&self) -> Option<AssemblyLevel> {
+        self.assembly_level.parse().ok()
+    }
+
+    /// Preference rank for picking one canonical genome per species (lower is better)
+    pub fn canonical_rank(&self) -> u8 {
+        let category_rank = match self.refseq_category.to_lowercase().as_str() {
+            "reference genome" => 0,
+            "representative genome" => 1,
+            _ => 2,
+        };
+        let type_rank = if self.from_type { 0 } else { 1 };
+        category_rank * 2 + type_rank
+    }
+}
+
+/// Keep one canonical genome per species, preferring RefSeq reference/representative
+/// genomes, then type strains, then complete assemblies and the most recent release.
+pub fn select_canonical_genomes(genomes: Vec<GenomeMetadata>) -> Vec<GenomeMetadata> {
+    let mut best: HashMap<String, GenomeMetadata> = HashMap::new();
+    let mut order: Vec<String> = Vec::new();
+
+    for genome in genomes {
+        let species = if genome.species_taxid.is_empty() {
+            genome.taxid.clone()
+        } else {
+            genome.species_taxid.clone()
+        };
+
+        match best.get(&species) {
+            Some(current) => {
+                let key = |g: &GenomeMetadata| {
+                    (
+                        g.canonical_rank(),
+                        g.level() != Some(AssemblyLevel::Complete),
+                        std::cmp::Reverse(g.release_date.clone()),
+                    )
+                };
+                if key(&genome) < key(current) {
+                    debug!(
+                        "Preferring {} over {} for species {}",
+                        genome.accession, current.accession, species
+                    );
+                    best.insert(species, genome);
+                }
+            }
+            None => {
+                order.push(species.clone());
+                best.insert(species, genome);
+            }
+        }
+    }
+
+    order
+        .into_iter()
+        .filter_map(|species| best.remove(&species))
+        .collect()
+}
+
+/// Taxon inclusion/exclusion lists used to build targeted reference panels
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaxonFilter {
+    /// If set, only genomes with one of these taxids in their lineage are kept
+    pub include: Option<HashSet<String>>,
+
+    /// Genomes with any of these taxids in their lineage are dropped
+    pub exclude: HashSet<String>,
+}
+
+impl TaxonFilter {
+    /// Load include/exclude lists from files with one taxid or taxon name
+    /// per line; names are resolved through `taxonomy`. An include list with
+    /// no taxa (only comments or blank lines) does not filter anything.
+    pub fn from_files(
+        include: Option<&Path>,
+        exclude: Option<&Path>,
+        taxonomy: Option<&Taxonomy>,
+    ) -> Result<Self, DatabaseError> {
+        let include = match include {
+            Some(path) => {
+                let taxids = Self::read_taxid_file(path, taxonomy)?;
+                if taxids.is_empty() {
+                    warn!(
+                        "Include list {} lists no taxa; not restricting genomes",
+                        path.display()
+                    );
+                    None
+                } else {
+                    Some(taxids)
+                }
+            }
+            None => None,
+        };
+        Ok(TaxonFilter {
+            include,
+            exclude: match exclude {
+                Some(path) => Self::read_taxid_file(path, taxonomy)?,
+                None => HashSet::new(),
+            },
+        })
+    }
+
+    /// Read a taxon list; blank lines and `#` comments are ignored. A line
+    /// starting with a taxid uses only that first column, so annotated lists
+    /// work too; any other line names a taxon (up to a tab), resolved to its
+    /// taxid. Every line that cannot be resolved is reported at once.
+    fn read_taxid_file(
+        path: &Path,
+        taxonomy: Option<&Taxonomy>,
+    ) -> Result<HashSet<String>, DatabaseError> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut taxids = HashSet::new();
+        let mut problems = Vec::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            let Some(first) = line.split_whitespace().next() else {
+                continue;
+            };
+            if is_taxid(first) {
+                taxids.insert(first.to_string());
+                continue;
+            }
+            let name = line.split('\t').next().unwrap_or(line).trim();
+            let resolved = match taxonomy {
+                Some(taxonomy) => taxonomy.resolve(name),
+                None => Err(TaxonLookupError::NoTaxonomy(name.to_string())),
+            };
+            match resolved {
+                Ok(taxid) => {
+                    debug!(
+                        "Resolved '{}' in {} to taxid {}",
+                        name,
+                        path.display(),
+                        taxid
+                    );
+                    taxids.insert(taxid);
+                }
+                Err(e) => problems.push(e.to_string()),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(DatabaseError::TaxonomyError(format!(
+                "Cannot resolve {} taxa in {}:\n  - {}",
+                problems.len(),
+                path.display(),
+                problems.join("\n  - ")
+            )));
+        }
+
+        info!("Loaded {} taxids from {}", taxids.len(), path.display());
+        Ok(taxids)
+    }
+
+    /// Whether the filter has any effect
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
+
+    /// Restrict an Entrez query to the included taxa
+    pub fn restrict_query(&self, query: &str) -> String {
+        match &self.include {
+            Some(include) if !include.is_empty() => {
+                let mut taxids: Vec<&String> = include.iter().collect();
+                taxids.sort();
+                let clause = taxids
+                    .iter()
+                    .map(|taxid| format!("txid{}[Organism:exp]", taxid))
+                    .collect::<Vec<_>>()
+                    .join(" OR ");
+                format!("({}) AND ({})", query, clause)
+            }
+            _ => query.to_string(),
+        }
+    }
+
+    /// Check a genome's taxid and lineage against the lists. Entries without
+    /// a lineage (e.g. built from local files) are matched only on their own
+    /// `taxid` and `species_taxid`, so an included genus does not cover them.
+    pub fn allows(&self, metadata: &GenomeMetadata) -> bool {
+        let taxids = metadata
+            .lineage
+            .iter()
+            .map(|(taxid, _)| taxid.as_str())
+            .chain([metadata.taxid.as_str(), metadata.species_taxid.as_str()]);
+
+        let mut included = self.include.is_none();
+        for taxid in taxids {
+            if self.exclude.contains(taxid) {
+                return false;
+            }
+            if let Some(include) = &self.include {
+                included |= include.contains(taxid);
+            }
+        }
+        included
+    }
+}
+
+/// NCBI assembly level, from most to least contiguous
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AssemblyLevel {
+    #[serde(rename = "Complete Genome")]
+    Complete,
+    Chromosome,
+    Scaffold,
+    Contig,
+}
+
+impl AssemblyLevel {
+    /// Name NCBI reports in assembly summaries
+    pub fn ncbi_name(self) -> &'static str {
+        match self {
+            AssemblyLevel::Complete => "Complete Genome",
+            AssemblyLevel::Chromosome => "Chromosome",
+            AssemblyLevel::Scaffold => "Scaffold",
+            AssemblyLevel::Contig => "Contig",
+        }
+    }
+
+    /// Entrez filter selecting assemblies of this level
+    fn search_filter(self) -> &'static str {
+        match self {
+            AssemblyLevel::Complete => "complete genome",
+            AssemblyLevel::Chromosome => "chromosome level",
+            AssemblyLevel::Scaffold => "scaffold level",
+            AssemblyLevel::Contig => "contig level",
+        }
+    }
+}
+
+impl FromStr for AssemblyLevel {
+    type Err = String;
+
+    /// NCBI names in any case, and `complete` for `Complete Genome`
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level.trim().to_lowercase().as_str() {
+            "complete genome" | "complete" => Ok(AssemblyLevel::Complete),
+            "chromosome" => Ok(AssemblyLevel::Chromosome),
+            "scaffold" => Ok(AssemblyLevel::Scaffold),
+            "contig" => Ok(AssemblyLevel::Contig),
+            _ => Err(format!(
+                "unknown assembly level '{}' (expected Complete Genome, Chromosome, Scaffold or Contig)",
+                level
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AssemblyLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.ncbi_name())
+    }
+}
+
+/// Quality filters applied to NCBI assemblies before they are downloaded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssemblyFilter {
+    /// Accepted assembly levels
+    pub assembly_levels: Vec<AssemblyLevel>,
+
+    /// Minimum contig N50 in base pairs
+    pub min_contig_n50: Option<usize>,
+
+    /// Maximum number of contigs
+    pub max_contig_count: Option<usize>,
+
+    /// Skip assemblies NCBI flags as anomalous, suppressed or excluded from RefSeq
+    pub exclude_anomalous: bool,
+
+    /// Keep only one canonical genome per species (reference/representative, type strain)
+    pub prefer_representative: bool,
+}
+
+impl Default for AssemblyFilter {
+    fn default() -> Self {
+        AssemblyFilter {
+            assembly_levels: vec![AssemblyLevel::Complete],
+            min_contig_n50: None,
+            max_contig_count: None,
+            exclude_anomalous: true,
+            prefer_representative: false,
+        }
+    }
+}
+
+impl AssemblyFilter {
+    /// Build the Entrez filter clause restricting the search to the accepted levels
+    fn search_term(&self) -> String {
+        let filters: Vec<String> = self
+            .assembly_levels
+            .iter()
+            .map(|level| format!("\"{}\"[filter]", level.search_filter()))
+            .collect();
+
+        if filters.is_empty() {
+            String::new()
+        } else {
+            format!("+AND+({})", filters.join("+OR+"))
+        }
+    }
+
+    /// Check an assembly against the filter, returning the rejection reason if it fails
+    pub fn check(&self, metadata: &GenomeMetadata) -> Result<(), String> {
+        if !self.assembly_levels.is_empty()
+            && !metadata
+                .level()
+                .map_or(false, |level| self.assembly_levels.contains(&level))
+        {
+            return Err(format!(
+                "assembly level '{}' not accepted",
+                metadata.assembly_level
+            ));
+        }
+
+        if let Some(min_n50) = self.min_contig_n50 {
+            // An unreported N50 cannot demonstrate the assembly is good enough
+            if metadata.contig_n50 < min_n50 {
+                return Err(format!(
+                    "contig N50 {} below minimum {}",
+                    metadata.contig_n50, min_n50
+                ));
+            }
+        }
+
+        if let Some(max_contigs) = self.max_contig_count {
+            if metadata.contig_count > max_contigs {
+                return Err(format!(
+                    "{} contigs exceeds maximum {}",
+                    metadata.contig_count, max_contigs
+                ));
+            }
+        }
+
+        if self.exclude_anomalous && !metadata.quality_flags.is_empty() {
+            return Err(format!(
+                "flagged by NCBI: {}",
+                metadata.quality_flags.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Extract a numeric statistic from the XML-ish `meta` field of an assembly summary,
+/// e.g. `<Stat category="contig_count" sequence_tag="all">123</Stat>`
+fn parse_meta_stat(meta: &str, category: &str) -> Option<usize> {
+    let marker = format!("category=\"{}\" sequence_tag=\"all\">", category);
+    let start = meta.find(&marker)? + marker.len();
+    let end = meta[start..].find('<')? + start;
+    meta[start..end].trim().parse().ok()
+}
+
+/// Collect the NCBI flags that mark an assembly as anomalous or suppressed
+fn parse_quality_flags(result: &serde_json::Value) -> Vec<String> {
+    let mut flags = Vec::new();
+
+    if let Some(anomalies) = result["anomalouslist"].as_array() {
+        for anomaly in anomalies {
+            if let Some(property) = anomaly["property"].as_str().or(anomaly.as_str()) {
+                flags.push(format!("anomalous: {}", property));
+            }
+        }
+    }
+
+    if let Some(exclusions) = result["exclfromrefseq"].as_array() {
+        for reason in exclusions.iter().filter_map(|r| r.as_str()) {
+            flags.push(format!("excluded from RefSeq: {}", reason));
+        }
+    }
+
+    if let Some(properties) = result["propertylist"].as_array() {
+        for property in properties.iter().filter_map(|p| p.as_str()) {
+            if property.contains("suppressed") {
+                flags.push(property.to_string());
+            }
+        }
+    }
+
+    flags
 }
 
 /// NCBI genome downloader
@@ -114,6 +617,9 @@ pub struct NCBIDownloader {
 
     /// Cache expiration time in days
     cache_expiry_days: u64,
+
+    /// Where downloads are written until complete (the cache directory if unset)
+    scratch_dir: Option<PathBuf>,
 }
 
 impl NCBIDownloader {
@@ -123,7 +629,7 @@ impl NCBIDownloader {
         api_key: Option<String>,
         cache_expiry_days: Option<u64>,
     ) -> Result<Self, DatabaseError> {
-        let cache_path = cache_dir.as_ref().to_path_buf();
+        let cache_path = long_path(cache_dir.as_ref());
         fs::create_dir_all(&cache_path)?;
 
         let mut headers = header::HeaderMap::new();
@@ -140,24 +646,41 @@ impl NCBIDownloader {
 
         Ok(NCBIDownloader {
             client,
-            base_url: "https://eutils.ncbi.nlm.nih.gov/entrez/eutils".to_string(),
+            base_url: NCBI_EUTILS_URL.to_string(),
             api_key,
             cache_dir: cache_path,
             cache_expiry_days: cache_expiry_days.unwrap_or(30),
+            scratch_dir: None,
         })
     }
 
-    /// Search for genomes matching a query
+    /// Write downloads to `dir` and only move complete files into the cache
+    pub fn set_scratch_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.scratch_dir = Some(dir.into());
+    }
+
+    /// Search for genomes matching a query using the default assembly filter
     pub fn search_genomes(
         &self,
         query: &str,
         max_results: usize,
+    ) -> Result<Vec<GenomeMetadata>, DatabaseError> {
+        self.search_genomes_filtered(query, max_results, &AssemblyFilter::default())
+    }
+
+    /// Search for genomes matching a query, skipping assemblies rejected by `filter`
+    pub fn search_genomes_filtered(
+        &self,
+        query: &str,
+        max_results: usize,
+        filter: &AssemblyFilter,
     ) -> Result<Vec<GenomeMetadata>, DatabaseError> {
         // Search for assembly IDs
         let search_url = format!(
-            "{}/esearch.fcgi?db=assembly&term={}+AND+\"latest refseq\"[filter]+AND+\"complete genome\"[filter]&retmax={}&retmode=json{}",
+            "{}/esearch.fcgi?db=assembly&term={}+AND+\"latest refseq\"[filter]{}&retmax={}&retmode=json{}",
             self.base_url,
             urlencoding::encode(query), // Ensure query is URL-encoded
+            filter.search_term(),
             max_results,
             self.api_key.as_ref().map_or(String::new(), |k| format!("&api_key={}", k))
         );
@@ -275,6 +798,27 @@ impl NCBIDownloader {
                             .parse::<f64>()
                             .unwrap_or(0.0);
 
+                        let contig_n50 = result["contign50"]
+                            .as_u64()
+                            .map(|n| n as usize)
+                            .or_else(|| result["contign50"].as_str()?.parse().ok())
+                            .unwrap_or(0);
+                        let contig_count = result["meta"]
+                            .as_str()
+                            .and_then(|meta| parse_meta_stat(meta, "contig_count"))
+                            .unwrap_or(0);
+                        let quality_flags = parse_quality_flags(result);
+                        let species_taxid =
+                            result["speciestaxid"].as_str().unwrap_or("").to_string();
+                        let refseq_category = result["refseq_category"]
+                            .as_str()
+                            .unwrap_or("na")
+                            .to_string();
+                        // `fromtype` holds e.g. "assembly from type material", empty otherwise
+                        let from_type = result["fromtype"]
+                            .as_str()
+                            .map_or(false, |s| !s.trim().is_empty());
+
                         if accession.is_empty() || taxid == "0" {
                             warn!(
                                 "Skipping assembly ID {} due to missing accession or taxid.",
@@ -283,23 +827,38 @@ impl NCBIDownloader {
                             continue;
                         }
 
+                        let mut metadata = GenomeMetadata {
+                            accession,
+                            assembly_id: id_str.to_string(), // Store the assembly ID
+                            organism,
+                            taxid,
+                            assembly_level,
+                            release_date,
+                            size,
+                            gc_content,
+                            lineage: Vec::new(),
+                            contig_n50,
+                            contig_count,
+                            quality_flags,
+                            species_taxid,
+                            refseq_category,
+                            from_type,
+                        };
+
+                        // Apply quality filters before spending a request on the lineage
+                        if let Err(reason) = filter.check(&metadata) {
+                            info!("Skipping assembly {}: {}", metadata.accession, reason);
+                            continue;
+                        }
+
                         // Fetch taxonomy lineage
-                        match self.fetch_taxonomy_lineage(&taxid) {
+                        match self.fetch_taxonomy_lineage(&metadata.taxid) {
                             Ok(lineage) => {
-                                results.push(GenomeMetadata {
-                                    accession,
-                                    assembly_id: id_str.to_string(), // Store the assembly ID
-                                    organism,
-                                    taxid,
-                                    assembly_level,
-                                    release_date,
-                                    size,
-                                    gc_content,
-                                    lineage,
-                                });
+                                metadata.lineage = lineage;
+                                results.push(metadata);
                             }
                             Err(e) => {
-                                warn!("Failed to fetch lineage for taxid {}: {}. Skipping assembly {}.", taxid, e, accession);
+                                warn!("Failed to fetch lineage for taxid {}: {}. Skipping assembly {}.", metadata.taxid, e, metadata.accession);
                                 // Decide whether to skip or add with empty lineage
                                 // Skipping for now:
                                 continue;
@@ -432,7 +991,7 @@ impl NCBIDownloader {
     /// Download a genome FASTA file by accession (e.g., GCF_...).
     /// Uses the cache if available and not expired.
     pub fn download_genome(&self, accession: &str) -> Result<PathBuf, DatabaseError> {
-        let expected_filename = format!("{}.fna.gz", accession);
+        let expected_filename = format!("{}.fna.gz", safe_file_name(accession));
         let cache_file = self.cache_dir.join(&expected_filename);
 
         // Check cache validity
@@ -557,13 +1116,11 @@ impl NCBIDownloader {
             }
             // Use the alternative response if successful
             let content = response_alt.bytes()?;
-            let mut file = File::create(&cache_file)?;
-            file.write_all(&content)?;
+            self.store_in_cache(&content, &cache_file)?;
         } else {
             // Save the primary response to cache
             let content = response.bytes()?;
-            let mut file = File::create(&cache_file)?;
-            file.write_all(&content)?;
+            self.store_in_cache(&content, &cache_file)?;
         }
 
         info!(
@@ -572,177 +1129,678 @@ impl NCBIDownloader {
         );
         Ok(cache_file)
     }
+
+    /// Write `content` to the scratch directory, then move it to `cache_file`.
+    /// The final rename is atomic, so an interrupted download never leaves a
+    /// truncated genome in the cache; across filesystems the file is copied
+    /// next to its destination first.
+    fn store_in_cache(&self, content: &[u8], cache_file: &Path) -> Result<(), DatabaseError> {
+        let scratch_dir = self.scratch_dir.as_deref().unwrap_or(&self.cache_dir);
+        let mut download = tempfile::NamedTempFile::new_in(scratch_dir)?;
+        download.write_all(content)?;
+        download.flush()?;
+        if let Err(e) = download.persist(cache_file) {
+            debug!(
+                "Cannot rename download into the cache ({}); copying",
+                e.error
+            );
+            let staged = tempfile::NamedTempFile::new_in(&self.cache_dir)?;
+            fs::copy(e.file.path(), staged.path())?;
+            staged.persist(cache_file).map_err(|e| e.error)?;
+        }
+        Ok(())
+    }
 }
 
-/// Signature database using sled
+/// Signature database on top of an embedded key-value store
 pub struct SignatureDatabase {
     /// Underlying key-value store
-    db: Db,
+    store: Box<dyn SignatureStore>,
 
     /// Index of taxonomy IDs to accessions (signature IDs)
     taxonomy_index: HashMap<String, HashSet<String>>, // Use HashSet for unique IDs
 
     /// Index of lineage terms (names) to accessions (signature IDs)
     lineage_index: HashMap<String, HashSet<String>>, // Use HashSet for unique IDs
+
+    /// zstd level for signature blobs, `None` to store them uncompressed
+    signature_zstd_level: Option<i32>,
 }
 
+/// Magic bytes that start every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Bytes that start a versioned signature record, followed by the format
+/// version (u32, little-endian) and the blob. No bincode signature starts
+/// with 0xFF (an invalid varint prefix), and no zstd frame does either, so
+/// records written before versions were recorded are told apart as version 0.
+const RECORD_MAGIC: [u8; 4] = [0xFF, b'A', b'H', b'S'];
+
+/// Length of the header before the blob of a versioned record
+const RECORD_HEADER_LEN: usize = RECORD_MAGIC.len() + 4;
+
 impl SignatureDatabase {
-    /// Open or create a signature database
+    /// Open or create a signature database with default storage settings
     pub fn open(path: impl AsRef<Path>) -> Result<Self, DatabaseError> {
-        info!("Opening database at: {}", path.as_ref().display());
-        let db = sled::open(path)?;
-
-        // Load indices, handling potential errors during decode gracefully
-        let taxonomy_index: HashMap<String, HashSet<String>> = db
-            .get("taxonomy_index")?
-            .map(|data| decode_from_slice(&data, standard()).map(|(d, _)| d))
-            .transpose() // Turns Option<Result<T, E>> into Result<Option<T>, E>
-            .map_err(|e| {
-                DatabaseError::SerializationError(format!("Failed to decode taxonomy index: {}", e))
-            })?
-            .unwrap_or_default(); // Use default empty map if None or decode error
+        Self::open_with_config(path, &DatabaseConfig::default())
+    }
 
-        let lineage_index: HashMap<String, HashSet<String>> = db
-            .get("lineage_index")?
-            .map(|data| decode_from_slice(&data, standard()).map(|(d, _)| d))
-            .transpose()
-            .map_err(|e| {
-                DatabaseError::SerializationError(format!("Failed to decode lineage index: {}", e))
-            })?
-            .unwrap_or_default();
+    /// Open or create a signature database with the given storage settings
+    pub fn open_with_config(
+        path: impl AsRef<Path>,
+        config: &DatabaseConfig,
+    ) -> Result<Self, DatabaseError> {
+        info!("Opening database at: {}", path.as_ref().display());
+        let store = open_store(path, config)?;
+
+        let mut database = SignatureDatabase {
+            taxonomy_index: Self::load_index(store.as_ref(), TAXONOMY_INDEX_TABLE)?,
+            lineage_index: Self::load_index(store.as_ref(), LINEAGE_INDEX_TABLE)?,
+            store,
+            signature_zstd_level: config
+                .compress_signatures
+                .then_some(config.signature_zstd_level),
+        };
+        database.migrate_legacy_indices()?;
 
         info!(
             "Database opened. Taxonomy index size: {}, Lineage index size: {}",
-            taxonomy_index.len(),
-            lineage_index.len()
+            database.taxonomy_index.len(),
+            database.lineage_index.len()
         );
 
-        Ok(SignatureDatabase {
-            db,
-            taxonomy_index,
-            lineage_index,
-        })
+        Ok(database)
     }
 
-    /// Validate a signature's levels and compatibility
-    fn validate_signature(
-        &self,
-        signature: &MultiResolutionSignature,
-    ) -> Result<(), DatabaseError> {
-        // Ensure signature has at least one level
-        if signature.levels.is_empty() {
-            return Err(DatabaseError::InvalidSignature(
-                "Signature must have at least one resolution level".to_string(),
-            ));
-        }
+    /// Load an index table into memory
+    fn load_index(
+        store: &dyn SignatureStore,
+        table: &str,
+    ) -> Result<HashMap<String, HashSet<String>>, DatabaseError> {
+        let mut index: HashMap<String, HashSet<String>> = HashMap::new();
+        store.scan_prefix(table, b"", &mut |key, _| {
+            match key.iter().position(|&b| b == 0) {
+                Some(split) => {
+                    let term = String::from_utf8_lossy(&key[..split]).to_string();
+                    let id = String::from_utf8_lossy(&key[split + 1..]).to_string();
+                    index.entry(term).or_default().insert(id);
+                }
+                None => warn!(
+                    "Ignoring malformed index entry in {}: {}",
+                    table,
+                    String::from_utf8_lossy(key)
+                ),
+            }
+            Ok(())
+        })?;
+        Ok(index)
+    }
 
-        // Check that levels are properly ordered (decreasing k-mer size)
-        for window in signature.levels.windows(2) {
-            if window[0].kmer_size <= window[1].kmer_size {
-                return Err(DatabaseError::InvalidSignature(
-                    "Resolution levels must have decreasing k-mer sizes".to_string(),
-                ));
+    /// Move indices stored as single serialized maps (older databases) into the
+    /// per-entry index tables
+    fn migrate_legacy_indices(&mut self) -> Result<(), DatabaseError> {
+        for (legacy_key, table) in [
+            ("taxonomy_index", TAXONOMY_INDEX_TABLE),
+            ("lineage_index", LINEAGE_INDEX_TABLE),
+        ] {
+            let Some(data) = self.store.get(SIGNATURE_TABLE, legacy_key.as_bytes())? else {
+                continue;
+            };
+            let (legacy, _): (HashMap<String, HashSet<String>>, _) =
+                decode_from_slice(&data, standard()).map_err(|e| {
+                    DatabaseError::SerializationError(format!(
+                        "Failed to decode legacy {}: {}",
+                        legacy_key, e
+                    ))
+                })?;
+
+            let entries: Vec<(Vec<u8>, Vec<u8>)> = legacy
+                .iter()
+                .flat_map(|(term, ids)| ids.iter().map(move |id| (index_key(term, id), Vec::new())))
+                .collect();
+            info!(
+                "Migrating {} entries from legacy {} blob",
+                entries.len(),
+                legacy_key
+            );
+            self.store.insert_batch(table, &entries)?;
+            self.store.remove(SIGNATURE_TABLE, legacy_key.as_bytes())?;
+
+            let index = if table == TAXONOMY_INDEX_TABLE {
+                &mut self.taxonomy_index
+            } else {
+                &mut self.lineage_index
+            };
+            for (term, ids) in legacy {
+                index.entry(term).or_default().extend(ids);
             }
         }
+        self.store.flush()
+    }
 
-        // Verify each level has valid parameters
-        for level in &signature.levels {
-            // Either num_hashes or scaled must be set, but not both
-            if (level.sketch.num_hashes == 0 && level.sketch.scaled == 0)
-                || (level.sketch.num_hashes > 0 && level.sketch.scaled > 0)
-            {
-                return Err(DatabaseError::InvalidSignature(
-                    "Each level must specify either num_hashes or scaled, but not both".to_string(),
-                ));
-            }
+    /// Serialize a signature behind the versioned record header, compressing
+    /// it with zstd if enabled
+    fn encode_signature(
+        &self,
+        signature: &MultiResolutionSignature,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        let data = encode_to_vec(signature, standard())?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + data.len());
+        record.extend_from_slice(&RECORD_MAGIC);
+        record.extend_from_slice(&Artifact::DatabaseRecord.current().to_le_bytes());
+        match self.signature_zstd_level {
+            Some(level) => record.extend(zstd::encode_all(data.as_slice(), level)?),
+            None => record.extend(data),
+        }
+        Ok(record)
+    }
 
-            // Verify k-mer size is reasonable
-            if level.kmer_size == 0 || level.kmer_size > 63 {
-                return Err(DatabaseError::InvalidSignature(format!(
-                    "Invalid k-mer size: {}",
-                    level.kmer_size
-                )));
+    /// Deserialize the signature record stored under `key`, after checking its
+    /// format version. Records without the header predate versions (version 0).
+    /// Blobs written without compression are still readable: a bincode signature
+    /// starts with the ID length followed by UTF-8, which can never form the zstd
+    /// magic number. Signatures stored before hashing parameters were recorded are
+    /// read with the legacy parameters.
+    fn decode_signature(key: &str, data: &[u8]) -> Result<MultiResolutionSignature, DatabaseError> {
+        let data = match data.strip_prefix(&RECORD_MAGIC) {
+            Some(record) => {
+                let (version, blob) = record.split_first_chunk::<4>().ok_or_else(|| {
+                    DatabaseError::SerializationError(format!(
+                        "Truncated record header for signature '{}'",
+                        key
+                    ))
+                })?;
+                Artifact::DatabaseRecord.check(
+                    u32::from_le_bytes(*version),
+                    format_args!("signature '{}'", key),
+                )?;
+                blob
+            }
+            None => data,
+        };
+        let decompressed;
+        let data = if data.starts_with(&ZSTD_MAGIC) {
+            decompressed = zstd::decode_all(data)?;
+            decompressed.as_slice()
+        } else {
+            data
+        };
+        match decode_from_slice::<MultiResolutionSignature, _>(data, standard()) {
+            // A legacy blob can only decode as the current layout by misreading
+            // one level's fields as another's, and then rarely to the last byte
+            Ok((signature, read)) if read == data.len() => Ok(signature),
+            current => {
+                match decode_from_slice::<LegacyMultiResolutionSignature, _>(data, standard()) {
+                    Ok((legacy, read)) if read == data.len() => Ok(legacy.into()),
+                    _ => Err(current.err().map_or_else(
+                        || {
+                            DatabaseError::SerializationError(
+                                "Trailing bytes after signature".to_string(),
+                            )
+                        },
+                        DatabaseError::from,
+                    )),
+                }
             }
         }
+    }
 
-        Ok(())
+    /// Validate a signature's levels and compatibility
+    fn validate_signature(
+        &self,
+        signature: &MultiResolutionSignature,
+    ) -> Result<(), DatabaseError> {
+        signature
+            .layout()
+            .validate()
+            .map_err(DatabaseError::InvalidSignature)
     }
 
-    /// Add a signature to the database
+    /// Add a signature to the database.
+    ///
+    /// Signatures whose sketch contents exactly match one already stored under a
+    /// different ID are skipped and reported as `InsertOutcome::Duplicate`.
     pub fn add_signature(
         &mut self,
         signature: &MultiResolutionSignature,
-    ) -> Result<(), DatabaseError> {
+    ) -> Result<InsertOutcome, DatabaseError> {
         // Validate signature structure
         self.validate_signature(signature)?;
 
+        // Skip exact duplicates (e.g. the same genome found by different queries)
+        let content_hash = signature.content_hash();
+        if let Some(existing) = self.store.get(CONTENT_HASH_TREE, content_hash.as_bytes())? {
+            let existing = String::from_utf8_lossy(&existing).to_string();
+            if existing != signature.taxon_id
+                && self
+                    .store
+                    .get(SIGNATURE_TABLE, existing.as_bytes())?
+                    .is_some()
+            {
+                info!(
+                    "Skipping signature {}: identical to existing signature {}",
+                    signature.taxon_id, existing
+                );
+                return Ok(InsertOutcome::Duplicate(existing));
+            }
+        }
+
         // Proceed with storage
         let key = signature.taxon_id.as_bytes();
-        let signature_data = encode_to_vec(signature, standard())?;
+        let signature_data = self.encode_signature(signature)?;
+        self.release_previous(&signature.taxon_id)?;
 
         // Store signature
-        self.db.insert(key, signature_data)?;
+        self.store.insert(SIGNATURE_TABLE, key, &signature_data)?;
+        self.store
+            .insert(CONTENT_HASH_TREE, content_hash.as_bytes(), key)?;
         info!("Added signature with ID: {}", signature.taxon_id);
 
-        // Update indices
+        // Record when the signature was added, for `db stats`
+        let added_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.store
+            .insert(ADDED_AT_TREE, key, &added_at.to_be_bytes())?;
+
+        // Update indices (only this signature's entries are written)
         self.update_indices(signature)?;
 
-        // Persist indices and data
-        self.save_indices()?;
-        self.db.flush()?;
+        // Persist data
+        self.store.flush()?;
 
-        Ok(())
+        Ok(InsertOutcome::Added)
     }
 
-    /// Update in-memory search indices for a signature
-    fn update_indices(
+    /// Add many signatures at once.
+    ///
+    /// Signatures are written in batches of `BULK_BATCH_SIZE`, index entries are
+    /// written once at the end and the store is flushed a single time, which is much
+    /// faster than calling `add_signature` in a loop. Invalid signatures and exact
+    /// duplicates are skipped and reported instead of aborting the whole insert.
+    /// An ID given more than once is stored as its last version, as with repeated
+    /// `add_signature` calls.
+    pub fn add_signatures_bulk<I>(
         &mut self,
-        signature: &MultiResolutionSignature,
-    ) -> Result<(), DatabaseError> {
-        let signature_id = signature.taxon_id.clone();
+        signatures: I,
+    ) -> Result<BulkInsertReport, DatabaseError>
+    where
+        I: IntoIterator<Item = MultiResolutionSignature>,
+    {
+        let mut report = BulkInsertReport::default();
+        let added_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+            .to_be_bytes()
+            .to_vec();
+
+        // Content hashes seen in this run, so duplicates within the input are caught too
+        let mut seen_hashes: HashMap<String, String> = HashMap::new();
+        let mut signature_batch = Vec::with_capacity(BULK_BATCH_SIZE);
+        let mut hash_batch = Vec::with_capacity(BULK_BATCH_SIZE);
+        let mut added_at_batch = Vec::with_capacity(BULK_BATCH_SIZE);
+        // Content hash and lineage of each added ID, all the index needs; the
+        // sketches are already serialized
+        let mut indexed: HashMap<String, (String, Vec<String>)> = HashMap::new();
 
-        // Update taxonomy index (TaxID -> Set<SignatureID>)
-        self.taxonomy_index
-            .entry(signature_id.clone())
-            .or_default()
-            .insert(signature_id.clone());
+        for signature in signatures {
+            if let Err(e) = self.validate_signature(&signature) {
+                warn!("Skipping invalid signature {}: {}", signature.taxon_id, e);
+                report
+                    .invalid
+                    .push((signature.taxon_id.clone(), e.to_string()));
+                continue;
+            }
 
-        // Update lineage index (Name -> Set<SignatureID>)
-        for name in &signature.lineage {
-            if !name.is_empty() {
-                self.lineage_index
+            let content_hash = signature.content_hash();
+            let existing = match seen_hashes.get(&content_hash) {
+                Some(id) => Some(id.clone()),
+                None => self
+                    .store
+                    .get(CONTENT_HASH_TREE, content_hash.as_bytes())?
+                    .map(|id| String::from_utf8_lossy(&id).to_string())
+                    .filter(|id| {
+                        // Only trust the hash entry if its signature still exists
+                        matches!(self.store.get(SIGNATURE_TABLE, id.as_bytes()), Ok(Some(_)))
+                    }),
+            };
+            if let Some(existing) = existing {
+                if existing != signature.taxon_id {
+                    report
+                        .duplicates
+                        .push((signature.taxon_id.clone(), existing));
+                    continue;
+                }
+            }
+
+            match indexed.get(&signature.taxon_id) {
+                // Replacing a version from earlier in this run: write it first, so
+                // that releasing it also drops its content hash
+                Some((previous_hash, _)) => {
+                    seen_hashes.remove(previous_hash);
+                    self.write_bulk_batch(
+                        &mut signature_batch,
+                        &mut hash_batch,
+                        &mut added_at_batch,
+                    )?;
+                }
+                None => report.added.push(signature.taxon_id.clone()),
+            }
+            self.release_previous(&signature.taxon_id)?;
+            let key = signature.taxon_id.as_bytes().to_vec();
+            signature_batch.push((key.clone(), self.encode_signature(&signature)?));
+            hash_batch.push((content_hash.as_bytes().to_vec(), key.clone()));
+            added_at_batch.push((key, added_at.clone()));
+            seen_hashes.insert(content_hash.clone(), signature.taxon_id.clone());
+            indexed.insert(signature.taxon_id, (content_hash, signature.lineage));
+
+            if signature_batch.len() >= BULK_BATCH_SIZE {
+                self.write_bulk_batch(&mut signature_batch, &mut hash_batch, &mut added_at_batch)?;
+            }
+        }
+        self.write_bulk_batch(&mut signature_batch, &mut hash_batch, &mut added_at_batch)?;
+
+        // Build the index entries once for everything that was added
+        let mut taxonomy_entries = Vec::with_capacity(indexed.len());
+        let mut lineage_entries = Vec::new();
+        for (id, (_, lineage)) in &indexed {
+            self.taxonomy_index
+                .entry(id.clone())
+                .or_default()
+                .insert(id.clone());
+            taxonomy_entries.push((index_key(id, id), Vec::new()));
+            for name in lineage.iter().filter(|name| !name.is_empty()) {
+                self.lineage_index
                     .entry(name.clone())
                     .or_default()
-                    .insert(signature_id.clone());
+                    .insert(id.clone());
+                lineage_entries.push((index_key(name, id), Vec::new()));
+            }
+        }
+        self.store
+            .insert_batch(TAXONOMY_INDEX_TABLE, &taxonomy_entries)?;
+        self.store
+            .insert_batch(LINEAGE_INDEX_TABLE, &lineage_entries)?;
+        self.store.flush()?;
+
+        info!(
+            "Bulk insert: {} added, {} duplicates skipped, {} invalid",
+            report.added.len(),
+            report.duplicates.len(),
+            report.invalid.len()
+        );
+        Ok(report)
+    }
+
+    /// Write and clear the pending batches of a bulk insert
+    fn write_bulk_batch(
+        &self,
+        signatures: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        hashes: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        added_at: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), DatabaseError> {
+        if signatures.is_empty() {
+            return Ok(());
+        }
+        debug!("Writing batch of {} signatures", signatures.len());
+        self.store.insert_batch(SIGNATURE_TABLE, signatures)?;
+        self.store.insert_batch(CONTENT_HASH_TREE, hashes)?;
+        self.store.insert_batch(ADDED_AT_TREE, added_at)?;
+        signatures.clear();
+        hashes.clear();
+        added_at.clear();
+        Ok(())
+    }
+
+    /// Remove a signature and its index entries from the database
+    pub fn remove_signature(&mut self, id: &str) -> Result<(), DatabaseError> {
+        let signature = self.get_signature(id)?;
+
+        self.store.remove(SIGNATURE_TABLE, id.as_bytes())?;
+        self.store.remove(ADDED_AT_TREE, id.as_bytes())?;
+        self.store.remove(MAG_METADATA_TABLE, id.as_bytes())?;
+        self.remove_entries(id, &signature)?;
+
+        self.store.flush()?;
+        info!("Removed signature with ID: {}", id);
+        Ok(())
+    }
+
+    /// Before `id` is overwritten, remove the content hash and index entries of
+    /// the signature stored under it, so its old contents no longer count as a
+    /// duplicate and its old lineage no longer finds it. An undecodable record
+    /// is overwritten as it is; one of a newer format version is not.
+    fn release_previous(&mut self, id: &str) -> Result<(), DatabaseError> {
+        let Some(data) = self.store.get(SIGNATURE_TABLE, id.as_bytes())? else {
+            return Ok(());
+        };
+        match Self::decode_signature(id, &data) {
+            Ok(previous) => self.remove_entries(id, &previous),
+            Err(e @ DatabaseError::UnsupportedVersion(_)) => Err(e),
+            Err(e) => {
+                warn!("Overwriting undecodable signature '{}': {}", id, e);
+                Ok(())
             }
         }
+    }
+
+    /// Remove the content hash and index entries of `signature`, stored under `id`
+    fn remove_entries(
+        &mut self,
+        id: &str,
+        signature: &MultiResolutionSignature,
+    ) -> Result<(), DatabaseError> {
+        let content_hash = signature.content_hash();
+        if self
+            .store
+            .get(CONTENT_HASH_TREE, content_hash.as_bytes())?
+            .as_deref()
+            == Some(id.as_bytes())
+        {
+            self.store
+                .remove(CONTENT_HASH_TREE, content_hash.as_bytes())?;
+        }
 
+        self.taxonomy_index.remove(id);
+        self.store
+            .remove(TAXONOMY_INDEX_TABLE, &index_key(id, id))?;
+        for name in &signature.lineage {
+            if let Some(ids) = self.lineage_index.get_mut(name) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.lineage_index.remove(name);
+                }
+            }
+            self.store
+                .remove(LINEAGE_INDEX_TABLE, &index_key(name, id))?;
+        }
         Ok(())
     }
 
-    /// Save indices to the database
-    fn save_indices(&self) -> Result<(), DatabaseError> {
-        let taxonomy_data = encode_to_vec(&self.taxonomy_index, standard())?;
-        let lineage_data = encode_to_vec(&self.lineage_index, standard())?;
+    /// Find signatures with identical sketch contents, keeping the lexicographically
+    /// first ID of each group. Duplicates are removed unless `dry_run` is set.
+    pub fn dedupe(&mut self, dry_run: bool) -> Result<DedupeReport, DatabaseError> {
+        let mut by_hash: HashMap<String, Vec<(String, u64)>> = HashMap::new();
+        self.store
+            .scan_prefix(SIGNATURE_TABLE, b"", &mut |key, value| {
+                let Ok(key_str) = std::str::from_utf8(key) else {
+                    return Ok(());
+                };
+                if key_str == "taxonomy_index" || key_str == "lineage_index" {
+                    return Ok(());
+                }
+                match Self::decode_signature(key_str, value) {
+                    Ok(signature) => by_hash
+                        .entry(signature.content_hash())
+                        .or_default()
+                        .push((key_str.to_string(), value.len() as u64)),
+                    // Skipping would keep duplicates of records this build cannot read
+                    Err(e @ DatabaseError::UnsupportedVersion(_)) => return Err(e),
+                    Err(e) => warn!("Skipping undecodable signature '{}': {}", key_str, e),
+                }
+                Ok(())
+            })?;
+
+        let mut report = DedupeReport::default();
+        for (content_hash, mut entries) in by_hash {
+            entries.sort();
+            let (kept, _) = entries.remove(0);
+            if !dry_run {
+                // Backfill the hash tree for databases created before it existed
+                self.store
+                    .insert(CONTENT_HASH_TREE, content_hash.as_bytes(), kept.as_bytes())?;
+            }
+            if entries.is_empty() {
+                continue;
+            }
+
+            report.bytes_reclaimed += entries.iter().map(|(_, size)| size).sum::<u64>();
+            let duplicates: Vec<String> = entries.into_iter().map(|(id, _)| id).collect();
+            if !dry_run {
+                for id in &duplicates {
+                    self.remove_signature(id)?;
+                }
+            }
+            report.groups.push((kept, duplicates));
+        }
+        report.groups.sort();
+
+        Ok(report)
+    }
+
+    /// The lineage name or signature ID `query` stands for, as taken by
+    /// `extract_lineages`: a taxid is replaced by its scientific name from
+    /// `taxonomy`, and a query matching nothing in the database fails with
+    /// the closest lineage names as suggestions
+    pub fn resolve_lineage(
+        &self,
+        query: &str,
+        taxonomy: Option<&Taxonomy>,
+    ) -> Result<String, TaxonLookupError> {
+        let query = query.trim();
+        let known = |term: &str| {
+            self.taxonomy_index.contains_key(term)
+                || self
+                    .lineage_index
+                    .keys()
+                    .any(|name| name.eq_ignore_ascii_case(term))
+        };
+        if known(query) {
+            return Ok(query.to_string());
+        }
+        if is_taxid(query) {
+            let Some(taxonomy) = taxonomy else {
+                return Err(TaxonLookupError::NoTaxonomy(query.to_string()));
+            };
+            return match taxonomy.node(query) {
+                Some(node) => Ok(node.name.clone()),
+                None => Err(TaxonLookupError::UnknownTaxid(query.to_string())),
+            };
+        }
+        Err(TaxonLookupError::UnknownName {
+            query: query.to_string(),
+            suggestions: suggestions(query, self.lineage_index.keys().map(String::as_str)),
+        })
+    }
+
+    /// Copy the signatures under any of the `lineages` nodes (a lineage name,
+    /// matched case-insensitively, or a signature ID) into `target`, with the
+    /// metadata of copied MAGs. Builds the small panel databases used to
+    /// re-classify within one clade.
+    pub fn extract_lineages(
+        &self,
+        lineages: &[String],
+        target: &mut SignatureDatabase,
+    ) -> Result<BulkInsertReport, DatabaseError> {
+        let wanted: HashSet<String> = lineages
+            .iter()
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+
+        let mut matching_ids: HashSet<String> = self
+            .taxonomy_index
+            .keys()
+            .filter(|id| wanted.contains(&id.to_lowercase()))
+            .cloned()
+            .collect();
+        for (name, ids) in &self.lineage_index {
+            if wanted.contains(&name.to_lowercase()) {
+                matching_ids.extend(ids.iter().cloned());
+            }
+        }
+        info!(
+            "Extracting {} signatures under {}",
+            matching_ids.len(),
+            lineages.join(", ")
+        );
+
+        let mut signatures = self.fetch_signatures(matching_ids);
+        signatures.sort_by(|a, b| a.taxon_id.cmp(&b.taxon_id));
+        let report = target.add_signatures_bulk(signatures)?;
+
+        let mags = self.mag_metadata()?;
+        for id in &report.added {
+            if let Some(metadata) = mags.get(id) {
+                let encoded = serde_json::to_vec(metadata)
+                    .map_err(|e| DatabaseError::SerializationError(e.to_string()))?;
+                target
+                    .store
+                    .insert(MAG_METADATA_TABLE, id.as_bytes(), &encoded)?;
+            }
+        }
+        // The copies were sketched without these, so later additions must be too
+        if let Some(common) = self.common_hashes()? {
+            target.save_common_hashes(&common)?;
+        }
+        target.store.flush()?;
+
+        Ok(report)
+    }
+
+    /// Update the in-memory search indices for a signature and write its entries
+    /// to the index tables
+    fn update_indices(
+        &mut self,
+        signature: &MultiResolutionSignature,
+    ) -> Result<(), DatabaseError> {
+        let signature_id = signature.taxon_id.clone();
+
+        // Update taxonomy index (TaxID -> Set<SignatureID>)
+        self.taxonomy_index
+            .entry(signature_id.clone())
+            .or_default()
+            .insert(signature_id.clone());
+        self.store.insert(
+            TAXONOMY_INDEX_TABLE,
+            &index_key(&signature_id, &signature_id),
+            &[],
+        )?;
 
-        // Use atomic batch for index updates if possible (sled >= 0.34)
-        let mut batch = sled::Batch::default();
-        batch.insert("taxonomy_index", taxonomy_data);
-        batch.insert("lineage_index", lineage_data);
-        self.db.apply_batch(batch)?;
+        // Update lineage index (Name -> Set<SignatureID>)
+        let mut lineage_entries = Vec::with_capacity(signature.lineage.len());
+        for name in &signature.lineage {
+            if !name.is_empty() {
+                self.lineage_index
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(signature_id.clone());
+                lineage_entries.push((index_key(name, &signature_id), Vec::new()));
+            }
+        }
+        self.store
+            .insert_batch(LINEAGE_INDEX_TABLE, &lineage_entries)?;
 
-        // Fallback for older sled versions:
-        // self.db.insert("taxonomy_index", taxonomy_data)?;
-        // self.db.insert("lineage_index", lineage_data)?;
         Ok(())
     }
 
     /// Get a signature by ID (e.g., accession)
     pub fn get_signature(&self, id: &str) -> Result<MultiResolutionSignature, DatabaseError> {
-        match self.db.get(id.as_bytes())? {
+        match self.store.get(SIGNATURE_TABLE, id.as_bytes())? {
             Some(data) => {
-                let (signature, _): (MultiResolutionSignature, _) =
-                    decode_from_slice(&data, standard())?;
+                let signature = Self::decode_signature(id, &data)?;
                 // Validate retrieved signature
                 self.validate_signature(&signature)?;
                 Ok(signature)
@@ -782,9 +1840,76 @@ impl SignatureDatabase {
             term
         );
 
-        // Fetch signatures for the unique IDs found
-        let mut results = Vec::with_capacity(matching_ids.len());
-        for id in matching_ids {
+        Ok(self.fetch_signatures(matching_ids))
+    }
+
+    /// Search signatures by accession, organism name or lineage term using the given mode.
+    /// Results are sorted by signature ID.
+    pub fn search(
+        &self,
+        term: &str,
+        mode: SearchMode,
+    ) -> Result<Vec<MultiResolutionSignature>, DatabaseError> {
+        let mut results = match mode {
+            SearchMode::Exact => self.search_by_taxonomy(term)?,
+            SearchMode::Accession => self.search_by_accession(term)?,
+            SearchMode::Substring | SearchMode::Glob | SearchMode::Regex => {
+                let pattern = match mode {
+                    SearchMode::Substring => format!("(?i){}", regex::escape(term)),
+                    SearchMode::Glob => format!("(?i)^{}$", glob_to_regex(term)),
+                    _ => format!("(?i){}", term),
+                };
+                let matcher = regex::Regex::new(&pattern)
+                    .map_err(|e| DatabaseError::InvalidPattern(format!("{}: {}", term, e)))?;
+
+                // Signature IDs are accessions; lineage index keys cover organism names
+                // and every lineage term
+                let mut matching_ids: HashSet<String> = self
+                    .taxonomy_index
+                    .keys()
+                    .filter(|id| matcher.is_match(id))
+                    .cloned()
+                    .collect();
+                for (name, ids) in &self.lineage_index {
+                    if matcher.is_match(name) {
+                        matching_ids.extend(ids.iter().cloned());
+                    }
+                }
+                self.fetch_signatures(matching_ids)
+            }
+        };
+
+        results.sort_by(|a, b| a.taxon_id.cmp(&b.taxon_id));
+        Ok(results)
+    }
+
+    /// Look up signatures by accession, ignoring case and accepting a missing version suffix
+    pub fn search_by_accession(
+        &self,
+        accession: &str,
+    ) -> Result<Vec<MultiResolutionSignature>, DatabaseError> {
+        let accession = accession.trim().to_uppercase();
+        match self.get_signature(&accession) {
+            Ok(signature) => return Ok(vec![signature]),
+            Err(DatabaseError::NotFoundError(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        // "GCF_000005845" should find "GCF_000005845.2"
+        let prefix = format!("{}.", accession);
+        let mut matching_ids = HashSet::new();
+        self.store
+            .scan_prefix(SIGNATURE_TABLE, prefix.as_bytes(), &mut |key, _| {
+                matching_ids.insert(String::from_utf8_lossy(key).to_string());
+                Ok(())
+            })?;
+        Ok(self.fetch_signatures(matching_ids))
+    }
+
+    /// Fetch signatures for a set of IDs, skipping (and logging) stale index entries
+    fn fetch_signatures(&self, ids: HashSet<String>) -> Vec<MultiResolutionSignature> {
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
             match self.get_signature(&id) {
                 Ok(signature) => results.push(signature),
                 Err(DatabaseError::NotFoundError(_)) => {
@@ -796,62 +1921,312 @@ impl SignatureDatabase {
                 }
                 Err(e) => {
                     error!("Error retrieving signature {}: {}", id, e);
-                    // Optionally propagate the error, or just log and continue
-                    // return Err(e); // Propagate
                     continue; // Log and skip
                 }
             }
         }
+        results
+    }
 
-        Ok(results)
+    /// Add a user MAG: the signature is stored like any reference and `metadata`
+    /// is recorded so hits to it can be reported as MAG hits
+    pub fn add_mag(
+        &mut self,
+        signature: &MultiResolutionSignature,
+        metadata: &MagMetadata,
+    ) -> Result<InsertOutcome, DatabaseError> {
+        let outcome = self.add_signature(signature)?;
+        if outcome == InsertOutcome::Added {
+            let encoded = serde_json::to_vec(metadata)
+                .map_err(|e| DatabaseError::SerializationError(e.to_string()))?;
+            self.store
+                .insert(MAG_METADATA_TABLE, signature.taxon_id.as_bytes(), &encoded)?;
+            self.store.flush()?;
+        }
+        Ok(outcome)
+    }
+
+    /// Metadata of every user MAG in the database, keyed by signature ID
+    pub fn mag_metadata(&self) -> Result<HashMap<String, MagMetadata>, DatabaseError> {
+        let mut mags = HashMap::new();
+        self.store
+            .scan_prefix(MAG_METADATA_TABLE, b"", &mut |key, value| {
+                let id = String::from_utf8_lossy(key).to_string();
+                match serde_json::from_slice(value) {
+                    Ok(metadata) => {
+                        mags.insert(id, metadata);
+                    }
+                    Err(e) => warn!("Ignoring unreadable MAG metadata for {}: {}", id, e),
+                }
+                Ok(())
+            })?;
+        Ok(mags)
+    }
+
+    /// Pairwise reference similarities computed by earlier runs
+    pub fn similarity_cache(&self) -> Result<SimilarityCache, DatabaseError> {
+        let mut entries = Vec::new();
+        self.store
+            .scan_prefix(SIMILARITY_CACHE_TABLE, b"", &mut |key, value| {
+                match <[u8; 8]>::try_from(value) {
+                    Ok(bytes) => entries.push((key.to_vec(), f64::from_le_bytes(bytes))),
+                    Err(_) => warn!("Ignoring malformed similarity cache entry"),
+                }
+                Ok(())
+            })?;
+        Ok(SimilarityCache::from_entries(entries))
+    }
+
+    /// Persist the similarities `cache` computed since it was loaded; returns
+    /// how many were written
+    pub fn save_similarities(&self, cache: &SimilarityCache) -> Result<usize, DatabaseError> {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = cache
+            .drain_new()
+            .into_iter()
+            .map(|(key, value)| (key, value.to_le_bytes().to_vec()))
+            .collect();
+        if !entries.is_empty() {
+            self.store.insert_batch(SIMILARITY_CACHE_TABLE, &entries)?;
+            self.store.flush()?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Hashes the references were sketched without, if the database was
+    /// built with common k-mer masking
+    pub fn common_hashes(&self) -> Result<Option<CommonHashes>, DatabaseError> {
+        match self.store.get(REFERENCE_MASKING_TABLE, COMMON_HASHES_KEY)? {
+            Some(bytes) => Ok(Some(decode_from_slice(&bytes, standard())?.0)),
+            None => Ok(None),
+        }
+    }
+
+    /// Record the hashes the references are sketched without
+    pub fn save_common_hashes(&self, common: &CommonHashes) -> Result<(), DatabaseError> {
+        let encoded = encode_to_vec(common, standard())?;
+        self.store
+            .insert(REFERENCE_MASKING_TABLE, COMMON_HASHES_KEY, &encoded)?;
+        self.store.flush()?;
+        Ok(())
     }
 
     /// Get all signatures stored in the database
     pub fn get_all_signatures(&self) -> Result<Vec<MultiResolutionSignature>, DatabaseError> {
         let mut results = Vec::new();
-        for item in self.db.iter() {
-            let (key, value) = item?;
-            // Skip non-UTF8 keys and index entries
-            if let Ok(key_str) = std::str::from_utf8(&key) {
-                if key_str == "taxonomy_index" || key_str == "lineage_index" {
-                    continue;
-                }
+        self.store
+            .scan_prefix(SIGNATURE_TABLE, b"", &mut |key, value| {
+                // Skip non-UTF8 keys and index entries
+                if let Ok(key_str) = std::str::from_utf8(key) {
+                    if key_str == "taxonomy_index" || key_str == "lineage_index" {
+                        return Ok(());
+                    }
 
-                match decode_from_slice::<MultiResolutionSignature, _>(&value, standard()) {
-                    Ok((signature, _)) => {
-                        if !signature.levels.is_empty() {
-                            results.push(signature);
-                        } else {
-                            warn!("Skipping signature {} with no resolution levels", key_str);
+                    match Self::decode_signature(key_str, value) {
+                        Ok(signature) => {
+                            if !signature.levels.is_empty() {
+                                results.push(signature);
+                            } else {
+                                warn!("Skipping signature {} with no resolution levels", key_str);
+                            }
+                        }
+                        // A newer database is refused rather than read in part
+                        Err(e @ DatabaseError::UnsupportedVersion(_)) => return Err(e),
+                        Err(e) => {
+                            error!(
+                                "Failed to decode signature data for key '{}': {}. Skipping.",
+                                key_str, e
+                            );
                         }
                     }
+                }
+                Ok(())
+            })?;
+        Ok(results)
+    }
+
+    /// Collect summary statistics over all stored signatures, streaming them
+    /// from the store. Ranks come from `taxonomy`, looking each reference up
+    /// by its taxon ID or the last name in its lineage; references it does
+    /// not know (or all of them, without a taxonomy) count as "unresolved".
+    pub fn stats(&self, taxonomy: Option<&Taxonomy>) -> Result<DatabaseStats, DatabaseError> {
+        let mut signature_count = 0;
+        let mut rank_counts: HashMap<String, usize> = HashMap::new();
+        let mut parameter_counts: HashMap<String, usize> = HashMap::new();
+        let mut total_hashes = 0;
+
+        self.store
+            .scan_prefix(SIGNATURE_TABLE, b"", &mut |key, value| {
+                let Ok(key_str) = std::str::from_utf8(key) else {
+                    return Ok(());
+                };
+                if key_str == "taxonomy_index" || key_str == "lineage_index" {
+                    return Ok(());
+                }
+                let signature = match Self::decode_signature(key_str, value) {
+                    Ok(signature) if !signature.levels.is_empty() => signature,
+                    Ok(_) => return Ok(()),
+                    // A newer database is refused rather than summarized in part
+                    Err(e @ DatabaseError::UnsupportedVersion(_)) => return Err(e),
                     Err(e) => {
-                        error!(
-                            "Failed to decode signature data for key '{}': {}. Skipping.",
-                            key_str, e
-                        );
+                        warn!("Skipping undecodable signature '{}': {}", key_str, e);
+                        return Ok(());
                     }
+                };
+                signature_count += 1;
+
+                let rank = taxonomy
+                    .and_then(|taxonomy| {
+                        if let Some(node) = taxonomy.node(&signature.taxon_id) {
+                            return Some(node.rank.clone());
+                        }
+                        let taxid = taxonomy.resolve(signature.lineage.last()?).ok()?;
+                        taxonomy.node(&taxid).map(|node| node.rank.clone())
+                    })
+                    .unwrap_or_else(|| "unresolved".to_string());
+                *rank_counts.entry(rank).or_default() += 1;
+
+                for level in &signature.levels {
+                    let key = format!(
+                        "{} k={} num={} scaled={}",
+                        level.sketch.algorithm,
+                        level.kmer_size,
+                        level.sketch.num_hashes,
+                        level.sketch.scaled
+                    );
+                    *parameter_counts.entry(key).or_default() += 1;
+                    total_hashes += level.sketch.hashes.len();
                 }
-            }
-        }
-        Ok(results)
+                Ok(())
+            })?;
+
+        // Standard ranks from domain to strain, then any others by name
+        let rank_order = |rank: &str| {
+            let rank = if rank == "superkingdom" {
+                "domain"
+            } else {
+                rank
+            };
+            TaxonomicLevel::all_levels()
+                .iter()
+                .position(|level| level.as_str() == rank)
+                .unwrap_or(usize::MAX)
+        };
+        let mut rank_distribution: Vec<(String, usize)> = rank_counts.into_iter().collect();
+        rank_distribution
+            .sort_by(|(a, _), (b, _)| rank_order(a).cmp(&rank_order(b)).then_with(|| a.cmp(b)));
+        let mut sketch_parameters: Vec<(String, usize)> = parameter_counts.into_iter().collect();
+        sketch_parameters.sort();
+
+        // Addition times are only recorded for signatures added since they were tracked
+        let mut oldest_addition: Option<(String, u64)> = None;
+        let mut newest_addition: Option<(String, u64)> = None;
+        self.store
+            .scan_prefix(ADDED_AT_TREE, b"", &mut |key, value| {
+                let Ok(bytes) = <[u8; 8]>::try_from(value) else {
+                    return Ok(());
+                };
+                let added_at = u64::from_be_bytes(bytes);
+                let id = String::from_utf8_lossy(key).to_string();
+                if oldest_addition
+                    .as_ref()
+                    .map_or(true, |(_, t)| added_at < *t)
+                {
+                    oldest_addition = Some((id.clone(), added_at));
+                }
+                if newest_addition
+                    .as_ref()
+                    .map_or(true, |(_, t)| added_at > *t)
+                {
+                    newest_addition = Some((id, added_at));
+                }
+                Ok(())
+            })?;
+
+        Ok(DatabaseStats {
+            signature_count,
+            rank_distribution,
+            sketch_parameters,
+            total_hashes,
+            disk_usage_bytes: self.size_on_disk()?,
+            oldest_addition,
+            newest_addition,
+        })
+    }
+
+    /// Size of the database on disk in bytes
+    pub fn size_on_disk(&self) -> Result<u64, DatabaseError> {
+        self.store.size_on_disk()
     }
 
     /// Get the number of signatures (excluding index entries)
     pub fn count(&self) -> Result<usize, DatabaseError> {
         let mut count = 0;
-        for item in self.db.iter() {
-            let (key, _) = item?;
-            if let Ok(key_str) = std::str::from_utf8(&key) {
-                if key_str != "taxonomy_index" && key_str != "lineage_index" {
+        self.store
+            .scan_prefix(SIGNATURE_TABLE, b"", &mut |key, _| {
+                if key != b"taxonomy_index" && key != b"lineage_index" {
                     count += 1;
                 }
-            }
-        }
+                Ok(())
+            })?;
         Ok(count)
     }
 }
 
+/// How a search term is matched against the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SearchMode {
+    /// Exact taxonomy ID or lineage name
+    #[default]
+    Exact,
+    /// Accession lookup, case-insensitive, version optional
+    Accession,
+    /// Case-insensitive substring of an accession, organism name or lineage term
+    Substring,
+    /// Case-insensitive glob (`*`, `?`) matching a whole name
+    Glob,
+    /// Case-insensitive regular expression
+    Regex,
+}
+
+/// Translate a shell-style glob into an (unanchored) regex pattern
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::with_capacity(glob.len() * 2);
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern
+}
+
+/// Summary of what a signature database contains
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DatabaseStats {
+    /// Number of stored signatures
+    pub signature_count: usize,
+
+    /// Number of references at each taxonomy rank, ordered from domain to
+    /// strain; references the taxonomy does not know are "unresolved"
+    pub rank_distribution: Vec<(String, usize)>,
+
+    /// Number of sketch levels per (algorithm, k, num_hashes, scaled) combination
+    pub sketch_parameters: Vec<(String, usize)>,
+
+    /// Total number of hashes across all levels
+    pub total_hashes: usize,
+
+    /// Size of the database on disk in bytes
+    pub disk_usage_bytes: u64,
+
+    /// Oldest addition as (signature ID, unix seconds), if recorded
+    pub oldest_addition: Option<(String, u64)>,
+
+    /// Newest addition as (signature ID, unix seconds), if recorded
+    pub newest_addition: Option<(String, u64)>,
+}
+
 /// Database manager for coordinating NCBI downloads and signature generation
 pub struct DatabaseManager {
     /// Signature database
@@ -862,31 +2237,82 @@ pub struct DatabaseManager {
 
     /// Signature builder
     pub builder: SignatureBuilder,
+
+    /// Quality filter applied when searching for reference assemblies
+    pub assembly_filter: AssemblyFilter,
+
+    /// Taxon inclusion/exclusion lists applied to every build
+    pub taxon_filter: TaxonFilter,
+
+    /// Sketch references without the hashes found in more than this fraction
+    /// of all references
+    pub max_shared_fraction: Option<f64>,
 }
 
 impl DatabaseManager {
-    /// Create a new database manager
+    /// Stage downloads in `workspace` rather than the genome cache
+    pub fn use_workspace(&mut self, workspace: &Workspace) -> Result<(), DatabaseError> {
+        self.downloader
+            .set_scratch_dir(workspace.subdir("downloads")?);
+        Ok(())
+    }
+
+    /// Create a new database manager that builds signatures with `layout`
     pub fn new(
         db_path: impl AsRef<Path>,
-        cache_dir: impl AsRef<Path>, // Primary k-mer for builder setup (using macro_k based on Init pattern)
-        builder_kmer_size: usize,    // Sketch size for builder setup
-        builder_sketch_size: usize,
-        api_key: Option<String>, // Note: meso_k and threads are removed
+        cache_dir: impl AsRef<Path>,
+        layout: SignatureLayout,
+        api_key: Option<String>,
     ) -> Result<Self, DatabaseError> {
-        let database = SignatureDatabase::open(db_path)?;
-        let downloader = NCBIDownloader::new(cache_dir, api_key, None)?; // Use default expiry for now
+        Self::with_config(
+            db_path,
+            cache_dir,
+            layout,
+            api_key,
+            &DatabaseConfig::default(),
+        )
+    }
 
-        // Ensure the builder is initialized with correct parameters
-        // This depends on the actual SignatureBuilder implementation
-        let builder = SignatureBuilder::new(31, 21, 1000, 1); // Example
+    /// Create a new database manager with explicit storage settings
+    pub fn with_config(
+        db_path: impl AsRef<Path>,
+        cache_dir: impl AsRef<Path>,
+        layout: SignatureLayout,
+        api_key: Option<String>,
+        config: &DatabaseConfig,
+    ) -> Result<Self, DatabaseError> {
+        let database = SignatureDatabase::open_with_config(db_path, config)?;
+        let downloader = NCBIDownloader::new(cache_dir, api_key, None)?; // Use default expiry for now
+        let builder = SignatureBuilder::from_layout(layout)
+            .map_err(|e| DatabaseError::InvalidSignature(e.to_string()))?;
 
         Ok(DatabaseManager {
             database,
             downloader,
-            builder: builder.unwrap(),
+            builder,
+            assembly_filter: AssemblyFilter::default(),
+            taxon_filter: TaxonFilter::default(),
+            max_shared_fraction: None,
         })
     }
 
+    /// Build new signatures with the layout most stored signatures have, so
+    /// references added later stay comparable with the existing ones
+    pub fn adopt_database_layout(&mut self) -> Result<(), DatabaseError> {
+        let signatures = self.database.get_all_signatures()?;
+        if let Some(layout) = SignatureLayout::dominant(&signatures) {
+            if layout != self.builder.layout {
+                info!(
+                    "Using the database's signature layout (k = {:?})",
+                    layout.kmer_sizes()
+                );
+                self.builder = SignatureBuilder::from_layout(layout)
+                    .map_err(|e| DatabaseError::InvalidSignature(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
     /// Search for and download reference genomes from NCBI
     pub fn download_references(
         &self,
@@ -897,8 +2323,29 @@ impl DatabaseManager {
             "Starting reference download for query: '{}', max_results: {}",
             query, max_results
         );
-        // Search for matching genomes
-        let genomes = self.downloader.search_genomes(query, max_results)?;
+        // Search for matching genomes that pass the assembly quality filter
+        let query = self.taxon_filter.restrict_query(query);
+        let mut genomes =
+            self.downloader
+                .search_genomes_filtered(&query, max_results, &self.assembly_filter)?;
+        if !self.taxon_filter.is_empty() {
+            genomes.retain(|genome| {
+                let allowed = self.taxon_filter.allows(genome);
+                if !allowed {
+                    info!("Skipping {}: excluded by taxon lists", genome.accession);
+                }
+                allowed
+            });
+        }
+        if self.assembly_filter.prefer_representative {
+            let found = genomes.len();
+            genomes = select_canonical_genomes(genomes);
+            info!(
+                "Kept {} canonical genomes out of {} after species deduplication",
+                genomes.len(),
+                found
+            );
+        }
         if genomes.is_empty() {
             info!("No genomes found matching the query.");
             return Ok(Vec::new());
@@ -949,6 +2396,23 @@ impl DatabaseManager {
             info!("No references to process.");
             return Ok(Vec::new());
         }
+        // Apply the taxon lists here as well so local builds honour them
+        let references: Vec<(GenomeMetadata, PathBuf)> = references
+            .into_iter()
+            .filter(|(metadata, _)| {
+                if !self.taxon_filter.is_empty() && metadata.lineage.is_empty() {
+                    warn!(
+                        "{} has no lineage; taxon lists only match its taxid {}",
+                        metadata.accession, metadata.taxid
+                    );
+                }
+                let allowed = self.taxon_filter.allows(metadata);
+                if !allowed {
+                    info!("Skipping {}: excluded by taxon lists", metadata.accession);
+                }
+                allowed
+            })
+            .collect();
         info!(
             "Processing {} downloaded references into signatures...",
             references.len()
@@ -972,36 +2436,73 @@ impl DatabaseManager {
             })
             .collect::<Vec<_>>();
 
+        // References already stored were sketched without these
+        let stored_common = self.database.common_hashes()?;
+        if let Some(common) = &stored_common {
+            let builder_common = self
+                .builder
+                .common_hashes
+                .get_or_insert_with(Default::default);
+            builder_common.merge(common);
+        }
+
         // Build signatures in batch
         // This can be computationally intensive
         info!("Starting signature batch build...");
-        let signatures = self.builder.build_batch(files_for_builder).map_err(|e| {
-            DatabaseError::SignatureError(format!("Signature building failed: {}", e))
-        })?;
+        let mut signatures = self
+            .builder
+            .build_batch(files_for_builder.clone())
+            .map_err(|e| {
+                DatabaseError::SignatureError(format!("Signature building failed: {}", e))
+            })?;
         info!("Successfully built {} signatures.", signatures.len());
 
-        // Add signatures to database
-        let mut added_ids = Vec::with_capacity(signatures.len());
-        for signature in signatures {
-            // Add signature and handle potential errors (e.g., DB error)
-            match self.database.add_signature(&signature) {
-                Ok(_) => added_ids.push(signature.taxon_id.clone()),
-                Err(e) => {
-                    error!(
-                        "Failed to add signature {} to database: {}",
-                        signature.taxon_id, e
-                    );
-                    // Decide whether to stop or continue
-                    return Err(e); // Stop processing on DB error
-                }
+        if let Some(fraction) = self.max_shared_fraction {
+            let mut all = self.database.get_all_signatures()?;
+            all.extend(signatures.iter().cloned());
+            let mut common = CommonHashes::from_signatures(&all, fraction);
+            if let Some(stored) = &stored_common {
+                common.merge(stored);
             }
+            let already_excluded = self
+                .builder
+                .common_hashes
+                .as_ref()
+                .is_some_and(|excluded| excluded.contains_all(&common));
+            if !already_excluded {
+                info!(
+                    "Re-sketching without {} k-mer hashes shared by more than {:.0}% of {} references",
+                    common.len(),
+                    fraction * 100.0,
+                    common.reference_count
+                );
+                self.builder.common_hashes = Some(common.clone());
+                signatures = self.builder.build_batch(files_for_builder).map_err(|e| {
+                    DatabaseError::SignatureError(format!("Signature building failed: {}", e))
+                })?;
+            }
+            if !common.is_empty() {
+                self.database.save_common_hashes(&common)?;
+            }
+        }
+
+        // Add signatures to database in one bulk write
+        let report = self.database.add_signatures_bulk(signatures)?;
+        for (id, existing) in &report.duplicates {
+            info!(
+                "Skipped {}: identical to existing signature {}",
+                id, existing
+            );
+        }
+        for (id, reason) in &report.invalid {
+            error!("Failed to add signature {} to database: {}", id, reason);
         }
 
         info!(
             "Successfully added {} new signatures to the database.",
-            added_ids.len()
+            report.added.len()
         );
-        Ok(added_ids)
+        Ok(report.added)
     }
 
     /// Search, download, and process reference genomes by query
@@ -1019,6 +2520,23 @@ impl DatabaseManager {
         self.process_references(references)
     }
 
+    /// Sketch a user MAG from a FASTA file and add it with its quality metadata
+    pub fn add_mag(
+        &mut self,
+        path: impl AsRef<Path>,
+        id: &str,
+        lineage: Vec<String>,
+        metadata: &MagMetadata,
+    ) -> Result<InsertOutcome, DatabaseError> {
+        let signature = self
+            .builder
+            .build_from_file(path.as_ref(), id, lineage)
+            .map_err(|e| {
+                DatabaseError::SignatureError(format!("Failed to sketch {}: {}", id, e))
+            })?;
+        self.database.add_mag(&signature, metadata)
+    }
+
     /// Check if the database contains any signatures
     pub fn is_empty(&self) -> Result<bool, DatabaseError> {
         Ok(self.database.count()? == 0)
@@ -1053,6 +2571,7 @@ mod tests {
             api_key: None,
             cache_dir: cache_dir.clone(),
             cache_expiry_days: 30,
+            scratch_dir: None,
         };
         (downloader, temp_dir.into_path()) // Return path to keep temp dir alive
     }
@@ -1166,6 +2685,7 @@ mod tests {
         assert_eq!(metadata.release_date, "2014/08/25 00:00"); // Check correct date field used
         assert_eq!(metadata.size, 4641652);
         assert_eq!(metadata.gc_content, 50.79);
+        assert!(metadata.quality_flags.is_empty());
 
         // Verify parsed lineage (includes the main taxon added at the end)
         let expected_lineage = vec![
@@ -1190,7 +2710,8 @@ mod tests {
         let cache_dir = temp_dir.path().join("test_manager_cache");
 
         // Create the database manager (uses dummy builder)
-        let manager = DatabaseManager::new(&db_path, &cache_dir, 31, 500, None); // k=31, size=500
+        let layout = SignatureLayout::minhash(&[31, 21], 500);
+        let manager = DatabaseManager::new(&db_path, &cache_dir, layout, None);
         assert!(manager.is_ok());
         let manager = manager.unwrap();
 
@@ -1201,6 +2722,650 @@ mod tests {
         // For now, this confirms basic setup.
     }
 
+    #[test]
+    fn test_database_stats() {
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let mut database = SignatureDatabase::open(temp_dir.path().join("stats_db")).unwrap();
+
+        let lineages = [
+            vec![
+                "Bacteria",
+                "Pseudomonadota",
+                "Gammaproteobacteria",
+                "Enterobacterales",
+                "Enterobacteriaceae",
+                "Escherichia",
+                "Escherichia coli",
+            ],
+            vec![
+                "Bacteria",
+                "Bacillota",
+                "Bacilli",
+                "Bacillales",
+                "Staphylococcaceae",
+                "Staphylococcus",
+            ],
+        ];
+        for (i, lineage) in lineages.iter().enumerate() {
+            let mut signature = MultiResolutionSignature::new(
+                format!("GCF_{}", i),
+                lineage.iter().map(|s| s.to_string()).collect(),
+            );
+            let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 3, 0).build();
+            level.sketch.hashes = vec![1, 2, 3];
+            signature.add_level(level);
+            database.add_signature(&signature).unwrap();
+        }
+
+        // Ranks come from the taxonomy, not from how long a lineage is
+        let taxdump = temp_dir.path().join("taxdump");
+        fs::create_dir_all(&taxdump).unwrap();
+        fs::write(
+            taxdump.join("nodes.dmp"),
+            "1\t|\t1\t|\tno rank\t|\n561\t|\t1\t|\tgenus\t|\n\
+             562\t|\t561\t|\tspecies\t|\n1279\t|\t1\t|\tgenus\t|\n",
+        )
+        .unwrap();
+        fs::write(
+            taxdump.join("names.dmp"),
+            "561\t|\tEscherichia\t|\t\t|\tscientific name\t|\n\
+             562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|\n\
+             1279\t|\tStaphylococcus\t|\t\t|\tscientific name\t|\n",
+        )
+        .unwrap();
+        let taxonomy = Taxonomy::from_taxdump(&taxdump).unwrap();
+
+        let stats = database.stats(Some(&taxonomy)).unwrap();
+        assert_eq!(stats.signature_count, 2);
+        assert_eq!(stats.total_hashes, 6);
+        assert_eq!(
+            stats.rank_distribution,
+            vec![("genus".to_string(), 1), ("species".to_string(), 1)]
+        );
+        assert_eq!(
+            database.stats(None).unwrap().rank_distribution,
+            vec![("unresolved".to_string(), 2)]
+        );
+        assert_eq!(
+            stats.sketch_parameters,
+            vec![("minhash k=31 num=3 scaled=0".to_string(), 2)]
+        );
+        assert!(stats.oldest_addition.is_some());
+        assert!(stats.newest_addition.is_some());
+        // The timestamp tree must not be counted as a signature
+        assert_eq!(database.count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_search_modes() {
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let mut database = SignatureDatabase::open(temp_dir.path().join("search_db")).unwrap();
+
+        for (id, lineage) in [
+            (
+                "GCF_000005845.2",
+                vec!["Bacteria", "Escherichia", "Escherichia coli"],
+            ),
+            (
+                "GCF_000013425.1",
+                vec!["Bacteria", "Staphylococcus", "Staphylococcus aureus"],
+            ),
+            (
+                "GCF_000006765.1",
+                vec!["Bacteria", "Pseudomonas", "Pseudomonas aeruginosa"],
+            ),
+        ] {
+            let mut signature = MultiResolutionSignature::new(
+                id.to_string(),
+                lineage.iter().map(|s| s.to_string()).collect(),
+            );
+            let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 3, 0).build();
+            level.sketch.hashes = vec![1, 2, 3];
+            signature.add_level(level);
+            database.add_signature(&signature).unwrap();
+        }
+
+        let ids = |results: Vec<MultiResolutionSignature>| -> Vec<String> {
+            results.into_iter().map(|s| s.taxon_id).collect()
+        };
+
+        assert_eq!(
+            ids(database
+                .search("gcf_000005845", SearchMode::Accession)
+                .unwrap()),
+            vec!["GCF_000005845.2"]
+        );
+        assert_eq!(
+            ids(database.search("AUREUS", SearchMode::Substring).unwrap()),
+            vec!["GCF_000013425.1"]
+        );
+        assert_eq!(
+            ids(database.search("*coli", SearchMode::Glob).unwrap()),
+            vec!["GCF_000005845.2"]
+        );
+        assert_eq!(
+            ids(database
+                .search("^(staph|pseudo)", SearchMode::Regex)
+                .unwrap()),
+            vec!["GCF_000006765.1", "GCF_000013425.1"]
+        );
+        assert_eq!(
+            database
+                .search("bacteria", SearchMode::Substring)
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(database
+            .search("coli", SearchMode::Exact)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            database.search("(unclosed", SearchMode::Regex),
+            Err(DatabaseError::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_signatures_skipped_and_deduped() {
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let mut database = SignatureDatabase::open(temp_dir.path().join("dedupe_db")).unwrap();
+
+        let make_signature = |id: &str, hashes: Vec<u64>| {
+            let mut signature =
+                MultiResolutionSignature::new(id.to_string(), vec!["Escherichia coli".to_string()]);
+            let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 3, 0).build();
+            level.sketch.hashes = hashes;
+            signature.add_level(level);
+            signature
+        };
+
+        let original = make_signature("GCF_000005845.2", vec![1, 2, 3]);
+        let copy = make_signature("GCA_000005845.2", vec![3, 2, 1]);
+        assert_eq!(
+            database.add_signature(&original).unwrap(),
+            InsertOutcome::Added
+        );
+        assert_eq!(
+            database.add_signature(&copy).unwrap(),
+            InsertOutcome::Duplicate("GCF_000005845.2".to_string())
+        );
+        // Re-adding under the same ID overwrites rather than counting as a duplicate
+        assert_eq!(
+            database.add_signature(&original).unwrap(),
+            InsertOutcome::Added
+        );
+        assert_eq!(database.count().unwrap(), 1);
+
+        // Simulate a duplicate that predates content hashing
+        database
+            .store
+            .insert(
+                SIGNATURE_TABLE,
+                copy.taxon_id.as_bytes(),
+                &encode_to_vec(&copy, standard()).unwrap(),
+            )
+            .unwrap();
+        database.update_indices(&copy).unwrap();
+        assert_eq!(database.count().unwrap(), 2);
+
+        let report = database.dedupe(true).unwrap();
+        assert_eq!(
+            report.groups,
+            vec![(
+                "GCA_000005845.2".to_string(),
+                vec!["GCF_000005845.2".to_string()]
+            )]
+        );
+        assert!(report.bytes_reclaimed > 0);
+        assert_eq!(database.count().unwrap(), 2);
+
+        database.dedupe(false).unwrap();
+        assert_eq!(database.count().unwrap(), 1);
+        assert!(database
+            .search_by_taxonomy("GCF_000005845.2")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            database
+                .search_by_taxonomy("Escherichia coli")
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_overwritten_signatures_release_their_old_entries() {
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let mut database = SignatureDatabase::open(temp_dir.path().join("overwrite_db")).unwrap();
+
+        let make_signature = |id: &str, lineage: &str, hashes: Vec<u64>| {
+            let mut signature =
+                MultiResolutionSignature::new(id.to_string(), vec![lineage.to_string()]);
+            let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 3, 0).build();
+            level.sketch.hashes = hashes;
+            signature.add_level(level);
+            signature
+        };
+
+        let first = make_signature("GCF_1", "Escherichia coli", vec![1, 2, 3]);
+        let replacement = make_signature("GCF_1", "Shigella flexneri", vec![4, 5, 6]);
+        let other = make_signature("GCF_2", "Escherichia coli", vec![1, 2, 3]);
+        assert_eq!(
+            database.add_signature(&first).unwrap(),
+            InsertOutcome::Added
+        );
+        assert_eq!(
+            database.add_signature(&replacement).unwrap(),
+            InsertOutcome::Added
+        );
+
+        // GCF_1 no longer holds the old contents, so they are not a duplicate
+        assert_eq!(
+            database.add_signature(&other).unwrap(),
+            InsertOutcome::Added
+        );
+        assert_eq!(
+            database
+                .add_signature(&make_signature("GCF_3", "x", vec![4, 5, 6]))
+                .unwrap(),
+            InsertOutcome::Duplicate("GCF_1".to_string())
+        );
+        let ids = |term: &str| -> Vec<String> {
+            let mut ids: Vec<String> = database
+                .search_by_taxonomy(term)
+                .unwrap()
+                .into_iter()
+                .map(|signature| signature.taxon_id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids("Escherichia coli"), vec!["GCF_2"]);
+        assert_eq!(ids("Shigella flexneri"), vec!["GCF_1"]);
+
+        // The same holds for bulk inserts, and across reopening
+        let report = database
+            .add_signatures_bulk(vec![make_signature("GCF_2", "Salmonella", vec![7, 8, 9])])
+            .unwrap();
+        assert_eq!(report.added, vec!["GCF_2"]);
+        drop(database);
+        let mut database = SignatureDatabase::open(temp_dir.path().join("overwrite_db")).unwrap();
+        assert!(database
+            .search_by_taxonomy("Escherichia coli")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            database
+                .add_signature(&make_signature("GCF_4", "Escherichia coli", vec![1, 2, 3]))
+                .unwrap(),
+            InsertOutcome::Added
+        );
+    }
+
+    #[test]
+    fn test_mag_metadata_stored_and_removed() {
+        use crate::database::mag::MagQuality;
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let mut database = SignatureDatabase::open(temp_dir.path().join("mag_db")).unwrap();
+
+        let mut signature = MultiResolutionSignature::new(
+            "bin.7".to_string(),
+            vec!["Bacteroides fragilis".to_string()],
+        );
+        let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 3, 0).build();
+        level.sketch.hashes = vec![4, 5, 6];
+        signature.add_level(level);
+        let metadata = MagMetadata {
+            completeness: Some(71.0),
+            contamination: Some(2.5),
+            strain_heterogeneity: None,
+            source: Some("checkm.tsv".to_string()),
+        };
+
+        assert_eq!(
+            database.add_mag(&signature, &metadata).unwrap(),
+            InsertOutcome::Added
+        );
+        let mags = database.mag_metadata().unwrap();
+        assert_eq!(mags["bin.7"], metadata);
+        assert_eq!(mags["bin.7"].quality(), MagQuality::Medium);
+
+        database.remove_signature("bin.7").unwrap();
+        assert!(database.mag_metadata().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_extract_lineages_into_panel() {
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let mut database = SignatureDatabase::open(temp_dir.path().join("full_db")).unwrap();
+
+        let make_signature = |id: &str, lineage: &[&str], hashes: Vec<u64>| {
+            let mut signature = MultiResolutionSignature::new(
+                id.to_string(),
+                lineage.iter().map(|s| s.to_string()).collect(),
+            );
+            let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 3, 0).build();
+            level.sketch.hashes = hashes;
+            signature.add_level(level);
+            signature
+        };
+        let entero = ["Bacteria", "Enterobacteriaceae", "Escherichia coli"];
+        database
+            .add_signature(&make_signature("GCF_000005845.2", &entero, vec![1, 2, 3]))
+            .unwrap();
+        database
+            .add_signature(&make_signature(
+                "GCF_000013425.1",
+                &["Bacteria", "Staphylococcaceae", "Staphylococcus aureus"],
+                vec![4, 5, 6],
+            ))
+            .unwrap();
+        let metadata = MagMetadata {
+            completeness: Some(95.0),
+            contamination: Some(1.0),
+            ..MagMetadata::default()
+        };
+        database
+            .add_mag(&make_signature("bin.3", &entero, vec![7, 8, 9]), &metadata)
+            .unwrap();
+
+        let mut panel = SignatureDatabase::open(temp_dir.path().join("entero_db")).unwrap();
+        let report = database
+            .extract_lineages(&["enterobacteriaceae".to_string()], &mut panel)
+            .unwrap();
+        assert_eq!(report.added, vec!["GCF_000005845.2", "bin.3"]);
+        assert_eq!(panel.count().unwrap(), 2);
+        assert_eq!(panel.mag_metadata().unwrap()["bin.3"], metadata);
+        assert!(panel
+            .search_by_taxonomy("Staphylococcus aureus")
+            .unwrap()
+            .is_empty());
+
+        // Signature IDs select single references
+        let mut single = SignatureDatabase::open(temp_dir.path().join("single_db")).unwrap();
+        let report = database
+            .extract_lineages(&["GCF_000013425.1".to_string()], &mut single)
+            .unwrap();
+        assert_eq!(report.added, vec!["GCF_000013425.1"]);
+    }
+
+    #[test]
+    fn test_built_references_and_samples_share_a_layout() {
+        use crate::sketch::Comparable;
+
+        let temp_dir = create_temp_dir();
+        // A pseudo-random genome, long enough for every k
+        let mut state = 12345u64;
+        let genome: Vec<u8> = (0..5000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                b"ACGT"[(state >> 62) as usize]
+            })
+            .collect();
+        let fasta = temp_dir.path().join("genome.fna");
+        let mut record = b">genome\n".to_vec();
+        record.extend_from_slice(&genome);
+        record.push(b'\n');
+        fs::write(&fasta, record).unwrap();
+
+        let layout = SignatureLayout::minhash(&[25, 17], 200);
+        let db_path = temp_dir.path().join("layout_db");
+        let cache_dir = temp_dir.path().join("layout_cache");
+        let mut manager = DatabaseManager::new(&db_path, &cache_dir, layout.clone(), None).unwrap();
+        let reference = manager
+            .builder
+            .build_from_file(&fasta, "GCF_1", vec!["Bacteria".to_string()])
+            .unwrap();
+        assert_eq!(reference.layout(), layout);
+        manager.database.add_signature(&reference).unwrap();
+        let stored = manager.database.get_signature("GCF_1").unwrap();
+        assert_eq!(stored.layout(), layout);
+        assert_eq!(stored.content_hash(), reference.content_hash());
+
+        // A sample of the same genome, sketched with the stored layout, matches at every level
+        let mut sample = MultiResolutionSignature::with_layout(
+            "sample".to_string(),
+            Vec::new(),
+            &SignatureLayout::dominant(&[stored.clone()]).unwrap(),
+        );
+        for level in &mut sample.levels {
+            level.add_sequence(&genome).unwrap();
+        }
+        for (sample_level, reference_level) in sample.levels.iter().zip(&stored.levels) {
+            assert_eq!(sample_level.jaccard(reference_level), Some(1.0));
+        }
+
+        // Managers opened with other parameters switch to the database's layout
+        drop(manager);
+        let mut manager =
+            DatabaseManager::new(&db_path, &cache_dir, SignatureLayout::default(), None).unwrap();
+        manager.adopt_database_layout().unwrap();
+        assert_eq!(manager.builder.layout, layout);
+    }
+
+    #[test]
+    fn test_compressed_and_plain_signatures_round_trip() {
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let mut signature = MultiResolutionSignature::new(
+            "GCF_000005845.2".to_string(),
+            vec!["Escherichia coli".to_string()],
+        );
+        let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 1000, 0).build();
+        level.sketch.hashes = (0..1000u64).map(|i| i * 7919).collect();
+        signature.add_level(level);
+
+        let plain_config = DatabaseConfig {
+            compress_signatures: false,
+            ..DatabaseConfig::default()
+        };
+        let db_path = temp_dir.path().join("compression_db");
+        {
+            let mut database =
+                SignatureDatabase::open_with_config(&db_path, &plain_config).unwrap();
+            database.add_signature(&signature).unwrap();
+        }
+
+        // Reopen with compression: old plain blobs stay readable, new ones are compressed
+        let mut database = SignatureDatabase::open(&db_path).unwrap();
+        let plain = database.get_signature("GCF_000005845.2").unwrap();
+        assert_eq!(
+            plain.levels[0].sketch.hashes,
+            signature.levels[0].sketch.hashes
+        );
+
+        let compressed = database.encode_signature(&signature).unwrap();
+        assert!(compressed.starts_with(&RECORD_MAGIC));
+        assert!(compressed[RECORD_HEADER_LEN..].starts_with(&ZSTD_MAGIC));
+        assert!(compressed.len() < encode_to_vec(&signature, standard()).unwrap().len());
+
+        // Records from a newer build are refused with their version, not misread
+        let mut newer = compressed.clone();
+        newer[RECORD_MAGIC.len()..RECORD_HEADER_LEN].copy_from_slice(&2u32.to_le_bytes());
+        match SignatureDatabase::decode_signature("GCF_000005845.2", &newer) {
+            Err(DatabaseError::UnsupportedVersion(e)) => {
+                assert_eq!(e.found, 2);
+                assert_eq!(e.artifact, Artifact::DatabaseRecord);
+            }
+            other => panic!("expected an unsupported version, got {:?}", other),
+        }
+
+        signature.taxon_id = "GCF_000005845.3".to_string();
+        signature.levels[0].sketch.hashes.push(1);
+        database.add_signature(&signature).unwrap();
+        let round_trip = database.get_signature("GCF_000005845.3").unwrap();
+        assert_eq!(
+            round_trip.levels[0].sketch.hashes,
+            signature.levels[0].sketch.hashes
+        );
+    }
+
+    #[test]
+    fn test_indices_persist_per_entry_and_migrate_legacy_blobs() {
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let db_path = temp_dir.path().join("index_db");
+
+        let mut signature = MultiResolutionSignature::new(
+            "GCF_000005845.2".to_string(),
+            vec!["Bacteria".to_string(), "Escherichia coli".to_string()],
+        );
+        let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 3, 0).build();
+        level.sketch.hashes = vec![1, 2, 3];
+        signature.add_level(level);
+
+        {
+            let database = SignatureDatabase::open(&db_path).unwrap();
+            // Write the signature and an old-style serialized lineage index by hand
+            database
+                .store
+                .insert(
+                    SIGNATURE_TABLE,
+                    signature.taxon_id.as_bytes(),
+                    &database.encode_signature(&signature).unwrap(),
+                )
+                .unwrap();
+            let mut legacy: HashMap<String, HashSet<String>> = HashMap::new();
+            legacy
+                .entry("Escherichia coli".to_string())
+                .or_default()
+                .insert(signature.taxon_id.clone());
+            database
+                .store
+                .insert(
+                    SIGNATURE_TABLE,
+                    b"lineage_index",
+                    &encode_to_vec(&legacy, standard()).unwrap(),
+                )
+                .unwrap();
+        }
+
+        {
+            let database = SignatureDatabase::open(&db_path).unwrap();
+            assert_eq!(
+                database
+                    .search_by_taxonomy("Escherichia coli")
+                    .unwrap()
+                    .len(),
+                1
+            );
+            assert!(database
+                .store
+                .get(SIGNATURE_TABLE, b"lineage_index")
+                .unwrap()
+                .is_none());
+        }
+
+        // Indices written by add_signature survive a reopen
+        let mut database = SignatureDatabase::open(&db_path).unwrap();
+        signature.taxon_id = "GCF_000005845.3".to_string();
+        signature.levels[0].sketch.hashes.push(4);
+        database.add_signature(&signature).unwrap();
+        drop(database);
+
+        let database = SignatureDatabase::open(&db_path).unwrap();
+        assert_eq!(
+            database
+                .search_by_taxonomy("Escherichia coli")
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            database
+                .search_by_taxonomy("GCF_000005845.3")
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_add_signatures_bulk() {
+        use crate::sketch::signature::KmerSignatureBuilder;
+
+        let temp_dir = create_temp_dir();
+        let db_path = temp_dir.path().join("bulk_db");
+        let mut database = SignatureDatabase::open(&db_path).unwrap();
+
+        let make_signature = |id: String, hashes: Vec<u64>| {
+            let mut signature = MultiResolutionSignature::new(
+                id,
+                vec!["Bacteria".to_string(), "Escherichia coli".to_string()],
+            );
+            let mut level = KmerSignatureBuilder::new(31, "DNA", "minhash", 3, 0).build();
+            level.sketch.hashes = hashes;
+            signature.add_level(level);
+            signature
+        };
+
+        // Enough signatures to span more than one storage batch
+        let mut signatures: Vec<MultiResolutionSignature> = (0..BULK_BATCH_SIZE as u64 + 5)
+            .map(|i| make_signature(format!("GCF_{:09}.1", i), vec![i, i + 1, i + 2]))
+            .collect();
+        // An in-input duplicate and an invalid signature
+        signatures.push(make_signature("GCA_000000000.1".to_string(), vec![0, 1, 2]));
+        signatures.push(MultiResolutionSignature::new(
+            "GCF_EMPTY".to_string(),
+            vec![],
+        ));
+        // New versions of an ID from an already written batch and of one still
+        // pending; the replaced content no longer counts as a duplicate
+        let last = format!("GCF_{:09}.1", BULK_BATCH_SIZE + 4);
+        for (i, id) in ["GCF_000000001.1".to_string(), last]
+            .into_iter()
+            .enumerate()
+        {
+            let mut signature = make_signature(id, vec![1_000_000 + i as u64]);
+            signature.lineage[1] = "Salmonella enterica".to_string();
+            signatures.push(signature);
+        }
+        signatures.push(make_signature("GCA_000000001.1".to_string(), vec![1, 2, 3]));
+
+        let report = database.add_signatures_bulk(signatures).unwrap();
+        assert_eq!(report.added.len(), BULK_BATCH_SIZE + 6);
+        assert_eq!(
+            report.duplicates,
+            vec![("GCA_000000000.1".to_string(), "GCF_000000000.1".to_string())]
+        );
+        assert_eq!(report.invalid.len(), 1);
+        assert_eq!(database.count().unwrap(), BULK_BATCH_SIZE + 6);
+        drop(database);
+
+        let database = SignatureDatabase::open(&db_path).unwrap();
+        assert_eq!(
+            database
+                .search_by_taxonomy("Escherichia coli")
+                .unwrap()
+                .len(),
+            BULK_BATCH_SIZE + 4
+        );
+        assert_eq!(
+            database
+                .search_by_taxonomy("Salmonella enterica")
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(database.stats(None).unwrap().newest_addition.is_some());
+    }
+
     #[test]
     fn test_genome_metadata_serialization() {
         let metadata = GenomeMetadata {
@@ -1216,6 +3381,12 @@ mod tests {
                 ("2".to_string(), "Bacteria".to_string()),
                 ("1224".to_string(), "Proteobacteria".to_string()),
             ],
+            contig_n50: 4641652,
+            contig_count: 1,
+            quality_flags: Vec::new(),
+            species_taxid: "562".to_string(),
+            refseq_category: "reference genome".to_string(),
+            from_type: false,
         };
 
         // Serialize and deserialize
@@ -1233,6 +3404,237 @@ mod tests {
         assert_eq!(deserialized.size, metadata.size);
         assert_eq!(deserialized.gc_content, metadata.gc_content);
         assert_eq!(deserialized.lineage, metadata.lineage);
+        assert_eq!(deserialized.contig_n50, metadata.contig_n50);
+        assert_eq!(deserialized.contig_count, metadata.contig_count);
+        assert_eq!(deserialized.refseq_category, metadata.refseq_category);
+    }
+
+    #[test]
+    fn test_taxon_filter_lists() {
+        let dir = tempdir().unwrap();
+        let include_path = dir.path().join("eskape.txt");
+        let exclude_path = dir.path().join("exclude.txt");
+        fs::write(
+            &include_path,
+            "# ESKAPE genera\n1350\tEnterococcus\n1279 Staphylococcus\n\n",
+        )
+        .unwrap();
+        fs::write(&exclude_path, "1280\n").unwrap();
+
+        let filter =
+            TaxonFilter::from_files(Some(&include_path), Some(&exclude_path), None).unwrap();
+        assert_eq!(filter.include.as_ref().unwrap().len(), 2);
+
+        let mut metadata = GenomeMetadata {
+            accession: "GCF_000013425.1".to_string(),
+            assembly_id: String::new(),
+            organism: "Staphylococcus epidermidis".to_string(),
+            taxid: "1282".to_string(),
+            assembly_level: "Complete Genome".to_string(),
+            release_date: String::new(),
+            size: 0,
+            gc_content: 0.0,
+            lineage: vec![
+                ("2".to_string(), "Bacteria".to_string()),
+                ("1279".to_string(), "Staphylococcus".to_string()),
+            ],
+            contig_n50: 0,
+            contig_count: 0,
+            quality_flags: Vec::new(),
+            species_taxid: "1282".to_string(),
+            refseq_category: "na".to_string(),
+            from_type: false,
+        };
+        assert!(filter.allows(&metadata));
+
+        // Excluded species inside an included genus
+        metadata.species_taxid = "1280".to_string();
+        assert!(!filter.allows(&metadata));
+
+        // Outside the panel
+        metadata.species_taxid = "562".to_string();
+        metadata.lineage = vec![("561".to_string(), "Escherichia".to_string())];
+        assert!(!filter.allows(&metadata));
+
+        assert_eq!(
+            filter.restrict_query("bacteria"),
+            "(bacteria) AND (txid1279[Organism:exp] OR txid1350[Organism:exp])"
+        );
+
+        // An include list without any taxa does not filter
+        fs::write(&include_path, "# nothing yet\n\n").unwrap();
+        let filter = TaxonFilter::from_files(Some(&include_path), None, None).unwrap();
+        assert!(filter.include.is_none());
+        assert!(filter.is_empty());
+        assert!(filter.allows(&metadata));
+
+        fs::write(&exclude_path, "not-a-taxid\n").unwrap();
+        assert!(TaxonFilter::from_files(None, Some(&exclude_path), None).is_err());
+
+        // Names are resolved to taxids through a taxdump
+        let taxdump = dir.path().join("taxdump");
+        fs::create_dir_all(&taxdump).unwrap();
+        fs::write(
+            taxdump.join("nodes.dmp"),
+            "1\t|\t1\t|\tno rank\t|\n1279\t|\t1\t|\tgenus\t|\n1280\t|\t1279\t|\tspecies\t|\n",
+        )
+        .unwrap();
+        fs::write(
+            taxdump.join("names.dmp"),
+            "1279\t|\tStaphylococcus\t|\t\t|\tscientific name\t|\n\
+             1280\t|\tStaphylococcus aureus\t|\t\t|\tscientific name\t|\n",
+        )
+        .unwrap();
+        let taxonomy = Taxonomy::from_taxdump(&taxdump).unwrap();
+        fs::write(&exclude_path, "staphylococcus aureus\t# MRSA\n").unwrap();
+        let filter = TaxonFilter::from_files(None, Some(&exclude_path), Some(&taxonomy)).unwrap();
+        assert!(filter.exclude.contains("1280"));
+        fs::write(&exclude_path, "Staphylococus aureus\n").unwrap();
+        let error = TaxonFilter::from_files(None, Some(&exclude_path), Some(&taxonomy))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("did you mean Staphylococcus aureus?"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_select_canonical_genomes() {
+        let genome =
+            |accession: &str, species: &str, category: &str, from_type: bool| GenomeMetadata {
+                accession: accession.to_string(),
+                assembly_id: String::new(),
+                organism: String::new(),
+                taxid: species.to_string(),
+                assembly_level: "Complete Genome".to_string(),
+                release_date: "2020/01/01 00:00".to_string(),
+                size: 0,
+                gc_content: 0.0,
+                lineage: Vec::new(),
+                contig_n50: 0,
+                contig_count: 0,
+                quality_flags: Vec::new(),
+                species_taxid: species.to_string(),
+                refseq_category: category.to_string(),
+                from_type,
+            };
+
+        let selected = select_canonical_genomes(vec![
+            genome("GCF_1", "562", "na", false),
+            genome("GCF_2", "562", "na", true),
+            genome("GCF_3", "562", "reference genome", false),
+            genome("GCF_4", "1280", "na", false),
+            genome("GCF_5", "1280", "na", true),
+        ]);
+
+        let accessions: Vec<&str> = selected.iter().map(|g| g.accession.as_str()).collect();
+        assert_eq!(accessions, vec!["GCF_3", "GCF_5"]);
+    }
+
+    #[test]
+    fn test_assembly_filter_check() {
+        let mut metadata = GenomeMetadata {
+            accession: "GCF_000001234.1".to_string(),
+            assembly_id: "54321".to_string(),
+            organism: "Escherichia coli".to_string(),
+            taxid: "562".to_string(),
+            assembly_level: "Contig".to_string(),
+            release_date: "2020-01-01".to_string(),
+            size: 5000000,
+            gc_content: 50.5,
+            lineage: Vec::new(),
+            contig_n50: 20000,
+            contig_count: 450,
+            quality_flags: Vec::new(),
+            species_taxid: "562".to_string(),
+            refseq_category: "na".to_string(),
+            from_type: false,
+        };
+
+        // Default filter only accepts complete genomes
+        let default_filter = AssemblyFilter::default();
+        assert!(default_filter.check(&metadata).is_err());
+
+        let filter = AssemblyFilter {
+            assembly_levels: vec![AssemblyLevel::Contig, AssemblyLevel::Scaffold],
+            min_contig_n50: Some(50000),
+            max_contig_count: Some(200),
+            exclude_anomalous: true,
+            prefer_representative: false,
+        };
+        assert!(filter.check(&metadata).unwrap_err().contains("N50"));
+
+        metadata.contig_n50 = 80000;
+        assert!(filter.check(&metadata).unwrap_err().contains("contigs"));
+
+        metadata.contig_count = 120;
+        assert!(filter.check(&metadata).is_ok());
+
+        metadata.quality_flags = vec!["anomalous: fragmented assembly".to_string()];
+        assert!(filter.check(&metadata).unwrap_err().contains("flagged"));
+    }
+
+    #[test]
+    fn test_complete_alias_selects_complete_genomes() {
+        let level: AssemblyLevel = "complete".parse().unwrap();
+        assert_eq!(level, AssemblyLevel::Complete);
+        assert_eq!(
+            "Complete Genome".parse::<AssemblyLevel>(),
+            Ok(AssemblyLevel::Complete)
+        );
+        assert!("complete-ish".parse::<AssemblyLevel>().is_err());
+
+        let filter = AssemblyFilter {
+            assembly_levels: vec![level],
+            ..AssemblyFilter::default()
+        };
+        assert_eq!(filter.search_term(), "+AND+(\"complete genome\"[filter])");
+        let metadata = GenomeMetadata {
+            accession: "GCF_000005845.2".to_string(),
+            assembly_id: "79781".to_string(),
+            organism: "Escherichia coli K-12".to_string(),
+            taxid: "511145".to_string(),
+            assembly_level: "Complete Genome".to_string(),
+            release_date: "2013-09-26".to_string(),
+            size: 4641652,
+            gc_content: 50.8,
+            lineage: Vec::new(),
+            contig_n50: 4641652,
+            contig_count: 1,
+            quality_flags: Vec::new(),
+            species_taxid: "562".to_string(),
+            refseq_category: "reference genome".to_string(),
+            from_type: false,
+        };
+        // The search and the check agree on what `complete` means
+        assert!(filter.check(&metadata).is_ok());
+    }
+
+    #[test]
+    fn test_parse_assembly_summary_quality_fields() {
+        let meta = r#" <Stats> <Stat category="contig_count" sequence_tag="all">87</Stat> <Stat category="total_length" sequence_tag="all">4641652</Stat> </Stats>"#;
+        assert_eq!(parse_meta_stat(meta, "contig_count"), Some(87));
+        assert_eq!(parse_meta_stat(meta, "scaffold_count"), None);
+
+        let summary: serde_json::Value = serde_json::from_str(
+            r#"{
+                "anomalouslist": [{"property": "fragmented assembly"}],
+                "exclfromrefseq": ["genus undefined"],
+                "propertylist": ["latest", "suppressed_refseq"]
+            }"#,
+        )
+        .unwrap();
+        let flags = parse_quality_flags(&summary);
+        assert_eq!(
+            flags,
+            vec![
+                "anomalous: fragmented assembly".to_string(),
+                "excluded from RefSeq: genus undefined".to_string(),
+                "suppressed_refseq".to_string(),
+            ]
+        );
     }
 }
 
@@ -1256,6 +3658,7 @@ mod mock_tests {
             api_key: None,
             cache_dir: cache_dir.clone(),
             cache_expiry_days: 30,
+            scratch_dir: None,
         };
         (downloader, temp_dir.into_path())
     }
@@ -1348,6 +3751,7 @@ mod mock_tests {
             api_key: Some(api_key.to_string()), // Set API key
             cache_dir: cache_dir.clone(),
             cache_expiry_days: 30,
+            scratch_dir: None,
         };
 
         // Mock search URL *with* API key
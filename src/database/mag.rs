@@ -0,0 +1,196 @@
+//! User-supplied metagenome-assembled genomes (MAGs) as references.
+//!
+//! MAGs are sketched like any other reference, but their quality varies far
+//! more than that of public assemblies: an incomplete bin misses part of the
+//! genome and a contaminated one carries k-mers of other organisms. Their
+//! CheckM-style completeness and contamination estimates are stored next to
+//! the signature so classification reports can flag hits to them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::downloader::DatabaseError;
+
+/// Quality metadata of a user MAG
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MagMetadata {
+    /// Estimated completeness in percent
+    pub completeness: Option<f64>,
+
+    /// Estimated contamination in percent
+    pub contamination: Option<f64>,
+
+    /// CheckM strain heterogeneity in percent, if reported
+    #[serde(default)]
+    pub strain_heterogeneity: Option<f64>,
+
+    /// Where the bin came from (file name or free text)
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+/// MIMAG-style quality tier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MagQuality {
+    /// Completeness >= 90% and contamination < 5%
+    High,
+    /// Completeness >= 50% and contamination < 10%
+    Medium,
+    /// Anything below medium quality
+    Low,
+    /// No completeness or contamination estimate
+    Unknown,
+}
+
+impl fmt::Display for MagQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MagQuality::High => "high",
+            MagQuality::Medium => "medium",
+            MagQuality::Low => "low",
+            MagQuality::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl MagMetadata {
+    /// Quality tier from completeness and contamination
+    pub fn quality(&self) -> MagQuality {
+        match (self.completeness, self.contamination) {
+            (Some(completeness), Some(contamination)) => {
+                if completeness >= 90.0 && contamination < 5.0 {
+                    MagQuality::High
+                } else if completeness >= 50.0 && contamination < 10.0 {
+                    MagQuality::Medium
+                } else {
+                    MagQuality::Low
+                }
+            }
+            _ => MagQuality::Unknown,
+        }
+    }
+
+    /// Caveats to print next to results that rely on this MAG
+    pub fn caveats(&self) -> Vec<String> {
+        let mut caveats = Vec::new();
+        match self.completeness {
+            Some(c) if c < 90.0 => caveats.push(format!(
+                "only {:.1}% complete: similarity to this bin understates similarity to the genome",
+                c
+            )),
+            None => caveats.push("completeness unknown".to_string()),
+            _ => {}
+        }
+        match self.contamination {
+            Some(c) if c >= 5.0 => caveats.push(format!(
+                "{:.1}% contamination: hits may come from other organisms binned with it",
+                c
+            )),
+            None => caveats.push("contamination unknown".to_string()),
+            _ => {}
+        }
+        if let Some(h) = self.strain_heterogeneity {
+            if h >= 50.0 {
+                caveats.push(format!(
+                    "{:.1}% strain heterogeneity: the bin mixes closely related strains",
+                    h
+                ));
+            }
+        }
+        caveats
+    }
+}
+
+/// A classification or strain call that matched a user MAG
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MagHit {
+    /// Signature ID of the MAG
+    pub taxon_id: String,
+    pub metadata: MagMetadata,
+}
+
+/// Read a CheckM (`checkm qa` tab output) or CheckM2 `quality_report.tsv` table,
+/// keyed by bin name. Bin names are the MAG file names without extension.
+pub fn read_checkm_table(
+    path: impl AsRef<Path>,
+) -> Result<HashMap<String, MagMetadata>, DatabaseError> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)?;
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<&str> = lines
+        .next()
+        .ok_or_else(|| DatabaseError::ParseError(format!("{} is empty", path.display())))?
+        .split('\t')
+        .map(str::trim)
+        .collect();
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|h| names.iter().any(|n| h.eq_ignore_ascii_case(n)))
+    };
+    let missing =
+        |name: &str| DatabaseError::ParseError(format!("{}: no '{}' column", path.display(), name));
+    let name_col = column(&["Bin Id", "Name"]).ok_or_else(|| missing("Bin Id"))?;
+    let completeness_col = column(&["Completeness"]).ok_or_else(|| missing("Completeness"))?;
+    let contamination_col = column(&["Contamination"]).ok_or_else(|| missing("Contamination"))?;
+    let heterogeneity_col = column(&["Strain heterogeneity"]);
+
+    let mut table = HashMap::new();
+    for (line_no, line) in lines.enumerate() {
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let number = |col: usize| -> Result<f64, DatabaseError> {
+            fields.get(col).and_then(|v| v.parse().ok()).ok_or_else(|| {
+                DatabaseError::ParseError(format!(
+                    "{}:{}: expected a number in column '{}'",
+                    path.display(),
+                    line_no + 2,
+                    header[col]
+                ))
+            })
+        };
+        let Some(name) = fields.get(name_col) else {
+            continue;
+        };
+        table.insert(
+            name.to_string(),
+            MagMetadata {
+                completeness: Some(number(completeness_col)?),
+                contamination: Some(number(contamination_col)?),
+                strain_heterogeneity: heterogeneity_col.map(number).transpose()?,
+                source: Some(path.display().to_string()),
+            },
+        );
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_read_checkm2_report_and_quality() {
+        let file = NamedTempFile::new().unwrap();
+        fs::write(
+            file.path(),
+            "Name\tCompleteness\tContamination\tCompleteness_Model_Used\n\
+             bin.1\t95.2\t1.1\tNeural Network\n\
+             bin.2\t62.0\t12.5\tNeural Network\n",
+        )
+        .unwrap();
+
+        let table = read_checkm_table(file.path()).unwrap();
+        assert_eq!(table["bin.1"].quality(), MagQuality::High);
+        assert!(table["bin.1"].caveats().is_empty());
+        assert_eq!(table["bin.2"].quality(), MagQuality::Low);
+        assert_eq!(table["bin.2"].caveats().len(), 2);
+        assert_eq!(MagMetadata::default().quality(), MagQuality::Unknown);
+    }
+}
@@ -1,7 +1,23 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
-
+use clap::{Args, Parser, Subcommand};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::bio::taxdump::Taxonomy;
+use crate::config::Config;
+use crate::database::bundle::{
+    fetch_bundle, generate_key_pair, sign_bundle, ReferenceBundle, BUNDLE_EXTENSION,
+};
+use crate::database::downloader::{
+    AssemblyFilter, AssemblyLevel, DatabaseError, InsertOutcome, SearchMode, SignatureDatabase,
+    TaxonFilter,
+};
+use crate::database::mag::{read_checkm_table, MagMetadata};
 use crate::database::DatabaseManager;
+use crate::sketch::masking::{LowComplexityMask, DEFAULT_DUST_LEVEL};
+use crate::sketch::signature::{SignatureLayout, DEFAULT_MACRO_K, DEFAULT_MESO_K};
+use crate::utils::paths::safe_file_name;
+use crate::utils::workspace::Workspace;
 use log::{info, warn}; // Added log imports
 
 #[derive(Parser, Debug)] // Added Debug
@@ -25,10 +41,137 @@ pub struct Cli {
     #[arg(short, long, default_value_t = 4)]
     pub threads: usize,
 
+    /// JSON configuration file (storage backend, cache size, compression, flush interval,
+    /// workspace location)
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Offline taxonomy (NCBI taxdump directory or lineage map) through which
+    /// taxon names and taxids in taxon lists and `subset --lineage` are resolved
+    #[arg(long, value_name = "PATH")]
+    pub taxonomy: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+/// Assembly quality filters shared by the commands that download references
+#[derive(Args, Debug, Clone)]
+pub struct AssemblyFilterArgs {
+    /// Accepted assembly levels (Complete Genome or complete, Chromosome, Scaffold, Contig)
+    #[arg(
+        long = "assembly-level",
+        value_name = "LEVEL",
+        value_parser = AssemblyLevel::from_str,
+        default_values_t = vec![AssemblyLevel::Complete]
+    )]
+    pub assembly_levels: Vec<AssemblyLevel>,
+
+    /// Minimum contig N50 in base pairs
+    #[arg(long, value_name = "BP")]
+    pub min_n50: Option<usize>,
+
+    /// Maximum number of contigs
+    #[arg(long, value_name = "N")]
+    pub max_contigs: Option<usize>,
+
+    /// Keep assemblies NCBI flags as anomalous, suppressed or excluded from RefSeq
+    #[arg(long)]
+    pub include_anomalous: bool,
+
+    /// Keep one genome per species, preferring RefSeq reference/representative genomes and type strains
+    #[arg(long)]
+    pub prefer_representative: bool,
+}
+
+impl From<AssemblyFilterArgs> for AssemblyFilter {
+    fn from(args: AssemblyFilterArgs) -> Self {
+        AssemblyFilter {
+            assembly_levels: args.assembly_levels,
+            min_contig_n50: args.min_n50,
+            max_contig_count: args.max_contigs,
+            exclude_anomalous: !args.include_anomalous,
+            prefer_representative: args.prefer_representative,
+        }
+    }
+}
+
+/// Taxon lists restricting which genomes end up in the database
+#[derive(Args, Debug, Clone)]
+pub struct TaxonFilterArgs {
+    /// File of taxids or taxon names (one per line) to include; genomes outside
+    /// these taxa are skipped. Names need `--taxonomy`.
+    #[arg(long, value_name = "FILE")]
+    pub include_taxids: Option<PathBuf>,
+
+    /// File of taxids or taxon names (one per line) to exclude, including all
+    /// descendants. Names need `--taxonomy`.
+    #[arg(long, value_name = "FILE")]
+    pub exclude_taxids: Option<PathBuf>,
+}
+
+impl TaxonFilterArgs {
+    /// Load the taxon lists from disk, resolving the taxon names in them
+    /// through the taxonomy at `taxonomy`, if given
+    pub fn load(
+        &self,
+        taxonomy: Option<&Path>,
+        cache_dir: &Path,
+    ) -> Result<TaxonFilter, DatabaseError> {
+        let taxonomy = match taxonomy {
+            Some(source) if self.include_taxids.is_some() || self.exclude_taxids.is_some() => Some(
+                Taxonomy::load_cached(source, cache_dir)
+                    .map_err(|e| DatabaseError::TaxonomyError(format!("{:#}", e)))?,
+            ),
+            _ => None,
+        };
+        TaxonFilter::from_files(
+            self.include_taxids.as_deref(),
+            self.exclude_taxids.as_deref(),
+            taxonomy.as_ref(),
+        )
+    }
+}
+
+/// Masking applied to references while they are sketched
+#[derive(Args, Debug, Clone)]
+pub struct MaskingArgs {
+    /// Leave low-complexity regions (homopolymers, short repeats) out of the sketches
+    #[arg(long)]
+    pub mask_low_complexity: bool,
+
+    /// DUST score above which a 64 bp window counts as low-complexity
+    #[arg(long, value_name = "SCORE", default_value_t = DEFAULT_DUST_LEVEL)]
+    pub dust_level: f64,
+
+    /// Sketch references without the k-mers found in more than this fraction
+    /// of all references (e.g. 0.5)
+    #[arg(long, value_name = "FRACTION")]
+    pub mask_common_kmers: Option<f64>,
+}
+
+impl MaskingArgs {
+    /// Set up `manager` to mask the references it sketches
+    pub fn configure(&self, manager: &mut DatabaseManager) -> Result<(), String> {
+        if let Some(fraction) = self.mask_common_kmers {
+            if !(fraction > 0.0 && fraction < 1.0) {
+                return Err(format!(
+                    "--mask-common-kmers must be between 0 and 1, got {}",
+                    fraction
+                ));
+            }
+        }
+        if self.mask_low_complexity {
+            manager.builder.low_complexity = Some(LowComplexityMask {
+                level: self.dust_level,
+                ..LowComplexityMask::default()
+            });
+        }
+        manager.max_shared_fraction = self.mask_common_kmers;
+        Ok(())
+    }
+}
+
 #[derive(Subcommand, Debug)] // Added Debug
 pub enum Commands {
     /// Initialize the database with reference genomes
@@ -41,17 +184,28 @@ pub enum Commands {
         #[arg(long, default_value_t = 20)] // Use long flag
         max_refs: usize,
 
-        /// K-mer size for the primary signature level (e.g., macro)
-        #[arg(long, default_value_t = 31)]
-        kmer_size: usize, // Renamed from macro_k for clarity in `new` call
-
-        // meso_k is specific to the builder's internal logic, not needed for DatabaseManager::new
-        // If your actual builder *requires* it in ::new, adjust DatabaseManager::new signature
-        // #[arg(long, default_value_t = 21)]
-        // meso_k: usize,
-        /// Sketch size (number of hashes) for MinHash signatures
-        #[arg(long, default_value_t = 1000)]
-        sketch_size: usize,
+        /// K-mer size for the primary signature level (e.g., macro); with
+        /// --meso-k, replaces the config's `sketch.kmer_sizes`
+        #[arg(long)]
+        kmer_size: Option<usize>,
+
+        /// K-mer size for the second (meso) signature level
+        #[arg(long)]
+        meso_k: Option<usize>,
+
+        /// Sketch size (number of hashes) for MinHash signatures; replaces
+        /// the config's `sketch.num_hashes` and `sketch.scaled`
+        #[arg(long)]
+        sketch_size: Option<usize>,
+
+        #[command(flatten)]
+        filter: AssemblyFilterArgs,
+
+        #[command(flatten)]
+        taxa: TaxonFilterArgs,
+
+        #[command(flatten)]
+        masking: MaskingArgs,
     },
 
     /// Add new reference genomes to the database
@@ -64,16 +218,126 @@ pub enum Commands {
         #[arg(long, default_value_t = 10)] // Use long flag
         max_refs: usize,
         // Note: Uses default kmer/sketch sizes when adding references later
+        #[command(flatten)]
+        filter: AssemblyFilterArgs,
+
+        #[command(flatten)]
+        taxa: TaxonFilterArgs,
+
+        #[command(flatten)]
+        masking: MaskingArgs,
+    },
+
+    /// Register user MAGs (genome bins in FASTA format) as references
+    AddMags {
+        /// MAG FASTA files; the file name without extension becomes the reference ID
+        #[arg(required = true)]
+        mags: Vec<PathBuf>,
+
+        /// CheckM or CheckM2 quality table with completeness and contamination per bin
+        #[arg(long, value_name = "FILE")]
+        checkm: Option<PathBuf>,
+
+        /// Lineage assigned to every MAG, root first, separated by ';'
+        /// (e.g. 'Bacteria;Bacteroidota;...;Bacteroides fragilis')
+        #[arg(long)]
+        lineage: Option<String>,
     },
 
     /// List all reference genomes currently in the database
     ListReferences,
 
-    /// Search for signatures matching a taxonomy term (name or ID)
+    /// Summarize the database contents (ranks, sketch parameters, disk usage);
+    /// ranks need `--taxonomy`
+    Stats {
+        /// Also write the statistics as JSON to this file
+        #[arg(long, value_name = "FILE")]
+        json: Option<PathBuf>,
+    },
+
+    /// Find and remove signatures with identical sketch contents
+    Dedupe {
+        /// Only report duplicates without removing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Copy the references under one or more lineage nodes into a new, smaller database
+    Subset {
+        /// Lineage name (e.g. 'Enterobacteriaceae'), taxid (with `--taxonomy`) or
+        /// reference ID; repeat to build a panel
+        #[arg(long, required = true)]
+        lineage: Vec<String>,
+
+        /// Directory of the new database; must not already contain signatures
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+    },
+
+    /// Pack the database into a bundle (`<name>.ahspdb` plus its `.sha256`) for publishing
+    Bundle {
+        /// Versioned panel name, e.g. 'standard-bacteria-v1'
+        #[arg(long, required = true)]
+        name: String,
+
+        /// Directory to write the bundle and its checksum to
+        #[arg(short, long, value_name = "DIR", default_value = ".")]
+        output: PathBuf,
+    },
+
+    /// Download a prebuilt reference bundle, verify it and install it into the (empty) database
+    Install {
+        /// Versioned panel name, e.g. 'standard-bacteria-v1'
+        name: String,
+
+        /// URL (http, https or file) or path of the bundle
+        #[arg(long, value_name = "URL")]
+        from: String,
+
+        /// Expected SHA-256 of the bundle; by default read from `<URL>.sha256`
+        #[arg(long, value_name = "HEX")]
+        sha256: Option<String>,
+
+        /// Ed25519 public key (hex) of a trusted publisher, in addition to the
+        /// config's `database.trusted_bundle_keys`; requires a valid `<URL>.sig`
+        #[arg(long, value_name = "HEX")]
+        public_key: Vec<String>,
+    },
+
+    /// Create an Ed25519 key pair for signing bundles (secret key in FILE, public key in FILE.pub)
+    Keygen {
+        /// File for the secret key; must not exist yet
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+
+    /// Sign a bundle with a publisher's secret key, writing `<bundle>.sig` next to it
+    Sign {
+        /// Bundle file to sign
+        bundle: PathBuf,
+
+        /// Secret key created by `keygen`
+        #[arg(long, value_name = "FILE")]
+        key: PathBuf,
+    },
+
+    /// Search for signatures by accession, organism name or lineage term
     Search {
-        /// Taxonomy term (e.g., 'Escherichia coli', '562') to search for
+        /// Term to search for (e.g., 'Escherichia coli', '562', 'GCF_000005845', 'Staph*')
         #[arg(short, long, required = true)] // Mark as required
         term: String,
+
+        /// How the term is matched
+        #[arg(long, value_enum, default_value_t = SearchMode::Exact)]
+        mode: SearchMode,
+
+        /// Page of results to show (1-based)
+        #[arg(long, default_value_t = 1)]
+        page: usize,
+
+        /// Number of results per page
+        #[arg(long, default_value_t = 25)]
+        page_size: usize,
     },
 }
 
@@ -88,28 +352,53 @@ pub fn run_database_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
     // info!("Set Rayon global thread pool to {} threads", cli.threads);
     // Otherwise, Rayon typically uses the number of logical cores by default.
 
+    let config = Config::load(cli.config.as_deref())?;
+    // Dropped without `finish` on error, which keeps it for debugging
+    let workspace = Workspace::create(&config.workspace, "db")?;
+
     match cli.command {
         Commands::Init {
             query,
             max_refs,
-            kmer_size, // Use the renamed argument
-            // meso_k is not passed here
+            kmer_size,
+            meso_k,
             sketch_size,
+            filter,
+            taxa,
+            masking,
         } => {
             info!("Initializing database...");
-            // Create database manager with parameters from Init command
-            // Assuming DatabaseManager::new(db, cache, k, sketch, api_key) -> 5 args
-            let mut manager = DatabaseManager::new(
-                &cli.db_path,        // Pass as reference
-                &cli.cache_dir,      // Pass as reference
-                kmer_size,           // Pass k-mer size from command
-                sketch_size,         // Pass sketch size from command
+            // Sketch parameters from the config, overridden by the Init flags
+            let mut sketch = config.sketch.clone();
+            if kmer_size.is_some() || meso_k.is_some() {
+                sketch.kmer_sizes = vec![
+                    kmer_size.unwrap_or(DEFAULT_MACRO_K),
+                    meso_k.unwrap_or(DEFAULT_MESO_K),
+                ];
+            }
+            if let Some(sketch_size) = sketch_size {
+                sketch.num_hashes = sketch_size;
+                sketch.scaled = 0;
+            }
+            if let Some(error) = sketch.validate().into_iter().next() {
+                return Err(error.into());
+            }
+            let layout = sketch.layout();
+            let mut manager = DatabaseManager::with_config(
+                &cli.db_path,   // Pass as reference
+                &cli.cache_dir, // Pass as reference
+                layout,
                 cli.api_key.clone(), // Clone Option<String>
+                &config.database,
             )?;
             info!(
-                "DatabaseManager created with k={}, sketch_size={}",
-                kmer_size, sketch_size
+                "DatabaseManager created with k={:?}, num_hashes={}, scaled={}",
+                sketch.kmer_sizes, sketch.num_hashes, sketch.scaled
             );
+            manager.assembly_filter = filter.into();
+            manager.taxon_filter = taxa.load(cli.taxonomy.as_deref(), &cli.cache_dir)?;
+            masking.configure(&mut manager)?;
+            manager.use_workspace(&workspace)?;
 
             // Check if database is already initialized
             // Note: DatabaseManager::is_empty checks signature count, not just existence of DB files
@@ -119,6 +408,7 @@ pub fn run_database_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
                     cli.db_path.display()
                 );
                 // Optionally, add a --force flag to Init to allow overwriting/clearing
+                workspace.finish()?;
                 return Ok(());
             }
 
@@ -143,18 +433,28 @@ pub fn run_database_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
             info!("Database initialization complete.");
         }
 
-        Commands::AddReferences { query, max_refs } => {
+        Commands::AddReferences {
+            query,
+            max_refs,
+            filter,
+            taxa,
+            masking,
+        } => {
             info!("Adding references to existing database...");
             // Create database manager with default signature parameters
-            // Assuming DatabaseManager::new(db, cache, k, sketch, api_key) -> 5 args
-            let mut manager = DatabaseManager::new(
+            let mut manager = DatabaseManager::with_config(
                 &cli.db_path,
                 &cli.cache_dir,
-                31,   // Default k-mer size for adding later
-                1000, // Default sketch size for adding later
+                SignatureLayout::default(),
                 cli.api_key.clone(),
+                &config.database,
             )?;
-            info!("DatabaseManager created with default signature parameters (k=31, sketch=1000)");
+            // New references must match the ones already stored
+            manager.adopt_database_layout()?;
+            manager.assembly_filter = filter.into();
+            manager.taxon_filter = taxa.load(cli.taxonomy.as_deref(), &cli.cache_dir)?;
+            masking.configure(&mut manager)?;
+            manager.use_workspace(&workspace)?;
 
             // Add references
             info!(
@@ -176,15 +476,68 @@ pub fn run_database_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
+        Commands::AddMags {
+            mags,
+            checkm,
+            lineage,
+        } => {
+            let mut manager = DatabaseManager::with_config(
+                &cli.db_path,
+                &cli.cache_dir,
+                SignatureLayout::default(),
+                cli.api_key.clone(),
+                &config.database,
+            )?;
+            manager.adopt_database_layout()?;
+            let quality = match &checkm {
+                Some(path) => read_checkm_table(path)?,
+                None => HashMap::new(),
+            };
+            let lineage: Vec<String> = lineage
+                .as_deref()
+                .map(|l| {
+                    l.split(';')
+                        .map(|name| name.trim().to_string())
+                        .filter(|name| !name.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            for path in &mags {
+                let id = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .ok_or_else(|| format!("Cannot derive a MAG ID from {}", path.display()))?;
+                let metadata = quality.get(&id).cloned().unwrap_or_else(|| {
+                    warn!(
+                        "No completeness/contamination estimate for MAG {}; reports will flag its quality as unknown",
+                        id
+                    );
+                    MagMetadata {
+                        source: Some(path.display().to_string()),
+                        ..MagMetadata::default()
+                    }
+                });
+                match manager.add_mag(path, &id, lineage.clone(), &metadata)? {
+                    InsertOutcome::Added => {
+                        println!("  - {} ({} quality MAG)", id, metadata.quality())
+                    }
+                    InsertOutcome::Duplicate(existing) => {
+                        println!("  - {} skipped: identical to {}", id, existing)
+                    }
+                }
+            }
+        }
+
         Commands::ListReferences => {
             info!("Listing references from database...");
             // Create database manager - signature params don't matter for listing
-            let manager = DatabaseManager::new(
+            let manager = DatabaseManager::with_config(
                 &cli.db_path,
                 &cli.cache_dir,
-                31,   // Default k-mer size (arbitrary for this command)
-                1000, // Default sketch size (arbitrary for this command)
+                SignatureLayout::default(),
                 cli.api_key.clone(),
+                &config.database,
             )?;
 
             // List all references
@@ -217,19 +570,300 @@ pub fn run_database_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
-        Commands::Search { term } => {
-            info!("Searching database for term: '{}'", term);
+        Commands::Stats { json } => {
+            let manager = DatabaseManager::with_config(
+                &cli.db_path,
+                &cli.cache_dir,
+                SignatureLayout::default(),
+                cli.api_key.clone(),
+                &config.database,
+            )?;
+            let taxonomy = cli
+                .taxonomy
+                .as_deref()
+                .map(|source| Taxonomy::load_cached(source, &cli.cache_dir))
+                .transpose()?;
+            let stats = manager.database.stats(taxonomy.as_ref())?;
+
+            println!("Database: {}", cli.db_path.display());
+            println!("  {:<24} {:>12}", "Signatures", stats.signature_count);
+            println!("  {:<24} {:>12}", "Total hashes", stats.total_hashes);
+            println!(
+                "  {:<24} {:>12}",
+                "Disk usage",
+                format_bytes(stats.disk_usage_bytes)
+            );
+            match (&stats.oldest_addition, &stats.newest_addition) {
+                (Some((oldest_id, oldest)), Some((newest_id, newest))) => {
+                    println!(
+                        "  {:<24} {} ({})",
+                        "Oldest addition",
+                        format_timestamp(*oldest),
+                        oldest_id
+                    );
+                    println!(
+                        "  {:<24} {} ({})",
+                        "Newest addition",
+                        format_timestamp(*newest),
+                        newest_id
+                    );
+                }
+                _ => println!("  {:<24} {:>12}", "Addition times", "not recorded"),
+            }
+
+            println!("\nReferences by rank:");
+            for (rank, count) in &stats.rank_distribution {
+                println!("  {:<24} {:>12}", rank, count);
+            }
+            if taxonomy.is_none() {
+                println!("  (pass --taxonomy to resolve reference ranks)");
+            }
+
+            println!("\nSketch parameters (levels):");
+            for (params, count) in &stats.sketch_parameters {
+                println!("  {:<40} {:>8}", params, count);
+            }
+
+            if let Some(path) = json {
+                let file = std::fs::File::create(&path)?;
+                serde_json::to_writer_pretty(file, &stats)?;
+                info!("Wrote database statistics to {}", path.display());
+            }
+        }
+
+        Commands::Dedupe { dry_run } => {
+            let mut manager = DatabaseManager::with_config(
+                &cli.db_path,
+                &cli.cache_dir,
+                SignatureLayout::default(),
+                cli.api_key.clone(),
+                &config.database,
+            )?;
+            let size_before = manager.database.size_on_disk()?;
+            let report = manager.database.dedupe(dry_run)?;
+
+            if report.groups.is_empty() {
+                println!("No duplicate signatures found.");
+            } else {
+                let duplicate_count: usize = report.groups.iter().map(|(_, d)| d.len()).sum();
+                println!(
+                    "{} {} duplicate signatures in {} groups:",
+                    if dry_run { "Found" } else { "Removed" },
+                    duplicate_count,
+                    report.groups.len()
+                );
+                for (kept, duplicates) in &report.groups {
+                    println!("  - {} (duplicates: {})", kept, duplicates.join(", "));
+                }
+                println!(
+                    "Duplicate records {}: {}",
+                    if dry_run { "occupy" } else { "reclaimed" },
+                    format_bytes(report.bytes_reclaimed)
+                );
+                if !dry_run {
+                    let size_after = manager.database.size_on_disk()?;
+                    println!(
+                        "Disk usage: {} -> {} (sled reclaims space as segments are compacted)",
+                        format_bytes(size_before),
+                        format_bytes(size_after)
+                    );
+                }
+            }
+        }
+
+        Commands::Subset { lineage, output } => {
+            if output == cli.db_path {
+                return Err("The subset must be written to a different directory".into());
+            }
+            let manager = DatabaseManager::with_config(
+                &cli.db_path,
+                &cli.cache_dir,
+                SignatureLayout::default(),
+                cli.api_key.clone(),
+                &config.database,
+            )?;
+            let mut subset = SignatureDatabase::open_with_config(&output, &config.database)?;
+            if subset.count()? > 0 {
+                return Err(format!(
+                    "Database at '{}' already contains signatures",
+                    output.display()
+                )
+                .into());
+            }
+
+            let taxonomy = cli
+                .taxonomy
+                .as_deref()
+                .map(|source| Taxonomy::load_cached(source, &cli.cache_dir))
+                .transpose()?;
+            let mut problems = Vec::new();
+            let lineage: Vec<String> = lineage
+                .iter()
+                .filter_map(|query| {
+                    manager
+                        .database
+                        .resolve_lineage(query, taxonomy.as_ref())
+                        .map_err(|e| problems.push(e.to_string()))
+                        .ok()
+                })
+                .collect();
+            if !problems.is_empty() {
+                return Err(format!(
+                    "Cannot resolve {} lineages in '{}':\n  - {}",
+                    problems.len(),
+                    cli.db_path.display(),
+                    problems.join("\n  - ")
+                )
+                .into());
+            }
+
+            let report = manager.database.extract_lineages(&lineage, &mut subset)?;
+            if report.added.is_empty() {
+                warn!(
+                    "No signatures found under {} in '{}'",
+                    lineage.join(", "),
+                    cli.db_path.display()
+                );
+            }
+            for (id, reason) in &report.invalid {
+                println!("  - {} skipped: {}", id, reason);
+            }
+            println!(
+                "Copied {} signatures under {} to '{}'.",
+                report.added.len(),
+                lineage.join(", "),
+                output.display()
+            );
+            println!(
+                "Classify against it with --db-path {}",
+                crate::pipeline::summary::quote(&output)
+            );
+        }
+
+        Commands::Bundle { name, output } => {
+            let manager = DatabaseManager::with_config(
+                &cli.db_path,
+                &cli.cache_dir,
+                SignatureLayout::default(),
+                cli.api_key.clone(),
+                &config.database,
+            )?;
+            let signatures = manager.database.get_all_signatures()?;
+            if signatures.is_empty() {
+                return Err(format!(
+                    "Database at '{}' contains no signatures to bundle",
+                    cli.db_path.display()
+                )
+                .into());
+            }
+            let bundle = ReferenceBundle::new(&name, signatures)?;
+            std::fs::create_dir_all(&output)?;
+            let path = output.join(format!("{}.{}", safe_file_name(&name), BUNDLE_EXTENSION));
+            let checksum = bundle.write(&path)?;
+            println!(
+                "Bundled {} signatures into {}",
+                bundle.signatures.len(),
+                path.display()
+            );
+            println!("  SHA-256 {} (also in {}.sha256)", checksum, path.display());
+            println!("  Database digest {}", bundle.database_sha256);
+            println!(
+                "Publish both files; install with: db install {} --from <URL of {}>",
+                name,
+                path.file_name().unwrap_or_default().to_string_lossy()
+            );
+        }
+
+        Commands::Install {
+            name,
+            from,
+            sha256,
+            public_key,
+        } => {
+            // Fetched and verified first, so a failed install leaves no empty database behind
+            let mut trusted_keys = config.database.trusted_bundle_keys.clone();
+            trusted_keys.extend(public_key);
+            let (path, bundle) = fetch_bundle(
+                &name,
+                &from,
+                sha256.as_deref(),
+                &trusted_keys,
+                &cli.cache_dir,
+            )?;
+            info!(
+                "Verified {} ({} signatures)",
+                path.display(),
+                bundle.signatures.len()
+            );
+            let mut database = SignatureDatabase::open_with_config(&cli.db_path, &config.database)?;
+            if database.count()? > 0 {
+                return Err(format!(
+                    "Database at '{}' already contains signatures; install bundles into a new --db-path",
+                    cli.db_path.display()
+                )
+                .into());
+            }
+            let digest = bundle.database_sha256.clone();
+            let report = database.add_signatures_bulk(bundle.signatures)?;
+            for (id, reason) in &report.invalid {
+                warn!("  - {} skipped: {}", id, reason);
+            }
+            for (id, existing) in &report.duplicates {
+                warn!("  - {} skipped: identical to {}", id, existing);
+            }
+            println!(
+                "Installed {} ({} signatures) into '{}'.",
+                name,
+                report.added.len(),
+                cli.db_path.display()
+            );
+            if report.invalid.is_empty() && report.duplicates.is_empty() {
+                println!(
+                    "Database digest {}; pin it as strict.database_sha256 to validate runs",
+                    digest
+                );
+            }
+        }
+
+        Commands::Keygen { output } => {
+            let public_key = generate_key_pair(&output)?;
+            println!(
+                "Secret key written to {} (keep it private)",
+                output.display()
+            );
+            println!("Public key {}", public_key);
+            println!(
+                "Users trust bundles signed with it by adding the public key to \
+                 database.trusted_bundle_keys in their config"
+            );
+        }
+
+        Commands::Sign { bundle, key } => {
+            let signature = sign_bundle(&bundle, &key)?;
+            println!(
+                "Signature written to {}; publish it next to the bundle",
+                signature.display()
+            );
+        }
+
+        Commands::Search {
+            term,
+            mode,
+            page,
+            page_size,
+        } => {
+            info!("Searching database for term: '{}' ({:?})", term, mode);
             // Create database manager - signature params don't matter for searching
-            let manager = DatabaseManager::new(
+            let manager = DatabaseManager::with_config(
                 &cli.db_path,
                 &cli.cache_dir,
-                31,   // Default k-mer size (arbitrary for this command)
-                1000, // Default sketch size (arbitrary for this command)
+                SignatureLayout::default(),
                 cli.api_key.clone(),
+                &config.database,
             )?;
 
-            // Search for signatures
-            let results = manager.database.search_by_taxonomy(&term)?;
+            // Results come back sorted by ID for consistent paging
+            let results = manager.database.search(&term, mode)?;
 
             if results.is_empty() {
                 println!(
@@ -238,12 +872,22 @@ pub fn run_database_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
                     cli.db_path.display()
                 );
             } else {
-                println!("Found {} matches for term '{}':", results.len(), term);
-                // Sort results by ID for consistent output
-                let mut sorted_results = results;
-                sorted_results.sort_by(|a, b| a.taxon_id.cmp(&b.taxon_id));
+                let page_size = page_size.max(1);
+                let total_pages = results.len().div_ceil(page_size);
+                let page = page.clamp(1, total_pages);
+                let start = (page - 1) * page_size;
+                let end = (start + page_size).min(results.len());
 
-                for sig in sorted_results {
+                println!(
+                    "Found {} matches for term '{}' (showing {}-{}, page {}/{}):",
+                    results.len(),
+                    term,
+                    start + 1,
+                    end,
+                    page,
+                    total_pages
+                );
+                for sig in &results[start..end] {
                     let species_name = sig
                         .lineage
                         .last()
@@ -251,9 +895,56 @@ pub fn run_database_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
                         .unwrap_or_else(|| "Unknown Species".to_string());
                     println!("  - {} ({})", sig.taxon_id, species_name);
                 }
+                if page < total_pages {
+                    println!("Use --page {} to see more results.", page + 1);
+                }
             }
         }
     }
 
+    workspace.finish()?;
     Ok(())
 }
+
+/// Format a byte count with a binary unit suffix
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Format unix seconds as a UTC date and time (YYYY-MM-DD HH:MM:SS)
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let time = secs % 86_400;
+
+    // Civil-from-days conversion (proleptic Gregorian calendar)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3_600,
+        (time % 3_600) / 60,
+        time % 60
+    )
+}
@@ -1,6 +1,12 @@
+pub mod bundle;
 pub mod downloader;
+pub mod mag;
 pub mod manager;
 pub mod storage;
 
 pub use downloader::DatabaseManager;
-pub use downloader::{GenomeMetadata, NCBIDownloader};
+pub use downloader::{
+    AssemblyFilter, AssemblyLevel, BulkInsertReport, DatabaseStats, DedupeReport, GenomeMetadata,
+    InsertOutcome, NCBIDownloader, SearchMode, TaxonFilter,
+};
+pub use mag::{MagHit, MagMetadata, MagQuality};
@@ -0,0 +1,335 @@
+//! Storage backends for the signature database.
+//!
+//! `SignatureDatabase` talks to its embedded key-value store through the
+//! `SignatureStore` trait. sled is the default backend; redb is available
+//! with the `redb` feature and selected through `DatabaseConfig::backend`.
+
+use std::path::Path;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::config::DatabaseConfig;
+use crate::database::downloader::DatabaseError;
+use crate::utils::paths::long_path;
+
+/// Table holding the serialized signatures (sled's default tree)
+pub const SIGNATURE_TABLE: &str = "signatures";
+
+/// Callback invoked for each (key, value) pair during a scan
+pub type ScanVisitor<'a> = dyn FnMut(&[u8], &[u8]) -> Result<(), DatabaseError> + 'a;
+
+/// Embedded key-value store with named tables
+pub trait SignatureStore: Send + Sync {
+    /// Get the value stored under `key`
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, DatabaseError>;
+
+    /// Insert or overwrite a value
+    fn insert(&self, table: &str, key: &[u8], value: &[u8]) -> Result<(), DatabaseError>;
+
+    /// Insert several values atomically
+    fn insert_batch(
+        &self,
+        table: &str,
+        entries: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<(), DatabaseError>;
+
+    /// Remove a value, if present
+    fn remove(&self, table: &str, key: &[u8]) -> Result<(), DatabaseError>;
+
+    /// Visit every entry whose key starts with `prefix` (all entries for an empty prefix),
+    /// in key order
+    fn scan_prefix(
+        &self,
+        table: &str,
+        prefix: &[u8],
+        visit: &mut ScanVisitor<'_>,
+    ) -> Result<(), DatabaseError>;
+
+    /// Make all writes durable
+    fn flush(&self) -> Result<(), DatabaseError>;
+
+    /// Size of the store on disk in bytes
+    fn size_on_disk(&self) -> Result<u64, DatabaseError>;
+}
+
+/// Available storage backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// sled (default)
+    #[default]
+    Sled,
+    /// redb, requires the `redb` feature
+    Redb,
+}
+
+/// Open the store configured in `config` at `path` (a directory)
+pub fn open_store(
+    path: impl AsRef<Path>,
+    config: &DatabaseConfig,
+) -> Result<Box<dyn SignatureStore>, DatabaseError> {
+    let path = long_path(path.as_ref());
+    match config.backend {
+        StorageBackend::Sled => Ok(Box::new(SledStore::open(path, config)?)),
+        #[cfg(feature = "redb")]
+        StorageBackend::Redb => Ok(Box::new(redb_store::RedbStore::open(path)?)),
+        #[cfg(not(feature = "redb"))]
+        StorageBackend::Redb => Err(DatabaseError::StorageError(
+            "redb backend requested but strain_ahsp was built without the `redb` feature"
+                .to_string(),
+        )),
+    }
+}
+
+/// sled-backed store. The signature table maps to sled's default tree so
+/// databases created before the trait existed open unchanged.
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    /// Open or create a sled store
+    pub fn open(path: impl AsRef<Path>, config: &DatabaseConfig) -> Result<Self, DatabaseError> {
+        info!("Opening sled store at: {}", path.as_ref().display());
+        Ok(SledStore {
+            db: config.sled_config(path).open()?,
+        })
+    }
+
+    fn tree(&self, table: &str) -> Result<sled::Tree, DatabaseError> {
+        if table == SIGNATURE_TABLE {
+            Ok((*self.db).clone())
+        } else {
+            Ok(self.db.open_tree(table)?)
+        }
+    }
+}
+
+impl SignatureStore for SledStore {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, DatabaseError> {
+        Ok(self.tree(table)?.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn insert(&self, table: &str, key: &[u8], value: &[u8]) -> Result<(), DatabaseError> {
+        self.tree(table)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn insert_batch(
+        &self,
+        table: &str,
+        entries: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<(), DatabaseError> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in entries {
+            batch.insert(key.as_slice(), value.as_slice());
+        }
+        self.tree(table)?.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn remove(&self, table: &str, key: &[u8]) -> Result<(), DatabaseError> {
+        self.tree(table)?.remove(key)?;
+        Ok(())
+    }
+
+    fn scan_prefix(
+        &self,
+        table: &str,
+        prefix: &[u8],
+        visit: &mut ScanVisitor<'_>,
+    ) -> Result<(), DatabaseError> {
+        for item in self.tree(table)?.scan_prefix(prefix) {
+            let (key, value) = item?;
+            visit(&key, &value)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), DatabaseError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<u64, DatabaseError> {
+        Ok(self.db.size_on_disk()?)
+    }
+}
+
+#[cfg(feature = "redb")]
+mod redb_store {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use log::info;
+    use redb::{Database, ReadableTable, TableDefinition, TableError};
+
+    use super::{ScanVisitor, SignatureStore};
+    use crate::database::downloader::DatabaseError;
+
+    /// File name of the redb database inside the database directory
+    const REDB_FILE: &str = "signatures.redb";
+
+    fn redb_error(err: impl Into<redb::Error>) -> DatabaseError {
+        DatabaseError::StorageError(err.into().to_string())
+    }
+
+    fn table(name: &str) -> TableDefinition<'_, &'static [u8], &'static [u8]> {
+        TableDefinition::new(name)
+    }
+
+    /// redb-backed store; every write is its own committed transaction
+    pub struct RedbStore {
+        db: Database,
+        path: PathBuf,
+    }
+
+    impl RedbStore {
+        /// Open or create a redb store inside the directory `path`
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+            fs::create_dir_all(path.as_ref())?;
+            let path = path.as_ref().join(REDB_FILE);
+            info!("Opening redb store at: {}", path.display());
+            let db = Database::create(&path).map_err(redb_error)?;
+            Ok(RedbStore { db, path })
+        }
+    }
+
+    impl SignatureStore for RedbStore {
+        fn get(&self, name: &str, key: &[u8]) -> Result<Option<Vec<u8>>, DatabaseError> {
+            let txn = self.db.begin_read().map_err(redb_error)?;
+            let table = match txn.open_table(table(name)) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+                Err(e) => return Err(redb_error(e)),
+            };
+            let value = table.get(key).map_err(redb_error)?;
+            Ok(value.map(|v| v.value().to_vec()))
+        }
+
+        fn insert(&self, name: &str, key: &[u8], value: &[u8]) -> Result<(), DatabaseError> {
+            self.insert_batch(name, &[(key.to_vec(), value.to_vec())])
+        }
+
+        fn insert_batch(
+            &self,
+            name: &str,
+            entries: &[(Vec<u8>, Vec<u8>)],
+        ) -> Result<(), DatabaseError> {
+            let txn = self.db.begin_write().map_err(redb_error)?;
+            {
+                let mut table = txn.open_table(table(name)).map_err(redb_error)?;
+                for (key, value) in entries {
+                    table
+                        .insert(key.as_slice(), value.as_slice())
+                        .map_err(redb_error)?;
+                }
+            }
+            txn.commit().map_err(redb_error)
+        }
+
+        fn remove(&self, name: &str, key: &[u8]) -> Result<(), DatabaseError> {
+            let txn = self.db.begin_write().map_err(redb_error)?;
+            {
+                let mut table = txn.open_table(table(name)).map_err(redb_error)?;
+                table.remove(key).map_err(redb_error)?;
+            }
+            txn.commit().map_err(redb_error)
+        }
+
+        fn scan_prefix(
+            &self,
+            name: &str,
+            prefix: &[u8],
+            visit: &mut ScanVisitor<'_>,
+        ) -> Result<(), DatabaseError> {
+            let txn = self.db.begin_read().map_err(redb_error)?;
+            let table = match txn.open_table(table(name)) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => return Ok(()),
+                Err(e) => return Err(redb_error(e)),
+            };
+            for item in table.range::<&[u8]>(prefix..).map_err(redb_error)? {
+                let (key, value) = item.map_err(redb_error)?;
+                if !key.value().starts_with(prefix) {
+                    break;
+                }
+                visit(key.value(), value.value())?;
+            }
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), DatabaseError> {
+            // Committed transactions are already durable
+            Ok(())
+        }
+
+        fn size_on_disk(&self) -> Result<u64, DatabaseError> {
+            Ok(fs::metadata(&self.path)?.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn exercise_store(store: &dyn SignatureStore) {
+        store.insert(SIGNATURE_TABLE, b"GCF_1.1", b"one").unwrap();
+        store
+            .insert_batch(
+                SIGNATURE_TABLE,
+                &[
+                    (b"GCF_1.2".to_vec(), b"two".to_vec()),
+                    (b"GCF_2.1".to_vec(), b"three".to_vec()),
+                ],
+            )
+            .unwrap();
+        store.insert("other", b"GCF_1.1", b"elsewhere").unwrap();
+
+        assert_eq!(
+            store.get(SIGNATURE_TABLE, b"GCF_1.1").unwrap(),
+            Some(b"one".to_vec())
+        );
+        assert_eq!(store.get("missing_table", b"GCF_1.1").unwrap(), None);
+
+        let mut keys = Vec::new();
+        store
+            .scan_prefix(SIGNATURE_TABLE, b"GCF_1.", &mut |key, _| {
+                keys.push(key.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(keys, vec![b"GCF_1.1".to_vec(), b"GCF_1.2".to_vec()]);
+
+        store.remove(SIGNATURE_TABLE, b"GCF_1.1").unwrap();
+        assert_eq!(store.get(SIGNATURE_TABLE, b"GCF_1.1").unwrap(), None);
+        assert_eq!(
+            store.get("other", b"GCF_1.1").unwrap(),
+            Some(b"elsewhere".to_vec())
+        );
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn test_sled_store() {
+        let dir = tempdir().unwrap();
+        let store = open_store(dir.path(), &DatabaseConfig::default()).unwrap();
+        exercise_store(store.as_ref());
+    }
+
+    #[cfg(feature = "redb")]
+    #[test]
+    fn test_redb_store() {
+        let dir = tempdir().unwrap();
+        let config = DatabaseConfig {
+            backend: StorageBackend::Redb,
+            ..DatabaseConfig::default()
+        };
+        let store = open_store(dir.path(), &config).unwrap();
+        exercise_store(store.as_ref());
+        assert!(store.size_on_disk().unwrap() > 0);
+    }
+}
@@ -0,0 +1,126 @@
+//! Escaping of feature and sample names in outputs.
+//!
+//! Names come from users' FASTQ file names, sample sheets and reference
+//! headers, so they can hold anything: commas and quotes
+//! (`E. coli "O157:H7", isolate 3`), a leading `#`, non-ASCII letters, or
+//! markup. Each output format escapes them its own way, through the helpers
+//! here rather than ad hoc:
+//!
+//! * CSV tables are written by `csv_writer_builder`, which quotes fields
+//!   with delimiters, quotes or line breaks, and also fields containing `#`,
+//!   since every table reader here (and the phyloseq import script) skips
+//!   `#` lines as provenance comments
+//! * HTML reports put names in element text through `html_escape`, and in
+//!   their chart scripts through `script_string`, so a name cannot close a
+//!   tag or a string and inject markup or script
+
+use std::fmt::Write;
+
+/// Character starting the comment lines readers skip in CSV tables
+pub const CSV_COMMENT: u8 = b'#';
+
+/// Builder of CSV writers that quote every field a reader could misread:
+/// those with delimiters, quotes or line breaks, and those with
+/// `CSV_COMMENT`, which would otherwise start a comment line
+pub fn csv_writer_builder() -> csv::WriterBuilder {
+    let mut builder = csv::WriterBuilder::new();
+    builder.comment(Some(CSV_COMMENT));
+    builder
+}
+
+/// `text` with the characters special in HTML replaced by entities, safe as
+/// element text and as a quoted attribute value
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `text` as a double-quoted JavaScript string literal that can be placed in
+/// an inline `<script>`: besides quotes and control characters, `<`, `>` and
+/// `&` are escaped so the text cannot end the script element, and the line
+/// separators U+2028 and U+2029 so older engines do not end the string
+pub fn script_string(text: &str) -> String {
+    let mut literal = String::with_capacity(text.len() + 2);
+    literal.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            '<' | '>' | '&' | '\'' | '\u{2028}' | '\u{2029}' => {
+                write!(literal, "\\u{:04x}", c as u32).unwrap()
+            }
+            c if c.is_control() => write!(literal, "\\u{:04x}", c as u32).unwrap(),
+            _ => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::count_table::{CountTable, DuplicatePolicy};
+    use crate::io::{read_count_table, write_count_table};
+    use std::collections::HashMap;
+
+    /// Names that break naive CSV or HTML output
+    const ADVERSARIAL_NAMES: [&str; 7] = [
+        "E. coli, O157:H7",
+        "strain \"K-12\"",
+        "#not_a_comment",
+        "Salmonella enterica subsp. enterica, serovar Typhimurium # 2",
+        "Ünïcödé 大肠杆菌 🦠",
+        "<script>alert('x')</script>",
+        "line\nbreak\ttab",
+    ];
+
+    #[test]
+    fn test_adversarial_names_round_trip_and_are_escaped() {
+        let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+        for (i, sample) in ADVERSARIAL_NAMES.iter().enumerate() {
+            let counts = ADVERSARIAL_NAMES
+                .iter()
+                .map(|feature| (feature.to_string(), (i + 1) as f64))
+                .collect();
+            data.insert(sample.to_string(), counts);
+        }
+        let table = CountTable::build_from_data(&data).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("counts.csv");
+        write_count_table(&table, None, path.to_str().unwrap()).unwrap();
+        let read = read_count_table(&path, DuplicatePolicy::Error).unwrap();
+        assert_eq!(read.sample_names(), table.sample_names());
+        assert_eq!(read.feature_names(), table.feature_names());
+        assert_eq!(read.counts_matrix(), table.counts_matrix());
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("\n\"#not_a_comment\","));
+
+        for name in ADVERSARIAL_NAMES {
+            let escaped = html_escape(name);
+            assert!(!escaped.contains(['<', '>', '"', '\'']), "{}", escaped);
+
+            let literal = script_string(name);
+            assert!(!literal[1..literal.len() - 1]
+                .replace("\\\"", "")
+                .contains(['"', '\'', '<', '>', '\n']));
+            let parsed: String = serde_json::from_str(&literal).unwrap();
+            assert_eq!(parsed, name);
+        }
+        assert_eq!(html_escape("<b>A & B</b>"), "&lt;b&gt;A &amp; B&lt;/b&gt;");
+        assert_eq!(script_string("</script>"), "\"\\u003c/script\\u003e\"");
+    }
+}
@@ -1,8 +1,10 @@
 use anyhow::{anyhow, Result};
 use bio::io::fastq;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use log::info;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter, Write};
 use std::path::Path;
 
 // Define SequenceRecord with the necessary fields
@@ -81,6 +83,133 @@ pub fn read_sequences_stream(input_paths: &[String]) -> Result<Vec<SequenceRecor
     Ok(all_records)
 }
 
+/// Output format for `write_records`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceFormat {
+    Fasta,
+    Fastq,
+}
+
+impl SequenceFormat {
+    /// Format implied by a file name (`.fa`, `.fasta`, `.fna`, optionally
+    /// gzipped, mean FASTA; anything else FASTQ)
+    pub fn from_path(path: &Path) -> Self {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let name = name.trim_end_matches(".gz");
+        if [".fa", ".fasta", ".fna"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+        {
+            SequenceFormat::Fasta
+        } else {
+            SequenceFormat::Fastq
+        }
+    }
+}
+
+/// Write records to `path` as FASTA or FASTQ, gzip-compressed if `gzip` is set.
+///
+/// This is the one writer for every stage that emits reads, so output
+/// formatting is the same everywhere. FASTQ output needs a quality string of
+/// the sequence's length on every record. Returns the number of records written.
+pub fn write_records<'a>(
+    path: &Path,
+    records: impl IntoIterator<Item = &'a SequenceRecord>,
+    format: SequenceFormat,
+    gzip: bool,
+) -> Result<usize> {
+    let mut writer = RecordWriter::create(path, format, gzip)?;
+    writer.write(records)?;
+    writer.finish()
+}
+
+/// A FASTA or FASTQ file written a batch of records at a time, for stages
+/// that emit reads as they stream through them. Records are formatted as by
+/// `write_records`; call `finish` to complete a gzip stream.
+pub struct RecordWriter {
+    sink: RecordSink,
+    format: SequenceFormat,
+    written: usize,
+}
+
+enum RecordSink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl RecordWriter {
+    /// Create `path`, gzip-compressed if `gzip` is set
+    pub fn create(path: &Path, format: SequenceFormat, gzip: bool) -> Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let sink = if gzip {
+            RecordSink::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            RecordSink::Plain(file)
+        };
+        Ok(RecordWriter {
+            sink,
+            format,
+            written: 0,
+        })
+    }
+
+    /// Append `records`, returning how many were written
+    pub fn write<'a>(
+        &mut self,
+        records: impl IntoIterator<Item = &'a SequenceRecord>,
+    ) -> Result<usize> {
+        let written = match &mut self.sink {
+            RecordSink::Plain(file) => write_records_to(file, records, self.format)?,
+            RecordSink::Gzip(encoder) => write_records_to(encoder, records, self.format)?,
+        };
+        self.written += written;
+        Ok(written)
+    }
+
+    /// Flush the file, returning the number of records written in all
+    pub fn finish(self) -> Result<usize> {
+        match self.sink {
+            RecordSink::Plain(mut file) => file.flush()?,
+            RecordSink::Gzip(encoder) => encoder.finish()?.flush()?,
+        }
+        Ok(self.written)
+    }
+}
+
+/// Write records to any writer; see `write_records`
+pub fn write_records_to<'a, W: Write>(
+    writer: &mut W,
+    records: impl IntoIterator<Item = &'a SequenceRecord>,
+    format: SequenceFormat,
+) -> Result<usize> {
+    let mut written = 0;
+    for record in records {
+        match format {
+            SequenceFormat::Fasta => writeln!(writer, ">{}\n{}", record.id, record.seq)?,
+            SequenceFormat::Fastq => {
+                let qual = record
+                    .qual
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("Record {} has no qualities for FASTQ", record.id))?;
+                if qual.len() != record.seq.len() {
+                    return Err(anyhow!(
+                        "Record {} has {} bases but {} qualities",
+                        record.id,
+                        record.seq.len(),
+                        qual.len()
+                    ));
+                }
+                writeln!(writer, "@{}\n{}\n+\n{}", record.id, record.seq, qual)?;
+            }
+        }
+        written += 1;
+    }
+    Ok(written)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -132,6 +261,71 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_write_records_fasta_and_gzipped_fastq() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let temp_dir = tempdir().unwrap();
+        let records = vec![
+            SequenceRecord {
+                id: "r1".to_string(),
+                seq: "ACGT".to_string(),
+                qual: Some("IIII".to_string()),
+            },
+            SequenceRecord {
+                id: "r2".to_string(),
+                seq: "GG".to_string(),
+                qual: Some("##".to_string()),
+            },
+        ];
+
+        let fasta = temp_dir.path().join("out.fa");
+        assert_eq!(SequenceFormat::from_path(&fasta), SequenceFormat::Fasta);
+        write_records(&fasta, &records, SequenceFormat::Fasta, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&fasta).unwrap(),
+            ">r1\nACGT\n>r2\nGG\n"
+        );
+
+        let fastq = temp_dir.path().join("out.fastq.gz");
+        assert_eq!(SequenceFormat::from_path(&fastq), SequenceFormat::Fastq);
+        assert_eq!(
+            write_records(&fastq, &records, SequenceFormat::Fastq, true).unwrap(),
+            2
+        );
+        let mut text = String::new();
+        GzDecoder::new(File::open(&fastq).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "@r1\nACGT\n+\nIIII\n@r2\nGG\n+\n##\n");
+
+        // Plain FASTQ output reads back unchanged
+        let plain = temp_dir.path().join("out.fastq");
+        write_records(&plain, &records, SequenceFormat::Fastq, false).unwrap();
+        let read_back = read_sequences_stream(&[plain.to_string_lossy().to_string()]).unwrap();
+        assert_eq!(read_back[1].seq, "GG");
+        assert_eq!(read_back[1].qual.as_deref(), Some("##"));
+
+        let no_qual = [SequenceRecord {
+            id: "r3".to_string(),
+            seq: "A".to_string(),
+            qual: None,
+        }];
+        assert!(write_records(&fastq, &no_qual, SequenceFormat::Fastq, true).is_err());
+
+        // Written a batch at a time, the file is the same
+        let streamed = temp_dir.path().join("streamed.fa");
+        let mut writer = RecordWriter::create(&streamed, SequenceFormat::Fasta, false).unwrap();
+        writer.write(&records[..1]).unwrap();
+        writer.write(&records[1..]).unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+        assert_eq!(
+            std::fs::read(&streamed).unwrap(),
+            std::fs::read(&fasta).unwrap()
+        );
+    }
+
     #[test]
     fn test_nonexistent_file() {
         let result = read_sequences_stream(&["nonexistent.fastq".to_string()]);
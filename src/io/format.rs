@@ -0,0 +1,241 @@
+//! Format versions of serialized artifacts.
+//!
+//! Sample signatures, database records, results files, count tables and
+//! reference bundles outlive the build that wrote them: they are archived
+//! with a study and read again years later, often by a newer (or older)
+//! strain_ahsp. Each records the version of its layout, and readers check it
+//! before anything else. A file from a newer build is refused with
+//! `UnsupportedVersion` naming the build to update to, instead of failing
+//! somewhere in the middle of parsing, or worse, being read as the wrong
+//! fields. Files written before versions were recorded are version 0 and
+//! read as they always were.
+//!
+//! JSON artifacts carry a `format_version` field, count tables a
+//! `# format_version: N` line among their leading `#` lines, and database
+//! records a short binary header (see `SignatureDatabase`). A change to any
+//! of these layouts bumps its version in `Artifact::current`, together with
+//! the reader of the previous one, or a raised `Artifact::oldest_readable`.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::database::bundle::BUNDLE_FORMAT_VERSION;
+use crate::io::json::open_json;
+
+/// Field (JSON) or `#` line key (CSV) holding the format version
+pub const FORMAT_VERSION_KEY: &str = "format_version";
+
+/// Kinds of serialized artifacts, each versioned on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Artifact {
+    /// A sample's stored sketch and read metrics (`*_sketch.json`)
+    Signature,
+    /// A signature stored in the database
+    DatabaseRecord,
+    /// A sample's classification results (`*_results.json`)
+    Results,
+    /// A count table CSV
+    CountTable,
+    /// A reference bundle (`.ahspdb`)
+    Bundle,
+}
+
+impl Artifact {
+    /// Version written by this build
+    pub const fn current(self) -> u32 {
+        match self {
+            Artifact::Signature
+            | Artifact::DatabaseRecord
+            | Artifact::Results
+            | Artifact::CountTable => 1,
+            Artifact::Bundle => BUNDLE_FORMAT_VERSION,
+        }
+    }
+
+    /// Oldest version this build still reads; 0 stands for artifacts written
+    /// before versions were recorded
+    pub const fn oldest_readable(self) -> u32 {
+        match self {
+            Artifact::Bundle => 1,
+            _ => 0,
+        }
+    }
+
+    /// Whether an artifact of version `found`, read from `location`, can be
+    /// read by this build
+    pub fn check(self, found: u32, location: impl fmt::Display) -> Result<(), UnsupportedVersion> {
+        if (self.oldest_readable()..=self.current()).contains(&found) {
+            Ok(())
+        } else {
+            Err(UnsupportedVersion {
+                artifact: self,
+                found,
+                location: location.to_string(),
+            })
+        }
+    }
+}
+
+impl fmt::Display for Artifact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Artifact::Signature => "sample signature",
+            Artifact::DatabaseRecord => "database record",
+            Artifact::Results => "results file",
+            Artifact::CountTable => "count table",
+            Artifact::Bundle => "reference bundle",
+        })
+    }
+}
+
+/// An artifact whose format version this build cannot read
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{location}: {artifact} format version {found} {}", advice(*artifact, *found))]
+pub struct UnsupportedVersion {
+    pub artifact: Artifact,
+    /// Version recorded in the artifact
+    pub found: u32,
+    /// File or database key the artifact was read from
+    pub location: String,
+}
+
+/// What to do about an artifact of an unsupported version
+fn advice(artifact: Artifact, found: u32) -> String {
+    if found > artifact.current() {
+        format!(
+            "is newer than this build reads (up to {}); update strain_ahsp",
+            artifact.current()
+        )
+    } else {
+        format!(
+            "is older than this build reads ({} or later); re-create it with strain_ahsp {}",
+            artifact.oldest_readable(),
+            env!("CARGO_PKG_VERSION")
+        )
+    }
+}
+
+/// Why a versioned JSON artifact could not be read
+#[derive(Debug, Error)]
+pub enum ArtifactError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    UnsupportedVersion(#[from] UnsupportedVersion),
+}
+
+/// Read a JSON artifact from `path`, compressed or not. Its `format_version`
+/// (0 when missing) is checked before the rest is parsed, so a layout this
+/// build does not know is reported as such rather than as a parse error.
+pub fn read_versioned_json<T: DeserializeOwned>(
+    artifact: Artifact,
+    path: &Path,
+) -> Result<T, ArtifactError> {
+    let value: serde_json::Value = serde_json::from_reader(open_json(path)?)?;
+    let found = match value.get(FORMAT_VERSION_KEY) {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid {} '{}'", FORMAT_VERSION_KEY, version),
+                )
+            })?,
+    };
+    artifact.check(found, path.display())?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Write the `# format_version: N` line of a CSV artifact
+pub fn write_csv_format_version<W: Write>(artifact: Artifact, writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "# {}: {}", FORMAT_VERSION_KEY, artifact.current())
+}
+
+/// Version in the `# format_version: N` line among the `#` lines that start
+/// the CSV file at `path`, 0 when there is none
+pub fn csv_format_version(path: &Path) -> io::Result<u32> {
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let Some(comment) = line.strip_prefix('#') else {
+            break;
+        };
+        let Some(version) = comment
+            .trim()
+            .strip_prefix(FORMAT_VERSION_KEY)
+            .and_then(|rest| rest.strip_prefix(':'))
+        else {
+            continue;
+        };
+        return version.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: invalid {} '{}'",
+                    path.display(),
+                    FORMAT_VERSION_KEY,
+                    version.trim()
+                ),
+            )
+        });
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    #[test]
+    fn test_versions_are_negotiated_before_parsing() {
+        assert!(Artifact::Results.check(0, "S1").is_ok());
+        assert!(Artifact::Results.check(1, "S1").is_ok());
+        let newer = Artifact::Results.check(2, "S1_results.json").unwrap_err();
+        assert_eq!(
+            newer.to_string(),
+            "S1_results.json: results file format version 2 is newer than this build \
+             reads (up to 1); update strain_ahsp"
+        );
+        let older = Artifact::Bundle.check(0, "panel.ahspdb").unwrap_err();
+        assert!(older
+            .to_string()
+            .contains("is older than this build reads (1 or later)"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("S1_results.json");
+        fs::write(&path, r#"{"format_version": 1, "sample_id": "S1"}"#).unwrap();
+        let value: serde_json::Value = read_versioned_json(Artifact::Results, &path).unwrap();
+        assert_eq!(value["sample_id"], "S1");
+        fs::write(&path, r#"{"sample_id": "S1"}"#).unwrap();
+        assert!(read_versioned_json::<serde_json::Value>(Artifact::Results, &path).is_ok());
+        // The version is checked even when the rest no longer parses
+        fs::write(&path, r#"{"format_version": 7, "sample_id": ["S1"]}"#).unwrap();
+        match read_versioned_json::<BTreeMap<String, String>>(Artifact::Results, &path) {
+            Err(ArtifactError::UnsupportedVersion(e)) => assert_eq!(e.found, 7),
+            other => panic!("expected an unsupported version, got {:?}", other),
+        }
+
+        let table = dir.path().join("counts.csv");
+        let mut csv = Vec::new();
+        write_csv_format_version(Artifact::CountTable, &mut csv).unwrap();
+        csv.extend_from_slice(b"# strain_ahsp_version: 0.1.0\nFeature,S1\nGCF_1,3\n");
+        fs::write(&table, &csv).unwrap();
+        assert_eq!(csv_format_version(&table).unwrap(), 1);
+        fs::write(&table, "Feature,S1\n# format_version: 3\n").unwrap();
+        assert_eq!(csv_format_version(&table).unwrap(), 0);
+        fs::write(&table, "# format_version: x\nFeature,S1\n").unwrap();
+        assert!(csv_format_version(&table).is_err());
+    }
+}
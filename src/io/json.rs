@@ -0,0 +1,164 @@
+//! JSON outputs, optionally compressed.
+//!
+//! A results file carries the sample's classifications, strain estimates,
+//! clusters and warnings, and with debug traces or many candidate strains it
+//! runs to many megabytes per sample. JSON compresses well, so results and
+//! sample sketches can be written with gzip (`.json.gz`) or zstd
+//! (`.json.zst`). Readers go by the content rather than the name: a file is
+//! decompressed when it starts with the gzip or zstd magic bytes, so every
+//! command that reads results accepts all three forms.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// zstd level of compressed JSON; higher levels gain little on JSON
+const ZSTD_LEVEL: i32 = 3;
+
+/// Bytes that start a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Bytes that start a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How JSON outputs are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonCompression {
+    /// Plain, indented JSON
+    #[default]
+    None,
+    /// gzip, readable with `zcat`
+    Gzip,
+    /// zstd, smaller and faster than gzip
+    Zstd,
+}
+
+impl JsonCompression {
+    /// Extension added after `.json`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            JsonCompression::None => "",
+            JsonCompression::Gzip => ".gz",
+            JsonCompression::Zstd => ".zst",
+        }
+    }
+
+    /// `path` (ending in `.json`) with the extension of this compression
+    pub fn path(&self, path: PathBuf) -> PathBuf {
+        let mut path = path.into_os_string();
+        path.push(self.extension());
+        PathBuf::from(path)
+    }
+}
+
+/// Whether a file name ends in `suffix` (e.g. `_results.json`), compressed or not
+pub fn has_json_suffix(name: &str, suffix: &str) -> bool {
+    [
+        JsonCompression::None,
+        JsonCompression::Gzip,
+        JsonCompression::Zstd,
+    ]
+    .iter()
+    .any(|compression| {
+        name.strip_suffix(compression.extension())
+            .is_some_and(|name| name.ends_with(suffix))
+    })
+}
+
+/// Write `value` as JSON to `path`, indented when uncompressed
+pub fn write_json<T: Serialize>(
+    path: &Path,
+    value: &T,
+    compression: JsonCompression,
+) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    match compression {
+        JsonCompression::None => {
+            serde_json::to_writer_pretty(file, value)?;
+        }
+        JsonCompression::Gzip => {
+            let mut encoder = GzEncoder::new(file, flate2::Compression::default());
+            serde_json::to_writer(&mut encoder, value)?;
+            encoder.finish()?.flush()?;
+        }
+        JsonCompression::Zstd => {
+            let mut encoder = zstd::Encoder::new(file, ZSTD_LEVEL)?;
+            serde_json::to_writer(&mut encoder, value)?;
+            encoder.finish()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Open a JSON file for reading, decompressing gzip and zstd files
+pub fn open_json(path: &Path) -> io::Result<Box<dyn Read>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    let mut read = 0;
+    while read < magic.len() {
+        match file.read(&mut magic[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    let reader = BufReader::new(io::Cursor::new(magic[..read].to_vec()).chain(file));
+    if magic[..read].starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else if magic[..read].starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(BufReader::new(zstd::Decoder::with_buffer(
+            reader,
+        )?)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Read JSON from `path`, compressed or not
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    Ok(serde_json::from_reader(open_json(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_json_round_trips_in_every_compression() {
+        let value: BTreeMap<String, Vec<u32>> = (0..200)
+            .map(|i| (format!("taxon_{}", i), vec![i; 20]))
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let mut sizes = Vec::new();
+        for compression in [
+            JsonCompression::None,
+            JsonCompression::Gzip,
+            JsonCompression::Zstd,
+        ] {
+            let path = compression.path(dir.path().join("S1_results.json"));
+            write_json(&path, &value, compression).unwrap();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            assert!(has_json_suffix(name, "_results.json"), "{}", name);
+            let read: BTreeMap<String, Vec<u32>> = read_json(&path).unwrap();
+            assert_eq!(read, value);
+            sizes.push(std::fs::metadata(&path).unwrap().len());
+        }
+        assert!(sizes[1] < sizes[0] / 4 && sizes[2] < sizes[0] / 4);
+        assert_eq!(
+            JsonCompression::Zstd.path(PathBuf::from("out/S1_results.json")),
+            PathBuf::from("out/S1_results.json.zst")
+        );
+        assert!(!has_json_suffix("S1_results.json.bak", "_results.json"));
+
+        // Files too short for a magic number are read as they are
+        let tiny = dir.path().join("tiny.json");
+        std::fs::write(&tiny, "1").unwrap();
+        assert_eq!(read_json::<u32>(&tiny).unwrap(), 1);
+    }
+}
@@ -0,0 +1,160 @@
+//! Per-read assignments in Kraken's standard output format.
+//!
+//! Each read is one tab-separated line:
+//!
+//! ```text
+//! C/U  read_id  taxid  length  LCA-kmer-string
+//! ```
+//!
+//! `length` is `len1|len2` for read pairs, and the k-mer string lists runs of
+//! consecutive k-mers as `taxid:count`, with `0` for k-mers without a hit,
+//! `A` for ambiguous k-mers (containing an N) and `|:|` between mates. Lines
+//! carry no header or comments, so KrakenTools scripts such as
+//! `extract_kraken_reads.py` read the file unmodified.
+//!
+//! Classification here works on sample sketches, so nothing produces per-read
+//! assignments yet; this is the output side for when it does.
+
+use std::io::{self, Write};
+
+/// What a single k-mer of a read was assigned to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KmerCall {
+    /// Lowest common ancestor of the references containing the k-mer
+    Taxon(String),
+    /// The k-mer is in no reference
+    Unassigned,
+    /// The k-mer contains an ambiguous base
+    Ambiguous,
+}
+
+/// Classification of one read or read pair
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadAssignment {
+    /// Read name; anything after the first whitespace is dropped
+    pub read_id: String,
+    /// Assigned taxon, `None` if the read is unclassified
+    pub taxon_id: Option<String>,
+    /// Length of each mate (one entry for single-end reads)
+    pub lengths: Vec<usize>,
+    /// Calls of each k-mer, per mate in read order
+    pub kmer_calls: Vec<Vec<KmerCall>>,
+}
+
+impl ReadAssignment {
+    /// The read as one line of Kraken output, without the newline
+    pub fn kraken_line(&self) -> String {
+        let read_id = self.read_id.split_whitespace().next().unwrap_or("");
+        let lengths: Vec<String> = self.lengths.iter().map(|l| l.to_string()).collect();
+        let mates: Vec<String> = self
+            .kmer_calls
+            .iter()
+            .map(|calls| lca_string(calls))
+            .collect();
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            if self.taxon_id.is_some() { "C" } else { "U" },
+            read_id,
+            self.taxon_id.as_deref().unwrap_or("0"),
+            lengths.join("|"),
+            mates.join(" |:| ")
+        )
+    }
+}
+
+/// Run-length encode the k-mer calls of one mate as `taxid:count` pairs
+fn lca_string(calls: &[KmerCall]) -> String {
+    let label = |call: &KmerCall| match call {
+        KmerCall::Taxon(taxon) => taxon.clone(),
+        KmerCall::Unassigned => "0".to_string(),
+        KmerCall::Ambiguous => "A".to_string(),
+    };
+    let mut runs: Vec<(String, usize)> = Vec::new();
+    for call in calls {
+        let call = label(call);
+        match runs.last_mut() {
+            Some((last, count)) if *last == call => *count += 1,
+            _ => runs.push((call, 1)),
+        }
+    }
+    runs.iter()
+        .map(|(taxon, count)| format!("{}:{}", taxon, count))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Writes read assignments as Kraken output, counting classified reads
+pub struct KrakenWriter<W: Write> {
+    writer: W,
+    pub classified: usize,
+    pub unclassified: usize,
+}
+
+impl<W: Write> KrakenWriter<W> {
+    pub fn new(writer: W) -> Self {
+        KrakenWriter {
+            writer,
+            classified: 0,
+            unclassified: 0,
+        }
+    }
+
+    /// Append one read
+    pub fn write(&mut self, assignment: &ReadAssignment) -> io::Result<()> {
+        if assignment.taxon_id.is_some() {
+            self.classified += 1;
+        } else {
+            self.unclassified += 1;
+        }
+        writeln!(self.writer, "{}", assignment.kraken_line())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kraken_lines_for_single_and_paired_reads() {
+        let taxon = |t: &str| KmerCall::Taxon(t.to_string());
+        let single = ReadAssignment {
+            read_id: "read1 length=10".to_string(),
+            taxon_id: Some("562".to_string()),
+            lengths: vec![10],
+            kmer_calls: vec![vec![
+                taxon("562"),
+                taxon("562"),
+                KmerCall::Unassigned,
+                KmerCall::Ambiguous,
+                taxon("561"),
+                taxon("562"),
+            ]],
+        };
+        assert_eq!(
+            single.kraken_line(),
+            "C\tread1\t562\t10\t562:2 0:1 A:1 561:1 562:1"
+        );
+
+        let pair = ReadAssignment {
+            read_id: "read2".to_string(),
+            taxon_id: None,
+            lengths: vec![8, 7],
+            kmer_calls: vec![vec![KmerCall::Unassigned; 3], vec![KmerCall::Unassigned; 2]],
+        };
+        assert_eq!(pair.kraken_line(), "U\tread2\t0\t8|7\t0:3 |:| 0:2");
+
+        let mut writer = KrakenWriter::new(Vec::new());
+        writer.write(&single).unwrap();
+        writer.write(&pair).unwrap();
+        assert_eq!((writer.classified, writer.unclassified), (1, 1));
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.ends_with('\n'));
+    }
+}
@@ -3,35 +3,64 @@
 //! Handles reading data (like FASTQ files, metadata) and writing
 //! results (like count tables, analysis outputs).
 
+pub mod escape;
 pub mod fastq; // Sub-module specifically for FASTQ handling
+pub mod format;
+pub mod json;
+pub mod kraken;
+pub mod npy;
+pub mod phyloseq;
 
-use crate::count_table::CountTable;
+use crate::count_table::{CountTable, DuplicatePolicy};
+use crate::io::format::{csv_format_version, write_csv_format_version, Artifact};
+use crate::provenance::Provenance;
 // use crate::metadata::Metadata; // Using internally
-use crate::stats::{AnalysisResults, Metadata}; // Assuming stats module defines this
-use anyhow::Result;
+use crate::stats::{AnalysisResults, DifferentialResult, Metadata, ModelCoefficient}; // Assuming stats module defines this
+use anyhow::{anyhow, bail, Context, Result};
 use csv; // Using the csv crate
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::Path;
 
 /// Writes analysis results to a CSV file.
 ///
+/// After the test columns come the dispersion, the degrees of freedom and an
+/// `<term>_estimate` / `<term>_se` pair for every model term of any feature,
+/// so studies can be combined by inverse-variance weighting from the CSV.
+///
 /// # Arguments
 ///
 /// * `results` - The analysis results structure to write.
+/// * `provenance` - Optional provenance, written as `#` header lines before the table.
 /// * `output_path` - The path to the output CSV file.
 ///
 /// # Returns
 ///
 /// * `Result<()>` - Ok(()) if writing was successful, or an error.
-pub fn write_results(results: &AnalysisResults, output_path: &str) -> Result<()> {
+pub fn write_results(
+    results: &AnalysisResults,
+    provenance: Option<&Provenance>,
+    output_path: &str,
+) -> Result<()> {
     let path = Path::new(output_path);
-    let file = File::create(path)?;
-    let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+    let mut file = BufWriter::new(File::create(path)?);
+    if let Some(provenance) = provenance {
+        provenance.write_header(&mut file)?;
+    }
+    let mut writer = escape::csv_writer_builder().from_writer(file);
+
+    // Model terms in the order they first appear
+    let mut terms: Vec<&str> = Vec::new();
+    for coefficient in results.iter().flat_map(|r| &r.coefficients) {
+        if !terms.contains(&coefficient.term.as_str()) {
+            terms.push(&coefficient.term);
+        }
+    }
 
     // Write header row - Adjust based on AnalysisResults structure
     // Example header:
-    writer.write_record(&[
+    let mut header: Vec<String> = [
         "feature_id",
         "base_mean", // Average normalized count
         "log2_fold_change",
@@ -39,7 +68,19 @@ pub fn write_results(results: &AnalysisResults, output_path: &str) -> Result<()>
         "stat",      // Wald statistic or similar
         "p_value",
         "p_adjusted", // Adjusted p-value (e.g., Benjamini-Hochberg)
-    ])?;
+        "dispersion",
+        "df",
+    ]
+    .iter()
+    .map(|column| column.to_string())
+    .collect();
+    for term in &terms {
+        header.push(format!("{}_estimate", term));
+        header.push(format!("{}_se", term));
+    }
+    writer.write_record(&header)?;
+
+    let na = |value: Option<f64>| value.map_or("NA".to_string(), |v| v.to_string());
 
     // Iterate through results and write each row
     // This depends heavily on the structure of AnalysisResults
@@ -64,29 +105,120 @@ pub fn write_results(results: &AnalysisResults, output_path: &str) -> Result<()>
             .p_adjusted
             .map_or("NA".to_string(), |v| v.to_string());
 
-        writer.write_record(&[
-            feature_id, &base_mean, &log2fc, &stderr, &stat, &pval, &padj,
-        ])?;
+        let mut record = vec![
+            feature_id.clone(),
+            base_mean,
+            log2fc,
+            stderr,
+            stat,
+            pval,
+            padj,
+            na(result_item.dispersion),
+            na(result_item.degrees_of_freedom),
+        ];
+        for term in &terms {
+            let coefficient = result_item.coefficients.iter().find(|c| c.term == *term);
+            record.push(na(coefficient.map(|c| c.estimate)));
+            record.push(na(coefficient.and_then(|c| c.std_error)));
+        }
+        writer.write_record(&record)?;
     }
 
     writer.flush()?; // Ensure all data is written to the file
     Ok(())
 }
 
+/// Reads analysis results as written by `write_results`.
+///
+/// `#` provenance lines are skipped and `NA` fields read as missing. Columns
+/// added after the original seven (dispersion, df, model terms) are optional,
+/// so older results files can still be read.
+pub fn read_results(path: &Path) -> Result<AnalysisResults> {
+    let mut reader = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .from_path(path)
+        .with_context(|| format!("Cannot open results {}", path.display()))?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let feature_column =
+        column("feature_id").ok_or_else(|| anyhow!("{}: no feature_id column", path.display()))?;
+    let terms: Vec<(String, usize, Option<usize>)> = headers
+        .iter()
+        .enumerate()
+        .filter_map(|(i, h)| {
+            let term = h.strip_suffix("_estimate")?;
+            Some((term.to_string(), i, column(&format!("{}_se", term))))
+        })
+        .collect();
+
+    let mut results = AnalysisResults::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        let value = |index: Option<usize>| -> Result<Option<f64>> {
+            match index.and_then(|i| record.get(i)).map(str::trim) {
+                None | Some("NA") | Some("") => Ok(None),
+                Some(field) => field.parse().map(Some).map_err(|_| {
+                    anyhow!(
+                        "{} line {}: invalid number '{}'",
+                        path.display(),
+                        line,
+                        field
+                    )
+                }),
+            }
+        };
+        let mut coefficients = Vec::new();
+        for (term, estimate_column, se_column) in &terms {
+            if let Some(estimate) = value(Some(*estimate_column))? {
+                coefficients.push(ModelCoefficient {
+                    term: term.clone(),
+                    estimate,
+                    std_error: value(*se_column)?,
+                });
+            }
+        }
+        results.push(DifferentialResult {
+            feature_id: record.get(feature_column).unwrap_or_default().to_string(),
+            base_mean: value(column("base_mean"))?.unwrap_or(0.0),
+            log2_fold_change: value(column("log2_fold_change"))?,
+            std_error: value(column("std_error"))?,
+            statistic: value(column("stat"))?,
+            p_value: value(column("p_value"))?,
+            p_adjusted: value(column("p_adjusted"))?,
+            dispersion: value(column("dispersion"))?,
+            coefficients,
+            degrees_of_freedom: value(column("df"))?,
+        });
+    }
+    Ok(results)
+}
+
 /// Writes a CountTable to a CSV file.
 ///
+/// The first line records the format version (`# format_version: N`).
+///
 /// # Arguments
 ///
 /// * `table` - The CountTable to write.
+/// * `provenance` - Optional provenance, written as `#` header lines before the table.
 /// * `output_path` - The path to the output CSV file.
 ///
 /// # Returns
 ///
 /// * `Result<()>` - Ok(()) if writing was successful, or an error.
-pub fn write_count_table(table: &CountTable, output_path: &str) -> Result<()> {
+pub fn write_count_table(
+    table: &CountTable,
+    provenance: Option<&Provenance>,
+    output_path: &str,
+) -> Result<()> {
     let path = Path::new(output_path);
-    let file = File::create(path)?;
-    let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+    let mut file = BufWriter::new(File::create(path)?);
+    write_csv_format_version(Artifact::CountTable, &mut file)?;
+    if let Some(provenance) = provenance {
+        provenance.write_header(&mut file)?;
+    }
+    let mut writer = escape::csv_writer_builder().from_writer(file);
 
     // Prepare header: "Feature" followed by sample names
     let mut header = vec!["Feature".to_string()];
@@ -111,24 +243,93 @@ pub fn write_count_table(table: &CountTable, output_path: &str) -> Result<()> {
     Ok(())
 }
 
+/// Reads a count table CSV as written by `write_count_table`.
+///
+/// The format version is checked first; tables without one predate it and
+/// are read as version 0. Other `#` lines before the header are skipped.
+/// Counts must be finite and non-negative.
+///
+/// # Arguments
+///
+/// * `path` - Path to the count table CSV.
+/// * `duplicates` - How sample columns sharing a name are handled; merged
+///   columns are summed.
+///
+/// # Returns
+///
+/// * `Result<CountTable>` - The table, with samples and features sorted by name.
+pub fn read_count_table(path: &Path, duplicates: DuplicatePolicy) -> Result<CountTable> {
+    let version = csv_format_version(path)
+        .with_context(|| format!("Cannot open count table {}", path.display()))?;
+    Artifact::CountTable.check(version, path.display())?;
+    let mut reader = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .from_path(path)
+        .with_context(|| format!("Cannot open count table {}", path.display()))?;
+    let columns: Vec<String> = reader.headers()?.iter().skip(1).map(String::from).collect();
+    let sample_names =
+        duplicates.sample_ids(&columns, &format!("Count table {}", path.display()))?;
+    let mut data: HashMap<String, HashMap<String, f64>> = sample_names
+        .iter()
+        .map(|sample| (sample.clone(), HashMap::new()))
+        .collect();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        if record.len() != sample_names.len() + 1 {
+            bail!(
+                "{} line {}: expected {} columns, found {}",
+                path.display(),
+                line,
+                sample_names.len() + 1,
+                record.len()
+            );
+        }
+        // Merged columns add up within a row
+        let mut row: HashMap<&str, f64> = HashMap::new();
+        for (sample, field) in sample_names.iter().zip(record.iter().skip(1)) {
+            let count = field
+                .trim()
+                .parse()
+                .ok()
+                .filter(|c: &f64| c.is_finite() && *c >= 0.0)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{} line {}: invalid count '{}'",
+                        path.display(),
+                        line,
+                        field
+                    )
+                })?;
+            *row.entry(sample).or_default() += count;
+        }
+        for (sample, count) in row {
+            data.get_mut(sample)
+                .expect("one entry per sample")
+                .insert(record[0].to_string(), count);
+        }
+    }
+    CountTable::build_from_data(&data)
+}
+
 /// Reads metadata from a file (typically CSV format).
 ///
 /// # Arguments
 ///
 /// * `metadata_path` - Path to the metadata file.
+/// * `duplicates` - How rows sharing a sample ID are handled.
 ///
 /// # Returns
 ///
 /// * `Result<Metadata>` - Loaded metadata structure or an error.
-pub fn read_metadata(metadata_path: &str) -> Result<Metadata> {
-    Metadata::from_file(metadata_path)
+pub fn read_metadata(metadata_path: &str, duplicates: DuplicatePolicy) -> Result<Metadata> {
+    Metadata::from_file(metadata_path, duplicates)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::count_table::CountTable;
-    use crate::stats::DifferentialResult; // Assuming this struct exists
     use ndarray::arr2;
     use std::fs;
     use tempfile::tempdir;
@@ -174,6 +375,20 @@ mod tests {
                 statistic: Some(2.0),
                 p_value: Some(0.05),
                 p_adjusted: Some(0.1),
+                dispersion: Some(0.2),
+                coefficients: vec![
+                    ModelCoefficient {
+                        term: "(Intercept)".to_string(),
+                        estimate: 3.5,
+                        std_error: Some(0.25),
+                    },
+                    ModelCoefficient {
+                        term: "condition_B_vs_A".to_string(),
+                        estimate: 1.0,
+                        std_error: Some(0.5),
+                    },
+                ],
+                degrees_of_freedom: Some(4.0),
             },
             DifferentialResult {
                 feature_id: "GeneB".to_string(),
@@ -183,6 +398,9 @@ mod tests {
                 statistic: None,
                 p_value: None,
                 p_adjusted: None,
+                dispersion: None,
+                coefficients: Vec::new(),
+                degrees_of_freedom: None,
             },
         ]
     }
@@ -194,10 +412,11 @@ mod tests {
         let file_path = dir.path().join("counts.csv");
         let output_path_str = file_path.to_str().unwrap();
 
-        write_count_table(&table, output_path_str).unwrap();
+        write_count_table(&table, None, output_path_str).unwrap();
 
         let content = fs::read_to_string(file_path).unwrap();
         let expected_content = "\
+# format_version: 1\n\
 Feature,Sample1,Sample2\n\
 GeneA,10.0,20.0\n\
 GeneB,5.0,0.0\n";
@@ -206,6 +425,43 @@ GeneB,5.0,0.0\n";
         dir.close().unwrap();
     }
 
+    #[test]
+    fn test_read_count_table_round_trip() {
+        let table = create_test_count_table();
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("counts.csv");
+        let provenance = Provenance::new(&[], None, &serde_json::json!({})).unwrap();
+        write_count_table(&table, Some(&provenance), file_path.to_str().unwrap()).unwrap();
+
+        let read = read_count_table(&file_path, DuplicatePolicy::Error).unwrap();
+        assert_eq!(read.sample_names(), table.sample_names());
+        assert_eq!(read.feature_names(), table.feature_names());
+        assert_eq!(read.counts_matrix(), table.counts_matrix());
+
+        fs::write(&file_path, "Feature,Sample1\nGeneA,-1\n").unwrap();
+        assert!(read_count_table(&file_path, DuplicatePolicy::Error).is_err());
+
+        // Tables from before versions were recorded still read; newer ones do not
+        fs::write(&file_path, "Feature,Sample1\nGeneA,1\n").unwrap();
+        assert!(read_count_table(&file_path, DuplicatePolicy::Error).is_ok());
+        fs::write(
+            &file_path,
+            "# format_version: 2\nFeature,Sample1\nGeneA,1\n",
+        )
+        .unwrap();
+        let newer = read_count_table(&file_path, DuplicatePolicy::Error).unwrap_err();
+        assert!(newer.downcast_ref::<format::UnsupportedVersion>().is_some());
+
+        // A re-sequenced sample given twice
+        fs::write(&file_path, "Feature,S1,S2,S1\nGeneA,1,2,3\n").unwrap();
+        assert!(read_count_table(&file_path, DuplicatePolicy::Error).is_err());
+        let suffixed = read_count_table(&file_path, DuplicatePolicy::Suffix).unwrap();
+        assert_eq!(suffixed.sample_names(), &vec!["S1", "S1_2", "S2"]);
+        let merged = read_count_table(&file_path, DuplicatePolicy::Merge).unwrap();
+        assert_eq!(merged.sample_names(), &vec!["S1", "S2"]);
+        assert_eq!(merged.get_sample_counts("S1").unwrap()[0], 4.0);
+    }
+
     #[test]
     fn test_write_results_csv() {
         let results = create_test_analysis_results();
@@ -213,15 +469,32 @@ GeneB,5.0,0.0\n";
         let file_path = dir.path().join("results.csv");
         let output_path_str = file_path.to_str().unwrap();
 
-        write_results(&results, output_path_str).unwrap();
+        write_results(&results, None, output_path_str).unwrap();
 
         let content = fs::read_to_string(file_path).unwrap();
         let expected_content = "\
-feature_id,base_mean,log2_fold_change,std_error,stat,p_value,p_adjusted\n\
-GeneA,15.0,1.0,0.5,2.0,0.05,0.1\n\
-GeneB,2.5,NA,NA,NA,NA,NA\n"; // Note NA for None values
+feature_id,base_mean,log2_fold_change,std_error,stat,p_value,p_adjusted,dispersion,df,\
+(Intercept)_estimate,(Intercept)_se,condition_B_vs_A_estimate,condition_B_vs_A_se\n\
+GeneA,15.0,1.0,0.5,2.0,0.05,0.1,0.2,4.0,3.5,0.25,1.0,0.5\n\
+GeneB,2.5,NA,NA,NA,NA,NA,NA,NA,NA,NA,NA,NA\n"; // Note NA for None values
         assert_eq!(content, expected_content);
 
         dir.close().unwrap();
     }
+
+    #[test]
+    fn test_read_results_round_trip() {
+        let results = create_test_analysis_results();
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("results.csv");
+        let provenance = Provenance::new(&[], None, &serde_json::json!({})).unwrap();
+        write_results(&results, Some(&provenance), file_path.to_str().unwrap()).unwrap();
+
+        let read = read_results(&file_path).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].coefficients, results[0].coefficients);
+        assert_eq!(read[0].degrees_of_freedom, Some(4.0));
+        assert_eq!(read[1].log2_fold_change, None);
+        assert!(read[1].coefficients.is_empty());
+    }
 }
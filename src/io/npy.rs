@@ -0,0 +1,69 @@
+//! NumPy `.npy` arrays.
+//!
+//! Feature matrices meant for model training are loaded with
+//! `numpy.load`, which reads `.npy` files directly and without a copy
+//! through text. The format is a short header, a Python dict literal giving
+//! the element type, memory order and shape, followed by the raw
+//! little-endian values; only 2-D `float32` arrays in C order are written.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use ndarray::Array2;
+
+/// Bytes that start every `.npy` file, followed by the format version (1.0)
+const MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+
+/// The header is padded so the values start at a multiple of this
+const ALIGNMENT: usize = 64;
+
+/// Write `array` to `path` as a little-endian `float32` `.npy` array
+pub fn write_npy(path: &Path, array: &Array2<f32>) -> io::Result<()> {
+    let (rows, columns) = array.dim();
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows, columns
+    );
+    // Magic, version, 2-byte header length, header, padding and newline
+    let unpadded = MAGIC.len() + 2 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(ALIGNMENT) - unpadded));
+    header.push('\n');
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.write_all(&(header.len() as u16).to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
+    for value in array.iter() {
+        file.write_all(&value.to_le_bytes())?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::arr2;
+
+    #[test]
+    fn test_npy_header_and_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("features.npy");
+        write_npy(&path, &arr2(&[[1.0, 0.5, 0.0], [0.25, 2.0, -1.0]])).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.starts_with(MAGIC));
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        let data_start = 10 + header_len;
+        assert_eq!(data_start % ALIGNMENT, 0);
+        let header = std::str::from_utf8(&bytes[10..data_start]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"));
+        assert!(header.ends_with(" \n"));
+
+        let values: Vec<f32> = bytes[data_start..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, vec![1.0, 0.5, 0.0, 0.25, 2.0, -1.0]);
+    }
+}
@@ -0,0 +1,242 @@
+//! Export of phyloseq input tables.
+//!
+//! Writes the three tables `phyloseq()` expects — an OTU (feature) table, a
+//! taxonomy table built from reference lineages and a sample data table from
+//! the study metadata — as CSVs sharing the same feature and sample IDs,
+//! together with a short R script that loads them.
+
+use anyhow::Result;
+use log::warn;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::count_table::CountTable;
+use crate::io::escape::csv_writer_builder;
+use crate::metadata::Metadata;
+use crate::provenance::Provenance;
+
+/// Taxonomy table columns, matching the positional lineage layout used by the classifier
+pub const TAX_RANKS: [&str; 9] = [
+    "Domain",
+    "Phylum",
+    "Class",
+    "Order",
+    "Family",
+    "Genus",
+    "Species",
+    "StrainGroup",
+    "Strain",
+];
+
+/// Paths of the files written by `write_phyloseq_tables`
+#[derive(Debug, Clone)]
+pub struct PhyloseqFiles {
+    pub otu_table: PathBuf,
+    pub tax_table: PathBuf,
+    pub sample_data: PathBuf,
+    pub import_script: PathBuf,
+}
+
+/// Writes `otu_table.csv`, `tax_table.csv`, `sample_data.csv` and `import_phyloseq.R`.
+///
+/// # Arguments
+///
+/// * `table` - Counts (features x samples); feature names are the taxon IDs.
+/// * `lineages` - Lineage (domain first) for each feature; missing ranks are written as NA.
+/// * `metadata` - Optional sample metadata; samples without metadata get NA.
+/// * `provenance` - Optional provenance, written as `#` header lines at the top of each CSV.
+/// * `output_dir` - Directory to write into (created if needed).
+pub fn write_phyloseq_tables(
+    table: &CountTable,
+    lineages: &HashMap<String, Vec<String>>,
+    metadata: Option<&Metadata>,
+    provenance: Option<&Provenance>,
+    output_dir: &Path,
+) -> Result<PhyloseqFiles> {
+    std::fs::create_dir_all(output_dir)?;
+    let files = PhyloseqFiles {
+        otu_table: output_dir.join("otu_table.csv"),
+        tax_table: output_dir.join("tax_table.csv"),
+        sample_data: output_dir.join("sample_data.csv"),
+        import_script: output_dir.join("import_phyloseq.R"),
+    };
+
+    // OTU table: taxa as rows, samples as columns
+    let mut writer = csv_writer(&files.otu_table, provenance)?;
+    let mut header = vec!["taxon_id".to_string()];
+    header.extend(table.sample_names().iter().cloned());
+    writer.write_record(&header)?;
+    let counts = table.counts_matrix();
+    for (r, feature) in table.feature_names().iter().enumerate() {
+        let mut record = vec![feature.clone()];
+        record.extend(counts.row(r).iter().map(|c| c.to_string()));
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    // Taxonomy table: one row per taxon in the OTU table
+    let mut writer = csv_writer(&files.tax_table, provenance)?;
+    let mut header = vec!["taxon_id"];
+    header.extend(TAX_RANKS);
+    writer.write_record(&header)?;
+    for feature in table.feature_names() {
+        let lineage = lineages.get(feature);
+        if lineage.is_none() {
+            warn!("No lineage for taxon {}; writing NA ranks", feature);
+        }
+        let mut record = vec![feature.as_str()];
+        for i in 0..TAX_RANKS.len() {
+            record.push(
+                lineage
+                    .and_then(|l| l.get(i))
+                    .map(String::as_str)
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or("NA"),
+            );
+        }
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    // Sample data: one row per sample in the OTU table
+    let mut writer = csv_writer(&files.sample_data, provenance)?;
+    writer.write_record(["sample_id", "condition", "replicate"])?;
+    for sample in table.sample_names() {
+        match metadata.and_then(|m| m.sample_info.get(sample)) {
+            Some(info) => writer.write_record([
+                sample.as_str(),
+                info.condition.as_str(),
+                &info.replicate.to_string(),
+            ])?,
+            None => {
+                if metadata.is_some() {
+                    warn!("Sample {} has no metadata; writing NA", sample);
+                }
+                writer.write_record([sample.as_str(), "NA", "NA"])?
+            }
+        }
+    }
+    writer.flush()?;
+
+    if let Some(metadata) = metadata {
+        for sample in metadata.sample_info.keys() {
+            if !table.sample_map.contains_key(sample) {
+                warn!(
+                    "Metadata sample {} has no results and is not exported",
+                    sample
+                );
+            }
+        }
+    }
+
+    let mut script = BufWriter::new(File::create(&files.import_script)?);
+    write!(
+        script,
+        r##"library(phyloseq)
+otu <- as.matrix(read.csv("otu_table.csv", row.names = 1, check.names = FALSE, comment.char = "#", encoding = "UTF-8"))
+tax <- as.matrix(read.csv("tax_table.csv", row.names = 1, na.strings = "NA", comment.char = "#", encoding = "UTF-8"))
+sam <- read.csv("sample_data.csv", row.names = 1, na.strings = "NA", comment.char = "#", encoding = "UTF-8")
+ps <- phyloseq(otu_table(otu, taxa_are_rows = TRUE), tax_table(tax), sample_data(sam))
+"##
+    )?;
+    script.flush()?;
+
+    Ok(files)
+}
+
+/// CSV writer for `path`, preceded by the provenance header lines if given
+fn csv_writer(
+    path: &Path,
+    provenance: Option<&Provenance>,
+) -> Result<csv::Writer<BufWriter<File>>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    if let Some(provenance) = provenance {
+        provenance.write_header(&mut file)?;
+    }
+    Ok(csv_writer_builder().from_writer(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::SampleInfo;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_phyloseq_tables() {
+        let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+        data.insert(
+            "S1".to_string(),
+            HashMap::from([("GCF_A".to_string(), 12.0)]),
+        );
+        data.insert(
+            "S2".to_string(),
+            HashMap::from([("GCF_A".to_string(), 3.0), ("GCF_B".to_string(), 7.0)]),
+        );
+        let table = CountTable::build_from_data(&data).unwrap();
+
+        let lineages = HashMap::from([(
+            "GCF_A".to_string(),
+            vec![
+                "Bacteria",
+                "Pseudomonadota",
+                "Gammaproteobacteria",
+                "Enterobacterales",
+                "Enterobacteriaceae",
+                "Escherichia",
+                "Escherichia coli",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        )]);
+
+        let mut metadata = Metadata::new();
+        metadata.add_sample(
+            "S1".to_string(),
+            SampleInfo {
+                condition: "case".to_string(),
+                replicate: 1,
+                biological_sample: None,
+            },
+        );
+
+        let dir = tempdir().unwrap();
+        let files =
+            write_phyloseq_tables(&table, &lineages, Some(&metadata), None, dir.path()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&files.otu_table).unwrap(),
+            "taxon_id,S1,S2\nGCF_A,12,3\nGCF_B,0,7\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&files.tax_table).unwrap(),
+            "taxon_id,Domain,Phylum,Class,Order,Family,Genus,Species,StrainGroup,Strain\n\
+             GCF_A,Bacteria,Pseudomonadota,Gammaproteobacteria,Enterobacterales,Enterobacteriaceae,Escherichia,Escherichia coli,NA,NA\n\
+             GCF_B,NA,NA,NA,NA,NA,NA,NA,NA,NA\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&files.sample_data).unwrap(),
+            "sample_id,condition,replicate\nS1,case,1\nS2,NA,NA\n"
+        );
+        assert!(fs::read_to_string(&files.import_script)
+            .unwrap()
+            .contains("phyloseq(otu_table(otu"));
+
+        let provenance = Provenance {
+            tool_version: "0.1.0".to_string(),
+            parameters_sha256: "ff".to_string(),
+            ..Provenance::default()
+        };
+        let files =
+            write_phyloseq_tables(&table, &lineages, None, Some(&provenance), dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&files.otu_table).unwrap(),
+            "# strain_ahsp_version: 0.1.0\n# parameters_sha256: ff\n\
+             taxon_id,S1,S2\nGCF_A,12,3\nGCF_B,0,7\n"
+        );
+    }
+}
@@ -9,6 +9,7 @@ pub mod metadata;
 pub mod midas_db;
 pub mod normalization;
 pub mod pipeline;
+pub mod provenance;
 pub mod sketch;
 pub mod stats;
 pub mod strain_method;
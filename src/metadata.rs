@@ -3,12 +3,14 @@
 //! This module provides structures and functions for working with sample metadata,
 //! including experimental design and sample information.
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
 
+use crate::count_table::DuplicatePolicy;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Metadata {
     pub sample_info: HashMap<String, SampleInfo>,
@@ -31,34 +33,134 @@ impl Metadata {
         self.condition_map.insert(condition, sample_id);
     }
 
-    pub fn from_file(path: &str) -> Result<Metadata> {
+    /// Read a metadata CSV with the sample ID in the first column. A sample
+    /// given more than once is handled according to `duplicates`; merged
+    /// rows must agree on every field.
+    pub fn from_file(path: &str, duplicates: DuplicatePolicy) -> Result<Metadata> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
-        let metadata = csv::Reader::from_reader(reader)
+        let rows = csv::Reader::from_reader(reader)
             .into_deserialize()
             .collect::<Result<Vec<(String, SampleInfo)>, _>>()?;
+        let ids: Vec<String> = rows.iter().map(|(id, _)| id.clone()).collect();
+        let ids = duplicates.sample_ids(&ids, &format!("Metadata {}", path))?;
+
+        let mut sample_info: HashMap<String, SampleInfo> = HashMap::new();
+        for (sample_id, (_, info)) in ids.into_iter().zip(rows) {
+            match sample_info.get(&sample_id) {
+                Some(existing) if *existing != info => bail!(
+                    "Metadata {} has conflicting rows for sample '{}', which cannot be merged",
+                    path,
+                    sample_id
+                ),
+                Some(_) => {}
+                None => {
+                    sample_info.insert(sample_id, info);
+                }
+            }
+        }
         Ok(Metadata {
-            sample_info: metadata.into_iter().collect(),
+            sample_info,
             condition_map: HashMap::new(),
         })
     }
+
+    /// Build metadata from an inline group spec such as
+    /// `"S1,S2:control;S3,S4:treated"`. Samples are numbered as replicates
+    /// within their group in the order given.
+    pub fn from_groups(spec: &str) -> Result<Metadata> {
+        let mut metadata = Metadata::new();
+        for group in spec.split(';').map(str::trim).filter(|g| !g.is_empty()) {
+            let (samples, condition) = group
+                .rsplit_once(':')
+                .ok_or_else(|| anyhow!("Group '{}' has no ':condition' suffix", group))?;
+            let condition = condition.trim();
+            if condition.is_empty() {
+                bail!("Group '{}' has an empty condition", group);
+            }
+            let samples: Vec<&str> = samples
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .collect();
+            if samples.is_empty() {
+                bail!("Group '{}' lists no samples", group);
+            }
+            for (i, sample_id) in samples.into_iter().enumerate() {
+                if metadata.sample_info.contains_key(sample_id) {
+                    bail!("Sample '{}' appears in more than one group", sample_id);
+                }
+                metadata.add_sample(
+                    sample_id.to_string(),
+                    SampleInfo {
+                        condition: condition.to_string(),
+                        replicate: i as u32 + 1,
+                        biological_sample: None,
+                    },
+                );
+            }
+        }
+        if metadata.sample_info.is_empty() {
+            bail!("No groups given in '{}'", spec);
+        }
+        Ok(metadata)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SampleInfo {
     pub condition: String,
     pub replicate: u32,
+    /// Biological sample this is a technical replicate of, if any
+    #[serde(default)]
+    pub biological_sample: Option<String>,
     // Add other metadata fields as needed
 }
 
+/// Read a metadata CSV, refusing sample IDs given more than once
 pub fn load_metadata(path: &str) -> Result<Metadata> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let metadata = csv::Reader::from_reader(reader)
-        .into_deserialize()
-        .collect::<Result<Vec<(String, SampleInfo)>, _>>()?;
-    Ok(Metadata {
-        sample_info: metadata.into_iter().collect(),
-        condition_map: HashMap::new(),
-    })
+    Metadata::from_file(path, DuplicatePolicy::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_groups() {
+        let metadata = Metadata::from_groups("S1,S2:control; S3,S4:treated").unwrap();
+        assert_eq!(metadata.sample_info.len(), 4);
+        assert_eq!(metadata.sample_info["S2"].condition, "control");
+        assert_eq!(metadata.sample_info["S2"].replicate, 2);
+        assert_eq!(metadata.sample_info["S3"].condition, "treated");
+
+        assert!(Metadata::from_groups("S1,S2").is_err());
+        assert!(Metadata::from_groups("S1:a;S1:b").is_err());
+        assert!(Metadata::from_groups("").is_err());
+    }
+
+    #[test]
+    fn test_from_file_with_repeated_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata.csv");
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            "sample_id,condition,replicate\nS1,control,1\nS2,treated,1\nS1,control,1\n",
+        )
+        .unwrap();
+
+        assert!(Metadata::from_file(path, DuplicatePolicy::Error).is_err());
+        let merged = Metadata::from_file(path, DuplicatePolicy::Merge).unwrap();
+        assert_eq!(merged.sample_info.len(), 2);
+        let suffixed = Metadata::from_file(path, DuplicatePolicy::Suffix).unwrap();
+        assert_eq!(suffixed.sample_info["S1_2"].condition, "control");
+
+        std::fs::write(
+            path,
+            "sample_id,condition,replicate\nS1,control,1\nS1,treated,1\n",
+        )
+        .unwrap();
+        assert!(Metadata::from_file(path, DuplicatePolicy::Merge).is_err());
+    }
 }
@@ -4,7 +4,13 @@
 //! in sequencing depth, library size, or other factors, making counts
 //! comparable across samples.
 
+pub mod streaming;
+
+use std::collections::HashMap;
+use std::path::Path;
+
 use crate::count_table::CountTable;
+use crate::io::escape::csv_writer_builder;
 use anyhow::{anyhow, Result};
 use log::warn;
 use ndarray::{s, Array1, ArrayView1, Axis};
@@ -37,23 +43,38 @@ pub fn normalize(table: &mut CountTable, method: &str) -> Result<()> {
 
 /// Normalizes counts using the Median-of-Ratios method (similar to DESeq2).
 ///
-/// 1. Calculate a pseudo-reference sample (geometric mean of counts across samples for each feature).
-/// 2. For each sample, calculate the ratio of its counts to the pseudo-reference for each feature.
-/// 3. Calculate the median of these ratios for each sample (this is the size factor).
-/// 4. Divide the counts in each sample by its size factor.
+/// Divides the counts in each sample by the size factor from
+/// `compute_size_factors`.
 ///
 /// # Arguments
 ///
 /// * `table` - A mutable reference to the CountTable.
 fn normalize_median_of_ratios(table: &mut CountTable) -> Result<()> {
-    let counts = table.counts_matrix();
-    let (n_features, n_samples) = counts.dim();
-    let sample_names = table.sample_names().to_vec(); // Store sample names upfront
-
-    if n_features == 0 || n_samples == 0 {
+    if table.dimensions().0 == 0 || table.dimensions().1 == 0 {
         warn!("Count table is empty, skipping median-of-ratios normalization.");
         return Ok(());
     }
+    let size_factors = compute_size_factors(table)?;
+    apply_size_factors(table, &size_factors)
+}
+
+/// Computes median-of-ratios size factors (similar to DESeq2).
+///
+/// 1. Calculate a pseudo-reference sample (geometric mean of counts across samples for each feature).
+/// 2. For each sample, calculate the ratio of its counts to the pseudo-reference for each feature.
+/// 3. Calculate the median of these ratios for each sample (this is the size factor).
+///
+/// # Arguments
+///
+/// * `table` - The CountTable of raw counts.
+///
+/// # Returns
+///
+/// * `Result<HashMap<String, f64>>` - Size factor of each sample, keyed by sample name.
+pub fn compute_size_factors(table: &CountTable) -> Result<HashMap<String, f64>> {
+    let counts = table.counts_matrix();
+    let (n_features, n_samples) = counts.dim();
+    let sample_names = table.sample_names();
 
     // Calculate geometric mean for each feature across samples, ignoring zeros
     let mut log_counts_sum = Array1::<f64>::zeros(n_features);
@@ -82,7 +103,7 @@ fn normalize_median_of_ratios(table: &mut CountTable) -> Result<()> {
         .collect::<Array1<f64>>();
 
     // Calculate size factors for each sample
-    let mut size_factors = Array1::<f64>::zeros(n_samples);
+    let mut size_factors = HashMap::with_capacity(n_samples);
     for c in 0..n_samples {
         let mut ratios = Vec::new();
         for r in 0..n_features {
@@ -93,40 +114,134 @@ fn normalize_median_of_ratios(table: &mut CountTable) -> Result<()> {
                 ratios.push(count / ref_val);
             }
         }
+        size_factors.insert(
+            sample_names[c].clone(),
+            median_size_factor(ratios, &sample_names[c]),
+        );
+    }
 
-        if ratios.is_empty() {
-            warn!("Sample {} has no features with positive counts common with the pseudo-reference. Setting size factor to 1.0.", sample_names[c]);
-            size_factors[c] = 1.0; // Or handle as error? Or use total count?
-        } else {
-            // Calculate median of ratios
-            let mut data = Data::new(ratios);
-            size_factors[c] = data.median();
-            if size_factors[c] <= 0.0 || !size_factors[c].is_finite() {
-                warn!("Calculated non-positive or non-finite size factor ({}) for sample {}. Setting to 1.0.", size_factors[c], sample_names[c]);
-                size_factors[c] = 1.0; // Fallback if median is zero or invalid
-            }
-        }
+    Ok(size_factors)
+}
+
+/// Median of a sample's ratios to the pseudo-reference, or 1.0 (with a
+/// warning) if there are none or the median is not positive
+pub(crate) fn median_size_factor(ratios: Vec<f64>, sample: &str) -> f64 {
+    if ratios.is_empty() {
+        warn!("Sample {} has no features with positive counts common with the pseudo-reference. Setting size factor to 1.0.", sample);
+        return 1.0;
     }
+    let median = Data::new(ratios).median();
+    if median <= 0.0 || !median.is_finite() {
+        warn!(
+            "Calculated non-positive or non-finite size factor ({}) for sample {}. Setting to 1.0.",
+            median, sample
+        );
+        return 1.0;
+    }
+    median
+}
+
+/// Divides each sample's counts by its size factor.
+///
+/// The factors may come from `compute_size_factors`, a sidecar file written
+/// by `write_size_factors`, or elsewhere (e.g. spike-in controls).
+///
+/// # Arguments
+///
+/// * `table` - A mutable reference to the CountTable.
+/// * `size_factors` - Size factor of every sample in the table, keyed by sample name.
+///
+/// # Returns
+///
+/// * `Result<()>` - An error if a sample has no factor or a non-positive one;
+///   the table is left unchanged in that case.
+pub fn apply_size_factors(
+    table: &mut CountTable,
+    size_factors: &HashMap<String, f64>,
+) -> Result<()> {
+    let factors = size_factors_in_order(table.sample_names(), size_factors)?;
 
-    // Normalize counts by dividing each sample's counts by its size factor
     let mut normalized_counts = table.counts_matrix_mut();
-    for c in 0..n_samples {
-        let sf = size_factors[c];
-        if sf > 0.0 && sf.is_finite() {
-            // Ensure size factor is valid
-            let mut sample_col = normalized_counts.column_mut(c);
-            sample_col /= sf;
-        } else {
-            warn!(
-                "Skipping normalization for sample {} due to invalid size factor {}.",
-                sample_names[c], sf
-            );
-        }
+    for (c, sf) in factors.into_iter().enumerate() {
+        let mut sample_col = normalized_counts.column_mut(c);
+        sample_col /= sf;
     }
+    Ok(())
+}
+
+/// Size factors of `samples` in order, each checked to be positive
+pub(crate) fn size_factors_in_order(
+    samples: &[String],
+    size_factors: &HashMap<String, f64>,
+) -> Result<Vec<f64>> {
+    samples
+        .iter()
+        .map(|sample| match size_factors.get(sample) {
+            Some(&sf) if sf > 0.0 && sf.is_finite() => Ok(sf),
+            Some(&sf) => Err(anyhow!("Invalid size factor {} for sample {}", sf, sample)),
+            None => Err(anyhow!("No size factor for sample {}", sample)),
+        })
+        .collect::<Result<Vec<f64>>>()
+}
 
+/// Writes size factors to a sidecar CSV with `sample` and `size_factor`
+/// columns, sorted by sample name.
+///
+/// # Arguments
+///
+/// * `size_factors` - Size factors keyed by sample name.
+/// * `output_path` - The path to the output CSV file.
+pub fn write_size_factors(size_factors: &HashMap<String, f64>, output_path: &Path) -> Result<()> {
+    let mut writer = csv_writer_builder().from_path(output_path)?;
+    writer.write_record(["sample", "size_factor"])?;
+    let mut samples: Vec<&String> = size_factors.keys().collect();
+    samples.sort();
+    for sample in samples {
+        writer.write_record([sample.as_str(), &size_factors[sample].to_string()])?;
+    }
+    writer.flush()?;
     Ok(())
 }
 
+/// Reads size factors from a sidecar CSV as written by `write_size_factors`.
+///
+/// # Arguments
+///
+/// * `path` - Path to a CSV with `sample` and `size_factor` columns.
+pub fn read_size_factors(path: &Path) -> Result<HashMap<String, f64>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("{}: no '{}' column", path.display(), name))
+    };
+    let (sample_col, factor_col) = (column("sample")?, column("size_factor")?);
+
+    let mut size_factors = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let sample = record.get(sample_col).unwrap_or("").trim();
+        let factor: f64 = record
+            .get(factor_col)
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "{}: invalid size factor for sample '{}'",
+                    path.display(),
+                    sample
+                )
+            })?;
+        if size_factors.insert(sample.to_string(), factor).is_some() {
+            return Err(anyhow!("{}: duplicate sample '{}'", path.display(), sample));
+        }
+    }
+    Ok(size_factors)
+}
+
 /// Normalizes counts to Counts Per Million (CPM).
 /// CPM = (count / total_counts_in_sample) * 1,000,000
 ///
@@ -295,6 +410,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_size_factors_sidecar_round_trip() {
+        let table = create_test_table();
+        let size_factors = compute_size_factors(&table).unwrap();
+        assert_relative_eq!(size_factors["S1"], 0.55, epsilon = 1e-2);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_size_factors(&size_factors, file.path()).unwrap();
+        let read = read_size_factors(file.path()).unwrap();
+        assert_eq!(read.len(), 3);
+        for (sample, sf) in &size_factors {
+            assert_relative_eq!(read[sample], *sf, epsilon = 1e-12);
+        }
+
+        // Overriding factors, e.g. from spike-ins
+        let mut overridden = create_test_table();
+        let spike_ins = HashMap::from([
+            ("S1".to_string(), 1.0),
+            ("S2".to_string(), 2.0),
+            ("S3".to_string(), 4.0),
+        ]);
+        apply_size_factors(&mut overridden, &spike_ins).unwrap();
+        assert_eq!(overridden.counts_matrix()[[0, 2]], 7.5);
+
+        let missing = HashMap::from([("S1".to_string(), 1.0)]);
+        assert!(apply_size_factors(&mut overridden, &missing).is_err());
+    }
+
     #[test]
     fn test_normalize_none() {
         let mut table = create_test_table();
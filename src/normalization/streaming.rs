@@ -0,0 +1,400 @@
+//! Two-pass normalization of count tables too large to load.
+//!
+//! The input is a count table CSV as written by `write_count_table`: a
+//! `Feature` column followed by one column per sample, with optional `#`
+//! provenance lines before the header. Rows are streamed twice. The first
+//! pass computes one size factor per sample; the second divides each row by
+//! them and writes it out. Memory use is a few values per sample, plus one
+//! sample's ratios at a time for median-of-ratios.
+//!
+//! Median-of-ratios needs the median over all features of each sample's
+//! ratios to the pseudo-reference, which cannot be computed from a single
+//! stream of rows. The ratios are spilled to one temporary file per sample and
+//! read back one sample at a time. At most `MAX_OPEN_SPILLS` spill files are
+//! open at once, so wider tables are read once per batch of that many samples
+//! rather than running into the limit on open files.
+//!
+//! The output is either a table of the same shape as the input, or a tidy
+//! (long) table with one row per feature and sample that keeps the raw count
+//! next to the normalized value and the sample's size factor, for checking
+//! what normalization did to each count.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+use crate::io::escape::csv_writer_builder;
+use crate::io::format::{csv_format_version, Artifact};
+use crate::normalization::{median_size_factor, size_factors_in_order};
+
+/// Most spill files median-of-ratios keeps open at once, well below the
+/// usual per-process limit on open files
+pub const MAX_OPEN_SPILLS: usize = 256;
+
+/// Shape of a normalized table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TableLayout {
+    /// Same shape as the input: `Feature`, then one column per sample
+    #[default]
+    Wide,
+    /// One row per feature and sample: `feature,sample,raw,normalized,size_factor`
+    Tidy,
+}
+
+/// Size factors computed by the first pass
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeFactors {
+    pub sample_names: Vec<String>,
+    /// Divisor applied to each sample's counts; 0 for CPM of an empty
+    /// sample, whose counts are written as zeros
+    pub factors: Vec<f64>,
+    /// Number of feature rows read
+    pub n_features: usize,
+}
+
+impl SizeFactors {
+    /// Size factors keyed by sample name, as used by `write_size_factors`
+    pub fn to_map(&self) -> HashMap<String, f64> {
+        self.sample_names
+            .iter()
+            .cloned()
+            .zip(self.factors.iter().copied())
+            .collect()
+    }
+}
+
+/// Normalize the count table CSV at `input` into `output` without loading it.
+///
+/// Supports `cpm` and `median-of-ratios` (alias `deseq2`), with the same
+/// results as `normalize` on the loaded table. Returns the size factors.
+pub fn normalize_csv(
+    input: &Path,
+    output: &Path,
+    method: &str,
+    layout: TableLayout,
+) -> Result<SizeFactors> {
+    let size_factors = match method.to_lowercase().as_str() {
+        "median-of-ratios" | "deseq2" => median_of_ratios_factors(input, MAX_OPEN_SPILLS)?,
+        "cpm" => cpm_factors(input)?,
+        _ => {
+            return Err(anyhow!(
+                "Unsupported streaming normalization method: {}",
+                method
+            ))
+        }
+    };
+    write_normalized(input, output, &size_factors, layout)?;
+    Ok(size_factors)
+}
+
+/// Divide the count table CSV at `input` by precomputed size factors (e.g.
+/// from `read_size_factors`) in a single pass. Every sample needs a positive factor.
+pub fn normalize_csv_with_factors(
+    input: &Path,
+    output: &Path,
+    size_factors: &HashMap<String, f64>,
+    layout: TableLayout,
+) -> Result<SizeFactors> {
+    let (_, sample_names) = open_table(input)?;
+    let factors = size_factors_in_order(&sample_names, size_factors)?;
+    let mut size_factors = SizeFactors {
+        sample_names,
+        factors,
+        n_features: 0,
+    };
+    size_factors.n_features = write_normalized(input, output, &size_factors, layout)?;
+    Ok(size_factors)
+}
+
+/// Open a count table CSV of a supported format version, returning the
+/// reader and the sample names
+fn open_table(path: &Path) -> Result<(csv::Reader<BufReader<File>>, Vec<String>)> {
+    let version = csv_format_version(path)
+        .with_context(|| format!("Cannot open count table {}", path.display()))?;
+    Artifact::CountTable.check(version, path.display())?;
+    let file =
+        File::open(path).with_context(|| format!("Cannot open count table {}", path.display()))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .from_reader(BufReader::new(file));
+    let sample_names: Vec<String> = reader.headers()?.iter().skip(1).map(String::from).collect();
+    if sample_names.is_empty() {
+        return Err(anyhow!("{} has no sample columns", path.display()));
+    }
+    Ok((reader, sample_names))
+}
+
+/// Stream the counts of each feature row to `visit`
+fn for_each_row(
+    reader: &mut csv::Reader<BufReader<File>>,
+    n_samples: usize,
+    mut visit: impl FnMut(&str, &[f64]) -> Result<()>,
+) -> Result<usize> {
+    let mut record = csv::StringRecord::new();
+    let mut counts = vec![0.0; n_samples];
+    let mut rows = 0;
+    while reader.read_record(&mut record)? {
+        let line = record.position().map_or(0, |p| p.line());
+        if record.len() != n_samples + 1 {
+            return Err(anyhow!(
+                "Line {}: expected {} columns, found {}",
+                line,
+                n_samples + 1,
+                record.len()
+            ));
+        }
+        for (count, field) in counts.iter_mut().zip(record.iter().skip(1)) {
+            *count = field
+                .trim()
+                .parse()
+                .ok()
+                .filter(|c: &f64| c.is_finite() && *c >= 0.0)
+                .ok_or_else(|| anyhow!("Line {}: invalid count '{}'", line, field))?;
+        }
+        visit(&record[0], &counts)?;
+        rows += 1;
+    }
+    Ok(rows)
+}
+
+/// CPM size factors: library size in millions
+fn cpm_factors(input: &Path) -> Result<SizeFactors> {
+    let (mut reader, sample_names) = open_table(input)?;
+    let mut totals = vec![0.0; sample_names.len()];
+    let n_features = for_each_row(&mut reader, sample_names.len(), |_, counts| {
+        for (total, count) in totals.iter_mut().zip(counts) {
+            *total += count;
+        }
+        Ok(())
+    })?;
+
+    if totals.iter().any(|&total| total <= 0.0) {
+        warn!("Some samples have zero total counts; their CPM values are set to zero.");
+    }
+    Ok(SizeFactors {
+        sample_names,
+        factors: totals.iter().map(|total| total / 1_000_000.0).collect(),
+        n_features,
+    })
+}
+
+/// Median-of-ratios size factors, spilling each sample's ratios to disk.
+/// Samples are spilled in batches of at most `max_open`, one pass over the
+/// table per batch.
+fn median_of_ratios_factors(input: &Path, max_open: usize) -> Result<SizeFactors> {
+    let (_, sample_names) = open_table(input)?;
+    let spill_dir = TempDir::new()?;
+    let mut factors = Vec::with_capacity(sample_names.len());
+    let mut n_features = 0;
+    let batch = max_open.max(1);
+
+    for start in (0..sample_names.len()).step_by(batch) {
+        let end = (start + batch).min(sample_names.len());
+        let (mut reader, names) = open_table(input)?;
+        if names != sample_names {
+            return Err(anyhow!(
+                "{} changed between normalization passes",
+                input.display()
+            ));
+        }
+        let spill_paths: Vec<PathBuf> = (start..end)
+            .map(|i| spill_dir.path().join(format!("sample_{}.f64", i)))
+            .collect();
+        let mut spills = spill_paths
+            .iter()
+            .map(|path| Ok(BufWriter::new(File::create(path)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        n_features = for_each_row(&mut reader, sample_names.len(), |_, counts| {
+            // Geometric mean of the non-zero counts, as in `normalize`
+            let (log_sum, non_zero) = counts
+                .iter()
+                .filter(|&&count| count > 0.0)
+                .fold((0.0, 0usize), |(sum, n), count| (sum + count.ln(), n + 1));
+            if non_zero == 0 {
+                return Ok(());
+            }
+            let reference = (log_sum / non_zero as f64).exp();
+            for (spill, &count) in spills.iter_mut().zip(&counts[start..end]) {
+                if count > 0.0 {
+                    spill.write_all(&(count / reference).to_le_bytes())?;
+                }
+            }
+            Ok(())
+        })?;
+        for spill in &mut spills {
+            spill.flush()?;
+        }
+        drop(spills);
+
+        for (path, sample) in spill_paths.iter().zip(&sample_names[start..end]) {
+            let mut bytes = Vec::new();
+            File::open(path)?.read_to_end(&mut bytes)?;
+            let ratios: Vec<f64> = bytes
+                .chunks_exact(8)
+                .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            factors.push(median_size_factor(ratios, sample));
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    Ok(SizeFactors {
+        sample_names,
+        factors,
+        n_features,
+    })
+}
+
+/// Second pass: divide every row by the size factors and write it out
+fn write_normalized(
+    input: &Path,
+    output: &Path,
+    size_factors: &SizeFactors,
+    layout: TableLayout,
+) -> Result<usize> {
+    let (mut reader, sample_names) = open_table(input)?;
+    if sample_names != size_factors.sample_names {
+        return Err(anyhow!(
+            "{} changed between normalization passes",
+            input.display()
+        ));
+    }
+    let file =
+        File::create(output).with_context(|| format!("Cannot create {}", output.display()))?;
+    let mut writer = csv_writer_builder().from_writer(BufWriter::new(file));
+    match layout {
+        TableLayout::Wide => {
+            let mut header = vec!["Feature".to_string()];
+            header.extend(sample_names.iter().cloned());
+            writer.write_record(&header)?;
+        }
+        TableLayout::Tidy => {
+            writer.write_record(["feature", "sample", "raw", "normalized", "size_factor"])?
+        }
+    }
+
+    let mut record = Vec::with_capacity(sample_names.len() + 1);
+    let rows = for_each_row(&mut reader, sample_names.len(), |feature, counts| {
+        record.clear();
+        if layout == TableLayout::Wide {
+            record.push(feature.to_string());
+        }
+        for ((&count, &factor), sample) in
+            counts.iter().zip(&size_factors.factors).zip(&sample_names)
+        {
+            let normalized = if factor > 0.0 { count / factor } else { 0.0 };
+            match layout {
+                TableLayout::Wide => record.push(normalized.to_string()),
+                TableLayout::Tidy => writer.write_record([
+                    feature,
+                    sample.as_str(),
+                    count.to_string().as_str(),
+                    normalized.to_string().as_str(),
+                    factor.to_string().as_str(),
+                ])?,
+            }
+        }
+        if layout == TableLayout::Wide {
+            writer.write_record(&record)?;
+        }
+        Ok(())
+    })?;
+    writer.flush()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::count_table::CountTable;
+    use crate::io::write_count_table;
+    use crate::normalization::normalize;
+    use approx::assert_relative_eq;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_streaming_matches_in_memory() {
+        let data: HashMap<String, HashMap<String, f64>> = [
+            ("S1", [("F1", 10.0), ("F2", 5.0), ("F4", 2.0)]),
+            ("S2", [("F1", 20.0), ("F3", 40.0), ("F4", 4.0)]),
+            ("S3", [("F1", 30.0), ("F2", 15.0), ("F3", 60.0)]),
+        ]
+        .into_iter()
+        .map(|(sample, counts)| {
+            let counts = counts.iter().map(|&(f, c)| (f.to_string(), c)).collect();
+            (sample.to_string(), counts)
+        })
+        .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("counts.csv");
+        write_count_table(
+            &CountTable::build_from_data(&data).unwrap(),
+            None,
+            &input.to_string_lossy(),
+        )
+        .unwrap();
+
+        for method in ["cpm", "median-of-ratios"] {
+            let output = dir.path().join(format!("{}.csv", method));
+            let factors = normalize_csv(&input, &output, method, TableLayout::Wide).unwrap();
+            assert_eq!(factors.n_features, 4);
+
+            let mut expected = CountTable::build_from_data(&data).unwrap();
+            normalize(&mut expected, method).unwrap();
+            let mut reader = csv::Reader::from_path(&output).unwrap();
+            for (row, record) in reader.records().enumerate() {
+                let record = record.unwrap();
+                assert_eq!(&record[0], expected.feature_names()[row]);
+                for (col, value) in record.iter().skip(1).enumerate() {
+                    let value: f64 = value.parse().unwrap();
+                    assert_relative_eq!(
+                        value,
+                        expected.counts_matrix()[[row, col]],
+                        epsilon = 1e-9
+                    );
+                }
+            }
+        }
+        assert!(
+            normalize_csv(&input, &dir.path().join("x.csv"), "tpm", TableLayout::Wide).is_err()
+        );
+
+        // Spilling in batches smaller than the table gives the same factors
+        let one_pass = median_of_ratios_factors(&input, MAX_OPEN_SPILLS).unwrap();
+        for max_open in [1, 2] {
+            let batched = median_of_ratios_factors(&input, max_open).unwrap();
+            assert_eq!(batched.sample_names, one_pass.sample_names);
+            assert_eq!(batched.n_features, one_pass.n_features);
+            for (a, b) in batched.factors.iter().zip(&one_pass.factors) {
+                assert_relative_eq!(*a, *b, epsilon = 1e-12);
+            }
+        }
+
+        // Tidy: one row per feature and sample, raw count beside normalized value
+        let tidy = dir.path().join("tidy.csv");
+        let factors = normalize_csv(&input, &tidy, "cpm", TableLayout::Tidy).unwrap();
+        let mut reader = csv::Reader::from_path(&tidy).unwrap();
+        assert_eq!(
+            reader.headers().unwrap().iter().collect::<Vec<_>>(),
+            vec!["feature", "sample", "raw", "normalized", "size_factor"]
+        );
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 4 * 3);
+        for row in &rows {
+            let raw: f64 = row[2].parse().unwrap();
+            let normalized: f64 = row[3].parse().unwrap();
+            let factor: f64 = row[4].parse().unwrap();
+            assert_eq!(raw, data[&row[1]].get(&row[0]).copied().unwrap_or(0.0));
+            assert_relative_eq!(normalized * factor, raw, epsilon = 1e-9);
+            assert_eq!(factor, factors.to_map()[&row[1]]);
+        }
+    }
+}
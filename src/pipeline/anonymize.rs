@@ -0,0 +1,276 @@
+//! Pseudonymization of sample identifiers.
+//!
+//! With `--anonymize`, sample IDs are replaced before anything is written, so
+//! result JSON, tables, plots and reports only ever see the pseudonym. IDs are
+//! mapped either through a user-supplied mapping file or through a salted
+//! SHA-256 hash. The original-to-pseudonym mapping (and the salt) is kept in
+//! a file readable only by its owner (`--anonymize-key`), which later runs
+//! reuse so the same sample always gets the same pseudonym. That file undoes
+//! the pseudonymization, so it is refused inside the output directory, which
+//! is what gets shared.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Name of the mapping file earlier versions wrote next to the anonymized outputs
+pub const MAPPING_FILE_NAME: &str = "sample_id_mapping.tsv";
+
+/// Prefix of the salt line in the mapping file
+const SALT_PREFIX: &str = "# salt\t";
+
+/// Maps sample IDs to stable pseudonyms
+#[derive(Debug)]
+pub struct SampleAnonymizer {
+    salt: String,
+    /// Original ID -> pseudonym
+    mapping: BTreeMap<String, String>,
+    /// Only IDs listed in a user-provided mapping may be pseudonymized
+    mapping_only: bool,
+    mapping_path: PathBuf,
+}
+
+impl SampleAnonymizer {
+    /// Create an anonymizer for outputs in `output_dir` whose mapping file is
+    /// `mapping_path`, which must lie outside `output_dir`.
+    ///
+    /// An existing mapping file is loaded first. `user_mapping` is a
+    /// two-column TSV (`sample_id<TAB>pseudonym`); when given, IDs missing from it
+    /// are rejected instead of hashed. The salt is taken from `salt`, then from the
+    /// existing mapping file, and is otherwise generated randomly.
+    pub fn new(
+        output_dir: impl AsRef<Path>,
+        mapping_path: impl Into<PathBuf>,
+        salt: Option<String>,
+        user_mapping: Option<&Path>,
+    ) -> Result<Self> {
+        let output_dir = output_dir.as_ref();
+        let mapping_path = mapping_path.into();
+        if resolved(&mapping_path)?.starts_with(resolved(output_dir)?) {
+            bail!(
+                "Anonymization mapping {} is inside the output directory {}; keep it elsewhere, \
+                 as it reveals the original sample IDs",
+                mapping_path.display(),
+                output_dir.display()
+            );
+        }
+        let stray = output_dir.join(MAPPING_FILE_NAME);
+        if stray.exists() {
+            bail!(
+                "{} reveals the original sample IDs of the outputs next to it; move it outside \
+                 the output directory and pass it with --anonymize-key",
+                stray.display()
+            );
+        }
+
+        let mut mapping = BTreeMap::new();
+        let mut stored_salt = None;
+        if mapping_path.exists() {
+            stored_salt = read_mapping(&mapping_path, &mut mapping)?;
+        }
+        if let Some(path) = user_mapping {
+            read_mapping(path, &mut mapping)?;
+        }
+
+        if let (Some(given), Some(stored)) = (&salt, &stored_salt) {
+            if given != stored {
+                bail!(
+                    "Salt differs from the one recorded in {}; pseudonyms would not match earlier runs",
+                    mapping_path.display()
+                );
+            }
+        }
+        let salt = salt
+            .or(stored_salt)
+            .unwrap_or_else(|| hex::encode(rand::random::<[u8; 16]>()));
+
+        Ok(SampleAnonymizer {
+            salt,
+            mapping,
+            mapping_only: user_mapping.is_some(),
+            mapping_path,
+        })
+    }
+
+    /// Pseudonym for `sample_id`. IDs that are already pseudonyms are returned unchanged.
+    pub fn pseudonym(&mut self, sample_id: &str) -> Result<String> {
+        if let Some(pseudonym) = self.mapping.get(sample_id) {
+            return Ok(pseudonym.clone());
+        }
+        if self.mapping.values().any(|p| p == sample_id) {
+            return Ok(sample_id.to_string());
+        }
+        if self.mapping_only {
+            bail!(
+                "Sample '{}' is not listed in the anonymization mapping",
+                sample_id
+            );
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(sample_id.as_bytes());
+        let pseudonym = format!("sample_{}", &hex::encode(hasher.finalize())[..12]);
+        self.mapping
+            .insert(sample_id.to_string(), pseudonym.clone());
+        Ok(pseudonym)
+    }
+
+    /// Path of the mapping file
+    pub fn mapping_path(&self) -> &Path {
+        &self.mapping_path
+    }
+
+    /// Write the salt and mapping to the mapping file with owner-only permissions
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.mapping_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(&self.mapping_path)
+            .with_context(|| format!("Failed to write {}", self.mapping_path.display()))?;
+        // `mode` only applies on creation; tighten pre-existing files too
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{}{}", SALT_PREFIX, self.salt)?;
+        writeln!(writer, "sample_id\tpseudonym")?;
+        for (original, pseudonym) in &self.mapping {
+            writeln!(writer, "{}\t{}", original, pseudonym)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// `path` made absolute with symlinks resolved, as far as it exists, so that
+/// paths yet to be created can be compared
+fn resolved(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = std::env::current_dir()?.join(path);
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                missing.push(name.to_os_string());
+                existing = parent.to_path_buf();
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = existing.canonicalize()?;
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
+}
+
+/// Read `sample_id<TAB>pseudonym` lines into `mapping`, returning the salt line if present
+fn read_mapping(path: &Path, mapping: &mut BTreeMap<String, String>) -> Result<Option<String>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open mapping file {}", path.display()))?;
+    let mut salt = None;
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if let Some(stored) = line.strip_prefix(SALT_PREFIX) {
+            salt = Some(stored.trim().to_string());
+            continue;
+        }
+        if line.trim().is_empty() || line.starts_with('#') || line == "sample_id\tpseudonym" {
+            continue;
+        }
+        match line.split_once('\t') {
+            Some((original, pseudonym)) if !pseudonym.trim().is_empty() => {
+                mapping.insert(original.trim().to_string(), pseudonym.trim().to_string());
+            }
+            _ => bail!(
+                "{}:{}: expected 'sample_id<TAB>pseudonym'",
+                path.display(),
+                line_no + 1
+            ),
+        }
+    }
+    Ok(salt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_salted_pseudonyms_are_stable_across_runs() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out");
+        let key = dir.path().join("key.tsv");
+        let mut first = SampleAnonymizer::new(&out, &key, None, None).unwrap();
+        let a = first.pseudonym("patient_42").unwrap();
+        assert!(a.starts_with("sample_"));
+        assert!(!a.contains("patient"));
+        assert_eq!(first.pseudonym("patient_42").unwrap(), a);
+        assert_eq!(first.pseudonym(&a).unwrap(), a);
+        first.save().unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(first.mapping_path())
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A later run reuses the stored salt
+        let mut second = SampleAnonymizer::new(&out, &key, None, None).unwrap();
+        second.mapping.clear();
+        assert_eq!(second.pseudonym("patient_42").unwrap(), a);
+        assert!(SampleAnonymizer::new(&out, &key, Some("other".to_string()), None).is_err());
+    }
+
+    #[test]
+    fn test_mapping_is_refused_inside_the_output_dir() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out");
+        assert!(SampleAnonymizer::new(&out, out.join("key.tsv"), None, None).is_err());
+        assert!(SampleAnonymizer::new(&out, out.join("private/key.tsv"), None, None).is_err());
+        assert!(SampleAnonymizer::new(dir.path(), dir.path().join("key.tsv"), None, None).is_err());
+        assert!(SampleAnonymizer::new(&out, dir.path().join("out.key"), None, None).is_ok());
+
+        // Nor is a mapping left next to the outputs by an earlier version
+        fs::create_dir(&out).unwrap();
+        fs::write(out.join(MAPPING_FILE_NAME), "# salt\tabc\n").unwrap();
+        let stray = SampleAnonymizer::new(&out, dir.path().join("key.tsv"), None, None);
+        assert!(stray.unwrap_err().to_string().contains("--anonymize-key"));
+    }
+
+    #[test]
+    fn test_user_mapping_rejects_unlisted_samples() {
+        let dir = tempdir().unwrap();
+        let map_path = dir.path().join("map.tsv");
+        fs::write(&map_path, "sample_id\tpseudonym\nMRN001\tP01\n").unwrap();
+
+        let mut anonymizer = SampleAnonymizer::new(
+            dir.path().join("out"),
+            dir.path().join("key.tsv"),
+            None,
+            Some(&map_path),
+        )
+        .unwrap();
+        assert_eq!(anonymizer.pseudonym("MRN001").unwrap(), "P01");
+        assert!(anonymizer.pseudonym("MRN002").is_err());
+    }
+}
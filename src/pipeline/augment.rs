@@ -0,0 +1,244 @@
+//! Suggestions for references missing from the database.
+//!
+//! A sample whose hashes largely go unassigned, yet which partially matches a
+//! few references (say 85% ANI to one genus), most likely contains a relative
+//! of those references that the database lacks. The partial matches name
+//! where to look: their genus or family becomes an NCBI query that
+//! `db add-references` (or `suggest-references --add`) can fetch.
+//!
+//! Sketches keep no reads, so whether the unassigned part of the sample is one
+//! tight group is judged from the partial matches: if they point at more than
+//! `max_suggestions` distinct taxa the signal is too diffuse and nothing is
+//! suggested.
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bio::taxdump::Taxonomy;
+use crate::bio::TaxonomicLevel;
+use crate::sketch::signature::KmerSignature;
+use crate::sketch::{Comparable, MultiResolutionSignature};
+
+/// A database query likely to fill a gap in the references
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReferenceSuggestion {
+    /// NCBI query for `db add-references`
+    pub query: String,
+    /// Genus or family the query targets
+    pub taxon_name: String,
+    /// Closest partially matching reference
+    pub nearest_reference: String,
+    /// Containment ANI of the sample to `nearest_reference`
+    pub nearest_ani: f64,
+    /// Fraction of sample hashes found in no reference
+    pub unassigned_fraction: f64,
+}
+
+/// Thresholds for suggesting missing references
+#[derive(Debug, Clone)]
+pub struct ReferenceAdvisor {
+    /// Only suggest when at least this fraction of sample hashes is unassigned
+    pub min_unassigned_fraction: f64,
+    /// Partial matches start at this ANI (below it, relatedness is too distant to guide a query)
+    pub min_ani: f64,
+    /// Matches at or above this ANI are the same species and need no new reference
+    pub max_ani: f64,
+    /// Maximum number of suggestions; more distinct candidate taxa than this means no suggestion
+    pub max_suggestions: usize,
+    /// Taxonomy through which the genus or family of a reference is found by
+    /// rank. References it does not know (and all references without one) are
+    /// assumed to have full Domain, Phylum, ..., Species lineages.
+    pub taxonomy: Option<Taxonomy>,
+}
+
+impl Default for ReferenceAdvisor {
+    fn default() -> Self {
+        ReferenceAdvisor {
+            min_unassigned_fraction: 0.3,
+            min_ani: 0.8,
+            max_ani: 0.95,
+            max_suggestions: 3,
+            taxonomy: None,
+        }
+    }
+}
+
+impl ReferenceAdvisor {
+    /// Suggest queries for references missing from `references`, best match first
+    pub fn suggest(
+        &self,
+        sample: &MultiResolutionSignature,
+        references: &[MultiResolutionSignature],
+    ) -> Vec<ReferenceSuggestion> {
+        let Some(sample_level) = sample.levels.first() else {
+            return Vec::new();
+        };
+        let unassigned = unassigned_fraction(sample_level, references);
+        if unassigned < self.min_unassigned_fraction {
+            return Vec::new();
+        }
+
+        // Best partial match per candidate taxon
+        let mut candidates: BTreeMap<String, (String, f64)> = BTreeMap::new();
+        for reference in references {
+            let Some(ani) = reference
+                .levels
+                .first()
+                .and_then(|level| level.containment_ani(sample_level))
+            else {
+                continue;
+            };
+            if ani < self.min_ani || ani >= self.max_ani {
+                continue;
+            }
+            // Close matches point to a missing species of the same genus,
+            // distant ones only to the family
+            let level = if ani >= 0.9 {
+                TaxonomicLevel::Genus
+            } else {
+                TaxonomicLevel::Family
+            };
+            let Some(name) = self.taxon_name(reference, level) else {
+                continue;
+            };
+            let best = candidates
+                .entry(name)
+                .or_insert_with(|| (reference.taxon_id.clone(), ani));
+            if ani > best.1 {
+                *best = (reference.taxon_id.clone(), ani);
+            }
+        }
+        if candidates.is_empty() || candidates.len() > self.max_suggestions {
+            return Vec::new();
+        }
+
+        let mut suggestions: Vec<ReferenceSuggestion> = candidates
+            .into_iter()
+            .map(
+                |(taxon_name, (nearest_reference, nearest_ani))| ReferenceSuggestion {
+                    query: format!("\"{}\"[Organism]", taxon_name),
+                    taxon_name,
+                    nearest_reference,
+                    nearest_ani,
+                    unassigned_fraction: unassigned,
+                },
+            )
+            .collect();
+        suggestions.sort_by(|a, b| b.nearest_ani.total_cmp(&a.nearest_ani));
+        suggestions
+    }
+
+    /// Name of the taxon at `level` that `reference` belongs to
+    fn taxon_name(
+        &self,
+        reference: &MultiResolutionSignature,
+        level: TaxonomicLevel,
+    ) -> Option<String> {
+        let known = self.taxonomy.as_ref().and_then(|taxonomy| {
+            let taxid = if taxonomy.node(&reference.taxon_id).is_some() {
+                reference.taxon_id.clone()
+            } else {
+                taxonomy.resolve(reference.lineage.last()?).ok()?
+            };
+            Some((taxonomy, taxid))
+        });
+        match known {
+            Some((taxonomy, taxid)) => taxonomy
+                .ancestor_at_rank(&taxid, level.as_str())
+                .map(|node| node.name.clone()),
+            None => {
+                let index = TaxonomicLevel::all_levels()
+                    .iter()
+                    .position(|l| *l == level)?;
+                reference.lineage.get(index).cloned()
+            }
+        }
+    }
+}
+
+/// Fraction of the sample sketch's hashes that appear in no reference at the same level
+pub fn unassigned_fraction(sample: &KmerSignature, references: &[MultiResolutionSignature]) -> f64 {
+    if sample.sketch.hashes.is_empty() {
+        return 0.0;
+    }
+    let assigned: HashSet<u64> = references
+        .iter()
+        .filter_map(|r| r.levels.first())
+        .filter(|level| level.kmer_size == sample.kmer_size)
+        .flat_map(|level| level.sketch.hashes.iter().copied())
+        .collect();
+    let unassigned = sample
+        .sketch
+        .hashes
+        .iter()
+        .filter(|hash| !assigned.contains(hash))
+        .count();
+    unassigned as f64 / sample.sketch.hashes.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_partial_match_suggests_genus_query() {
+        let lineage = [
+            "Bacteria",
+            "P",
+            "C",
+            "O",
+            "Enterobacteriaceae",
+            "Klebsiella",
+            "K. pneumoniae",
+        ];
+        // The reference shares 20% of its hashes with the sample: ANI 0.2^(1/21) ~ 0.926
+        let reference = signature("GCF_1", &lineage, [level(21, 1000, 0..100)]);
+        let sample = signature("sample", &[], [level(21, 1000, 80..180)]);
+
+        let suggestions = ReferenceAdvisor::default().suggest(&sample, &[reference.clone()]);
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].taxon_name, "Klebsiella");
+        assert_eq!(suggestions[0].query, "\"Klebsiella\"[Organism]");
+        assert_eq!(suggestions[0].nearest_reference, "GCF_1");
+        assert!((suggestions[0].unassigned_fraction - 0.8).abs() < 1e-12);
+
+        // A well-covered sample needs no new references
+        let covered = signature("sample", &[], [level(21, 1000, 0..100)]);
+        assert!(ReferenceAdvisor::default()
+            .suggest(&covered, &[reference])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_genus_found_by_rank_in_taxonomy() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = "1\t|\t1\t|\tno rank\t|\n\
+                     2\t|\t1\t|\tsuperkingdom\t|\n\
+                     570\t|\t2\t|\tgenus\t|\n\
+                     573\t|\t570\t|\tspecies\t|\n";
+        let names = "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+                     2\t|\tBacteria\t|\t\t|\tscientific name\t|\n\
+                     570\t|\tKlebsiella\t|\t\t|\tscientific name\t|\n\
+                     573\t|\tKlebsiella pneumoniae\t|\t\t|\tscientific name\t|\n";
+        std::fs::write(dir.path().join("nodes.dmp"), nodes).unwrap();
+        std::fs::write(dir.path().join("names.dmp"), names).unwrap();
+
+        // A lineage with ranks missing: the genus is not at its usual position
+        let lineage = ["Bacteria", "Klebsiella", "Klebsiella pneumoniae"];
+        let reference = signature("GCF_1", &lineage, [level(21, 1000, 0..100)]);
+        let sample = signature("sample", &[], [level(21, 1000, 80..180)]);
+        assert!(ReferenceAdvisor::default()
+            .suggest(&sample, &[reference.clone()])
+            .is_empty());
+
+        let advisor = ReferenceAdvisor {
+            taxonomy: Some(Taxonomy::from_taxdump(dir.path()).unwrap()),
+            ..ReferenceAdvisor::default()
+        };
+        let suggestions = advisor.suggest(&sample, &[reference]);
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].taxon_name, "Klebsiella");
+    }
+}
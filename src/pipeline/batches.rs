@@ -0,0 +1,288 @@
+//! Record batches read on a dedicated IO thread.
+//!
+//! Parsing and decompressing a FASTQ file is serial work, and when it runs on
+//! the same threads as QC and sketching, every worker waits on it between
+//! chunks. A `BatchReader` parses on its own thread and hands the compute
+//! threads whole batches of reads through a bounded channel, so the next
+//! batch is read while the current one is sketched. Each batch stores its
+//! names, bases and qualities in flat buffers rather than a `Vec<u8>` per read,
+//! and batches the consumer is done with go back to the reader to be filled
+//! again, so a file is read with a handful of allocations rather than two per
+//! read.
+//!
+//! How many reads make a good batch depends on how long they are: a fixed
+//! count that keeps the workers busy on 150 bp reads runs out of memory on
+//! nanopore reads a hundred times longer. Unless a size is given, batches are
+//! sized from the mean length of the first reads of the file, so that all the
+//! batches in flight fit in a memory budget.
+
+use std::mem::size_of;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+use needletail::parse_fastx_file;
+
+use crate::pipeline::qc::ProcessingError;
+
+/// Batches read ahead of the compute threads by default
+pub const DEFAULT_READ_AHEAD_BATCHES: usize = 4;
+
+/// Memory the batches of a file may take together, by default
+pub const DEFAULT_BATCH_MEMORY_BYTES: usize = 256 * 1024 * 1024;
+
+/// Reads at the head of a file whose mean length sizes its batches
+pub const BATCH_SIZE_SAMPLE_READS: usize = 1000;
+
+/// Fewest reads in an automatically sized batch
+pub const MIN_BATCH_READS: usize = 64;
+
+/// Most reads in an automatically sized batch
+pub const MAX_BATCH_READS: usize = 1_000_000;
+
+/// Where one read lies in the buffers of its batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ReadSpan {
+    name: Range<usize>,
+    seq: Range<usize>,
+    qual: Option<Range<usize>>,
+}
+
+/// Reads stored back to back in shared buffers
+#[derive(Debug, Clone, Default)]
+pub struct ReadBatch {
+    names: Vec<u8>,
+    bases: Vec<u8>,
+    qualities: Vec<u8>,
+    spans: Vec<ReadSpan>,
+}
+
+impl ReadBatch {
+    /// Append a read with its record ID and qualities (`None` for FASTA)
+    pub fn push(&mut self, id: &[u8], seq: &[u8], qual: Option<&[u8]>) {
+        let name_start = self.names.len();
+        self.names.extend_from_slice(id);
+        let start = self.bases.len();
+        self.bases.extend_from_slice(seq);
+        let qual = qual.map(|qual| {
+            let start = self.qualities.len();
+            self.qualities.extend_from_slice(qual);
+            start..self.qualities.len()
+        });
+        self.spans.push(ReadSpan {
+            name: name_start..self.names.len(),
+            seq: start..self.bases.len(),
+            qual,
+        });
+    }
+
+    /// Remove all reads, keeping the buffers' capacity
+    pub fn clear(&mut self) {
+        self.names.clear();
+        self.bases.clear();
+        self.qualities.clear();
+        self.spans.clear();
+    }
+
+    /// Number of reads in the batch
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Sequence and qualities of the `i`th read
+    pub fn read(&self, i: usize) -> (&[u8], Option<&[u8]>) {
+        let span = &self.spans[i];
+        let qual = span.qual.clone().map(|qual| &self.qualities[qual]);
+        (&self.bases[span.seq.clone()], qual)
+    }
+
+    /// Record ID of the `i`th read
+    pub fn name(&self, i: usize) -> &[u8] {
+        &self.names[self.spans[i].name.clone()]
+    }
+}
+
+/// Batches of reads parsed from a file on a background thread.
+///
+/// Iterating yields each batch (or the parse error that ended the file) in
+/// file order. Dropping the reader stops the background thread after the
+/// batch it is filling.
+pub struct BatchReader {
+    // Declared first so it is dropped first, which wakes the reader thread
+    batches: Receiver<Result<ReadBatch, ProcessingError>>,
+    recycled: SyncSender<ReadBatch>,
+}
+
+impl BatchReader {
+    /// Start reading `path` in batches of `batch_size` reads, with up to
+    /// `read_ahead` batches waiting for the consumer
+    pub fn open(
+        path: impl AsRef<Path>,
+        batch_size: usize,
+        read_ahead: usize,
+    ) -> Result<Self, ProcessingError> {
+        // Open on the caller's thread, so a missing or unreadable file fails here
+        let mut reader = parse_fastx_file(path.as_ref())?;
+        let batch_size = batch_size.max(1);
+        let (batch_tx, batches) = sync_channel(read_ahead);
+        let (recycled, recycled_rx) = sync_channel::<ReadBatch>(read_ahead + 1);
+
+        thread::Builder::new()
+            .name("fastq-reader".to_string())
+            .spawn(move || {
+                let mut batch = recycled_rx.try_recv().unwrap_or_default();
+                while let Some(record) = reader.next() {
+                    match record {
+                        Ok(record) => batch.push(record.id(), &record.seq(), record.qual()),
+                        Err(e) => {
+                            let _ = batch_tx.send(Err(e.into()));
+                            return;
+                        }
+                    }
+                    if batch.len() >= batch_size {
+                        let next = recycled_rx.try_recv().unwrap_or_default();
+                        if batch_tx
+                            .send(Ok(std::mem::replace(&mut batch, next)))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+                if !batch.is_empty() {
+                    let _ = batch_tx.send(Ok(batch));
+                }
+            })?;
+
+        Ok(BatchReader { batches, recycled })
+    }
+
+    /// Give a processed batch back to be refilled
+    pub fn recycle(&self, mut batch: ReadBatch) {
+        batch.clear();
+        // When the reader has enough spare batches, this one is dropped
+        let _ = self.recycled.try_send(batch);
+    }
+}
+
+/// Reads per batch for reads of `mean_read_length` bases, so that the
+/// `read_ahead` batches waiting, the one being filled and the one being
+/// processed fit in `memory_budget` bytes together
+pub fn batch_size_for(mean_read_length: f64, memory_budget: usize, read_ahead: usize) -> usize {
+    // Bases and qualities, and where the read lies in them (names are short
+    // next to reads and left out)
+    let read_bytes = 2.0 * mean_read_length.max(0.0) + size_of::<ReadSpan>() as f64;
+    let batches = (read_ahead + 2) as f64;
+    let reads = memory_budget as f64 / batches / read_bytes;
+    (reads as usize).clamp(MIN_BATCH_READS, MAX_BATCH_READS)
+}
+
+/// Mean length of the first `BATCH_SIZE_SAMPLE_READS` records of `path`,
+/// `None` for an empty file
+pub fn sample_mean_read_length(path: impl AsRef<Path>) -> Result<Option<f64>, ProcessingError> {
+    let mut reader = parse_fastx_file(path.as_ref())?;
+    let mut bases = 0;
+    let mut reads = 0;
+    while let Some(record) = reader.next() {
+        bases += record?.num_bases();
+        reads += 1;
+        if reads >= BATCH_SIZE_SAMPLE_READS {
+            break;
+        }
+    }
+    Ok((reads > 0).then(|| bases as f64 / reads as f64))
+}
+
+impl Iterator for BatchReader {
+    type Item = Result<ReadBatch, ProcessingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.batches.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_batches_preserve_reads_and_recycle_buffers() {
+        let mut fastq = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..5 {
+            writeln!(
+                fastq,
+                "@read{}\n{}\n+\n{}",
+                i,
+                "ACGT".repeat(i + 1),
+                "I".repeat(4 * (i + 1))
+            )
+            .unwrap();
+        }
+        fastq.flush().unwrap();
+
+        let mut reader = BatchReader::open(fastq.path(), 2, 1).unwrap();
+        let mut sizes = Vec::new();
+        let mut names = Vec::new();
+        let mut lengths = Vec::new();
+        while let Some(batch) = reader.next() {
+            let batch = batch.unwrap();
+            sizes.push(batch.len());
+            for i in 0..batch.len() {
+                let (seq, qual) = batch.read(i);
+                assert!(seq.iter().all(|b| b"ACGT".contains(b)));
+                assert_eq!(qual.map(<[u8]>::len), Some(seq.len()));
+                names.push(String::from_utf8(batch.name(i).to_vec()).unwrap());
+                lengths.push(seq.len());
+            }
+            reader.recycle(batch);
+        }
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert_eq!(names, vec!["read0", "read1", "read2", "read3", "read4"]);
+        assert_eq!(lengths, vec![4, 8, 12, 16, 20]);
+
+        let mut batch = ReadBatch::default();
+        batch.push(b"r1", b"ACGT", None);
+        batch.clear();
+        batch.push(b"r2", b"GG", Some(b"II"));
+        assert_eq!(batch.read(0), (&b"GG"[..], Some(&b"II"[..])));
+        assert_eq!(batch.name(0), b"r2");
+        assert!(batch.bases.capacity() >= 4);
+
+        assert!(BatchReader::open("/nonexistent/reads.fq", 2, 1).is_err());
+    }
+
+    #[test]
+    fn test_batch_size_follows_read_length() {
+        let mut fastq = tempfile::NamedTempFile::new().unwrap();
+        for length in [100, 200, 300] {
+            writeln!(
+                fastq,
+                "@r\n{}\n+\n{}",
+                "A".repeat(length),
+                "I".repeat(length)
+            )
+            .unwrap();
+        }
+        fastq.flush().unwrap();
+        assert_eq!(sample_mean_read_length(fastq.path()).unwrap(), Some(200.0));
+        let empty = tempfile::NamedTempFile::new().unwrap();
+        assert_eq!(sample_mean_read_length(empty.path()).unwrap(), None);
+
+        let budget = DEFAULT_BATCH_MEMORY_BYTES;
+        let short = batch_size_for(150.0, budget, DEFAULT_READ_AHEAD_BATCHES);
+        let long = batch_size_for(15_000.0, budget, DEFAULT_READ_AHEAD_BATCHES);
+        assert!(short > 100_000, "{}", short);
+        assert!(long < 2000, "{}", long);
+        // The batches in flight stay within the budget
+        let in_flight = (DEFAULT_READ_AHEAD_BATCHES + 2) * long * 2 * 15_000;
+        assert!(in_flight <= budget);
+        assert_eq!(batch_size_for(1e9, budget, 4), MIN_BATCH_READS);
+        assert_eq!(batch_size_for(0.0, usize::MAX, 4), MAX_BATCH_READS);
+    }
+}
@@ -0,0 +1,168 @@
+//! Budgeted (any-time) classification.
+//!
+//! Deeply sequenced samples often settle on their top call after a small
+//! fraction of the reads; sketching the rest costs time without changing the
+//! answer. With an early-stop policy the sample is classified after every
+//! increment of reads, and processing stops once the top call (taxon and
+//! level) has stayed the same, with confidence moving by no more than the
+//! tolerance, for a number of consecutive increments. The results record how
+//! many reads were needed and the call at each checkpoint.
+
+use serde::{Deserialize, Serialize};
+
+use crate::adaptive::classifier::{Classification, TaxonomicLevel};
+
+/// Reads between two checkpoints
+pub const DEFAULT_EARLY_STOP_INCREMENT: usize = 50_000;
+
+/// Largest change in top-call confidence between checkpoints that counts as stable
+pub const DEFAULT_EARLY_STOP_TOLERANCE: f64 = 0.01;
+
+/// Consecutive stable checkpoints needed to stop
+pub const DEFAULT_EARLY_STOP_CHECKS: usize = 3;
+
+/// When to stop reading a sample early
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct EarlyStopPolicy {
+    /// Reads between checkpoints
+    pub increment_reads: usize,
+    /// Largest confidence change between checkpoints that counts as stable
+    pub tolerance: f64,
+    /// Consecutive stable checkpoints needed to stop
+    pub stable_checks: usize,
+}
+
+impl Default for EarlyStopPolicy {
+    fn default() -> Self {
+        EarlyStopPolicy {
+            increment_reads: DEFAULT_EARLY_STOP_INCREMENT,
+            tolerance: DEFAULT_EARLY_STOP_TOLERANCE,
+            stable_checks: DEFAULT_EARLY_STOP_CHECKS,
+        }
+    }
+}
+
+/// Top call after a number of reads
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub reads: usize,
+    /// `None` when nothing could be classified yet
+    pub taxon_id: Option<String>,
+    pub level: Option<TaxonomicLevel>,
+    pub confidence: f64,
+}
+
+/// How much of a sample was read under an early-stop policy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EarlyStopSummary {
+    pub policy: EarlyStopPolicy,
+    /// Reads processed before classification
+    pub reads_used: usize,
+    /// Whether processing stopped before the end of the input
+    pub stopped_early: bool,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+/// Follows the top call across checkpoints and decides when it is stable
+#[derive(Debug, Clone)]
+pub struct ConvergenceTracker {
+    policy: EarlyStopPolicy,
+    checkpoints: Vec<Checkpoint>,
+    stable: usize,
+}
+
+impl ConvergenceTracker {
+    pub fn new(policy: EarlyStopPolicy) -> Self {
+        ConvergenceTracker {
+            policy,
+            checkpoints: Vec::new(),
+            stable: 0,
+        }
+    }
+
+    /// Whether a checkpoint is due after `reads` reads
+    pub fn is_due(&self, reads: usize) -> bool {
+        let last = self
+            .checkpoints
+            .last()
+            .map_or(0, |checkpoint| checkpoint.reads);
+        reads >= last + self.policy.increment_reads.max(1)
+    }
+
+    /// Record the top call after `reads` reads (`None` if classification
+    /// failed); returns whether the call has converged
+    pub fn observe(&mut self, reads: usize, classification: Option<&Classification>) -> bool {
+        let checkpoint = Checkpoint {
+            reads,
+            taxon_id: classification.map(|c| c.taxon_id.clone()),
+            level: classification.map(|c| c.level),
+            confidence: classification.map_or(0.0, |c| c.confidence),
+        };
+        let stable = match self.checkpoints.last() {
+            Some(previous) => {
+                checkpoint.taxon_id.is_some()
+                    && checkpoint.taxon_id == previous.taxon_id
+                    && checkpoint.level == previous.level
+                    && (checkpoint.confidence - previous.confidence).abs() <= self.policy.tolerance
+            }
+            None => false,
+        };
+        self.stable = if stable { self.stable + 1 } else { 0 };
+        self.checkpoints.push(checkpoint);
+        self.stable >= self.policy.stable_checks
+    }
+
+    /// Summary for a sample of which `reads_used` reads were processed
+    pub fn finish(self, reads_used: usize, stopped_early: bool) -> EarlyStopSummary {
+        EarlyStopSummary {
+            policy: self.policy,
+            reads_used,
+            stopped_early,
+            checkpoints: self.checkpoints,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn call(taxon_id: &str, confidence: f64) -> Classification {
+        Classification {
+            taxon_id: taxon_id.to_string(),
+            lineage: Vec::new(),
+            level: TaxonomicLevel::Species,
+            confidence,
+            best_match: taxon_id.to_string(),
+            similarity_scores: HashMap::new(),
+            level_weights: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_stops_once_top_call_is_stable() {
+        let policy = EarlyStopPolicy {
+            increment_reads: 1000,
+            tolerance: 0.01,
+            stable_checks: 2,
+        };
+        let mut tracker = ConvergenceTracker::new(policy);
+        assert!(!tracker.is_due(999));
+        assert!(tracker.is_due(1000));
+
+        // Nothing classified yet, then the call flips, then confidence drifts
+        assert!(!tracker.observe(1000, None));
+        assert!(!tracker.observe(2000, Some(&call("561", 0.6))));
+        assert!(!tracker.observe(3000, Some(&call("562", 0.7))));
+        assert!(!tracker.observe(4000, Some(&call("562", 0.75))));
+        assert!(!tracker.is_due(4500));
+        assert!(!tracker.observe(5000, Some(&call("562", 0.755))));
+        assert!(tracker.observe(6000, Some(&call("562", 0.76))));
+
+        let summary = tracker.finish(6000, true);
+        assert_eq!(summary.reads_used, 6000);
+        assert_eq!(summary.checkpoints.len(), 6);
+        assert_eq!(summary.checkpoints[0].taxon_id, None);
+    }
+}
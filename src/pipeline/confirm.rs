@@ -0,0 +1,439 @@
+//! Confirmation of strain calls by read alignment.
+//!
+//! Sketch similarity says a reference shares k-mers with the sample; it does
+//! not show that reads cover the reference. For calls that matter, the top
+//! strains can be checked with an independent method: the sample reads are
+//! aligned to each called reference with minimap2 (run as a subprocess), and
+//! the breadth and depth of coverage are reported. A call whose breadth stays
+//! below the threshold gets a warning. The stage is off unless requested, and
+//! the minimap2 executable and preset are configurable.
+//!
+//! Coverage is also binned along the reference into a track drawn in the
+//! report: a true call is covered roughly evenly, while reads piling up on a
+//! few regions of an otherwise empty genome point to a false positive.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use needletail::parse_fastx_file;
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::qc::ProcessingError;
+use crate::pipeline::warnings::{ReportWarning, WarningKind};
+
+/// Number of top strain calls aligned against
+pub const DEFAULT_CONFIRM_TOP: usize = 1;
+
+/// Share of the reference that must be covered for a call to count as confirmed
+pub const DEFAULT_MIN_BREADTH: f64 = 0.5;
+
+/// Number of bins the coverage track divides a reference into
+pub const COVERAGE_TRACK_BINS: usize = 60;
+
+/// Depth levels of the coverage track, lowest to highest
+const TRACK_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// How the confirmation aligner is run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlignerConfig {
+    /// minimap2 executable (looked up on `PATH` if not a path)
+    pub executable: PathBuf,
+    /// minimap2 preset (`-x`), e.g. `sr` for short reads or `map-ont`
+    pub preset: String,
+    pub threads: usize,
+    /// Number of top strain calls to confirm
+    pub top_calls: usize,
+    /// Share of the reference that must be covered
+    pub min_breadth: f64,
+}
+
+impl Default for AlignerConfig {
+    fn default() -> Self {
+        AlignerConfig {
+            executable: PathBuf::from("minimap2"),
+            preset: "sr".to_string(),
+            threads: 1,
+            top_calls: DEFAULT_CONFIRM_TOP,
+            min_breadth: DEFAULT_MIN_BREADTH,
+        }
+    }
+}
+
+/// Alignment evidence for one call
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AlignmentConfirmation {
+    pub taxon_id: String,
+    /// Reference genome the reads were aligned to
+    pub reference: Option<PathBuf>,
+    /// Reads with a primary alignment
+    pub aligned_reads: usize,
+    pub reference_length: u64,
+    /// Reference bases covered by at least one alignment
+    pub covered_bases: u64,
+    /// `covered_bases / reference_length`
+    pub breadth: f64,
+    /// Aligned bases per reference base
+    pub mean_depth: f64,
+    /// Breadth reached the threshold
+    pub confirmed: bool,
+    /// Why the call could not be checked
+    #[serde(default)]
+    pub skipped: Option<String>,
+    /// Breadth and depth along the reference
+    #[serde(default)]
+    pub track: Option<CoverageTrack>,
+}
+
+/// Coverage of a reference in equal bins, contigs laid end to end in file order
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CoverageTrack {
+    /// Reference bases per bin (the last bin may be shorter)
+    pub bin_size: u64,
+    /// Share of each bin covered by at least one alignment
+    pub breadth: Vec<f64>,
+    /// Mean depth of each bin
+    pub depth: Vec<f64>,
+}
+
+impl CoverageTrack {
+    /// One character per bin: `.` for an uncovered bin, otherwise a bar
+    /// whose height is the bin's depth relative to the deepest bin
+    pub fn render(&self) -> String {
+        let max_depth = self.depth.iter().copied().fold(0.0, f64::max);
+        self.depth
+            .iter()
+            .zip(&self.breadth)
+            .map(|(&depth, &breadth)| {
+                if breadth <= 0.0 || max_depth <= 0.0 {
+                    return '.';
+                }
+                let level = (depth / max_depth * TRACK_LEVELS.len() as f64).ceil() as usize;
+                TRACK_LEVELS[level.clamp(1, TRACK_LEVELS.len()) - 1]
+            })
+            .collect()
+    }
+
+    /// Share of bins without any coverage
+    pub fn empty_fraction(&self) -> f64 {
+        if self.breadth.is_empty() {
+            return 0.0;
+        }
+        let empty = self
+            .breadth
+            .iter()
+            .filter(|&&breadth| breadth <= 0.0)
+            .count();
+        empty as f64 / self.breadth.len() as f64
+    }
+}
+
+impl AlignmentConfirmation {
+    /// A call that could not be checked
+    pub fn skipped(taxon_id: &str, reason: impl Into<String>) -> Self {
+        AlignmentConfirmation {
+            taxon_id: taxon_id.to_string(),
+            skipped: Some(reason.into()),
+            ..AlignmentConfirmation::default()
+        }
+    }
+
+    /// A warning if alignment did not confirm the call
+    pub fn warning(&self, min_breadth: f64) -> Option<ReportWarning> {
+        if self.confirmed || self.skipped.is_some() {
+            return None;
+        }
+        Some(ReportWarning {
+            kind: WarningKind::UnconfirmedCall,
+            level: None,
+            detail: format!(
+                "reads cover {:.1}% of {} (mean depth {:.2}x, {} reads aligned), below {:.0}%",
+                self.breadth * 100.0,
+                self.taxon_id,
+                self.mean_depth,
+                self.aligned_reads,
+                min_breadth * 100.0
+            ),
+            effect: "The sketch match may come from a related genome or from shared regions \
+                     such as plasmids or mobile elements rather than this reference"
+                .to_string(),
+        })
+    }
+}
+
+/// Runs minimap2 and summarizes its alignments
+#[derive(Debug, Clone)]
+pub struct ReadAligner {
+    pub config: AlignerConfig,
+}
+
+impl ReadAligner {
+    pub fn new(config: AlignerConfig) -> Self {
+        ReadAligner { config }
+    }
+
+    /// Version string of the aligner, or an error if it cannot be run
+    pub fn version(&self) -> Result<String, ProcessingError> {
+        let output = Command::new(&self.config.executable)
+            .arg("--version")
+            .output()
+            .map_err(|e| self.launch_error(e))?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Align `reads` to `reference` and measure how well they cover it
+    pub fn confirm(
+        &self,
+        taxon_id: &str,
+        reference: &Path,
+        reads: &Path,
+    ) -> Result<AlignmentConfirmation, ProcessingError> {
+        let contigs = contig_lengths(reference)?;
+        let reference_length: u64 = contigs.iter().map(|(_, length)| length).sum();
+        let mut child = Command::new(&self.config.executable)
+            .arg("-x")
+            .arg(&self.config.preset)
+            .arg("-t")
+            .arg(self.config.threads.max(1).to_string())
+            .arg("--secondary=no")
+            .arg(reference)
+            .arg(reads)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| self.launch_error(e))?;
+
+        // Drain stderr alongside stdout so a chatty aligner cannot block
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let stderr_reader = thread::spawn(move || {
+            let mut text = String::new();
+            let _ = stderr.read_to_string(&mut text);
+            text
+        });
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let coverage = PafCoverage::read(BufReader::new(stdout));
+        let status = child.wait()?;
+        let stderr_text = stderr_reader.join().unwrap_or_default();
+        if !status.success() {
+            let last_line = stderr_text.lines().last().unwrap_or("").to_string();
+            return Err(ProcessingError::ExternalTool(format!(
+                "{} exited with {}: {}",
+                self.config.executable.display(),
+                status,
+                last_line
+            )));
+        }
+        let coverage = coverage?;
+
+        let covered_bases = coverage.covered_bases();
+        let breadth = covered_bases as f64 / reference_length.max(1) as f64;
+        Ok(AlignmentConfirmation {
+            taxon_id: taxon_id.to_string(),
+            reference: Some(reference.to_path_buf()),
+            aligned_reads: coverage.reads.len(),
+            reference_length,
+            covered_bases,
+            breadth,
+            mean_depth: coverage.aligned_bases as f64 / reference_length.max(1) as f64,
+            confirmed: breadth >= self.config.min_breadth,
+            skipped: None,
+            track: Some(coverage.track(&contigs, COVERAGE_TRACK_BINS)),
+        })
+    }
+
+    fn launch_error(&self, e: std::io::Error) -> ProcessingError {
+        ProcessingError::ExternalTool(format!(
+            "cannot run {} ({}); install minimap2 or point --aligner at it",
+            self.config.executable.display(),
+            e
+        ))
+    }
+}
+
+/// Primary alignments from PAF output, as target intervals
+#[derive(Debug, Default)]
+struct PafCoverage {
+    reads: HashSet<String>,
+    intervals: HashMap<String, Vec<(u64, u64)>>,
+    aligned_bases: u64,
+}
+
+impl PafCoverage {
+    fn read(paf: impl BufRead) -> Result<Self, ProcessingError> {
+        let mut coverage = PafCoverage::default();
+        for line in paf.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 12 {
+                continue;
+            }
+            // Secondary and supplementary alignments would count bases twice
+            if fields[12..]
+                .iter()
+                .any(|tag| *tag != "tp:A:P" && tag.starts_with("tp:A:"))
+            {
+                continue;
+            }
+            let parse = |field: &str| {
+                field.parse::<u64>().map_err(|_| {
+                    ProcessingError::ExternalTool(format!("malformed PAF line: {}", line))
+                })
+            };
+            let (start, end) = (parse(fields[7])?, parse(fields[8])?);
+            coverage.reads.insert(fields[0].to_string());
+            coverage.aligned_bases += end.saturating_sub(start);
+            coverage
+                .intervals
+                .entry(fields[5].to_string())
+                .or_default()
+                .push((start, end));
+        }
+        Ok(coverage)
+    }
+
+    /// Target bases inside at least one interval
+    fn covered_bases(&self) -> u64 {
+        self.intervals
+            .values()
+            .flat_map(|intervals| merge_intervals(intervals))
+            .map(|(start, end)| end - start)
+            .sum()
+    }
+
+    /// Breadth and depth in `bins` bins over `contigs`; alignments to
+    /// targets missing from `contigs` are left out
+    fn track(&self, contigs: &[(String, u64)], bins: usize) -> CoverageTrack {
+        let total: u64 = contigs.iter().map(|(_, length)| length).sum();
+        if total == 0 || bins == 0 {
+            return CoverageTrack::default();
+        }
+        let bin_size = total.div_ceil(bins as u64);
+        let bins = total.div_ceil(bin_size) as usize;
+        let mut covered = vec![0u64; bins];
+        let mut aligned = vec![0u64; bins];
+        let mut offset = 0;
+        for (name, length) in contigs {
+            if let Some(intervals) = self.intervals.get(name) {
+                for &(start, end) in intervals {
+                    add_span(
+                        &mut aligned,
+                        bin_size,
+                        offset + start,
+                        offset + end.min(*length),
+                    );
+                }
+                for (start, end) in merge_intervals(intervals) {
+                    add_span(
+                        &mut covered,
+                        bin_size,
+                        offset + start,
+                        offset + end.min(*length),
+                    );
+                }
+            }
+            offset += length;
+        }
+        let bin_length = |i: usize| (bin_size.min(total - i as u64 * bin_size)) as f64;
+        CoverageTrack {
+            bin_size,
+            breadth: (0..bins)
+                .map(|i| covered[i] as f64 / bin_length(i))
+                .collect(),
+            depth: (0..bins)
+                .map(|i| aligned[i] as f64 / bin_length(i))
+                .collect(),
+        }
+    }
+}
+
+/// Sorted, non-overlapping union of `intervals`
+fn merge_intervals(intervals: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut sorted = intervals.to_vec();
+    sorted.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(sorted.len());
+    for (start, end) in sorted {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Add the bases of `start..end` to the bins they fall in
+fn add_span(bins: &mut [u64], bin_size: u64, start: u64, end: u64) {
+    let mut position = start;
+    while position < end {
+        let bin = (position / bin_size) as usize;
+        if bin >= bins.len() {
+            break;
+        }
+        let bin_end = ((bin as u64 + 1) * bin_size).min(end);
+        bins[bin] += bin_end - position;
+        position = bin_end;
+    }
+}
+
+/// Name (first word of the header) and length of each sequence in a
+/// (possibly compressed) FASTA file, in file order
+fn contig_lengths(path: &Path) -> Result<Vec<(String, u64)>, ProcessingError> {
+    let mut reader = parse_fastx_file(path)?;
+    let mut contigs = Vec::new();
+    while let Some(record) = reader.next() {
+        let record = record?;
+        let name = String::from_utf8_lossy(record.id())
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        contigs.push((name, record.num_bases() as u64));
+    }
+    Ok(contigs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paf_coverage_breadth_and_depth() {
+        let paf = "\
+r1\t150\t0\t150\t+\tchr\t1000\t0\t150\t150\t150\t60\ttp:A:P
+r2\t150\t0\t150\t+\tchr\t1000\t100\t250\t150\t150\t60\ttp:A:P
+r2\t150\t0\t150\t-\tchr\t1000\t600\t750\t150\t150\t0\ttp:A:S
+r3\t150\t0\t100\t+\tplasmid\t200\t50\t150\t100\t100\t60\ttp:A:P
+";
+        let coverage = PafCoverage::read(paf.as_bytes()).unwrap();
+        assert_eq!(coverage.reads.len(), 3);
+        assert_eq!(coverage.aligned_bases, 400);
+        // chr 0-250 merged, plasmid 50-150; the secondary hit is ignored
+        assert_eq!(coverage.covered_bases(), 350);
+
+        // 1200 bases in 4 bins of 300: chr 0-250 twice over 100-150, the
+        // plasmid at 1050-1150
+        let contigs = vec![("chr".to_string(), 1000), ("plasmid".to_string(), 200)];
+        let track = coverage.track(&contigs, 4);
+        assert_eq!(track.bin_size, 300);
+        assert_eq!(track.breadth, vec![250.0 / 300.0, 0.0, 0.0, 100.0 / 300.0]);
+        assert_eq!(track.depth, vec![300.0 / 300.0, 0.0, 0.0, 100.0 / 300.0]);
+        assert_eq!(track.render(), "█..▃");
+        assert_eq!(track.empty_fraction(), 0.5);
+
+        assert!(PafCoverage::read(
+            "r1\t150\t0\t150\t+\tchr\t1000\tx\t150\t150\t150\t60\n".as_bytes()
+        )
+        .is_err());
+
+        let unconfirmed = AlignmentConfirmation {
+            taxon_id: "GCF_000005845".to_string(),
+            breadth: 0.05,
+            ..AlignmentConfirmation::default()
+        };
+        assert!(unconfirmed.warning(DEFAULT_MIN_BREADTH).is_some());
+        assert!(AlignmentConfirmation::skipped("GCF_000005845", "no genome")
+            .warning(DEFAULT_MIN_BREADTH)
+            .is_none());
+    }
+}
@@ -0,0 +1,317 @@
+//! Contamination by sequences from unexpected sources.
+//!
+//! Adapter read-through, spiked-in controls, host DNA and cloning vectors all
+//! end up in the sample sketch and are either misclassified or silently
+//! dilute every abundance. Reads passing QC are screened against control
+//! panels (canonical k-mers of the control sequences) and the share of reads
+//! and of k-mers matching each panel is recorded in the processing metrics.
+//!
+//! Every run is screened against two default panels: a small adapter panel
+//! built into the binary, and the PhiX174 genome (NC_001422.1, 5,386 bp) used
+//! as an Illumina spike-in. The PhiX genome is downloaded from NCBI once and
+//! kept in the cache directory; a run without network access and without a
+//! cached copy warns and screens against the adapters only. Larger controls,
+//! such as a host genome or a vector collection like UniVec, are loaded from
+//! FASTA. Reads matching a panel marked for removal, typically PhiX, are
+//! dropped before sketching so they never reach count tables or
+//! classifications.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use needletail::parse_fastx_file;
+use nthash::NtHashIterator;
+use serde::{Deserialize, Serialize};
+
+use crate::database::downloader::NCBI_EUTILS_URL;
+use crate::pipeline::qc::ProcessingError;
+
+/// k-mer size of control panels
+pub const DEFAULT_CONTROL_KMER_SIZE: usize = 21;
+
+/// Fraction of a read's k-mers that must be in a panel for the read to count as a match
+pub const DEFAULT_CONTROL_MIN_CONTAINMENT: f64 = 0.5;
+
+/// Share of reads matching one panel above which the sample is flagged
+pub const DEFAULT_MAX_CONTAMINATION_FRACTION: f64 = 0.05;
+
+/// Accession of the PhiX174 genome of the default `phix` panel
+pub const PHIX_ACCESSION: &str = "NC_001422.1";
+
+/// Length of `PHIX_ACCESSION`, checked when it is downloaded
+const PHIX_LENGTH: usize = 5386;
+
+/// How long the PhiX download may take
+const PHIX_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Illumina adapter sequences of the built-in `adapters` panel
+const ADAPTER_SEQUENCES: [&str; 5] = [
+    // TruSeq read 1 and read 2 adapters
+    "AGATCGGAAGAGCACACGTCTGAACTCCAGTCAC",
+    "AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTGT",
+    // Nextera read 1 and read 2 adapters
+    "CTGTCTCTTATACACATCTCCGAGCCCACGAGAC",
+    "CTGTCTCTTATACACATCTGACGCTGCCGACGA",
+    // TruSeq small RNA 3' adapter
+    "TGGAATTCTCGGGTGCCAAGG",
+];
+
+/// Canonical k-mers of a set of control sequences
+#[derive(Debug, Clone)]
+pub struct ControlPanel {
+    pub name: String,
+    pub kmer_size: usize,
+    pub min_containment: f64,
+    /// Remove matching reads before sketching instead of only counting them
+    pub remove: bool,
+    hashes: HashSet<u64>,
+}
+
+impl ControlPanel {
+    /// Build a panel from control sequences. Ambiguity codes are read as `N`;
+    /// sequences shorter than `kmer_size` add nothing.
+    pub fn from_sequences<I, S>(name: &str, sequences: I, kmer_size: usize) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        let mut hashes = HashSet::new();
+        for sequence in sequences {
+            let sequence: Vec<u8> = sequence
+                .as_ref()
+                .iter()
+                .map(|&base| match base.to_ascii_uppercase() {
+                    base @ (b'A' | b'C' | b'G' | b'T') => base,
+                    _ => b'N',
+                })
+                .collect();
+            hashes.extend(canonical_hashes(&sequence, kmer_size));
+        }
+        ControlPanel {
+            name: name.to_string(),
+            kmer_size,
+            min_containment: DEFAULT_CONTROL_MIN_CONTAINMENT,
+            remove: false,
+            hashes,
+        }
+    }
+
+    /// Build a panel from a (possibly compressed) FASTA file
+    pub fn from_fasta(name: &str, path: &Path, kmer_size: usize) -> Result<Self, ProcessingError> {
+        if kmer_size == 0 {
+            return Err(ProcessingError::InvalidParameters(
+                "control panel k-mer size must be positive".to_string(),
+            ));
+        }
+        let mut reader = parse_fastx_file(path)?;
+        let mut sequences = Vec::new();
+        while let Some(record) = reader.next() {
+            sequences.push(record?.seq().into_owned());
+        }
+        let panel = ControlPanel::from_sequences(name, &sequences, kmer_size);
+        if panel.is_empty() {
+            return Err(ProcessingError::InvalidParameters(format!(
+                "control panel {} has no sequences of at least {} bp",
+                path.display(),
+                kmer_size
+            )));
+        }
+        Ok(panel)
+    }
+
+    /// The panels every run is screened against
+    pub fn builtin() -> Vec<ControlPanel> {
+        vec![ControlPanel::from_sequences(
+            "adapters",
+            ADAPTER_SEQUENCES,
+            DEFAULT_CONTROL_KMER_SIZE,
+        )]
+    }
+
+    /// The PhiX174 spike-in panel (`phix`), from the copy of the genome in
+    /// `cache_dir`, which is downloaded from NCBI the first time
+    pub fn phix(cache_dir: &Path) -> Result<Self, ProcessingError> {
+        let path = phix_path(cache_dir);
+        if !path.exists() {
+            download_phix(&path)?;
+        }
+        ControlPanel::from_fasta("phix", &path, DEFAULT_CONTROL_KMER_SIZE)
+    }
+
+    /// Number of k-mers in the panel
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Number of k-mers of `read` and how many of them are in the panel
+    pub fn matches(&self, read: &[u8]) -> (usize, usize) {
+        canonical_hashes(read, self.kmer_size).fold((0, 0), |(total, found), hash| {
+            (total + 1, found + self.hashes.contains(&hash) as usize)
+        })
+    }
+
+    /// Whether a read with `kmers` k-mers, `matching` of them in the panel, comes from it
+    pub fn is_match(&self, kmers: usize, matching: usize) -> bool {
+        kmers > 0 && matching as f64 >= self.min_containment * kmers as f64
+    }
+}
+
+/// Reads and k-mers of a sample matching one control panel
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ContaminationEstimate {
+    pub panel: String,
+    /// Reads whose k-mers are mostly in the panel
+    pub matching_reads: usize,
+    /// Whether the matching reads were removed from the sample
+    #[serde(default)]
+    pub removed: bool,
+    pub screened_reads: usize,
+    pub matching_kmers: usize,
+    pub screened_kmers: usize,
+    /// `matching_reads / screened_reads`
+    pub read_fraction: f64,
+    /// `matching_kmers / screened_kmers`; also counts partial matches such as
+    /// adapter read-through
+    pub kmer_fraction: f64,
+}
+
+impl ContaminationEstimate {
+    /// An empty estimate for each panel, in panel order
+    pub fn for_panels(panels: &[ControlPanel]) -> Vec<ContaminationEstimate> {
+        panels
+            .iter()
+            .map(|panel| ContaminationEstimate {
+                panel: panel.name.clone(),
+                removed: panel.remove,
+                ..ContaminationEstimate::default()
+            })
+            .collect()
+    }
+
+    /// Count one read with `kmers` k-mers, `matching` of them in the panel;
+    /// `is_match` is the panel's call on the whole read
+    pub fn add_read(&mut self, kmers: usize, matching: usize, is_match: bool) {
+        self.screened_reads += 1;
+        self.screened_kmers += kmers;
+        self.matching_kmers += matching;
+        if is_match {
+            self.matching_reads += 1;
+        }
+        self.read_fraction = self.matching_reads as f64 / self.screened_reads as f64;
+        self.kmer_fraction = self.matching_kmers as f64 / self.screened_kmers.max(1) as f64;
+    }
+}
+
+/// Where the PhiX genome is cached
+fn phix_path(cache_dir: &Path) -> PathBuf {
+    cache_dir
+        .join("controls")
+        .join(format!("{}.fasta", PHIX_ACCESSION))
+}
+
+/// Download the PhiX genome to `path`, which is only written once the
+/// sequence has the expected length
+fn download_phix(path: &Path) -> Result<(), ProcessingError> {
+    let download_error = |e: reqwest::Error| {
+        ProcessingError::DatabaseError(format!("Cannot download {}: {}", PHIX_ACCESSION, e))
+    };
+    let url = format!(
+        "{}/efetch.fcgi?db=nuccore&id={}&rettype=fasta&retmode=text",
+        NCBI_EUTILS_URL, PHIX_ACCESSION
+    );
+    let fasta = reqwest::blocking::Client::builder()
+        .timeout(PHIX_DOWNLOAD_TIMEOUT)
+        .build()
+        .and_then(|client| client.get(&url).send())
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(download_error)?;
+    let length: usize = fasta
+        .lines()
+        .filter(|line| !line.starts_with('>'))
+        .map(|line| line.trim().len())
+        .sum();
+    if !fasta.starts_with('>') || length != PHIX_LENGTH {
+        return Err(ProcessingError::DatabaseError(format!(
+            "{} from NCBI has {} bp instead of {}",
+            PHIX_ACCESSION, length, PHIX_LENGTH
+        )));
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let mut staged = tempfile::NamedTempFile::new_in(dir)?;
+    staged.write_all(fasta.as_bytes())?;
+    staged.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Canonical ntHash values of the k-mers of `sequence` (none if it is shorter than `kmer_size`)
+fn canonical_hashes(sequence: &[u8], kmer_size: usize) -> impl Iterator<Item = u64> + '_ {
+    NtHashIterator::new(sequence, kmer_size)
+        .ok()
+        .into_iter()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bio::reverse_complement;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_reads_screened_against_control_panels() {
+        let panels = ControlPanel::builtin();
+        let adapters = &panels[0];
+        assert_eq!(adapters.name, "adapters");
+        assert!(!adapters.is_empty());
+
+        // An adapter dimer matches on either strand; a read with adapter
+        // read-through only contributes matching k-mers
+        let dimer = format!("{}{}", ADAPTER_SEQUENCES[0], ADAPTER_SEQUENCES[1]);
+        let insert = "ACGTTGCAAGCTTGACCTAGGCATCGATCCGGAATTCAGGCTTAACCGGTTAGCTAGCTACG";
+        let read_through = format!("{}{}", insert, ADAPTER_SEQUENCES[0]);
+
+        let mut estimate = ContaminationEstimate::for_panels(&panels).remove(0);
+        for read in [
+            dimer.as_bytes().to_vec(),
+            reverse_complement(dimer.as_bytes()),
+            read_through.into_bytes(),
+            insert.as_bytes().to_vec(),
+        ] {
+            let (kmers, matching) = adapters.matches(&read);
+            estimate.add_read(kmers, matching, adapters.is_match(kmers, matching));
+        }
+
+        assert_eq!(estimate.screened_reads, 4);
+        assert_eq!(estimate.matching_reads, 2);
+        assert_relative_eq!(estimate.read_fraction, 0.5);
+        assert!(estimate.kmer_fraction > 0.0 && estimate.kmer_fraction < estimate.read_fraction);
+
+        // Reads shorter than k have nothing to match
+        assert_eq!(adapters.matches(b"ACGT"), (0, 0));
+    }
+
+    #[test]
+    fn test_phix_panel_is_read_from_the_cache() {
+        // A cached copy is used as is, without contacting NCBI
+        let cache = tempfile::tempdir().unwrap();
+        let genome = "GAGTTTTATCGCTTCCATGACGCAGAAGTTAACACTTTCGGATATTTCTGATGAGTCG";
+        let path = phix_path(cache.path());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, format!(">{}\n{}\n", PHIX_ACCESSION, genome)).unwrap();
+
+        let phix = ControlPanel::phix(cache.path()).unwrap();
+        assert_eq!(phix.name, "phix");
+        let (kmers, matching) = phix.matches(genome[5..50].as_bytes());
+        assert!(phix.is_match(kmers, matching));
+    }
+}
@@ -0,0 +1,545 @@
+//! Environment diagnostics (`doctor`).
+//!
+//! Most support requests come down to the environment rather than the
+//! classifier: a database locked by another run or written by a newer
+//! version, a cache directory on a read-only mount, a cluster node without
+//! outbound network access, more threads requested than the job was given,
+//! or minimap2 missing from `PATH`. `doctor` checks each of these and prints
+//! a table that can be pasted into an issue as is.
+//!
+//! It ends with a self-test that needs nothing but the program itself: two
+//! small genomes are generated, sketched into a scratch database, and reads
+//! drawn from one of them are classified. If that call is wrong, the problem
+//! is in the build, not in the user's data or database.
+
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::blocking::Client;
+
+use crate::config::DatabaseConfig;
+use crate::database::downloader::{SignatureDatabase, NCBI_EUTILS_URL};
+use crate::pipeline::confirm::{AlignerConfig, ReadAligner};
+use crate::pipeline::qc::FastqProcessor;
+use crate::sketch::{SignatureBuilder, SignatureLayout};
+
+/// How long the NCBI check waits for an answer
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Length of each self-test genome
+const TOY_GENOME_LENGTH: usize = 20_000;
+
+/// Reads drawn from the first self-test genome
+const TOY_READS: usize = 600;
+
+/// Length of each self-test read
+const TOY_READ_LENGTH: usize = 150;
+
+/// Outcome of one check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but something may cause trouble or is only needed by some commands
+    Warn,
+    Fail,
+    /// Not run, e.g. the network check without `--network`
+    Skip,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "SKIP",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A check, its outcome and what was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Check {
+            name: name.to_string(),
+            status,
+            detail: detail.into(),
+        }
+    }
+
+    /// `Pass` with the value, or `failure` with the error
+    fn from_result(name: &str, result: Result<String>, failure: CheckStatus) -> Self {
+        match result {
+            Ok(detail) => Check::new(name, CheckStatus::Pass, detail),
+            Err(e) => Check::new(name, failure, format!("{:#}", e)),
+        }
+    }
+}
+
+/// What `doctor` checks
+#[derive(Debug, Clone)]
+pub struct DoctorOptions {
+    pub db_path: PathBuf,
+    /// Storage settings the database is opened with
+    pub database: DatabaseConfig,
+    pub cache_dir: PathBuf,
+    /// Threads the run was asked to use
+    pub threads: usize,
+    /// Memory the read batches may take, in bytes
+    pub batch_memory_bytes: usize,
+    /// minimap2 executable used by `--confirm`
+    pub aligner: PathBuf,
+    /// Contact NCBI
+    pub network: bool,
+    /// Classify the toy reads
+    pub self_test: bool,
+}
+
+/// Outcome of every check, printed as a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    /// Number of checks that failed
+    pub fn failed(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Fail)
+            .count()
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "strain_ahsp {} on {}-{}",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        )?;
+        let width = self
+            .checks
+            .iter()
+            .map(|check| check.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("CHECK".len());
+        writeln!(f, "{:<width$}  STATUS  DETAIL", "CHECK")?;
+        for check in &self.checks {
+            writeln!(
+                f,
+                "{:<width$}  {:<6}  {}",
+                check.name, check.status, check.detail
+            )?;
+        }
+        write!(
+            f,
+            "{} of {} checks failed",
+            self.failed(),
+            self.checks.len()
+        )
+    }
+}
+
+/// Run every check in `options`
+pub fn run_checks(options: &DoctorOptions) -> DoctorReport {
+    let mut checks = vec![
+        check_database(&options.db_path, &options.database),
+        check_cache(&options.cache_dir),
+    ];
+    checks.push(if options.network {
+        Check::from_result("ncbi network", check_network(), CheckStatus::Fail)
+    } else {
+        Check::new(
+            "ncbi network",
+            CheckStatus::Skip,
+            "not checked; use --network",
+        )
+    });
+    checks.push(check_threads(options.threads));
+    checks.push(check_memory(options));
+    checks.push(Check::from_result(
+        "minimap2",
+        ReadAligner::new(AlignerConfig {
+            executable: options.aligner.clone(),
+            ..AlignerConfig::default()
+        })
+        .version()
+        .map(|version| format!("{} ({})", version, options.aligner.display()))
+        .map_err(|e| anyhow!("{}; only needed for --confirm", e)),
+        CheckStatus::Warn,
+    ));
+    checks.push(Check::from_result(
+        "Rscript",
+        tool_version("Rscript").map_err(|e| anyhow!("{}; only needed to run import_phyloseq.R", e)),
+        CheckStatus::Warn,
+    ));
+    checks.push(if options.self_test {
+        Check::from_result("self-test", self_test(options.threads), CheckStatus::Fail)
+    } else {
+        Check::new(
+            "self-test",
+            CheckStatus::Skip,
+            "skipped with --skip-self-test",
+        )
+    });
+    DoctorReport { checks }
+}
+
+/// The database opens, is not locked by another run, and its signatures decode
+fn check_database(db_path: &Path, config: &DatabaseConfig) -> Check {
+    let name = "database";
+    if !db_path.exists() {
+        return Check::new(
+            name,
+            CheckStatus::Fail,
+            format!(
+                "{} does not exist; create one with `db init` or `db install`",
+                db_path.display()
+            ),
+        );
+    }
+    let opened = SignatureDatabase::open_with_config(db_path, config).and_then(|database| {
+        let signatures = database.get_all_signatures()?;
+        Ok((signatures.len(), database.size_on_disk()?))
+    });
+    match opened {
+        Ok((0, _)) => Check::new(
+            name,
+            CheckStatus::Warn,
+            format!("{} opens but holds no signatures", db_path.display()),
+        ),
+        Ok((count, bytes)) => Check::new(
+            name,
+            CheckStatus::Pass,
+            format!(
+                "{} signatures readable, {:.1} MiB at {}",
+                count,
+                bytes as f64 / (1 << 20) as f64,
+                db_path.display()
+            ),
+        ),
+        Err(e) => Check::new(
+            name,
+            CheckStatus::Fail,
+            format!(
+                "{}: {} (another run may hold the database open)",
+                db_path.display(),
+                e
+            ),
+        ),
+    }
+}
+
+/// A file can be created, written and removed in the cache directory
+fn check_cache(cache_dir: &Path) -> Check {
+    let probe = cache_dir.join(format!(".ahsp-doctor-{}", std::process::id()));
+    let written = fs::create_dir_all(cache_dir)
+        .and_then(|_| fs::File::create(&probe))
+        .and_then(|mut file| file.write_all(b"ahsp doctor\n"))
+        .and_then(|_| fs::remove_file(&probe));
+    match written {
+        Ok(()) => Check::new(
+            "cache",
+            CheckStatus::Pass,
+            format!("{} is writable", cache_dir.display()),
+        ),
+        Err(e) => Check::new(
+            "cache",
+            CheckStatus::Fail,
+            format!("cannot write to {}: {}", cache_dir.display(), e),
+        ),
+    }
+}
+
+/// NCBI E-utilities answer, as `db add-references` and `suggest-references --add` need
+fn check_network() -> Result<String> {
+    let client = Client::builder().timeout(NETWORK_TIMEOUT).build()?;
+    let url = format!("{}/einfo.fcgi?retmode=json", NCBI_EUTILS_URL);
+    let started = Instant::now();
+    let response = client.get(&url).send()?;
+    if !response.status().is_success() {
+        bail!("{} answered {}", url, response.status());
+    }
+    Ok(format!(
+        "E-utilities reachable ({} ms)",
+        started.elapsed().as_millis()
+    ))
+}
+
+/// The requested threads do not exceed the CPUs this process may use
+fn check_threads(threads: usize) -> Check {
+    let available = std::thread::available_parallelism().map_or(1, |n| n.get());
+    if threads > available {
+        Check::new(
+            "threads",
+            CheckStatus::Warn,
+            format!(
+                "{} requested but only {} CPUs available; extra threads only compete",
+                threads, available
+            ),
+        )
+    } else {
+        Check::new(
+            "threads",
+            CheckStatus::Pass,
+            format!("{} requested, {} CPUs available", threads, available),
+        )
+    }
+}
+
+/// Available memory covers the read batches and the references, which are
+/// held in memory while classifying (estimated by the database's size on disk)
+fn check_memory(options: &DoctorOptions) -> Check {
+    let Some(available) = available_memory() else {
+        return Check::new(
+            "memory",
+            CheckStatus::Skip,
+            "available memory is only read on Linux",
+        );
+    };
+    let database = fs::metadata(&options.db_path)
+        .ok()
+        .filter(|metadata| metadata.is_dir())
+        .map_or(0, |_| directory_size(&options.db_path));
+    let needed = options.batch_memory_bytes as u64 + database;
+    let mib = |bytes: u64| bytes >> 20;
+    let detail = format!(
+        "{} MiB available, about {} MiB needed ({} MiB batches, {} MiB database)",
+        mib(available),
+        mib(needed),
+        mib(options.batch_memory_bytes as u64),
+        mib(database)
+    );
+    if available < needed {
+        Check::new(
+            "memory",
+            CheckStatus::Warn,
+            format!("{}; lower --batch-memory", detail),
+        )
+    } else {
+        Check::new("memory", CheckStatus::Pass, detail)
+    }
+}
+
+/// `MemAvailable` of `/proc/meminfo`, in bytes
+fn available_memory() -> Option<u64> {
+    parse_mem_available(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Total size of the files below `dir`
+fn directory_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// First line the tool prints for `--version`, on stdout or stderr
+fn tool_version(executable: &str) -> Result<String> {
+    let output = Command::new(executable)
+        .arg("--version")
+        .output()
+        .map_err(|e| anyhow!("cannot run {}: {}", executable, e))?;
+    let text = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    Ok(String::from_utf8_lossy(&text)
+        .lines()
+        .next()
+        .unwrap_or(executable)
+        .trim()
+        .to_string())
+}
+
+/// Random but reproducible DNA sequence
+fn toy_genome(length: usize, mut state: u64) -> Vec<u8> {
+    (0..length)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            b"ACGT"[(state >> 62) as usize]
+        })
+        .collect()
+}
+
+/// Lineage of a toy species, domain to species
+fn toy_lineage(species: &str) -> Vec<String> {
+    [
+        "Bacteria",
+        "Toyobacteria",
+        "Toyobacteria",
+        "Toyales",
+        "Toyaceae",
+        "Toya",
+        species,
+    ]
+    .iter()
+    .map(|name| name.to_string())
+    .collect()
+}
+
+/// Sketch two toy genomes into a scratch database, classify reads drawn from
+/// the first, and check that they are called as it
+fn self_test(threads: usize) -> Result<String> {
+    let started = Instant::now();
+    let scratch = tempfile::tempdir()?;
+    let dir = scratch.path();
+    let genomes = [
+        (
+            "toy_a",
+            "Toya alpha",
+            toy_genome(TOY_GENOME_LENGTH, 0x9E37_79B9_7F4A_7C15),
+        ),
+        (
+            "toy_b",
+            "Toya beta",
+            toy_genome(TOY_GENOME_LENGTH, 0xD1B5_4A32_D192_ED03),
+        ),
+    ];
+
+    let (macro_k, meso_k, sketch_size) = (31, 21, 1000);
+    let builder =
+        SignatureBuilder::from_layout(SignatureLayout::minhash(&[macro_k, meso_k], sketch_size))?;
+    let db_path = dir.join("db");
+    {
+        let mut database = SignatureDatabase::open(&db_path)?;
+        for (id, species, genome) in &genomes {
+            let fasta = dir.join(format!("{}.fasta", id));
+            fs::write(
+                &fasta,
+                format!(">{}\n{}\n", id, String::from_utf8_lossy(genome)),
+            )?;
+            let signature = builder.build_from_file(&fasta, id, toy_lineage(species))?;
+            database.add_signature(&signature)?;
+        }
+        // Dropped here, so the processor can open the database
+    }
+
+    let fastq = dir.join("toy_reads.fastq");
+    let genome = &genomes[0].2;
+    let quality = "I".repeat(TOY_READ_LENGTH);
+    let step = (genome.len() - TOY_READ_LENGTH) / TOY_READS;
+    let mut reads = String::new();
+    for i in 0..TOY_READS {
+        let start = i * step;
+        reads.push_str(&format!(
+            "@toy_read_{}\n{}\n+\n{}\n",
+            i,
+            String::from_utf8_lossy(&genome[start..start + TOY_READ_LENGTH]),
+            quality
+        ));
+    }
+    fs::write(&fastq, reads)?;
+
+    let output = dir.join("results");
+    fs::create_dir_all(&output)?;
+    let mut processor = FastqProcessor::new(
+        &db_path,
+        dir.join("cache"),
+        threads.max(1),
+        macro_k,
+        meso_k,
+        sketch_size,
+        None,
+        None,
+    )?;
+    processor.init_classifier()?;
+    let results = processor.process_file(&fastq, "doctor_self_test", &output)?;
+
+    let expected = genomes[0].0;
+    match results.classifications.first() {
+        Some(top) if top.best_match == expected => Ok(format!(
+            "toy reads classified as {} ({:?}, confidence {:.2}) in {:.1} s",
+            expected,
+            top.level,
+            top.confidence,
+            started.elapsed().as_secs_f64()
+        )),
+        Some(top) => bail!(
+            "toy reads from {} were classified as {}",
+            expected,
+            top.best_match
+        ),
+        None => bail!(
+            "toy reads from {} were not classified ({} of {} reads passed QC)",
+            expected,
+            results.metrics.passed_reads,
+            results.metrics.total_reads
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_checks_and_table() {
+        let meminfo = "MemTotal:       16318412 kB\nMemAvailable:    8159206 kB\n";
+        assert_eq!(parse_mem_available(meminfo), Some(8159206 * 1024));
+        assert_eq!(parse_mem_available("MemTotal: 1 kB\n"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = check_cache(&dir.path().join("cache"));
+        assert_eq!(cache.status, CheckStatus::Pass);
+        assert_eq!(fs::read_dir(dir.path().join("cache")).unwrap().count(), 0);
+        assert_eq!(
+            check_database(&dir.path().join("missing"), &DatabaseConfig::default()).status,
+            CheckStatus::Fail
+        );
+        assert_eq!(check_threads(usize::MAX).status, CheckStatus::Warn);
+
+        let genome = toy_genome(1000, 1);
+        assert_eq!(genome, toy_genome(1000, 1));
+        assert_ne!(genome, toy_genome(1000, 2));
+        assert!(genome.iter().all(|base| b"ACGT".contains(base)));
+
+        let report = DoctorReport {
+            checks: vec![
+                cache,
+                Check::new("self-test", CheckStatus::Fail, "toy reads not classified"),
+            ],
+        };
+        assert_eq!(report.failed(), 1);
+        let table = report.to_string();
+        assert!(table.contains("CHECK      STATUS  DETAIL\n"));
+        assert!(table.contains("self-test  FAIL    toy reads not classified\n"));
+        assert!(table.ends_with("1 of 2 checks failed"));
+    }
+}
@@ -0,0 +1,302 @@
+//! Report language and number formatting.
+//!
+//! Clinical readers outside English-speaking regions expect `1.234.567` and
+//! `98,5 %` rather than `1234567` and `98.5%`. A `ReportLocale` carries the
+//! language of report section headers and the decimal and thousands
+//! separators used for numbers. It is built from the `report` section of the
+//! configuration; the language `auto` is taken from `LC_ALL`, `LC_MESSAGES`
+//! or `LANG`.
+
+use std::env;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ReportConfig;
+
+/// Language of report section headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportLanguage {
+    #[default]
+    En,
+    De,
+    Fr,
+    Es,
+    /// Taken from the environment, falling back to English
+    Auto,
+}
+
+impl ReportLanguage {
+    /// The language itself, with `Auto` looked up in the environment
+    pub fn resolve(self) -> ReportLanguage {
+        if self != ReportLanguage::Auto {
+            return self;
+        }
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|name| env::var(name).ok())
+            .find(|value| !value.is_empty())
+            .map_or(ReportLanguage::En, |value| Self::from_posix_locale(&value))
+    }
+
+    /// Language of a POSIX locale name such as `de_DE.UTF-8`
+    pub fn from_posix_locale(locale: &str) -> ReportLanguage {
+        let language = locale
+            .split(|c| c == '_' || c == '.' || c == '@' || c == '-')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match language.as_str() {
+            "de" => ReportLanguage::De,
+            "fr" => ReportLanguage::Fr,
+            "es" => ReportLanguage::Es,
+            _ => ReportLanguage::En,
+        }
+    }
+
+    /// ISO 639-1 code, for the HTML `lang` attribute
+    pub fn code(self) -> &'static str {
+        match self.resolve() {
+            ReportLanguage::De => "de",
+            ReportLanguage::Fr => "fr",
+            ReportLanguage::Es => "es",
+            ReportLanguage::En | ReportLanguage::Auto => "en",
+        }
+    }
+}
+
+/// Translatable report text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportText {
+    ReportTitle,
+    ProcessingMetrics,
+    TotalReads,
+    PassedQc,
+    AverageReadLength,
+    ProcessingTime,
+    Seconds,
+    TaxonomicClassification,
+    StrainAbundances,
+    ClassificationDetails,
+    StrainDetails,
+    Rank,
+    Taxon,
+    Level,
+    Confidence,
+    Strain,
+    Abundance,
+    ConfidenceInterval,
+}
+
+/// Language and number conventions of a report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportLocale {
+    pub language: ReportLanguage,
+    pub decimal_separator: char,
+    /// `None` writes large numbers without grouping
+    pub thousands_separator: Option<char>,
+}
+
+impl Default for ReportLocale {
+    fn default() -> Self {
+        ReportLocale::for_language(ReportLanguage::En)
+    }
+}
+
+impl ReportLocale {
+    /// The usual conventions of a language
+    pub fn for_language(language: ReportLanguage) -> Self {
+        let language = language.resolve();
+        let (decimal_separator, thousands_separator) = match language {
+            ReportLanguage::En | ReportLanguage::Auto => ('.', Some(',')),
+            ReportLanguage::De | ReportLanguage::Es => (',', Some('.')),
+            // Narrow no-break space, as recommended for French
+            ReportLanguage::Fr => (',', Some('\u{202F}')),
+        };
+        ReportLocale {
+            language,
+            decimal_separator,
+            thousands_separator,
+        }
+    }
+
+    /// The locale described by the `report` configuration section; an empty
+    /// `thousands_separator` turns grouping off
+    pub fn from_config(config: &ReportConfig) -> Self {
+        let mut locale = ReportLocale::for_language(config.language);
+        if let Some(separator) = config.decimal_separator {
+            locale.decimal_separator = separator;
+        }
+        if let Some(separator) = &config.thousands_separator {
+            locale.thousands_separator = separator.chars().next();
+        }
+        locale
+    }
+
+    /// An integer with thousands separators
+    pub fn integer(&self, value: u64) -> String {
+        group_digits(&value.to_string(), self.thousands_separator)
+    }
+
+    /// A number with `decimals` digits after the decimal separator
+    pub fn decimal(&self, value: f64, decimals: usize) -> String {
+        let formatted = format!("{:.*}", decimals, value);
+        if !value.is_finite() {
+            return formatted;
+        }
+        let (sign, digits) = match formatted.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", formatted.as_str()),
+        };
+        let (integer, fraction) = match digits.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (digits, None),
+        };
+        let mut out = format!(
+            "{}{}",
+            sign,
+            group_digits(integer, self.thousands_separator)
+        );
+        if let Some(fraction) = fraction {
+            out.push(self.decimal_separator);
+            out.push_str(fraction);
+        }
+        out
+    }
+
+    /// A fraction (0.25) as a percentage (`25.0%`, or `25,0 %` in French and German)
+    pub fn percent(&self, fraction: f64, decimals: usize) -> String {
+        let space = match self.language {
+            ReportLanguage::De | ReportLanguage::Fr => "\u{00A0}",
+            _ => "",
+        };
+        format!("{}{}%", self.decimal(fraction * 100.0, decimals), space)
+    }
+
+    /// `text` in the report language
+    pub fn text(&self, text: ReportText) -> &'static str {
+        use ReportLanguage::*;
+        use ReportText::*;
+        match (self.language, text) {
+            (De, ReportTitle) => "AHSP-Analysebericht",
+            (Fr, ReportTitle) => "Rapport d'analyse AHSP",
+            (Es, ReportTitle) => "Informe de análisis AHSP",
+            (_, ReportTitle) => "AHSP Analysis Report",
+            (De, ProcessingMetrics) => "Verarbeitungskennzahlen",
+            (Fr, ProcessingMetrics) => "Métriques de traitement",
+            (Es, ProcessingMetrics) => "Métricas de procesamiento",
+            (_, ProcessingMetrics) => "Processing Metrics",
+            (De, TotalReads) => "Reads gesamt",
+            (Fr, TotalReads) => "Lectures totales",
+            (Es, TotalReads) => "Lecturas totales",
+            (_, TotalReads) => "Total reads",
+            (De, PassedQc) => "QC bestanden",
+            (Fr, PassedQc) => "CQ réussi",
+            (Es, PassedQc) => "CC superado",
+            (_, PassedQc) => "Passed QC",
+            (De, AverageReadLength) => "Mittlere Read-Länge",
+            (Fr, AverageReadLength) => "Longueur moyenne des lectures",
+            (Es, AverageReadLength) => "Longitud media de lectura",
+            (_, AverageReadLength) => "Average read length",
+            (De, ProcessingTime) => "Verarbeitungszeit",
+            (Fr, ProcessingTime) => "Temps de traitement",
+            (Es, ProcessingTime) => "Tiempo de procesamiento",
+            (_, ProcessingTime) => "Processing time",
+            (De, Seconds) => "Sekunden",
+            (Fr, Seconds) => "secondes",
+            (Es, Seconds) => "segundos",
+            (_, Seconds) => "seconds",
+            (De, TaxonomicClassification) => "Taxonomische Klassifikation",
+            (Fr, TaxonomicClassification) => "Classification taxonomique",
+            (Es, TaxonomicClassification) => "Clasificación taxonómica",
+            (_, TaxonomicClassification) => "Taxonomic Classification",
+            (De, StrainAbundances) => "Stammhäufigkeiten",
+            (Fr, StrainAbundances) => "Abondances des souches",
+            (Es, StrainAbundances) => "Abundancias de cepas",
+            (_, StrainAbundances) => "Strain Abundances",
+            (De, ClassificationDetails) => "Details der Klassifikation",
+            (Fr, ClassificationDetails) => "Détails de la classification",
+            (Es, ClassificationDetails) => "Detalles de la clasificación",
+            (_, ClassificationDetails) => "Classification Details",
+            (De, StrainDetails) => "Details der Stämme",
+            (Fr, StrainDetails) => "Détails des souches",
+            (Es, StrainDetails) => "Detalles de las cepas",
+            (_, StrainDetails) => "Strain Details",
+            (De | Fr, Rank) => "Rang",
+            (Es, Rank) => "Posición",
+            (_, Rank) => "Rank",
+            (_, Taxon) => "Taxon",
+            (De, Level) => "Ebene",
+            (Fr, Level) => "Niveau",
+            (Es, Level) => "Nivel",
+            (_, Level) => "Level",
+            (De, Confidence) => "Konfidenz",
+            (Fr, Confidence) => "Confiance",
+            (Es, Confidence) => "Confianza",
+            (_, Confidence) => "Confidence",
+            (De, Strain) => "Stamm",
+            (Fr, Strain) => "Souche",
+            (Es, Strain) => "Cepa",
+            (_, Strain) => "Strain",
+            (De, Abundance) => "Häufigkeit",
+            (Fr, Abundance) => "Abondance",
+            (Es, Abundance) => "Abundancia",
+            (_, Abundance) => "Abundance",
+            (De, ConfidenceInterval) => "Konfidenzintervall",
+            (Fr, ConfidenceInterval) => "Intervalle de confiance",
+            (Es, ConfidenceInterval) => "Intervalo de confianza",
+            (_, ConfidenceInterval) => "Confidence Interval",
+        }
+    }
+}
+
+/// Insert `separator` between groups of three digits of `digits`
+fn group_digits(digits: &str, separator: Option<char>) -> String {
+    let Some(separator) = separator else {
+        return digits.to_string();
+    };
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out.push(separator);
+        }
+        out.push(digit);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_formatting_follows_locale() {
+        let en = ReportLocale::default();
+        assert_eq!(en.integer(1234567), "1,234,567");
+        assert_eq!(en.decimal(-1234.5, 2), "-1,234.50");
+        assert_eq!(en.percent(0.985, 1), "98.5%");
+        assert_eq!(en.integer(999), "999");
+
+        let de = ReportLocale::for_language(ReportLanguage::De);
+        assert_eq!(de.integer(1234567), "1.234.567");
+        assert_eq!(de.decimal(1234.5, 1), "1.234,5");
+        assert_eq!(de.percent(0.985, 1), "98,5\u{00A0}%");
+        assert_eq!(de.text(ReportText::StrainAbundances), "Stammhäufigkeiten");
+
+        // Config overrides the language's conventions; "" turns grouping off
+        let config = ReportConfig {
+            language: ReportLanguage::Fr,
+            decimal_separator: Some('.'),
+            thousands_separator: Some(String::new()),
+        };
+        let custom = ReportLocale::from_config(&config);
+        assert_eq!(custom.decimal(12345.25, 2), "12345.25");
+        assert_eq!(custom.text(ReportText::Confidence), "Confiance");
+
+        assert_eq!(
+            ReportLanguage::from_posix_locale("es_ES.UTF-8"),
+            ReportLanguage::Es
+        );
+        assert_eq!(ReportLanguage::from_posix_locale("C"), ReportLanguage::En);
+    }
+}
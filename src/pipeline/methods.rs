@@ -0,0 +1,222 @@
+//! Methods paragraph for reports.
+//!
+//! Writing up an analysis means reconstructing every setting that shaped its
+//! results, often months later and from shell history. Each run stores the
+//! parameters it used with its results, and the report turns them into a
+//! paragraph that can be pasted into a manuscript. References are left as
+//! bracketed placeholders, to be filled in with the journal's citation style,
+//! and so is anything the results cannot know (such as the name and release
+//! of the reference collection).
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::qc::{ClassificationResults, QualityControlParams};
+use crate::pipeline::rna::SequencingMode;
+use crate::pipeline::trimming::TrimmingStrategy;
+use crate::sketch::signature::LevelParameters;
+use crate::sketch::SignatureLayout;
+use crate::stats::estimators::EstimatorKind;
+
+/// Settings of the run that produced a sample's results
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnalysisParameters {
+    pub qc: QualityControlParams,
+    /// Sketch parameters the sample was sketched and compared with
+    pub layout: SignatureLayout,
+    /// Method that estimated the strain abundances
+    pub estimator: EstimatorKind,
+}
+
+/// Multiple-testing correction applied to differential abundance p-values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdrMethod {
+    /// Benjamini-Hochberg across all features
+    BenjaminiHochberg,
+    /// Top-down across taxonomic ranks (TreeBH)
+    TreeBh,
+}
+
+impl fmt::Display for FdrMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FdrMethod::BenjaminiHochberg => write!(
+                f,
+                "the Benjamini-Hochberg procedure [CITATION: Benjamini & Hochberg 1995]"
+            ),
+            FdrMethod::TreeBh => write!(
+                f,
+                "hierarchical false discovery rate control across taxonomic ranks (TreeBH) \
+                 [CITATION: Bogomolov et al. 2021]"
+            ),
+        }
+    }
+}
+
+/// How differences between samples were tested
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatisticalMethods {
+    /// The model, as it should read after "were tested with", including its citation
+    pub model: String,
+    pub fdr: FdrMethod,
+}
+
+impl StatisticalMethods {
+    /// One sentence describing the model and the multiple-testing correction
+    pub fn sentence(&self) -> String {
+        format!(
+            "Differential abundance was tested with {}, and p-values were adjusted for \
+             multiple testing with {}.",
+            self.model, self.fdr
+        )
+    }
+}
+
+/// Placeholder sentence for the statistics of a study, which a sample's
+/// results do not record
+const STATISTICS_PLACEHOLDER: &str = "[STATISTICS: describe the differential abundance model \
+     and multiple-testing correction, if any, e.g. from the methods text of the meta command.]";
+
+/// Methods paragraph for a sample, `None` for results written before their
+/// parameters were recorded
+pub fn methods_paragraph(results: &ClassificationResults) -> Option<String> {
+    let parameters = results.parameters.as_ref()?;
+    let provenance = results.provenance.as_ref();
+    let version = provenance.map_or("[VERSION]".to_string(), |p| format!("v{}", p.tool_version));
+    let database = provenance
+        .and_then(|p| p.database_sha256.as_deref())
+        .map_or("[DATABASE DIGEST]".to_string(), |digest| {
+            format!("content SHA-256 {}", digest)
+        });
+
+    let mut sentences = qc_sentences(&parameters.qc);
+    sentences.push(format!(
+        "Reads passing QC were sketched with strain_ahsp {} [CITATION: strain_ahsp] as {} \
+         and classified against [REFERENCE DATABASE AND RELEASE] ({}) \
+         [CITATION: reference database].",
+        version,
+        describe_layout(&parameters.layout),
+        database
+    ));
+    let shares = match results.sequencing_mode {
+        SequencingMode::Dna => "genomes",
+        SequencingMode::Rna => "transcripts",
+    };
+    sentences.push(format!(
+        "Strain abundances within the classified group, as shares of {}, were estimated {}.",
+        shares,
+        describe_estimator(parameters.estimator)
+    ));
+    sentences.push(STATISTICS_PLACEHOLDER.to_string());
+    Some(sentences.join(" "))
+}
+
+fn qc_sentences(qc: &QualityControlParams) -> Vec<String> {
+    let trimming = match qc.trimming {
+        TrimmingStrategy::Threshold => {
+            "to the span from the first to the last base at or above that quality".to_string()
+        }
+        TrimmingStrategy::SlidingWindow => {
+            format!("with a {}-base sliding window", qc.trim_window)
+        }
+        TrimmingStrategy::Mott => "with the modified Mott algorithm".to_string(),
+    };
+    let mut sentences = vec![format!(
+        "Reads were quality trimmed at Q{} {}.",
+        qc.trim_quality, trimming
+    )];
+    let tails: Vec<String> = [("G", qc.poly_g_min_length), ("A", qc.poly_a_min_length)]
+        .into_iter()
+        .filter_map(|(base, length)| {
+            length.map(|length| format!("3' poly-{} runs of at least {} bases", base, length))
+        })
+        .collect();
+    if !tails.is_empty() {
+        sentences.push(format!("{} were trimmed.", tails.join(" and ")));
+    }
+    sentences.push(format!(
+        "Reads shorter than {} bp, with a mean base quality below Q{} or with more than {}% \
+         ambiguous bases were discarded.",
+        qc.min_length, qc.min_avg_quality, qc.max_n_percent
+    ));
+    sentences
+}
+
+/// "DNA k-mer sketches (k = 31, MinHash of 1000 hashes; k = 21, ...)"
+fn describe_layout(layout: &SignatureLayout) -> String {
+    let molecule = layout
+        .levels
+        .first()
+        .map_or("DNA", |level| level.molecule_type.as_str());
+    let levels: Vec<String> = layout.levels.iter().map(describe_level).collect();
+    format!("{} k-mer sketches ({})", molecule, levels.join("; "))
+}
+
+fn describe_level(level: &LevelParameters) -> String {
+    if level.scaled > 0 {
+        format!(
+            "k = {}, FracMinHash scaled {}",
+            level.kmer_size, level.scaled
+        )
+    } else {
+        format!(
+            "k = {}, MinHash of {} hashes",
+            level.kmer_size, level.num_hashes
+        )
+    }
+}
+
+fn describe_estimator(estimator: EstimatorKind) -> &'static str {
+    match estimator {
+        EstimatorKind::Similarity => {
+            "in proportion to the Jaccard similarity of each strain to the sample"
+        }
+        EstimatorKind::Em => {
+            "as maximum-likelihood mixture proportions fitted by expectation maximization \
+             [CITATION: Dempster et al. 1977]"
+        }
+        EstimatorKind::Nnls => "by non-negative least squares [CITATION: Lawson & Hanson 1974]",
+        EstimatorKind::Mcmc => {
+            "as the posterior mean of a strain mixture model sampled by Metropolis-Hastings \
+             [CITATION: Hastings 1970]"
+        }
+        EstimatorKind::Vi => {
+            "as the posterior mean of a strain mixture model approximated by variational \
+             inference [CITATION: Blei et al. 2017]"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::golden::toy_results;
+
+    #[test]
+    fn test_methods_paragraph_from_recorded_parameters() {
+        let mut results = toy_results();
+        // Results written before parameters were recorded have no paragraph
+        assert_eq!(methods_paragraph(&results[1]), None);
+
+        let sample = &mut results[0];
+        let parameters = sample.parameters.as_mut().unwrap();
+        parameters.layout.levels[1].scaled = 500;
+        parameters.qc.poly_g_min_length = None;
+        sample.sequencing_mode = SequencingMode::Rna;
+        let paragraph = methods_paragraph(sample).unwrap();
+        assert!(
+            paragraph.contains("(k = 31, MinHash of 1000 hashes; k = 21, FracMinHash scaled 500)")
+        );
+        assert!(paragraph.contains("as shares of transcripts"));
+        assert!(!paragraph.contains("poly-G"));
+
+        let statistics = StatisticalMethods {
+            model: "a negative binomial GLM".to_string(),
+            fdr: FdrMethod::BenjaminiHochberg,
+        };
+        assert!(statistics
+            .sentence()
+            .starts_with("Differential abundance was tested with a negative binomial GLM, and"));
+    }
+}
@@ -1,6 +1,26 @@
+pub mod anonymize;
+pub mod augment;
+pub mod batches;
+pub mod budget;
+pub mod confirm;
+pub mod contamination;
+pub mod doctor;
+pub mod locale;
+pub mod methods;
+pub mod monitor;
+pub mod pairs;
 pub mod processor;
 pub mod qc;
+pub mod reclassify;
+pub mod redact;
 pub mod report;
+pub mod rna;
+pub mod strict;
+pub mod summary;
+pub mod telemetry;
+pub mod trimming;
+pub mod warnings;
+pub mod watchlist;
 
 pub use crate::pipeline::qc::FastqProcessor;
 // pub use processor::{ClassificationResults, ProcessingMetrics};
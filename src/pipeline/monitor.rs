@@ -0,0 +1,283 @@
+//! Composition drift between two runs of the same source.
+//!
+//! Bioreactor timepoints, repeat clinical samples and routine monitoring of a
+//! facility are sequenced again and again, and the question is what changed
+//! since the last run. Each results file is reduced to a composition (strain
+//! shares, with the share no strain explains kept on the species, or the top
+//! call alone when there are no strain estimates), the two compositions are
+//! compared with the Bray-Curtis dissimilarity, and taxa whose share moved by
+//! more than an absolute and a fold-change threshold are flagged.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::qc::ClassificationResults;
+use crate::stats::outliers::bray_curtis;
+
+/// Smallest change in a taxon's share that is flagged
+pub const DEFAULT_MIN_SHARE_CHANGE: f64 = 0.05;
+
+/// Smallest fold change in a taxon's share that is flagged
+pub const DEFAULT_MIN_FOLD_CHANGE: f64 = 2.0;
+
+/// Bray-Curtis dissimilarity above which the whole sample counts as drifted
+pub const DEFAULT_MAX_DISTANCE: f64 = 0.2;
+
+/// When a change counts as drift
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DriftThresholds {
+    pub min_share_change: f64,
+    pub min_fold_change: f64,
+    pub max_distance: f64,
+}
+
+impl Default for DriftThresholds {
+    fn default() -> Self {
+        DriftThresholds {
+            min_share_change: DEFAULT_MIN_SHARE_CHANGE,
+            min_fold_change: DEFAULT_MIN_FOLD_CHANGE,
+            max_distance: DEFAULT_MAX_DISTANCE,
+        }
+    }
+}
+
+/// Direction of a flagged change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    /// Absent from the baseline
+    Appeared,
+    /// Absent from the current run
+    Disappeared,
+    Increased,
+    Decreased,
+}
+
+/// A taxon whose share changed beyond the thresholds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaxonChange {
+    pub taxon_id: String,
+    pub kind: ChangeKind,
+    pub baseline_share: f64,
+    pub current_share: f64,
+    /// `log2(current / baseline)`, `None` if either share is zero
+    pub log2_fold_change: Option<f64>,
+}
+
+/// Comparison of a run with its baseline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DriftReport {
+    pub baseline_sample: String,
+    pub current_sample: String,
+    pub thresholds: DriftThresholds,
+    /// Bray-Curtis dissimilarity of the two compositions
+    pub distance: f64,
+    /// Whether the distance exceeds `thresholds.max_distance`
+    pub drifted: bool,
+    /// Flagged taxa, largest change first
+    pub changes: Vec<TaxonChange>,
+    /// The runs were classified against different database contents, so part
+    /// of the change may come from the references rather than the sample
+    pub database_changed: bool,
+}
+
+impl DriftReport {
+    /// Compare `current` with `baseline`
+    pub fn compare(
+        baseline: &ClassificationResults,
+        current: &ClassificationResults,
+        thresholds: DriftThresholds,
+    ) -> Self {
+        let before = composition(baseline);
+        let after = composition(current);
+        let taxa: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+        let share = |composition: &BTreeMap<String, f64>, taxon: &str| {
+            composition.get(taxon).copied().unwrap_or(0.0)
+        };
+        let baseline_shares: Vec<f64> = taxa.iter().map(|taxon| share(&before, taxon)).collect();
+        let current_shares: Vec<f64> = taxa.iter().map(|taxon| share(&after, taxon)).collect();
+        let distance = if taxa.is_empty() {
+            0.0
+        } else {
+            bray_curtis(&baseline_shares, &current_shares)
+        };
+
+        let mut changes: Vec<TaxonChange> = taxa
+            .iter()
+            .zip(baseline_shares.iter().zip(&current_shares))
+            .filter_map(|(taxon, (&baseline_share, &current_share))| {
+                if (current_share - baseline_share).abs() < thresholds.min_share_change {
+                    return None;
+                }
+                let (kind, log2_fold_change) = match (baseline_share > 0.0, current_share > 0.0) {
+                    (false, _) => (ChangeKind::Appeared, None),
+                    (_, false) => (ChangeKind::Disappeared, None),
+                    _ => {
+                        let ratio = current_share / baseline_share;
+                        if ratio.max(1.0 / ratio) < thresholds.min_fold_change {
+                            return None;
+                        }
+                        let kind = if ratio > 1.0 {
+                            ChangeKind::Increased
+                        } else {
+                            ChangeKind::Decreased
+                        };
+                        (kind, Some(ratio.log2()))
+                    }
+                };
+                Some(TaxonChange {
+                    taxon_id: taxon.to_string(),
+                    kind,
+                    baseline_share,
+                    current_share,
+                    log2_fold_change,
+                })
+            })
+            .collect();
+        changes.sort_by(|a, b| {
+            let change = |c: &TaxonChange| (c.current_share - c.baseline_share).abs();
+            change(b)
+                .total_cmp(&change(a))
+                .then_with(|| a.taxon_id.cmp(&b.taxon_id))
+        });
+
+        let database = |results: &ClassificationResults| {
+            results
+                .provenance
+                .as_ref()
+                .and_then(|provenance| provenance.database_sha256.clone())
+        };
+        DriftReport {
+            baseline_sample: baseline.sample_id.clone(),
+            current_sample: current.sample_id.clone(),
+            thresholds,
+            distance,
+            drifted: distance > thresholds.max_distance,
+            changes,
+            database_changed: database(baseline) != database(current),
+        }
+    }
+}
+
+impl fmt::Display for DriftReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Composition drift {} -> {}: Bray-Curtis {:.3} ({})",
+            self.baseline_sample,
+            self.current_sample,
+            self.distance,
+            if self.drifted {
+                "DRIFTED"
+            } else {
+                "within threshold"
+            }
+        )?;
+        if self.database_changed {
+            writeln!(
+                f,
+                "WARNING: the runs used different reference databases; changes may reflect \
+                 the references rather than the sample"
+            )?;
+        }
+        if self.changes.is_empty() {
+            return writeln!(f, "No taxon changed beyond the thresholds");
+        }
+        writeln!(f, "Taxa changed beyond the thresholds:")?;
+        for change in &self.changes {
+            write!(
+                f,
+                "  {} {:?}: {:.2}% -> {:.2}%",
+                change.taxon_id,
+                change.kind,
+                change.baseline_share * 100.0,
+                change.current_share * 100.0
+            )?;
+            if let Some(log2_fold_change) = change.log2_fold_change {
+                write!(f, " (log2 fold change {:+.2})", log2_fold_change)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Share of each taxon in a sample: strain abundances plus the unresolved
+/// share of the species, or the top classification alone, normalized to sum to one
+pub fn composition(results: &ClassificationResults) -> BTreeMap<String, f64> {
+    let mut shares = BTreeMap::new();
+    let top = results.classifications.first();
+    if results.strain_abundances.is_empty() {
+        if let Some(top) = top {
+            shares.insert(top.taxon_id.clone(), 1.0);
+        }
+        return shares;
+    }
+    for (strain_id, (abundance, _)) in &results.strain_abundances {
+        shares.insert(strain_id.clone(), *abundance);
+    }
+    if let (Some(reconciliation), Some(top)) = (&results.reconciliation, top) {
+        if reconciliation.unresolved_fraction > 0.0 {
+            *shares.entry(top.taxon_id.clone()).or_default() += reconciliation.unresolved_fraction;
+        }
+    }
+    let total: f64 = shares.values().sum();
+    if total > 0.0 {
+        shares.values_mut().for_each(|share| *share /= total);
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::golden::toy_results;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_drift_between_runs() {
+        let baseline = toy_results().remove(0);
+        assert!(
+            DriftReport::compare(&baseline, &baseline, DriftThresholds::default())
+                .changes
+                .is_empty()
+        );
+
+        let mut current = baseline.clone();
+        current.sample_id = "S1_week2".to_string();
+        current.reconciliation = None;
+        current.strain_abundances = HashMap::from([
+            ("GCF_000005845".to_string(), (0.2, 0.1)),
+            ("strain_new".to_string(), (0.8, 0.1)),
+        ]);
+        let report = DriftReport::compare(&baseline, &current, DriftThresholds::default());
+
+        assert!(report.drifted);
+        assert!((report.distance - 0.8).abs() < 1e-12);
+        let kinds: Vec<(&str, ChangeKind)> = report
+            .changes
+            .iter()
+            .map(|change| (change.taxon_id.as_str(), change.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("strain_new", ChangeKind::Appeared),
+                ("GCF_000005845", ChangeKind::Decreased),
+                ("GCF_000008865", ChangeKind::Disappeared),
+            ]
+        );
+        assert!(report.to_string().contains("DRIFTED"));
+
+        // A small shift stays below the thresholds
+        current.strain_abundances = HashMap::from([
+            ("GCF_000005845".to_string(), (0.72, 0.05)),
+            ("GCF_000008865".to_string(), (0.28, 0.05)),
+        ]);
+        let report = DriftReport::compare(&baseline, &current, DriftThresholds::default());
+        assert!(!report.drifted);
+        assert!(report.changes.is_empty());
+    }
+}
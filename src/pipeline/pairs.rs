@@ -0,0 +1,238 @@
+//! Read pair concordance.
+//!
+//! Both mates of a pair come from the same fragment, so in a clean library
+//! they should point at the same taxon. Pairs whose mates point at different
+//! taxa suggest chimeric fragments, index hopping or references close enough
+//! for reads to be misassigned, all of which also blur the sample-level call.
+//!
+//! Paired reads are recognised in interleaved input (mates as consecutive
+//! records with the same name, optionally ending in `/1` and `/2`). Each mate
+//! is assigned to the reference sharing the most sketch hashes with it; with
+//! fixed-size sketches only a minority of reads carries any such hash, so the
+//! concordance is reported together with the number of pairs it rests on.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sketch::MultiResolutionSignature;
+
+/// Concordance below which the report flags the library
+pub const DEFAULT_MIN_PAIR_CONCORDANCE: f64 = 0.9;
+
+/// Number of leading records inspected to decide whether input is interleaved
+pub const INTERLEAVED_SAMPLE_RECORDS: usize = 100;
+
+/// How often the mates of a pair point at the same taxon
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PairConcordance {
+    /// Pairs in which both mates passed QC
+    pub pairs: usize,
+    /// Pairs in which only one mate could be assigned
+    pub one_assigned: usize,
+    /// Pairs in which both mates could be assigned
+    pub both_assigned: usize,
+    /// Pairs whose mates were assigned to the same taxon
+    pub concordant: usize,
+}
+
+impl PairConcordance {
+    /// Share of pairs with both mates assigned whose mates agree
+    pub fn concordance(&self) -> Option<f64> {
+        (self.both_assigned > 0).then(|| self.concordant as f64 / self.both_assigned as f64)
+    }
+
+    /// Record a pair given the taxa its mates were assigned to
+    pub fn add_pair(&mut self, first: Option<&str>, second: Option<&str>) {
+        self.pairs += 1;
+        match (first, second) {
+            (Some(a), Some(b)) => {
+                self.both_assigned += 1;
+                self.concordant += (a == b) as usize;
+            }
+            (None, None) => {}
+            _ => self.one_assigned += 1,
+        }
+    }
+}
+
+/// What became of one read offered for pairing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mate<'a> {
+    /// Dropped by QC, the rRNA filter or control removal
+    Dropped,
+    /// Kept for sketching, with the taxon it was assigned to, if any
+    Kept(Option<&'a str>),
+}
+
+/// Pairs up consecutive reads of interleaved input as they are processed.
+///
+/// Reads are added in file order, after QC and filtering, so only pairs in
+/// which both mates were kept count. A read whose name differs from the one
+/// before it starts the next pair.
+#[derive(Debug, Clone)]
+pub struct MatePairs<'a> {
+    assigner: ReadAssigner<'a>,
+    /// Name of the read waiting for its mate
+    name: Vec<u8>,
+    pending: Option<Mate<'a>>,
+    concordance: PairConcordance,
+}
+
+impl<'a> MatePairs<'a> {
+    pub fn new(assigner: ReadAssigner<'a>) -> Self {
+        MatePairs {
+            assigner,
+            name: Vec::new(),
+            pending: None,
+            concordance: PairConcordance::default(),
+        }
+    }
+
+    /// Assigns the kept reads to references
+    pub fn assigner(&self) -> &ReadAssigner<'a> {
+        &self.assigner
+    }
+
+    /// Add the next read, given its record ID
+    pub fn add(&mut self, id: &[u8], mate: Mate<'a>) {
+        let name = mate_name(id);
+        match self.pending.take() {
+            Some(first) if self.name == name => {
+                if let (Mate::Kept(first), Mate::Kept(second)) = (first, mate) {
+                    self.concordance.add_pair(first, second);
+                }
+            }
+            _ => {
+                self.name.clear();
+                self.name.extend_from_slice(name);
+                self.pending = Some(mate);
+            }
+        }
+    }
+
+    /// Concordance of the pairs added
+    pub fn finish(self) -> PairConcordance {
+        self.concordance
+    }
+}
+
+/// Read name without its comment and `/1` or `/2` mate suffix
+pub fn mate_name(id: &[u8]) -> &[u8] {
+    let name = id
+        .split(|b| b.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default();
+    match name {
+        [stem @ .., b'/', b'1' | b'2'] => stem,
+        _ => name,
+    }
+}
+
+/// Whether consecutive records in `ids` pair up by name
+pub fn is_interleaved<T: AsRef<[u8]>>(ids: &[T]) -> bool {
+    ids.len() >= 2
+        && ids
+            .chunks_exact(2)
+            .all(|pair| mate_name(pair[0].as_ref()) == mate_name(pair[1].as_ref()))
+}
+
+/// Assigns reads to references by the sketch hashes they share
+#[derive(Debug, Clone)]
+pub struct ReadAssigner<'a> {
+    references: &'a [MultiResolutionSignature],
+    level: usize,
+    /// References (by index) whose sketch at `level` holds each hash
+    index: HashMap<u64, Vec<usize>>,
+}
+
+impl<'a> ReadAssigner<'a> {
+    /// Index the sketches of `references` at resolution `level`
+    pub fn new(references: &'a [MultiResolutionSignature], level: usize) -> Self {
+        let mut index: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, reference) in references.iter().enumerate() {
+            if let Some(signature) = reference.levels.get(level) {
+                for &hash in &signature.sketch.hashes {
+                    index.entry(hash).or_default().push(i);
+                }
+            }
+        }
+        ReadAssigner {
+            references,
+            level,
+            index,
+        }
+    }
+
+    /// Taxon of the reference sharing the most hashes with `read`; `None` if
+    /// no reference shares any or references of different taxa tie
+    pub fn assign(&self, read: &[u8]) -> Option<&'a str> {
+        let level = self.references.first()?.levels.get(self.level)?;
+        let mut hits: HashMap<usize, usize> = HashMap::new();
+        for hash in level.kmer_hashes(read) {
+            for &i in self.index.get(&hash).into_iter().flatten() {
+                *hits.entry(i).or_default() += 1;
+            }
+        }
+        let best = hits.values().copied().max()?;
+        // Tied references may still be one taxon
+        let mut leaders = hits
+            .iter()
+            .filter(|(_, &count)| count == best)
+            .map(|(&i, _)| self.references[i].taxon_id.as_str());
+        let taxon = leaders.next()?;
+        leaders.all(|other| other == taxon).then_some(taxon)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::SignatureLayout;
+
+    #[test]
+    fn test_mates_assigned_and_counted() {
+        assert_eq!(mate_name(b"read7/1 extra"), b"read7");
+        assert_eq!(mate_name(b"read7 2:N:0:ACGT"), b"read7");
+        assert!(is_interleaved(&["r1/1", "r1/2", "r2/1", "r2/2"]));
+        assert!(!is_interleaved(&["r1", "r2", "r3", "r4"]));
+
+        let genome_a = b"ACGTTGCAAGGCTTAACCGGTATGCATCGATCGGATCCTAGGCTAGCTTAAGCGCGATATCGGCCAATTG";
+        let genome_b = b"TTTTGGGGCCCCAAAATTGGCCAAGGTTCCAATGCATGCAAATTTGGGCCCTTTAAAGGGCCCATATATG";
+        let layout = SignatureLayout::minhash(&[11], 1000);
+        let reference = |id: &str, genome: &[u8]| {
+            let mut signature =
+                MultiResolutionSignature::with_layout(id.to_string(), Vec::new(), &layout);
+            signature.levels[0].add_sequence(genome).unwrap();
+            signature
+        };
+        let references = vec![reference("A", genome_a), reference("B", genome_b)];
+        let assigner = ReadAssigner::new(&references, 0);
+        assert_eq!(assigner.assign(&genome_a[5..45]), Some("A"));
+        assert_eq!(assigner.assign(&genome_b[20..60]), Some("B"));
+        assert_eq!(assigner.assign(b"ACGT"), None);
+
+        let mut concordance = PairConcordance::default();
+        concordance.add_pair(Some("A"), Some("A"));
+        concordance.add_pair(Some("A"), Some("B"));
+        concordance.add_pair(Some("A"), None);
+        concordance.add_pair(None, None);
+        assert_eq!(concordance.pairs, 4);
+        assert_eq!(concordance.one_assigned, 1);
+        assert_eq!(concordance.concordance(), Some(0.5));
+
+        let mut pairs = MatePairs::new(assigner);
+        pairs.add(b"r1/1", Mate::Kept(Some("A")));
+        pairs.add(b"r1/2", Mate::Kept(Some("A")));
+        // A dropped mate leaves its pair out
+        pairs.add(b"r2/1", Mate::Kept(Some("A")));
+        pairs.add(b"r2/2", Mate::Dropped);
+        // A record without its mate starts the next pair
+        pairs.add(b"r3/1", Mate::Kept(Some("B")));
+        pairs.add(b"r4/1", Mate::Kept(Some("A")));
+        pairs.add(b"r4/2", Mate::Kept(Some("B")));
+        let concordance = pairs.finish();
+        assert_eq!(concordance.pairs, 2);
+        assert_eq!(concordance.concordant, 1);
+    }
+}
@@ -40,147 +40,6 @@
 //     }
 // }
 
-// // --- Sketch Trait ---
-// pub trait Sketch {
-//     fn add_sequence(&mut self, seq: &[u8]) -> Result<(), String>;
-//     fn len(&self) -> usize;
-//     fn is_empty(&self) -> bool;
-//     fn sketch_size(&self) -> usize;
-//     fn best_level_similarity(&self, other: &Self) -> Result<f64, String>;
-// }
-
-// impl Sketch for MultiResolutionSignature {
-//     fn add_sequence(&mut self, seq: &[u8]) -> Result<(), String> {
-//         // Add sequence to all resolution levels
-//         for level in &mut self.levels {
-//             level.add_sequence(seq)?;
-//         }
-//         Ok(())
-//     }
-
-//     fn len(&self) -> usize {
-//         // Use first level (macro) for length
-//         self.levels.first().map_or(0, |s| s.sketch.hashes.len())
-//     }
-
-//     fn is_empty(&self) -> bool {
-//         self.levels.iter().all(|s| s.sketch.hashes.is_empty())
-//     }
-
-//     fn sketch_size(&self) -> usize {
-//         // Use first level for sketch size
-//         self.levels.first().map_or(0, |s| s.sketch.num_hashes)
-//     }
-
-//     fn best_level_similarity(&self, other: &Self) -> Result<f64, String> {
-//         // Calculate similarity at each level and return the maximum
-//         let mut max_sim = 0.0;
-//         for (self_level, other_level) in self.levels.iter().zip(other.levels.iter()) {
-//             if let Some(sim) = self_level.jaccard_similarity(other_level) {
-//                 max_sim = f64::max(max_sim, sim);
-//             }
-//         }
-//         if max_sim.is_nan() {
-//             return Err("Similarity calculation resulted in NaN".to_string());
-//         }
-//         Ok(max_sim)
-//     }
-// }
-
-// impl Sketch for Signature {
-//     fn add_sequence(&mut self, seq: &[u8]) -> Result<(), String> {
-//         let k = self.k();
-//         if seq.len() < k {
-//             return Err("Sequence length is smaller than k-mer size".to_string());
-//         }
-
-//         let mut hasher = DefaultHasher::new();
-//         for i in 0..=seq.len() - k {
-//             let kmer = &seq[i..i + k];
-//             ByteSlice(kmer).hash(&mut hasher);
-//             let hash = hasher.finish();
-
-//             if self.hashes.len() < self.num_hashes {
-//                 self.hashes.push(hash);
-//                 if self.hashes.len() == self.num_hashes {
-//                     self.hashes.sort_unstable();
-//                 }
-//             } else if hash < self.hashes[self.num_hashes - 1] {
-//                 self.hashes[self.num_hashes - 1] = hash;
-//                 self.hashes.sort_unstable();
-//             }
-//         }
-//         Ok(())
-//     }
-
-//     fn len(&self) -> usize {
-//         self.hashes.len()
-//     }
-
-//     fn is_empty(&self) -> bool {
-//         self.hashes.is_empty()
-//     }
-
-//     fn sketch_size(&self) -> usize {
-//         self.num_hashes
-//     }
-
-//     fn best_level_similarity(&self, other: &Self) -> Result<f64, String> {
-//         if self.is_empty() || other.is_empty() {
-//             return Ok(0.0);
-//         }
-
-//         let intersection: Vec<_> = self
-//             .hashes
-//             .iter()
-//             .filter(|h| other.hashes.contains(h))
-//             .collect();
-
-//         Ok(intersection.len() as f64 / self.hashes.len().max(other.hashes.len()) as f64)
-//     }
-// }
-
-// // --- Implementing required traits ---
-// impl Sketch for KmerSignature {
-//     fn add_sequence(&mut self, seq: &[u8]) -> Result<(), String> {
-//         // Process k-mers from sequence and add to kmers
-//         let mut hasher = DefaultHasher::new();
-//         for i in 0..=seq.len().saturating_sub(self.kmer_size) {
-//             let kmer = &seq[i..i + self.kmer_size];
-//             ByteSlice(kmer).hash(&mut hasher);
-//             let hash = hasher.finish();
-//             self.kmers.push(hash);
-//         }
-//         Ok(())
-//     }
-
-//     fn len(&self) -> usize {
-//         self.kmers.len()
-//     }
-
-//     fn is_empty(&self) -> bool {
-//         self.kmers.is_empty()
-//     }
-
-//     fn sketch_size(&self) -> usize {
-//         self.num_hashes
-//     }
-
-//     fn best_level_similarity(&self, other: &Self) -> Result<f64, String> {
-//         let intersection: Vec<_> = self
-//             .kmers
-//             .iter()
-//             .filter(|h| other.kmers.contains(h))
-//             .collect();
-
-//         if self.kmers.is_empty() || other.kmers.is_empty() {
-//             return Ok(0.0);
-//         }
-
-//         Ok(intersection.len() as f64 / self.kmers.len().max(other.kmers.len()) as f64)
-//     }
-// }
-
 // impl From<KmerSignature> for Signature {
 //     fn from(kmer_sig: KmerSignature) -> Self {
 //         Self {
@@ -1,17 +1,57 @@
-use crate::adaptive::classifier::{AdaptiveClassifier, Classification, TaxonomicLevel};
-use crate::database::DatabaseManager;
-// Fix: Ensure correct signature types are imported and used consistently
-// Assuming KmerSignature is the intended type for macro/meso signatures
-use crate::sketch::signature::{KmerSignature, Signature}; // Removed ResolutionLevel
-use crate::sketch::MultiResolutionSignature;
+use crate::adaptive::classifier::{
+    AdaptiveClassifier, Classification, ClassificationError, ClassifierBuilder, TaxonomicLevel,
+};
+use crate::adaptive::explain::{explain_classification, write_classification_debug};
+use crate::adaptive::scores::{score_matrix, write_score_matrix};
+use crate::config::{DatabaseConfig, ReportConfig};
+use crate::database::{DatabaseManager, MagHit, MagMetadata};
+use crate::io::fastq::{RecordWriter, SequenceFormat, SequenceRecord};
+use crate::io::format::{Artifact, UnsupportedVersion};
+use crate::io::json::{write_json, JsonCompression};
+use crate::pipeline::augment::{unassigned_fraction, ReferenceAdvisor, ReferenceSuggestion};
+use crate::pipeline::batches::{
+    batch_size_for, sample_mean_read_length, BatchReader, ReadBatch, DEFAULT_BATCH_MEMORY_BYTES,
+    DEFAULT_READ_AHEAD_BATCHES, MIN_BATCH_READS,
+};
+use crate::pipeline::budget::{ConvergenceTracker, EarlyStopPolicy, EarlyStopSummary};
+use crate::pipeline::confirm::{AlignerConfig, AlignmentConfirmation, ReadAligner};
+use crate::pipeline::contamination::{
+    ContaminationEstimate, ControlPanel, DEFAULT_MAX_CONTAMINATION_FRACTION,
+};
+use crate::pipeline::methods::{methods_paragraph, AnalysisParameters};
+use crate::pipeline::pairs::{
+    is_interleaved, Mate, MatePairs, PairConcordance, ReadAssigner, DEFAULT_MIN_PAIR_CONCORDANCE,
+    INTERLEAVED_SAMPLE_RECORDS,
+};
+use crate::pipeline::reclassify::SampleSketch;
+use crate::pipeline::redact::Redactor;
+use crate::pipeline::rna::{RrnaFilter, SequencingMode, Strandedness};
+use crate::pipeline::strict::{enforce, StrictConfig};
+use crate::pipeline::telemetry::{StageSpan, Telemetry};
+use crate::pipeline::trimming::{
+    poly_tail_length, trim_range, TrimmingStrategy, DEFAULT_POLY_G_MIN_LENGTH, DEFAULT_TRIM_WINDOW,
+};
+use crate::pipeline::warnings::{
+    contamination_warnings, degenerate_input_warning, inconsistency_warning,
+    insufficient_data_warning, read_length_warnings, DatabaseProfile, DegenerateInputPolicy,
+    ReportWarning,
+};
+use crate::pipeline::watchlist::{Watchlist, WatchlistAlert};
+use crate::provenance::{database_digest, Provenance};
+use crate::sketch::{Comparable, MultiResolutionSignature, SignatureLayout, SimilarityCache};
+use crate::stats::diversity::shannon_index;
+use crate::stats::estimators::{EstimatorKind, EstimatorOptions, PosteriorDraws, StrainAbundances};
+use crate::stats::reconciliation::{AbundanceReconciliation, DEFAULT_RECONCILIATION_TOLERANCE};
+use crate::stats::strain_clusters::{SpeciesStrainClusters, DEFAULT_ANI_THRESHOLDS};
+use crate::utils::paths::sample_file;
 use log::{error, info, warn};
 // Fix: Import needletail parser
 use needletail::parse_fastx_file;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufWriter};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
@@ -31,6 +71,9 @@ impl MoleculeType {
     }
 }
 
+/// Number of reads inspected to check k-mer sizes against read length
+const READ_LENGTH_SAMPLE: usize = 1000;
+
 // --- Error Type ---
 #[derive(Error, Debug)]
 pub enum ProcessingError {
@@ -53,8 +96,20 @@ pub enum ProcessingError {
     #[error("Database error: {0}")]
     DatabaseError(String),
 
+    #[error("Invalid parameters: {0}")]
+    InvalidParameters(String),
+
+    #[error("Degenerate input: {0}")]
+    DegenerateInput(String),
+
+    #[error("External tool error: {0}")]
+    ExternalTool(String),
+
     #[error("Needletail parsing error: {0}")] // Specific error for needletail
     NeedletailError(#[from] needletail::errors::ParseError),
+
+    #[error("{0}")]
+    UnsupportedVersion(#[from] UnsupportedVersion),
 }
 
 // --- Structs (QC Params, Metrics, Results) ---
@@ -67,6 +122,77 @@ pub struct QualityControlParams {
     pub min_length: usize,
     pub trim_quality: u8,
     pub max_n_percent: f64,
+    /// How reads are trimmed against `trim_quality`
+    #[serde(default)]
+    pub trimming: TrimmingStrategy,
+    /// Window length for `TrimmingStrategy::SlidingWindow`
+    #[serde(default = "default_trim_window")]
+    pub trim_window: usize,
+    /// Trim 3' poly-G runs at least this long (`None` disables)
+    #[serde(default = "default_poly_g_min_length")]
+    pub poly_g_min_length: Option<usize>,
+    /// Trim 3' poly-A runs at least this long (`None` disables)
+    #[serde(default)]
+    pub poly_a_min_length: Option<usize>,
+}
+
+/// Named bundles of QC thresholds tuned for common data types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum QcPreset {
+    /// High-accuracy short reads where depth allows discarding marginal reads
+    Strict,
+    /// Typical Illumina short reads
+    #[default]
+    Default,
+    /// Low-input or degraded samples where every read counts
+    Lenient,
+    /// Nanopore and PacBio CLR reads: low per-base quality, long minimum length
+    LongRead,
+}
+
+impl QualityControlParams {
+    /// Parameters of a preset
+    pub fn preset(preset: QcPreset) -> Self {
+        match preset {
+            QcPreset::Default => QualityControlParams::default(),
+            QcPreset::Strict => QualityControlParams {
+                min_avg_quality: 25.0,
+                min_length: 75,
+                trim_quality: 20,
+                max_n_percent: 1.0,
+                trimming: TrimmingStrategy::SlidingWindow,
+                ..QualityControlParams::default()
+            },
+            QcPreset::Lenient => QualityControlParams {
+                min_avg_quality: 15.0,
+                min_length: 30,
+                trim_quality: 10,
+                max_n_percent: 10.0,
+                ..QualityControlParams::default()
+            },
+            // Quality dips are frequent along long reads, so Mott keeps the best
+            // segment rather than cutting at the first bad window; poly-G is a
+            // two-colour chemistry artifact these platforms do not produce
+            QcPreset::LongRead => QualityControlParams {
+                min_avg_quality: 10.0,
+                min_length: 1000,
+                trim_quality: 7,
+                max_n_percent: 5.0,
+                trimming: TrimmingStrategy::Mott,
+                poly_g_min_length: None,
+                ..QualityControlParams::default()
+            },
+        }
+    }
+}
+
+fn default_trim_window() -> usize {
+    DEFAULT_TRIM_WINDOW
+}
+
+fn default_poly_g_min_length() -> Option<usize> {
+    Some(DEFAULT_POLY_G_MIN_LENGTH)
 }
 
 impl Default for QualityControlParams {
@@ -76,6 +202,10 @@ impl Default for QualityControlParams {
             min_length: 50,
             trim_quality: 15,
             max_n_percent: 5.0,
+            trimming: TrimmingStrategy::default(),
+            trim_window: DEFAULT_TRIM_WINDOW,
+            poly_g_min_length: default_poly_g_min_length(),
+            poly_a_min_length: None,
         }
     }
 }
@@ -89,34 +219,183 @@ pub struct ProcessingMetrics {
     pub passed_bases: usize,
     pub avg_read_length: f64,
     pub processing_time_seconds: f64,
+    /// Reads passing QC that were removed as rRNA (RNA mode only)
+    #[serde(default)]
+    pub rrna_reads: usize,
+    /// Reads passing QC that match each control panel (adapters, host, vectors)
+    #[serde(default)]
+    pub contamination: Vec<ContaminationEstimate>,
+    /// Reads passing QC that were removed as matching a control panel marked for removal
+    #[serde(default)]
+    pub control_reads_removed: usize,
+    /// How often mates point at the same taxon (interleaved paired-end input only)
+    #[serde(default)]
+    pub pair_concordance: Option<PairConcordance>,
 }
 
 /// Sample classification results
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClassificationResults {
+    /// Layout version of the file, 0 for results written before it was recorded
+    #[serde(default)]
+    pub format_version: u32,
     pub sample_id: String,
     pub metrics: ProcessingMetrics,
     pub classifications: Vec<Classification>,
     pub strain_abundances: HashMap<String, (f64, f64)>,
     pub results_file: Option<PathBuf>,
+    /// Input, database and parameter digests for the run that produced these results
+    #[serde(default)]
+    pub provenance: Option<Provenance>,
+    /// Parameter, database and taxonomy mismatches that affect interpretation
+    #[serde(default)]
+    pub warnings: Vec<ReportWarning>,
+    /// Too few reads were classified (`--min-classified-reads`) for the
+    /// classifications and abundances to be relied on
+    #[serde(default)]
+    pub insufficient_data: bool,
+    /// ANI clusters of the candidate strains of the classified species
+    #[serde(default)]
+    pub strain_clusters: Vec<SpeciesStrainClusters>,
+    /// Hits (top classification or strains) to user-registered MAGs
+    #[serde(default)]
+    pub mag_hits: Vec<MagHit>,
+    /// Queries for references that would cover unassigned parts of the sample
+    #[serde(default)]
+    pub reference_suggestions: Vec<ReferenceSuggestion>,
+    /// Fraction of the sample's sketch hashes found in at least one reference
+    #[serde(default)]
+    pub assigned_fraction: Option<f64>,
+    /// Whether the abundances are shares of genomes (DNA) or of transcripts (RNA)
+    #[serde(default)]
+    pub sequencing_mode: SequencingMode,
+    /// Watchlist members detected in the sample
+    #[serde(default)]
+    pub alerts: Vec<WatchlistAlert>,
+    /// Species abundance and how the strain abundances were fitted into it
+    #[serde(default)]
+    pub reconciliation: Option<AbundanceReconciliation>,
+    /// Strain abundances of each estimator run for comparison, including the
+    /// primary one, before reconciliation with the species abundance
+    #[serde(default)]
+    pub estimator_comparison: BTreeMap<EstimatorKind, StrainAbundances>,
+    /// Posterior draws of the strain abundances (`--posterior-draws`), scaled
+    /// like `strain_abundances` by the reconciliation
+    #[serde(default)]
+    pub posterior_draws: Option<PosteriorDraws>,
+    /// Checkpoints and reads used when classification could stop early
+    #[serde(default)]
+    pub early_stop: Option<EarlyStopSummary>,
+    /// Read alignment evidence for the top calls, when confirmation was run
+    #[serde(default)]
+    pub confirmations: Vec<AlignmentConfirmation>,
+    /// Settings of the run, for the methods paragraph of the report
+    #[serde(default)]
+    pub parameters: Option<AnalysisParameters>,
 }
 
 // --- FastqProcessor ---
 
+/// A sketched sample on its way to classification
+struct SketchedSample<'a> {
+    sample_id: &'a str,
+    signature: MultiResolutionSignature,
+    metrics: ProcessingMetrics,
+    /// Files the sample was read from, for provenance
+    inputs: Vec<&'a Path>,
+    /// Reads to align for confirmation, when they are at hand
+    reads: Option<&'a Path>,
+    warnings: Vec<ReportWarning>,
+    early_stop: Option<EarlyStopSummary>,
+}
+
 /// FASTQ processing pipeline
 pub struct FastqProcessor {
     pub qc_params: QualityControlParams,
     pub threads: usize,
-    pub chunk_size: usize,
+    /// Reads per batch; `None` sizes batches from each file's read length
+    /// to fit `batch_memory_bytes`
+    pub chunk_size: Option<usize>,
+    /// Memory the batches of a file may take together, when sized automatically
+    pub batch_memory_bytes: usize,
+    /// Batches of reads parsed ahead of the worker threads
+    pub read_ahead_batches: usize,
     pub macro_k: usize,
     pub meso_k: usize,
     pub sketch_size: usize,
+    /// Levels samples are sketched with; the references' layout once the classifier is loaded
+    pub layout: SignatureLayout,
     pub db_manager: DatabaseManager,
     pub classifier: Option<AdaptiveClassifier>,
+    /// Digest of the reference signatures loaded by `init_classifier`
+    pub database_sha256: Option<String>,
+    /// Sketch parameters and consistency of the loaded references
+    pub database_profile: DatabaseProfile,
+    /// Record input digests without file names (used with `--anonymize`)
+    pub redact_input_names: bool,
+    /// Method that estimates the strain abundances
+    pub estimator: EstimatorKind,
+    /// Further estimators run on each sample for comparison
+    pub compare_estimators: Vec<EstimatorKind>,
+    /// Settings of the estimators that take any
+    pub estimator_options: EstimatorOptions,
+    /// Posterior draws of the estimator kept in the results, for comparisons
+    /// between groups; 0 keeps none, and only `mcmc` and `vi` have draws
+    pub posterior_draws: usize,
+    /// ANI thresholds at which candidate strains are clustered in the report
+    pub ani_thresholds: Vec<f64>,
+    /// Quality metadata of the user MAGs among the references
+    pub mag_metadata: HashMap<String, MagMetadata>,
+    /// Thresholds for suggesting missing references
+    pub reference_advisor: ReferenceAdvisor,
+    /// Warn when a k-mer size exceeds this fraction of the median read length
+    pub max_kmer_read_fraction: f64,
+    /// Whether a sample with nothing to classify, or no matches, fails or only warns
+    pub on_degenerate_input: DegenerateInputPolicy,
+    /// Classified reads below which a sample is reported as insufficient data
+    pub min_classified_reads: Option<usize>,
+    /// Metagenome or metatranscriptome processing
+    pub sequencing_mode: SequencingMode,
+    /// Strand of the transcript the reads come from (RNA mode)
+    pub strandedness: Strandedness,
+    /// Panel whose matching reads are removed as rRNA before sketching (RNA mode)
+    pub rrna_filter: Option<RrnaFilter>,
+    /// Sequences the sample should not contain; matching reads are counted, not removed
+    pub control_panels: Vec<ControlPanel>,
+    /// Share of reads matching a control panel above which the sample gets a warning
+    pub max_contamination_fraction: f64,
+    /// Directory each sample's reads are written to after QC, rRNA and control
+    /// removal (`<sample>.filtered.fastq.gz`, or `.fasta.gz` for FASTA input)
+    pub filtered_reads_dir: Option<PathBuf>,
+    /// Taxa whose detection raises an alert
+    pub watchlist: Option<Watchlist>,
+    /// Write the top K references per resolution level behind each call
+    pub debug_classification: Option<usize>,
+    /// Write each sample's similarity to every reference at every resolution level
+    pub export_scores: bool,
+    /// Leave query hashes found in more than this fraction of the references out of classification
+    pub max_hash_frequency: Option<f64>,
+    /// Weigh hashes by their rarity across the references for genus-level calls
+    pub idf_weighting: bool,
+    /// Directory to store each sample's final sketch in, for `reclassify`
+    pub sketch_dir: Option<PathBuf>,
+    /// Compression of the results and sketch JSON files
+    pub json_compression: JsonCompression,
+    /// Reference-vs-reference similarities, loaded from and saved to the database
+    pub similarity_cache: SimilarityCache,
+    /// Classify after every increment of reads and stop once the top call is stable
+    pub early_stop: Option<EarlyStopPolicy>,
+    /// Align the reads to the top calls' references to confirm them
+    pub confirmation: Option<AlignerConfig>,
+    /// Pins the database must match (`--strict`)
+    pub strict: Option<StrictConfig>,
+    /// Traces of each sample's stages and counters of reads and samples
+    pub telemetry: Telemetry,
 }
 
 impl FastqProcessor {
-    /// Create a new FASTQ processor
+    /// Create a new FASTQ processor with default database storage settings
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         db_path: impl AsRef<Path>,
         cache_dir: impl AsRef<Path>,
@@ -127,24 +406,83 @@ impl FastqProcessor {
         qc_params: Option<QualityControlParams>,
         api_key: Option<String>,
     ) -> Result<Self, ProcessingError> {
-        let db_manager = DatabaseManager::new(
+        Self::with_config(
             db_path,
             cache_dir,
-            sketch_size, // Assuming DB Manager needs sketch_size, threads
             threads,
+            macro_k,
+            meso_k,
+            sketch_size,
+            qc_params,
             api_key,
+            &DatabaseConfig::default(),
         )
-        .map_err(|e| ProcessingError::DatabaseError(format!("DB Manager init failed: {}", e)))?;
+    }
+
+    /// Create a new FASTQ processor whose database is opened with `database`
+    /// (backend, compression), as configured for it
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_config(
+        db_path: impl AsRef<Path>,
+        cache_dir: impl AsRef<Path>,
+        threads: usize,
+        macro_k: usize,
+        meso_k: usize,
+        sketch_size: usize,
+        qc_params: Option<QualityControlParams>,
+        api_key: Option<String>,
+        database: &DatabaseConfig,
+    ) -> Result<Self, ProcessingError> {
+        let layout = SignatureLayout::minhash(&[macro_k, meso_k], sketch_size);
+        let db_manager =
+            DatabaseManager::with_config(db_path, cache_dir, layout.clone(), api_key, database)
+                .map_err(|e| {
+                    ProcessingError::DatabaseError(format!("DB Manager init failed: {}", e))
+                })?;
 
         Ok(FastqProcessor {
             qc_params: qc_params.unwrap_or_default(),
             threads,
-            chunk_size: 100000,
+            chunk_size: None,
+            batch_memory_bytes: DEFAULT_BATCH_MEMORY_BYTES,
+            read_ahead_batches: DEFAULT_READ_AHEAD_BATCHES,
             macro_k,
             meso_k,
             sketch_size,
+            layout,
             db_manager,
             classifier: None,
+            database_sha256: None,
+            database_profile: DatabaseProfile::default(),
+            redact_input_names: false,
+            estimator: EstimatorKind::default(),
+            compare_estimators: Vec::new(),
+            estimator_options: EstimatorOptions::default(),
+            posterior_draws: 0,
+            ani_thresholds: DEFAULT_ANI_THRESHOLDS.to_vec(),
+            mag_metadata: HashMap::new(),
+            reference_advisor: ReferenceAdvisor::default(),
+            max_kmer_read_fraction: 0.5,
+            on_degenerate_input: DegenerateInputPolicy::default(),
+            min_classified_reads: None,
+            sequencing_mode: SequencingMode::default(),
+            strandedness: Strandedness::default(),
+            rrna_filter: None,
+            control_panels: ControlPanel::builtin(),
+            filtered_reads_dir: None,
+            max_contamination_fraction: DEFAULT_MAX_CONTAMINATION_FRACTION,
+            watchlist: None,
+            debug_classification: None,
+            export_scores: false,
+            max_hash_frequency: None,
+            idf_weighting: false,
+            sketch_dir: None,
+            json_compression: JsonCompression::default(),
+            similarity_cache: SimilarityCache::default(),
+            early_stop: None,
+            confirmation: None,
+            strict: None,
+            telemetry: Telemetry::default(),
         })
     }
 
@@ -154,6 +492,33 @@ impl FastqProcessor {
         let db_references = self.db_manager.database.get_all_signatures().map_err(|e| {
             ProcessingError::DatabaseError(format!("Failed to get signatures: {}", e))
         })?;
+        let digest = database_digest(&db_references);
+        if let Some(strict) = &self.strict {
+            enforce(strict.check_database(&digest).err().into_iter().collect())?;
+        }
+        self.database_sha256 = Some(digest);
+        // Sketch samples exactly like the references, whatever was requested
+        if let Some(layout) = SignatureLayout::dominant(&db_references) {
+            if layout != self.layout {
+                info!(
+                    "Sketching samples with the database's layout (k = {:?}) instead of k = {:?}",
+                    layout.kmer_sizes(),
+                    self.layout.kmer_sizes()
+                );
+                self.layout = layout;
+            }
+        }
+        self.database_profile = DatabaseProfile::from_signatures(&db_references);
+        self.mag_metadata = self.db_manager.database.mag_metadata().map_err(|e| {
+            ProcessingError::DatabaseError(format!("Failed to read MAG metadata: {}", e))
+        })?;
+        match self.db_manager.database.similarity_cache() {
+            Ok(cache) => {
+                info!("Loaded {} cached reference similarities", cache.len());
+                self.similarity_cache = cache;
+            }
+            Err(e) => warn!("Cannot read the similarity cache, starting empty: {}", e),
+        }
 
         // Convert database signatures to sketches
         let mut sketch_signatures: Vec<Arc<MultiResolutionSignature>> =
@@ -171,34 +536,126 @@ impl FastqProcessor {
             // Arc wrap and store the signature
             sketch_signatures.push(Arc::new(db_sig_arc));
         }
+        if sketch_signatures.is_empty() {
+            return Err(ProcessingError::DatabaseError(
+                "no usable reference signatures: check --db-path, or populate the database \
+                 with `init` or `add-references`"
+                    .to_string(),
+            ));
+        }
 
         // Configure and initialize classifier
-        let thresholds = None; // Use default thresholds
-        let min_coverage = Some(100); // Minimum coverage requirement
-        self.classifier = Some(
-            AdaptiveClassifier::new(
-                sketch_signatures
-                    .iter()
-                    .map(|sig: &Arc<MultiResolutionSignature>| {
-                        // Dereference the Arc to get the inner MultiResolutionSignature
-                        (**sig).clone()
-                    })
-                    .collect::<Vec<_>>(),
-                thresholds,
-                min_coverage,
-            )
-            .unwrap(),
-        );
+        let mut builder = ClassifierBuilder::new(
+            sketch_signatures
+                .iter()
+                .map(|sig: &Arc<MultiResolutionSignature>| {
+                    // Dereference the Arc to get the inner MultiResolutionSignature
+                    (**sig).clone()
+                })
+                .collect::<Vec<_>>(),
+        )
+        .min_coverage(100) // Minimum coverage requirement
+        .idf_weighting(self.idf_weighting);
+        if let Some(max_frequency) = self.max_hash_frequency {
+            builder = builder.max_hash_frequency(max_frequency);
+        }
+        let classifier = builder.build().map_err(|e| match e {
+            ClassificationError::InvalidParameter(message) => {
+                ProcessingError::InvalidParameters(message)
+            }
+            e => ProcessingError::ClassificationError(e.to_string()),
+        })?;
+        if let Some(max_frequency) = self.max_hash_frequency {
+            info!(
+                "Ignoring {} hashes found in more than {:.0}% of the references",
+                classifier.promiscuous_hash_count(),
+                max_frequency * 100.0
+            );
+        }
+        self.classifier = Some(classifier);
 
         Ok(())
     }
 
+    /// Align the reads to the references of the most abundant strains (or of
+    /// the top call when there are no strain estimates). Calls that cannot be
+    /// aligned are recorded as skipped rather than failing the sample.
+    fn confirm_calls(
+        &self,
+        config: &AlignerConfig,
+        fastq_path: &Path,
+        classifier: &AdaptiveClassifier,
+        classifications: &[Classification],
+        strain_abundances: &HashMap<String, (f64, f64)>,
+    ) -> Vec<AlignmentConfirmation> {
+        let mut calls: Vec<(&String, f64)> = strain_abundances
+            .iter()
+            .map(|(id, (abundance, _))| (id, *abundance))
+            .collect();
+        calls.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let mut calls: Vec<&String> = calls.into_iter().map(|(id, _)| id).collect();
+        if calls.is_empty() {
+            calls.extend(classifications.first().map(|c| &c.best_match));
+        }
+        calls.truncate(config.top_calls);
+
+        let aligner = ReadAligner::new(config.clone());
+        calls
+            .into_iter()
+            .map(|taxon_id| {
+                let source = classifier
+                    .references
+                    .iter()
+                    .find(|r| &r.taxon_id == taxon_id)
+                    .and_then(|r| r.levels.iter().find_map(|level| level.path.clone()))
+                    .filter(|path| path.exists());
+                let reference = match source {
+                    Some(path) => path,
+                    None => match self.db_manager.downloader.download_genome(taxon_id) {
+                        Ok(path) => path,
+                        Err(e) => {
+                            warn!("No reference genome to confirm {}: {}", taxon_id, e);
+                            return AlignmentConfirmation::skipped(
+                                taxon_id,
+                                format!("no reference genome: {}", e),
+                            );
+                        }
+                    },
+                };
+                info!(
+                    "Confirming {} by aligning reads to {}",
+                    taxon_id,
+                    reference.display()
+                );
+                aligner
+                    .confirm(taxon_id, &reference, fastq_path)
+                    .unwrap_or_else(|e| {
+                        warn!("Cannot confirm {}: {}", taxon_id, e);
+                        AlignmentConfirmation::skipped(taxon_id, e.to_string())
+                    })
+            })
+            .collect()
+    }
+
     /// Process a FASTQ file: read, QC, sketch, classify, estimate strains, and report.
     pub fn process_file(
         &self,
         fastq_path: impl AsRef<Path>,
         sample_id: &str,
         output_dir: impl AsRef<Path>,
+    ) -> Result<ClassificationResults, ProcessingError> {
+        let span = self.telemetry.sample(sample_id);
+        let result = self.sketch_and_classify(fastq_path, sample_id, output_dir, &span);
+        self.telemetry.finish_sample(span, &result);
+        result
+    }
+
+    fn sketch_and_classify(
+        &self,
+        fastq_path: impl AsRef<Path>,
+        sample_id: &str,
+        output_dir: impl AsRef<Path>,
+        span: &StageSpan,
     ) -> Result<ClassificationResults, ProcessingError> {
         let start_time = Instant::now();
 
@@ -218,57 +675,132 @@ impl FastqProcessor {
             passed_bases: 0,
             avg_read_length: 0.0,
             processing_time_seconds: 0.0,
+            rrna_reads: 0,
+            contamination: ContaminationEstimate::for_panels(&self.control_panels),
+            control_reads_removed: 0,
+            pair_concordance: None,
         }));
 
-        let macro_sig = KmerSignature {
-            sketch: Signature::new("minhash".to_string(), 100, 1000),
-            kmer_size: 21,
-            molecule_type: MoleculeType::Dna.to_string(),
-            name: Some("Macro Signature".to_string()),
-            filename: Some("macro_signature.txt".to_string()),
-            path: Some(PathBuf::from("/path/to/macro_signature")),
-        };
-
-        let meso_sig = KmerSignature {
-            sketch: Signature::new("minhash".to_string(), 50, 500),
-            kmer_size: 21,
-            molecule_type: MoleculeType::Dna.to_string(),
-            name: Some("Meso Signature".to_string()),
-            filename: Some("meso_signature.txt".to_string()),
-            path: Some(PathBuf::from("/path/to/meso_signature")),
-        };
+        let initial_signature =
+            MultiResolutionSignature::with_layout(sample_id.to_string(), Vec::new(), &self.layout);
+        // Fail before sketching when the k-mer sizes cannot fit the reads
+        let kmer_sizes: Vec<(Option<usize>, usize)> = initial_signature
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| (Some(i), level.kmer_size))
+            .collect();
+        let read_lengths = self.sample_read_lengths(fastq_path.as_ref())?;
+        let length_warnings =
+            read_length_warnings(&kmer_sizes, &read_lengths, self.max_kmer_read_fraction)
+                .map_err(ProcessingError::InvalidParameters)?;
 
-        let initial_signature = MultiResolutionSignature {
-            taxon_id: sample_id.to_string(),
-            lineage: Vec::new(),
-            levels: vec![macro_sig, meso_sig], // Store signatures directly in levels
-        };
         let signature = Arc::new(Mutex::new(initial_signature));
-
-        let mut reader = parse_fastx_file(fastq_path.as_ref())?; // Use '?'
-
-        let mut current_chunk = Vec::with_capacity(self.chunk_size);
+        let sketching = span.stage("sketch");
+
+        // Parsing runs on its own thread; QC and sketching on `threads` workers
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads.max(1))
+            .build()
+            .map_err(|e| {
+                ProcessingError::InvalidParameters(format!("Failed to start worker threads: {}", e))
+            })?;
+        let mut reader = BatchReader::open(
+            fastq_path.as_ref(),
+            self.batch_size(fastq_path.as_ref())?,
+            self.read_ahead_batches,
+        )?;
+
+        let mut tracker = self.early_stop.map(ConvergenceTracker::new);
+        let mut pairs = None;
+        let mut filtered_reads = None;
+        let mut reads_read = 0;
+        let mut stopped_early = false;
 
         info!("Processing file: {}", fastq_path.as_ref().display());
 
-        while let Some(record_result) = reader.next() {
-            let record = record_result?; // Use '?'
-            current_chunk.push((record.seq().to_vec(), record.qual().map(|q| q.to_vec())));
-
-            if current_chunk.len() >= self.chunk_size {
-                self.process_chunk(&current_chunk, &metrics, &signature)?;
-                current_chunk.clear();
+        'reading: while let Some(batch) = reader.next() {
+            let batch = batch?;
+            if reads_read == 0 {
+                // Interleaved input pairs up by name from its first records
+                let ids: Vec<&[u8]> = (0..batch.len().min(INTERLEAVED_SAMPLE_RECORDS))
+                    .map(|i| batch.name(i))
+                    .collect();
+                if is_interleaved(&ids) {
+                    info!("Input is interleaved paired-end; checking mate concordance");
+                    pairs = Some(MatePairs::new(ReadAssigner::new(&classifier.references, 0)));
+                }
+                if let Some(dir) = &self.filtered_reads_dir {
+                    std::fs::create_dir_all(dir)?;
+                    let (format, suffix) = match batch.read(0).1 {
+                        Some(_) => (SequenceFormat::Fastq, ".filtered.fastq.gz"),
+                        None => (SequenceFormat::Fasta, ".filtered.fasta.gz"),
+                    };
+                    let path = sample_file(dir, sample_id, suffix);
+                    let writer =
+                        RecordWriter::create(&path, format, true).map_err(filtered_reads_error)?;
+                    filtered_reads = Some((path, writer));
+                }
+            }
+            let mut start = 0;
+            while start < batch.len() {
+                // Process up to the next early-stop checkpoint, if it falls in this batch
+                let end = tracker
+                    .as_ref()
+                    .and_then(|tracker| {
+                        (start..batch.len()).find(|&i| tracker.is_due(reads_read + i - start + 1))
+                    })
+                    .map_or(batch.len(), |i| i + 1);
+                pool.install(|| {
+                    self.process_chunk(
+                        &batch,
+                        start..end,
+                        &metrics,
+                        &signature,
+                        pairs.as_mut(),
+                        filtered_reads.as_mut().map(|(_, writer)| writer),
+                    )
+                })?;
+                reads_read += end - start;
+                start = end;
+
+                let Some(tracker) = tracker.as_mut().filter(|t| t.is_due(reads_read)) else {
+                    continue;
+                };
+                let snapshot = signature.lock().unwrap().clone();
+                let call = classifier.classify(&snapshot).ok();
+                if tracker.observe(reads_read, call.as_ref()) {
+                    info!(
+                        "Top call stable after {} reads; skipping the rest of {}",
+                        reads_read,
+                        fastq_path.as_ref().display()
+                    );
+                    stopped_early = true;
+                    break 'reading;
+                }
             }
+            reader.recycle(batch);
         }
+        let early_stop = tracker.map(|tracker| tracker.finish(reads_read, stopped_early));
+        drop(sketching);
 
-        if !current_chunk.is_empty() {
-            self.process_chunk(&current_chunk, &metrics, &signature)?;
+        let pair_concordance = pairs.map(MatePairs::finish);
+        if let Some((path, writer)) = filtered_reads {
+            let written = writer.finish().map_err(filtered_reads_error)?;
+            info!("{} filtered reads written to {}", written, path.display());
+        }
+        if let Some(concordance) = &pair_concordance {
+            info!(
+                "Paired-end input: {} pairs, {} with both mates assigned, {} concordant",
+                concordance.pairs, concordance.both_assigned, concordance.concordant
+            );
         }
 
         let elapsed = start_time.elapsed().as_secs_f64();
 
         let final_metrics = {
             let mut metrics_guard = metrics.lock().unwrap();
+            metrics_guard.pair_concordance = pair_concordance;
             metrics_guard.processing_time_seconds = elapsed;
             if metrics_guard.passed_reads > 0 {
                 metrics_guard.avg_read_length =
@@ -276,16 +808,131 @@ impl FastqProcessor {
             }
             metrics_guard.clone()
         };
+        self.telemetry.record_reads(&final_metrics);
 
         let final_signature = signature.lock().unwrap().clone();
+        if let Some(dir) = &self.sketch_dir {
+            let sketch = SampleSketch {
+                format_version: Artifact::Signature.current(),
+                sample_id: sample_id.to_string(),
+                signature: final_signature.clone(),
+                metrics: final_metrics.clone(),
+                database_sha256: self.database_sha256.clone(),
+            };
+            let path = sketch.save(dir, self.json_compression)?;
+            info!("Sample sketch written to {}", path.display());
+        }
+
+        self.classify_sample(
+            classifier,
+            SketchedSample {
+                sample_id,
+                signature: final_signature,
+                metrics: final_metrics,
+                inputs: vec![fastq_path.as_ref()],
+                reads: Some(fastq_path.as_ref()),
+                warnings: length_warnings,
+                early_stop,
+            },
+            output_path,
+            span,
+        )
+    }
+
+    /// Classify a stored sample sketch again with the current database,
+    /// writing `<sample>_results.json` to `output_dir`. Confirmation, which
+    /// needs the reads, is skipped.
+    pub fn reclassify(
+        &self,
+        sketch: &SampleSketch,
+        sketch_path: &Path,
+        output_dir: impl AsRef<Path>,
+    ) -> Result<ClassificationResults, ProcessingError> {
+        let classifier = self.classifier.as_ref().ok_or_else(|| {
+            ProcessingError::ClassificationError(
+                "Classifier not initialized. Call init_classifier() first.".to_string(),
+            )
+        })?;
+        let kmer_sizes: Vec<usize> = sketch
+            .signature
+            .levels
+            .iter()
+            .map(|level| level.kmer_size)
+            .collect();
+        if kmer_sizes != self.layout.kmer_sizes() {
+            return Err(ProcessingError::InvalidParameters(format!(
+                "{} was sketched with k = {:?}, but the database uses k = {:?}; process its reads again",
+                sketch.sample_id,
+                kmer_sizes,
+                self.layout.kmer_sizes()
+            )));
+        }
+        let output_path = output_dir.as_ref();
+        std::fs::create_dir_all(output_path)?;
+        let span = self.telemetry.sample(&sketch.sample_id);
+        let result = self.classify_sample(
+            classifier,
+            SketchedSample {
+                sample_id: &sketch.sample_id,
+                signature: sketch.signature.clone(),
+                metrics: sketch.metrics.clone(),
+                inputs: vec![sketch_path],
+                reads: None,
+                warnings: Vec::new(),
+                early_stop: None,
+            },
+            output_path,
+            &span,
+        );
+        self.telemetry.finish_sample(span, &result);
+        result
+    }
+
+    /// Classify a sketched sample, estimate its strains and write its results
+    fn classify_sample(
+        &self,
+        classifier: &AdaptiveClassifier,
+        sample: SketchedSample,
+        output_path: &Path,
+        span: &StageSpan,
+    ) -> Result<ClassificationResults, ProcessingError> {
+        let SketchedSample {
+            sample_id,
+            signature: final_signature,
+            metrics: final_metrics,
+            inputs,
+            reads,
+            mut warnings,
+            early_stop,
+        } = sample;
+        let elapsed = final_metrics.processing_time_seconds;
 
         info!("Classifying final sample signature...");
+        let classifying = span.stage("classify");
         // Use the get_hierarchical_classifications which currently wraps classify
         let classifications =
             self.get_hierarchical_classifications(&final_signature, classifier)?;
+        drop(classifying);
 
         let best_classification = classifications.first(); // get_hierarchical_classifications returns Vec
+        if let (Some(top_k), Some(cls)) = (self.debug_classification, best_classification) {
+            let details = explain_classification(classifier, &final_signature, cls, top_k);
+            let debug_path = sample_file(output_path, &sample_id, "_classification_debug.tsv");
+            write_classification_debug(cls, &details, &debug_path)?;
+            info!("Classification details written to {}", debug_path.display());
+        }
+        if self.export_scores {
+            let rows = score_matrix(classifier, &final_signature);
+            let scores_path = sample_file(output_path, &sample_id, "_scores.tsv");
+            write_score_matrix(&sample_id, &rows, &scores_path)?;
+            info!("Score matrix written to {}", scores_path.display());
+        }
 
+        let mut strain_clusters = Vec::new();
+        let mut reconciliation = None;
+        let mut estimator_comparison = BTreeMap::new();
+        let mut posterior_draws = None;
+        let estimating = span.stage("estimate_strains");
         let strain_abundances = if let Some(cls) = best_classification {
             info!(
                 "Top classification: {} ({:?}), Confidence: {:.4}",
@@ -293,7 +940,94 @@ impl FastqProcessor {
             );
             if cls.level <= TaxonomicLevel::Species {
                 info!("Attempting strain estimation for {}...", cls.taxon_id);
-                self.estimate_strain_abundances(&final_signature, classifier, &cls.taxon_id)?
+                let (mut abundances, draws) = self.estimate_strain_abundances(
+                    &final_signature,
+                    classifier,
+                    &cls.taxon_id,
+                    self.estimator,
+                )?;
+                if self.posterior_draws > 0 {
+                    posterior_draws = draws.map(|draws| draws.thinned(self.posterior_draws));
+                }
+                if !self.compare_estimators.is_empty() {
+                    estimator_comparison.insert(self.estimator, abundances.clone());
+                    for &kind in &self.compare_estimators {
+                        if !estimator_comparison.contains_key(&kind) {
+                            let (compared, _) = self.estimate_strain_abundances(
+                                &final_signature,
+                                classifier,
+                                &cls.taxon_id,
+                                kind,
+                            )?;
+                            estimator_comparison.insert(kind, compared);
+                        }
+                    }
+                }
+                let candidates = candidate_strains(&classifier.references, &cls.taxon_id);
+                if !abundances.is_empty() {
+                    let species_references: Vec<&MultiResolutionSignature> = classifier
+                        .references
+                        .iter()
+                        .filter(|r| r.taxon_id == cls.taxon_id)
+                        .chain(candidates.iter().copied())
+                        .collect();
+                    if let Some((species_abundance, strain_total)) =
+                        AbundanceReconciliation::measure(
+                            &cls.taxon_id,
+                            &final_signature,
+                            &species_references,
+                            &candidates,
+                        )
+                    {
+                        let relative_total: f64 = abundances.values().map(|(a, _)| a).sum();
+                        let reconciled = AbundanceReconciliation::reconcile(
+                            &cls.taxon_id,
+                            species_abundance,
+                            strain_total,
+                            &mut abundances,
+                            DEFAULT_RECONCILIATION_TOLERANCE,
+                        );
+                        // The draws are rescaled by the same factor as their means
+                        if let Some(draws) = &mut posterior_draws {
+                            let assigned: f64 = abundances.values().map(|(a, _)| a).sum();
+                            draws.scale(if relative_total > 0.0 {
+                                assigned / relative_total
+                            } else {
+                                0.0
+                            });
+                        }
+                        info!(
+                            "Species {} is {:.2}% of the sample; strains explain {:.2}% ({:.1}% of the species unresolved)",
+                            cls.taxon_id,
+                            species_abundance * 100.0,
+                            strain_total * 100.0,
+                            reconciled.unresolved_fraction * 100.0
+                        );
+                        reconciliation = Some(reconciled);
+                    }
+                }
+                if candidates.len() > 1 && !self.ani_thresholds.is_empty() {
+                    let point_estimates: HashMap<String, f64> = abundances
+                        .iter()
+                        .map(|(id, (abundance, _))| (id.clone(), *abundance))
+                        .collect();
+                    strain_clusters.push(SpeciesStrainClusters::new(
+                        &cls.taxon_id,
+                        &candidates,
+                        &point_estimates,
+                        &self.ani_thresholds,
+                        &self.similarity_cache,
+                    ));
+                    // A cache that cannot be written only costs time on the next run
+                    if let Err(e) = self
+                        .db_manager
+                        .database
+                        .save_similarities(&self.similarity_cache)
+                    {
+                        warn!("Cannot save reference similarities to the database: {}", e);
+                    }
+                }
+                abundances
             } else {
                 info!(
                     "Classification level ({:?}) is above Species, skipping strain estimation.",
@@ -305,21 +1039,207 @@ impl FastqProcessor {
             warn!("Classifier returned Ok but no classification found.");
             HashMap::new()
         };
+        drop(estimating);
+
+        let mut hit_ids: Vec<&String> = classifications
+            .first()
+            .map(|c| &c.taxon_id)
+            .into_iter()
+            .chain(strain_abundances.keys())
+            .collect();
+        hit_ids.sort();
+        hit_ids.dedup();
+        let mag_hits: Vec<MagHit> = hit_ids
+            .into_iter()
+            .filter_map(|id| {
+                self.mag_metadata.get(id).map(|metadata| MagHit {
+                    taxon_id: id.clone(),
+                    metadata: metadata.clone(),
+                })
+            })
+            .collect();
 
-        let results_file_path = output_path.join(format!("{}_results.json", sample_id));
-        let results = ClassificationResults {
+        let reference_suggestions = self
+            .reference_advisor
+            .suggest(&final_signature, &classifier.references);
+        let assigned_fraction = final_signature
+            .levels
+            .first()
+            .filter(|level| !level.sketch.hashes.is_empty())
+            .map(|level| 1.0 - unassigned_fraction(level, &classifier.references));
+        for suggestion in &reference_suggestions {
+            info!(
+                "{:.0}% of hashes unassigned; nearest partial match {} ({:.1}% ANI). Consider: db add-references --query '{}'",
+                suggestion.unassigned_fraction * 100.0,
+                suggestion.nearest_reference,
+                suggestion.nearest_ani * 100.0,
+                suggestion.query
+            );
+        }
+
+        if let Some(warning) =
+            degenerate_input_warning(&final_metrics, &final_signature, classifications.len())
+        {
+            if self.on_degenerate_input == DegenerateInputPolicy::Error {
+                return Err(ProcessingError::DegenerateInput(format!(
+                    "{}: {} (use --on-degenerate-input warn to keep going)",
+                    sample_id, warning.detail
+                )));
+            }
+            warnings.push(warning);
+        }
+        let insufficient = self.min_classified_reads.and_then(|min_reads| {
+            insufficient_data_warning(&final_metrics, assigned_fraction, min_reads)
+        });
+        let insufficient_data = insufficient.is_some();
+        warnings.extend(insufficient);
+        warnings.extend(self.database_profile.check_query(&final_signature));
+        if let Some(warning) = reconciliation.as_ref().and_then(inconsistency_warning) {
+            warnings.push(warning);
+        }
+        warnings.extend(contamination_warnings(
+            &final_metrics,
+            self.max_contamination_fraction,
+        ));
+        let confirmations = match (&self.confirmation, reads) {
+            (Some(config), Some(reads)) => {
+                let _confirming = span.stage("confirm");
+                let confirmations = self.confirm_calls(
+                    config,
+                    reads,
+                    classifier,
+                    &classifications,
+                    &strain_abundances,
+                );
+                warnings.extend(
+                    confirmations
+                        .iter()
+                        .filter_map(|confirmation| confirmation.warning(config.min_breadth)),
+                );
+                confirmations
+            }
+            (Some(_), None) => {
+                info!(
+                    "Reads of {} are not at hand; skipping confirmation",
+                    sample_id
+                );
+                Vec::new()
+            }
+            (None, _) => Vec::new(),
+        };
+        for warning in &warnings {
+            warn!("{}", warning);
+        }
+
+        let mut parameters = serde_json::json!({
+            "qc": self.qc_params,
+            "macro_k": self.macro_k,
+            "meso_k": self.meso_k,
+            "sketch_size": self.sketch_size,
+            "inference": self.estimator.inference_method(),
+        });
+        // Recorded only for estimators added after `inference`, so digests of
+        // similarity, MCMC and VI runs stay as they were
+        if self.estimator.inference_method().is_none()
+            && self.estimator != EstimatorKind::Similarity
+        {
+            parameters["estimator"] = serde_json::json!(self.estimator);
+        }
+        // Only recorded when set, so digests of runs without them stay as they were
+        if self.estimator_options != EstimatorOptions::default() {
+            parameters["estimator_options"] = serde_json::json!(self.estimator_options);
+        }
+        // Only RNA runs record these, so DNA parameter digests stay as they were
+        if self.sequencing_mode == SequencingMode::Rna {
+            parameters["rna"] = serde_json::json!({
+                "strandedness": self.strandedness,
+                "rrna_panel": self.rrna_filter.as_ref().map(|filter| serde_json::json!({
+                    "kmer_size": filter.kmer_size,
+                    "scaled": filter.scaled,
+                    "min_containment": filter.min_containment,
+                    "hashes": filter.len(),
+                })),
+            });
+        }
+        // Removing control reads changes the sketch; counting them does not
+        let removed_controls: Vec<&str> = self
+            .control_panels
+            .iter()
+            .filter(|panel| panel.remove)
+            .map(|panel| panel.name.as_str())
+            .collect();
+        if !removed_controls.is_empty() {
+            parameters["removed_controls"] = serde_json::json!(removed_controls);
+        }
+        // Stopping early changes which reads are sketched
+        if let Some(policy) = self.early_stop {
+            parameters["early_stop"] = serde_json::json!(policy);
+        }
+        // Promiscuous hashes are left out of every comparison
+        if let Some(max_frequency) = self.max_hash_frequency {
+            parameters["max_hash_frequency"] = serde_json::json!(max_frequency);
+        }
+        if self.idf_weighting {
+            parameters["idf_weighting"] = serde_json::json!(true);
+        }
+        // Confirmation adds evidence and warnings without changing the calls
+        if let Some(config) = &self.confirmation {
+            parameters["confirm"] = serde_json::json!(config);
+        }
+        // Decides whether the calls are reported, not what they are
+        if let Some(min_reads) = self.min_classified_reads {
+            parameters["min_classified_reads"] = serde_json::json!(min_reads);
+        }
+        let mut provenance = Provenance::new(&inputs, self.database_sha256.clone(), &parameters)?;
+        if self.redact_input_names {
+            provenance.redact_input_names();
+        }
+
+        let results_file_path =
+            self.json_compression
+                .path(sample_file(output_path, &sample_id, "_results.json"));
+        let mut results = ClassificationResults {
+            format_version: Artifact::Results.current(),
             sample_id: sample_id.to_string(),
             metrics: final_metrics.clone(),
             classifications, // Store the Vec from get_hierarchical_classifications
             strain_abundances,
             results_file: Some(results_file_path.clone()),
+            provenance: Some(provenance),
+            warnings,
+            insufficient_data,
+            strain_clusters,
+            mag_hits,
+            reference_suggestions,
+            assigned_fraction,
+            sequencing_mode: self.sequencing_mode,
+            alerts: Vec::new(),
+            reconciliation,
+            estimator_comparison,
+            posterior_draws,
+            early_stop,
+            confirmations,
+            parameters: Some(AnalysisParameters {
+                qc: self.qc_params.clone(),
+                layout: self.layout.clone(),
+                estimator: self.estimator,
+            }),
         };
+        if let Some(watchlist) = &self.watchlist {
+            results.alerts = watchlist.check(&results);
+            for alert in &results.alerts {
+                warn!("WATCHLIST ALERT in {}: {}", sample_id, alert);
+            }
+            let alerts_path = watchlist
+                .write_alerts(sample_id, &results.alerts, output_path)
+                .map_err(|e| ProcessingError::IoError(io::Error::new(io::ErrorKind::Other, e)))?;
+            info!("Watchlist alerts written to {}", alerts_path.display());
+        }
 
         info!("Writing results to {}", results_file_path.display());
-        let file = File::create(&results_file_path)?;
-        let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, &results)
-            .map_err(|e| ProcessingError::IoError(io::Error::new(io::ErrorKind::Other, e)))?;
+        let writing = span.stage("write_results");
+        write_json(&results_file_path, &results, self.json_compression)?;
+        drop(writing);
 
         info!("Processed sample {} in {:.2} seconds", sample_id, elapsed);
         info!(
@@ -332,6 +1252,18 @@ impl FastqProcessor {
             "Avg Read Length (Passed QC): {:.1} bp",
             final_metrics.avg_read_length
         );
+        if self.sequencing_mode == SequencingMode::Rna {
+            info!("rRNA reads removed: {}", final_metrics.rrna_reads);
+        }
+        for estimate in &final_metrics.contamination {
+            info!(
+                "Reads matching {} panel: {} ({:.2}%){}",
+                estimate.panel,
+                estimate.matching_reads,
+                estimate.read_fraction * 100.0,
+                if estimate.removed { ", removed" } else { "" }
+            );
+        }
 
         Ok(results)
     }
@@ -378,21 +1310,111 @@ impl FastqProcessor {
         Ok(processed)
     }
 
-    /// Process a chunk of reads in parallel: apply QC and update the shared signature.
-    fn process_chunk(
+    /// Reads per batch for `fastq_path`: `chunk_size` if set, otherwise as
+    /// many of the file's reads as fit the memory budget
+    fn batch_size(&self, fastq_path: &Path) -> Result<usize, ProcessingError> {
+        if let Some(chunk_size) = self.chunk_size {
+            return Ok(chunk_size.max(1));
+        }
+        let Some(mean_length) = sample_mean_read_length(fastq_path)? else {
+            return Ok(MIN_BATCH_READS);
+        };
+        let size = batch_size_for(
+            mean_length,
+            self.batch_memory_bytes,
+            self.read_ahead_batches,
+        );
+        info!(
+            "Batches of {} reads for {} (mean read length {:.0})",
+            size,
+            fastq_path.display(),
+            mean_length
+        );
+        Ok(size)
+    }
+
+    /// Lengths after QC of the first `READ_LENGTH_SAMPLE` reads that pass it
+    fn sample_read_lengths(&self, fastq_path: &Path) -> Result<Vec<usize>, ProcessingError> {
+        let mut reader = parse_fastx_file(fastq_path)?;
+        let mut lengths = Vec::with_capacity(READ_LENGTH_SAMPLE);
+        let mut seen = 0;
+        while let Some(record) = reader.next() {
+            let record = record?;
+            let qual = record.qual().map(|q| q.to_vec());
+            if let Some(read) = self.apply_quality_control(&record.seq(), qual.as_ref()) {
+                lengths.push(read.len());
+            }
+            seen += 1;
+            if seen >= READ_LENGTH_SAMPLE {
+                break;
+            }
+        }
+        Ok(lengths)
+    }
+
+    /// Process a range of a batch's reads in parallel: apply QC and update the
+    /// shared signature. With `pairs`, the reads kept are assigned to references
+    /// and paired up in file order; with `filtered_reads`, they are written out.
+    fn process_chunk<'a>(
         &self,
-        chunk: &[(Vec<u8>, Option<Vec<u8>>)],
+        batch: &ReadBatch,
+        reads: Range<usize>,
         metrics: &Arc<Mutex<ProcessingMetrics>>,
         signature: &Arc<Mutex<MultiResolutionSignature>>,
+        pairs: Option<&mut MatePairs<'a>>,
+        filtered_reads: Option<&mut RecordWriter>,
     ) -> Result<(), ProcessingError> {
-        chunk.par_iter().try_for_each(|(seq, _quality)| {
-            let processed_seq = self.process_sequence(seq)?;
-            if !processed_seq.is_empty() {
+        let assigner = pairs.as_deref().map(MatePairs::assigner);
+        let mates = reads
+            .clone()
+            .into_par_iter()
+            .map(|i| -> Result<Mate<'a>, ProcessingError> {
+                let (seq, _quality) = batch.read(i);
+                let processed_seq = self.process_sequence(seq)?;
+                if processed_seq.is_empty() {
+                    return Ok(Mate::Dropped);
+                }
+                if self.sequencing_mode == SequencingMode::Rna {
+                    // Sketches are canonical, so orientation only matters to the rRNA panel
+                    let is_rrna = self.rrna_filter.as_ref().map_or(false, |filter| {
+                        let read = self.strandedness.orient(&processed_seq);
+                        filter.is_rrna(&read, self.strandedness)
+                    });
+                    if is_rrna {
+                        let mut metrics = metrics.lock().unwrap();
+                        metrics.total_reads += 1;
+                        metrics.total_bases += processed_seq.len();
+                        metrics.rrna_reads += 1;
+                        return Ok(Mate::Dropped);
+                    }
+                }
+                let control_matches: Vec<(usize, usize, bool)> = self
+                    .control_panels
+                    .iter()
+                    .map(|panel| {
+                        let (kmers, matching) = panel.matches(&processed_seq);
+                        (kmers, matching, panel.is_match(kmers, matching))
+                    })
+                    .collect();
+                let is_control = self
+                    .control_panels
+                    .iter()
+                    .zip(&control_matches)
+                    .any(|(panel, &(_, _, is_match))| panel.remove && is_match);
                 // Update metrics
                 {
                     let mut metrics = metrics.lock().unwrap();
                     metrics.total_reads += 1;
                     metrics.total_bases += processed_seq.len();
+                    for (estimate, (kmers, matching, is_match)) in
+                        metrics.contamination.iter_mut().zip(control_matches)
+                    {
+                        estimate.add_read(kmers, matching, is_match);
+                    }
+                    if is_control {
+                        metrics.control_reads_removed += 1;
+                        return Ok(Mate::Dropped);
+                    }
                     metrics.passed_reads += 1;
                     metrics.passed_bases += processed_seq.len();
                 }
@@ -407,19 +1429,57 @@ impl FastqProcessor {
                         ))
                     })?;
                 }
+                drop(sig_guard);
+                Ok(Mate::Kept(
+                    assigner.and_then(|assigner| assigner.assign(&processed_seq)),
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(writer) = filtered_reads {
+            let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+            let kept: Vec<SequenceRecord> = reads
+                .clone()
+                .zip(&mates)
+                .filter(|(_, mate)| matches!(mate, Mate::Kept(_)))
+                .map(|(i, _)| {
+                    let (seq, qual) = batch.read(i);
+                    SequenceRecord {
+                        id: text(batch.name(i)),
+                        seq: text(seq),
+                        qual: qual.map(text),
+                    }
+                })
+                .collect();
+            writer.write(&kept).map_err(filtered_reads_error)?;
+        }
+        if let Some(pairs) = pairs {
+            for (i, mate) in reads.zip(mates) {
+                pairs.add(batch.name(i), mate);
             }
-            Ok(())
-        })
+        }
+        Ok(())
     }
 
     /// Apply quality control filters to a single read.
     fn apply_quality_control(&self, seq: &[u8], qual: Option<&Vec<u8>>) -> Option<Vec<u8>> {
-        // 1. Check initial length
+        // 1. Trim poly-G artifacts, then poly-A tails in front of them
+        let mut tail = 0;
+        if let Some(min_length) = self.qc_params.poly_g_min_length {
+            tail += poly_tail_length(seq, b'G', min_length);
+        }
+        if let Some(min_length) = self.qc_params.poly_a_min_length {
+            tail += poly_tail_length(&seq[..seq.len() - tail], b'A', min_length);
+        }
+        let seq = &seq[..seq.len() - tail];
+        let qual = qual.map(|q| &q[..q.len().saturating_sub(tail)]);
+
+        // 2. Check initial length
         if seq.len() < self.qc_params.min_length {
             return None;
         }
 
-        // 2. Check N content
+        // 3. Check N content
         let n_count = seq
             .iter()
             .filter(|&&base| base == b'N' || base == b'n')
@@ -429,7 +1489,7 @@ impl FastqProcessor {
             return None;
         }
 
-        // 3. Quality trimming and average quality check
+        // 4. Quality trimming and average quality check
         if let Some(qual_vec) = qual {
             if qual_vec.len() != seq.len() {
                 error!(
@@ -453,38 +1513,18 @@ impl FastqProcessor {
                 return None;
             }
 
-            let mut trim_start = 0;
-            let mut trim_end = seq.len();
-            let mut found_start = false;
-
-            for (i, &q) in qual_vec.iter().enumerate() {
-                if q.saturating_sub(33) >= self.qc_params.trim_quality {
-                    trim_start = i;
-                    found_start = true;
-                    break;
-                }
-            }
-            if !found_start {
-                return None;
-            }
-
-            let mut found_end = false;
-            for i in (trim_start..qual_vec.len()).rev() {
-                if qual_vec[i].saturating_sub(33) >= self.qc_params.trim_quality {
-                    trim_end = i + 1;
-                    found_end = true;
-                    break;
-                }
-            }
-            if !found_end {
-                return None;
-            }
-
-            if trim_start >= trim_end || (trim_end - trim_start) < self.qc_params.min_length {
+            let phred: Vec<u8> = qual_vec.iter().map(|&q| q.saturating_sub(33)).collect();
+            let kept = trim_range(
+                self.qc_params.trimming,
+                &phred,
+                self.qc_params.trim_quality,
+                self.qc_params.trim_window,
+            )?;
+            if kept.len() < self.qc_params.min_length {
                 return None;
             }
 
-            Some(seq[trim_start..trim_end].to_vec())
+            Some(seq[kept].to_vec())
         } else {
             Some(seq.to_vec()) // Passed length/N%, no quality scores
         }
@@ -502,37 +1542,28 @@ impl FastqProcessor {
         Ok(vec![best_classification])
     }
 
-    /// Estimate relative abundances of strains related to the classified species.
+    /// Estimate relative abundances of strains related to the classified species
+    /// with the estimator `kind`, with its posterior draws if it has any.
     fn estimate_strain_abundances(
         &self,
         signature: &MultiResolutionSignature,
         classifier: &AdaptiveClassifier,
         target_species_id: &str,
-    ) -> Result<HashMap<String, (f64, f64)>, ProcessingError> {
+        kind: EstimatorKind,
+    ) -> Result<(StrainAbundances, Option<PosteriorDraws>), ProcessingError> {
         info!(
-            "Estimating strain abundances relative to target: {}",
-            target_species_id
+            "Estimating strain abundances relative to target {} ({} estimator)",
+            target_species_id, kind
         );
 
-        // Use the existing filtering logic based on lineage and taxon_id
-        let relevant_strains = classifier
-            .references
-            .iter()
-            .filter(|ref_sig| {
-                // Crude check: is it downstream in lineage and not the species itself?
-                ref_sig.lineage.contains(&target_species_id.to_string())
-                    && ref_sig.taxon_id != target_species_id
-                // A check based on TaxonomicLevel might be better if available and reliable on ref_sig
-                // For example: if let Some(ref_level) = ref_sig.level { ref_level > TaxonomicLevel::Species && ... }
-            })
-            .collect::<Vec<_>>();
+        let relevant_strains = candidate_strains(&classifier.references, target_species_id);
 
         if relevant_strains.is_empty() {
             warn!(
                 "No potential reference strains found downstream of target {}.",
                 target_species_id
             );
-            return Ok(HashMap::new());
+            return Ok((HashMap::new(), None));
         }
 
         info!(
@@ -541,42 +1572,66 @@ impl FastqProcessor {
             target_species_id
         );
 
-        let mut similarities = HashMap::new();
-        let mut total_similarity = 0.0;
-
-        for strain_sig in relevant_strains {
-            let sim = signature.similarity(strain_sig, None); // Use overall similarity
-            if sim > Some(0.0) {
-                similarities.insert(strain_sig.taxon_id.clone(), sim.unwrap_or(0.0));
-                total_similarity += sim.unwrap_or(0.0);
-            }
+        let (abundances, draws) = kind
+            .build_with(self.estimator_options)
+            .estimate_with_draws(signature, &relevant_strains)
+            .map_err(|e| ProcessingError::StrainEstimationError(e.to_string()))?;
+        for (id, (abundance, interval)) in &abundances {
+            info!(
+                "  Strain {}: Relative Abundance ~{:.2}% (± {:.1}%)",
+                id,
+                abundance * 100.0,
+                interval * 100.0
+            );
         }
+        Ok((abundances, draws))
+    }
+}
 
-        let mut abundances = HashMap::new();
-        if total_similarity > f64::EPSILON {
-            for (id, sim) in similarities {
-                let abundance = sim / total_similarity;
-                let confidence = 0.1; // Placeholder
-                                      // Fix 20: Clone id before inserting into abundances map
-                abundances.insert(id.clone(), (abundance, confidence));
-                // Log using the original (uncloned) id is fine here
-                info!(
-                    "  Strain {}: Relative Abundance ~{:.2}%, Similarity {:.4}",
-                    id, // Use original id here
-                    abundance * 100.0,
-                    sim
-                );
-            }
-        } else {
-            info!("Total similarity to relevant strains is zero or negligible.");
-        }
+/// Failure to write the filtered reads
+fn filtered_reads_error(e: anyhow::Error) -> ProcessingError {
+    ProcessingError::FastqError(format!("Failed to write filtered reads: {}", e))
+}
 
-        Ok(abundances)
-    }
+/// Reference strains downstream of `target_species_id` among `references`
+pub(crate) fn candidate_strains<'a>(
+    references: &'a [MultiResolutionSignature],
+    target_species_id: &str,
+) -> Vec<&'a MultiResolutionSignature> {
+    references
+        .iter()
+        .filter(|ref_sig| {
+            // Crude check: is it downstream in lineage and not the species itself?
+            ref_sig
+                .lineage
+                .iter()
+                .any(|taxon| taxon == target_species_id)
+                && ref_sig.taxon_id != target_species_id
+            // A check based on TaxonomicLevel might be better if available and reliable on ref_sig
+            // For example: if let Some(ref_level) = ref_sig.level { ref_level > TaxonomicLevel::Species && ... }
+        })
+        .collect()
 }
 
-/// Generate a formatted text report from the classification results.
+/// Generate a formatted text report from the classification results, with
+/// every section.
 pub fn generate_report(results: &ClassificationResults) -> Result<String, ProcessingError> {
+    generate_report_with(results, &ReportConfig::default())
+}
+
+/// Generate a text report with the sections enabled in `config`, with paths
+/// and the host name removed if it asks for redaction.
+pub fn generate_report_with(
+    results: &ClassificationResults,
+    config: &ReportConfig,
+) -> Result<String, ProcessingError> {
+    // Calls from too few reads look as certain as any other, so they are left out
+    let mut sections = config.sections.clone();
+    if results.insufficient_data {
+        sections.taxonomy = false;
+        sections.strains = false;
+        sections.diversity = false;
+    }
     let mut report = String::new();
 
     // Header
@@ -586,98 +1641,401 @@ pub fn generate_report(results: &ClassificationResults) -> Result<String, Proces
     ));
     report.push_str("=================================================\n\n");
 
-    // Metrics Section
-    report.push_str("Processing Metrics:\n");
-    report.push_str(&format!(
-        "  Total reads processed: {}\n",
-        results.metrics.total_reads
-    ));
-    report.push_str(&format!(
-        "  Reads passed QC: {} ({:.1}%)\n",
-        results.metrics.passed_reads,
-        100.0 * results.metrics.passed_reads as f64 / results.metrics.total_reads.max(1) as f64
-    ));
-    report.push_str(&format!(
-        "  Bases passed QC: {}\n",
-        results.metrics.passed_bases
-    ));
-    report.push_str(&format!(
-        "  Average read length (passed QC): {:.1} bp\n",
-        results.metrics.avg_read_length
-    ));
-    report.push_str(&format!(
-        "  Processing time: {:.2} seconds\n\n",
-        results.metrics.processing_time_seconds
-    ));
+    // Watchlist alerts come before everything else
+    if !results.alerts.is_empty() {
+        report.push_str(&format!(
+            "*** WATCHLIST ALERTS ({}) ***\n",
+            results.alerts.len()
+        ));
+        for alert in &results.alerts {
+            report.push_str(&format!("  - {}\n", alert));
+        }
+        report.push('\n');
+    }
 
-    // Classification Section
-    if results.classifications.is_empty() {
-        report.push_str("Classification Results: No confident classification found.\n\n");
-    } else {
-        // Report top hit primarily
-        report.push_str("Classification Results (Top Hit):\n");
-        if let Some(classification) = results.classifications.first() {
-            report.push_str(&format!("  Taxon ID: {}\n", classification.taxon_id));
-            report.push_str(&format!("  Taxonomic level: {:?}\n", classification.level));
-            report.push_str(&format!("  Confidence: {:.4}\n", classification.confidence));
-
-            if !classification.lineage.is_empty() {
-                report.push_str("  Lineage: ");
-                report.push_str(&classification.lineage.join(" > "));
-                report.push('\n');
+    // Warnings go next so they are read before any result they qualify
+    if !results.warnings.is_empty() {
+        report.push_str(&format!(
+            "!!! WARNINGS ({}) - read before interpreting results !!!\n",
+            results.warnings.len()
+        ));
+        for warning in &results.warnings {
+            report.push_str(&format!("  - {}\n", warning));
+        }
+        report.push('\n');
+    }
+
+    if sections.qc {
+        // Metrics Section
+        report.push_str("Processing Metrics:\n");
+        report.push_str(&format!(
+            "  Total reads processed: {}\n",
+            results.metrics.total_reads
+        ));
+        report.push_str(&format!(
+            "  Reads passed QC: {} ({:.1}%)\n",
+            results.metrics.passed_reads,
+            100.0 * results.metrics.passed_reads as f64 / results.metrics.total_reads.max(1) as f64
+        ));
+        if results.sequencing_mode == SequencingMode::Rna {
+            report.push_str(&format!(
+                "  rRNA reads removed: {} ({:.1}% of reads passing QC)\n",
+                results.metrics.rrna_reads,
+                100.0 * results.metrics.rrna_reads as f64
+                    / (results.metrics.passed_reads + results.metrics.rrna_reads).max(1) as f64
+            ));
+        }
+        for estimate in &results.metrics.contamination {
+            report.push_str(&format!(
+                "  Reads matching {} panel: {} ({:.2}%; {:.2}% of k-mers){}\n",
+                estimate.panel,
+                estimate.matching_reads,
+                estimate.read_fraction * 100.0,
+                estimate.kmer_fraction * 100.0,
+                if estimate.removed { ", removed" } else { "" }
+            ));
+        }
+        if let Some(early_stop) = &results.early_stop {
+            if early_stop.stopped_early {
+                report.push_str(&format!(
+                    "  Stopped early: top call stable for {} checkpoints after {} reads\n",
+                    early_stop.policy.stable_checks, early_stop.reads_used
+                ));
             } else {
-                report.push_str("  Lineage: N/A\n");
+                report.push_str(
+                    "  Stopped early: no, top call did not settle before the end of the input\n",
+                );
             }
+        }
+        if let Some(pairs) = &results.metrics.pair_concordance {
+            report.push_str(&format!(
+                "  Read pairs (both mates passed QC): {}; both mates assigned: {}, one mate: {}\n",
+                pairs.pairs, pairs.both_assigned, pairs.one_assigned
+            ));
+        }
+        report.push_str(&format!(
+            "  Bases passed QC: {}\n",
+            results.metrics.passed_bases
+        ));
+        report.push_str(&format!(
+            "  Average read length (passed QC): {:.1} bp\n",
+            results.metrics.avg_read_length
+        ));
+        report.push_str(&format!(
+            "  Processing time: {:.2} seconds\n\n",
+            results.metrics.processing_time_seconds
+        ));
+    }
 
-            report.push_str("  Similarity scores:\n");
-            if classification.similarity_scores.is_empty() {
-                report.push_str("    N/A\n");
-            } else {
-                // Sort scores by similarity value (descending)
-                let mut sorted_scores: Vec<_> = classification.similarity_scores.iter().collect();
-                sorted_scores.sort_by(|(_, score_a), (_, score_b)| {
-                    score_b
-                        .partial_cmp(score_a)
-                        .unwrap_or(std::cmp::Ordering::Equal)
-                });
-
-                for (level, score) in sorted_scores {
-                    // Assuming level is Debug-printable (like ResolutionLevel or similar enum/struct)
-                    report.push_str(&format!("    {:?}: {:.4}\n", level, score));
+    if results.insufficient_data && config.sections.taxonomy {
+        report.push_str(
+            "Classification Results: Insufficient data - too few reads were classified to \
+             report taxa or abundances (see warnings).\n\n",
+        );
+    }
+
+    if sections.taxonomy {
+        // Classification Section
+        if results.classifications.is_empty() {
+            report.push_str("Classification Results: No confident classification found.\n\n");
+        } else {
+            // Report top hit primarily
+            report.push_str("Classification Results (Top Hit):\n");
+            if let Some(classification) = results.classifications.first() {
+                report.push_str(&format!("  Taxon ID: {}\n", classification.taxon_id));
+                if let Some(hit) = results
+                    .mag_hits
+                    .iter()
+                    .find(|hit| hit.taxon_id == classification.taxon_id)
+                {
+                    report.push_str(&format!(
+                        "  Reference: user MAG ({} quality)\n",
+                        hit.metadata.quality()
+                    ));
+                }
+                report.push_str(&format!("  Taxonomic level: {:?}\n", classification.level));
+                report.push_str(&format!("  Confidence: {:.4}\n", classification.confidence));
+                let pairs = results.metrics.pair_concordance.as_ref();
+                if let Some((pairs, concordance)) =
+                    pairs.and_then(|pairs| pairs.concordance().map(|c| (pairs, c)))
+                {
+                    report.push_str(&format!(
+                        "  Mate concordance: {:.1}% of {} assigned pairs{}\n",
+                        concordance * 100.0,
+                        pairs.both_assigned,
+                        if concordance < DEFAULT_MIN_PAIR_CONCORDANCE {
+                            " (LOW: chimeric library or misassigned reads likely)"
+                        } else {
+                            ""
+                        }
+                    ));
+                }
+
+                if !classification.lineage.is_empty() {
+                    report.push_str("  Lineage: ");
+                    report.push_str(&classification.lineage.join(" > "));
+                    report.push('\n');
+                } else {
+                    report.push_str("  Lineage: N/A\n");
+                }
+
+                report.push_str("  Similarity scores:\n");
+                if classification.similarity_scores.is_empty() {
+                    report.push_str("    N/A\n");
+                } else {
+                    // Sort scores by similarity value (descending)
+                    let mut sorted_scores: Vec<_> =
+                        classification.similarity_scores.iter().collect();
+                    sorted_scores.sort_by(|(_, score_a), (_, score_b)| {
+                        score_b
+                            .partial_cmp(score_a)
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                    });
+
+                    for (level, score) in sorted_scores {
+                        // Assuming level is Debug-printable (like ResolutionLevel or similar enum/struct)
+                        report.push_str(&format!("    {:?}: {:.4}\n", level, score));
+                    }
+                }
+                report.push('\n');
+            }
+        }
+    }
+
+    if sections.strains {
+        // Strain Abundance Section
+        if !results.strain_abundances.is_empty() {
+            match (results.sequencing_mode, &results.reconciliation) {
+                (SequencingMode::Dna, None) => {
+                    report.push_str("Strain Abundance Estimates (relative within classified group):\n")
+                }
+                (SequencingMode::Rna, None) => report.push_str(
+                    "Strain Expression Estimates (share of non-rRNA transcripts within classified \
+                     group; not cell abundance):\n",
+                ),
+                (SequencingMode::Dna, Some(reconciliation)) => report.push_str(&format!(
+                    "Strain Abundance Estimates (share of species {}, itself {:.2}% of the sample):\n",
+                    reconciliation.species_id,
+                    reconciliation.species_abundance * 100.0
+                )),
+                (SequencingMode::Rna, Some(reconciliation)) => report.push_str(&format!(
+                    "Strain Expression Estimates (share of species {} transcripts, themselves {:.2}% \
+                     of non-rRNA transcripts; not cell abundance):\n",
+                    reconciliation.species_id,
+                    reconciliation.species_abundance * 100.0
+                )),
+            }
+            let mut strains: Vec<_> = results.strain_abundances.iter().collect();
+            strains.sort_by(|a, b| {
+                b.1 .0
+                    .partial_cmp(&a.1 .0)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            for (strain_id, (abundance, confidence)) in strains {
+                if *abundance > 1e-6 {
+                    let source = if results
+                        .mag_hits
+                        .iter()
+                        .any(|hit| &hit.taxon_id == strain_id)
+                    {
+                        " [user MAG]"
+                    } else {
+                        ""
+                    };
+                    report.push_str(&format!(
+                        "  - {}{}: {:.2}% (± {:.1}%)\n",
+                        strain_id,
+                        source,
+                        abundance * 100.0,
+                        confidence * 100.0
+                    ));
+                }
+            }
+            if let Some(reconciliation) = &results.reconciliation {
+                if reconciliation.unresolved_fraction > 1e-6 {
+                    report.push_str(&format!(
+                        "  - unresolved (no reference strain): {:.2}%\n",
+                        reconciliation.unresolved_fraction * 100.0
+                    ));
+                }
+            }
+            report.push('\n');
+        } else if results
+            .classifications
+            .first()
+            .map_or(false, |c| c.level <= TaxonomicLevel::Species)
+        {
+            report.push_str(
+                "Strain Abundance Estimates: No significant strain abundance detected or resolved.\n\n",
+            );
+        }
+
+        // Alignment Confirmation Section
+        if !results.confirmations.is_empty() {
+            report.push_str("Alignment Confirmation (minimap2):\n");
+            for confirmation in &results.confirmations {
+                match &confirmation.skipped {
+                    Some(reason) => report.push_str(&format!(
+                        "  - {}: not checked ({})\n",
+                        confirmation.taxon_id, reason
+                    )),
+                    None => report.push_str(&format!(
+                        "  - {}: {} - breadth {:.1}%, mean depth {:.2}x, {} reads aligned\n",
+                        confirmation.taxon_id,
+                        if confirmation.confirmed {
+                            "confirmed"
+                        } else {
+                            "NOT confirmed"
+                        },
+                        confirmation.breadth * 100.0,
+                        confirmation.mean_depth,
+                        confirmation.aligned_reads
+                    )),
+                }
+                if let Some(track) = &confirmation.track {
+                    report.push_str(&format!(
+                        "      |{}| ({} bp per bin, {:.0}% of bins uncovered)\n",
+                        track.render(),
+                        track.bin_size,
+                        track.empty_fraction() * 100.0
+                    ));
                 }
             }
             report.push('\n');
         }
     }
 
-    // Strain Abundance Section
-    if !results.strain_abundances.is_empty() {
-        report.push_str("Strain Abundance Estimates (relative within classified group):\n");
-        let mut strains: Vec<_> = results.strain_abundances.iter().collect();
-        strains.sort_by(|a, b| {
-            b.1 .0
-                .partial_cmp(&a.1 .0)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        });
-        for (strain_id, (abundance, confidence)) in strains {
-            if *abundance > 1e-6 {
+    if sections.taxonomy {
+        // User MAG Section
+        if !results.mag_hits.is_empty() {
+            report.push_str("User MAG Hits (not public references; interpret with care):\n");
+            for hit in &results.mag_hits {
+                let percent =
+                    |value: Option<f64>| value.map_or("n/a".to_string(), |v| format!("{:.1}%", v));
                 report.push_str(&format!(
-                    "  - {}: {:.2}% (± {:.1}%)\n",
-                    strain_id,
-                    abundance * 100.0,
-                    confidence * 100.0
+                    "  - {}: {} quality (completeness {}, contamination {})\n",
+                    hit.taxon_id,
+                    hit.metadata.quality(),
+                    percent(hit.metadata.completeness),
+                    percent(hit.metadata.contamination)
                 ));
+                for caveat in hit.metadata.caveats() {
+                    report.push_str(&format!("      caveat: {}\n", caveat));
+                }
             }
+            report.push('\n');
+        }
+    }
+
+    if sections.strains {
+        // Estimator Comparison Section
+        if !results.estimator_comparison.is_empty() {
+            report.push_str("Strain Abundances by Estimator (before reconciliation):\n");
+            let mut strain_ids: Vec<&String> = results
+                .estimator_comparison
+                .values()
+                .flat_map(|abundances| abundances.keys())
+                .collect();
+            strain_ids.sort();
+            strain_ids.dedup();
+            report.push_str("  strain");
+            for kind in results.estimator_comparison.keys() {
+                report.push_str(&format!("\t{}", kind));
+            }
+            report.push('\n');
+            for strain_id in strain_ids {
+                report.push_str(&format!("  {}", strain_id));
+                for abundances in results.estimator_comparison.values() {
+                    match abundances.get(strain_id) {
+                        Some((abundance, _)) => {
+                            report.push_str(&format!("\t{:.2}%", abundance * 100.0))
+                        }
+                        None => report.push_str("\t-"),
+                    }
+                }
+                report.push('\n');
+            }
+            report.push('\n');
+        }
+    }
+
+    if sections.taxonomy {
+        // Missing Reference Section
+        if let Some(first) = results.reference_suggestions.first() {
+            report.push_str(&format!(
+                "Possible Missing References ({:.0}% of sample hashes unassigned):\n",
+                first.unassigned_fraction * 100.0
+            ));
+            for suggestion in &results.reference_suggestions {
+                report.push_str(&format!(
+                    "  - {} (nearest: {}, {:.1}% ANI) -> db add-references --query '{}'\n",
+                    suggestion.taxon_name,
+                    suggestion.nearest_reference,
+                    suggestion.nearest_ani * 100.0,
+                    suggestion.query
+                ));
+            }
+            report.push('\n');
+        }
+    }
+
+    if sections.strains {
+        // Strain Cluster Section
+        for species in &results.strain_clusters {
+            report.push_str(&format!(
+                "Strain Clusters for {} ({} reference strains):\n",
+                species.species_id,
+                species.ani.strain_ids.len()
+            ));
+            for level in &species.levels {
+                report.push_str(&format!(
+                    "  ANI >= {:.1}%: {} clusters\n",
+                    level.ani_threshold * 100.0,
+                    level.clusters.len()
+                ));
+                for cluster in &level.clusters {
+                    report.push_str(&format!(
+                        "    - {}: {:.2}% (min ANI {:.2}%; members: {})\n",
+                        cluster.representative,
+                        cluster.abundance * 100.0,
+                        cluster.min_ani * 100.0,
+                        cluster.members.join(", ")
+                    ));
+                }
+            }
+            report.push('\n');
+        }
+    }
+
+    // Strain Diversity Section
+    let strain_shares = || {
+        results
+            .strain_abundances
+            .values()
+            .map(|(abundance, _)| *abundance)
+            .filter(|&abundance| abundance > 1e-6)
+    };
+    if sections.diversity && strain_shares().count() > 1 {
+        let shannon = shannon_index(strain_shares());
+        report.push_str("Strain Diversity:\n");
+        report.push_str(&format!(
+            "  Strains with estimated abundance: {}\n",
+            strain_shares().count()
+        ));
+        report.push_str(&format!(
+            "  Shannon index: {:.3} (effective number of strains {:.2})\n\n",
+            shannon,
+            shannon.exp()
+        ));
+    }
+
+    // Methods Section
+    if sections.methods {
+        if let Some(paragraph) = methods_paragraph(results) {
+            report.push_str("Methods (fill in the bracketed placeholders):\n");
+            report.push_str(&paragraph);
+            report.push_str("\n\n");
         }
-        report.push('\n');
-    } else if results
-        .classifications
-        .first()
-        .map_or(false, |c| c.level <= TaxonomicLevel::Species)
-    {
-        report.push_str(
-            "Strain Abundance Estimates: No significant strain abundance detected or resolved.\n\n",
-        );
     }
 
     // Footer
@@ -690,6 +2048,9 @@ pub fn generate_report(results: &ClassificationResults) -> Result<String, Proces
             .map_or("Not saved".to_string(), |p| p.display().to_string())
     ));
 
+    if config.redact {
+        report = Redactor::for_this_host().redact(&report);
+    }
     Ok(report)
 }
 
@@ -0,0 +1,152 @@
+//! Classifying stored sample sketches again against an updated database.
+//!
+//! References are added, replaced and withdrawn long after a sample was
+//! sequenced, and reading its FASTQ files again only to rebuild the same
+//! sketch is the slow part of a run. With `--save-sketches DIR` each
+//! sample's final sketch and read metrics are written to DIR, and
+//! `reclassify` runs classification and strain estimation on them with the
+//! current database. Each new result is compared with the sample's previous
+//! one, with the same composition diff `monitor` uses, so the report lists
+//! the taxa a database update added, removed or moved.
+
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::io::format::{read_versioned_json, Artifact, ArtifactError};
+use crate::io::json::{has_json_suffix, write_json, JsonCompression};
+use crate::pipeline::qc::{ProcessingError, ProcessingMetrics};
+use crate::sketch::MultiResolutionSignature;
+use crate::utils::paths::sample_file;
+
+/// Suffix of stored sample sketch files
+const SKETCH_SUFFIX: &str = "_sketch.json";
+
+/// A sample's final sketch and read metrics, kept to classify it again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SampleSketch {
+    /// Layout version of the file, 0 for sketches saved before it was recorded
+    #[serde(default)]
+    pub format_version: u32,
+    pub sample_id: String,
+    pub signature: MultiResolutionSignature,
+    pub metrics: ProcessingMetrics,
+    /// Digest of the database the sample was first classified against
+    #[serde(default)]
+    pub database_sha256: Option<String>,
+}
+
+impl SampleSketch {
+    /// Where the uncompressed sketch of `sample_id` is stored in `dir`
+    pub fn path(dir: &Path, sample_id: &str) -> PathBuf {
+        sample_file(dir, sample_id, SKETCH_SUFFIX)
+    }
+
+    /// Write the sketch to `dir`, returning its path
+    pub fn save(
+        &self,
+        dir: &Path,
+        compression: JsonCompression,
+    ) -> Result<PathBuf, ProcessingError> {
+        std::fs::create_dir_all(dir)?;
+        let path = compression.path(Self::path(dir, &self.sample_id));
+        write_json(&path, self, compression)?;
+        Ok(path)
+    }
+
+    /// Read a sketch, compressed or not, refusing layouts this build does not know
+    pub fn load(path: &Path) -> Result<Self, ProcessingError> {
+        read_versioned_json(Artifact::Signature, path).map_err(|e| match e {
+            ArtifactError::UnsupportedVersion(e) => ProcessingError::UnsupportedVersion(e),
+            e => ProcessingError::SignatureError(format!(
+                "{} is not a sample sketch: {}",
+                path.display(),
+                e
+            )),
+        })
+    }
+
+    /// Every sample sketch in `dir` with its path, sorted by sample ID;
+    /// unreadable files are skipped with a warning, but sketches of an
+    /// unsupported format version are an error
+    pub fn load_dir(dir: &Path) -> Result<Vec<(PathBuf, SampleSketch)>, ProcessingError> {
+        let mut sketches = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_sketch = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| has_json_suffix(name, SKETCH_SUFFIX));
+            if !is_sketch {
+                continue;
+            }
+            match Self::load(&path) {
+                Ok(sketch) => sketches.push((path, sketch)),
+                Err(e @ ProcessingError::UnsupportedVersion(_)) => return Err(e),
+                Err(e) => warn!("Skipping {}: {}", path.display(), e),
+            }
+        }
+        sketches.sort_by(|a, b| a.1.sample_id.cmp(&b.1.sample_id));
+        Ok(sketches)
+    }
+}
+
+/// Where the diff report of `sample_id` is written in `dir`
+pub fn diff_path(dir: &Path, sample_id: &str) -> PathBuf {
+    sample_file(dir, sample_id, "_reclassify_diff.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_sample_sketches_round_trip() {
+        let signature = signature("S1", &[], [level(31, 10, [1, 2, 3]), level(21, 10, [])]);
+        let sketch = SampleSketch {
+            format_version: Artifact::Signature.current(),
+            sample_id: "S1".to_string(),
+            signature,
+            metrics: ProcessingMetrics {
+                total_reads: 10,
+                passed_reads: 8,
+                total_bases: 1000,
+                passed_bases: 800,
+                avg_read_length: 100.0,
+                processing_time_seconds: 1.0,
+                rrna_reads: 0,
+                contamination: Vec::new(),
+                control_reads_removed: 0,
+                pair_concordance: None,
+            },
+            database_sha256: Some("abc".to_string()),
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = sketch.save(dir.path(), JsonCompression::Zstd).unwrap();
+        assert_eq!(path, dir.path().join("S1_sketch.json.zst"));
+        std::fs::write(dir.path().join("S0_sketch.json"), "not json").unwrap();
+        std::fs::write(dir.path().join("S1_results.json"), "{}").unwrap();
+
+        let loaded = SampleSketch::load_dir(dir.path()).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].0, path);
+        assert_eq!(loaded[0].1.sample_id, "S1");
+        assert_eq!(loaded[0].1.signature.levels[0].sketch.hashes, vec![1, 2, 3]);
+        assert_eq!(loaded[0].1.metrics.passed_reads, 8);
+
+        let mut newer = serde_json::to_value(&sketch).unwrap();
+        newer["format_version"] = 2.into();
+        std::fs::write(dir.path().join("S2_sketch.json"), newer.to_string()).unwrap();
+        assert!(matches!(
+            SampleSketch::load_dir(dir.path()),
+            Err(ProcessingError::UnsupportedVersion(_))
+        ));
+        assert_eq!(
+            diff_path(dir.path(), "S1"),
+            dir.path().join("S1_reclassify_diff.json")
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Redaction of reports shared outside the lab.
+//!
+//! Reports name the files they were built from, and paths give away user
+//! names, project directories and mount points; some also carry the name of
+//! the machine that ran the analysis. Redaction rewrites every path in the
+//! finished text to its file name and replaces the host name, so the check
+//! does not depend on each section remembering which values are sensitive.
+
+use std::fs;
+
+/// Placeholder for the host name in redacted text
+pub const REDACTED_HOST: &str = "<host>";
+
+/// Host names shorter than this are not redacted, as they would match ordinary words
+const MIN_HOSTNAME_LENGTH: usize = 3;
+
+/// Rewrites paths and host names in report text
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    hostnames: Vec<String>,
+}
+
+impl Redactor {
+    /// Redact the given host names (and their short forms before the first dot)
+    pub fn new(hostnames: impl IntoIterator<Item = String>) -> Self {
+        let mut names: Vec<String> = Vec::new();
+        for hostname in hostnames {
+            let hostname = hostname.trim().to_string();
+            if let Some((short, _)) = hostname.split_once('.') {
+                names.push(short.to_string());
+            }
+            names.push(hostname);
+        }
+        names.retain(|name| name.len() >= MIN_HOSTNAME_LENGTH);
+        // Longest first, so a fully qualified name is not left with its domain
+        names.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        names.dedup();
+        Redactor { hostnames: names }
+    }
+
+    /// Redactor for the host this process runs on
+    pub fn for_this_host() -> Self {
+        let from_files = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+            .into_iter()
+            .filter_map(|path| fs::read_to_string(path).ok());
+        let from_env = ["HOSTNAME", "COMPUTERNAME"]
+            .into_iter()
+            .filter_map(|var| std::env::var(var).ok());
+        Redactor::new(from_files.chain(from_env))
+    }
+
+    /// `text` with every path replaced by its file name and every host name
+    /// by `REDACTED_HOST`
+    pub fn redact(&self, text: &str) -> String {
+        let mut redacted = String::with_capacity(text.len());
+        let mut rest = text;
+        while !rest.is_empty() {
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let space_end = rest[word_end..]
+                .find(|c: char| !c.is_whitespace())
+                .map_or(rest.len(), |i| word_end + i);
+            redacted.push_str(&redact_word(&rest[..word_end]));
+            redacted.push_str(&rest[word_end..space_end]);
+            rest = &rest[space_end..];
+        }
+        for hostname in &self.hostnames {
+            redacted = redacted.replace(hostname.as_str(), REDACTED_HOST);
+        }
+        redacted
+    }
+}
+
+/// A word with the path it contains, if any, shortened to its file name.
+/// URLs are left alone.
+fn redact_word(word: &str) -> String {
+    let core = word.trim_matches(|c: char| "()[]{}<>'\"`,;".contains(c));
+    let core = core.trim_end_matches(['.', ':']);
+    let is_path = core.len() > 1
+        && !core.contains("://")
+        && (core.trim_start_matches('/').contains('/') || core.contains('\\'));
+    if !is_path {
+        return word.to_string();
+    }
+    let file_name = core
+        .trim_end_matches(['/', '\\'])
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default();
+    word.replacen(core, file_name, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_and_hostnames_redacted() {
+        let redactor = Redactor::new(["labserver07.example.org\n".to_string()]);
+        let text = "Results JSON: /home/alice/project/S1_results.json\n\
+                    Input (C:\\Users\\bob\\reads.fq) on labserver07 or labserver07.example.org.\n\
+                    Lineage: Bacteria > Escherichia coli; see https://www.ncbi.nlm.nih.gov/genome/\n\
+                    Also results/S2_results.json, 1.5% of reads";
+        assert_eq!(
+            redactor.redact(text),
+            "Results JSON: S1_results.json\n\
+             Input (reads.fq) on <host> or <host>.\n\
+             Lineage: Bacteria > Escherichia coli; see https://www.ncbi.nlm.nih.gov/genome/\n\
+             Also S2_results.json, 1.5% of reads"
+        );
+        // Too short to redact safely
+        assert_eq!(Redactor::new(["pc".to_string()]).redact("pc"), "pc");
+    }
+}
@@ -1,38 +1,766 @@
-use clap::{Parser, Subcommand};
-use log::info;
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 // Assuming these imports are correct relative to your project structure
+use crate::bio::taxdump::Taxonomy;
+use crate::config::Config;
+use crate::count_table::{CountPrecision, CountTable, DuplicatePolicy};
+use crate::database::downloader::SignatureDatabase;
+use crate::database::DatabaseManager;
+use crate::io::format::{read_versioned_json, Artifact, ArtifactError};
+use crate::io::json::{has_json_suffix, JsonCompression};
+use crate::io::phyloseq::write_phyloseq_tables;
+use crate::io::{read_count_table, read_results, write_count_table, write_results};
+use crate::metadata::Metadata;
+use crate::normalization::streaming::{normalize_csv, normalize_csv_with_factors, TableLayout};
+use crate::normalization::{read_size_factors, write_size_factors};
+use crate::pipeline::anonymize::SampleAnonymizer;
+use crate::pipeline::batches::DEFAULT_BATCH_MEMORY_BYTES;
+use crate::pipeline::budget::{
+    EarlyStopPolicy, DEFAULT_EARLY_STOP_CHECKS, DEFAULT_EARLY_STOP_INCREMENT,
+    DEFAULT_EARLY_STOP_TOLERANCE,
+};
+use crate::pipeline::confirm::{
+    AlignerConfig, ReadAligner, DEFAULT_CONFIRM_TOP, DEFAULT_MIN_BREADTH,
+};
+use crate::pipeline::contamination::{
+    ControlPanel, DEFAULT_CONTROL_KMER_SIZE, DEFAULT_MAX_CONTAMINATION_FRACTION, PHIX_ACCESSION,
+};
+use crate::pipeline::doctor::{run_checks, DoctorOptions};
+use crate::pipeline::methods::{FdrMethod, StatisticalMethods};
+use crate::pipeline::monitor::{
+    DriftReport, DriftThresholds, DEFAULT_MAX_DISTANCE, DEFAULT_MIN_FOLD_CHANGE,
+    DEFAULT_MIN_SHARE_CHANGE,
+};
+use crate::pipeline::reclassify::{diff_path, SampleSketch};
+use crate::pipeline::rna::{
+    RrnaFilter, SequencingMode, Strandedness, DEFAULT_RRNA_KMER_SIZE, DEFAULT_RRNA_MIN_CONTAINMENT,
+    DEFAULT_RRNA_SCALED,
+};
+use crate::pipeline::strict;
+use crate::pipeline::summary::{quote, BatchSummary, RunSummary, SampleStatus};
+use crate::pipeline::telemetry::Telemetry;
+use crate::pipeline::trimming::TrimmingStrategy;
+use crate::pipeline::warnings::{check_run_consistency, DegenerateInputPolicy};
+use crate::pipeline::watchlist::{
+    Watchlist, DEFAULT_WATCHLIST_MIN_ABUNDANCE, DEFAULT_WATCHLIST_MIN_CONFIDENCE,
+};
 use crate::pipeline::{
     // processor::generate_report,
-    qc::QualityControlParams, // Changed import to use qc module
+    qc::{
+        candidate_strains, generate_report_with, ClassificationResults, ProcessingError, QcPreset,
+        QualityControlParams,
+    }, // Changed import to use qc module
     FastqProcessor,
 };
+use crate::provenance::{database_digest, Provenance};
+use crate::sketch::features::{
+    hashed_embedding, reference_similarities, FeatureKind, DEFAULT_EMBEDDING_DIMENSIONS,
+    DEFAULT_TOP_REFERENCES,
+};
+use crate::sketch::{MultiResolutionSignature, SignatureLayout};
+use crate::stats::deconvolution::{
+    write_hierarchical_abundances, InferenceMethod, StrainMixtureModel, DEFAULT_MCMC_ITERATIONS,
+};
+use crate::stats::diversity::{unifrac_matrix, UniFrac};
+use crate::stats::estimators::{EstimatorKind, EstimatorOptions};
+use crate::stats::feature_matrix::shared_feature_matrix;
+use crate::stats::harmonize::{harmonize_tables, FeatureEquivalences};
+use crate::stats::meta::{
+    meta_analyze, write_forest_table, write_meta_results, DEFAULT_MIN_STUDIES,
+};
+use crate::stats::outliers::{assess_samples, write_sample_qc, OutlierThresholds};
+use crate::stats::power::{
+    write_power_curve, PilotEstimates, PowerAnalysis, PowerSettings, DEFAULT_SIMULATIONS,
+    DEFAULT_TARGET_POWER,
+};
+use crate::stats::reference_selection::{
+    select_references, write_reference_scores, ReferenceCrossValidation, DEFAULT_FOLDS,
+};
+use crate::stats::replicates::{collapse_posteriors, collapse_replicates, CollapseMethod};
+use crate::stats::strain_clusters::DEFAULT_ANI_THRESHOLDS;
+use crate::stats::uncertainty::{AbundancePosterior, UncertaintyComparison, DEFAULT_IMPUTATIONS};
+use crate::utils::paths::{colliding_file_names, is_case_insensitive, safe_file_name, sample_file};
+
+/// How the `AHSP_*` environment variables combine with flags, for `--help`
+const ENV_HELP: &str = "Global options marked [env: AHSP_*] can be set through those environment \
+variables instead. A flag on the command line takes precedence over its variable, and the \
+variable over the default.";
 
 #[derive(Parser, Debug)] // Added Debug for easier printing if needed
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = ENV_HELP)]
 pub struct Cli {
     /// Path to the signature database directory
-    #[arg(long, value_name = "DIR", required = true)] // Made required explicitly
+    #[arg(
+        long,
+        alias = "db",
+        value_name = "DIR",
+        env = "AHSP_DB_PATH",
+        required = true
+    )]
     pub db_path: PathBuf,
 
     /// Path to the cache directory for downloads
-    #[arg(long, value_name = "DIR", required = true)] // Made required explicitly
+    #[arg(long, value_name = "DIR", env = "AHSP_CACHE_DIR", required = true)]
     pub cache_dir: PathBuf,
 
-    /// Number of threads to use for processing
-    #[arg(short, long, default_value_t = 4)] // Set a default value
+    /// Number of threads to use for processing (defaults to the CPUs available
+    /// to the process, which honours container CPU limits)
+    #[arg(short, long, env = "AHSP_THREADS", default_value_t = default_threads())]
     pub threads: usize,
 
     /// NCBI API key (optional)
-    #[arg(long)]
+    #[arg(long, env = "AHSP_API_KEY", hide_env_values = true)]
     pub api_key: Option<String>,
 
+    /// JSON config file (report sections and redaction, strict-mode pins)
+    #[arg(long, value_name = "FILE", env = "AHSP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// ANI thresholds (comma-separated fractions) for clustering candidate strains
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = "AHSP_ANI_THRESHOLDS",
+        default_values_t = DEFAULT_ANI_THRESHOLDS
+    )]
+    pub ani_thresholds: Vec<f64>,
+
+    /// What to do when a sample has no reads passing QC, an empty sketch, or no
+    /// matches: record a warning and continue, or fail it (non-zero exit status)
+    #[arg(
+        long,
+        value_enum,
+        env = "AHSP_ON_DEGENERATE_INPUT",
+        default_value_t = DegenerateInputPolicy::Warn
+    )]
+    pub on_degenerate_input: DegenerateInputPolicy,
+
+    /// Report a sample as insufficient data, without classifications or abundances,
+    /// when fewer than N of its reads were classified (reads passing QC times the
+    /// share of sketch hashes assigned to a reference)
+    #[arg(long, value_name = "N", env = "AHSP_MIN_CLASSIFIED_READS")]
+    pub min_classified_reads: Option<usize>,
+
+    /// Write the K closest references per resolution level, with shared hashes, containment,
+    /// ANI and threshold decisions, to `<sample>_classification_debug.tsv` (K defaults to 5)
+    #[arg(long, value_name = "K", num_args = 0..=1, default_missing_value = "5")]
+    pub debug_classification: Option<usize>,
+
+    /// Write the similarity of each sample to every reference at every resolution level
+    /// (shared hashes, Jaccard, containment, ANI) to `<sample>_scores.tsv`
+    #[arg(long)]
+    pub export_scores: bool,
+
+    /// Ignore query hashes found in more than this fraction of the references
+    /// (conserved genes shared across the database) when classifying
+    #[arg(long, value_name = "FRACTION", env = "AHSP_MAX_HASH_FREQUENCY")]
+    pub max_hash_frequency: Option<f64>,
+
+    /// Make genus-level calls with a Jaccard index that weighs each hash by its
+    /// inverse frequency across the references, so conserved k-mers count for little
+    #[arg(long, env = "AHSP_IDF_WEIGHTING")]
+    pub idf_weighting: bool,
+
+    /// Store each sample's final sketch and read metrics in DIR, so `reclassify`
+    /// can classify it again against a later database without the reads
+    #[arg(long, value_name = "DIR", env = "AHSP_SAVE_SKETCHES")]
+    pub save_sketches: Option<PathBuf>,
+
+    /// Compress results and sketch JSON files (`.json.gz` or `.json.zst`); commands
+    /// reading them accept any of the three forms
+    #[arg(
+        long,
+        value_enum,
+        value_name = "METHOD",
+        env = "AHSP_COMPRESS_JSON",
+        default_value_t = JsonCompression::None
+    )]
+    pub compress_json: JsonCompression,
+
+    /// Offline taxonomy (NCBI taxdump directory or lineage map) through which
+    /// taxon names and taxids in user inputs such as `--watchlist` are resolved,
+    /// and the genus or family of references suggested for missing taxa is found
+    #[arg(long, value_name = "PATH", env = "AHSP_TAXONOMY")]
+    pub taxonomy: Option<PathBuf>,
+
+    /// How a sample ID given more than once in a count table, metadata file or
+    /// results directory is handled, e.g. for re-sequenced samples
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        env = "AHSP_DUPLICATE_SAMPLES",
+        default_value_t = DuplicatePolicy::Error
+    )]
+    pub duplicate_samples: DuplicatePolicy,
+
+    #[command(flatten)]
+    pub anonymize: AnonymizeArgs,
+
+    #[command(flatten)]
+    pub rna: RnaArgs,
+
+    #[command(flatten)]
+    pub watchlist: WatchlistArgs,
+
+    #[command(flatten)]
+    pub contamination: ContaminationArgs,
+
+    #[command(flatten)]
+    pub estimators: EstimatorArgs,
+
+    #[command(flatten)]
+    pub early_stop: EarlyStopArgs,
+
+    #[command(flatten)]
+    pub batches: BatchArgs,
+
+    #[command(flatten)]
+    pub confirm: ConfirmArgs,
+
+    #[command(flatten)]
+    pub strict: StrictArgs,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
-#[derive(Subcommand, Debug)] // Added Debug
+/// Sample ID pseudonymization options
+#[derive(Args, Debug, Clone, Default)]
+pub struct AnonymizeArgs {
+    /// Replace sample IDs with pseudonyms in every output
+    #[arg(long, requires = "anonymize_key")]
+    pub anonymize: bool,
+
+    /// File keeping the salt and the sample ID to pseudonym mapping for later
+    /// runs; it reveals the original IDs, so it must be outside the output directory
+    #[arg(long, value_name = "FILE", requires = "anonymize")]
+    pub anonymize_key: Option<PathBuf>,
+
+    /// Salt for hashing sample IDs (defaults to the salt stored in the `--anonymize-key` file)
+    #[arg(long, value_name = "SALT", requires = "anonymize")]
+    pub anonymize_salt: Option<String>,
+
+    /// TSV mapping sample IDs to pseudonyms (`sample_id<TAB>pseudonym`)
+    #[arg(long, value_name = "FILE", requires = "anonymize")]
+    pub anonymize_map: Option<PathBuf>,
+}
+
+impl AnonymizeArgs {
+    /// Open the anonymizer for outputs in `output_dir`, if `--anonymize` was given
+    pub fn open(&self, output_dir: &Path) -> anyhow::Result<Option<SampleAnonymizer>> {
+        if !self.anonymize {
+            return Ok(None);
+        }
+        let key = self
+            .anonymize_key
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("--anonymize needs --anonymize-key"))?;
+        let anonymizer = SampleAnonymizer::new(
+            output_dir,
+            key,
+            self.anonymize_salt.clone(),
+            self.anonymize_map.as_deref(),
+        )?;
+        info!(
+            "Anonymizing sample IDs; mapping kept in {}",
+            anonymizer.mapping_path().display()
+        );
+        Ok(Some(anonymizer))
+    }
+}
+
+/// Metatranscriptomics options
+#[derive(Args, Debug, Clone, Default)]
+pub struct RnaArgs {
+    /// Library type: `dna` (metagenome) or `rna` (metatranscriptome, reported as expression)
+    #[arg(long, value_enum, default_value_t = SequencingMode::Dna)]
+    pub mode: SequencingMode,
+
+    /// Strand of the transcript the reads come from, in RNA mode
+    #[arg(long, value_enum, default_value_t = Strandedness::Unstranded)]
+    pub strandedness: Strandedness,
+
+    /// rRNA FASTA (e.g. SILVA SSU/LSU) whose matching reads are removed in RNA mode
+    #[arg(long, value_name = "FILE")]
+    pub rrna_panel: Option<PathBuf>,
+
+    /// Fraction of a read's panel-sampled k-mers that marks it as rRNA
+    #[arg(long, default_value_t = DEFAULT_RRNA_MIN_CONTAINMENT)]
+    pub rrna_min_containment: f64,
+}
+
+impl RnaArgs {
+    /// Set the processor's sequencing mode, loading the rRNA panel if one was given
+    pub fn configure(&self, processor: &mut FastqProcessor) -> Result<(), ProcessingError> {
+        processor.sequencing_mode = self.mode;
+        processor.strandedness = self.strandedness;
+        if self.mode == SequencingMode::Dna {
+            if self.rrna_panel.is_some() || self.strandedness != Strandedness::Unstranded {
+                warn!("--rrna-panel and --strandedness only apply with --mode rna; ignoring them");
+            }
+            return Ok(());
+        }
+        match &self.rrna_panel {
+            Some(path) => {
+                let mut filter =
+                    RrnaFilter::from_fasta(path, DEFAULT_RRNA_KMER_SIZE, DEFAULT_RRNA_SCALED)?;
+                filter.min_containment = self.rrna_min_containment;
+                info!(
+                    "Loaded rRNA panel {} ({} k-mers)",
+                    path.display(),
+                    filter.len()
+                );
+                processor.rrna_filter = Some(filter);
+            }
+            None => warn!(
+                "RNA mode without --rrna-panel: rRNA reads are not removed and will dominate \
+                 expression estimates unless the library was depleted"
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Strain abundance estimation options
+#[derive(Args, Debug, Clone, Default)]
+pub struct EstimatorArgs {
+    /// Method that estimates strain abundances
+    #[arg(long, value_enum, default_value_t = EstimatorKind::Similarity)]
+    pub estimator: EstimatorKind,
+
+    /// Same as `--estimator mcmc` or `--estimator vi`
+    #[arg(long, value_enum, conflicts_with = "estimator")]
+    pub inference: Option<InferenceMethod>,
+
+    /// Also run these estimators (comma-separated) on each sample and list their
+    /// strain abundances side by side in the results
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub compare_estimators: Vec<EstimatorKind>,
+
+    /// Keep up to N posterior draws of the `mcmc` or `vi` estimator in the
+    /// results, so `differential` can account for the uncertainty of each
+    /// sample's abundances
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub posterior_draws: usize,
+
+    /// L1 penalty of the `nnls` estimator; larger values drop weakly supported
+    /// strains (0 disables)
+    #[arg(long, default_value_t = 0.0)]
+    pub l1_penalty: f64,
+
+    /// Use a spike-and-slab prior in the `mcmc` estimator, with this prior
+    /// probability that each strain is present; small values favour fewer strains
+    #[arg(long, value_name = "P")]
+    pub spike_and_slab: Option<f64>,
+}
+
+impl EstimatorArgs {
+    /// Select the processor's strain abundance estimators
+    pub fn configure(&self, processor: &mut FastqProcessor) -> Result<(), ProcessingError> {
+        if self.l1_penalty < 0.0 || !self.l1_penalty.is_finite() {
+            return Err(ProcessingError::InvalidParameters(
+                "--l1-penalty must be a non-negative number".to_string(),
+            ));
+        }
+        if let Some(p) = self
+            .spike_and_slab
+            .filter(|&p| p <= 0.0 || p >= 1.0 || p.is_nan())
+        {
+            return Err(ProcessingError::InvalidParameters(format!(
+                "--spike-and-slab must be between 0 and 1 (exclusive), got {}",
+                p
+            )));
+        }
+        processor.estimator = self.inference.map_or(self.estimator, EstimatorKind::from);
+        processor.compare_estimators = self.compare_estimators.clone();
+        processor.posterior_draws = self.posterior_draws;
+        processor.estimator_options = EstimatorOptions {
+            l1_penalty: self.l1_penalty,
+            spike_and_slab: self.spike_and_slab,
+        };
+        Ok(())
+    }
+}
+
+/// Budgeted classification options
+#[derive(Args, Debug, Clone)]
+pub struct EarlyStopArgs {
+    /// Classify after every increment of reads and stop reading a sample once
+    /// the top call is stable; the results record how many reads were used
+    #[arg(long)]
+    pub early_stop: bool,
+
+    /// Reads between early-stop checkpoints
+    #[arg(long, value_name = "READS", default_value_t = DEFAULT_EARLY_STOP_INCREMENT)]
+    pub early_stop_increment: usize,
+
+    /// Largest change in top-call confidence between checkpoints that counts as stable
+    #[arg(long, default_value_t = DEFAULT_EARLY_STOP_TOLERANCE)]
+    pub early_stop_tolerance: f64,
+
+    /// Consecutive stable checkpoints needed to stop
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_EARLY_STOP_CHECKS)]
+    pub early_stop_checks: usize,
+}
+
+impl EarlyStopArgs {
+    /// Set the processor's early-stop policy, if early stopping was requested
+    pub fn configure(&self, processor: &mut FastqProcessor) -> Result<(), ProcessingError> {
+        if !self.early_stop {
+            return Ok(());
+        }
+        if self.early_stop_increment == 0 || self.early_stop_checks == 0 {
+            return Err(ProcessingError::InvalidParameters(
+                "--early-stop-increment and --early-stop-checks must be positive".to_string(),
+            ));
+        }
+        processor.early_stop = Some(EarlyStopPolicy {
+            increment_reads: self.early_stop_increment,
+            tolerance: self.early_stop_tolerance,
+            stable_checks: self.early_stop_checks,
+        });
+        Ok(())
+    }
+}
+
+/// Read batching options
+#[derive(Args, Debug, Clone)]
+pub struct BatchArgs {
+    /// Reads per batch handed to the worker threads (by default sized from
+    /// the mean length of each file's first reads to fit --batch-memory)
+    #[arg(long, value_name = "READS")]
+    pub chunk_size: Option<usize>,
+
+    /// Memory the read batches of a file may take together, in MiB
+    #[arg(long, value_name = "MIB", default_value_t = DEFAULT_BATCH_MEMORY_BYTES >> 20)]
+    pub batch_memory: usize,
+}
+
+impl BatchArgs {
+    /// Set the processor's batch size, or its memory budget for sizing batches
+    pub fn configure(&self, processor: &mut FastqProcessor) -> Result<(), ProcessingError> {
+        if self.chunk_size == Some(0) || self.batch_memory == 0 {
+            return Err(ProcessingError::InvalidParameters(
+                "--chunk-size and --batch-memory must be positive".to_string(),
+            ));
+        }
+        processor.chunk_size = self.chunk_size;
+        processor.batch_memory_bytes = self.batch_memory << 20;
+        Ok(())
+    }
+}
+
+/// Alignment confirmation options
+#[derive(Args, Debug, Clone)]
+pub struct ConfirmArgs {
+    /// Align the reads to the references of the top strain calls with
+    /// minimap2 and report coverage breadth and depth
+    #[arg(long)]
+    pub confirm: bool,
+
+    /// minimap2 executable used for confirmation
+    #[arg(long, value_name = "PATH", default_value = "minimap2")]
+    pub aligner: PathBuf,
+
+    /// minimap2 preset (e.g. sr for short reads, map-ont or map-hifi for long reads)
+    #[arg(long, default_value = "sr")]
+    pub aligner_preset: String,
+
+    /// Number of top strain calls to confirm
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_CONFIRM_TOP)]
+    pub confirm_top: usize,
+
+    /// Share of a reference the reads must cover for its call to count as confirmed
+    #[arg(long, default_value_t = DEFAULT_MIN_BREADTH)]
+    pub confirm_min_breadth: f64,
+}
+
+impl ConfirmArgs {
+    /// Enable confirmation on the processor, checking that the aligner runs
+    pub fn configure(&self, processor: &mut FastqProcessor) -> Result<(), ProcessingError> {
+        if !self.confirm {
+            return Ok(());
+        }
+        if !(0.0..=1.0).contains(&self.confirm_min_breadth) {
+            return Err(ProcessingError::InvalidParameters(
+                "--confirm-min-breadth must be between 0 and 1".to_string(),
+            ));
+        }
+        let config = AlignerConfig {
+            executable: self.aligner.clone(),
+            preset: self.aligner_preset.clone(),
+            threads: processor.threads,
+            top_calls: self.confirm_top,
+            min_breadth: self.confirm_min_breadth,
+        };
+        let version = ReadAligner::new(config.clone()).version()?;
+        info!(
+            "Confirming top calls with {} {}",
+            self.aligner.display(),
+            version
+        );
+        processor.confirmation = Some(config);
+        Ok(())
+    }
+}
+
+/// Reproducibility options for validated pipelines
+#[derive(Args, Debug, Clone, Default)]
+pub struct StrictArgs {
+    /// Refuse to run unless the config pins the database digest, no adaptive
+    /// setting (such as --early-stop) is used and the environment matches the
+    /// config's `strict` pins
+    #[arg(long)]
+    pub strict: bool,
+}
+
+impl StrictArgs {
+    /// Check a configured processor against the config's pins, if `--strict` was given
+    pub fn configure(
+        &self,
+        config: &Config,
+        processor: &mut FastqProcessor,
+    ) -> Result<(), ProcessingError> {
+        if !self.strict {
+            return Ok(());
+        }
+        let aligner_version = match &processor.confirmation {
+            Some(aligner) => Some(ReadAligner::new(aligner.clone()).version()?),
+            None => None,
+        };
+        strict::enforce(strict::check_settings(
+            config,
+            processor.early_stop.is_some(),
+            aligner_version.as_deref(),
+        ))?;
+        processor.strict = Some(config.strict.clone());
+        Ok(())
+    }
+}
+
+/// Contamination screening options
+#[derive(Args, Debug, Clone, Default)]
+pub struct ContaminationArgs {
+    /// Extra control panel as NAME=FASTA (e.g. human=GRCh38.fa.gz, vector=UniVec.fa;
+    /// phix=FILE replaces the downloaded PhiX genome); reads matching it are counted in
+    /// the metrics. Repeat for several panels.
+    #[arg(long, value_name = "NAME=FILE")]
+    pub control_panel: Vec<String>,
+
+    /// Remove reads matching this control panel (e.g. phix) before sketching. Repeatable.
+    #[arg(long, value_name = "NAME")]
+    pub remove_control: Vec<String>,
+
+    /// Write each sample's reads left after QC, rRNA and control removal to
+    /// `DIR/<sample>.filtered.fastq.gz` (`.fasta.gz` for FASTA input)
+    #[arg(long, value_name = "DIR")]
+    pub write_filtered_reads: Option<PathBuf>,
+
+    /// Skip the default adapter and PhiX panels
+    #[arg(long)]
+    pub no_builtin_controls: bool,
+
+    /// Share of reads matching a control panel above which a sample gets a warning
+    #[arg(long, default_value_t = DEFAULT_MAX_CONTAMINATION_FRACTION)]
+    pub max_contamination: f64,
+}
+
+impl ContaminationArgs {
+    /// Load the control panels the processor screens reads against; the PhiX
+    /// genome of the default `phix` panel is cached in `cache_dir`
+    pub fn configure(
+        &self,
+        cache_dir: &Path,
+        processor: &mut FastqProcessor,
+    ) -> Result<(), ProcessingError> {
+        let mut panels = if self.no_builtin_controls {
+            Vec::new()
+        } else {
+            ControlPanel::builtin()
+        };
+        let phix_given = self
+            .control_panel
+            .iter()
+            .any(|spec| spec.split_once('=').is_some_and(|(name, _)| name == "phix"));
+        if !self.no_builtin_controls && !phix_given {
+            match ControlPanel::phix(cache_dir) {
+                Ok(panel) => panels.push(panel),
+                Err(e) => warn!(
+                    "PhiX control panel unavailable ({}); PhiX reads are not screened. \
+                     Pass --control-panel phix={}.fasta to use a local copy",
+                    e, PHIX_ACCESSION
+                ),
+            }
+        }
+        for spec in &self.control_panel {
+            let (name, path) = spec.split_once('=').ok_or_else(|| {
+                ProcessingError::InvalidParameters(format!(
+                    "--control-panel expects NAME=FILE, got '{}'",
+                    spec
+                ))
+            })?;
+            let panel = ControlPanel::from_fasta(name, Path::new(path), DEFAULT_CONTROL_KMER_SIZE)?;
+            info!(
+                "Loaded control panel {} from {} ({} k-mers)",
+                name,
+                path,
+                panel.len()
+            );
+            panels.push(panel);
+        }
+        for name in &self.remove_control {
+            let panel = panels
+                .iter_mut()
+                .find(|panel| &panel.name == name)
+                .ok_or_else(|| {
+                    ProcessingError::InvalidParameters(format!(
+                        "--remove-control {}: no such control panel (load it with \
+                         --control-panel {}=FILE)",
+                        name, name
+                    ))
+                })?;
+            panel.remove = true;
+        }
+        processor.control_panels = panels;
+        processor.max_contamination_fraction = self.max_contamination;
+        processor.filtered_reads_dir = self.write_filtered_reads.clone();
+        Ok(())
+    }
+}
+
+/// Watchlist alerting options
+#[derive(Args, Debug, Clone, Default)]
+pub struct WatchlistArgs {
+    /// Taxa of concern: one taxid, accession or lineage name per line (optionally `id<TAB>name`)
+    #[arg(long, value_name = "FILE")]
+    pub watchlist: Option<PathBuf>,
+
+    /// Minimum classification confidence for a watchlist alert
+    #[arg(long, default_value_t = DEFAULT_WATCHLIST_MIN_CONFIDENCE, requires = "watchlist")]
+    pub watchlist_min_confidence: f64,
+
+    /// Minimum relative strain abundance for a watchlist alert
+    #[arg(long, default_value_t = DEFAULT_WATCHLIST_MIN_ABUNDANCE, requires = "watchlist")]
+    pub watchlist_min_abundance: f64,
+}
+
+impl WatchlistArgs {
+    /// Load the watchlist, if `--watchlist` was given, with its names and
+    /// taxids resolved through `taxonomy`, if given
+    pub fn load(&self, taxonomy: Option<&Taxonomy>) -> anyhow::Result<Option<Watchlist>> {
+        let Some(path) = &self.watchlist else {
+            return Ok(None);
+        };
+        let mut watchlist = Watchlist::from_file(path)?;
+        if let Some(taxonomy) = taxonomy {
+            watchlist.resolve_names(taxonomy)?;
+        }
+        watchlist.min_confidence = self.watchlist_min_confidence;
+        watchlist.min_abundance = self.watchlist_min_abundance;
+        info!(
+            "Screening samples against {} watchlist taxa from {}",
+            watchlist.len(),
+            path.display()
+        );
+        Ok(Some(watchlist))
+    }
+}
+
+/// Sample ID to use in outputs: its pseudonym when anonymizing, itself otherwise.
+/// The mapping file is updated immediately so it never lags behind written outputs.
+fn output_sample_id(
+    anonymizer: &mut Option<SampleAnonymizer>,
+    sample_id: &str,
+) -> anyhow::Result<String> {
+    match anonymizer {
+        Some(anonymizer) => {
+            let pseudonym = anonymizer.pseudonym(sample_id)?;
+            anonymizer.save()?;
+            Ok(pseudonym)
+        }
+        None => Ok(sample_id.to_string()),
+    }
+}
+
+/// Read QC options: a preset, with any individually given flag taking precedence
+#[derive(Args, Debug, Clone, Default)]
+pub struct QcArgs {
+    /// QC threshold preset to start from
+    #[arg(long, value_enum, default_value_t = QcPreset::Default)]
+    pub qc_preset: QcPreset,
+
+    /// Minimum average quality score for reads
+    #[arg(long)]
+    pub min_quality: Option<f64>,
+
+    /// Minimum read length after trimming
+    #[arg(long)]
+    pub min_length: Option<usize>,
+
+    /// Quality below which bases are trimmed
+    #[arg(long)]
+    pub trim_quality: Option<u8>,
+
+    /// Maximum percentage of N bases in a read
+    #[arg(long)]
+    pub max_n_percent: Option<f64>,
+
+    /// Quality trimming strategy
+    #[arg(long, value_enum)]
+    pub trimming: Option<TrimmingStrategy>,
+
+    /// Window length for sliding-window trimming
+    #[arg(long)]
+    pub trim_window: Option<usize>,
+
+    /// Minimum length of a 3' poly-G tail to trim (0 disables)
+    #[arg(long)]
+    pub poly_g_min_length: Option<usize>,
+
+    /// Minimum length of a 3' poly-A tail to trim (0 disables)
+    #[arg(long)]
+    pub poly_a_min_length: Option<usize>,
+}
+
+impl QcArgs {
+    /// Preset parameters with the given flags applied on top
+    pub fn params(&self) -> QualityControlParams {
+        let mut params = QualityControlParams::preset(self.qc_preset);
+        if let Some(min_quality) = self.min_quality {
+            params.min_avg_quality = min_quality;
+        }
+        if let Some(min_length) = self.min_length {
+            params.min_length = min_length;
+        }
+        if let Some(trim_quality) = self.trim_quality {
+            params.trim_quality = trim_quality;
+        }
+        if let Some(max_n_percent) = self.max_n_percent {
+            params.max_n_percent = max_n_percent;
+        }
+        if let Some(trimming) = self.trimming {
+            params.trimming = trimming;
+        }
+        if let Some(trim_window) = self.trim_window {
+            params.trim_window = trim_window;
+        }
+        if let Some(length) = self.poly_g_min_length {
+            params.poly_g_min_length = (length > 0).then_some(length);
+        }
+        if let Some(length) = self.poly_a_min_length {
+            params.poly_a_min_length = (length > 0).then_some(length);
+        }
+        params
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)] // Added Debug
 pub enum Commands {
     /// Process a FASTQ file to classify its contents
     ProcessFastq {
@@ -48,13 +776,8 @@ pub enum Commands {
         #[arg(short, long, default_value = "results", value_name = "DIR")]
         output: PathBuf,
 
-        /// Minimum average quality score for reads
-        #[arg(long, default_value_t = 20.0)]
-        min_quality: f64,
-
-        /// Minimum read length after trimming
-        #[arg(long, default_value_t = 50)]
-        min_length: usize,
+        #[command(flatten)]
+        qc: QcArgs,
     },
     /// Process multiple FASTQ files in a directory
     ProcessDir {
@@ -65,6 +788,9 @@ pub enum Commands {
         /// Path to the output directory
         #[arg(short, long, default_value = "results", value_name = "DIR")]
         output: PathBuf,
+
+        #[command(flatten)]
+        qc: QcArgs,
     },
     /// Visualization stuff
     Visualize {
@@ -80,13 +806,8 @@ pub enum Commands {
         #[arg(short, long, default_value = "results", value_name = "DIR")]
         output: PathBuf,
 
-        /// Minimum average quality score for reads
-        #[arg(long, default_value_t = 20.0)]
-        min_quality: f64,
-
-        /// Minimum read length after trimming
-        #[arg(long, default_value_t = 50)]
-        min_length: usize,
+        #[command(flatten)]
+        qc: QcArgs,
     },
     /// Multiple samples
     CompareSamples {
@@ -102,153 +823,601 @@ pub enum Commands {
         #[arg(short, long, default_value = "results", value_name = "DIR")]
         output: PathBuf,
 
-        /// Minimum average quality score for reads
-        #[arg(long, default_value_t = 20.0)]
-        min_quality: f64,
-
-        /// Minimum read length after trimming
-        #[arg(long, default_value_t = 50)]
-        min_length: usize,
+        #[command(flatten)]
+        qc: QcArgs,
     },
+    /// Write a text report for each `*_results.json` file in a directory
     GenerateSummaryReport {
         #[arg(short, long, default_value = "results", value_name = "DIR")]
         output: PathBuf,
+
+        /// Replace file paths with file names and remove the host name, for
+        /// reports shared outside the lab (also set by `report.redact` in the config)
+        #[arg(long)]
+        redact: bool,
     },
-}
+    /// Export results as phyloseq input tables (OTU, taxonomy and sample data CSVs)
+    ExportPhyloseq {
+        /// Directory containing `*_results.json` files
+        #[arg(short, long, default_value = "results", value_name = "DIR")]
+        results: PathBuf,
 
-/// Main entry point for CLI
-pub fn run_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
-    // Configure logging (example using env_logger) - add if you haven't
-    // env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+        /// Sample metadata CSV
+        #[arg(short, long, value_name = "FILE")]
+        metadata: Option<PathBuf>,
 
-    // Now you can access db_path, cache_dir etc. directly from cli *before* the match
+        /// Inline sample groups instead of a metadata file, e.g. "S1,S2:control;S3,S4:treated"
+        #[arg(long, value_name = "SPEC", conflicts_with = "metadata")]
+        groups: Option<String>,
 
-    match cli.command {
-        Commands::ProcessFastq {
-            fastq,
-            sample_id,
-            output,
-            min_quality,
-            min_length,
-        } => {
-            info!(
-                "Processing FASTQ file: {} with Sample ID: {}",
-                fastq.display(),
-                sample_id
-            );
+        /// Output directory for the phyloseq tables
+        #[arg(short, long, default_value = "phyloseq", value_name = "DIR")]
+        output: PathBuf,
 
-            // Create QC parameters with the correct type
-            let qc_params = QualityControlParams {
-                min_avg_quality: min_quality,
-                min_length,
-                trim_quality: 15,   // Example Default
-                max_n_percent: 5.0, // Example Default
-            };
-            info!("QC Parameters: {:?}", qc_params);
+        /// Leave samples flagged in `sample_qc.csv` out of the exported tables
+        #[arg(long)]
+        exclude_outliers: bool,
 
-            // Create FASTQ processor using the global args from `cli`
-            let mut processor = FastqProcessor::new(
-                &cli.db_path,        // Pass reference if needed by constructor
-                &cli.cache_dir,      // Pass reference if needed by constructor
-                cli.threads,         // Pass value
-                31,                  // Default macro_k - consider making these CLI args too?
-                21,                  // Default meso_k
-                1000,                // Default sketch_size
-                Some(qc_params),     // Pass specific QC params for this command
-                cli.api_key.clone(), // Clone Option<String> if needed
-            )?;
-            info!("FastqProcessor created.");
+        /// Merge technical replicates sharing a `biological_sample` in the metadata
+        #[arg(long, value_enum, value_name = "METHOD", requires = "metadata")]
+        collapse_replicates: Option<CollapseMethod>,
 
-            // Initialize classifier
-            processor.init_classifier()?;
-            info!("Classifier initialized.");
+        /// Also write lineage-based UniFrac distance matrices, e.g. `weighted,unweighted`
+        #[arg(long, value_enum, value_delimiter = ',', value_name = "KIND")]
+        unifrac: Vec<UniFrac>,
+    },
+    /// Normalize a count table CSV in two streaming passes, for tables too large to load
+    NormalizeTable {
+        /// Count table CSV (`Feature` column, then one column per sample)
+        #[arg(short, long, value_name = "FILE", required = true)]
+        input: PathBuf,
 
-            // Process FASTQ file
-            let results = processor.process_file(&fastq, &sample_id, &output)?; // Pass references
-            info!("File processing complete. Results: {:?}", results); // Example log
+        /// Normalized count table CSV to write
+        #[arg(short, long, value_name = "FILE", required = true)]
+        output: PathBuf,
 
-            // Generate and print report
-            // Ensure generate_report takes the correct type from process_file result
-            // let report = generate_report(&results)?;
-            // println!("{}", report);
-            println!("Processing finished. Results summary struct: {:?}", results);
-            // Placeholder report
-        }
-        Commands::ProcessDir { dir, output } => {
-            info!(
-                "Processing directory: {} into output: {}",
-                dir.display(),
-                output.display()
-            );
+        /// Normalization method: cpm or median-of-ratios
+        #[arg(short, long, default_value = "median-of-ratios")]
+        method: String,
 
-            // Create FASTQ processor using the global args from `cli`
-            let mut processor = FastqProcessor::new(
-                &cli.db_path,        // Pass reference
-                &cli.cache_dir,      // Pass reference
-                cli.threads,         // Pass value
-                31,                  // Default macro_k
-                21,                  // Default meso_k
-                1000,                // Default sketch_size
-                None, // No specific QC parameters for directory processing (uses defaults in processor)
-                cli.api_key.clone(), // Clone Option<String>
-            )?;
-            info!("FastqProcessor created for directory processing.");
+        /// Use these size factors (CSV with `sample` and `size_factor` columns) instead of computing them
+        #[arg(long, value_name = "FILE", conflicts_with = "method")]
+        size_factors: Option<PathBuf>,
 
-            // Initialize classifier
-            processor.init_classifier()?;
-            info!("Classifier initialized.");
+        /// Write the size factors used to this CSV
+        #[arg(long, value_name = "FILE")]
+        write_size_factors: Option<PathBuf>,
 
-            // Find all FASTQ files in the directory
-            let mut fastq_files = Vec::new();
-            for entry in std::fs::read_dir(&dir)? {
-                // Pass reference to dir
-                let entry = entry?;
-                let path = entry.path();
+        /// `wide` (same shape as the input) or `tidy`: one row per feature and
+        /// sample with the raw count, normalized value and size factor side by side
+        #[arg(long, value_enum, default_value_t = TableLayout::Wide)]
+        layout: TableLayout,
+    },
+    /// Merge count tables built against different database versions into one feature space
+    HarmonizeTables {
+        /// Count table CSVs to merge (`Feature` column, then one column per sample)
+        #[arg(short, long = "input", value_name = "FILE", required = true, num_args = 1..)]
+        inputs: Vec<PathBuf>,
 
-                // Improved check for fastq files (case-insensitive extensions)
-                if path.is_file() {
-                    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                        let lower_ext = ext.to_lowercase();
-                        if lower_ext == "fastq" || lower_ext == "fq" {
-                            fastq_files.push(path);
-                        } else if lower_ext == "gz" {
-                            // Check the part before .gz
-                            if let Some(stem) = path.file_stem() {
-                                if let Some(stem_str) = stem.to_str() {
-                                    let stem_path = PathBuf::from(stem_str);
-                                    if let Some(stem_ext) = stem_path.extension() {
-                                        if let Some(ext_str) = stem_ext.to_str() {
-                                            let lower_stem_ext = ext_str.to_lowercase();
-                                            if lower_stem_ext == "fastq" || lower_stem_ext == "fq" {
-                                                fastq_files.push(path);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        /// Old-to-current feature IDs (accessions or taxids), two tab- or comma-separated
+        /// columns; `-` as current ID marks a withdrawn reference
+        #[arg(short, long, value_name = "FILE", required = true)]
+        equivalences: PathBuf,
 
-            if fastq_files.is_empty() {
-                log::warn!(
-                    "No FASTQ files (.fastq, .fq, .fastq.gz, .fq.gz) found in directory: {}",
-                    dir.display()
-                );
-                return Ok(()); // Nothing to do
-            }
+        /// Merged count table CSV to write
+        #[arg(short, long, value_name = "FILE", required = true)]
+        output: PathBuf,
 
-            println!("Found {} FASTQ files to process.", fastq_files.len());
+        /// Drop features with no current ID instead of keeping them under their own ID
+        #[arg(long)]
+        drop_unmapped: bool,
 
-            // Process each FASTQ file
-            for (i, path) in fastq_files.iter().enumerate() {
-                // Generate sample ID from file stem more robustly
-                let sample_id = path
-                    .file_name() // Get full filename first
-                    .and_then(|name| name.to_str())
-                    .map(|name_str| {
-                        // Remove common fastq extensions
+        /// Precision the input counts are held in while merging; `f32` and `u32`
+        /// halve the memory, and `u32` accepts only whole counts
+        #[arg(long, value_enum, value_name = "TYPE", default_value_t = CountPrecision::F64)]
+        count_precision: CountPrecision,
+    },
+    /// Compare the composition of a run with a baseline run of the same source
+    Monitor {
+        /// Results of the earlier run (`<sample>_results.json`)
+        #[arg(long, value_name = "FILE", required = true)]
+        baseline: PathBuf,
+
+        /// Results of the run to check
+        #[arg(long, value_name = "FILE", required = true)]
+        current: PathBuf,
+
+        /// Drift report JSON to write
+        #[arg(short, long, default_value = "drift_report.json", value_name = "FILE")]
+        output: PathBuf,
+
+        /// Smallest change in a taxon's share (0-1) that is flagged
+        #[arg(long, default_value_t = DEFAULT_MIN_SHARE_CHANGE)]
+        min_share_change: f64,
+
+        /// Smallest fold change in a taxon's share that is flagged
+        #[arg(long, default_value_t = DEFAULT_MIN_FOLD_CHANGE)]
+        min_fold_change: f64,
+
+        /// Bray-Curtis dissimilarity above which the sample counts as drifted
+        #[arg(long, default_value_t = DEFAULT_MAX_DISTANCE)]
+        max_distance: f64,
+    },
+    /// Classify stored sample sketches again against the current database and
+    /// report the taxa added, removed or changed since their previous results
+    Reclassify {
+        /// Directory of sample sketches written with `--save-sketches`
+        #[arg(long, value_name = "DIR", required = true)]
+        sketches: PathBuf,
+
+        /// Directory of the samples' previous `*_results.json` files (defaults to `--output`)
+        #[arg(long, value_name = "DIR")]
+        previous: Option<PathBuf>,
+
+        /// Output directory for the new results and `<sample>_reclassify_diff.json` reports
+        #[arg(short, long, default_value = "results", value_name = "DIR")]
+        output: PathBuf,
+
+        /// Smallest change in a taxon's share (0-1) that is reported
+        #[arg(long, default_value_t = DEFAULT_MIN_SHARE_CHANGE)]
+        min_share_change: f64,
+
+        /// Smallest fold change in a taxon's share that is reported
+        #[arg(long, default_value_t = DEFAULT_MIN_FOLD_CHANGE)]
+        min_fold_change: f64,
+
+        /// Bray-Curtis dissimilarity above which the sample counts as changed
+        #[arg(long, default_value_t = DEFAULT_MAX_DISTANCE)]
+        max_distance: f64,
+    },
+    /// Export a fixed-length feature vector per stored sample sketch as a
+    /// NumPy array, for training sample-level classifiers
+    ExportFeatures {
+        /// Directory of sample sketches written with `--save-sketches`
+        #[arg(long, value_name = "DIR", required = true)]
+        sketches: PathBuf,
+
+        /// Features to compute: hashed sketch embedding or reference similarities
+        #[arg(long, value_enum, default_value_t = FeatureKind::Hashed)]
+        kind: FeatureKind,
+
+        /// Bins per resolution level of the hashed embedding
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_EMBEDDING_DIMENSIONS)]
+        dimensions: usize,
+
+        /// Closest references per sample kept as columns (0 keeps all)
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_TOP_REFERENCES)]
+        top_k: usize,
+
+        /// Output directory for `features.npy` and its `samples.csv` and
+        /// `features.csv` labels
+        #[arg(short, long, default_value = "features", value_name = "DIR")]
+        output: PathBuf,
+    },
+    /// Cross-validate how many of a species' reference strains each stored
+    /// sample sketch supports, scoring nested sets of its most abundant strains
+    SelectReferences {
+        /// Directory of sample sketches written with `--save-sketches`
+        #[arg(long, value_name = "DIR", required = true)]
+        sketches: PathBuf,
+
+        /// Species whose reference strains are the candidates
+        #[arg(long, value_name = "TAXON", required = true)]
+        species: String,
+
+        /// Cross-validation folds over the sketch hashes
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_FOLDS)]
+        folds: usize,
+
+        /// Random seed for the assignment of hashes to folds
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+
+        /// Held-out error CSV to write (one row per sample and candidate set)
+        #[arg(
+            short,
+            long,
+            default_value = "reference_selection.csv",
+            value_name = "FILE"
+        )]
+        output: PathBuf,
+    },
+    /// Fit the strains of a species to all stored sample sketches jointly,
+    /// giving study-level abundances and per-sample abundances that borrow
+    /// strength from the other samples
+    Hierarchical {
+        /// Directory of sample sketches written with `--save-sketches`
+        #[arg(long, value_name = "DIR", required = true)]
+        sketches: PathBuf,
+
+        /// Species whose reference strains are fitted
+        #[arg(long, value_name = "TAXON", required = true)]
+        species: String,
+
+        /// MCMC iterations
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_MCMC_ITERATIONS)]
+        iterations: usize,
+
+        /// Random seed for the sampler
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+
+        /// Study-level abundance CSV to write
+        #[arg(
+            short,
+            long,
+            default_value = "hierarchical_abundances.csv",
+            value_name = "FILE"
+        )]
+        output: PathBuf,
+
+        /// Per-sample abundance CSV to write
+        #[arg(long, default_value = "hierarchical_samples.csv", value_name = "FILE")]
+        samples: PathBuf,
+    },
+    /// Compare strain abundances between two conditions, pooling over the
+    /// samples' posterior draws (`--posterior-draws`) where the results have them
+    Differential {
+        /// Directory containing `*_results.json` files
+        #[arg(short, long, default_value = "results", value_name = "DIR")]
+        results: PathBuf,
+
+        /// Sample metadata CSV (`sample_id`, `condition`, `replicate` columns)
+        #[arg(short, long, value_name = "FILE", required_unless_present = "groups")]
+        metadata: Option<PathBuf>,
+
+        /// Inline sample groups instead of a metadata file, e.g. "S1,S2:control;S3,S4:treated"
+        #[arg(long, value_name = "SPEC", conflicts_with = "metadata")]
+        groups: Option<String>,
+
+        /// Condition the fold changes are relative to
+        #[arg(long, required = true)]
+        reference: String,
+
+        /// Condition compared with the reference
+        #[arg(long, required = true)]
+        treatment: String,
+
+        /// Posterior abundance tables to analyse and pool
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_IMPUTATIONS)]
+        imputations: usize,
+
+        /// Treat the abundances as exact and ignore any posterior draws
+        #[arg(long)]
+        point_estimates: bool,
+
+        /// Leave samples flagged as outliers out of the comparison
+        #[arg(long)]
+        exclude_outliers: bool,
+
+        /// Merge technical replicates sharing a `biological_sample` in the metadata
+        #[arg(long, value_enum, value_name = "METHOD")]
+        collapse_replicates: Option<CollapseMethod>,
+
+        /// Per-strain results CSV to write
+        #[arg(
+            short,
+            long,
+            default_value = "differential_results.csv",
+            value_name = "FILE"
+        )]
+        output: PathBuf,
+    },
+    /// Meta-analyse differential abundance results of several studies
+    Meta {
+        /// Differential results CSVs, one per study (`feature_id`, `log2_fold_change`,
+        /// `std_error` columns)
+        #[arg(short, long, value_name = "FILE", required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+
+        /// Per-feature fixed- and random-effects results CSV to write
+        #[arg(short, long, default_value = "meta_results.csv", value_name = "FILE")]
+        output: PathBuf,
+
+        /// Forest-plot table CSV to write (one row per study and per pooled estimate)
+        #[arg(long, default_value = "forest_plot.csv", value_name = "FILE")]
+        forest: PathBuf,
+
+        /// Number of studies a feature must be tested in to be pooled
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_MIN_STUDIES)]
+        min_studies: usize,
+
+        /// Also write a methods paragraph describing the meta-analysis to this file
+        #[arg(long, value_name = "FILE")]
+        methods: Option<PathBuf>,
+    },
+    /// Simulate detection power versus samples per group from pilot dispersions
+    Power {
+        /// Pilot raw count table CSV (features x samples, at least two samples)
+        #[arg(short, long, value_name = "FILE")]
+        pilot: PathBuf,
+
+        /// Fold change between groups to detect
+        #[arg(long, default_value_t = 2.0)]
+        effect_size: f64,
+
+        /// Per-feature significance level
+        #[arg(long, default_value_t = 0.05)]
+        alpha: f64,
+
+        /// Samples per group to evaluate, comma-separated
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "2,3,4,5,6,8,10,15,20",
+            value_name = "N,..."
+        )]
+        sample_sizes: Vec<usize>,
+
+        /// Simulated experiments per sample size
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_SIMULATIONS)]
+        simulations: usize,
+
+        /// Power at which a sample size is recommended
+        #[arg(long, default_value_t = DEFAULT_TARGET_POWER)]
+        target_power: f64,
+
+        /// Random seed for the simulations
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+
+        /// Power curve CSV to write
+        #[arg(short, long, default_value = "power.csv", value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// List references suggested by unassigned sample content and optionally add them
+    SuggestReferences {
+        /// Directory containing `*_results.json` files
+        #[arg(short, long, default_value = "results", value_name = "DIR")]
+        results: PathBuf,
+
+        /// Download and add the suggested references (asks for confirmation per query)
+        #[arg(long)]
+        add: bool,
+
+        /// Do not ask for confirmation when adding
+        #[arg(long, requires = "add")]
+        yes: bool,
+
+        /// Maximum number of genomes to add per suggested query
+        #[arg(long, default_value_t = 5)]
+        max_refs: usize,
+    },
+    /// Check the database, cache, resources and optional tools, and classify toy
+    /// reads, printing a pass/fail table to attach to support requests
+    Doctor {
+        /// Also check that NCBI can be reached
+        #[arg(long)]
+        network: bool,
+
+        /// Leave out the end-to-end test on generated toy data
+        #[arg(long)]
+        skip_self_test: bool,
+    },
+}
+
+/// Load every `*_results.json` file in a directory (also gzip- or
+/// zstd-compressed), sorted by sample ID.
+/// `results_file` is set to the path each result was loaded from. Files that
+/// are not results are skipped with a warning; results of a format version
+/// this build cannot read are an error.
+pub(crate) fn load_results_dir(
+    dir: &Path,
+) -> Result<Vec<ClassificationResults>, Box<dyn std::error::Error>> {
+    let mut results = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_results_file = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| has_json_suffix(name, "_results.json"));
+        if !is_results_file {
+            continue;
+        }
+        match read_versioned_json::<ClassificationResults>(Artifact::Results, &path) {
+            Ok(mut result) => {
+                result.results_file = Some(path);
+                results.push(result);
+            }
+            Err(ArtifactError::Json(e)) => {
+                warn!("Skipping {}: not a results file ({})", path.display(), e)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    results.sort_by(|a, b| a.sample_id.cmp(&b.sample_id));
+    Ok(results)
+}
+
+/// Abundance posteriors of the samples for `differential`: their posterior
+/// draws, or their strain abundances as exact values when they have none or
+/// `point_estimates` is set
+fn sample_posteriors(
+    samples: &[ClassificationResults],
+    point_estimates: bool,
+) -> Vec<AbundancePosterior> {
+    samples
+        .iter()
+        .map(|sample| match &sample.posterior_draws {
+            Some(draws) if !point_estimates && !draws.draws.is_empty() => {
+                AbundancePosterior::from_draws(&sample.sample_id, draws)
+            }
+            _ => {
+                let abundances: HashMap<String, f64> = sample
+                    .strain_abundances
+                    .iter()
+                    .map(|(id, (abundance, _))| (id.clone(), *abundance))
+                    .collect();
+                AbundancePosterior::point_estimate(&sample.sample_id, &abundances)
+            }
+        })
+        .collect()
+}
+
+/// A processor set up from the global options, with `qc_params` (the
+/// defaults if `None`) and its classifier loaded
+fn configure_processor(
+    cli: &Cli,
+    config: &Config,
+    telemetry: &Telemetry,
+    qc_params: Option<QualityControlParams>,
+) -> Result<FastqProcessor, Box<dyn std::error::Error>> {
+    let mut processor = FastqProcessor::with_config(
+        &cli.db_path,
+        &cli.cache_dir,
+        cli.threads,
+        31,   // Default macro_k
+        21,   // Default meso_k
+        1000, // Default sketch_size
+        qc_params,
+        cli.api_key.clone(),
+        &config.database,
+    )?;
+    info!("FastqProcessor created.");
+
+    processor.redact_input_names = cli.anonymize.anonymize;
+    cli.estimators.configure(&mut processor)?;
+    cli.early_stop.configure(&mut processor)?;
+    cli.batches.configure(&mut processor)?;
+    cli.confirm.configure(&mut processor)?;
+    processor.ani_thresholds = cli.ani_thresholds.clone();
+    processor.on_degenerate_input = cli.on_degenerate_input;
+    processor.min_classified_reads = cli.min_classified_reads;
+    processor.debug_classification = cli.debug_classification;
+    processor.export_scores = cli.export_scores;
+    processor.max_hash_frequency = cli.max_hash_frequency;
+    processor.idf_weighting = cli.idf_weighting;
+    processor.json_compression = cli.compress_json;
+    processor.telemetry = telemetry.clone();
+    processor.sketch_dir = cli.save_sketches.clone();
+    cli.rna.configure(&mut processor)?;
+    let taxonomy = cli
+        .taxonomy
+        .as_deref()
+        .map(|source| Taxonomy::load_cached(source, &cli.cache_dir))
+        .transpose()?;
+    processor.watchlist = cli.watchlist.load(taxonomy.as_ref())?;
+    processor.reference_advisor.taxonomy = taxonomy;
+    cli.contamination
+        .configure(&cli.cache_dir, &mut processor)?;
+    cli.strict.configure(config, &mut processor)?;
+    processor.init_classifier()?;
+    info!("Classifier initialized.");
+    Ok(processor)
+}
+
+/// Main entry point for CLI
+pub fn run_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    // Configure logging (example using env_logger) - add if you haven't
+    // env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    // Now you can access db_path, cache_dir etc. directly from cli *before* the match
+    let invocation = invocation(&cli);
+    let config = Config::load(cli.config.as_deref())?;
+    // Exporters flush when the last handle is dropped, as the command returns
+    let telemetry = Telemetry::init(&config.telemetry)?;
+
+    // Cloned so that handlers can still pass the whole `cli` on
+    match cli.command.clone() {
+        Commands::ProcessFastq {
+            fastq,
+            sample_id,
+            output,
+            qc,
+        } => {
+            let mut anonymizer = cli.anonymize.open(&output)?;
+            let sample_id = output_sample_id(&mut anonymizer, &sample_id)?;
+            info!(
+                "Processing FASTQ file: {} with Sample ID: {}",
+                fastq.display(),
+                sample_id
+            );
+
+            let qc_params = qc.params();
+            info!("QC Parameters: {:?}", qc_params);
+
+            let processor = configure_processor(&cli, &config, &telemetry, Some(qc_params))?;
+
+            // Process FASTQ file
+            let results = processor.process_file(&fastq, &sample_id, &output)?; // Pass references
+            info!("File processing complete. Results: {:?}", results); // Example log
+
+            // Generate and print report
+            // Ensure generate_report takes the correct type from process_file result
+            // let report = generate_report(&results)?;
+            // println!("{}", report);
+            let mut summary = RunSummary::new("process-fastq", invocation);
+            summary.add_sample(&results);
+            summary.suggest_for_results(&output, std::slice::from_ref(&results));
+            println!("{}", summary);
+        }
+        Commands::ProcessDir { dir, output, qc } => {
+            info!(
+                "Processing directory: {} into output: {}",
+                dir.display(),
+                output.display()
+            );
+
+            let qc_params = qc.params();
+            info!("QC Parameters: {:?}", qc_params);
+            let processor = configure_processor(&cli, &config, &telemetry, Some(qc_params))?;
+
+            // Find all FASTQ files in the directory
+            let mut fastq_files = Vec::new();
+            for entry in std::fs::read_dir(&dir)? {
+                // Pass reference to dir
+                let entry = entry?;
+                let path = entry.path();
+
+                // Improved check for fastq files (case-insensitive extensions)
+                if path.is_file() {
+                    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+                        let lower_ext = ext.to_lowercase();
+                        if lower_ext == "fastq" || lower_ext == "fq" {
+                            fastq_files.push(path);
+                        } else if lower_ext == "gz" {
+                            // Check the part before .gz
+                            if let Some(stem) = path.file_stem() {
+                                if let Some(stem_str) = stem.to_str() {
+                                    let stem_path = PathBuf::from(stem_str);
+                                    if let Some(stem_ext) = stem_path.extension() {
+                                        if let Some(ext_str) = stem_ext.to_str() {
+                                            let lower_stem_ext = ext_str.to_lowercase();
+                                            if lower_stem_ext == "fastq" || lower_stem_ext == "fq" {
+                                                fastq_files.push(path);
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            if fastq_files.is_empty() {
+                let message = format!(
+                    "No FASTQ files (.fastq, .fq, .fastq.gz, .fq.gz) found in directory: {}",
+                    dir.display()
+                );
+                if cli.on_degenerate_input == DegenerateInputPolicy::Error {
+                    return Err(message.into());
+                }
+                log::warn!("{}", message);
+                return Ok(()); // Nothing to do
+            }
+
+            println!("Found {} FASTQ files to process.", fastq_files.len());
+            let mut anonymizer = cli.anonymize.open(&output)?;
+
+            let mut sample_ids = Vec::with_capacity(fastq_files.len());
+            for (i, path) in fastq_files.iter().enumerate() {
+                // Generate sample ID from file stem more robustly
+                let sample_id = path
+                    .file_name() // Get full filename first
+                    .and_then(|name| name.to_str())
+                    .map(|name_str| {
+                        // Remove common fastq extensions
                         name_str
                             .trim_end_matches(".gz")
                             .trim_end_matches(".fastq")
@@ -256,7 +1425,36 @@ pub fn run_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
                             .to_string()
                     })
                     .unwrap_or_else(|| format!("sample_{}", i + 1)); // Fallback ID
+                sample_ids.push(output_sample_id(&mut anonymizer, &sample_id)?);
+            }
+            // Samples whose output files are the same file would overwrite each other
+            let case_insensitive = is_case_insensitive(&output)?;
+            let collisions = colliding_file_names(sample_ids.iter().map(String::as_str), true);
+            for group in &collisions {
+                let message = format!("Samples {} share output file names", group.join(", "));
+                let same_case = group
+                    .iter()
+                    .map(|id| safe_file_name(id))
+                    .collect::<BTreeSet<_>>();
+                if case_insensitive || same_case.len() < group.len() {
+                    return Err(format!(
+                        "{} in {}; rename the input files",
+                        message,
+                        output.display()
+                    )
+                    .into());
+                }
+                warn!(
+                    "{} on case-insensitive file systems (Windows, macOS)",
+                    message
+                );
+            }
 
+            // A failing sample is recorded and skipped; the run fails only at the end
+            let mut batch = BatchSummary::new("process-dir");
+            let mut summary = RunSummary::new("process-dir", invocation);
+            let mut processed = Vec::new();
+            for (i, (path, sample_id)) in fastq_files.iter().zip(sample_ids).enumerate() {
                 println!(
                     "Processing file {}/{}: {} (Sample ID: {})",
                     i + 1,
@@ -265,8 +1463,25 @@ pub fn run_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
                     sample_id
                 );
 
-                // Ensure paths are passed as references
-                match processor.process_file(path, &sample_id, &output) {
+                // A panic on malformed input costs this sample, not the batch
+                let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    processor.process_file(path, &sample_id, &output)
+                }));
+                let outcome = match outcome {
+                    Ok(outcome) => outcome,
+                    Err(panic) => {
+                        let reason = panic
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_else(|| "unknown panic".to_string());
+                        eprintln!("Error processing {}: {}", path.display(), reason);
+                        summary.sample_failed(&sample_id, &reason);
+                        batch.failed_with(&sample_id, path, SampleStatus::Failed, reason);
+                        continue;
+                    }
+                };
+                match outcome {
                     Ok(results) => {
                         println!(
                             "Processed '{}' successfully. Results file: {}",
@@ -278,108 +1493,1239 @@ pub fn run_cli(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
                                 .map(|p| p.display().to_string())
                                 .unwrap_or_else(|| "N/A".to_string())
                         );
+                        summary.add_sample(&results);
+                        batch.succeeded(path, &results);
+                        processed.push(results);
                     }
                     Err(e) => {
                         eprintln!("Error processing {}: {}", path.display(), e);
-                        // Decide if you want to continue processing other files or stop
-                        // continue; // Example: continue to next file on error
+                        summary.sample_failed(&sample_id, &e);
+                        batch.failed(&sample_id, path, &e);
                     }
                 }
             }
 
             println!("Finished processing {} FASTQ files.", fastq_files.len());
+            let batch_path = batch.write(&output)?;
+            summary.output("batch summary", &batch_path);
+            summary.suggest_for_results(&output, &processed);
+            println!("{}", summary);
+            batch.check(&batch_path)?;
         }
         Commands::Visualize {
             fastq,
             sample_id,
             output,
-            min_quality,
-            min_length,
+            qc,
         } => {
+            let mut anonymizer = cli.anonymize.open(&output)?;
+            let sample_id = output_sample_id(&mut anonymizer, &sample_id)?;
             info!("Generating visualizations for sample: {}", sample_id);
 
-            let qc_params = QualityControlParams {
-                min_avg_quality: min_quality,
-                min_length,
-                trim_quality: 15,
-                max_n_percent: 5.0,
-            };
-
-            let mut processor = FastqProcessor::new(
-                &cli.db_path,
-                &cli.cache_dir,
-                cli.threads,
-                31,
-                21,
-                1000,
-                Some(qc_params),
-                cli.api_key.clone(),
-            )?;
-
-            // Initialize and process
-            processor.init_classifier()?;
+            let processor = configure_processor(&cli, &config, &telemetry, Some(qc.params()))?;
             let results = processor.process_file(&fastq, &sample_id, &output)?;
 
-            // Generate visualizations
-            processor.generate_quality_plots(&results, &output)?;
-            processor.generate_taxonomy_plots(&results, &output)?;
+            processor.generate_visualizations(&results, &output)?;
 
             println!("Visualizations generated in: {}", output.display());
+            let mut summary = RunSummary::new("visualize", invocation);
+            summary.add_sample(&results);
+            summary.output("plots", &output);
+            println!("{}", summary);
         }
         Commands::CompareSamples {
             fastq,
             sample_id,
             output,
-            min_quality,
-            min_length,
+            qc,
         } => {
+            let mut anonymizer = cli.anonymize.open(&output)?;
+            let sample_id = output_sample_id(&mut anonymizer, &sample_id)?;
             info!("Comparing sample {} with existing samples", sample_id);
-            let qc_params = QualityControlParams {
-                min_avg_quality: min_quality,
-                min_length,
-                trim_quality: 15,
-                max_n_percent: 5.0,
-            };
-            let mut processor = FastqProcessor::new(
-                &cli.db_path,
-                &cli.cache_dir,
-                cli.threads,
-                31,
-                21,
-                1000,
-                Some(qc_params),
-                cli.api_key.clone(),
-            )?;
-            processor.init_classifier()?;
+            let processor = configure_processor(&cli, &config, &telemetry, Some(qc.params()))?;
             let new_results = processor.process_file(&fastq, &sample_id, &output)?;
             let comparison_results = processor.process_file(&fastq, &sample_id, &output)?;
             println!(
                 "Sample comparison complete. Results in: {}",
                 output.display()
             );
+            let mut summary = RunSummary::new("compare-samples", invocation);
+            summary.add_sample(&new_results);
+            summary.suggest_for_results(&output, std::slice::from_ref(&new_results));
+            println!("{}", summary);
         }
-        Commands::GenerateSummaryReport { output } => {
+        Commands::GenerateSummaryReport { output, redact } => {
             info!("Generating summary report in: {}", output.display());
+            let mut report_config = config.report.clone();
+            report_config.redact |= redact;
+
+            let samples = load_results_dir(&output)?;
+            if samples.is_empty() {
+                println!("No result files found in: {}", output.display());
+                return Ok(());
+            }
+
+            let mut summary = RunSummary::new("generate-summary-report", invocation);
+            for results in &samples {
+                let report = generate_report_with(results, &report_config)?;
+                let report_path = sample_file(&output, &results.sample_id, "_report.txt");
+                std::fs::write(&report_path, report)?;
+                summary.add_sample(results);
+                summary.output("text report", &report_path);
+            }
+            println!("{}", summary);
+        }
+        Commands::ExportPhyloseq {
+            results,
+            metadata,
+            groups,
+            output,
+            exclude_outliers,
+            collapse_replicates: collapse_method,
+            unifrac,
+        } => {
+            let samples = load_results_dir(&results)?;
+            if samples.is_empty() {
+                println!("No result files found in: {}", results.display());
+                return Ok(());
+            }
+            for warning in check_run_consistency(&samples) {
+                eprintln!("WARNING: {}", warning);
+            }
+            let database = SignatureDatabase::open_with_config(&cli.db_path, &config.database)?;
+            // Results written with --anonymize already carry pseudonyms and map to themselves
+            let mut anonymizer = cli.anonymize.open(&output)?;
+
+            // Estimated read counts per reference: strain abundance x reads passing QC.
+            // Samples without strain estimates contribute their top classification.
+            // Merged runs of a sample add up their reads.
+            let ids: Vec<String> = samples.iter().map(|s| s.sample_id.clone()).collect();
+            let ids = cli
+                .duplicate_samples
+                .sample_ids(&ids, &format!("Results directory {}", results.display()))?;
+            let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+            let mut lineages: HashMap<String, Vec<String>> = HashMap::new();
+            // Read-weighted sum of assigned fractions, and the weight
+            let mut assigned: HashMap<String, (f64, f64)> = HashMap::new();
+            for (sample, sample_id) in samples.iter().zip(&ids) {
+                let sample_id = output_sample_id(&mut anonymizer, sample_id)?;
+                let reads = sample.metrics.passed_reads as f64;
+                if let Some(fraction) = sample.assigned_fraction {
+                    let (sum, weight) = assigned.entry(sample_id.clone()).or_default();
+                    *sum += fraction * reads.max(1.0);
+                    *weight += reads.max(1.0);
+                }
+                let counts = data.entry(sample_id).or_default();
+                if sample.strain_abundances.is_empty() {
+                    if let Some(top) = sample.classifications.first() {
+                        *counts.entry(top.taxon_id.clone()).or_default() += reads;
+                        lineages
+                            .entry(top.taxon_id.clone())
+                            .or_insert_with(|| top.lineage.clone());
+                    }
+                    continue;
+                }
+                for (strain_id, (abundance, _)) in &sample.strain_abundances {
+                    *counts.entry(strain_id.clone()).or_default() += (abundance * reads).round();
+                    if !lineages.contains_key(strain_id) {
+                        match database.get_signature(strain_id) {
+                            Ok(signature) => {
+                                lineages.insert(strain_id.clone(), signature.lineage);
+                            }
+                            Err(e) => warn!("No lineage for {}: {}", strain_id, e),
+                        }
+                    }
+                }
+                // Mass no strain explains stays with the species
+                if let (Some(reconciliation), Some(top)) =
+                    (&sample.reconciliation, sample.classifications.first())
+                {
+                    let unresolved = (reconciliation.unresolved_fraction * reads).round();
+                    if unresolved > 0.0 {
+                        *counts.entry(top.taxon_id.clone()).or_default() += unresolved;
+                        lineages
+                            .entry(top.taxon_id.clone())
+                            .or_insert_with(|| top.lineage.clone());
+                    }
+                }
+            }
 
-            // Find all result files in the output directory
-            let result_files: Vec<PathBuf> = std::fs::read_dir(&output)?
-                .filter_map(|entry| entry.ok())
-                .filter(|entry| {
-                    entry
-                        .path()
-                        .extension()
-                        .and_then(|ext| ext.to_str())
-                        .map_or(false, |ext| ext == "json")
+            let assigned_fractions: HashMap<String, f64> = assigned
+                .into_iter()
+                .map(|(sample_id, (sum, weight))| (sample_id, sum / weight))
+                .collect();
+
+            let mut table = CountTable::build_from_data(&data)?;
+            let sample_qc =
+                assess_samples(&table, &assigned_fractions, &OutlierThresholds::default());
+            let mut excluded_samples = BTreeMap::new();
+            for sample in sample_qc.iter().filter(|sample| sample.is_outlier()) {
+                eprintln!(
+                    "WARNING: sample {} is an outlier: {}",
+                    sample.sample_id,
+                    sample.reasons.join("; ")
+                );
+                if exclude_outliers {
+                    data.remove(&sample.sample_id);
+                    excluded_samples.insert(sample.sample_id.clone(), sample.reasons.join("; "));
+                }
+            }
+            if !excluded_samples.is_empty() {
+                table = CountTable::build_from_data(&data)?;
+            }
+            let metadata = match (metadata, groups) {
+                (Some(path), _) => Some(Metadata::from_file(
+                    &path.to_string_lossy(),
+                    cli.duplicate_samples,
+                )?),
+                (None, Some(spec)) => Some(Metadata::from_groups(&spec)?),
+                (None, None) => None,
+            };
+            let metadata = match metadata {
+                Some(mut metadata) => {
+                    if anonymizer.is_some() {
+                        let mut sample_info = HashMap::new();
+                        for (sample_id, mut info) in metadata.sample_info.drain() {
+                            if let Some(biological) = info.biological_sample.take() {
+                                info.biological_sample =
+                                    Some(output_sample_id(&mut anonymizer, &biological)?);
+                            }
+                            sample_info
+                                .insert(output_sample_id(&mut anonymizer, &sample_id)?, info);
+                        }
+                        metadata.sample_info = sample_info;
+                    }
+                    metadata
+                        .sample_info
+                        .retain(|sample_id, _| !excluded_samples.contains_key(sample_id));
+                    Some(metadata)
+                }
+                None => None,
+            };
+
+            // Technical replicates become one column per biological sample
+            let (table, metadata) = match (collapse_method, metadata) {
+                (Some(method), Some(metadata)) => {
+                    let (collapsed, collapsed_metadata, merged) =
+                        collapse_replicates(&table, &metadata, method)?;
+                    for (biological, members) in &merged {
+                        println!(
+                            "Collapsed {} technical replicates into {}: {}",
+                            members.len(),
+                            biological,
+                            members.join(", ")
+                        );
+                    }
+                    (collapsed, Some(collapsed_metadata))
+                }
+                (_, metadata) => (table, metadata),
+            };
+
+            // Inputs are the results files; parameters are those of the runs that wrote them
+            let inputs: Vec<&Path> = samples
+                .iter()
+                .filter_map(|sample| sample.results_file.as_deref())
+                .collect();
+            let run_parameters: BTreeSet<&str> = samples
+                .iter()
+                .filter_map(|sample| sample.provenance.as_ref())
+                .map(|provenance| provenance.parameters_sha256.as_str())
+                .collect();
+            let mut provenance = Provenance::new(
+                &inputs,
+                Some(database_digest(&database.get_all_signatures()?)),
+                &serde_json::json!({
+                    "run_parameters_sha256": run_parameters,
+                    "collapse_replicates": collapse_method,
+                }),
+            )?;
+            if anonymizer.is_some() {
+                provenance.redact_input_names();
+            }
+            provenance.excluded_samples = excluded_samples;
+
+            let files = write_phyloseq_tables(
+                &table,
+                &lineages,
+                metadata.as_ref(),
+                Some(&provenance),
+                &output,
+            )?;
+            write_sample_qc(&sample_qc, Some(&provenance), &output.join("sample_qc.csv"))?;
+            let mut distance_files = Vec::new();
+            for kind in unifrac {
+                let path = output.join(format!("unifrac_{}.csv", kind));
+                unifrac_matrix(&table, &lineages, kind).write_csv(Some(&provenance), &path)?;
+                distance_files.push((kind, path));
+            }
+            println!(
+                "Exported {} samples x {} taxa to {} (load with {})",
+                table.sample_names().len(),
+                table.feature_names().len(),
+                output.display(),
+                files.import_script.display()
+            );
+            let mut summary = RunSummary::new("export-phyloseq", invocation);
+            summary.samples_processed = table.sample_names().len();
+            summary.warnings = samples.iter().map(|sample| sample.warnings.len()).sum();
+            summary.alerts = samples.iter().map(|sample| sample.alerts.len()).sum();
+            summary.output("OTU table", &files.otu_table);
+            summary.output("taxonomy table", &files.tax_table);
+            summary.output("sample data", &files.sample_data);
+            summary.output("sample QC", &output.join("sample_qc.csv"));
+            for (kind, path) in &distance_files {
+                summary.output(&format!("{} UniFrac distances", kind), path);
+            }
+            summary.next_external(
+                "Load the tables into a phyloseq object in R",
+                &format!("Rscript -e 'source(\"{}\")'", files.import_script.display()),
+            );
+            if let (Some((kind, path)), Some(_)) = (distance_files.first(), &metadata) {
+                summary.next_external(
+                    &format!("Test group differences in {} UniFrac with PERMANOVA", kind),
+                    &format!(
+                        "Rscript -e 'library(vegan); d <- as.dist(as.matrix(read.csv(\"{}\", row.names = 1, check.names = FALSE, comment.char = \"#\"))); sam <- read.csv(\"{}\", row.names = 1, comment.char = \"#\"); adonis2(d ~ condition, data = sam[labels(d), , drop = FALSE])'",
+                        path.display(),
+                        files.sample_data.display()
+                    ),
+                );
+            }
+            if metadata.is_none() {
+                summary.next(
+                    "Export again with sample groups for differential analysis",
+                    &format!(
+                        "export-phyloseq --results {} --metadata <metadata.csv>",
+                        quote(&results)
+                    ),
+                );
+            }
+            println!("{}", summary);
+        }
+
+        Commands::NormalizeTable {
+            input,
+            output,
+            method,
+            size_factors,
+            write_size_factors: size_factors_output,
+            layout,
+        } => {
+            let (factors, source) = match &size_factors {
+                Some(path) => (
+                    normalize_csv_with_factors(&input, &output, &read_size_factors(path)?, layout)?,
+                    format!("size factors from {}", path.display()),
+                ),
+                None => (normalize_csv(&input, &output, &method, layout)?, method),
+            };
+            let mut summary = RunSummary::new("normalize-table", invocation);
+            summary.output("normalized table", &output);
+            if let Some(path) = &size_factors_output {
+                write_size_factors(&factors.to_map(), path)?;
+                summary.output("size factors", path);
+            }
+            println!(
+                "Normalized {} features x {} samples ({}) into {}",
+                factors.n_features,
+                factors.sample_names.len(),
+                source,
+                output.display()
+            );
+            println!("{}", summary);
+        }
+        Commands::HarmonizeTables {
+            inputs,
+            equivalences: equivalences_path,
+            output,
+            drop_unmapped,
+            count_precision,
+        } => {
+            let equivalences = FeatureEquivalences::from_file(&equivalences_path)?;
+            // Only one input is held in f64 at a time
+            let tables = inputs
+                .iter()
+                .map(|path| {
+                    read_count_table(path, cli.duplicate_samples)?
+                        .to_compact(count_precision)
+                        .map_err(|e| e.context(format!("Count table {}", path.display())))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let (table, report) = harmonize_tables(&tables, &equivalences, !drop_unmapped)?;
+
+            for (feature, count) in &report.unmapped {
+                eprintln!(
+                    "WARNING: feature {} ({} counts) has no current ID; {}",
+                    feature,
+                    count,
+                    if report.kept_unmapped {
+                        "kept under its own ID"
+                    } else {
+                        "dropped"
+                    }
+                );
+            }
+
+            let mut provenance_inputs: Vec<&Path> = inputs.iter().map(PathBuf::as_path).collect();
+            provenance_inputs.push(&equivalences_path);
+            let provenance = Provenance::new(
+                &provenance_inputs,
+                None,
+                &serde_json::json!({
+                    "drop_unmapped": drop_unmapped,
+                    "count_precision": count_precision,
+                }),
+            )?;
+            write_count_table(&table, Some(&provenance), &output.to_string_lossy())?;
+            println!(
+                "Merged {} tables into {} samples x {} features ({} renamed, {} unmapped)",
+                tables.len(),
+                table.sample_names().len(),
+                table.feature_names().len(),
+                report.renamed.len(),
+                report.unmapped.len()
+            );
+            let mut summary = RunSummary::new("harmonize-tables", invocation);
+            summary.samples_processed = table.sample_names().len();
+            summary.warnings = report.unmapped.len();
+            summary.output("harmonized count table", &output);
+            summary.next(
+                "Normalize the merged table",
+                &format!(
+                    "normalize-table --input {} --output <normalized.csv>",
+                    quote(&output)
+                ),
+            );
+            println!("{}", summary);
+        }
+        Commands::Monitor {
+            baseline,
+            current,
+            output,
+            min_share_change,
+            min_fold_change,
+            max_distance,
+        } => {
+            let load = |path: &Path| -> Result<ClassificationResults, Box<dyn std::error::Error>> {
+                read_versioned_json(Artifact::Results, path).map_err(|e| match e {
+                    ArtifactError::Json(e) => {
+                        format!("{} is not a results file: {}", path.display(), e).into()
+                    }
+                    e => e.into(),
                 })
-                .map(|entry| entry.path())
+            };
+            let thresholds = DriftThresholds {
+                min_share_change,
+                min_fold_change,
+                max_distance,
+            };
+            let report = DriftReport::compare(&load(&baseline)?, &load(&current)?, thresholds);
+            print!("{}", report);
+            serde_json::to_writer_pretty(File::create(&output)?, &report)?;
+
+            let mut summary = RunSummary::new("monitor", invocation);
+            summary.samples_processed = 2;
+            summary.warnings = report.changes.len() + report.database_changed as usize;
+            summary.output("drift report", &output);
+            println!("{}", summary);
+        }
+        Commands::Reclassify {
+            sketches,
+            previous,
+            output,
+            min_share_change,
+            min_fold_change,
+            max_distance,
+        } => {
+            let sample_sketches = SampleSketch::load_dir(&sketches)?;
+            if sample_sketches.is_empty() {
+                println!("No sample sketches found in: {}", sketches.display());
+                return Ok(());
+            }
+            // Read before the new results replace them when both are in `output`
+            let previous_dir = previous.unwrap_or_else(|| output.clone());
+            let previous_results: HashMap<String, ClassificationResults> = if previous_dir.is_dir()
+            {
+                load_results_dir(&previous_dir)?
+                    .into_iter()
+                    .map(|results| (results.sample_id.clone(), results))
+                    .collect()
+            } else {
+                HashMap::new()
+            };
+
+            let processor = configure_processor(&cli, &config, &telemetry, None)?;
+
+            let thresholds = DriftThresholds {
+                min_share_change,
+                min_fold_change,
+                max_distance,
+            };
+            let mut summary = RunSummary::new("reclassify", invocation);
+            let mut changed = 0;
+            for (path, sketch) in &sample_sketches {
+                let results = match processor.reclassify(sketch, path, &output) {
+                    Ok(results) => results,
+                    Err(e) => {
+                        eprintln!("Error reclassifying {}: {}", sketch.sample_id, e);
+                        summary.sample_failed(&sketch.sample_id, &e);
+                        continue;
+                    }
+                };
+                summary.add_sample(&results);
+                let Some(previous) = previous_results.get(&sketch.sample_id) else {
+                    warn!(
+                        "No previous results for {} in {}; nothing to compare with",
+                        sketch.sample_id,
+                        previous_dir.display()
+                    );
+                    continue;
+                };
+                let report = DriftReport::compare(previous, &results, thresholds);
+                print!("{}", report);
+                let diff = diff_path(&output, &sketch.sample_id);
+                serde_json::to_writer_pretty(File::create(&diff)?, &report)?;
+                summary.output("diff report", &diff);
+                if report.drifted || !report.changes.is_empty() {
+                    changed += 1;
+                }
+            }
+            println!(
+                "{} of {} samples changed beyond the thresholds",
+                changed,
+                sample_sketches.len()
+            );
+            println!("{}", summary);
+        }
+        Commands::ExportFeatures {
+            sketches,
+            kind,
+            dimensions,
+            top_k,
+            output,
+        } => {
+            let samples: Vec<(String, MultiResolutionSignature)> =
+                SampleSketch::load_dir(&sketches)?
+                    .into_iter()
+                    .map(|(_, sketch)| (sketch.sample_id, sketch.signature))
+                    .collect();
+            if samples.is_empty() {
+                println!("No sample sketches found in: {}", sketches.display());
+                return Ok(());
+            }
+            let features = match kind {
+                FeatureKind::Hashed => hashed_embedding(&samples, dimensions)?,
+                FeatureKind::References => {
+                    let references =
+                        SignatureDatabase::open_with_config(&cli.db_path, &config.database)?
+                            .get_all_signatures()?;
+                    reference_similarities(&samples, &references, top_k)?
+                }
+            };
+            let written = features.write(&output)?;
+            println!(
+                "Exported {} features for {} samples",
+                features.feature_names.len(),
+                features.sample_ids.len()
+            );
+
+            let mut summary = RunSummary::new("export-features", invocation);
+            summary.samples_processed = samples.len();
+            for (description, path) in ["feature matrix", "sample labels", "feature labels"]
+                .into_iter()
+                .zip(&written)
+            {
+                summary.output(description, path);
+            }
+            println!("{}", summary);
+        }
+        Commands::SelectReferences {
+            sketches,
+            species,
+            folds,
+            seed,
+            output,
+        } => {
+            let sample_sketches = SampleSketch::load_dir(&sketches)?;
+            if sample_sketches.is_empty() {
+                println!("No sample sketches found in: {}", sketches.display());
+                return Ok(());
+            }
+            let references = SignatureDatabase::open_with_config(&cli.db_path, &config.database)?
+                .get_all_signatures()?;
+            let strains = candidate_strains(&references, &species);
+            if strains.is_empty() {
+                return Err(format!(
+                    "No reference strains of {} in {}",
+                    species,
+                    cli.db_path.display()
+                )
+                .into());
+            }
+
+            let cv = ReferenceCrossValidation { folds, seed };
+            let mut summary = RunSummary::new("select-references", invocation);
+            let mut scores = Vec::new();
+            for (_, sketch) in &sample_sketches {
+                match select_references(&sketch.signature, &strains, &cv) {
+                    Ok(sample_scores) => {
+                        if let Some(best) = sample_scores.first() {
+                            println!(
+                                "{}: {} ({}), held-out RMSE {:.4} \u{b1} {:.4}",
+                                sketch.sample_id,
+                                best.name,
+                                best.strain_ids.join(", "),
+                                best.mean_error,
+                                best.std_error
+                            );
+                        }
+                        summary.samples_processed += 1;
+                        scores.push((sketch.sample_id.clone(), sample_scores));
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Error cross-validating references for {}: {}",
+                            sketch.sample_id, e
+                        );
+                        summary.sample_failed(&sketch.sample_id, &e);
+                    }
+                }
+            }
+            write_reference_scores(&scores, &output)?;
+            summary.output("reference selection", &output);
+            println!("{}", summary);
+        }
+        Commands::Hierarchical {
+            sketches,
+            species,
+            iterations,
+            seed,
+            output,
+            samples,
+        } => {
+            let sample_sketches = SampleSketch::load_dir(&sketches)?;
+            if sample_sketches.is_empty() {
+                println!("No sample sketches found in: {}", sketches.display());
+                return Ok(());
+            }
+            let references = SignatureDatabase::open_with_config(&cli.db_path, &config.database)?
+                .get_all_signatures()?;
+            let strains = candidate_strains(&references, &species);
+            if strains.is_empty() {
+                return Err(format!(
+                    "No reference strains of {} in {}",
+                    species,
+                    cli.db_path.display()
+                )
+                .into());
+            }
+
+            let signatures: Vec<&MultiResolutionSignature> = sample_sketches
+                .iter()
+                .map(|(_, sketch)| &sketch.signature)
+                .collect();
+            let (features, observed) = shared_feature_matrix(&signatures, &strains)?;
+            let observed: Vec<(String, _)> = sample_sketches
+                .iter()
+                .map(|(_, sketch)| sketch.sample_id.clone())
+                .zip(observed)
                 .collect();
+            let mut model = StrainMixtureModel::new(
+                features.signatures,
+                features.strain_ids,
+                None,
+                Some(iterations),
+                Some(seed),
+            )?;
+            let result = model.estimate_hierarchical(&observed)?;
+            write_hierarchical_abundances(&result, &output, &samples)?;
 
-            if result_files.is_empty() {
-                println!("No result files found in: {}", output.display());
+            let mut study: Vec<_> = result.study_abundances.iter().collect();
+            study.sort_by(|a, b| b.1.mean.total_cmp(&a.1.mean).then_with(|| a.0.cmp(b.0)));
+            println!(
+                "Study-level abundances of {} strains over {} samples (concentration {:.2}):",
+                study.len(),
+                observed.len(),
+                result.concentration
+            );
+            for (strain_id, abundance) in study {
+                println!(
+                    "  {}: {:.2}% (95% CrI {:.2}-{:.2}%)",
+                    strain_id,
+                    abundance.mean * 100.0,
+                    abundance.lower * 100.0,
+                    abundance.upper * 100.0
+                );
+            }
+
+            let mut summary = RunSummary::new("hierarchical", invocation);
+            summary.samples_processed = observed.len();
+            summary.output("study abundances", &output);
+            summary.output("sample abundances", &samples);
+            println!("{}", summary);
+        }
+        Commands::Differential {
+            results,
+            metadata,
+            groups,
+            reference,
+            treatment,
+            imputations,
+            point_estimates,
+            exclude_outliers,
+            collapse_replicates: collapse_method,
+            output,
+        } => {
+            let samples = load_results_dir(&results)?;
+            let mut metadata = match (metadata, groups) {
+                (Some(path), _) => {
+                    Metadata::from_file(&path.to_string_lossy(), cli.duplicate_samples)?
+                }
+                (None, Some(spec)) => Metadata::from_groups(&spec)?,
+                (None, None) => {
+                    return Err(ProcessingError::InvalidParameters(
+                        "Either --metadata or --groups is required".to_string(),
+                    )
+                    .into())
+                }
+            };
+            let mut posteriors = sample_posteriors(&samples, point_estimates);
+
+            // Outliers are judged on estimated read counts, as in export-phyloseq
+            let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+            let mut assigned_fractions = HashMap::new();
+            for sample in &samples {
+                let reads = sample.metrics.passed_reads as f64;
+                if let Some(fraction) = sample.assigned_fraction {
+                    assigned_fractions.insert(sample.sample_id.clone(), fraction);
+                }
+                let counts = data.entry(sample.sample_id.clone()).or_default();
+                if sample.strain_abundances.is_empty() {
+                    if let Some(top) = sample.classifications.first() {
+                        *counts.entry(top.taxon_id.clone()).or_default() += reads;
+                    }
+                }
+                for (strain_id, (abundance, _)) in &sample.strain_abundances {
+                    *counts.entry(strain_id.clone()).or_default() += (abundance * reads).round();
+                }
+            }
+            let table = CountTable::build_from_data(&data)?;
+            let sample_qc =
+                assess_samples(&table, &assigned_fractions, &OutlierThresholds::default());
+            let mut excluded_samples = BTreeMap::new();
+            for sample in sample_qc.iter().filter(|sample| sample.is_outlier()) {
+                eprintln!(
+                    "WARNING: sample {} is an outlier: {}",
+                    sample.sample_id,
+                    sample.reasons.join("; ")
+                );
+                if exclude_outliers {
+                    excluded_samples.insert(sample.sample_id.clone(), sample.reasons.join("; "));
+                }
+            }
+            let samples: Vec<ClassificationResults> = samples
+                .into_iter()
+                .filter(|sample| !excluded_samples.contains_key(&sample.sample_id))
+                .collect();
+            posteriors.retain(|posterior| !excluded_samples.contains_key(&posterior.sample_id));
+            metadata
+                .sample_info
+                .retain(|sample_id, _| !excluded_samples.contains_key(sample_id));
+
+            let with_draws = posteriors.iter().filter(|p| p.draws.len() > 1).count();
+            if !point_estimates && with_draws < posteriors.len() {
+                warn!(
+                    "{} of {} samples have no posterior draws (process them with \
+                     --posterior-draws and an mcmc or vi estimator); their abundances are \
+                     treated as exact",
+                    posteriors.len() - with_draws,
+                    posteriors.len()
+                );
+            }
+            // Technical replicates become one posterior per biological sample
+            let (posteriors, metadata) = match collapse_method {
+                Some(method) => {
+                    let depths: Vec<f64> = samples
+                        .iter()
+                        .map(|sample| sample.metrics.passed_reads as f64)
+                        .collect();
+                    let (collapsed, collapsed_metadata, merged) =
+                        collapse_posteriors(&posteriors, &depths, &metadata, method)?;
+                    for (biological, members) in &merged {
+                        println!(
+                            "Collapsed {} technical replicates into {}: {}",
+                            members.len(),
+                            biological,
+                            members.join(", ")
+                        );
+                    }
+                    (collapsed, collapsed_metadata)
+                }
+                None => (posteriors, metadata),
+            };
+            let comparison = UncertaintyComparison {
+                imputations,
+                ..UncertaintyComparison::default()
+            };
+            let analysis =
+                comparison.compare_groups(&posteriors, &metadata, &reference, &treatment)?;
+
+            let input_paths: Vec<&Path> = samples
+                .iter()
+                .filter_map(|sample| sample.results_file.as_deref())
+                .collect();
+            let mut provenance = Provenance::new(
+                &input_paths,
+                None,
+                &serde_json::json!({
+                    "reference": reference,
+                    "treatment": treatment,
+                    "imputations": imputations,
+                    "point_estimates": point_estimates,
+                    "collapse_replicates": collapse_method,
+                }),
+            )?;
+            provenance.excluded_samples = excluded_samples;
+            write_results(&analysis, Some(&provenance), &output.to_string_lossy())?;
+            let significant = analysis
+                .iter()
+                .filter(|result| result.p_adjusted.map_or(false, |p| p < 0.05))
+                .count();
+            println!(
+                "Compared {} strains between {} and {} ({} of {} samples with posterior draws); \
+                 {} with adjusted p < 0.05",
+                analysis.len(),
+                treatment,
+                reference,
+                with_draws,
+                samples.len(),
+                significant
+            );
+
+            let mut summary = RunSummary::new("differential", invocation);
+            summary.output("differential results", &output);
+            println!("{}", summary);
+        }
+        Commands::Meta {
+            inputs,
+            output,
+            forest,
+            min_studies,
+            methods,
+        } => {
+            // Studies are named after their files, or their paths when file names repeat
+            let stems: Vec<String> = inputs
+                .iter()
+                .map(|path| {
+                    path.file_stem().map_or_else(
+                        || path.display().to_string(),
+                        |stem| stem.to_string_lossy().into_owned(),
+                    )
+                })
+                .collect();
+            let unique = stems.iter().collect::<BTreeSet<_>>().len() == stems.len();
+            let studies = inputs
+                .iter()
+                .zip(stems)
+                .map(|(path, stem)| {
+                    let name = if unique {
+                        stem
+                    } else {
+                        path.display().to_string()
+                    };
+                    read_results(path).map(|results| (name, results))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let results = meta_analyze(&studies, min_studies.max(2));
+            if results.is_empty() {
+                return Err(format!(
+                    "No feature has a log2 fold change and standard error in {} of the {} studies",
+                    min_studies.max(2),
+                    studies.len()
+                )
+                .into());
+            }
+
+            let input_paths: Vec<&Path> = inputs.iter().map(PathBuf::as_path).collect();
+            let provenance = Provenance::new(
+                &input_paths,
+                None,
+                &serde_json::json!({ "min_studies": min_studies }),
+            )?;
+            write_meta_results(&results, Some(&provenance), &output)?;
+            write_forest_table(&results, &forest)?;
+            let heterogeneous = results
+                .iter()
+                .filter(|result| result.i_squared > 0.5)
+                .count();
+            println!(
+                "Pooled {} features across {} studies; {} with I\u{b2} > 50%",
+                results.len(),
+                studies.len(),
+                heterogeneous
+            );
+
+            let mut summary = RunSummary::new("meta", invocation);
+            summary.output("meta-analysis", &output);
+            summary.output("forest plot table", &forest);
+            if let Some(path) = methods {
+                let statistics = StatisticalMethods {
+                    model: format!(
+                        "a meta-analysis of the log2 fold changes of {} studies, pooled by \
+                         inverse-variance weighting (fixed effect) and with a DerSimonian-Laird \
+                         between-study variance (random effects) [CITATION: DerSimonian & Laird \
+                         1986], for features tested in at least {} studies",
+                        studies.len(),
+                        min_studies.max(2)
+                    ),
+                    fdr: FdrMethod::BenjaminiHochberg,
+                };
+                std::fs::write(&path, format!("{}\n", statistics.sentence()))?;
+                summary.output("methods paragraph", &path);
+            }
+            println!("{}", summary);
+        }
+        Commands::Power {
+            pilot,
+            effect_size,
+            alpha,
+            sample_sizes,
+            simulations,
+            target_power,
+            seed,
+            output,
+        } => {
+            let table = read_count_table(&pilot, cli.duplicate_samples)?;
+            let estimates = PilotEstimates::from_counts(&table)?;
+            if estimates.skipped_features > 0 {
+                println!(
+                    "Skipped {} pilot features with a mean normalized count below 1",
+                    estimates.skipped_features
+                );
+            }
+            let settings = PowerSettings {
+                effect_size,
+                alpha,
+                sample_sizes,
+                simulations,
+                target_power,
+                seed,
+            };
+            let provenance = Provenance::new(&[pilot.as_path()], None, &settings)?;
+            let analysis = PowerAnalysis::simulate(&estimates, settings)?;
+            write_power_curve(&analysis, Some(&provenance), &output)?;
+            print!("{}", analysis);
+
+            let mut summary = RunSummary::new("power", invocation);
+            summary.output("power curve", &output);
+            println!("{}", summary);
+        }
+        Commands::SuggestReferences {
+            results,
+            add,
+            yes,
+            max_refs,
+        } => {
+            let samples = load_results_dir(&results)?;
+            // Queries with the samples that triggered them, strongest match first
+            let mut queries: Vec<(String, Vec<&str>, f64)> = Vec::new();
+            for sample in &samples {
+                for suggestion in &sample.reference_suggestions {
+                    match queries.iter_mut().find(|(q, _, _)| *q == suggestion.query) {
+                        Some((_, sample_ids, best)) => {
+                            sample_ids.push(sample.sample_id.as_str());
+                            *best = best.max(suggestion.nearest_ani);
+                        }
+                        None => queries.push((
+                            suggestion.query.clone(),
+                            vec![sample.sample_id.as_str()],
+                            suggestion.nearest_ani,
+                        )),
+                    }
+                }
+            }
+            if queries.is_empty() {
+                println!(
+                    "No missing references suggested for results in {}",
+                    results.display()
+                );
                 return Ok(());
             }
+            queries.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(b.2.total_cmp(&a.2)));
+
+            println!("Suggested reference queries:");
+            for (query, sample_ids, best) in &queries {
+                println!(
+                    "  {} (best partial match {:.1}% ANI; samples: {})",
+                    query,
+                    best * 100.0,
+                    sample_ids.join(", ")
+                );
+            }
+            if !add {
+                println!(
+                    "Add them with: db add-references --query '<query>' (or rerun with --add)"
+                );
+                return Ok(());
+            }
+
+            let mut manager = DatabaseManager::with_config(
+                &cli.db_path,
+                &cli.cache_dir,
+                SignatureLayout::default(),
+                cli.api_key.clone(),
+                &config.database,
+            )?;
+            manager.adopt_database_layout()?;
+            let mut added_total = 0;
+            for (query, _, _) in &queries {
+                if !yes && !confirm(&format!("Add up to {} genomes for {}?", max_refs, query))? {
+                    continue;
+                }
+                let added = manager.search_and_add_references(query, max_refs)?;
+                println!("  {}: added {} references", query, added.len());
+                added_total += added.len();
+            }
+            let mut summary = RunSummary::new("suggest-references", invocation);
+            summary.samples_processed = samples.len();
+            if added_total > 0 {
+                summary.next(
+                    &format!(
+                        "Reprocess the samples against the {} new references",
+                        added_total
+                    ),
+                    "process-dir --dir <fastq-dir> --output <new-results-dir>",
+                );
+            }
+            println!("{}", summary);
+        }
+        Commands::Doctor {
+            network,
+            skip_self_test,
+        } => {
+            let report = run_checks(&DoctorOptions {
+                db_path: cli.db_path.clone(),
+                database: config.database.clone(),
+                cache_dir: cli.cache_dir.clone(),
+                threads: cli.threads,
+                batch_memory_bytes: cli.batches.batch_memory << 20,
+                aligner: cli.confirm.aligner.clone(),
+                network,
+                self_test: !skip_self_test,
+            });
+            println!("{}", report);
+            if report.failed() > 0 {
+                return Err(format!("doctor: {} checks failed", report.failed()).into());
+            }
         }
     }
 
     Ok(())
 }
+
+/// How this program was invoked, with the global arguments later commands need
+fn invocation(cli: &Cli) -> String {
+    let mut invocation = format!(
+        "{} --db-path {} --cache-dir {}",
+        env!("CARGO_PKG_NAME"),
+        quote(&cli.db_path),
+        quote(&cli.cache_dir)
+    );
+    if let Some(config) = &cli.config {
+        invocation.push_str(&format!(" --config {}", quote(config)));
+    }
+    if cli.strict.strict {
+        invocation.push_str(" --strict");
+    }
+    invocation
+}
+
+/// Threads to use by default: the CPUs this process may run on, which
+/// accounts for affinity masks and cgroup CPU quotas
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/// Ask a yes/no question on the terminal; anything but y/yes is a no
+fn confirm(question: &str) -> std::io::Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::estimators::PosteriorDraws;
+    use crate::utils::golden::toy_results;
+    use std::ffi::OsString;
+
+    #[test]
+    fn test_qc_flags_override_preset() {
+        let args = QcArgs {
+            qc_preset: QcPreset::LongRead,
+            min_length: Some(500),
+            poly_a_min_length: Some(0),
+            ..QcArgs::default()
+        };
+        let params = args.params();
+        assert_eq!(params.min_length, 500);
+        assert_eq!(params.trimming, TrimmingStrategy::Mott);
+        assert_eq!(params.poly_g_min_length, None);
+        assert_eq!(params.poly_a_min_length, None);
+        assert_eq!(
+            QcArgs::default().params().min_length,
+            QualityControlParams::default().min_length
+        );
+
+        // Every command that reads FASTQ takes the same QC flags
+        for command in ["process-dir", "visualize", "compare-samples"] {
+            let mut args = vec!["strain_ahsp", command, "--min-length", "500"];
+            args.extend(match command {
+                "process-dir" => ["--dir", "reads"].as_slice(),
+                _ => ["--fastq", "reads.fq", "--sample-id", "S1"].as_slice(),
+            });
+            let qc = match Cli::try_parse_from(args).unwrap().command {
+                Commands::ProcessDir { qc, .. }
+                | Commands::Visualize { qc, .. }
+                | Commands::CompareSamples { qc, .. } => qc,
+                other => panic!("unexpected command {:?}", other),
+            };
+            assert_eq!(qc.params().min_length, 500, "{}", command);
+        }
+    }
+
+    #[test]
+    fn test_environment_fills_in_global_options() {
+        /// Restores the variables it set when dropped, also when an assertion fails
+        struct EnvGuard(Vec<(&'static str, Option<OsString>)>);
+        impl EnvGuard {
+            fn set(&mut self, key: &'static str, value: &str) {
+                self.0.push((key, std::env::var_os(key)));
+                std::env::set_var(key, value);
+            }
+        }
+        impl Drop for EnvGuard {
+            fn drop(&mut self) {
+                for (key, previous) in self.0.drain(..).rev() {
+                    match previous {
+                        Some(value) => std::env::set_var(key, value),
+                        None => std::env::remove_var(key),
+                    }
+                }
+            }
+        }
+
+        let mut env = EnvGuard(Vec::new());
+        env.set("AHSP_DB_PATH", "/data/db");
+        env.set("AHSP_CACHE_DIR", "/data/cache");
+        env.set("AHSP_THREADS", "2");
+        env.set("AHSP_COMPRESS_JSON", "zstd");
+
+        let cli = Cli::try_parse_from(["strain_ahsp", "process-dir", "--dir", "reads"]).unwrap();
+        assert_eq!(cli.db_path, PathBuf::from("/data/db"));
+        assert_eq!(cli.cache_dir, PathBuf::from("/data/cache"));
+        assert_eq!(cli.threads, 2);
+        assert_eq!(cli.compress_json, JsonCompression::Zstd);
+        // Degenerate samples are warned about unless failing them is asked for
+        assert_eq!(cli.on_degenerate_input, DegenerateInputPolicy::Warn);
+
+        // Flags win over the environment
+        let cli = Cli::try_parse_from([
+            "strain_ahsp",
+            "--threads",
+            "8",
+            "--db-path",
+            "db",
+            "process-dir",
+            "--dir",
+            "reads",
+        ])
+        .unwrap();
+        assert_eq!(cli.threads, 8);
+        assert_eq!(cli.db_path, PathBuf::from("db"));
+        assert!(default_threads() >= 1);
+    }
+
+    #[test]
+    fn test_differential_pools_posterior_draws() {
+        let dir = tempfile::tempdir().unwrap();
+        let results_dir = dir.path().join("results");
+        std::fs::create_dir(&results_dir).unwrap();
+        let strains = vec!["GCF_A".to_string(), "GCF_B".to_string()];
+        let samples = [
+            ("c1", "ctrl", [[0.1, 0.9], [0.3, 0.7]]),
+            ("c2", "ctrl", [[0.15, 0.85], [0.35, 0.65]]),
+            ("t1", "case", [[0.4, 0.6], [0.8, 0.2]]),
+            ("t2", "case", [[0.45, 0.55], [0.85, 0.15]]),
+        ];
+        let mut metadata = String::from("sample_id,condition,replicate\n");
+        for (id, condition, draws) in samples {
+            let mut result = toy_results().remove(0);
+            result.sample_id = id.to_string();
+            result.strain_abundances = strains
+                .iter()
+                .enumerate()
+                .map(|(i, strain)| (strain.clone(), ((draws[0][i] + draws[1][i]) / 2.0, 0.2)))
+                .collect();
+            result.posterior_draws = Some(PosteriorDraws {
+                strain_ids: strains.clone(),
+                draws: draws.iter().map(|draw| draw.to_vec()).collect(),
+            });
+            let file = File::create(results_dir.join(format!("{}_results.json", id))).unwrap();
+            serde_json::to_writer(file, &result).unwrap();
+            metadata.push_str(&format!("{},{},1\n", id, condition));
+        }
+        let metadata_path = dir.path().join("metadata.csv");
+        std::fs::write(&metadata_path, metadata).unwrap();
+
+        let differential = |point_estimates: bool, groups: bool| {
+            let output = dir
+                .path()
+                .join(format!("differential_{}_{}.csv", point_estimates, groups));
+            let mut args: Vec<OsString> = vec![
+                "strain_ahsp".into(),
+                "--db-path".into(),
+                dir.path().join("db").into(),
+                "--cache-dir".into(),
+                dir.path().join("cache").into(),
+                "differential".into(),
+                "--results".into(),
+                results_dir.clone().into(),
+                "--reference".into(),
+                "ctrl".into(),
+                "--treatment".into(),
+                "case".into(),
+                "--imputations".into(),
+                "4".into(),
+                "--output".into(),
+                output.clone().into(),
+            ];
+            if point_estimates {
+                args.push("--point-estimates".into());
+            }
+            if groups {
+                args.push("--groups".into());
+                args.push("c1,c2:ctrl;t1,t2:case".into());
+            } else {
+                args.push("--metadata".into());
+                args.push(metadata_path.clone().into());
+            }
+            run_cli(Cli::try_parse_from(args).unwrap()).unwrap();
+            read_results(&output).unwrap()
+        };
+        let pooled = differential(false, false);
+        let exact = differential(true, false);
+
+        // Inline groups stand in for the metadata file
+        let inline = differential(true, true);
+        assert_eq!(inline[0].log2_fold_change, exact[0].log2_fold_change);
+
+        assert_eq!(pooled[0].feature_id, "GCF_A");
+        assert!(pooled[0].log2_fold_change.unwrap() > 1.0);
+        assert!(exact[0].log2_fold_change.unwrap() > 1.0);
+        assert!(pooled[0].std_error.unwrap() > exact[0].std_error.unwrap());
+        assert!(pooled[0].p_value.unwrap() > exact[0].p_value.unwrap());
+        assert_eq!(exact[0].degrees_of_freedom, Some(2.0));
+    }
+}
@@ -0,0 +1,238 @@
+//! Metatranscriptomics (RNA) mode.
+//!
+//! Total RNA libraries are dominated by ribosomal RNA even after depletion, and
+//! rRNA reads say nothing about which genes a community expresses. In RNA mode
+//! each read is first put in transcript orientation according to the
+//! library's strandedness, and reads matching an rRNA panel (k-mers of SILVA
+//! SSU/LSU sequences) are removed before sketching. What remains measures
+//! transcript abundance, so the resulting abundances are labeled as
+//! expression rather than as shares of cells.
+//!
+//! SILVA sequences are given in the sense orientation of the rRNA, so the
+//! panel keeps forward-strand k-mers only. Reads from a stranded library are
+//! matched on their sense strand alone, which avoids calling antisense
+//! transcripts rRNA; reads from an unstranded library are matched on both.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::path::Path;
+
+use needletail::parse_fastx_file;
+use nthash::NtHashForwardIterator;
+use serde::{Deserialize, Serialize};
+
+use crate::bio::reverse_complement;
+use crate::pipeline::qc::ProcessingError;
+
+/// k-mer size of the rRNA panel
+pub const DEFAULT_RRNA_KMER_SIZE: usize = 21;
+
+/// Keep one in this many panel k-mers (FracMinHash); SILVA is too large to keep them all
+pub const DEFAULT_RRNA_SCALED: u64 = 4;
+
+/// Fraction of a read's sampled k-mers that must be in the panel for it to be rRNA
+pub const DEFAULT_RRNA_MIN_CONTAINMENT: f64 = 0.5;
+
+/// What kind of nucleic acid the reads were sequenced from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SequencingMode {
+    /// Metagenome: abundances estimate shares of genomes
+    #[default]
+    Dna,
+    /// Metatranscriptome: rRNA is filtered and abundances estimate shares of transcripts
+    Rna,
+}
+
+/// Which strand of the transcript the reads come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Strandedness {
+    /// Either strand (strand information was not kept)
+    #[default]
+    Unstranded,
+    /// Reads are in the sense orientation (e.g. ligation-based kits)
+    Forward,
+    /// Reads are antisense to the transcript (e.g. dUTP protocols such as TruSeq Stranded)
+    Reverse,
+}
+
+impl Strandedness {
+    /// The read in transcript orientation; only reverse-stranded reads change
+    pub fn orient<'a>(&self, read: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Strandedness::Reverse => Cow::Owned(reverse_complement(read)),
+            Strandedness::Unstranded | Strandedness::Forward => Cow::Borrowed(read),
+        }
+    }
+}
+
+/// Sense-strand k-mers of an rRNA panel, for recognizing rRNA reads
+#[derive(Debug, Clone)]
+pub struct RrnaFilter {
+    pub kmer_size: usize,
+    pub scaled: u64,
+    pub min_containment: f64,
+    hashes: HashSet<u64>,
+}
+
+impl RrnaFilter {
+    /// Build the panel from rRNA sequences in sense orientation. `U` is read as
+    /// `T` and ambiguity codes as `N`, so SILVA exports can be used directly.
+    pub fn from_sequences<I, S>(sequences: I, kmer_size: usize, scaled: u64) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        let mut hashes = HashSet::new();
+        for sequence in sequences {
+            let sequence: Vec<u8> = sequence
+                .as_ref()
+                .iter()
+                .map(|&base| match base.to_ascii_uppercase() {
+                    b'U' => b'T',
+                    base @ (b'A' | b'C' | b'G' | b'T') => base,
+                    _ => b'N',
+                })
+                .collect();
+            hashes.extend(sampled_hashes(&sequence, kmer_size, scaled));
+        }
+        RrnaFilter {
+            kmer_size,
+            scaled,
+            min_containment: DEFAULT_RRNA_MIN_CONTAINMENT,
+            hashes,
+        }
+    }
+
+    /// Build the panel from a (possibly compressed) FASTA file such as a SILVA release
+    pub fn from_fasta(path: &Path, kmer_size: usize, scaled: u64) -> Result<Self, ProcessingError> {
+        if kmer_size == 0 || scaled == 0 {
+            return Err(ProcessingError::InvalidParameters(
+                "rRNA panel k-mer size and scaled factor must be positive".to_string(),
+            ));
+        }
+        let mut reader = parse_fastx_file(path)?;
+        let mut sequences = Vec::new();
+        while let Some(record) = reader.next() {
+            sequences.push(record?.seq().into_owned());
+        }
+        let filter = RrnaFilter::from_sequences(&sequences, kmer_size, scaled);
+        if filter.is_empty() {
+            return Err(ProcessingError::InvalidParameters(format!(
+                "rRNA panel {} has no sequences of at least {} bp",
+                path.display(),
+                kmer_size
+            )));
+        }
+        Ok(filter)
+    }
+
+    /// Number of k-mers kept from the panel
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Whether a read in transcript orientation (see `Strandedness::orient`) is rRNA
+    pub fn is_rrna(&self, read: &[u8], strandedness: Strandedness) -> bool {
+        let mut containment = self.containment(read);
+        if strandedness == Strandedness::Unstranded && containment < self.min_containment {
+            containment = containment.max(self.containment(&reverse_complement(read)));
+        }
+        containment >= self.min_containment
+    }
+
+    /// Fraction of the sampled forward k-mers of `sequence` found in the panel
+    fn containment(&self, sequence: &[u8]) -> f64 {
+        let (total, found) = sampled_hashes(sequence, self.kmer_size, self.scaled)
+            .fold((0usize, 0usize), |(total, found), hash| {
+                (total + 1, found + self.hashes.contains(&hash) as usize)
+            });
+        if total == 0 {
+            0.0
+        } else {
+            found as f64 / total as f64
+        }
+    }
+}
+
+/// Forward-strand ntHash values of `sequence` kept at the given scaled factor
+/// (none if the sequence is shorter than `kmer_size`)
+fn sampled_hashes(
+    sequence: &[u8],
+    kmer_size: usize,
+    scaled: u64,
+) -> impl Iterator<Item = u64> + '_ {
+    let threshold = u64::MAX / scaled.max(1);
+    NtHashForwardIterator::new(sequence, kmer_size)
+        .ok()
+        .into_iter()
+        .flatten()
+        .filter(move |&hash| hash <= threshold)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::io::Write;
+
+    fn random_dna(rng: &mut StdRng, length: usize) -> Vec<u8> {
+        (0..length)
+            .map(|_| b"ACGT"[rng.random_range(0..4)])
+            .collect()
+    }
+
+    #[test]
+    fn test_rrna_filter_respects_strandedness() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let rrna = random_dna(&mut rng, 1500);
+        // SILVA style: RNA alphabet, lowercase and an ambiguity code
+        let panel: Vec<u8> = rrna
+            .iter()
+            .enumerate()
+            .map(|(i, &base)| match (i, base) {
+                (700, _) => b'R',
+                (_, b'T') => b'u',
+                (_, base) => base.to_ascii_lowercase(),
+            })
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("silva.fasta");
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, ">AB000001.1.1500 Bacteria;Pseudomonadota").unwrap();
+        file.write_all(&panel).unwrap();
+        writeln!(file).unwrap();
+        drop(file);
+
+        let filter = RrnaFilter::from_fasta(&path, DEFAULT_RRNA_KMER_SIZE, 1).unwrap();
+        let sense = rrna[100..250].to_vec();
+        let antisense = reverse_complement(&sense);
+        let other = random_dna(&mut rng, 150);
+
+        assert!(filter.is_rrna(&sense, Strandedness::Unstranded));
+        assert!(filter.is_rrna(&antisense, Strandedness::Unstranded));
+        assert!(!filter.is_rrna(&other, Strandedness::Unstranded));
+        // Stranded reads are oriented first, and only the sense strand can match
+        assert!(filter.is_rrna(
+            &Strandedness::Reverse.orient(&antisense),
+            Strandedness::Reverse
+        ));
+        assert!(!filter.is_rrna(&antisense, Strandedness::Forward));
+        // Reads shorter than k have no k-mers to match
+        assert!(!filter.is_rrna(&sense[..10], Strandedness::Unstranded));
+
+        let scaled = RrnaFilter::from_sequences([&panel], DEFAULT_RRNA_KMER_SIZE, 8);
+        assert!(scaled.len() < filter.len() / 2);
+        assert!(scaled.is_rrna(&sense, Strandedness::Forward));
+
+        let empty = dir.path().join("empty.fasta");
+        std::fs::write(&empty, ">short\nACGU\n").unwrap();
+        assert!(RrnaFilter::from_fasta(&empty, DEFAULT_RRNA_KMER_SIZE, 1).is_err());
+    }
+}
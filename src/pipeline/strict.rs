@@ -0,0 +1,207 @@
+//! Strict mode for validated pipelines.
+//!
+//! A validated clinical pipeline must produce the same calls for the same
+//! reads for as long as its validation holds. Several settings work against
+//! that by design: early stopping reads a data-dependent number of reads, the
+//! `auto` report language follows the locale of whoever runs the job, and an
+//! unpinned database or aligner can change underneath the pipeline. Under
+//! `--strict` a run refuses to start unless the configuration pins the
+//! database contents, every adaptive setting is off, and the runtime
+//! environment matches whatever the configuration pins. All problems are
+//! reported together, so a pipeline can be fixed in one pass.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::pipeline::locale::ReportLanguage;
+use crate::pipeline::qc::ProcessingError;
+
+/// Pins a strict run must match (the `strict` section of the configuration)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StrictConfig {
+    /// Digest of the validated database contents, as recorded in
+    /// `provenance.database_sha256` of its results (required)
+    pub database_sha256: Option<String>,
+
+    /// strain_ahsp version the pipeline was validated with
+    pub tool_version: Option<String>,
+
+    /// Operating system and architecture, e.g. `linux-x86_64`
+    pub platform: Option<String>,
+
+    /// Version string reported by the aligner (required with `--confirm`)
+    pub aligner_version: Option<String>,
+}
+
+/// Why a strict run cannot start
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum StrictViolation {
+    #[error("no database is pinned: set strict.database_sha256 in the config")]
+    UnpinnedDatabase,
+
+    #[error("database digest is {actual}, but the config pins {pinned}")]
+    DatabaseChanged { pinned: String, actual: String },
+
+    #[error("{setting} is adaptive: {reason}")]
+    Adaptive {
+        setting: &'static str,
+        reason: &'static str,
+    },
+
+    #[error("{what} is {actual}, but the config pins {pinned}")]
+    Environment {
+        what: &'static str,
+        pinned: String,
+        actual: String,
+    },
+
+    #[error("--confirm runs an aligner whose version is not pinned: set strict.aligner_version")]
+    UnpinnedAligner,
+}
+
+/// Operating system and architecture this build runs on, e.g. `linux-x86_64`
+pub fn current_platform() -> String {
+    format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+impl StrictConfig {
+    /// Whether the loaded database is the pinned one
+    pub fn check_database(&self, database_sha256: &str) -> Result<(), StrictViolation> {
+        match &self.database_sha256 {
+            None => Err(StrictViolation::UnpinnedDatabase),
+            Some(pinned) if !pinned.trim().eq_ignore_ascii_case(database_sha256) => {
+                Err(StrictViolation::DatabaseChanged {
+                    pinned: pinned.clone(),
+                    actual: database_sha256.to_string(),
+                })
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// Everything about a run's settings and environment that strict mode
+/// cannot accept, before the database is opened. `aligner_version` is the
+/// version the aligner reports, when confirmation is enabled.
+pub fn check_settings(
+    config: &Config,
+    early_stop: bool,
+    aligner_version: Option<&str>,
+) -> Vec<StrictViolation> {
+    let strict = &config.strict;
+    let mut violations = Vec::new();
+    if strict.database_sha256.is_none() {
+        violations.push(StrictViolation::UnpinnedDatabase);
+    }
+    if early_stop {
+        violations.push(StrictViolation::Adaptive {
+            setting: "--early-stop",
+            reason: "the number of reads used depends on the sample",
+        });
+    }
+    if config.report.language == ReportLanguage::Auto {
+        violations.push(StrictViolation::Adaptive {
+            setting: "report.language = auto",
+            reason: "the language depends on the locale of the environment",
+        });
+    }
+
+    let environment = [
+        (
+            "strain_ahsp version",
+            &strict.tool_version,
+            Some(env!("CARGO_PKG_VERSION").to_string()),
+        ),
+        ("platform", &strict.platform, Some(current_platform())),
+        (
+            "aligner version",
+            &strict.aligner_version,
+            aligner_version.map(str::to_string),
+        ),
+    ];
+    for (what, pinned, actual) in environment {
+        if let (Some(pinned), Some(actual)) = (pinned, actual) {
+            if pinned.trim() != actual.trim() {
+                violations.push(StrictViolation::Environment {
+                    what,
+                    pinned: pinned.clone(),
+                    actual,
+                });
+            }
+        }
+    }
+    if aligner_version.is_some() && strict.aligner_version.is_none() {
+        violations.push(StrictViolation::UnpinnedAligner);
+    }
+    violations
+}
+
+/// `Ok` if there are no violations, otherwise an error listing all of them
+pub fn enforce(violations: Vec<StrictViolation>) -> Result<(), ProcessingError> {
+    if violations.is_empty() {
+        return Ok(());
+    }
+    let list: Vec<String> = violations.iter().map(|v| format!("  - {}", v)).collect();
+    Err(ProcessingError::InvalidParameters(format!(
+        "--strict: refusing to run:\n{}",
+        list.join("\n")
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict_requires_pins_and_matching_database() {
+        let strict = StrictConfig::default();
+        assert_eq!(
+            strict.check_database("abc"),
+            Err(StrictViolation::UnpinnedDatabase)
+        );
+
+        let strict = StrictConfig {
+            database_sha256: Some("ABC\n".to_string()),
+            platform: Some(current_platform()),
+            ..StrictConfig::default()
+        };
+        assert_eq!(strict.check_database("abc"), Ok(()));
+        assert!(matches!(
+            strict.check_database("abd"),
+            Err(StrictViolation::DatabaseChanged { .. })
+        ));
+
+        let message = enforce(vec![
+            StrictViolation::UnpinnedDatabase,
+            StrictViolation::UnpinnedAligner,
+        ])
+        .unwrap_err()
+        .to_string();
+        assert!(message.contains("strict.database_sha256"));
+        assert!(message.contains("strict.aligner_version"));
+        assert!(enforce(Vec::new()).is_ok());
+
+        let mut config = Config::default();
+        config.strict = strict.clone();
+        assert!(check_settings(&config, false, None).is_empty());
+        config.report.language = ReportLanguage::Auto;
+        let violations = check_settings(&config, true, Some("2.26-r1175"));
+        assert_eq!(violations.len(), 3);
+        assert!(violations.contains(&StrictViolation::UnpinnedAligner));
+
+        let mut config = Config::default();
+        config.strict = StrictConfig {
+            platform: Some("plan9-mips".to_string()),
+            ..strict
+        };
+        assert!(matches!(
+            check_settings(&config, false, None)[..],
+            [StrictViolation::Environment {
+                what: "platform",
+                ..
+            }]
+        ));
+    }
+}
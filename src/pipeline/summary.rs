@@ -0,0 +1,357 @@
+//! Summary printed when a command finishes.
+//!
+//! The workflow has several stages (process, suggest references, export,
+//! normalize), and which one comes next is not obvious from log output. Each
+//! command ends with a short block listing what it processed, how many
+//! warnings and alerts to review, where its outputs are, and the commands
+//! that usually follow, ready to copy.
+//!
+//! Batch commands also write `batch_summary.json`, the outcome of every
+//! sample with the reason for each failure, so a study with one corrupted
+//! FASTQ file still gets the rest of its results and a record of what to
+//! rerun. When any sample failed the command exits with
+//! `EXIT_SAMPLES_FAILED` rather than the generic error status.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::pipeline::qc::{ClassificationResults, ProcessingError};
+
+/// Exit status of a batch command in which some samples failed
+pub const EXIT_SAMPLES_FAILED: i32 = 3;
+
+/// File name of the per-sample outcomes of a batch command
+pub const BATCH_SUMMARY_FILE: &str = "batch_summary.json";
+
+/// What a command did and what to run next
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    /// Subcommand that ran
+    pub command: String,
+    /// Program and global arguments that suggested commands start with
+    pub invocation: String,
+    pub samples_processed: usize,
+    /// Samples that failed, with the reason
+    pub failed_samples: Vec<(String, String)>,
+    pub warnings: usize,
+    pub alerts: usize,
+    /// Output description and path
+    pub outputs: Vec<(String, PathBuf)>,
+    /// Description and full command line
+    pub next_steps: Vec<(String, String)>,
+}
+
+impl RunSummary {
+    /// `invocation` is how the program was called, minus the subcommand
+    pub fn new(command: &str, invocation: String) -> Self {
+        RunSummary {
+            command: command.to_string(),
+            invocation,
+            ..RunSummary::default()
+        }
+    }
+
+    /// Count a processed sample with its warnings and alerts, and list its results file
+    pub fn add_sample(&mut self, results: &ClassificationResults) {
+        self.samples_processed += 1;
+        self.warnings += results.warnings.len();
+        self.alerts += results.alerts.len();
+        if let Some(path) = &results.results_file {
+            self.output(&format!("results for {}", results.sample_id), path);
+        }
+    }
+
+    pub fn sample_failed(&mut self, sample_id: &str, reason: impl fmt::Display) {
+        self.failed_samples
+            .push((sample_id.to_string(), reason.to_string()));
+    }
+
+    pub fn output(&mut self, description: &str, path: &Path) {
+        self.outputs
+            .push((description.to_string(), path.to_path_buf()));
+    }
+
+    /// Suggest another subcommand of this program, e.g. `export-phyloseq --results out`
+    pub fn next(&mut self, description: &str, subcommand: &str) {
+        let command = format!("{} {}", self.invocation, subcommand);
+        self.next_steps.push((description.to_string(), command));
+    }
+
+    /// Suggest a command of another program
+    pub fn next_external(&mut self, description: &str, command: &str) {
+        self.next_steps
+            .push((description.to_string(), command.to_string()));
+    }
+
+    /// Follow-up commands for a directory of `*_results.json` files
+    pub fn suggest_for_results(&mut self, results_dir: &Path, samples: &[ClassificationResults]) {
+        let dir = quote(results_dir);
+        if samples
+            .iter()
+            .any(|sample| !sample.reference_suggestions.is_empty())
+        {
+            self.next(
+                "Review references missing from the database",
+                &format!("suggest-references --results {}", dir),
+            );
+        }
+        self.next(
+            "Build count, taxonomy and sample tables for analysis in R",
+            &format!("export-phyloseq --results {} --output phyloseq", dir),
+        );
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "==== {} summary ====", self.command)?;
+        if self.samples_processed > 0 || !self.failed_samples.is_empty() {
+            write!(f, "Samples processed: {}", self.samples_processed)?;
+            if !self.failed_samples.is_empty() {
+                write!(f, " ({} failed)", self.failed_samples.len())?;
+            }
+            writeln!(f)?;
+            for (sample_id, reason) in &self.failed_samples {
+                writeln!(f, "  failed {}: {}", sample_id, reason)?;
+            }
+        }
+        if self.warnings > 0 {
+            writeln!(
+                f,
+                "Warnings: {} (listed at the top of each report)",
+                self.warnings
+            )?;
+        }
+        if self.alerts > 0 {
+            writeln!(f, "WATCHLIST ALERTS: {}", self.alerts)?;
+        }
+        if !self.outputs.is_empty() {
+            writeln!(f, "Outputs:")?;
+            for (description, path) in &self.outputs {
+                writeln!(f, "  {}: {}", description, path.display())?;
+            }
+        }
+        if !self.next_steps.is_empty() {
+            writeln!(f, "Next steps:")?;
+            for (description, command) in &self.next_steps {
+                writeln!(f, "  {}:\n    {}", description, command)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a sample of a batch ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleStatus {
+    Succeeded,
+    /// Nothing to classify, under `--on-degenerate-input error`
+    Degenerate,
+    Failed,
+}
+
+/// Outcome of one sample of a batch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SampleOutcome {
+    pub sample_id: String,
+    pub input: PathBuf,
+    pub status: SampleStatus,
+    /// Why the sample failed
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub results_file: Option<PathBuf>,
+}
+
+/// Outcome of every sample of a batch command
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchSummary {
+    pub command: String,
+    pub samples: Vec<SampleOutcome>,
+}
+
+impl BatchSummary {
+    pub fn new(command: &str) -> Self {
+        BatchSummary {
+            command: command.to_string(),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn succeeded(&mut self, input: &Path, results: &ClassificationResults) {
+        self.samples.push(SampleOutcome {
+            sample_id: results.sample_id.clone(),
+            input: input.to_path_buf(),
+            status: SampleStatus::Succeeded,
+            reason: None,
+            results_file: results.results_file.clone(),
+        });
+    }
+
+    /// Record a failed sample; degenerate input gets its own status
+    pub fn failed(&mut self, sample_id: &str, input: &Path, error: &ProcessingError) {
+        let status = match error {
+            ProcessingError::DegenerateInput(_) => SampleStatus::Degenerate,
+            _ => SampleStatus::Failed,
+        };
+        self.failed_with(sample_id, input, status, error.to_string());
+    }
+
+    /// Record a sample that failed with `status` for `reason`
+    pub fn failed_with(
+        &mut self,
+        sample_id: &str,
+        input: &Path,
+        status: SampleStatus,
+        reason: String,
+    ) {
+        self.samples.push(SampleOutcome {
+            sample_id: sample_id.to_string(),
+            input: input.to_path_buf(),
+            status,
+            reason: Some(reason),
+            results_file: None,
+        });
+    }
+
+    /// Samples that did not succeed
+    pub fn failures(&self) -> impl Iterator<Item = &SampleOutcome> {
+        self.samples
+            .iter()
+            .filter(|sample| sample.status != SampleStatus::Succeeded)
+    }
+
+    /// Write `batch_summary.json` to `dir`, returning its path
+    pub fn write(&self, dir: &Path) -> io::Result<PathBuf> {
+        let path = dir.join(BATCH_SUMMARY_FILE);
+        let writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer_pretty(writer, self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(path)
+    }
+
+    /// `Err` listing the failed samples if there are any
+    pub fn check(&self, summary_path: &Path) -> Result<(), SamplesFailed> {
+        let failed: Vec<String> = self
+            .failures()
+            .map(|sample| sample.sample_id.clone())
+            .collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(SamplesFailed {
+            failed,
+            total: self.samples.len(),
+            summary_path: summary_path.to_path_buf(),
+        })
+    }
+}
+
+/// Some samples of a batch failed; the rest have results
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{} of {total} samples failed: {} (details in {})", failed.len(), failed.join(", "), summary_path.display())]
+pub struct SamplesFailed {
+    pub failed: Vec<String>,
+    pub total: usize,
+    pub summary_path: PathBuf,
+}
+
+/// Exit status for an error returned by a command: `EXIT_SAMPLES_FAILED`
+/// when only some samples of a batch failed, 1 otherwise
+pub fn exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    if error.downcast_ref::<SamplesFailed>().is_some() {
+        EXIT_SAMPLES_FAILED
+    } else {
+        1
+    }
+}
+
+/// A path as a shell argument, quoted if it contains spaces or quotes
+pub fn quote(path: &Path) -> String {
+    let path = path.display().to_string();
+    if path
+        .chars()
+        .any(|c| c.is_whitespace() || matches!(c, '\'' | '"' | '$' | '&' | ';'))
+    {
+        format!("'{}'", path.replace('\'', r"'\''"))
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::golden::toy_results;
+
+    #[test]
+    fn test_summary_lists_outputs_and_next_steps() {
+        let mut summary = RunSummary::new(
+            "process-dir",
+            "strain_ahsp --db-path db --cache-dir cache".to_string(),
+        );
+        let results = toy_results();
+        for sample in &results {
+            summary.add_sample(sample);
+        }
+        summary.sample_failed("S3", "0 of 10 reads passed QC");
+        summary.suggest_for_results(Path::new("my results"), &results);
+
+        let text = summary.to_string();
+        assert!(text.starts_with("==== process-dir summary ====\n"));
+        assert!(text.contains("Samples processed: 2 (1 failed)\n"));
+        assert!(text.contains("  results for S1: results/S1_results.json\n"));
+        assert!(text.contains(
+            "    strain_ahsp --db-path db --cache-dir cache export-phyloseq --results 'my results' --output phyloseq\n"
+        ));
+        assert_eq!(quote(Path::new("it's")), r"'it'\''s'");
+        assert_eq!(quote(Path::new("plain/dir")), "plain/dir");
+    }
+
+    #[test]
+    fn test_batch_summary_records_failures() {
+        let results = toy_results();
+        let mut batch = BatchSummary::new("process-dir");
+        batch.succeeded(Path::new("in/S1.fq"), &results[0]);
+        batch.failed(
+            "S2",
+            Path::new("in/S2.fq"),
+            &ProcessingError::FastqError("truncated record".to_string()),
+        );
+        batch.failed(
+            "S3",
+            Path::new("in/S3.fq"),
+            &ProcessingError::DegenerateInput("no reads".to_string()),
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = batch.write(dir.path()).unwrap();
+        let written: BatchSummary = serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(written, batch);
+        assert_eq!(written.samples[1].status, SampleStatus::Failed);
+        assert!(written.samples[1]
+            .reason
+            .as_deref()
+            .unwrap()
+            .contains("truncated record"));
+        assert_eq!(written.samples[2].status, SampleStatus::Degenerate);
+
+        let error = batch.check(&path).unwrap_err();
+        assert_eq!(error.failed, vec!["S2", "S3"]);
+        assert_eq!(error.total, 3);
+        let boxed: Box<dyn std::error::Error> = Box::new(error);
+        assert_eq!(exit_code(boxed.as_ref()), EXIT_SAMPLES_FAILED);
+        let other: Box<dyn std::error::Error> = "bad config".into();
+        assert_eq!(exit_code(other.as_ref()), 1);
+
+        let mut clean = BatchSummary::new("process-dir");
+        clean.succeeded(Path::new("in/S1.fq"), &results[0]);
+        assert!(clean.check(&path).is_ok());
+    }
+}
@@ -0,0 +1,365 @@
+//! OpenTelemetry traces and metrics for service deployments.
+//!
+//! A service that processes samples as they arrive is watched from the same
+//! dashboards as the rest of a lab's systems, not from its log files. Built
+//! with the `telemetry` feature and with `"telemetry": {"enabled": true}` in
+//! the config, each sample becomes a trace whose child spans are the pipeline
+//! stages (reading and sketching, classification, strain estimation,
+//! confirmation, writing results), and counters of reads, classifications and
+//! samples by outcome are exported alongside. Both go over OTLP/HTTP to a
+//! collector (the OpenTelemetry Collector, Grafana Alloy), from which
+//! throughput and failure rates can be graphed.
+//!
+//! Without the feature, or with telemetry disabled, `Telemetry` and its spans
+//! hold nothing and every call on them returns at once.
+
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::qc::{ClassificationResults, ProcessingError, ProcessingMetrics};
+
+/// OTLP/HTTP endpoint of a collector running on the same host
+pub const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4318";
+
+/// Export settings, the `telemetry` section of the config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Export traces and metrics; requires the `telemetry` feature
+    pub enabled: bool,
+
+    /// Base URL of the OTLP/HTTP collector; `/v1/traces` and `/v1/metrics`
+    /// are appended
+    pub endpoint: String,
+
+    /// `service.name` the traces and metrics are exported under
+    pub service_name: String,
+
+    /// Seconds between metric exports
+    pub export_interval_secs: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            enabled: false,
+            endpoint: DEFAULT_OTLP_ENDPOINT.to_string(),
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+            export_interval_secs: 30,
+        }
+    }
+}
+
+/// Handle to the trace and metric exporters, shared by clones; exporting
+/// stops, after a last flush, when the final clone is dropped
+#[derive(Clone, Default)]
+pub struct Telemetry {
+    exporters: Option<Arc<otel::Exporters>>,
+}
+
+impl Telemetry {
+    /// Start exporting as `config` says. Fails when telemetry is enabled in
+    /// a build without the `telemetry` feature, rather than running unwatched.
+    pub fn init(config: &TelemetryConfig) -> Result<Self> {
+        if !config.enabled {
+            return Ok(Telemetry::default());
+        }
+        let exporters = otel::Exporters::start(config)?;
+        info!("Exporting traces and metrics to {}", config.endpoint);
+        Ok(Telemetry {
+            exporters: Some(Arc::new(exporters)),
+        })
+    }
+
+    /// Whether traces and metrics are exported
+    pub fn is_enabled(&self) -> bool {
+        self.exporters.is_some()
+    }
+
+    /// Root span of the processing of `sample_id`
+    pub fn sample(&self, sample_id: &str) -> StageSpan {
+        StageSpan {
+            span: self
+                .exporters
+                .as_ref()
+                .map(|exporters| exporters.sample_span(sample_id)),
+        }
+    }
+
+    /// Count the reads of a sample, and those that passed QC
+    pub fn record_reads(&self, metrics: &ProcessingMetrics) {
+        if let Some(exporters) = &self.exporters {
+            exporters.record_reads(metrics);
+        }
+    }
+
+    /// End the span of a sample, failed if `result` is an error, and count
+    /// the sample by outcome and its top classification by rank
+    pub fn finish_sample(
+        &self,
+        mut span: StageSpan,
+        result: &Result<ClassificationResults, ProcessingError>,
+    ) {
+        if let Err(e) = result {
+            span.fail(e);
+        }
+        let Some(exporters) = &self.exporters else {
+            return;
+        };
+        let outcome = match result {
+            Ok(_) => "succeeded",
+            Err(ProcessingError::DegenerateInput(_)) => "degenerate",
+            Err(_) => "failed",
+        };
+        let level = result
+            .as_ref()
+            .ok()
+            .and_then(|results| results.classifications.first())
+            .map(|classification| format!("{:?}", classification.level));
+        exporters.record_sample(outcome, level);
+    }
+}
+
+/// A span of a sample's trace, ended when dropped
+#[derive(Default)]
+pub struct StageSpan {
+    span: Option<otel::Span>,
+}
+
+impl StageSpan {
+    /// Span of the stage `name`, as a child of this one
+    pub fn stage(&self, name: &'static str) -> StageSpan {
+        StageSpan {
+            span: self.span.as_ref().map(|span| span.child(name)),
+        }
+    }
+
+    /// Mark the span as failed with `error`
+    pub fn fail(&mut self, error: &dyn fmt::Display) {
+        if let Some(span) = &mut self.span {
+            span.fail(error);
+        }
+    }
+}
+
+#[cfg(feature = "telemetry")]
+mod otel {
+    use std::fmt;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use log::warn;
+    use opentelemetry::metrics::{Counter, MeterProvider as _};
+    use opentelemetry::trace::{
+        Span as _, Status, TraceContextExt as _, Tracer as _, TracerProvider as _,
+    };
+    use opentelemetry::{Context, KeyValue};
+    use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig as _};
+    use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::trace::{self, SdkTracerProvider};
+    use opentelemetry_sdk::Resource;
+
+    use super::TelemetryConfig;
+    use crate::pipeline::qc::ProcessingMetrics;
+
+    /// Name of the tracer and meter
+    const SCOPE: &str = "strain_ahsp";
+
+    pub struct Exporters {
+        tracer_provider: SdkTracerProvider,
+        meter_provider: SdkMeterProvider,
+        tracer: trace::Tracer,
+        reads: Counter<u64>,
+        reads_passed: Counter<u64>,
+        samples: Counter<u64>,
+        classifications: Counter<u64>,
+    }
+
+    impl Exporters {
+        pub fn start(config: &TelemetryConfig) -> Result<Self> {
+            let endpoint = config.endpoint.trim_end_matches('/');
+            let resource = Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build();
+
+            let span_exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint))
+                .build()?;
+            let tracer_provider = SdkTracerProvider::builder()
+                .with_batch_exporter(span_exporter)
+                .with_resource(resource.clone())
+                .build();
+
+            let metric_exporter = MetricExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/metrics", endpoint))
+                .build()?;
+            let reader = PeriodicReader::builder(metric_exporter)
+                .with_interval(Duration::from_secs(config.export_interval_secs.max(1)))
+                .build();
+            let meter_provider = SdkMeterProvider::builder()
+                .with_reader(reader)
+                .with_resource(resource)
+                .build();
+
+            let meter = meter_provider.meter(SCOPE);
+            Ok(Exporters {
+                tracer: tracer_provider.tracer(SCOPE),
+                reads: meter
+                    .u64_counter("ahsp.reads")
+                    .with_description("Reads read from input files")
+                    .build(),
+                reads_passed: meter
+                    .u64_counter("ahsp.reads.passed_qc")
+                    .with_description("Reads that passed quality control")
+                    .build(),
+                samples: meter
+                    .u64_counter("ahsp.samples")
+                    .with_description("Samples processed, by outcome")
+                    .build(),
+                classifications: meter
+                    .u64_counter("ahsp.classifications")
+                    .with_description("Top classifications made, by rank")
+                    .build(),
+                tracer_provider,
+                meter_provider,
+            })
+        }
+
+        pub fn sample_span(&self, sample_id: &str) -> Span {
+            let mut span = self.tracer.start("sample");
+            span.set_attribute(KeyValue::new("sample_id", sample_id.to_string()));
+            Span {
+                tracer: self.tracer.clone(),
+                span,
+            }
+        }
+
+        pub fn record_reads(&self, metrics: &ProcessingMetrics) {
+            self.reads.add(metrics.total_reads as u64, &[]);
+            self.reads_passed.add(metrics.passed_reads as u64, &[]);
+        }
+
+        pub fn record_sample(&self, outcome: &'static str, level: Option<String>) {
+            self.samples.add(1, &[KeyValue::new("outcome", outcome)]);
+            if let Some(level) = level {
+                self.classifications
+                    .add(1, &[KeyValue::new("level", level)]);
+            }
+        }
+    }
+
+    impl Drop for Exporters {
+        fn drop(&mut self) {
+            if let Err(e) = self.tracer_provider.shutdown() {
+                warn!("Cannot flush traces: {}", e);
+            }
+            if let Err(e) = self.meter_provider.shutdown() {
+                warn!("Cannot flush metrics: {}", e);
+            }
+        }
+    }
+
+    /// A span with the tracer its children are started from
+    pub struct Span {
+        tracer: trace::Tracer,
+        span: trace::Span,
+    }
+
+    impl Span {
+        pub fn child(&self, name: &'static str) -> Span {
+            let parent = Context::new().with_remote_span_context(self.span.span_context().clone());
+            Span {
+                tracer: self.tracer.clone(),
+                span: self.tracer.start_with_context(name, &parent),
+            }
+        }
+
+        pub fn fail(&mut self, error: &dyn fmt::Display) {
+            self.span.set_status(Status::error(error.to_string()));
+        }
+    }
+}
+
+/// Stand-ins for a build without the `telemetry` feature; they cannot be
+/// constructed, so telemetry stays disabled
+#[cfg(not(feature = "telemetry"))]
+mod otel {
+    use std::fmt;
+
+    use anyhow::{bail, Result};
+
+    use super::TelemetryConfig;
+    use crate::pipeline::qc::ProcessingMetrics;
+
+    pub enum Exporters {}
+
+    impl Exporters {
+        pub fn start(_config: &TelemetryConfig) -> Result<Self> {
+            bail!("telemetry is enabled in the config but strain_ahsp was built without the `telemetry` feature")
+        }
+
+        pub fn sample_span(&self, _sample_id: &str) -> Span {
+            match *self {}
+        }
+
+        pub fn record_reads(&self, _metrics: &ProcessingMetrics) {
+            match *self {}
+        }
+
+        pub fn record_sample(&self, _outcome: &'static str, _level: Option<String>) {
+            match *self {}
+        }
+    }
+
+    pub enum Span {}
+
+    impl Span {
+        pub fn child(&self, _name: &'static str) -> Span {
+            match *self {}
+        }
+
+        pub fn fail(&mut self, _error: &dyn fmt::Display) {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_telemetry_does_nothing() {
+        let config: TelemetryConfig =
+            serde_json::from_str(r#"{"service_name": "ahsp-lab"}"#).unwrap();
+        assert!(!config.enabled);
+        assert_eq!(config.endpoint, DEFAULT_OTLP_ENDPOINT);
+        assert_eq!(config.service_name, "ahsp-lab");
+
+        let telemetry = Telemetry::init(&config).unwrap();
+        assert!(!telemetry.is_enabled());
+        let sample = telemetry.sample("S1");
+        let mut stage = sample.stage("classify");
+        stage.fail(&"no references");
+        drop(stage);
+        telemetry.finish_sample(
+            sample,
+            &Err(ProcessingError::DegenerateInput("no reads".to_string())),
+        );
+
+        let enabled = TelemetryConfig {
+            enabled: true,
+            ..config
+        };
+        assert_eq!(
+            Telemetry::init(&enabled).is_err(),
+            cfg!(not(feature = "telemetry"))
+        );
+    }
+}
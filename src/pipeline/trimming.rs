@@ -0,0 +1,194 @@
+//! Per-base quality trimming strategies.
+//!
+//! `Threshold` keeps everything between the first and last base at or above
+//! `trim_quality`, so a low-quality stretch in the middle of a read survives.
+//! `SlidingWindow` (Trimmomatic's SLIDINGWINDOW) cuts the read where the
+//! average quality of a window first drops below the threshold, and `Mott`
+//! keeps the maximum-scoring segment under the modified Mott algorithm used by
+//! phred and many assemblers.
+//!
+//! Poly-G tails are trimmed before any of these. Two-colour instruments
+//! (NextSeq, NovaSeq) read "no signal" as G with high quality, so the tails
+//! survive quality trimming and would otherwise fill sketches with identical
+//! G-run k-mers.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// Default window length for `TrimmingStrategy::SlidingWindow`
+pub const DEFAULT_TRIM_WINDOW: usize = 4;
+
+/// Default minimum length of a poly-G tail to trim
+pub const DEFAULT_POLY_G_MIN_LENGTH: usize = 10;
+
+/// One mismatch is tolerated per this many tail bases
+const POLY_TAIL_BASES_PER_MISMATCH: usize = 8;
+
+/// Mismatches after which a tail scan always stops
+const POLY_TAIL_MAX_MISMATCHES: usize = 5;
+
+/// How reads are trimmed against `trim_quality`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TrimmingStrategy {
+    /// Keep the span from the first to the last base at or above the threshold
+    #[default]
+    Threshold,
+    /// Drop leading bases below the threshold, then cut at the first window
+    /// whose mean quality is below it
+    SlidingWindow,
+    /// Keep the segment maximizing the sum of `limit - p_error`, where `limit`
+    /// is the error probability of the threshold quality
+    Mott,
+}
+
+/// Range of `phred` (quality values, offset already removed) to keep, or
+/// `None` if no base is worth keeping
+pub fn trim_range(
+    strategy: TrimmingStrategy,
+    phred: &[u8],
+    threshold: u8,
+    window: usize,
+) -> Option<Range<usize>> {
+    match strategy {
+        TrimmingStrategy::Threshold => threshold_range(phred, threshold),
+        TrimmingStrategy::SlidingWindow => sliding_window_range(phred, threshold, window),
+        TrimmingStrategy::Mott => mott_range(phred, threshold),
+    }
+}
+
+fn threshold_range(phred: &[u8], threshold: u8) -> Option<Range<usize>> {
+    let start = phred.iter().position(|&q| q >= threshold)?;
+    let end = phred.iter().rposition(|&q| q >= threshold)? + 1;
+    Some(start..end)
+}
+
+fn sliding_window_range(phred: &[u8], threshold: u8, window: usize) -> Option<Range<usize>> {
+    let start = phred.iter().position(|&q| q >= threshold)?;
+    let rest = &phred[start..];
+    let window = window.clamp(1, rest.len());
+    let required = threshold as u32 * window as u32;
+
+    let mut end = rest.len();
+    let mut sum: u32 = rest[..window].iter().map(|&q| q as u32).sum();
+    for i in 0..=rest.len() - window {
+        if i > 0 {
+            sum += rest[i + window - 1] as u32;
+            sum -= rest[i - 1] as u32;
+        }
+        if sum < required {
+            // Like Trimmomatic, keep the good bases leading into the failing window
+            end = i + rest[i..i + window]
+                .iter()
+                .take_while(|&&q| q >= threshold)
+                .count();
+            break;
+        }
+    }
+    while end > 0 && rest[end - 1] < threshold {
+        end -= 1;
+    }
+    (end > 0).then_some(start..start + end)
+}
+
+fn mott_range(phred: &[u8], threshold: u8) -> Option<Range<usize>> {
+    let error = |q: u8| 10f64.powf(-(q as f64) / 10.0);
+    let limit = error(threshold);
+
+    // Kadane's maximum-sum segment
+    let (mut best, mut best_range) = (0.0, None);
+    let (mut sum, mut start) = (0.0, 0);
+    for (i, &q) in phred.iter().enumerate() {
+        if sum <= 0.0 {
+            sum = 0.0;
+            start = i;
+        }
+        sum += limit - error(q);
+        if sum > best {
+            best = sum;
+            best_range = Some(start..i + 1);
+        }
+    }
+    best_range
+}
+
+/// Length of the run of `base` at the 3' end of `seq`, or 0 if shorter than
+/// `min_length`.
+///
+/// As in fastp, the scan from the 3' end tolerates one mismatch per eight
+/// bases scanned (and at most five overall), so a sequencing error inside a
+/// long tail does not end it. The allowance applies from the first base, so a
+/// GC-rich 3' end with a mismatch every few bases is not taken for a tail.
+/// The tail's 5' end is always a `base`.
+pub fn poly_tail_length(seq: &[u8], base: u8, min_length: usize) -> usize {
+    let mut mismatches = 0;
+    let mut tail = 0;
+    for (i, b) in seq.iter().rev().enumerate() {
+        if b.eq_ignore_ascii_case(&base) {
+            tail = i + 1;
+            continue;
+        }
+        mismatches += 1;
+        let allowed = (i + 1) / POLY_TAIL_BASES_PER_MISMATCH;
+        if mismatches > POLY_TAIL_MAX_MISMATCHES || mismatches > allowed {
+            break;
+        }
+    }
+    if tail >= min_length.max(1) {
+        tail
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strategies_handle_interior_low_quality() {
+        // Good start, a low-quality stretch, then good bases again
+        let phred = [30, 30, 30, 30, 30, 30, 2, 2, 2, 2, 30, 30];
+
+        assert_eq!(
+            trim_range(TrimmingStrategy::Threshold, &phred, 15, 4),
+            Some(0..12)
+        );
+        assert_eq!(
+            trim_range(TrimmingStrategy::SlidingWindow, &phred, 15, 4),
+            Some(0..6)
+        );
+        assert_eq!(
+            trim_range(TrimmingStrategy::Mott, &phred, 15, 4),
+            Some(0..6)
+        );
+    }
+
+    #[test]
+    fn test_trimming_ends_and_all_bad_reads() {
+        let phred = [5, 5, 35, 35, 35, 35, 35, 5];
+        for strategy in [
+            TrimmingStrategy::Threshold,
+            TrimmingStrategy::SlidingWindow,
+            TrimmingStrategy::Mott,
+        ] {
+            assert_eq!(trim_range(strategy, &phred, 20, 3), Some(2..7));
+            assert_eq!(trim_range(strategy, &[3, 4, 5], 20, 3), None);
+        }
+    }
+
+    #[test]
+    fn test_poly_tail_length() {
+        // A sequencing error inside the tail does not end it
+        let read = b"ACGTACGTACTCGGGAGGGGGGGGGGG";
+        assert_eq!(poly_tail_length(read, b'G', 10), 15);
+        assert_eq!(poly_tail_length(read, b'G', 20), 0);
+        // Mismatches near the 3' end are not yet covered by the allowance
+        assert_eq!(poly_tail_length(b"ACGTAAAAAAAAAAAC", b'A', 10), 0);
+        assert_eq!(poly_tail_length(b"ACGTACGTAAAAACGT", b'A', 10), 0);
+        // A G-rich genuine 3' end (60% G) is not a poly-G tail
+        assert_eq!(poly_tail_length(b"ACGTACGTGAGCGTGAGG", b'G', 10), 0);
+        assert_eq!(poly_tail_length(b"ACGTaaaaaaaaaaaa", b'A', 10), 12);
+    }
+}
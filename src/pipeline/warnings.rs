@@ -0,0 +1,755 @@
+//! Warnings about parameter and database mismatches.
+//!
+//! A query sketched with different parameters than the references (or a
+//! database mixing several builds) still produces numbers, just not
+//! meaningful ones. These checks collect every such mismatch so results JSON
+//! and reports can show them next to the classifications they affect.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::qc::{ClassificationResults, ProcessingMetrics};
+use crate::pipeline::rna::SequencingMode;
+use crate::sketch::signature::LevelParameters;
+use crate::sketch::MultiResolutionSignature;
+use crate::stats::reconciliation::AbundanceReconciliation;
+
+/// Position of the species name in a lineage
+const SPECIES_INDEX: usize = 6;
+
+/// What a warning is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    /// Query and references use different k-mer sizes
+    KmerSize,
+    /// Query and references use different sketching algorithms
+    SketchAlgorithm,
+    /// Query and references use different sketch sizes or scaled factors
+    SketchSize,
+    /// Query and references describe different molecule types
+    MoleculeType,
+    /// Query and references have a different number of resolution levels
+    ResolutionLevels,
+    /// References in the database were built with different parameters
+    MixedDatabaseParameters,
+    /// Reference lineages disagree, suggesting different taxonomy releases
+    TaxonomyVersion,
+    /// Samples being combined were produced by different databases or parameters
+    RunMismatch,
+    /// k-mer sizes are large relative to the reads
+    ReadLength,
+    /// The sample produced nothing to classify, or nothing matched
+    DegenerateInput,
+    /// Too few reads were classified for the calls to be relied on
+    InsufficientData,
+    /// Strain abundances add up to more than their species
+    AbundanceInconsistency,
+    /// Many reads match a control panel (adapters, host, vectors)
+    Contamination,
+    /// Aligning the reads to a called reference covers little of it
+    UnconfirmedCall,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WarningKind::KmerSize => "k-mer size",
+            WarningKind::SketchAlgorithm => "sketch algorithm",
+            WarningKind::SketchSize => "sketch size",
+            WarningKind::MoleculeType => "molecule type",
+            WarningKind::ResolutionLevels => "resolution levels",
+            WarningKind::MixedDatabaseParameters => "mixed database parameters",
+            WarningKind::TaxonomyVersion => "taxonomy version",
+            WarningKind::RunMismatch => "run mismatch",
+            WarningKind::ReadLength => "read length",
+            WarningKind::DegenerateInput => "degenerate input",
+            WarningKind::InsufficientData => "insufficient data",
+            WarningKind::AbundanceInconsistency => "abundance inconsistency",
+            WarningKind::Contamination => "contamination",
+            WarningKind::UnconfirmedCall => "unconfirmed call",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A mismatch and its likely effect on interpretation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportWarning {
+    pub kind: WarningKind,
+    /// Resolution level the mismatch applies to, if level-specific
+    pub level: Option<usize>,
+    /// What differs
+    pub detail: String,
+    /// Likely effect on interpretation
+    pub effect: String,
+}
+
+impl fmt::Display for ReportWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.level {
+            Some(level) => write!(f, "[{}, level {}] {}", self.kind, level, self.detail)?,
+            None => write!(f, "[{}] {}", self.kind, self.detail)?,
+        }
+        write!(f, "\n      Effect: {}", self.effect)
+    }
+}
+
+/// How a sample with degenerate input or empty results is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DegenerateInputPolicy {
+    /// Fail the sample; the command exits with a non-zero status
+    Error,
+    /// Record a warning in the results and carry on
+    #[default]
+    Warn,
+}
+
+/// Sketch parameters and taxonomy consistency of a set of reference signatures
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseProfile {
+    /// Distinct parameters found at each resolution level
+    pub levels: Vec<BTreeSet<LevelParameters>>,
+    /// Problems within the database itself (mixed builds, inconsistent lineages)
+    pub warnings: Vec<ReportWarning>,
+}
+
+impl DatabaseProfile {
+    /// Profile the reference signatures
+    pub fn from_signatures(references: &[MultiResolutionSignature]) -> Self {
+        let mut levels: Vec<BTreeSet<LevelParameters>> = Vec::new();
+        for reference in references {
+            for (i, level) in reference.levels.iter().enumerate() {
+                if levels.len() <= i {
+                    levels.resize_with(i + 1, BTreeSet::new);
+                }
+                levels[i].insert(LevelParameters::of(level));
+            }
+        }
+
+        let mut warnings = Vec::new();
+        for (i, params) in levels.iter().enumerate() {
+            if params.len() > 1 {
+                warnings.push(ReportWarning {
+                    kind: WarningKind::MixedDatabaseParameters,
+                    level: Some(i),
+                    detail: format!(
+                        "references use {} parameter sets: {}",
+                        params.len(),
+                        join(params)
+                    ),
+                    effect: "Scores against references built with other parameters are not \
+                             comparable, so rankings between those references are unreliable"
+                        .to_string(),
+                });
+            }
+        }
+        warnings.extend(taxonomy_warnings(references));
+
+        DatabaseProfile { levels, warnings }
+    }
+
+    /// Database warnings plus every mismatch between `query` and the references
+    pub fn check_query(&self, query: &MultiResolutionSignature) -> Vec<ReportWarning> {
+        let mut warnings = self.warnings.clone();
+        if query.levels.len() != self.levels.len() && !self.levels.is_empty() {
+            warnings.push(ReportWarning {
+                kind: WarningKind::ResolutionLevels,
+                level: None,
+                detail: format!(
+                    "query has {} resolution levels, database has {}",
+                    query.levels.len(),
+                    self.levels.len()
+                ),
+                effect: "Levels present on only one side are ignored, so classification \
+                         relies on fewer resolutions than expected"
+                    .to_string(),
+            });
+        }
+
+        for (i, (level, reference_params)) in query.levels.iter().zip(&self.levels).enumerate() {
+            let query_params = LevelParameters::of(level);
+            if reference_params.is_empty() || reference_params.contains(&query_params) {
+                continue;
+            }
+            let mismatch = |field: fn(&LevelParameters) -> String| {
+                let reference_values: BTreeSet<String> =
+                    reference_params.iter().map(field).collect();
+                let query_value = field(&query_params);
+                (!reference_values.contains(&query_value)).then(|| {
+                    format!(
+                        "query {}, database {}",
+                        query_value,
+                        join(&reference_values)
+                    )
+                })
+            };
+
+            if let Some(detail) = mismatch(|p| format!("k={}", p.kmer_size)) {
+                warnings.push(ReportWarning {
+                    kind: WarningKind::KmerSize,
+                    level: Some(i),
+                    detail,
+                    effect: "Different k-mers share no hashes; similarity at this level is \
+                             near zero and its classifications should be disregarded"
+                        .to_string(),
+                });
+            }
+            if let Some(detail) = mismatch(|p| p.algorithm.clone()) {
+                warnings.push(ReportWarning {
+                    kind: WarningKind::SketchAlgorithm,
+                    level: Some(i),
+                    detail,
+                    effect: "Sketches from different algorithms cannot be compared; this level \
+                             contributes no usable similarity"
+                        .to_string(),
+                });
+            }
+            if let Some(detail) = mismatch(|p| format!("num={} scaled={}", p.num_hashes, p.scaled))
+            {
+                warnings.push(ReportWarning {
+                    kind: WarningKind::SketchSize,
+                    level: Some(i),
+                    detail,
+                    effect: "Similarities are estimated from the smaller sketch, so confidence \
+                             values are noisier than reported"
+                        .to_string(),
+                });
+            }
+            if let Some(detail) = mismatch(|p| p.molecule_type.clone()) {
+                warnings.push(ReportWarning {
+                    kind: WarningKind::MoleculeType,
+                    level: Some(i),
+                    detail,
+                    effect: "Sketches of different molecule types are not compared; matches at \
+                             this level are missing"
+                        .to_string(),
+                });
+            }
+        }
+        warnings
+    }
+}
+
+/// Flag species whose references disagree on the ranks above them, which
+/// happens when the references were annotated with different taxonomy releases
+fn taxonomy_warnings(references: &[MultiResolutionSignature]) -> Vec<ReportWarning> {
+    let mut parents: BTreeMap<&str, BTreeSet<&[String]>> = BTreeMap::new();
+    for reference in references {
+        if let Some(species) = reference.lineage.get(SPECIES_INDEX) {
+            if !species.is_empty() {
+                parents
+                    .entry(species.as_str())
+                    .or_default()
+                    .insert(&reference.lineage[..SPECIES_INDEX]);
+            }
+        }
+    }
+
+    parents
+        .into_iter()
+        .filter(|(_, lineages)| lineages.len() > 1)
+        .map(|(species, lineages)| ReportWarning {
+            kind: WarningKind::TaxonomyVersion,
+            level: None,
+            detail: format!(
+                "references for {} have {} different lineages: {}",
+                species,
+                lineages.len(),
+                join(lineages.iter().map(|l| l.join(";")))
+            ),
+            effect: "Higher-rank names for this species depend on which reference matched; \
+                     summaries above species level may split or misassign it"
+                .to_string(),
+        })
+        .collect()
+}
+
+/// Warnings for samples combined into one table that came from different
+/// databases or parameter sets (per their provenance)
+pub fn check_run_consistency(samples: &[ClassificationResults]) -> Vec<ReportWarning> {
+    let mut databases: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut parameters: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut modes: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for sample in samples {
+        let mode = match sample.sequencing_mode {
+            SequencingMode::Dna => "DNA",
+            SequencingMode::Rna => "RNA",
+        };
+        modes.entry(mode).or_default().push(&sample.sample_id);
+        let provenance = sample.provenance.as_ref();
+        let database = provenance
+            .and_then(|p| p.database_sha256.as_deref())
+            .unwrap_or("unknown");
+        let params = provenance
+            .map(|p| p.parameters_sha256.as_str())
+            .unwrap_or("unknown");
+        databases
+            .entry(database)
+            .or_default()
+            .push(&sample.sample_id);
+        parameters
+            .entry(params)
+            .or_default()
+            .push(&sample.sample_id);
+    }
+
+    let mut warnings = Vec::new();
+    if databases.len() > 1 {
+        warnings.push(ReportWarning {
+            kind: WarningKind::RunMismatch,
+            level: None,
+            detail: format!(
+                "samples were classified against {} databases: {}",
+                databases.len(),
+                describe_groups(&databases)
+            ),
+            effect: "Taxa missing from one database read as absent in its samples; \
+                     between-sample differences may reflect the database, not biology"
+                .to_string(),
+        });
+    }
+    if parameters.len() > 1 {
+        warnings.push(ReportWarning {
+            kind: WarningKind::RunMismatch,
+            level: None,
+            detail: format!(
+                "samples were processed with {} parameter sets: {}",
+                parameters.len(),
+                describe_groups(&parameters)
+            ),
+            effect: "QC and sketch settings change read counts and sensitivity, so \
+                     abundances are not directly comparable across these groups"
+                .to_string(),
+        });
+    }
+    if modes.len() > 1 {
+        warnings.push(ReportWarning {
+            kind: WarningKind::RunMismatch,
+            level: None,
+            detail: format!(
+                "samples mix metagenomes and metatranscriptomes: {}",
+                join(modes.iter().map(|(mode, samples)| format!(
+                    "{} ({})",
+                    mode,
+                    samples.join(", ")
+                )))
+            ),
+            effect: "RNA counts measure expression and DNA counts measure genome abundance; \
+                     analyze the two library types separately or model the type explicitly"
+                .to_string(),
+        });
+    }
+    warnings
+}
+
+fn describe_groups(groups: &BTreeMap<&str, Vec<&str>>) -> String {
+    join(groups.iter().map(|(digest, samples)| {
+        format!(
+            "{} ({})",
+            &digest[..digest.len().min(12)],
+            samples.join(", ")
+        )
+    }))
+}
+
+/// Check k-mer sizes against the lengths of reads sampled after QC.
+///
+/// `kmer_sizes` pairs each k with its resolution level (`None` for configured
+/// sizes not tied to a level). A k above `max_fraction` of the median read
+/// length gets a warning, since each read then yields few k-mers. A k that most
+/// reads are too short to hold would leave the sketch (nearly) empty, so it is
+/// returned as an error message instead.
+pub fn read_length_warnings(
+    kmer_sizes: &[(Option<usize>, usize)],
+    read_lengths: &[usize],
+    max_fraction: f64,
+) -> Result<Vec<ReportWarning>, String> {
+    if read_lengths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut sorted = read_lengths.to_vec();
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2];
+
+    let mut warnings = Vec::new();
+    let mut too_long = Vec::new();
+    for &(level, k) in kmer_sizes {
+        let holding = sorted.iter().filter(|&&len| len >= k).count();
+        if holding * 2 < sorted.len() {
+            too_long.push(format!(
+                "k={} ({} of {} sampled reads are at least {} bp)",
+                k,
+                holding,
+                sorted.len(),
+                k
+            ));
+        } else if k as f64 > max_fraction * median as f64 {
+            warnings.push(ReportWarning {
+                kind: WarningKind::ReadLength,
+                level,
+                detail: format!(
+                    "k={} is {:.0}% of the median read length after QC ({} bp)",
+                    k,
+                    100.0 * k as f64 / median as f64,
+                    median
+                ),
+                effect: format!(
+                    "each read yields only ~{} k-mers; sketches are sparse and sensitivity is reduced",
+                    median + 1 - k
+                ),
+            });
+        }
+    }
+    if too_long.is_empty() {
+        Ok(warnings)
+    } else {
+        Err(format!(
+            "k-mer sizes exceed most read lengths after QC: {}",
+            too_long.join("; ")
+        ))
+    }
+}
+
+/// Check whether a processed sample left anything to classify.
+///
+/// Returns at most one warning, for the earliest stage that came up empty: no
+/// reads at all, no reads passing QC, no k-mers sketched, or no reference
+/// matching. The detail names the likely fix.
+pub fn degenerate_input_warning(
+    metrics: &ProcessingMetrics,
+    sample: &MultiResolutionSignature,
+    classifications: usize,
+) -> Option<ReportWarning> {
+    let no_results = "The sample has no classifications or strain abundances".to_string();
+    let (detail, effect) = if metrics.total_reads == 0 {
+        (
+            "input contains no reads: check the file path and that it is FASTQ or FASTA"
+                .to_string(),
+            no_results,
+        )
+    } else if metrics.passed_reads == 0 && metrics.rrna_reads > 0 {
+        (
+            format!(
+                "all {} reads passing QC were rRNA: check the library's rRNA depletion, or \
+                 whether it is a metatranscriptome at all",
+                metrics.rrna_reads
+            ),
+            no_results,
+        )
+    } else if metrics.passed_reads == 0 && metrics.control_reads_removed > 0 {
+        (
+            format!(
+                "all {} reads passing QC matched a control panel marked for removal (e.g. \
+                 PhiX): the run may hold only the spike-in",
+                metrics.control_reads_removed
+            ),
+            no_results,
+        )
+    } else if metrics.passed_reads == 0 {
+        (
+            format!(
+                "0 of {} reads passed QC: lower --min-length or --min-quality, or check the \
+                 quality encoding (Phred+33 expected)",
+                metrics.total_reads
+            ),
+            no_results,
+        )
+    } else if sample.levels.iter().all(|l| l.sketch.hashes.is_empty()) {
+        (
+            format!(
+                "no k-mers were sketched from {} reads passing QC: reads may be shorter than k \
+                 or mostly N; use a smaller k",
+                metrics.passed_reads
+            ),
+            no_results,
+        )
+    } else if classifications == 0 {
+        (
+            "no reference matched the sample: the database may lack these organisms; see \
+             `suggest-references` or add references to the database"
+                .to_string(),
+            "Absence of classifications says nothing about what the sample contains".to_string(),
+        )
+    } else {
+        return None;
+    };
+    Some(ReportWarning {
+        kind: WarningKind::DegenerateInput,
+        level: None,
+        detail,
+        effect,
+    })
+}
+
+/// Estimated reads behind a sample's classifications: the reads passing QC
+/// times the share of the sample's sketch hashes assigned to a reference
+pub fn classified_reads(metrics: &ProcessingMetrics, assigned_fraction: Option<f64>) -> usize {
+    (metrics.passed_reads as f64 * assigned_fraction.unwrap_or(0.0)).round() as usize
+}
+
+/// Warning for a sample whose classifications rest on fewer than `min_reads`
+/// classified reads, too few for the abundances to mean much
+pub fn insufficient_data_warning(
+    metrics: &ProcessingMetrics,
+    assigned_fraction: Option<f64>,
+    min_reads: usize,
+) -> Option<ReportWarning> {
+    let classified = classified_reads(metrics, assigned_fraction);
+    if classified >= min_reads {
+        return None;
+    }
+    Some(ReportWarning {
+        kind: WarningKind::InsufficientData,
+        level: None,
+        detail: format!(
+            "about {} reads were classified ({} passed QC, {:.1}% of sketch hashes assigned), \
+             fewer than --min-classified-reads {}",
+            classified,
+            metrics.passed_reads,
+            assigned_fraction.unwrap_or(0.0) * 100.0,
+            min_reads
+        ),
+        effect: "A handful of reads can match a reference by chance; the report shows no \
+                 classifications or abundances, and those in the results JSON are not to be \
+                 relied on"
+            .to_string(),
+    })
+}
+
+/// Warning for strains that explained more of the sample than their species
+/// did, before being scaled down to fit it
+pub fn inconsistency_warning(reconciliation: &AbundanceReconciliation) -> Option<ReportWarning> {
+    if !reconciliation.inconsistent {
+        return None;
+    }
+    Some(ReportWarning {
+        kind: WarningKind::AbundanceInconsistency,
+        level: None,
+        detail: format!(
+            "strains explain {:.2}% of the sample but species {} only {:.2}%; strain \
+             abundances were scaled by {:.3} to fit the species",
+            reconciliation.strain_total * 100.0,
+            reconciliation.species_id,
+            reconciliation.species_abundance * 100.0,
+            reconciliation.scale
+        ),
+        effect: "Strain proportions are kept, but the coarse and fine sketches disagree on how \
+                 much of the sample they make up"
+            .to_string(),
+    })
+}
+
+/// Warnings for control panels matching more than `max_fraction` of the reads passing QC
+pub fn contamination_warnings(
+    metrics: &ProcessingMetrics,
+    max_fraction: f64,
+) -> Vec<ReportWarning> {
+    metrics
+        .contamination
+        .iter()
+        .filter(|estimate| estimate.read_fraction > max_fraction)
+        .map(|estimate| ReportWarning {
+            kind: WarningKind::Contamination,
+            level: None,
+            detail: format!(
+                "{:.1}% of reads passing QC ({} of {}) match the {} control panel",
+                estimate.read_fraction * 100.0,
+                estimate.matching_reads,
+                estimate.screened_reads,
+                estimate.panel
+            ),
+            effect: "These reads are sketched with the sample: they dilute all abundances and \
+                     may be classified as whatever reference they resemble"
+                .to_string(),
+        })
+        .collect()
+}
+
+fn join<T: fmt::Display>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_matching_parameters_produce_no_warnings() {
+        let lineage = [
+            "Bacteria",
+            "P",
+            "C",
+            "O",
+            "F",
+            "Escherichia",
+            "Escherichia coli",
+        ];
+        let references = vec![
+            signature(
+                "GCF_1",
+                &lineage,
+                vec![level(31, 1000, []), level(21, 1000, [])],
+            ),
+            signature(
+                "GCF_2",
+                &lineage,
+                vec![level(31, 1000, []), level(21, 1000, [])],
+            ),
+        ];
+        let profile = DatabaseProfile::from_signatures(&references);
+        let query = signature(
+            "sample",
+            &[],
+            vec![level(31, 1000, []), level(21, 1000, [])],
+        );
+        assert!(profile.check_query(&query).is_empty());
+    }
+
+    #[test]
+    fn test_query_and_database_mismatches() {
+        let references = vec![
+            signature(
+                "GCF_1",
+                &[
+                    "Bacteria",
+                    "Proteobacteria",
+                    "C",
+                    "O",
+                    "F",
+                    "Escherichia",
+                    "Escherichia coli",
+                ],
+                vec![level(31, 1000, []), level(21, 1000, [])],
+            ),
+            signature(
+                "GCF_2",
+                &[
+                    "Bacteria",
+                    "Pseudomonadota",
+                    "C",
+                    "O",
+                    "F",
+                    "Escherichia",
+                    "Escherichia coli",
+                ],
+                vec![level(31, 1000, []), level(21, 500, [])],
+            ),
+        ];
+        let profile = DatabaseProfile::from_signatures(&references);
+        let kinds: Vec<_> = profile.warnings.iter().map(|w| (w.kind, w.level)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (WarningKind::MixedDatabaseParameters, Some(1)),
+                (WarningKind::TaxonomyVersion, None)
+            ]
+        );
+
+        let query = signature(
+            "sample",
+            &[],
+            vec![level(21, 1000, []), level(21, 1000, [])],
+        );
+        let warnings = profile.check_query(&query);
+        assert!(warnings
+            .iter()
+            .any(|w| w.kind == WarningKind::KmerSize && w.level == Some(0)));
+        // Level 1 matches one of the database's parameter sets
+        assert!(!warnings
+            .iter()
+            .any(|w| w.level == Some(1) && w.kind != WarningKind::MixedDatabaseParameters));
+    }
+
+    #[test]
+    fn test_read_length_checks() {
+        let lengths = vec![75; 100];
+        let warnings =
+            read_length_warnings(&[(Some(0), 51), (Some(1), 21)], &lengths, 0.5).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::ReadLength);
+        assert_eq!(warnings[0].level, Some(0));
+
+        assert!(read_length_warnings(&[(None, 81)], &lengths, 0.5).is_err());
+        assert!(read_length_warnings(&[(None, 81)], &[], 0.5)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_degenerate_input_warning_names_first_empty_stage() {
+        let metrics = |total_reads, passed_reads| ProcessingMetrics {
+            total_reads,
+            passed_reads,
+            total_bases: 0,
+            passed_bases: 0,
+            avg_read_length: 0.0,
+            processing_time_seconds: 0.0,
+            rrna_reads: 0,
+            contamination: Vec::new(),
+            control_reads_removed: 0,
+            pair_concordance: None,
+        };
+        let empty = signature("sample", &[], vec![level(21, 10, [])]);
+        let sketched = signature("sample", &[], vec![level(21, 10, [1, 2, 3])]);
+
+        let no_qc = degenerate_input_warning(&metrics(100, 0), &empty, 0).unwrap();
+        assert_eq!(no_qc.kind, WarningKind::DegenerateInput);
+        assert!(no_qc.detail.starts_with("0 of 100 reads passed QC"));
+        assert!(degenerate_input_warning(&metrics(0, 0), &empty, 0)
+            .unwrap()
+            .detail
+            .contains("no reads"));
+        assert!(degenerate_input_warning(&metrics(100, 90), &empty, 0)
+            .unwrap()
+            .detail
+            .contains("no k-mers"));
+        assert!(degenerate_input_warning(&metrics(100, 90), &sketched, 0)
+            .unwrap()
+            .detail
+            .contains("no reference matched"));
+        assert!(degenerate_input_warning(&metrics(100, 90), &sketched, 1).is_none());
+
+        let phix_only = ProcessingMetrics {
+            control_reads_removed: 100,
+            pair_concordance: None,
+            ..metrics(100, 0)
+        };
+        assert!(degenerate_input_warning(&phix_only, &empty, 0)
+            .unwrap()
+            .detail
+            .contains("control panel"));
+    }
+
+    #[test]
+    fn test_insufficient_data_below_min_classified_reads() {
+        let metrics = ProcessingMetrics {
+            total_reads: 1000,
+            passed_reads: 800,
+            total_bases: 0,
+            passed_bases: 0,
+            avg_read_length: 0.0,
+            processing_time_seconds: 0.0,
+            rrna_reads: 0,
+            contamination: Vec::new(),
+            control_reads_removed: 0,
+            pair_concordance: None,
+        };
+        assert_eq!(classified_reads(&metrics, Some(0.05)), 40);
+        assert_eq!(classified_reads(&metrics, None), 0);
+
+        let warning = insufficient_data_warning(&metrics, Some(0.05), 100).unwrap();
+        assert_eq!(warning.kind, WarningKind::InsufficientData);
+        assert!(warning.detail.starts_with("about 40 reads were classified"));
+        assert!(insufficient_data_warning(&metrics, Some(0.5), 100).is_none());
+        assert!(insufficient_data_warning(&metrics, Some(0.05), 40).is_none());
+    }
+}
@@ -0,0 +1,386 @@
+//! Watchlist alerts for taxa of concern.
+//!
+//! A watchlist names pathogens or other taxa that must never go unnoticed:
+//! NCBI taxids, assembly accessions or lineage names. With an offline
+//! taxonomy, names and taxids stand for each other, so `573` also matches a
+//! lineage naming `Klebsiella pneumoniae`. Every classified sample
+//! is checked against it, and members found above the confidence (and, for
+//! strains, abundance) thresholds become alerts. Alerts lead the text report
+//! and are also written as `<sample>_alerts.json`, so notification systems do
+//! not need to parse the report.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::bio::names::TaxonLookupError;
+use crate::bio::taxdump::Taxonomy;
+use crate::pipeline::qc::ClassificationResults;
+use crate::utils::paths::sample_file;
+
+/// Default minimum classification confidence for an alert
+pub const DEFAULT_WATCHLIST_MIN_CONFIDENCE: f64 = 0.5;
+
+/// Default minimum strain abundance for an alert
+pub const DEFAULT_WATCHLIST_MIN_ABUNDANCE: f64 = 0.01;
+
+/// Taxa of concern and the thresholds at which they raise an alert
+#[derive(Debug, Clone, Default)]
+pub struct Watchlist {
+    /// Identifier (without accession version) -> display name
+    entries: BTreeMap<String, String>,
+    /// Taxid or scientific name -> the identifier it stands for in `entries`
+    aliases: BTreeMap<String, String>,
+    /// Classifications below this confidence do not alert
+    pub min_confidence: f64,
+    /// Strains below this relative abundance do not alert
+    pub min_abundance: f64,
+}
+
+/// How a watchlist member was found in a sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMatch {
+    /// A classification's taxon or best-matching reference
+    Classification,
+    /// A taxon in a classification's lineage
+    Lineage,
+    /// A reference strain with an abundance estimate
+    Strain,
+}
+
+/// A watchlist member detected in a sample
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchlistAlert {
+    /// Watchlist identifier that matched
+    pub watchlist_id: String,
+    /// Display name from the watchlist
+    pub name: String,
+    /// Taxon or reference in the results that matched it
+    pub matched_id: String,
+    pub matched_via: AlertMatch,
+    /// Confidence of the classification the match belongs to
+    pub confidence: f64,
+    /// Relative strain abundance, for strain matches
+    pub abundance: Option<f64>,
+}
+
+impl fmt::Display for WatchlistAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name == self.watchlist_id {
+            write!(f, "{}: ", self.name)?;
+        } else {
+            write!(f, "{} [{}]: ", self.name, self.watchlist_id)?;
+        }
+        match self.matched_via {
+            AlertMatch::Classification => write!(f, "classified as {}", self.matched_id)?,
+            AlertMatch::Lineage => write!(f, "{} is in the classified lineage", self.matched_id)?,
+            AlertMatch::Strain => write!(
+                f,
+                "strain {} at {:.2}%",
+                self.matched_id,
+                self.abundance.unwrap_or(0.0) * 100.0
+            )?,
+        }
+        write!(f, " (confidence {:.4})", self.confidence)
+    }
+}
+
+/// Machine-readable alerts of one sample
+#[derive(Debug, Serialize)]
+struct AlertsFile<'a> {
+    sample_id: &'a str,
+    min_confidence: f64,
+    min_abundance: f64,
+    alerts: &'a [WatchlistAlert],
+}
+
+impl Watchlist {
+    /// Read a watchlist file: one identifier per line, optionally followed by a
+    /// tab and a display name. Blank lines and `#` comments are skipped.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Cannot open watchlist {}", path.display()))?;
+        let mut entries = BTreeMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, name) = match line.split_once('\t') {
+                Some((id, name)) => (id.trim(), name.trim()),
+                None => (line, line),
+            };
+            entries.insert(strip_version(id).to_string(), name.to_string());
+        }
+        if entries.is_empty() {
+            bail!("Watchlist {} lists no taxa", path.display());
+        }
+        Ok(Watchlist {
+            entries,
+            aliases: BTreeMap::new(),
+            min_confidence: DEFAULT_WATCHLIST_MIN_CONFIDENCE,
+            min_abundance: DEFAULT_WATCHLIST_MIN_ABUNDANCE,
+        })
+    }
+
+    /// Let the names and taxids on the watchlist match each other's forms in
+    /// results, resolving them through `taxonomy`; accessions are left as
+    /// they are. Fails listing every name that is unknown or ambiguous.
+    pub fn resolve_names(&mut self, taxonomy: &Taxonomy) -> Result<()> {
+        let mut problems = Vec::new();
+        for id in self.entries.keys() {
+            if is_accession(id) {
+                continue;
+            }
+            match taxonomy.resolve(id) {
+                Ok(taxid) => {
+                    let name = taxonomy.node(&taxid).map(|node| node.name.clone());
+                    for alias in std::iter::once(taxid).chain(name) {
+                        if alias != *id {
+                            self.aliases.insert(alias, id.clone());
+                        }
+                    }
+                }
+                // Results may still name the taxon by this taxid
+                Err(TaxonLookupError::UnknownTaxid(_)) => {
+                    warn!("Watchlist taxid {} is not in the taxonomy", id)
+                }
+                Err(e) => problems.push(e.to_string()),
+            }
+        }
+        if !problems.is_empty() {
+            bail!(
+                "Cannot resolve {} watchlist taxa:\n  - {}",
+                problems.len(),
+                problems.join("\n  - ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Watchlist identifier and display name of the member `id` stands for
+    fn member(&self, id: &str) -> Option<(&String, &String)> {
+        let id = strip_version(id);
+        self.entries.get_key_value(id).or_else(|| {
+            self.aliases
+                .get(id)
+                .and_then(|canonical| self.entries.get_key_value(canonical))
+        })
+    }
+
+    /// Number of taxa on the watchlist
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Alerts for the watchlist members in `results`, one per member and
+    /// matched taxon, most confident first
+    pub fn check(&self, results: &ClassificationResults) -> Vec<WatchlistAlert> {
+        let mut alerts = Vec::new();
+        let mut push = |id: &str, matched_via, confidence, abundance| {
+            if let Some((watchlist_id, name)) = self.member(id) {
+                let duplicate = alerts.iter().any(|alert: &WatchlistAlert| {
+                    &alert.watchlist_id == watchlist_id && alert.matched_id == id
+                });
+                if !duplicate {
+                    alerts.push(WatchlistAlert {
+                        watchlist_id: watchlist_id.clone(),
+                        name: name.clone(),
+                        matched_id: id.to_string(),
+                        matched_via,
+                        confidence,
+                        abundance,
+                    });
+                }
+            }
+        };
+
+        for classification in &results.classifications {
+            if classification.confidence < self.min_confidence {
+                continue;
+            }
+            let confidence = classification.confidence;
+            push(
+                &classification.taxon_id,
+                AlertMatch::Classification,
+                confidence,
+                None,
+            );
+            push(
+                &classification.best_match,
+                AlertMatch::Classification,
+                confidence,
+                None,
+            );
+            for taxon in &classification.lineage {
+                push(taxon, AlertMatch::Lineage, confidence, None);
+            }
+        }
+        // Strain estimates belong to the top classification
+        if let Some(top) = results
+            .classifications
+            .first()
+            .filter(|top| top.confidence >= self.min_confidence)
+        {
+            for (strain_id, (abundance, _)) in &results.strain_abundances {
+                if *abundance >= self.min_abundance {
+                    push(
+                        strain_id,
+                        AlertMatch::Strain,
+                        top.confidence,
+                        Some(*abundance),
+                    );
+                }
+            }
+        }
+
+        alerts.sort_by(|a, b| {
+            b.confidence
+                .total_cmp(&a.confidence)
+                .then(
+                    b.abundance
+                        .unwrap_or(0.0)
+                        .total_cmp(&a.abundance.unwrap_or(0.0)),
+                )
+                .then(a.matched_id.cmp(&b.matched_id))
+        });
+        alerts
+    }
+
+    /// Write `<sample>_alerts.json` to `output_dir`. It is written even
+    /// without alerts, so its presence shows the sample was screened.
+    pub fn write_alerts(
+        &self,
+        sample_id: &str,
+        alerts: &[WatchlistAlert],
+        output_dir: &Path,
+    ) -> Result<PathBuf> {
+        fs::create_dir_all(output_dir)?;
+        let path = sample_file(output_dir, sample_id, "_alerts.json");
+        let file = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer_pretty(
+            file,
+            &AlertsFile {
+                sample_id,
+                min_confidence: self.min_confidence,
+                min_abundance: self.min_abundance,
+                alerts,
+            },
+        )?;
+        Ok(path)
+    }
+}
+
+/// Whether `id` is an accession (`GCF_000008865`, `NZ_CP009072`) rather
+/// than a taxid or name
+fn is_accession(id: &str) -> bool {
+    id.contains('_') && id.bytes().any(|b| b.is_ascii_digit()) && !id.contains(char::is_whitespace)
+}
+
+/// Accession without its version suffix (`GCF_000005845.2` -> `GCF_000005845`)
+fn strip_version(id: &str) -> &str {
+    match id.rsplit_once('.') {
+        Some((base, version))
+            if !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::golden::toy_results;
+
+    #[test]
+    fn test_watchlist_matches_taxa_lineages_and_strains() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watchlist.tsv");
+        fs::write(
+            &path,
+            "# taxa of concern\n\
+             GCF_000008865.2\tE. coli O157:H7 Sakai\n\
+             Escherichia\n\
+             \n\
+             632\tYersinia pestis\n",
+        )
+        .unwrap();
+        let mut watchlist = Watchlist::from_file(&path).unwrap();
+        assert_eq!(watchlist.len(), 3);
+
+        let results = toy_results();
+        // S1: the strain matches despite the missing accession version, the genus via the lineage
+        let alerts = watchlist.check(&results[0]);
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].matched_via, AlertMatch::Strain);
+        assert_eq!(alerts[0].name, "E. coli O157:H7 Sakai");
+        assert_eq!(alerts[0].abundance, Some(0.25));
+        assert_eq!(alerts[1].matched_via, AlertMatch::Lineage);
+        assert_eq!(alerts[1].matched_id, "Escherichia");
+        assert_eq!(
+            alerts[0].to_string(),
+            "E. coli O157:H7 Sakai [GCF_000008865]: strain GCF_000008865 at 25.00% (confidence 0.9234)"
+        );
+
+        // S2: the best match is on the list, but classification confidence is 0.6
+        let alerts = watchlist.check(&results[1]);
+        assert!(alerts.iter().any(
+            |a| a.matched_id == "GCF_000008865" && a.matched_via == AlertMatch::Classification
+        ));
+        watchlist.min_confidence = 0.7;
+        assert!(watchlist.check(&results[1]).is_empty());
+
+        // Strains below the abundance threshold do not alert
+        watchlist.min_abundance = 0.5;
+        assert!(watchlist
+            .check(&results[0])
+            .iter()
+            .all(|a| a.matched_via != AlertMatch::Strain));
+
+        let written = watchlist.write_alerts("S2", &[], dir.path()).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(written).unwrap()).unwrap();
+        assert_eq!(json["alerts"].as_array().unwrap().len(), 0);
+        assert_eq!(strip_version("GCF_000005845.2"), "GCF_000005845");
+        assert_eq!(strip_version("Escherichia coli"), "Escherichia coli");
+
+        // Taxids match lineage names and names match taxids through the taxonomy
+        let taxonomy_path = dir.path().join("lineages.tsv");
+        fs::write(
+            &taxonomy_path,
+            "562\tBacteria; Pseudomonadota; Gammaproteobacteria; Enterobacterales; \
+             Enterobacteriaceae; Escherichia; Escherichia coli\n",
+        )
+        .unwrap();
+        let taxonomy = Taxonomy::from_lineage_map(&taxonomy_path).unwrap();
+        fs::write(&path, "GCF_000008865.2\nescherichia\n").unwrap();
+        let mut watchlist = Watchlist::from_file(&path).unwrap();
+        watchlist.resolve_names(&taxonomy).unwrap();
+        let alerts = watchlist.check(&results[0]);
+        assert!(alerts
+            .iter()
+            .any(|a| a.watchlist_id == "escherichia" && a.matched_id == "Escherichia"));
+        fs::write(&path, "Escherichia colli\n").unwrap();
+        let mut watchlist = Watchlist::from_file(&path).unwrap();
+        let error = watchlist.resolve_names(&taxonomy).unwrap_err().to_string();
+        assert!(
+            error.contains("did you mean Escherichia coli?"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Result provenance.
+//!
+//! Every results file records what produced it: SHA-256 digests of the input
+//! files, a digest of the reference database contents and a digest of the
+//! analysis parameters. JSON outputs carry a `provenance` object; CSV outputs
+//! start with `#`-prefixed header lines holding the same values.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::sketch::MultiResolutionSignature;
+
+/// Provenance record attached to results
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Version of strain_ahsp that wrote the results
+    pub tool_version: String,
+
+    /// SHA-256 of each input file, keyed by file name
+    pub input_sha256: BTreeMap<String, String>,
+
+    /// Digest of the reference database contents (see `database_digest`)
+    pub database_sha256: Option<String>,
+
+    /// SHA-256 of the canonical encoding of the analysis parameters (see `parameters_digest`)
+    pub parameters_sha256: String,
+
+    /// Samples left out of the analysis, with the reason
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub excluded_samples: BTreeMap<String, String>,
+}
+
+impl Provenance {
+    /// Build a provenance record, hashing each input file
+    pub fn new<P: Serialize>(
+        inputs: &[&Path],
+        database_sha256: Option<String>,
+        parameters: &P,
+    ) -> io::Result<Self> {
+        let mut input_sha256 = BTreeMap::new();
+        for path in inputs {
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.display().to_string());
+            input_sha256.insert(name, sha256_file(path)?);
+        }
+        Ok(Provenance {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            input_sha256,
+            database_sha256,
+            parameters_sha256: parameters_digest(parameters)?,
+            excluded_samples: BTreeMap::new(),
+        })
+    }
+
+    /// `# key: value` lines for the top of a CSV file
+    pub fn header_lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("# strain_ahsp_version: {}", self.tool_version)];
+        for (name, digest) in &self.input_sha256 {
+            lines.push(format!("# input_sha256: {} {}", digest, name));
+        }
+        if let Some(digest) = &self.database_sha256 {
+            lines.push(format!("# database_sha256: {}", digest));
+        }
+        lines.push(format!("# parameters_sha256: {}", self.parameters_sha256));
+        for (sample, reason) in &self.excluded_samples {
+            lines.push(format!("# excluded_sample: {} ({})", sample, reason));
+        }
+        lines
+    }
+
+    /// Replace input file names with `input_1`, `input_2`, ... (in name order), for
+    /// outputs that must not reveal sample-identifying file names
+    pub fn redact_input_names(&mut self) {
+        let inputs = std::mem::take(&mut self.input_sha256);
+        self.input_sha256 = inputs
+            .into_values()
+            .enumerate()
+            .map(|(i, digest)| (format!("input_{}", i + 1), digest))
+            .collect();
+    }
+
+    /// Write the header lines to `writer`
+    pub fn write_header<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for line in self.header_lines() {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// SHA-256 of a file's contents, hex encoded
+pub fn sha256_file(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// SHA-256 of a canonical encoding of the parameters. Object keys are hashed in
+/// sorted order and numbers by value, so the digest depends neither on field
+/// order nor on how serde_json happens to format its output.
+pub fn parameters_digest<P: Serialize>(parameters: &P) -> io::Result<String> {
+    let value = serde_json::to_value(parameters)?;
+    let mut hasher = Sha256::new();
+    hash_canonical(&value, &mut hasher);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Feed `value` to `hasher` as a type tag followed by its contents, with
+/// lengths before strings, arrays and objects so no two values collide
+fn hash_canonical(value: &serde_json::Value, hasher: &mut Sha256) {
+    use serde_json::Value;
+
+    fn hash_str(text: &str, hasher: &mut Sha256) {
+        hasher.update((text.len() as u64).to_le_bytes());
+        hasher.update(text.as_bytes());
+    }
+
+    match value {
+        Value::Null => hasher.update([0u8]),
+        Value::Bool(flag) => hasher.update([1u8, u8::from(*flag)]),
+        Value::Number(number) => {
+            if let Some(int) = number.as_i64() {
+                hasher.update([2u8]);
+                hasher.update(int.to_le_bytes());
+            } else if let Some(uint) = number.as_u64() {
+                hasher.update([3u8]);
+                hasher.update(uint.to_le_bytes());
+            } else {
+                hasher.update([4u8]);
+                hasher.update(number.as_f64().unwrap_or(f64::NAN).to_bits().to_le_bytes());
+            }
+        }
+        Value::String(text) => {
+            hasher.update([5u8]);
+            hash_str(text, hasher);
+        }
+        Value::Array(items) => {
+            hasher.update([6u8]);
+            hasher.update((items.len() as u64).to_le_bytes());
+            for item in items {
+                hash_canonical(item, hasher);
+            }
+        }
+        Value::Object(map) => {
+            hasher.update([7u8]);
+            hasher.update((map.len() as u64).to_le_bytes());
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (key, item) in entries {
+                hash_str(key, hasher);
+                hash_canonical(item, hasher);
+            }
+        }
+    }
+}
+
+/// Digest of a reference database: SHA-256 over the (ID, content hash) pairs of
+/// its signatures in ID order, independent of storage layout and compression.
+pub fn database_digest<'a>(
+    signatures: impl IntoIterator<Item = &'a MultiResolutionSignature>,
+) -> String {
+    let mut entries: Vec<(&str, String)> = signatures
+        .into_iter()
+        .map(|sig| (sig.taxon_id.as_str(), sig.content_hash()))
+        .collect();
+    entries.sort();
+
+    let mut hasher = Sha256::new();
+    for (id, content_hash) in entries {
+        hasher.update(id.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(content_hash.as_bytes());
+        hasher.update([b'\n']);
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_provenance_digests() {
+        let mut input = NamedTempFile::new().unwrap();
+        write!(input, "@r1\nACGT\n+\nIIII\n").unwrap();
+
+        let params = json!({"k": 21, "min_quality": 20.0});
+        let provenance =
+            Provenance::new(&[input.path()], Some("abc".to_string()), &params).unwrap();
+
+        let name = input.path().file_name().unwrap().to_string_lossy();
+        assert_eq!(
+            provenance.input_sha256[name.as_ref()],
+            "8add5b3bf0a7df65f939b0f2f0650f97ae63c37796bf0d1e769b384e666986cd"
+        );
+
+        // Key order does not change the parameter digest; values do
+        assert_eq!(
+            provenance.parameters_sha256,
+            parameters_digest(&json!({"min_quality": 20.0, "k": 21})).unwrap()
+        );
+        assert_ne!(
+            provenance.parameters_sha256,
+            parameters_digest(&json!({"k": 31, "min_quality": 20.0})).unwrap()
+        );
+        assert_eq!(
+            parameters_digest(&json!({"qc": {"k": [21, 31], "trim": null}})).unwrap(),
+            parameters_digest(&json!({"qc": {"trim": null, "k": [21, 31]}})).unwrap()
+        );
+        assert_ne!(
+            parameters_digest(&json!({"k": [21, 31]})).unwrap(),
+            parameters_digest(&json!({"k": [31, 21]})).unwrap()
+        );
+
+        let mut provenance = provenance;
+        provenance
+            .excluded_samples
+            .insert("S3".to_string(), "library size 10".to_string());
+        let header = provenance.header_lines();
+        assert!(header.iter().all(|line| line.starts_with("# ")));
+        assert!(header.contains(&"# database_sha256: abc".to_string()));
+        assert!(header.contains(&"# excluded_sample: S3 (library size 10)".to_string()));
+    }
+}
@@ -5,6 +5,8 @@
 //! adapts based on the data or a scaling factor. This allows comparing
 //! datasets of vastly different sizes more accurately than fixed-size MinHash.
 
+use crate::bio::kmers::{KmerWord, PackedKmerIter};
+use crate::bio::packed::PackedSequence;
 use crate::sketch::signature::Signature;
 use crate::sketch::Sketcher; // Implement the common Sketcher trait
 use anyhow::Result;
@@ -12,7 +14,7 @@ use needletail::parser::FastaReader; // Add this import at the top with other im
 use needletail::parser::SequenceRecord;
 use needletail::Sequence;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 
 /// Structure for creating adaptive sketches (e.g., Scaled MinHash).
@@ -37,6 +39,12 @@ impl AdaptiveSketcher {
         if kmer_size == 0 {
             return Err(anyhow::anyhow!("K-mer size must be greater than 0."));
         }
+        if kmer_size > u128::MAX_K {
+            return Err(anyhow::anyhow!(
+                "K-mer size must be at most {}.",
+                u128::MAX_K
+            ));
+        }
         Ok(AdaptiveSketcher {
             scaling_factor,
             kmer_size,
@@ -44,12 +52,50 @@ impl AdaptiveSketcher {
         })
     }
 
-    /// Calculates a hash value for a k-mer.
-    fn hash_kmer(&self, kmer: &[u8]) -> u64 {
+    /// Calculates a hash value for a packed k-mer.
+    fn hash_kmer<W: KmerWord>(&self, kmer: W) -> u64 {
         let mut hasher = DefaultHasher::new();
         kmer.hash(&mut hasher);
         hasher.finish()
     }
+
+    /// Add the hashes of `kmers` below `threshold`
+    fn keep_hashes<W: KmerWord>(
+        &self,
+        kmers: impl Iterator<Item = W>,
+        threshold: u64,
+        kept_hashes: &mut HashSet<u64>,
+    ) {
+        for kmer in kmers {
+            let hash_value = self.hash_kmer(kmer);
+            if hash_value < threshold {
+                kept_hashes.insert(hash_value);
+            }
+        }
+    }
+
+    /// Creates an adaptive signature for a 2-bit packed sequence, such as a
+    /// reference genome. Gives the same hashes as sketching its decoded bases.
+    pub fn sketch_packed(&self, sequence: &PackedSequence) -> Result<Signature> {
+        let threshold = u64::MAX / self.scaling_factor;
+        let mut kept_hashes = HashSet::new();
+        if self.kmer_size <= u64::MAX_K {
+            let kmers = sequence.canonical_kmers::<u64>(self.kmer_size)?;
+            self.keep_hashes(kmers, threshold, &mut kept_hashes);
+        } else {
+            let kmers = sequence.canonical_kmers::<u128>(self.kmer_size)?;
+            self.keep_hashes(kmers, threshold, &mut kept_hashes);
+        }
+        Ok(Self::signature_from(self.kmer_size, kept_hashes))
+    }
+
+    fn signature_from(kmer_size: usize, kept_hashes: HashSet<u64>) -> Signature {
+        let mut signature = Signature::new("scaled_minhash".to_string(), kmer_size, 0);
+        signature.hashes = kept_hashes.into_iter().collect();
+        signature.hashes.sort_unstable(); // Keep sorted for consistency
+        signature.num_hashes = signature.hashes.len(); // Update num_hashes to actual count
+        signature
+    }
 }
 
 impl Sketcher for AdaptiveSketcher {
@@ -59,51 +105,28 @@ impl Sketcher for AdaptiveSketcher {
         // The threshold for keeping hashes
         let threshold = u64::MAX / self.scaling_factor;
 
-        // Note: num_hashes in the signature is less meaningful here, it reflects the *actual*
-        // number of hashes kept, which varies. We store 0 or the actual count.
-        let mut signature = Signature::new(
-            "scaled_minhash".to_string(), // Or other adaptive method name
-            self.kmer_size,
-            0, // Initial num_hashes is 0, will be updated
-        );
-        // If the sequence is empty, return an empty signature
-        if record.all().is_empty() {
-            return Ok(signature);
-        }
         let seq = record.sequence();
-        let mut kept_hashes = std::collections::HashSet::new(); // Use HashSet to store unique hashes below threshold
-
-        // 1. Generate canonical k-mers and hash them
-        // TODO: Use the actual CanonicalKmerIter from bio::kmers when implemented correctly.
-        // Manual canonicalization for now:
-        for i in 0..=(seq.len().saturating_sub(self.kmer_size)) {
-            let kmer = &seq[i..i + self.kmer_size];
-            if kmer.iter().any(|&b| !crate::bio::is_valid_base(b)) {
-                continue; // Skip k-mers with invalid bases
-            }
-            let rc = crate::bio::reverse_complement(kmer);
-            let canonical_kmer = if kmer < &rc[..] { kmer } else { &rc[..] };
-
-            let hash_value = self.hash_kmer(canonical_kmer);
-
-            // 2. Keep hash if it's below the threshold
-            if hash_value < threshold {
-                kept_hashes.insert(hash_value);
-            }
+        let mut kept_hashes = HashSet::new(); // Use HashSet to store unique hashes below threshold
+
+        // 1-2. Hash the packed canonical k-mers and keep those below the threshold
+        if self.kmer_size <= u64::MAX_K {
+            let kmers = PackedKmerIter::<u64>::new(seq, self.kmer_size)?;
+            self.keep_hashes(kmers, threshold, &mut kept_hashes);
+        } else {
+            let kmers = PackedKmerIter::<u128>::new(seq, self.kmer_size)?;
+            self.keep_hashes(kmers, threshold, &mut kept_hashes);
         }
 
-        // 3. Store the kept hashes in the signature
-        signature.hashes = kept_hashes.into_iter().collect();
-        signature.hashes.sort_unstable(); // Keep sorted for consistency
-        signature.num_hashes = signature.hashes.len(); // Update num_hashes to actual count
-
-        Ok(signature)
+        // 3. Store the kept hashes in the signature. num_hashes is less meaningful
+        // here: it reflects the number of hashes kept, which varies.
+        Ok(Self::signature_from(self.kmer_size, kept_hashes))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bio::packed::NPolicy;
     use needletail::{parse_fastx_file, parser::SequenceRecord};
     use std::io::Cursor;
     use std::io::{Seek, Write}; // For writing to tempfile in tests
@@ -141,6 +164,7 @@ mod tests {
     fn test_adaptive_sketcher_new_invalid() {
         assert!(AdaptiveSketcher::new(0, 21).is_err());
         assert!(AdaptiveSketcher::new(1000, 0).is_err());
+        assert!(AdaptiveSketcher::new(1000, 65).is_err());
     }
 
     #[test]
@@ -180,6 +204,18 @@ mod tests {
         assert_eq!(signature.hashes, sorted_hashes);
     }
 
+    #[test]
+    fn test_adaptive_sketch_packed_matches_bytes() {
+        let sketcher = AdaptiveSketcher::new(2, 5).unwrap();
+        let sequence = "ACGTTACGTACGTNNACTGGATTACA";
+        let record = seq_rec("bytes", sequence);
+        let packed = PackedSequence::encode(sequence.as_bytes(), NPolicy::Skip);
+        assert_eq!(
+            sketcher.sketch_packed(&packed[0]).unwrap().hashes,
+            sketcher.sketch_sequence(&record).unwrap().hashes
+        );
+    }
+
     #[test]
     fn test_adaptive_sketch_empty() {
         let sketcher = AdaptiveSketcher::new(100, 3).unwrap();
@@ -198,84 +234,3 @@ mod tests {
         assert_eq!(signature.num_hashes, 0);
     }
 }
-
-/// Classifier using adaptive sketches for taxonomic classification.
-/// Implements an algorithm to determine taxonomic identity.
-#[derive(Debug)]
-pub struct AdaptiveClassifier {
-    // Database of reference sketches
-    reference_sketches: HashMap<String, Signature>,
-    // Scaling factor used for the sketches
-    scaling_factor: u64,
-    // Minimum similarity threshold to report a match
-    min_similarity: f64,
-}
-
-impl AdaptiveClassifier {
-    /// Creates a new classifier with reference signatures.
-    pub fn new(
-        reference_sketches: HashMap<String, Signature>,
-        scaling_factor: u64,
-        min_similarity: f64,
-    ) -> Self {
-        AdaptiveClassifier {
-            reference_sketches,
-            scaling_factor,
-            min_similarity,
-        }
-    }
-
-    /// Creates a new classifier with empty references.
-    pub fn empty(scaling_factor: u64, min_similarity: f64) -> Self {
-        AdaptiveClassifier {
-            reference_sketches: HashMap::new(),
-            scaling_factor,
-            min_similarity,
-        }
-    }
-
-    /// Adds a reference signature to the classifier.
-    pub fn add_reference(&mut self, id: String, signature: Signature) {
-        self.reference_sketches.insert(id, signature);
-    }
-
-    /// Classifies a query signature against the reference database.
-    ///
-    /// # Arguments
-    ///
-    /// * `query_signature` - The signature to classify
-    ///
-    /// # Returns
-    ///
-    /// A Vec of (reference ID, similarity score) pairs, sorted by descending similarity
-    pub fn classify(&self, query_signature: &Signature) -> Vec<(String, f64)> {
-        let mut results = Vec::new();
-
-        for (ref_id, ref_sig) in &self.reference_sketches {
-            let similarity = query_signature.estimate_jaccard(ref_sig);
-            if similarity >= Some(self.min_similarity) {
-                results.push((ref_id.clone(), similarity.unwrap_or(0.0)));
-            }
-        }
-
-        // Sort by similarity (descending)
-        results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-
-        results
-    }
-
-    /// Returns the number of reference sketches in the classifier.
-    pub fn reference_count(&self) -> usize {
-        self.reference_sketches.len()
-    }
-
-    /// Returns the scaling factor used for the sketches.
-    pub fn scaling_factor(&self) -> u64 {
-        self.scaling_factor
-    }
-
-    /// Returns the minimum similarity threshold.
-    pub fn min_similarity(&self) -> f64 {
-        self.min_similarity
-    }
-}
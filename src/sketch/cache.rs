@@ -0,0 +1,123 @@
+//! Cache of pairwise similarities between reference signatures.
+//!
+//! Strain clustering compares every pair of candidate strains of the
+//! classified species, and every sample of a run, or of the next run against
+//! the same database, asks for the same pairs again. Results are kept per
+//! measure under the content hashes of both sketches, so an entry stays valid
+//! however the references are renamed, and a changed sketch simply misses.
+//! `SignatureDatabase` persists the cache in its own table.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Cached similarities, with the entries not yet persisted
+#[derive(Debug, Default)]
+pub struct SimilarityCache {
+    values: Mutex<HashMap<Vec<u8>, f64>>,
+    /// Keys computed since the cache was loaded or last drained
+    added: Mutex<Vec<Vec<u8>>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl SimilarityCache {
+    /// A cache holding persisted `(key, value)` entries, as produced by `drain_new`
+    pub fn from_entries(entries: impl IntoIterator<Item = (Vec<u8>, f64)>) -> Self {
+        SimilarityCache {
+            values: Mutex::new(entries.into_iter().collect()),
+            ..SimilarityCache::default()
+        }
+    }
+
+    /// `measure` of the sketches with content hashes `a` and `b`, computed and
+    /// stored on a miss. Measures must be symmetric.
+    pub fn get_or_insert_with(
+        &self,
+        measure: &str,
+        a: &str,
+        b: &str,
+        compute: impl FnOnce() -> f64,
+    ) -> f64 {
+        let key = cache_key(measure, a, b);
+        if let Some(&value) = self.values.lock().unwrap().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return value;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let value = compute();
+        self.values.lock().unwrap().insert(key.clone(), value);
+        self.added.lock().unwrap().push(key);
+        value
+    }
+
+    /// Entries computed since the last call, ready to be persisted
+    pub fn drain_new(&self) -> Vec<(Vec<u8>, f64)> {
+        let added = std::mem::take(&mut *self.added.lock().unwrap());
+        let values = self.values.lock().unwrap();
+        added
+            .into_iter()
+            .filter_map(|key| values.get(&key).map(|&value| (key, value)))
+            .collect()
+    }
+
+    /// Number of cached similarities
+    pub fn len(&self) -> usize {
+        self.values.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Lookups answered from the cache and lookups that had to compute
+    pub fn hit_counts(&self) -> (usize, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// `measure \0 hash \0 hash`, with the content hashes in sorted order
+fn cache_key(measure: &str, a: &str, b: &str) -> Vec<u8> {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let mut key = Vec::with_capacity(measure.len() + first.len() + second.len() + 2);
+    key.extend_from_slice(measure.as_bytes());
+    key.push(0);
+    key.extend_from_slice(first.as_bytes());
+    key.push(0);
+    key.extend_from_slice(second.as_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_pairs_are_symmetric_and_drained_once() {
+        let cache = SimilarityCache::default();
+        assert_eq!(cache.get_or_insert_with("ani", "aa", "bb", || 0.98), 0.98);
+        // The reversed pair is a hit; the closure is not called
+        assert_eq!(
+            cache.get_or_insert_with("ani", "bb", "aa", || unreachable!()),
+            0.98
+        );
+        // Another measure of the same pair is a separate entry
+        assert_eq!(cache.get_or_insert_with("jaccard", "aa", "bb", || 0.4), 0.4);
+        assert_eq!(cache.hit_counts(), (1, 2));
+
+        let new = cache.drain_new();
+        assert_eq!(new.len(), 2);
+        assert!(cache.drain_new().is_empty());
+
+        let reloaded = SimilarityCache::from_entries(new);
+        assert_eq!(reloaded.len(), 2);
+        assert_eq!(
+            reloaded.get_or_insert_with("ani", "aa", "bb", || unreachable!()),
+            0.98
+        );
+        assert!(reloaded.drain_new().is_empty());
+    }
+}
@@ -0,0 +1,348 @@
+//! Similarity between sketches.
+//!
+//! Jaccard, containment and ANI used to be computed separately by the
+//! classifier, the candidate explanations, strain clustering and reference
+//! suggestions, each with its own compatibility checks and edge cases. The
+//! `Comparable` trait gives every signature type the same three measures:
+//!
+//! * `jaccard` - shared hashes over the union (or the sketch size for
+//!   fixed-size MinHash)
+//! * `containment` - share of this sketch's hashes found in the other, which
+//!   is not diluted by whatever else the other sketch contains
+//! * `ani` - average nucleotide identity from the Mash distance of the Jaccard
+//!   index, and `containment_ani` from containment as `C^(1/k)`
+//!
+//! All return `None` when the sketches cannot be compared (different
+//! algorithms, scaling factors, k-mer sizes, molecule types or hashing);
+//! the inherent `similarity` and `weighted_similarity` methods say why.
+//!
+//! Multi-resolution signatures can also be compared with automatic level
+//! weights. A level whose estimate rests on few shared hashes is noisy, so
+//! each level is weighted by the inverse of the relative variance of its
+//! Jaccard estimate, and the per-level breakdown is returned with the score.
+//!
+//! Not every shared hash says as much. A hash from a gene conserved across a
+//! whole genus is shared by every reference of it, while a rare hash singles
+//! out a few. `KmerSignature::weighted_jaccard` and `weighted_containment`
+//! count each hash by a caller-supplied weight, such as the inverse document
+//! frequency from `masking::HashFrequencies::idf`.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sketch::params::IncompatibleSketches;
+use crate::sketch::signature::{KmerSignature, MultiResolutionSignature, Signature};
+
+/// Similarity measures shared by all signature types
+pub trait Comparable {
+    /// Estimated Jaccard index of the two sketches
+    fn jaccard(&self, other: &Self) -> Option<f64>;
+
+    /// Estimated fraction of this sketch's content found in `other`
+    fn containment(&self, other: &Self) -> Option<f64>;
+
+    /// K-mer size the sketch was built with, `None` if unknown
+    fn kmer_size(&self) -> Option<usize>;
+
+    /// ANI from the Mash distance `d = -ln(2J / (1 + J)) / k`
+    fn ani(&self, other: &Self) -> Option<f64> {
+        let k = self.kmer_size()?;
+        let jaccard = self.jaccard(other)?;
+        if jaccard <= 0.0 || k == 0 {
+            return Some(0.0);
+        }
+        let distance = -(2.0 * jaccard / (1.0 + jaccard)).ln() / k as f64;
+        Some((1.0 - distance).clamp(0.0, 1.0))
+    }
+
+    /// ANI implied by containment, `C^(1/k)`
+    fn containment_ani(&self, other: &Self) -> Option<f64> {
+        let k = self.kmer_size()?.max(1);
+        let containment = self.containment(other)?;
+        Some(containment.powf(1.0 / k as f64))
+    }
+}
+
+impl Comparable for Signature {
+    fn jaccard(&self, other: &Self) -> Option<f64> {
+        self.estimate_jaccard(other)
+    }
+
+    /// `None` if the sketches are incompatible or this one is empty
+    fn containment(&self, other: &Self) -> Option<f64> {
+        if !self.is_compatible(other) || self.is_empty() {
+            return None;
+        }
+        Some(self.shared_hashes(other) as f64 / self.size() as f64)
+    }
+
+    /// A bare sketch does not record its k-mer size, so it has no ANI
+    fn kmer_size(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Comparable for KmerSignature {
+    /// Incompatible sketch parameters count as no similarity once the k-mer
+    /// size and molecule type match
+    fn jaccard(&self, other: &Self) -> Option<f64> {
+        if !self.is_comparable(other) {
+            return None;
+        }
+        Some(self.sketch.jaccard(&other.sketch).unwrap_or(0.0))
+    }
+
+    fn containment(&self, other: &Self) -> Option<f64> {
+        if !self.is_comparable(other) {
+            return None;
+        }
+        self.sketch.containment(&other.sketch)
+    }
+
+    fn kmer_size(&self) -> Option<usize> {
+        Some(self.kmer_size)
+    }
+}
+
+impl Comparable for MultiResolutionSignature {
+    /// Mean Jaccard index over the levels both signatures have
+    fn jaccard(&self, other: &Self) -> Option<f64> {
+        self.similarity(other, None).ok()
+    }
+
+    /// Mean containment over the levels both signatures have
+    fn containment(&self, other: &Self) -> Option<f64> {
+        let num_levels = self.levels.len().min(other.levels.len());
+        if num_levels == 0 {
+            return None;
+        }
+        let mut total = 0.0;
+        for (level, other_level) in self.levels.iter().zip(&other.levels) {
+            total += level.containment(other_level)?;
+        }
+        Some(total / num_levels as f64)
+    }
+
+    /// K-mer size of the finest level
+    fn kmer_size(&self) -> Option<usize> {
+        self.levels.last().map(|level| level.kmer_size)
+    }
+
+    /// ANI of the finest levels; coarse levels saturate for close genomes
+    fn ani(&self, other: &Self) -> Option<f64> {
+        self.levels.last()?.ani(other.levels.last()?)
+    }
+
+    fn containment_ani(&self, other: &Self) -> Option<f64> {
+        self.levels.last()?.containment_ani(other.levels.last()?)
+    }
+}
+
+/// Similarity at one resolution level and how much it counted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LevelSimilarity {
+    pub kmer_size: usize,
+    pub jaccard: f64,
+    pub shared_hashes: usize,
+    /// Hashes the estimate is based on: the sketch size, or the union of
+    /// both sketches for scaled MinHash
+    pub compared_hashes: usize,
+    /// Relative variance of the Jaccard estimate, `(1 - J) / shared`, with
+    /// pseudocounts so exact and empty matches stay finite
+    pub relative_variance: f64,
+    /// Share of the overall score given to this level
+    pub weight: f64,
+}
+
+/// Score of a multi-resolution comparison with its per-level breakdown
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeightedSimilarity {
+    pub score: f64,
+    pub levels: Vec<LevelSimilarity>,
+}
+
+impl WeightedSimilarity {
+    /// Index of the level that contributed most to the score
+    pub fn driving_level(&self) -> Option<usize> {
+        self.levels
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| (a.weight * a.jaccard).total_cmp(&(b.weight * b.jaccard)))
+            .map(|(index, _)| index)
+    }
+}
+
+impl KmerSignature {
+    /// Jaccard index with each hash counted by `weight`: the summed weight of
+    /// the hashes in both sketches over that of the hashes in either. `None`
+    /// if the sketches cannot be compared or neither has a hash of positive
+    /// weight.
+    pub fn weighted_jaccard(&self, other: &Self, weight: impl Fn(u64) -> f64) -> Option<f64> {
+        if !self.is_comparable(other) || !self.sketch.is_compatible(&other.sketch) {
+            return None;
+        }
+        let own: HashSet<u64> = self.sketch.hashes.iter().copied().collect();
+        let theirs: HashSet<u64> = other.sketch.hashes.iter().copied().collect();
+        let shared: f64 = own.intersection(&theirs).map(|&hash| weight(hash)).sum();
+        let union: f64 = own.union(&theirs).map(|&hash| weight(hash)).sum();
+        (union > 0.0).then(|| shared / union)
+    }
+
+    /// Containment with each hash counted by `weight`: the summed weight of
+    /// this sketch's hashes found in `other` over that of all of them
+    pub fn weighted_containment(&self, other: &Self, weight: impl Fn(u64) -> f64) -> Option<f64> {
+        if !self.is_comparable(other) || !self.sketch.is_compatible(&other.sketch) {
+            return None;
+        }
+        let theirs: HashSet<u64> = other.sketch.hashes.iter().copied().collect();
+        let own: HashSet<u64> = self.sketch.hashes.iter().copied().collect();
+        let total: f64 = own.iter().map(|&hash| weight(hash)).sum();
+        let found: f64 = own
+            .iter()
+            .filter(|hash| theirs.contains(hash))
+            .map(|&hash| weight(hash))
+            .sum();
+        (total > 0.0).then(|| found / total)
+    }
+}
+
+impl MultiResolutionSignature {
+    /// Jaccard similarity over the levels both signatures have, each level
+    /// weighted by the inverse relative variance of its estimate: large
+    /// sketches and many shared hashes count more. Fails with the first
+    /// level that is not comparable.
+    pub fn weighted_similarity(
+        &self,
+        other: &Self,
+    ) -> Result<WeightedSimilarity, IncompatibleSketches> {
+        self.check_comparable(other)?;
+
+        let mut levels = Vec::with_capacity(self.levels.len().min(other.levels.len()));
+        for (level, other_level) in self.levels.iter().zip(&other.levels) {
+            let jaccard = level.jaccard(other_level).unwrap_or(0.0);
+            let shared_hashes = level.sketch.shared_hashes(&other_level.sketch);
+            let compared_hashes =
+                compared_hashes(&level.sketch, &other_level.sketch, shared_hashes);
+            let relative_variance =
+                (1.0 - jaccard + 1.0 / compared_hashes.max(1) as f64) / (shared_hashes + 1) as f64;
+            levels.push(LevelSimilarity {
+                kmer_size: level.kmer_size,
+                jaccard,
+                shared_hashes,
+                compared_hashes,
+                relative_variance,
+                weight: 1.0 / relative_variance,
+            });
+        }
+
+        let total_weight: f64 = levels.iter().map(|level| level.weight).sum();
+        let mut score = 0.0;
+        for level in &mut levels {
+            level.weight /= total_weight;
+            score += level.weight * level.jaccard;
+        }
+        Ok(WeightedSimilarity { score, levels })
+    }
+}
+
+/// Size of the hash sample a Jaccard estimate of `a` and `b` is based on
+fn compared_hashes(a: &Signature, b: &Signature, shared_hashes: usize) -> usize {
+    if a.scaled == 0 && a.num_hashes > 0 && b.num_hashes > 0 {
+        a.num_hashes.min(b.num_hashes)
+    } else {
+        a.size() + b.size() - shared_hashes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_measures_agree_across_signature_types() {
+        let reference = level(21, 0, [1, 2, 3, 4]);
+        let sample = level(21, 0, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // Jaccard is diluted by the rest of the sample, containment is not
+        assert_relative_eq!(reference.jaccard(&sample).unwrap(), 0.5);
+        assert_relative_eq!(reference.containment(&sample).unwrap(), 1.0);
+        assert_relative_eq!(sample.containment(&reference).unwrap(), 0.5);
+        assert_relative_eq!(reference.containment_ani(&sample).unwrap(), 1.0);
+        assert!(reference.ani(&sample).unwrap() < 1.0);
+        assert_eq!(reference.ani(&reference), Some(1.0));
+        assert_eq!(
+            reference.sketch.containment(&sample.sketch),
+            reference.containment(&sample)
+        );
+        assert_eq!(reference.sketch.ani(&sample.sketch), None);
+
+        // Different k-mer sizes are not comparable
+        assert_eq!(reference.containment(&level(31, 0, [1, 2, 3, 4])), None);
+
+        let multi = signature("562", &[], [level(15, 0, [1, 2]), reference.clone()]);
+        let other = signature("sample", &[], [level(15, 0, [1, 2, 3, 4]), sample.clone()]);
+        assert_relative_eq!(multi.jaccard(&other).unwrap(), 0.5);
+        assert_relative_eq!(multi.containment(&other).unwrap(), 1.0);
+        assert_eq!(multi.ani(&other), reference.ani(&sample));
+    }
+
+    #[test]
+    fn test_weights_follow_shared_hashes() {
+        // Coarse level: 90 of 100 hashes shared; fine level: 2 of 100
+        let reference = signature("562", &[], [level(31, 0, 0..100), level(21, 0, 0..100)]);
+        let query = signature(
+            "sample",
+            &[],
+            [level(31, 0, 10..110), level(21, 0, 98..198)],
+        );
+
+        let weighted = reference.weighted_similarity(&query).unwrap();
+        assert_eq!(weighted.levels.len(), 2);
+        assert_eq!(weighted.levels[0].shared_hashes, 90);
+        assert_eq!(weighted.levels[1].shared_hashes, 2);
+        assert!(weighted.levels[0].weight > 0.9);
+        assert_relative_eq!(
+            weighted.levels.iter().map(|l| l.weight).sum::<f64>(),
+            1.0,
+            epsilon = 1e-12
+        );
+        assert_eq!(weighted.driving_level(), Some(0));
+        // Equal weights would let the noisy fine level drag the score down
+        assert!(weighted.score > reference.similarity(&query, None).unwrap());
+
+        assert!(reference
+            .weighted_similarity(&MultiResolutionSignature::new(
+                "empty".to_string(),
+                Vec::new()
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn test_weighted_jaccard_discounts_common_hashes() {
+        let reference = level(21, 0, [1, 2, 3, 4]);
+        let sample = level(21, 0, [1, 2, 5, 6]);
+        // Equal weights give the plain set Jaccard and containment
+        assert_relative_eq!(
+            reference.weighted_jaccard(&sample, |_| 1.0).unwrap(),
+            1.0 / 3.0
+        );
+        assert_relative_eq!(
+            reference.weighted_containment(&sample, |_| 1.0).unwrap(),
+            0.5
+        );
+
+        // Hashes 1 and 2 are shared by everything, so they barely count
+        let rare = |hash: u64| if hash <= 2 { 0.01 } else { 1.0 };
+        assert!(reference.weighted_jaccard(&sample, rare).unwrap() < 0.01);
+        assert!(reference.weighted_containment(&sample, rare).unwrap() < 0.01);
+        assert_eq!(reference.weighted_jaccard(&sample, |_| 0.0), None);
+        assert_eq!(
+            reference.weighted_jaccard(&level(31, 0, [1, 2]), |_| 1.0),
+            None
+        );
+    }
+}
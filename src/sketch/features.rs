@@ -0,0 +1,276 @@
+//! Fixed-length feature vectors of samples, for training classifiers.
+//!
+//! Sketches differ in length from sample to sample and hold hash values, not
+//! quantities a model can learn from, so `export-features` turns the stored
+//! sample sketches into a matrix with one row per sample and the same
+//! columns for all:
+//!
+//! * `hashed` - each level's hashes folded into `dimensions` bins by
+//!   `hash % dimensions`, as the share of the level's hashes in each bin.
+//!   Hashes are uniform, so samples sharing k-mers fill the same bins and the
+//!   embedding needs no references; columns are `k<k>_bin<i>`.
+//! * `references` - the containment of references in each sample, which
+//!   grows with how much of a reference's genome the sample holds. Only
+//!   references among the `top_k` closest to at least one sample become
+//!   columns, named by reference ID, so the matrix stays small with large
+//!   databases yet every row covers the same references.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use ndarray::Array2;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::io::escape::csv_writer_builder;
+use crate::io::npy::write_npy;
+use crate::sketch::signature::MultiResolutionSignature;
+use crate::sketch::Comparable;
+
+/// Bins per resolution level of the hashed embedding
+pub const DEFAULT_EMBEDDING_DIMENSIONS: usize = 1024;
+
+/// Closest references per sample kept as reference similarity columns
+pub const DEFAULT_TOP_REFERENCES: usize = 10;
+
+/// What the features of a sample are computed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatureKind {
+    /// Sketch hashes folded into a fixed number of bins per level
+    #[default]
+    Hashed,
+    /// Containment of the closest references in the sample
+    References,
+}
+
+/// Feature matrix with its row and column labels
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleFeatures {
+    /// Sample ID of each row
+    pub sample_ids: Vec<String>,
+    /// Name of each column
+    pub feature_names: Vec<String>,
+    /// Samples × features
+    pub values: Array2<f32>,
+}
+
+impl SampleFeatures {
+    /// Write `features.npy`, with the row labels in `samples.csv` and the
+    /// column labels in `features.csv`, to `dir`; returns the paths written
+    pub fn write(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        std::fs::create_dir_all(dir)?;
+        let matrix = dir.join("features.npy");
+        write_npy(&matrix, &self.values)?;
+        let samples = dir.join("samples.csv");
+        write_labels(&samples, "sample_id", &self.sample_ids)?;
+        let features = dir.join("features.csv");
+        write_labels(&features, "feature", &self.feature_names)?;
+        Ok(vec![matrix, samples, features])
+    }
+}
+
+/// Write one label per line under `header`, in row or column order
+fn write_labels(path: &Path, header: &str, labels: &[String]) -> Result<()> {
+    let mut writer = csv_writer_builder().from_writer(BufWriter::new(File::create(path)?));
+    writer.write_record([header])?;
+    for label in labels {
+        writer.write_record([label])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Hashed embedding of `samples`, `dimensions` bins per level. All samples
+/// must have been sketched with the same k-mer sizes.
+pub fn hashed_embedding(
+    samples: &[(String, MultiResolutionSignature)],
+    dimensions: usize,
+) -> Result<SampleFeatures> {
+    if dimensions == 0 {
+        bail!("The hashed embedding needs at least one dimension");
+    }
+    let Some((_, first)) = samples.first() else {
+        bail!("No sample sketches to export features from");
+    };
+    let kmer_sizes: Vec<usize> = first.levels.iter().map(|level| level.kmer_size).collect();
+    for (sample_id, signature) in samples {
+        let sizes: Vec<usize> = signature
+            .levels
+            .iter()
+            .map(|level| level.kmer_size)
+            .collect();
+        if sizes != kmer_sizes {
+            bail!(
+                "Sample {} was sketched with k-mer sizes {:?}, other samples with {:?}",
+                sample_id,
+                sizes,
+                kmer_sizes
+            );
+        }
+    }
+
+    let mut values = Array2::zeros((samples.len(), kmer_sizes.len() * dimensions));
+    for (mut row, (_, signature)) in values.outer_iter_mut().zip(samples) {
+        for (index, level) in signature.levels.iter().enumerate() {
+            let hashes = &level.sketch.hashes;
+            if hashes.is_empty() {
+                continue;
+            }
+            let weight = 1.0 / hashes.len() as f32;
+            for hash in hashes {
+                row[index * dimensions + (hash % dimensions as u64) as usize] += weight;
+            }
+        }
+    }
+
+    let feature_names = kmer_sizes
+        .iter()
+        .flat_map(|k| (0..dimensions).map(move |bin| format!("k{}_bin{}", k, bin)))
+        .collect();
+    Ok(SampleFeatures {
+        sample_ids: samples.iter().map(|(id, _)| id.clone()).collect(),
+        feature_names,
+        values,
+    })
+}
+
+/// Containment of each reference in `query` at the finest level both can be
+/// compared at, 0 if none
+fn reference_containment(
+    reference: &MultiResolutionSignature,
+    query: &MultiResolutionSignature,
+) -> f64 {
+    reference
+        .levels
+        .iter()
+        .zip(&query.levels)
+        .rev()
+        .find_map(|(reference_level, query_level)| reference_level.containment(query_level))
+        .unwrap_or(0.0)
+}
+
+/// Reference similarity vectors of `samples`: the containment of every
+/// reference among the `top_k` closest to some sample (all references when
+/// `top_k` is 0), columns in reference order
+pub fn reference_similarities(
+    samples: &[(String, MultiResolutionSignature)],
+    references: &[MultiResolutionSignature],
+    top_k: usize,
+) -> Result<SampleFeatures> {
+    if samples.is_empty() {
+        bail!("No sample sketches to export features from");
+    }
+    if references.is_empty() {
+        bail!("The database has no reference signatures");
+    }
+    let containments: Vec<Vec<f64>> = samples
+        .par_iter()
+        .map(|(_, signature)| {
+            references
+                .iter()
+                .map(|reference| reference_containment(reference, signature))
+                .collect()
+        })
+        .collect();
+
+    let columns: Vec<usize> = if top_k == 0 || top_k >= references.len() {
+        (0..references.len()).collect()
+    } else {
+        let mut kept = BTreeSet::new();
+        for row in &containments {
+            let mut order: Vec<usize> = (0..references.len()).collect();
+            order.sort_by(|&a, &b| row[b].total_cmp(&row[a]));
+            kept.extend(order.into_iter().take(top_k));
+        }
+        kept.into_iter().collect()
+    };
+
+    let values = Array2::from_shape_fn((samples.len(), columns.len()), |(row, column)| {
+        containments[row][columns[column]] as f32
+    });
+    Ok(SampleFeatures {
+        sample_ids: samples.iter().map(|(id, _)| id.clone()).collect(),
+        feature_names: columns
+            .iter()
+            .map(|&index| references[index].taxon_id.clone())
+            .collect(),
+        values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_features_have_fixed_length() {
+        let samples = vec![
+            (
+                "S1".to_string(),
+                signature(
+                    "S1",
+                    &["Bacteria", "S1"],
+                    [level(31, 100, 0..100), level(21, 100, 0..100)],
+                ),
+            ),
+            (
+                "S2".to_string(),
+                signature(
+                    "S2",
+                    &["Bacteria", "S2"],
+                    [level(31, 100, 50..150), level(21, 100, 100..200)],
+                ),
+            ),
+        ];
+
+        let hashed = hashed_embedding(&samples, 8).unwrap();
+        assert_eq!(hashed.values.dim(), (2, 16));
+        assert_eq!(hashed.feature_names[0], "k31_bin0");
+        assert_eq!(hashed.feature_names[15], "k21_bin7");
+        for row in hashed.values.outer_iter() {
+            assert!((row.sum() - 2.0).abs() < 1e-5);
+        }
+        // Hashes 0..100 put 13 of 100 in bin 0 (0, 8, ..., 96)
+        assert!((hashed.values[[0, 0]] - 0.13).abs() < 1e-6);
+
+        let references = vec![
+            signature(
+                "A",
+                &["Bacteria", "A"],
+                [level(31, 100, 0..100), level(21, 100, 0..100)],
+            ),
+            signature(
+                "B",
+                &["Bacteria", "B"],
+                [level(31, 100, 100..200), level(21, 100, 100..200)],
+            ),
+            signature(
+                "C",
+                &["Bacteria", "C"],
+                [level(31, 100, 500..600), level(21, 100, 500..600)],
+            ),
+        ];
+        let similar = reference_similarities(&samples, &references, 1).unwrap();
+        assert_eq!(similar.feature_names, vec!["A", "B"]);
+        assert_eq!(similar.values[[0, 0]], 1.0);
+        assert_eq!(similar.values[[0, 1]], 0.0);
+        assert_eq!(similar.values[[1, 1]], 1.0);
+        let all = reference_similarities(&samples, &references, 0).unwrap();
+        assert_eq!(all.values.dim(), (2, 3));
+
+        let mut mixed = samples.clone();
+        mixed[1].1.levels[1].kmer_size = 15;
+        assert!(hashed_embedding(&mixed, 8).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let written = similar.write(dir.path()).unwrap();
+        assert_eq!(written.len(), 3);
+        let labels = std::fs::read_to_string(dir.path().join("features.csv")).unwrap();
+        assert_eq!(labels, "feature\nA\nB\n");
+    }
+}
@@ -0,0 +1,302 @@
+//! Masking of reference genomes before sketching.
+//!
+//! Two kinds of sequence make references look alike without saying anything
+//! about which strain is present. Low-complexity regions (homopolymers,
+//! short tandem repeats) produce the same k-mers in unrelated genomes, and
+//! some k-mers, from conserved genes, mobile elements or common vectors, are
+//! shared by a large share of all references. Either kind draws reads
+//! towards references that merely contain it.
+//!
+//! * `LowComplexityMask` finds windows whose trinucleotide composition is
+//!   too repetitive, scored as in DUST, and leaves them out of the sketch
+//! * `CommonHashes` lists, for each k-mer size, the hashes found in the
+//!   sketches of more than a given fraction of the references of a database;
+//!   references are then sketched without them, so their sketches fill up
+//!   with hashes that tell them apart
+//!
+//! Both lists come from `HashFrequencies`, the share of references whose
+//! sketches contain each hash, which classification also uses to ignore
+//! query hashes that nearly every reference shares, or to weigh each hash by
+//! how rare it is.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+
+use bincode::{Decode, Encode};
+
+use crate::bio::kmers::base_code;
+use crate::sketch::MultiResolutionSignature;
+
+/// DUST score above which a window is masked
+pub const DEFAULT_DUST_LEVEL: f64 = 20.0;
+
+/// Bases per window scored for low complexity
+pub const DEFAULT_DUST_WINDOW: usize = 64;
+
+/// Fewer references than this cannot say which k-mers are common
+pub const MIN_REFERENCES_FOR_COMMON_HASHES: usize = 3;
+
+/// Masks windows whose trinucleotide composition is too repetitive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LowComplexityMask {
+    /// Bases per window
+    pub window: usize,
+    /// DUST score above which a window is masked
+    pub level: f64,
+}
+
+impl Default for LowComplexityMask {
+    fn default() -> Self {
+        LowComplexityMask {
+            window: DEFAULT_DUST_WINDOW,
+            level: DEFAULT_DUST_LEVEL,
+        }
+    }
+}
+
+impl LowComplexityMask {
+    /// Low-complexity regions of `sequence`, merged and in order.
+    ///
+    /// A window of l triplets (trinucleotides) with counts c_t scores
+    /// sum_t c_t (c_t - 1) / 2 / (l - 1): about l / 2 for a homopolymer and
+    /// close to 0 for random sequence. Triplets with ambiguous bases are not
+    /// counted.
+    pub fn regions(&self, sequence: &[u8]) -> Vec<Range<usize>> {
+        let window = self.window;
+        let mut regions: Vec<Range<usize>> = Vec::new();
+        if window < 4 || sequence.len() < window {
+            return regions;
+        }
+        let triplets = window - 2;
+        let triplet = |i: usize| {
+            sequence[i..i + 3]
+                .iter()
+                .try_fold(0, |t, &base| Some(t * 4 + base_code(base)? as usize))
+        };
+
+        let mut counts = [0u32; 64];
+        // sum_t c_t (c_t - 1) / 2 over the triplets in the window
+        let mut pairs: u64 = 0;
+        for i in 0..sequence.len() - 2 {
+            if let Some(t) = triplet(i) {
+                pairs += counts[t] as u64;
+                counts[t] += 1;
+            }
+            if i >= triplets {
+                if let Some(t) = triplet(i - triplets) {
+                    counts[t] -= 1;
+                    pairs -= counts[t] as u64;
+                }
+            }
+            if i + 1 < triplets {
+                continue;
+            }
+            let start = i + 1 - triplets;
+            if pairs as f64 / (triplets - 1) as f64 > self.level {
+                match regions.last_mut() {
+                    Some(last) if last.end >= start => last.end = start + window,
+                    _ => regions.push(start..start + window),
+                }
+            }
+        }
+        regions
+    }
+
+    /// The parts of `sequence` outside its low-complexity regions
+    pub fn unmasked<'a>(&self, sequence: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut parts = Vec::new();
+        let mut start = 0;
+        for region in self.regions(sequence) {
+            if region.start > start {
+                parts.push(&sequence[start..region.start]);
+            }
+            start = region.end;
+        }
+        if start < sequence.len() {
+            parts.push(&sequence[start..]);
+        }
+        parts
+    }
+}
+
+/// How many references' sketches contain each hash, by k-mer size
+#[derive(Debug, Clone, Default)]
+pub struct HashFrequencies {
+    reference_count: usize,
+    counts: HashMap<(usize, u64), u32>,
+}
+
+impl HashFrequencies {
+    /// Count the hashes of every level of `signatures`
+    pub fn from_signatures(signatures: &[MultiResolutionSignature]) -> Self {
+        let mut counts: HashMap<(usize, u64), u32> = HashMap::new();
+        for signature in signatures {
+            for level in &signature.levels {
+                for &hash in &level.sketch.hashes {
+                    *counts.entry((level.kmer_size, hash)).or_insert(0) += 1;
+                }
+            }
+        }
+        HashFrequencies {
+            reference_count: signatures.len(),
+            counts,
+        }
+    }
+
+    /// Share of the references whose sketches for k-mers of `kmer_size` contain `hash`
+    pub fn frequency(&self, kmer_size: usize, hash: u64) -> f64 {
+        if self.reference_count == 0 {
+            return 0.0;
+        }
+        let count = self.counts.get(&(kmer_size, hash)).copied().unwrap_or(0);
+        count as f64 / self.reference_count as f64
+    }
+
+    /// Inverse document frequency of `hash`, `ln((1 + N) / (1 + n)) + 1` for
+    /// `n` of `N` references: 1 for a hash in every reference, and largest
+    /// for one in none
+    pub fn idf(&self, kmer_size: usize, hash: u64) -> f64 {
+        let count = self.counts.get(&(kmer_size, hash)).copied().unwrap_or(0);
+        ((1 + self.reference_count) as f64 / (1 + count) as f64).ln() + 1.0
+    }
+
+    /// Hashes found in more than `max_shared_fraction` of the references;
+    /// none if there are too few references to tell
+    pub fn common(&self, max_shared_fraction: f64) -> CommonHashes {
+        let mut common = CommonHashes {
+            max_shared_fraction,
+            reference_count: self.reference_count,
+            by_kmer_size: BTreeMap::new(),
+        };
+        if self.reference_count < MIN_REFERENCES_FOR_COMMON_HASHES {
+            return common;
+        }
+        let limit = max_shared_fraction * self.reference_count as f64;
+        for (&(kmer_size, hash), &count) in &self.counts {
+            if count as f64 > limit {
+                common
+                    .by_kmer_size
+                    .entry(kmer_size)
+                    .or_default()
+                    .insert(hash);
+            }
+        }
+        common
+    }
+}
+
+/// Hashes shared by too many of a database's references, by k-mer size
+#[derive(Debug, Clone, Default, PartialEq, Encode, Decode)]
+pub struct CommonHashes {
+    /// Hashes in more than this fraction of the references are common
+    pub max_shared_fraction: f64,
+    /// References the hashes were counted over
+    pub reference_count: usize,
+    by_kmer_size: BTreeMap<usize, HashSet<u64>>,
+}
+
+impl CommonHashes {
+    /// Hashes found in the sketches of more than `max_shared_fraction` of
+    /// `signatures`; none if there are too few signatures to tell
+    pub fn from_signatures(
+        signatures: &[MultiResolutionSignature],
+        max_shared_fraction: f64,
+    ) -> Self {
+        HashFrequencies::from_signatures(signatures).common(max_shared_fraction)
+    }
+
+    /// Common hashes of k-mers of `kmer_size`
+    pub fn for_kmer_size(&self, kmer_size: usize) -> Option<&HashSet<u64>> {
+        self.by_kmer_size.get(&kmer_size)
+    }
+
+    /// Number of common hashes across all k-mer sizes
+    pub fn len(&self) -> usize {
+        self.by_kmer_size.values().map(HashSet::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add the hashes of `other`, keeping the larger reference count
+    pub fn merge(&mut self, other: &CommonHashes) {
+        for (&kmer_size, hashes) in &other.by_kmer_size {
+            self.by_kmer_size
+                .entry(kmer_size)
+                .or_default()
+                .extend(hashes);
+        }
+        self.reference_count = self.reference_count.max(other.reference_count);
+    }
+
+    /// `signature` without the common hashes, in every level
+    pub fn remove_from(&self, signature: &MultiResolutionSignature) -> MultiResolutionSignature {
+        let mut filtered = signature.clone();
+        for level in &mut filtered.levels {
+            if let Some(common) = self.for_kmer_size(level.kmer_size) {
+                level.sketch.hashes.retain(|hash| !common.contains(hash));
+            }
+        }
+        filtered
+    }
+
+    /// Whether every hash of `other` is already in this set
+    pub fn contains_all(&self, other: &CommonHashes) -> bool {
+        other.by_kmer_size.iter().all(|(kmer_size, hashes)| {
+            self.for_kmer_size(*kmer_size)
+                .is_some_and(|own| hashes.is_subset(own))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_masks_low_complexity_and_common_hashes() {
+        let unique = b"GATCGGAAGAGCACACGTCTGAACTCCAGTCACTTAGGCATCTCGTATGCCGTCTTCTGCTTGAAAGT";
+        let mut sequence = unique.to_vec();
+        sequence.extend_from_slice(&[b'A'; 100]);
+        sequence.extend_from_slice(unique);
+
+        let mask = LowComplexityMask::default();
+        let regions = mask.regions(&sequence);
+        assert_eq!(regions.len(), 1);
+        assert!(regions[0].start <= unique.len() && regions[0].end >= unique.len() + 100);
+        let unmasked = mask.unmasked(&sequence);
+        assert_eq!(unmasked.len(), 2);
+        assert!(unmasked
+            .iter()
+            .all(|part| !part.windows(20).any(|w| w == [b'A'; 20])));
+        assert!(mask.regions(unique).is_empty());
+        assert!(mask.regions(b"AAAA").is_empty());
+
+        let signatures = [
+            signature("a", &[], [level(21, 4, [1, 2, 10])]),
+            signature("b", &[], [level(21, 4, [1, 2, 20])]),
+            signature("c", &[], [level(21, 4, [1, 3, 30])]),
+        ];
+        let frequencies = HashFrequencies::from_signatures(&signatures);
+        assert_eq!(frequencies.frequency(21, 1), 1.0);
+        assert_eq!(frequencies.frequency(31, 1), 0.0);
+        assert_eq!(frequencies.idf(21, 1), 1.0);
+        assert!(frequencies.idf(21, 10) > frequencies.idf(21, 2));
+        let common = CommonHashes::from_signatures(&signatures, 0.5);
+        assert_eq!(common.for_kmer_size(21), Some(&HashSet::from([1, 2])));
+        assert_eq!(
+            common.remove_from(&signatures[0]).levels[0].sketch.hashes,
+            vec![10]
+        );
+        assert_eq!(common.for_kmer_size(31), None);
+        assert!(CommonHashes::from_signatures(&signatures[..2], 0.5).is_empty());
+
+        let mut merged = CommonHashes::from_signatures(&signatures, 0.9);
+        assert_eq!(merged.len(), 1);
+        assert!(!merged.contains_all(&common));
+        merged.merge(&common);
+        assert!(merged.contains_all(&common));
+    }
+}
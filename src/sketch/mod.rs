@@ -5,17 +5,27 @@
 //! by creating compressed representations (signatures or sketches).
 
 pub mod adaptive;
+pub mod cache;
+pub mod compare;
+pub mod features;
+pub mod masking;
 pub mod minhash; // MinHash implementation // Potentially adaptive MinHash or other adaptive sketching
+pub mod params;
 pub mod signature;
 
-pub use adaptive::AdaptiveClassifier;
-pub use signature::MultiResolutionSignature;
-use signature::{KmerSignature, KmerSignatureBuilder};
+#[cfg(test)]
+pub(crate) mod testing;
+
+pub use crate::adaptive::AdaptiveClassifier;
+pub use cache::SimilarityCache;
+pub use compare::Comparable;
+pub use signature::{MultiResolutionSignature, SignatureLayout};
 
 // Re-export key structures or functions if needed
 // pub use minhash::MinHashSketcher;
 // pub use adaptive::AdaptiveSketcher;
 
+use crate::sketch::masking::{CommonHashes, LowComplexityMask};
 use crate::sketch::signature::Signature; // Use our own Signature structure
 use anyhow::{anyhow, Result};
 use needletail::parser::SequenceRecord;
@@ -58,15 +68,21 @@ pub trait Sketcher {
 }
 
 /// Builder for creating genomic signatures from sequence data.
+#[derive(Debug, Clone)]
 pub struct SignatureBuilder {
-    pub kmer_size: u8,
-    pub sketch_size: usize,
-    pub min_kmer_size: u8,
-    pub levels: u8,
+    /// Levels every built signature has
+    pub layout: SignatureLayout,
+
+    /// Leave low-complexity regions out of the sketches
+    pub low_complexity: Option<LowComplexityMask>,
+
+    /// Hashes never kept in the sketches, as too many references share them
+    pub common_hashes: Option<CommonHashes>,
 }
 
 impl SignatureBuilder {
-    /// Creates a new SignatureBuilder with the specified parameters.
+    /// Creates a new SignatureBuilder with `levels` MinHash levels whose k-mer
+    /// sizes step evenly from `kmer_size` down to `min_kmer_size`.
     pub fn new(kmer_size: u8, min_kmer_size: u8, sketch_size: usize, levels: u8) -> Result<Self> {
         if kmer_size < min_kmer_size {
             return Err(anyhow!("kmer_size must be >= min_kmer_size"));
@@ -78,11 +94,24 @@ impl SignatureBuilder {
             return Err(anyhow!("levels must be > 0"));
         }
 
+        let k_step = if levels > 1 {
+            (kmer_size - min_kmer_size) as f32 / (levels - 1) as f32
+        } else {
+            0.0
+        };
+        let kmer_sizes: Vec<usize> = (0..levels)
+            .map(|level| (kmer_size as f32 - level as f32 * k_step).round() as usize)
+            .collect();
+        Self::from_layout(SignatureLayout::minhash(&kmer_sizes, sketch_size))
+    }
+
+    /// Creates a SignatureBuilder producing signatures with exactly `layout`
+    pub fn from_layout(layout: SignatureLayout) -> Result<Self> {
+        layout.validate().map_err(|e| anyhow!(e))?;
         Ok(Self {
-            kmer_size,
-            sketch_size,
-            min_kmer_size,
-            levels,
+            layout,
+            low_complexity: None,
+            common_hashes: None,
         })
     }
 
@@ -94,21 +123,39 @@ impl SignatureBuilder {
         lineage: Vec<String>,
     ) -> Result<MultiResolutionSignature> {
         let mut reader = parse_fastx_file(file_path.as_ref())?;
-        let mut multi_sig = MultiResolutionSignature::new(taxon_id.to_string(), lineage);
-
-        // Calculate k-mer sizes for each level
-        let k_step = (self.kmer_size - self.min_kmer_size) as f32 / (self.levels - 1) as f32;
-
-        // Create signatures for each resolution level
-        for level in 0..self.levels {
-            let level_k = (self.kmer_size as f32 - (level as f32 * k_step)).round() as usize;
-            let level_sketch_size = self.sketch_size / (1 << level); // Decrease sketch size for finer resolutions
+        let mut multi_sig =
+            MultiResolutionSignature::with_layout(taxon_id.to_string(), lineage, &self.layout);
+        let path = file_path.as_ref();
+        for (index, level) in multi_sig.levels.iter_mut().enumerate() {
+            level.name = Some(format!("level_{}", index));
+            level.filename = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned());
+            level.path = Some(path.to_path_buf());
+        }
 
-            let mut level_sig =
-                KmerSignatureBuilder::new(level_k, "DNA", "minhash", level_sketch_size, 0);
-            let name = format!("level_{}", level);
-            let built_sig = level_sig.source(file_path.as_ref()).name(&name).build();
-            multi_sig.add_level(built_sig);
+        while let Some(record) = reader.next() {
+            let record = record?;
+            let sequence = record.normalize(false);
+            let parts = match &self.low_complexity {
+                Some(mask) => mask.unmasked(&sequence),
+                None => vec![&sequence[..]],
+            };
+            for level in &mut multi_sig.levels {
+                let excluded = self
+                    .common_hashes
+                    .as_ref()
+                    .and_then(|common| common.for_kmer_size(level.kmer_size));
+                for part in &parts {
+                    // Contigs shorter than k contribute no k-mers
+                    if part.len() < level.kmer_size {
+                        continue;
+                    }
+                    level
+                        .add_sequence_excluding(part, excluded)
+                        .map_err(|e| anyhow!(e))?;
+                }
+            }
         }
 
         Ok(multi_sig)
@@ -0,0 +1,115 @@
+//! Hashing parameters recorded with every sketch, and why two sketches
+//! cannot be compared.
+//!
+//! The k-mer size, molecule type and sketch size have always been stored
+//! next to the hashes, but the hash function and whether k-mers were
+//! canonicalized were only implied by the code that built the sketch. A
+//! change to either would make new sample sketches share no hashes with an
+//! older database, which looks exactly like a sample with nothing in it.
+//! Both are now stored in each `KmerSignature` together with the version of
+//! the software that built it, and comparisons report which parameter
+//! differs instead of returning nothing.
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Hash function used for k-mers
+pub const HASH_FUNCTION: &str = "nthash";
+
+/// Software version recorded for sketches built before versions were stored
+pub const UNKNOWN_VERSION: &str = "unknown";
+
+/// How the hashes of a sketch were produced
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Encode, Decode)]
+pub struct HashingParameters {
+    pub hash_function: String,
+    /// Whether each k-mer was hashed together with its reverse complement
+    pub canonical: bool,
+    /// Version of the software that built the sketch
+    pub software_version: String,
+}
+
+impl HashingParameters {
+    /// Parameters of sketches built by this version
+    pub fn current(canonical: bool) -> Self {
+        HashingParameters {
+            hash_function: HASH_FUNCTION.to_string(),
+            canonical,
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Parameters of sketches stored before they were recorded, which were
+    /// all canonical ntHash DNA sketches
+    pub fn legacy() -> Self {
+        HashingParameters {
+            hash_function: HASH_FUNCTION.to_string(),
+            canonical: true,
+            software_version: UNKNOWN_VERSION.to_string(),
+        }
+    }
+
+    /// Whether sketches hashed with `self` and `other` contain comparable
+    /// hashes. The software version is informational: it changes with every
+    /// release while the hashes stay the same.
+    pub fn check(&self, other: &HashingParameters) -> Result<(), IncompatibleSketches> {
+        if self.hash_function != other.hash_function {
+            return Err(IncompatibleSketches::HashFunction(
+                self.hash_function.clone(),
+                other.hash_function.clone(),
+            ));
+        }
+        if self.canonical != other.canonical {
+            return Err(IncompatibleSketches::Canonicalization);
+        }
+        Ok(())
+    }
+}
+
+/// Why two sketches cannot be compared
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum IncompatibleSketches {
+    #[error("k-mer sizes differ ({0} vs {1})")]
+    KmerSize(usize, usize),
+
+    #[error("molecule types differ ({0} vs {1})")]
+    MoleculeType(String, String),
+
+    #[error("sketch algorithms differ ({0} vs {1})")]
+    Algorithm(String, String),
+
+    #[error("sketch kinds differ ({0} vs {1})")]
+    SketchKind(String, String),
+
+    #[error("scaling factors differ ({0} vs {1})")]
+    Scaled(u64, u64),
+
+    #[error("sketch has neither a fixed size nor a scaling factor")]
+    Unsized,
+
+    #[error("hash functions differ ({0} vs {1})")]
+    HashFunction(String, String),
+
+    #[error("one sketch hashes canonical k-mers and the other does not")]
+    Canonicalization,
+
+    #[error("signature has no resolution levels")]
+    NoLevels,
+
+    #[error("level {level}: {reason}")]
+    Level {
+        level: usize,
+        reason: Box<IncompatibleSketches>,
+    },
+}
+
+impl IncompatibleSketches {
+    /// This incompatibility, found at resolution level `level`
+    pub fn at_level(self, level: usize) -> Self {
+        IncompatibleSketches::Level {
+            level,
+            reason: Box::new(self),
+        }
+    }
+}
@@ -5,12 +5,17 @@
 use bincode::{Decode, Encode};
 use nthash::NtHashIterator;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::BinaryHeap; // Added for efficient intersection
-use std::collections::HashSet; // Added for efficient intersection
+use std::collections::{HashMap, HashSet}; // Added for efficient intersection
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf}; // Added Path for function args
 
+use crate::sketch::compare::Comparable;
+use crate::sketch::params::{HashingParameters, IncompatibleSketches};
+
 // --- Generic Signature (Sketch) ---
 
 /// Represents the core sketch data, typically a collection of hash values.
@@ -75,6 +80,62 @@ impl Signature {
         self.hashes.len()
     }
 
+    /// Whether two sketches were built with parameters that allow comparing them:
+    /// the same algorithm, and the same scaling factor for scaled MinHash or
+    /// fixed sizes on both sides for standard MinHash.
+    pub fn is_compatible(&self, other: &Signature) -> bool {
+        self.check_compatible(other).is_ok()
+    }
+
+    /// Like `is_compatible`, but says which parameter differs
+    pub fn check_compatible(&self, other: &Signature) -> Result<(), IncompatibleSketches> {
+        if self.algorithm != other.algorithm {
+            // Different algorithms cannot be compared directly
+            return Err(IncompatibleSketches::Algorithm(
+                self.algorithm.clone(),
+                other.algorithm.clone(),
+            ));
+        }
+        if self.scaled > 0 && other.scaled > 0 {
+            // Scaled MinHash requires matching scaling factors
+            if self.scaled != other.scaled {
+                return Err(IncompatibleSketches::Scaled(self.scaled, other.scaled));
+            }
+            return Ok(());
+        }
+        // Fixed num_hashes can be compared even if the sizes differ, using the
+        // smaller one. Cannot compare fixed num with scaled (num_hashes = 0),
+        // and sketches with neither only with the same kind.
+        if self.sketch_kind() != other.sketch_kind() {
+            return Err(IncompatibleSketches::SketchKind(
+                self.sketch_kind().to_string(),
+                other.sketch_kind().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn sketch_kind(&self) -> &'static str {
+        if self.scaled > 0 {
+            "scaled"
+        } else if self.num_hashes > 0 {
+            "fixed-size"
+        } else {
+            "unsized"
+        }
+    }
+
+    /// Number of hashes present in both sketches
+    pub fn shared_hashes(&self, other: &Signature) -> usize {
+        // Use HashSet for efficiency with larger sketches
+        let self_hashes: HashSet<u64> = self.hashes.iter().copied().collect();
+        other
+            .hashes
+            .iter()
+            .filter(|hash| self_hashes.contains(hash))
+            .count()
+    }
+
     /// Calculates the Jaccard similarity estimate between this sketch and another.
     /// Assumes a MinHash-like sketch (standard or scaled).
     ///
@@ -85,31 +146,8 @@ impl Signature {
     /// The Jaccard similarity estimate (between 0.0 and 1.0), or None if
     /// sketches are incompatible (different algorithms, incompatible parameters).
     pub fn estimate_jaccard(&self, other: &Signature) -> Option<f64> {
-        // --- Compatibility Checks ---
-        if self.algorithm != other.algorithm {
-            return None; // Different algorithms cannot be compared directly
-        }
-
-        // Check if parameters make sense for comparison
-        // For standard MinHash (num_hashes > 0), num_hashes should ideally match,
-        // but comparison using the minimum is common.
-        // For scaled MinHash (scaled > 0), scaled factors must match.
-        if self.scaled > 0 {
-            if self.scaled != other.scaled {
-                return None; // Scaled MinHash requires matching scaling factors
-            }
-        } else if self.num_hashes > 0 {
-            // For fixed num_hashes, we can compare even if nums differ, using the smaller num.
-            // Warning: This estimate is less reliable if num_hashes differ significantly.
-            if other.num_hashes == 0 {
-                return None;
-            } // Cannot compare fixed num with scaled (scaled=0)
-        } else {
-            // Both num_hashes and scaled are 0 - undefined sketch type? Or empty?
-            if other.num_hashes > 0 || other.scaled > 0 {
-                return None;
-            } // Incompatible
-              // If both are 0 and empty, similarity is arguably 1.0? Or 0.0? Or undefined? Let's return 0.0 for empty.
+        if !self.is_compatible(other) {
+            return None;
         }
 
         if self.is_empty() || other.is_empty() {
@@ -127,15 +165,7 @@ impl Signature {
             }
         }
 
-        // --- Calculate Intersection ---
-        // Use HashSet for efficiency with larger sketches
-        let self_hashes: HashSet<u64> = self.hashes.iter().cloned().collect();
-        let mut intersection_size = 0;
-        for hash in &other.hashes {
-            if self_hashes.contains(hash) {
-                intersection_size += 1;
-            }
-        }
+        let intersection_size = self.shared_hashes(other);
 
         // --- Estimate Jaccard based on algorithm type ---
         if self.scaled > 0 {
@@ -195,6 +225,10 @@ pub struct KmerSignature {
     // Optional filename and path information
     pub filename: Option<String>,
     pub path: Option<PathBuf>,
+
+    // Hash function, canonicalization and software version the sketch was built with
+    #[serde(default = "HashingParameters::legacy")]
+    pub hashing: HashingParameters,
 }
 
 impl KmerSignature {
@@ -204,25 +238,42 @@ impl KmerSignature {
     }
 
     /// Calculates the Jaccard similarity between this KmerSignature and another.
-    /// Ensures that k-mer sizes and molecule types are compatible before comparing sketches.
+    /// Ensures that all sketching parameters are compatible before comparing sketches.
     ///
     /// # Arguments
     /// * `other` - Another KmerSignature to compare with.
     ///
     /// # Returns
-    /// The Jaccard similarity estimate (0.0 to 1.0) if comparable, otherwise None.
-    pub fn jaccard_similarity(&self, other: &KmerSignature) -> Option<f64> {
-        // Check context first
+    /// The Jaccard similarity estimate (0.0 to 1.0), or the parameter that
+    /// keeps the two sketches from being compared.
+    pub fn jaccard_similarity(&self, other: &KmerSignature) -> Result<f64, IncompatibleSketches> {
+        self.check_comparable(other)?;
+        self.sketch.check_compatible(&other.sketch)?;
+        self.sketch
+            .estimate_jaccard(&other.sketch)
+            .ok_or(IncompatibleSketches::Unsized)
+    }
+
+    /// Whether the k-mer sizes, molecule types and hashing allow comparing the sketches
+    pub fn is_comparable(&self, other: &KmerSignature) -> bool {
+        self.check_comparable(other).is_ok()
+    }
+
+    /// Like `is_comparable`, but says which parameter differs
+    pub fn check_comparable(&self, other: &KmerSignature) -> Result<(), IncompatibleSketches> {
         if self.kmer_size != other.kmer_size {
-            return None;
+            return Err(IncompatibleSketches::KmerSize(
+                self.kmer_size,
+                other.kmer_size,
+            ));
         }
-        // Molecule types should be compatible for meaningful comparison
         if !self.are_molecule_types_compatible(&other.molecule_type) {
-            return None;
+            return Err(IncompatibleSketches::MoleculeType(
+                self.molecule_type.clone(),
+                other.molecule_type.clone(),
+            ));
         }
-
-        // Delegate to the underlying Signature's jaccard_similarity
-        Some(self.sketch.estimate_jaccard(&other.sketch).unwrap_or(0.0))
+        self.hashing.check(&other.hashing)
     }
 
     /// Checks if molecule types are compatible for comparison
@@ -237,16 +288,40 @@ impl KmerSignature {
             || self.molecule_type.eq_ignore_ascii_case(other_type)
     }
 
+    /// Hashes of the k-mers of `sequence` as this signature's sketch stores
+    /// them, whether or not they would be kept in it; empty if the sequence
+    /// is shorter than the k-mer size
+    pub fn kmer_hashes(&self, sequence: &[u8]) -> Vec<u64> {
+        let use_canonical = self.hashing.canonical;
+        NtHashIterator::new(sequence, self.kmer_size)
+            .map(|hasher| {
+                hasher
+                    .map(|hash_value| sketch_hash(hash_value, use_canonical))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     /// Adds a sequence to the signature by processing its k-mers and updating the sketch.
-    /// Uses ntHash for hashing. If molecule_type is "DNA" or "RNA" (case-insensitive),
-    /// it processes canonical k-mer hashes.
+    /// Uses ntHash for hashing, with canonical k-mer hashes if the signature's
+    /// hashing parameters say so (the default for "DNA" and "RNA").
     ///
     /// Returns an error if the sequence is invalid, k-mer size is incompatible,
     /// or hashing/sketching fails.
     pub fn add_sequence(&mut self, sequence: &[u8]) -> Result<(), String> {
-        // Determine if we should use canonical k-mers
-        let use_canonical = self.molecule_type.eq_ignore_ascii_case("DNA")
-            || self.molecule_type.eq_ignore_ascii_case("RNA");
+        self.add_sequence_excluding(sequence, None)
+    }
+
+    /// Like `add_sequence`, but never keeps the hashes in `excluded`, so a
+    /// fixed-size sketch fills up with the next smallest hashes instead
+    pub fn add_sequence_excluding(
+        &mut self,
+        sequence: &[u8],
+        excluded: Option<&HashSet<u64>>,
+    ) -> Result<(), String> {
+        let is_excluded = |hash: &u64| excluded.is_some_and(|excluded| excluded.contains(hash));
+        // Canonical k-mers as recorded when the signature was built
+        let use_canonical = self.hashing.canonical;
 
         // Create ntHash Iterator
         let hasher = NtHashIterator::new(sequence, self.kmer_size)
@@ -258,13 +333,10 @@ impl KmerSignature {
             let mut heap = BinaryHeap::from(self.sketch.hashes.clone());
 
             for hash_value in hasher {
-                let canonical_hash = if use_canonical {
-                    // For DNA/RNA, hash both k-mer and its reverse complement, take the smaller value
-                    let rc_hash = hash_value.rotate_left(1); // Simple way to get a different hash for RC
-                    hash_value.min(rc_hash)
-                } else {
-                    hash_value
-                };
+                let canonical_hash = sketch_hash(hash_value, use_canonical);
+                if is_excluded(&canonical_hash) {
+                    continue;
+                }
 
                 if heap.len() < self.sketch.num_hashes {
                     heap.push(canonical_hash);
@@ -280,17 +352,13 @@ impl KmerSignature {
         } else if self.sketch.scaled > 0 {
             // Scaled MinHash: Keep all hashes below the threshold
             let threshold = u64::MAX / self.sketch.scaled;
-            let mut kept_hashes = HashSet::new();
+            // Keep the hashes of sequences added before
+            let mut kept_hashes: HashSet<u64> = self.sketch.hashes.iter().copied().collect();
 
             for hash_value in hasher {
-                let canonical_hash = if use_canonical {
-                    let rc_hash = hash_value.rotate_left(1);
-                    hash_value.min(rc_hash)
-                } else {
-                    hash_value
-                };
-
-                if canonical_hash < threshold {
+                let canonical_hash = sketch_hash(hash_value, use_canonical);
+
+                if canonical_hash < threshold && !is_excluded(&canonical_hash) {
                     kept_hashes.insert(canonical_hash);
                 }
             }
@@ -308,6 +376,17 @@ impl KmerSignature {
     }
 }
 
+/// Hash stored in a sketch for an ntHash value
+fn sketch_hash(hash_value: u64, use_canonical: bool) -> u64 {
+    if use_canonical {
+        // For DNA/RNA, hash both k-mer and its reverse complement, take the smaller value
+        let rc_hash = hash_value.rotate_left(1); // Simple way to get a different hash for RC
+        hash_value.min(rc_hash)
+    } else {
+        hash_value
+    }
+}
+
 // --- Multi Resolution Signature ---
 
 /// Resolution level for hierarchical sketches (Conceptual).
@@ -349,6 +428,46 @@ impl MultiResolutionSignature {
         }
     }
 
+    /// SHA-256 (hex) of the sketch contents: per level the k-mer size, algorithm,
+    /// sketch parameters and sorted hashes. Names, paths and lineage are ignored,
+    /// so the same genome added under different accessions hashes identically.
+    pub fn content_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        for level in &self.levels {
+            hasher.update((level.kmer_size as u64).to_le_bytes());
+            hasher.update(level.sketch.algorithm.as_bytes());
+            hasher.update([0u8]);
+            hasher.update((level.sketch.num_hashes as u64).to_le_bytes());
+            hasher.update(level.sketch.scaled.to_le_bytes());
+
+            let mut hashes = level.sketch.hashes.clone();
+            hashes.sort_unstable();
+            hasher.update((hashes.len() as u64).to_le_bytes());
+            for hash in hashes {
+                hasher.update(hash.to_le_bytes());
+            }
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    /// An empty signature with the levels of `layout`, ready for sequences
+    pub fn with_layout(taxon_id: String, lineage: Vec<String>, layout: &SignatureLayout) -> Self {
+        MultiResolutionSignature {
+            taxon_id,
+            lineage,
+            levels: layout
+                .levels
+                .iter()
+                .map(LevelParameters::empty_sketch)
+                .collect(),
+        }
+    }
+
+    /// Sketch parameters of the levels
+    pub fn layout(&self) -> SignatureLayout {
+        SignatureLayout::from(self)
+    }
+
     /// Adds a KmerSignature for a specific resolution level.
     /// Note: This simple version just adds to the Vec. A real implementation
     /// might associate it with a ResolutionLevel enum or ensure specific ordering.
@@ -356,10 +475,28 @@ impl MultiResolutionSignature {
         self.levels.push(signature);
     }
 
+    /// Whether every level both signatures have can be compared, and if not,
+    /// the first level that cannot and why
+    pub fn check_comparable(&self, other: &Self) -> Result<(), IncompatibleSketches> {
+        if self.levels.is_empty() || other.levels.is_empty() {
+            return Err(IncompatibleSketches::NoLevels);
+        }
+        for (i, (level, other_level)) in self.levels.iter().zip(&other.levels).enumerate() {
+            level
+                .check_comparable(other_level)
+                .map_err(|reason| reason.at_level(i))?;
+        }
+        Ok(())
+    }
+
     /// Calculate similarity between this signature and another
-    pub fn similarity(&self, other: &Self, weights: Option<Vec<f64>>) -> Option<f64> {
+    pub fn similarity(
+        &self,
+        other: &Self,
+        weights: Option<Vec<f64>>,
+    ) -> Result<f64, IncompatibleSketches> {
         if self.levels.is_empty() || other.levels.is_empty() {
-            return None;
+            return Err(IncompatibleSketches::NoLevels);
         }
 
         // Use equal weights if none provided
@@ -377,14 +514,203 @@ impl MultiResolutionSignature {
             .take(num_levels)
             .enumerate()
         {
-            if let Some(sim) = self_level.jaccard_similarity(other_level) {
-                total_similarity += weights[i] * sim;
-            } else {
-                return None; // Unable to compare signatures at this level
-            }
+            let sim = self_level
+                .jaccard_similarity(other_level)
+                .map_err(|reason| reason.at_level(i))?;
+            total_similarity += weights[i] * sim;
         }
 
-        Some(total_similarity)
+        Ok(total_similarity)
+    }
+}
+
+/// A level as stored before hashing parameters were recorded
+#[derive(Debug, Clone, Encode, Decode)]
+struct LegacyKmerSignature {
+    sketch: Signature,
+    kmer_size: usize,
+    molecule_type: String,
+    name: Option<String>,
+    filename: Option<String>,
+    path: Option<PathBuf>,
+}
+
+/// A signature as stored before hashing parameters were recorded, so older
+/// databases stay readable
+#[derive(Debug, Clone, Encode, Decode)]
+pub(crate) struct LegacyMultiResolutionSignature {
+    taxon_id: String,
+    lineage: Vec<String>,
+    levels: Vec<LegacyKmerSignature>,
+}
+
+impl From<LegacyMultiResolutionSignature> for MultiResolutionSignature {
+    fn from(legacy: LegacyMultiResolutionSignature) -> Self {
+        MultiResolutionSignature {
+            taxon_id: legacy.taxon_id,
+            lineage: legacy.lineage,
+            levels: legacy
+                .levels
+                .into_iter()
+                .map(|level| KmerSignature {
+                    sketch: level.sketch,
+                    kmer_size: level.kmer_size,
+                    molecule_type: level.molecule_type,
+                    name: level.name,
+                    filename: level.filename,
+                    path: level.path,
+                    hashing: HashingParameters::legacy(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// --- Signature Layout ---
+
+/// K-mer size of the coarsest (macro) level in the default layout
+pub const DEFAULT_MACRO_K: usize = 31;
+
+/// K-mer size of the meso level in the default layout
+pub const DEFAULT_MESO_K: usize = 21;
+
+/// Hashes kept per level in the default layout
+pub const DEFAULT_SKETCH_SIZE: usize = 1000;
+
+/// Sketch parameters of one resolution level
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct LevelParameters {
+    pub kmer_size: usize,
+    pub algorithm: String,
+    pub num_hashes: usize,
+    pub scaled: u64,
+    pub molecule_type: String,
+}
+
+impl LevelParameters {
+    /// Parameters of a sketch
+    pub fn of(signature: &KmerSignature) -> Self {
+        LevelParameters {
+            kmer_size: signature.kmer_size,
+            algorithm: signature.sketch.algorithm.clone(),
+            num_hashes: signature.sketch.num_hashes,
+            scaled: signature.sketch.scaled,
+            molecule_type: signature.molecule_type.clone(),
+        }
+    }
+
+    /// An empty sketch with these parameters
+    pub fn empty_sketch(&self) -> KmerSignature {
+        KmerSignatureBuilder::new(
+            self.kmer_size,
+            &self.molecule_type,
+            &self.algorithm,
+            self.num_hashes,
+            self.scaled,
+        )
+        .build()
+    }
+}
+
+impl fmt::Display for LevelParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "k={} {} num={} scaled={} {}",
+            self.kmer_size, self.algorithm, self.num_hashes, self.scaled, self.molecule_type
+        )
+    }
+}
+
+/// The sketch parameters of every resolution level of a signature, coarsest first.
+///
+/// References and samples are only comparable level by level when both were
+/// sketched with the same layout. The database builder and the sample
+/// processor both create their signatures from a layout, and a layout can be
+/// read back from any stored signature.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SignatureLayout {
+    pub levels: Vec<LevelParameters>,
+}
+
+impl Default for SignatureLayout {
+    fn default() -> Self {
+        SignatureLayout::minhash(&[DEFAULT_MACRO_K, DEFAULT_MESO_K], DEFAULT_SKETCH_SIZE)
+    }
+}
+
+impl From<&MultiResolutionSignature> for SignatureLayout {
+    fn from(signature: &MultiResolutionSignature) -> Self {
+        SignatureLayout {
+            levels: signature.levels.iter().map(LevelParameters::of).collect(),
+        }
+    }
+}
+
+impl SignatureLayout {
+    /// Fixed-size MinHash DNA sketches of `sketch_size` hashes, one level per k-mer size
+    pub fn minhash(kmer_sizes: &[usize], sketch_size: usize) -> Self {
+        SignatureLayout {
+            levels: kmer_sizes
+                .iter()
+                .map(|&kmer_size| LevelParameters {
+                    kmer_size,
+                    algorithm: "minhash".to_string(),
+                    num_hashes: sketch_size,
+                    scaled: 0,
+                    molecule_type: "DNA".to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The layout shared by most of `signatures`, `None` if there are none
+    pub fn dominant(signatures: &[MultiResolutionSignature]) -> Option<Self> {
+        let mut counts: HashMap<SignatureLayout, usize> = HashMap::new();
+        for signature in signatures {
+            *counts.entry(SignatureLayout::from(signature)).or_default() += 1;
+        }
+        // Ties go to the layout with more levels, then the larger k-mers
+        counts
+            .into_iter()
+            .max_by(|(a, a_count), (b, b_count)| {
+                a_count
+                    .cmp(b_count)
+                    .then_with(|| a.levels.len().cmp(&b.levels.len()))
+                    .then_with(|| a.levels.cmp(&b.levels))
+            })
+            .map(|(layout, _)| layout)
+    }
+
+    pub fn kmer_sizes(&self) -> Vec<usize> {
+        self.levels.iter().map(|level| level.kmer_size).collect()
+    }
+
+    /// Check the rules every stored signature must follow: at least one level,
+    /// k-mer sizes decreasing from 1 to 63, and each level either fixed-size
+    /// or scaled
+    pub fn validate(&self) -> Result<(), String> {
+        if self.levels.is_empty() {
+            return Err("Signature must have at least one resolution level".to_string());
+        }
+        if self
+            .levels
+            .windows(2)
+            .any(|pair| pair[0].kmer_size <= pair[1].kmer_size)
+        {
+            return Err("Resolution levels must have decreasing k-mer sizes".to_string());
+        }
+        for level in &self.levels {
+            if (level.num_hashes == 0) == (level.scaled == 0) {
+                return Err(
+                    "Each level must specify either num_hashes or scaled, but not both".to_string(),
+                );
+            }
+            if level.kmer_size == 0 || level.kmer_size > 63 {
+                return Err(format!("Invalid k-mer size: {}", level.kmer_size));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -434,8 +760,11 @@ impl KmerSignatureBuilder {
         self
     }
 
-    /// Builds the KmerSignature.
+    /// Builds the KmerSignature, recording the current hashing parameters.
+    /// Nucleotide k-mers are canonicalized.
     pub fn build(&self) -> KmerSignature {
+        let canonical = self.molecule_type.eq_ignore_ascii_case("DNA")
+            || self.molecule_type.eq_ignore_ascii_case("RNA");
         let mut signature = KmerSignature {
             sketch: Signature::new(self.algorithm.clone(), self.num_hashes, self.scaled),
             kmer_size: self.kmer_size,
@@ -447,6 +776,7 @@ impl KmerSignatureBuilder {
                 .and_then(|p| p.file_name())
                 .map(|n| n.to_string_lossy().into_owned()),
             path: self.path.clone(),
+            hashing: HashingParameters::current(canonical),
         };
         signature
     }
@@ -617,7 +947,7 @@ mod tests {
 
         // Test with default weights (equal weighting)
         let sim_default = mrs1.similarity(&mrs2, None);
-        assert!(sim_default.is_some());
+        assert!(sim_default.is_ok());
         // Expected similarity: (0.5 + 0.4) / 2 = 0.45
         // Level 1: 5 shared / 10 total = 0.5
         // Level 2: 2 shared / 5 total = 0.4
@@ -626,8 +956,146 @@ mod tests {
         // Test with custom weights
         let weights = vec![0.3, 0.7];
         let sim_custom = mrs1.similarity(&mrs2, Some(weights));
-        assert!(sim_custom.is_some());
+        assert!(sim_custom.is_ok());
         // Expected: (0.3 * 0.5) + (0.7 * 0.4) = 0.15 + 0.28 = 0.43
         assert!((sim_custom.unwrap() - 0.43).abs() < 1e-9);
     }
+
+    #[test]
+    fn test_content_hash_ignores_names_and_hash_order() {
+        let mut mrs1 = MultiResolutionSignature::new("GCF_1".to_string(), vec!["A".to_string()]);
+        let mut mrs2 = MultiResolutionSignature::new("GCA_1".to_string(), vec![]);
+        mrs1.add_level(create_test_kmer_sig("a", 21, 5, vec![1, 2, 3, 4, 5]));
+        mrs2.add_level(create_test_kmer_sig("b", 21, 5, vec![5, 4, 3, 2, 1]));
+        assert_eq!(mrs1.content_hash(), mrs2.content_hash());
+
+        let mut mrs3 = MultiResolutionSignature::new("GCF_2".to_string(), vec![]);
+        mrs3.add_level(create_test_kmer_sig("c", 31, 5, vec![1, 2, 3, 4, 5]));
+        assert_ne!(mrs1.content_hash(), mrs3.content_hash());
+    }
+
+    #[test]
+    fn test_layout_survives_serialization_and_rejects_invalid_levels() {
+        let layout = SignatureLayout::default();
+        assert!(layout.validate().is_ok());
+        assert_eq!(layout.kmer_sizes(), vec![DEFAULT_MACRO_K, DEFAULT_MESO_K]);
+
+        let mut signature =
+            MultiResolutionSignature::with_layout("GCF_1".to_string(), Vec::new(), &layout);
+        for level in &mut signature.levels {
+            level
+                .add_sequence(b"ACGTTGCATGCATGCAAGTCGATCGATCGGATCCGATAGCTAGC")
+                .unwrap();
+            assert!(!level.sketch.is_empty());
+        }
+        assert_eq!(signature.layout(), layout);
+
+        let json: MultiResolutionSignature =
+            serde_json::from_str(&serde_json::to_string(&signature).unwrap()).unwrap();
+        assert_eq!(json.layout(), layout);
+        let bytes = bincode::encode_to_vec(&signature, bincode::config::standard()).unwrap();
+        let (decoded, _): (MultiResolutionSignature, usize) =
+            bincode::decode_from_slice(&bytes, bincode::config::standard()).unwrap();
+        assert_eq!(decoded.layout(), layout);
+        assert_eq!(decoded.content_hash(), signature.content_hash());
+
+        let other = SignatureLayout::minhash(&[21, 15], 500);
+        let signatures = vec![
+            signature.clone(),
+            MultiResolutionSignature::with_layout("GCF_2".to_string(), Vec::new(), &other),
+            decoded,
+        ];
+        assert_eq!(SignatureLayout::dominant(&signatures), Some(layout));
+        assert_eq!(SignatureLayout::dominant(&[]), None);
+        // A tie goes to the layout with more levels, even with smaller k-mers
+        let single = SignatureLayout::minhash(&[31], 1000);
+        let tied = [
+            MultiResolutionSignature::with_layout("GCF_3".to_string(), Vec::new(), &single),
+            MultiResolutionSignature::with_layout("GCF_2".to_string(), Vec::new(), &other),
+        ];
+        assert_eq!(SignatureLayout::dominant(&tied), Some(other));
+
+        assert!(SignatureLayout::minhash(&[21, 31], 1000)
+            .validate()
+            .is_err());
+        assert!(SignatureLayout::minhash(&[31], 0).validate().is_err());
+        assert!(SignatureLayout::minhash(&[64], 1000).validate().is_err());
+        assert!(SignatureLayout { levels: Vec::new() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_hashing_parameters_recorded_and_checked() {
+        let sig1 = create_test_kmer_sig("sig1", 21, 5, vec![1, 2, 3, 4, 5]);
+        assert_eq!(sig1.hashing, HashingParameters::current(true));
+        assert_eq!(sig1.hashing.software_version, env!("CARGO_PKG_VERSION"));
+        let protein = KmerSignatureBuilder::new(7, "protein", "minhash", 5, 0).build();
+        assert!(!protein.hashing.canonical);
+
+        let other_k = create_test_kmer_sig("sig2", 31, 5, vec![1, 2, 3, 4, 5]);
+        assert_eq!(
+            sig1.jaccard_similarity(&other_k),
+            Err(IncompatibleSketches::KmerSize(21, 31))
+        );
+        let scaled = create_scaled_test_kmer_sig("sig3", 21, 1000, vec![1, 2]);
+        assert_eq!(
+            sig1.jaccard_similarity(&scaled).unwrap_err().to_string(),
+            "sketch algorithms differ (minhash vs scaled_minhash)"
+        );
+        let mut forward_only = sig1.clone();
+        forward_only.hashing.canonical = false;
+        assert_eq!(
+            sig1.jaccard_similarity(&forward_only),
+            Err(IncompatibleSketches::Canonicalization)
+        );
+        // Only the version differs: still comparable
+        let mut older = sig1.clone();
+        older.hashing.software_version = "0.0.1".to_string();
+        assert_eq!(sig1.jaccard_similarity(&older), Ok(1.0));
+
+        let mut mrs1 = MultiResolutionSignature::new("tax1".to_string(), vec![]);
+        mrs1.add_level(create_test_kmer_sig("a", 31, 5, vec![1, 2, 3]));
+        mrs1.add_level(sig1.clone());
+        let mut mrs2 = mrs1.clone();
+        mrs2.levels[1] = forward_only;
+        let err = mrs1.similarity(&mrs2, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "level 1: one sketch hashes canonical k-mers and the other does not"
+        );
+        assert_eq!(mrs1.check_comparable(&mrs2), Err(err));
+        assert_eq!(
+            mrs1.similarity(&MultiResolutionSignature::default(), None),
+            Err(IncompatibleSketches::NoLevels)
+        );
+
+        // Signatures stored before the parameters were recorded
+        let mut json = serde_json::to_value(&sig1).unwrap();
+        json.as_object_mut().unwrap().remove("hashing");
+        let from_json: KmerSignature = serde_json::from_value(json).unwrap();
+        assert_eq!(from_json.hashing, HashingParameters::legacy());
+        assert!(from_json.is_comparable(&sig1));
+
+        let legacy = LegacyMultiResolutionSignature {
+            taxon_id: "tax1".to_string(),
+            lineage: Vec::new(),
+            levels: vec![LegacyKmerSignature {
+                sketch: sig1.sketch.clone(),
+                kmer_size: 21,
+                molecule_type: "DNA".to_string(),
+                name: None,
+                filename: None,
+                path: None,
+            }],
+        };
+        let bytes = bincode::encode_to_vec(&legacy, bincode::config::standard()).unwrap();
+        let (decoded, _): (LegacyMultiResolutionSignature, usize) =
+            bincode::decode_from_slice(&bytes, bincode::config::standard()).unwrap();
+        let upgraded = MultiResolutionSignature::from(decoded);
+        assert_eq!(upgraded.levels[0].hashing, HashingParameters::legacy());
+        assert_eq!(upgraded.content_hash(), {
+            let mut current = MultiResolutionSignature::new("tax1".to_string(), vec![]);
+            current.add_level(sig1);
+            current.content_hash()
+        });
+    }
 }
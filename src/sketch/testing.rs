@@ -0,0 +1,39 @@
+//! Signatures built from literal hash sets, for unit tests across the crate.
+
+use crate::sketch::params::HashingParameters;
+use crate::sketch::signature::{KmerSignature, Signature};
+use crate::sketch::MultiResolutionSignature;
+
+/// A MinHash level of `kmer_size`-mers holding exactly `hashes`, sized for
+/// `num_hashes` hashes; with `num_hashes` 0, a scaled sketch that keeps every hash
+pub(crate) fn level(
+    kmer_size: usize,
+    num_hashes: usize,
+    hashes: impl IntoIterator<Item = u64>,
+) -> KmerSignature {
+    let scaled = u64::from(num_hashes == 0);
+    let mut sketch = Signature::new("minhash".to_string(), num_hashes, scaled);
+    sketch.hashes = hashes.into_iter().collect();
+    KmerSignature {
+        sketch,
+        kmer_size,
+        molecule_type: "DNA".to_string(),
+        name: None,
+        filename: None,
+        path: None,
+        hashing: HashingParameters::current(true),
+    }
+}
+
+/// A signature of `id` with `lineage` and `levels`
+pub(crate) fn signature(
+    id: &str,
+    lineage: &[&str],
+    levels: impl IntoIterator<Item = KmerSignature>,
+) -> MultiResolutionSignature {
+    MultiResolutionSignature {
+        taxon_id: id.to_string(),
+        lineage: lineage.iter().map(|name| name.to_string()).collect(),
+        levels: levels.into_iter().collect(),
+    }
+}
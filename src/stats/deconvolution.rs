@@ -1,12 +1,18 @@
 use nalgebra::ComplexField;
 use ndarray::{Array1, Array2};
 use rand::prelude::*;
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+use statrs::function::gamma::ln_gamma;
 // Import random libraries with feature flag
 #[cfg(feature = "random")]
 use rand_distr::{Dirichlet, Distribution};
 use std::collections::HashMap;
+use std::path::Path;
 use thiserror::Error;
 
+use crate::io::escape::csv_writer_builder;
+
 #[derive(Error, Debug)]
 pub enum BayesianError {
     #[error("Invalid dimensions: observed features {0} != signature features {1}")]
@@ -23,8 +29,93 @@ pub struct StrainAbundanceResult {
     pub effective_sample_size: f64,
     /// Model fit quality measure
     pub goodness_of_fit: f64,
+    /// Fraction of draws in which each strain has non-zero abundance
+    pub inclusion_probabilities: HashMap<String, f64>,
+    /// Strain IDs, in the order used by `draws`
+    pub strain_ids: Vec<String>,
+    /// Retained (post burn-in, thinned) posterior draws of the abundance vector
+    pub draws: Vec<Vec<f64>>,
+}
+
+/// How strain abundance posteriors are computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum InferenceMethod {
+    /// Metropolis-Hastings sampling (exact, slow)
+    #[default]
+    Mcmc,
+    /// Mean-field variational inference (approximate, fast)
+    Vi,
+}
+
+/// Study-level abundance of one strain from the hierarchical model
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StudyAbundance {
+    /// Posterior mean of the study-level mean abundance
+    pub mean: f64,
+    /// Lower bound of the 95% credible interval
+    pub lower: f64,
+    /// Upper bound of the 95% credible interval
+    pub upper: f64,
+}
+
+/// Results from the multi-sample hierarchical model
+pub struct HierarchicalAbundanceResult {
+    /// Per-sample results, in input order
+    pub samples: Vec<(String, StrainAbundanceResult)>,
+    /// Study-level mean abundance per strain
+    pub study_abundances: HashMap<String, StudyAbundance>,
+    /// Posterior mean of the Dirichlet concentration; larger values mean samples
+    /// sit closer to the study mean
+    pub concentration: f64,
 }
 
+/// MCMC iterations used when none are given
+pub const DEFAULT_MCMC_ITERATIONS: usize = 10000;
+
+/// Write the study-level abundances of a hierarchical fit to `study_path`
+/// and each sample's abundances to `samples_path`, as CSV sorted by strain
+pub fn write_hierarchical_abundances(
+    result: &HierarchicalAbundanceResult,
+    study_path: &Path,
+    samples_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv_writer_builder().from_path(study_path)?;
+    writer.write_record(["strain_id", "mean", "ci_lower", "ci_upper"])?;
+    let mut strains: Vec<&String> = result.study_abundances.keys().collect();
+    strains.sort();
+    for strain_id in &strains {
+        let abundance = result.study_abundances[*strain_id];
+        writer.write_record([
+            strain_id.to_string(),
+            abundance.mean.to_string(),
+            abundance.lower.to_string(),
+            abundance.upper.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+
+    let mut writer = csv_writer_builder().from_path(samples_path)?;
+    writer.write_record(["sample_id", "strain_id", "abundance", "interval_width"])?;
+    for (sample_id, sample) in &result.samples {
+        for strain_id in &strains {
+            if let Some((abundance, width)) = sample.abundances.get(*strain_id) {
+                writer.write_record([
+                    sample_id.clone(),
+                    strain_id.to_string(),
+                    abundance.to_string(),
+                    width.to_string(),
+                ])?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Floor applied to proposed abundances so Dirichlet densities stay finite
+const MIN_ABUNDANCE: f64 = 1e-8;
+
 /// Bayesian mixture model for strain deconvolution
 pub struct StrainMixtureModel {
     /// Number of strains in the model
@@ -41,6 +132,9 @@ pub struct StrainMixtureModel {
     mcmc_iterations: usize,
     mcmc_burnin: usize,
     mcmc_thin: usize,
+    /// Prior inclusion probability of each strain under the spike-and-slab prior
+    /// (`None` keeps every strain in the model)
+    spike_and_slab: Option<f64>,
     /// Random number generator
     rng: StdRng,
 }
@@ -80,7 +174,14 @@ impl StrainMixtureModel {
         };
 
         // Set MCMC parameters with reasonable defaults
-        let iterations = mcmc_iterations.unwrap_or(10000);
+        let iterations = mcmc_iterations.unwrap_or(DEFAULT_MCMC_ITERATIONS);
+        let burnin = iterations / 5; // 20% burnin
+        if iterations <= burnin {
+            return Err(format!(
+                "MCMC needs more iterations than its burn-in of {burnin} to keep any draws, got {iterations}"
+            )
+            .into());
+        }
 
         // Initialize RNG with provided seed or random seed
         #[cfg(feature = "random")]
@@ -98,13 +199,25 @@ impl StrainMixtureModel {
             strain_ids,
             abundance_prior: prior,
             mcmc_iterations: iterations,
-            mcmc_burnin: iterations / 5, // 20% burnin
-            mcmc_thin: 10,               // Keep every 10th sample
+            mcmc_burnin: burnin,
+            mcmc_thin: 10, // Keep every 10th sample
+            spike_and_slab: None,
             rng,
         })
     }
 
-    /// Estimate strain abundances from observed data using MCMC
+    /// Use a spike-and-slab prior in `estimate_abundances`: each strain is in the
+    /// mixture with prior probability `inclusion_prob`, so small values favour
+    /// explanations with few strains over spreading abundance across similar ones.
+    pub fn with_spike_and_slab(mut self, inclusion_prob: f64) -> Self {
+        self.spike_and_slab = Some(inclusion_prob.clamp(1e-6, 1.0 - 1e-6));
+        self
+    }
+
+    /// Estimate strain abundances from observed data using MCMC.
+    ///
+    /// With a spike-and-slab prior, each iteration also proposes adding or removing
+    /// one strain; removed strains have zero abundance until added back.
     pub fn estimate_abundances(
         &mut self,
         observed: &Array1<f64>,
@@ -128,6 +241,7 @@ impl StrainMixtureModel {
         // Initialize abundance vector (starting point for MCMC)
         let mut current_abundances =
             Array1::from_vec(vec![1.0 / self.n_strains as f64; self.n_strains]);
+        let mut included = vec![true; self.n_strains];
 
         // Storage for MCMC samples
         let samples_to_store = (self.mcmc_iterations - self.mcmc_burnin) / self.mcmc_thin;
@@ -141,10 +255,10 @@ impl StrainMixtureModel {
             let mut proposal = Vec::with_capacity(self.n_strains);
             let perturbation_scale = 0.1;
 
-            // Generate perturbed values
-            for a in &current_abundances {
+            // Generate perturbed values (excluded strains stay at zero)
+            for (a, in_model) in current_abundances.iter().zip(&included) {
                 let mut new_val = a + self.rng.gen_range(-perturbation_scale..perturbation_scale);
-                if new_val < 0.0 {
+                if new_val < 0.0 || !in_model {
                     new_val = 0.0;
                 }
                 proposal.push(new_val);
@@ -174,6 +288,15 @@ impl StrainMixtureModel {
                 current_abundances = proposal_abundances;
             }
 
+            if let Some(inclusion_prob) = self.spike_and_slab {
+                self.propose_inclusion_flip(
+                    &observed_norm,
+                    &mut current_abundances,
+                    &mut included,
+                    inclusion_prob,
+                );
+            }
+
             // Store samples after burn-in, applying thinning
             if iteration >= self.mcmc_burnin && (iteration - self.mcmc_burnin) % self.mcmc_thin == 0
             {
@@ -188,6 +311,321 @@ impl StrainMixtureModel {
         Ok(result)
     }
 
+    /// Spike-and-slab move: toggle one strain in or out of the mixture and accept
+    /// by likelihood ratio times prior odds. Removing the last strain is not allowed.
+    fn propose_inclusion_flip(
+        &mut self,
+        observed_norm: &Array1<f64>,
+        abundances: &mut Array1<f64>,
+        included: &mut [bool],
+        inclusion_prob: f64,
+    ) {
+        let j = self.rng.random_range(0..self.n_strains);
+        let adding = !included[j];
+        if !adding && included.iter().filter(|&&in_model| in_model).count() == 1 {
+            return;
+        }
+
+        let mut proposal = abundances.clone();
+        proposal[j] = if adding {
+            1.0 / self.n_strains as f64
+        } else {
+            0.0
+        };
+        let sum = proposal.sum();
+        proposal /= sum;
+
+        let prior_odds = (inclusion_prob / (1.0 - inclusion_prob)).ln();
+        let log_ratio = self.calculate_likelihood(observed_norm, &proposal)
+            - self.calculate_likelihood(observed_norm, abundances)
+            + if adding { prior_odds } else { -prior_odds };
+        if self.accept(log_ratio) {
+            *abundances = proposal;
+            included[j] = adding;
+        }
+    }
+
+    /// Estimate strain abundances jointly for several samples.
+    ///
+    /// Each sample's abundances are drawn from a Dirichlet with mean `mu` (the
+    /// study-level abundance, with `abundance_prior` as its Dirichlet prior) and
+    /// concentration `alpha` (exponential prior). Each sample's likelihood is
+    /// scaled by its total count, so shallow samples carry less evidence and are
+    /// pulled further towards the study mean. Sampling is Metropolis-within-Gibbs with
+    /// the same iteration, burn-in and thinning settings as `estimate_abundances`.
+    pub fn estimate_hierarchical(
+        &mut self,
+        samples: &[(String, Array1<f64>)],
+    ) -> Result<HierarchicalAbundanceResult, Box<dyn std::error::Error>> {
+        if samples.is_empty() {
+            return Err("Hierarchical estimation needs at least one sample".into());
+        }
+        for (_, observed) in samples {
+            if observed.len() != self.n_features {
+                return Err(Box::new(BayesianError::DimensionMismatch(
+                    observed.len(),
+                    self.n_features,
+                )));
+            }
+        }
+
+        let totals: Vec<f64> = samples.iter().map(|(_, observed)| observed.sum()).collect();
+        // Proportions (total 1) or empty samples get unit weight
+        let weights: Vec<f64> = totals.iter().map(|t| t.max(1.0)).collect();
+        let normalized: Vec<Array1<f64>> = samples
+            .iter()
+            .zip(&totals)
+            .map(|((_, observed), total)| {
+                if *total > 0.0 {
+                    observed / *total
+                } else {
+                    observed.clone()
+                }
+            })
+            .collect();
+
+        let uniform = Array1::from_elem(self.n_strains, 1.0 / self.n_strains as f64);
+        let mut thetas = vec![uniform.clone(); samples.len()];
+        let mut log_likelihoods: Vec<f64> = (0..samples.len())
+            .map(|s| weights[s] * self.calculate_likelihood(&normalized[s], &thetas[s]))
+            .collect();
+        let mut mu = uniform;
+        let mut log_alpha = (self.n_strains as f64).ln();
+        let alpha_rate = 0.01;
+
+        let mut sample_draws: Vec<Vec<Vec<f64>>> = vec![Vec::new(); samples.len()];
+        let mut sample_log_likelihoods: Vec<Vec<f64>> = vec![Vec::new(); samples.len()];
+        let mut mu_draws: Vec<Vec<f64>> = Vec::new();
+        let mut alpha_draws: Vec<f64> = Vec::new();
+
+        for iteration in 0..self.mcmc_iterations {
+            let alpha = log_alpha.exp();
+            let params: Vec<f64> = mu.iter().map(|m| alpha * m).collect();
+
+            // Per-sample abundances given the study-level prior
+            for s in 0..samples.len() {
+                let (proposal, log_jacobian) = self.perturb(&thetas[s], 0.5);
+                let proposal_ll = weights[s] * self.calculate_likelihood(&normalized[s], &proposal);
+                let log_ratio = proposal_ll - log_likelihoods[s]
+                    + dirichlet_log_density(&proposal, &params)
+                    - dirichlet_log_density(&thetas[s], &params)
+                    + log_jacobian;
+                if self.accept(log_ratio) {
+                    thetas[s] = proposal;
+                    log_likelihoods[s] = proposal_ll;
+                }
+            }
+
+            // Study-level mean abundance
+            let (mu_proposal, log_jacobian) = self.perturb(&mu, 0.25);
+            let proposal_params: Vec<f64> = mu_proposal.iter().map(|m| alpha * m).collect();
+            let log_ratio = thetas
+                .iter()
+                .map(|theta| {
+                    dirichlet_log_density(theta, &proposal_params)
+                        - dirichlet_log_density(theta, &params)
+                })
+                .sum::<f64>()
+                + dirichlet_log_density(&mu_proposal, &self.abundance_prior)
+                - dirichlet_log_density(&mu, &self.abundance_prior)
+                + log_jacobian;
+            if self.accept(log_ratio) {
+                mu = mu_proposal;
+            }
+
+            // Concentration, as a random walk on log(alpha)
+            let params: Vec<f64> = mu.iter().map(|m| alpha * m).collect();
+            let log_alpha_proposal = log_alpha + self.rng.random_range(-0.3..0.3);
+            let alpha_proposal = log_alpha_proposal.exp();
+            let proposal_params: Vec<f64> = mu.iter().map(|m| alpha_proposal * m).collect();
+            let log_ratio = thetas
+                .iter()
+                .map(|theta| {
+                    dirichlet_log_density(theta, &proposal_params)
+                        - dirichlet_log_density(theta, &params)
+                })
+                .sum::<f64>()
+                - alpha_rate * (alpha_proposal - alpha)
+                + (log_alpha_proposal - log_alpha);
+            if self.accept(log_ratio) {
+                log_alpha = log_alpha_proposal;
+            }
+
+            if iteration >= self.mcmc_burnin && (iteration - self.mcmc_burnin) % self.mcmc_thin == 0
+            {
+                for s in 0..samples.len() {
+                    sample_draws[s].push(thetas[s].to_vec());
+                    sample_log_likelihoods[s].push(log_likelihoods[s]);
+                }
+                mu_draws.push(mu.to_vec());
+                alpha_draws.push(log_alpha.exp());
+            }
+        }
+
+        let sample_results = samples
+            .iter()
+            .zip(sample_draws.into_iter().zip(sample_log_likelihoods))
+            .map(|((sample_id, _), (draws, log_likelihoods))| {
+                (
+                    sample_id.clone(),
+                    self.process_samples(draws, log_likelihoods),
+                )
+            })
+            .collect();
+
+        let mut study_abundances = HashMap::new();
+        for (i, strain_id) in self.strain_ids.iter().enumerate() {
+            let mut values: Vec<f64> = mu_draws.iter().map(|draw| draw[i]).collect();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let n = values.len();
+            study_abundances.insert(
+                strain_id.clone(),
+                StudyAbundance {
+                    mean: values.iter().sum::<f64>() / n as f64,
+                    lower: values[(0.025 * n as f64) as usize],
+                    upper: values[((0.975 * n as f64) as usize).min(n - 1)],
+                },
+            );
+        }
+
+        Ok(HierarchicalAbundanceResult {
+            samples: sample_results,
+            study_abundances,
+            concentration: alpha_draws.iter().sum::<f64>() / alpha_draws.len() as f64,
+        })
+    }
+
+    /// Estimate strain abundances with the chosen inference method
+    pub fn estimate(
+        &mut self,
+        observed: &Array1<f64>,
+        method: InferenceMethod,
+    ) -> Result<StrainAbundanceResult, Box<dyn std::error::Error>> {
+        match method {
+            InferenceMethod::Mcmc => self.estimate_abundances(observed),
+            InferenceMethod::Vi => self.estimate_abundances_vi(observed),
+        }
+    }
+
+    /// Estimate strain abundances with mean-field variational inference.
+    ///
+    /// Abundances are the softmax of a latent vector with a diagonal Gaussian
+    /// posterior, fitted by stochastic gradient ascent on the ELBO using the
+    /// reparameterization trick and Adam (ADVI). The Dirichlet prior is applied
+    /// in softmax coordinates. The result has the same form as the MCMC
+    /// estimator, with `draws` sampled from the fitted approximation; credible
+    /// intervals are typically somewhat narrower than MCMC's.
+    pub fn estimate_abundances_vi(
+        &mut self,
+        observed: &Array1<f64>,
+    ) -> Result<StrainAbundanceResult, Box<dyn std::error::Error>> {
+        if observed.len() != self.n_features {
+            return Err(Box::new(BayesianError::DimensionMismatch(
+                observed.len(),
+                self.n_features,
+            )));
+        }
+        let total = observed.sum();
+        let observed_norm = if total > 0.0 {
+            observed / total
+        } else {
+            observed.clone()
+        };
+
+        let k = self.n_strains;
+        let mut mean = Array1::<f64>::zeros(k);
+        let mut log_sd = Array1::<f64>::from_elem(k, -1.0);
+        let (learning_rate, beta1, beta2, eps) = (0.05, 0.9, 0.999, 1e-8);
+        let mut adam_m = Array1::<f64>::zeros(2 * k);
+        let mut adam_v = Array1::<f64>::zeros(2 * k);
+        // Broad Gaussian prior on the latent vector pins down softmax's shift invariance
+        let latent_prior_var = 100.0;
+
+        for step in 1..=self.vi_iterations() {
+            let noise: Array1<f64> = (0..k).map(|_| self.rng.sample(StandardNormal)).collect();
+            let sd = log_sd.mapv(f64::exp);
+            let z = &mean + &(&sd * &noise);
+            let theta = softmax(&z);
+
+            // d(log joint)/d(theta): Poisson likelihood plus Dirichlet prior
+            let expected = self.signatures.dot(&theta);
+            let ratio: Array1<f64> = observed_norm
+                .iter()
+                .zip(expected.iter())
+                .map(|(o, e)| if *e > 0.0 { o / e - 1.0 } else { -1.0 })
+                .collect();
+            let grad_theta = self.signatures.t().dot(&ratio);
+            // Chain rule through softmax, then the prior terms in softmax coordinates
+            let weighted = theta.dot(&grad_theta);
+            let alpha_sum: f64 = self.abundance_prior.iter().sum();
+            let grad_z: Array1<f64> = (0..k)
+                .map(|j| {
+                    theta[j] * (grad_theta[j] - weighted) + self.abundance_prior[j]
+                        - theta[j] * alpha_sum
+                        - z[j] / latent_prior_var
+                })
+                .collect();
+
+            // Reparameterization gradients; the entropy term adds 1 to each log-sd gradient
+            let mut grad = Array1::<f64>::zeros(2 * k);
+            for j in 0..k {
+                grad[j] = grad_z[j];
+                grad[k + j] = grad_z[j] * noise[j] * sd[j] + 1.0;
+            }
+
+            adam_m = beta1 * &adam_m + (1.0 - beta1) * &grad;
+            adam_v = beta2 * &adam_v + (1.0 - beta2) * &grad.mapv(|g| g * g);
+            let m_hat = &adam_m / (1.0 - beta1.powi(step as i32));
+            let v_hat = &adam_v / (1.0 - beta2.powi(step as i32));
+            for j in 0..k {
+                mean[j] += learning_rate * m_hat[j] / (v_hat[j].sqrt() + eps);
+                log_sd[j] += learning_rate * m_hat[k + j] / (v_hat[k + j].sqrt() + eps);
+            }
+        }
+
+        let n_draws = (self.mcmc_iterations - self.mcmc_burnin) / self.mcmc_thin;
+        let sd = log_sd.mapv(f64::exp);
+        let mut draws = Vec::with_capacity(n_draws.max(1));
+        let mut log_likelihoods = Vec::with_capacity(n_draws.max(1));
+        for _ in 0..n_draws.max(1) {
+            let noise: Array1<f64> = (0..k).map(|_| self.rng.sample(StandardNormal)).collect();
+            let theta = softmax(&(&mean + &(&sd * &noise)));
+            log_likelihoods.push(self.calculate_likelihood(&observed_norm, &theta));
+            draws.push(theta.to_vec());
+        }
+
+        Ok(self.process_samples(draws, log_likelihoods))
+    }
+
+    /// Optimization steps for variational inference, a tenth of the MCMC iterations
+    fn vi_iterations(&self) -> usize {
+        (self.mcmc_iterations / 10).max(500)
+    }
+
+    /// Symmetric random-walk proposal on the simplex, made in additive log-ratio
+    /// coordinates (each abundance relative to the last one).
+    ///
+    /// Returns the proposal and the log Jacobian ratio `sum(ln x') - sum(ln x)` of the
+    /// simplex-to-log-ratio map, which must be added to the acceptance log ratio for
+    /// the chain to target the density on the simplex.
+    fn perturb(&mut self, current: &Array1<f64>, scale: f64) -> (Array1<f64>, f64) {
+        let log_current = current.mapv(|x| x.max(MIN_ABUNDANCE).ln());
+        let reference = log_current[log_current.len() - 1];
+        let mut log_ratios = &log_current - reference;
+        let last = log_ratios.len() - 1;
+        for value in log_ratios.iter_mut().take(last) {
+            *value += self.rng.random_range(-scale..scale);
+        }
+        let proposal = softmax(&log_ratios);
+        let log_jacobian = proposal.mapv(|x| x.max(MIN_ABUNDANCE).ln()).sum() - log_current.sum();
+        (proposal, log_jacobian)
+    }
+
+    /// Metropolis-Hastings acceptance for a log acceptance ratio
+    fn accept(&mut self, log_ratio: f64) -> bool {
+        log_ratio >= 0.0 || self.rng.random_range(0.0..1.0f64).ln() < log_ratio
+    }
+
     /// Calculate log-likelihood of observed data given abundance parameters
     fn calculate_likelihood(&self, observed: &Array1<f64>, abundances: &Array1<f64>) -> f64 {
         // Generate expected profile by mixing strain signatures according to abundances
@@ -200,6 +638,9 @@ impl StrainMixtureModel {
             if *exp > 0.0 {
                 // Poisson log-likelihood (ignoring constants)
                 log_likelihood += obs * exp.ln() - *exp;
+            } else if *obs > 0.0 {
+                // Observed features no strain in the mixture can explain
+                return f64::NEG_INFINITY;
             }
         }
 
@@ -214,6 +655,7 @@ impl StrainMixtureModel {
     ) -> StrainAbundanceResult {
         let n_samples = abundance_samples.len();
         let mut abundances = HashMap::new();
+        let mut inclusion_probabilities = HashMap::new();
 
         // Calculate mean and 95% confidence interval for each strain
         for i in 0..self.n_strains {
@@ -235,6 +677,9 @@ impl StrainMixtureModel {
             let confidence_interval = strain_samples[upper_idx] - strain_samples[lower_idx];
 
             abundances.insert(strain_id.clone(), (mean_abundance, confidence_interval));
+
+            let nonzero = strain_samples.iter().filter(|&&a| a > 0.0).count();
+            inclusion_probabilities.insert(strain_id.clone(), nonzero as f64 / n_samples as f64);
         }
 
         // Calculate effective sample size (simplified)
@@ -248,10 +693,31 @@ impl StrainMixtureModel {
             abundances,
             effective_sample_size,
             goodness_of_fit,
+            inclusion_probabilities,
+            strain_ids: self.strain_ids.clone(),
+            draws: abundance_samples,
         }
     }
 }
 
+/// Numerically stable softmax
+fn softmax(z: &Array1<f64>) -> Array1<f64> {
+    let max = z.fold(f64::NEG_INFINITY, |a, b| a.max(*b));
+    let exp = z.mapv(|v| (v - max).exp());
+    let sum = exp.sum();
+    exp / sum
+}
+
+/// Log density of a Dirichlet distribution with parameters `alpha` at `x`
+fn dirichlet_log_density(x: &Array1<f64>, alpha: &[f64]) -> f64 {
+    let alpha_sum: f64 = alpha.iter().sum();
+    ln_gamma(alpha_sum)
+        + x.iter()
+            .zip(alpha)
+            .map(|(xi, ai)| (ai - 1.0) * xi.max(MIN_ABUNDANCE).ln() - ln_gamma(*ai))
+            .sum::<f64>()
+}
+
 /// Strain deconvolution algorithm for metagenomic samples
 ///
 /// This struct provides methods for estimating the relative abundance
@@ -269,6 +735,11 @@ pub struct StrainDeconvolution {
 
     /// Maximum iterations for optimization
     pub max_iterations: usize,
+
+    /// L1 penalty on abundances (0 disables). Abundances are renormalized after
+    /// every step, so the penalty acts as a threshold that drops weakly supported
+    /// strains instead of shrinking the total.
+    pub l1_penalty: f64,
 }
 
 impl StrainDeconvolution {
@@ -297,6 +768,7 @@ impl StrainDeconvolution {
             reference_ids,
             min_abundance: min_abundance.unwrap_or(0.01), // Default 1%
             max_iterations: max_iterations.unwrap_or(1000),
+            l1_penalty: 0.0,
         })
     }
 
@@ -342,8 +814,9 @@ impl StrainDeconvolution {
             let step_size = 0.01;
             abundances = &abundances + step_size * gradient;
 
-            // Project to non-negative values
+            // Proximal L1 step and projection to non-negative values
             for a in abundances.iter_mut() {
+                *a -= step_size * self.l1_penalty;
                 if *a < 0.0 {
                     *a = 0.0;
                 }
@@ -368,3 +841,146 @@ impl StrainDeconvolution {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{arr1, Array2};
+
+    #[test]
+    fn test_hierarchical_model_shares_strength_across_samples() {
+        let signatures = Array2::eye(2);
+        let strain_ids = vec!["A".to_string(), "B".to_string()];
+        let mut model =
+            StrainMixtureModel::new(signatures, strain_ids, None, Some(3000), Some(7)).unwrap();
+
+        let samples = vec![
+            ("deep1".to_string(), arr1(&[800.0, 200.0])),
+            ("deep2".to_string(), arr1(&[750.0, 250.0])),
+            ("shallow".to_string(), arr1(&[1.0, 1.0])),
+        ];
+        let result = model.estimate_hierarchical(&samples).unwrap();
+
+        assert_eq!(result.samples.len(), 3);
+        assert_eq!(result.samples[2].0, "shallow");
+        assert!(result.concentration > 0.0);
+
+        let study_a = result.study_abundances["A"];
+        assert!(study_a.lower <= study_a.mean && study_a.mean <= study_a.upper);
+        assert!(study_a.mean > 0.5);
+
+        // The shallow sample is pulled towards the study mean rather than staying at 50/50
+        let shallow_a = result.samples[2].1.abundances["A"].0;
+        assert!(shallow_a > 0.5);
+        assert!(!result.samples[0].1.draws.is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let study = dir.path().join("study.csv");
+        let per_sample = dir.path().join("samples.csv");
+        write_hierarchical_abundances(&result, &study, &per_sample).unwrap();
+        let study_rows: Vec<csv::StringRecord> = csv::Reader::from_path(&study)
+            .unwrap()
+            .records()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(study_rows.len(), 2);
+        assert_eq!(&study_rows[0][0], "A");
+        assert_eq!(
+            csv::Reader::from_path(&per_sample)
+                .unwrap()
+                .records()
+                .count(),
+            6
+        );
+    }
+
+    #[test]
+    fn test_variational_inference_matches_mcmc_point_estimate() {
+        let signatures = Array2::eye(3);
+        let strain_ids = vec!["A".to_string(), "B".to_string(), "C".to_string()];
+        let observed = arr1(&[70.0, 20.0, 10.0]);
+
+        let mut model =
+            StrainMixtureModel::new(signatures, strain_ids, None, Some(5000), Some(3)).unwrap();
+        let vi = model.estimate(&observed, InferenceMethod::Vi).unwrap();
+        let mcmc = model.estimate(&observed, InferenceMethod::Mcmc).unwrap();
+
+        assert!(!vi.draws.is_empty());
+        let (vi_a, vi_width) = vi.abundances["A"];
+        let (mcmc_a, _) = mcmc.abundances["A"];
+        assert!(vi_a > vi.abundances["B"].0 && vi_a > vi.abundances["C"].0);
+        assert!((vi_a - mcmc_a).abs() < 0.2);
+        assert!(vi_width > 0.0);
+    }
+
+    #[test]
+    fn test_spike_and_slab_drops_unsupported_strains() {
+        let strain_ids = vec!["A".to_string(), "B".to_string(), "C".to_string()];
+        let observed = arr1(&[50.0, 50.0, 0.0]);
+        let mut model =
+            StrainMixtureModel::new(Array2::eye(3), strain_ids, None, Some(5000), Some(11))
+                .unwrap()
+                .with_spike_and_slab(0.1);
+        let result = model.estimate_abundances(&observed).unwrap();
+
+        assert!(result.inclusion_probabilities["C"] < 0.5);
+        // A and B explain observed features, so removing either is never accepted
+        assert_eq!(result.inclusion_probabilities["A"], 1.0);
+        assert_eq!(result.inclusion_probabilities["B"], 1.0);
+    }
+
+    #[test]
+    fn test_l1_penalty_zeroes_unneeded_strains() {
+        let references = vec![arr1(&[1.0, 1.0, 0.0, 0.0]), arr1(&[0.0, 0.0, 1.0, 1.0])];
+        let ids = vec!["A".to_string(), "B".to_string()];
+        let profile = arr1(&[1.0, 1.0, 0.0, 0.0]);
+
+        let mut deconvolution =
+            StrainDeconvolution::new(references, ids, Some(0.0), Some(1000)).unwrap();
+        let plain = deconvolution.estimate_abundances(&profile);
+        assert!(plain["B"] > 0.0);
+
+        deconvolution.l1_penalty = 0.1;
+        let sparse = deconvolution.estimate_abundances(&profile);
+        assert_eq!(sparse["B"], 0.0);
+        assert!((sparse["A"] - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_model_rejects_too_few_iterations_for_any_draws() {
+        let build = |iterations| {
+            StrainMixtureModel::new(
+                Array2::eye(2),
+                vec!["A".to_string(), "B".to_string()],
+                None,
+                Some(iterations),
+                Some(1),
+            )
+        };
+        assert!(build(0).is_err());
+        assert!(!build(1)
+            .unwrap()
+            .estimate_hierarchical(&[("s".to_string(), arr1(&[1.0, 2.0]))])
+            .unwrap()
+            .samples[0]
+            .1
+            .draws
+            .is_empty());
+    }
+
+    #[test]
+    fn test_hierarchical_model_rejects_bad_dimensions() {
+        let mut model = StrainMixtureModel::new(
+            Array2::eye(2),
+            vec!["A".to_string(), "B".to_string()],
+            None,
+            Some(100),
+            Some(1),
+        )
+        .unwrap();
+        assert!(model.estimate_hierarchical(&[]).is_err());
+        assert!(model
+            .estimate_hierarchical(&[("s".to_string(), arr1(&[1.0, 2.0, 3.0]))])
+            .is_err());
+    }
+}
@@ -0,0 +1,305 @@
+//! Within-sample (alpha) and between-sample (beta) diversity.
+//!
+//! Within a sample, the Shannon index of the strain abundances and its
+//! effective number of strains summarize how evenly the classified group is
+//! split.
+//!
+//! Bray-Curtis treats every reference as equally different from every other,
+//! so two samples dominated by different strains of one species look as far
+//! apart as samples with nothing in common. UniFrac instead measures the
+//! branch length of a tree that the two samples do not share. Without a
+//! phylogeny of the references, their lineages serve as the tree: each rank
+//! is one unit-length branch below the rank above it, and each reference is
+//! one more branch below its deepest rank. References without a lineage hang
+//! directly off the root.
+//!
+//! * weighted UniFrac (normalized) weighs branches by the difference in the
+//!   share of each sample below them, and ranges from 0 to 1
+//! * unweighted UniFrac is the fraction of the branches present in either
+//!   sample that only one of them has
+//!
+//! The distance matrices are written as square CSVs that R reads with
+//! `as.dist()` for ordination or PERMANOVA.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::Result;
+use ndarray::{Array2, ArrayView1};
+use serde::{Deserialize, Serialize};
+
+use crate::count_table::CountTable;
+use crate::io::escape::csv_writer_builder;
+use crate::provenance::Provenance;
+
+/// Which UniFrac variant to compute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum UniFrac {
+    /// Abundance-weighted, normalized to 0-1
+    Weighted,
+    /// Presence/absence only
+    Unweighted,
+}
+
+impl fmt::Display for UniFrac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UniFrac::Weighted => write!(f, "weighted"),
+            UniFrac::Unweighted => write!(f, "unweighted"),
+        }
+    }
+}
+
+/// Tree built from the lineages of the features of a count table
+#[derive(Debug, Clone)]
+pub struct LineageTree {
+    /// Parent of each node; node 0 is the root
+    parents: Vec<usize>,
+    /// Length of the branch above each node (0 for the root)
+    lengths: Vec<f64>,
+    /// Node of each feature, in feature order
+    leaves: Vec<usize>,
+    /// Distance from the root to each feature's node, in feature order
+    depths: Vec<f64>,
+}
+
+impl LineageTree {
+    /// Tree over `features`, each placed below its lineage (domain first).
+    /// Empty trailing ranks are ignored.
+    pub fn from_lineages(features: &[String], lineages: &HashMap<String, Vec<String>>) -> Self {
+        let mut parents = vec![0];
+        let mut lengths = vec![0.0];
+        let mut depths_by_node = vec![0.0];
+        let mut children: HashMap<(usize, &str), usize> = HashMap::new();
+        let mut add_node = |parent: usize, parents: &mut Vec<usize>, lengths: &mut Vec<f64>| {
+            parents.push(parent);
+            lengths.push(1.0);
+            depths_by_node.push(depths_by_node[parent] + 1.0);
+            parents.len() - 1
+        };
+
+        let mut leaves = Vec::with_capacity(features.len());
+        for feature in features {
+            let lineage = lineages.get(feature).map(Vec::as_slice).unwrap_or_default();
+            let named = lineage
+                .iter()
+                .rposition(|rank| !rank.is_empty())
+                .map_or(0, |last| last + 1);
+            let mut node = 0;
+            for rank in &lineage[..named] {
+                node = match children.get(&(node, rank.as_str())) {
+                    Some(&child) => child,
+                    None => {
+                        let child = add_node(node, &mut parents, &mut lengths);
+                        children.insert((node, rank.as_str()), child);
+                        child
+                    }
+                };
+            }
+            leaves.push(add_node(node, &mut parents, &mut lengths));
+        }
+        let depths = leaves.iter().map(|&leaf| depths_by_node[leaf]).collect();
+        LineageTree {
+            parents,
+            lengths,
+            leaves,
+            depths,
+        }
+    }
+
+    /// Share of `abundances` (in feature order) below each node
+    fn branch_shares(&self, abundances: ArrayView1<f64>) -> Vec<f64> {
+        let total: f64 = abundances.sum();
+        let mut shares = vec![0.0; self.parents.len()];
+        if total <= 0.0 {
+            return shares;
+        }
+        for (&leaf, &abundance) in self.leaves.iter().zip(abundances) {
+            shares[leaf] += abundance / total;
+        }
+        // Children are always added after their parents
+        for node in (1..self.parents.len()).rev() {
+            shares[self.parents[node]] += shares[node];
+        }
+        shares
+    }
+
+    /// UniFrac distance between two samples' abundances, in feature order
+    pub fn distance(&self, a: ArrayView1<f64>, b: ArrayView1<f64>, kind: UniFrac) -> f64 {
+        self.distance_between(&self.branch_shares(a), &self.branch_shares(b), kind)
+    }
+
+    fn distance_between(&self, a: &[f64], b: &[f64], kind: UniFrac) -> f64 {
+        let (mut unshared, mut total) = (0.0, 0.0);
+        match kind {
+            UniFrac::Weighted => {
+                for node in 1..self.parents.len() {
+                    unshared += self.lengths[node] * (a[node] - b[node]).abs();
+                }
+                for (&leaf, depth) in self.leaves.iter().zip(&self.depths) {
+                    total += depth * (a[leaf] + b[leaf]);
+                }
+            }
+            UniFrac::Unweighted => {
+                for node in 1..self.parents.len() {
+                    let (in_a, in_b) = (a[node] > 0.0, b[node] > 0.0);
+                    if in_a || in_b {
+                        total += self.lengths[node];
+                    }
+                    if in_a != in_b {
+                        unshared += self.lengths[node];
+                    }
+                }
+            }
+        }
+        if total > 0.0 {
+            unshared / total
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Shannon index (natural log) of abundances; they need not sum to one, and
+/// zero or negative values are ignored
+pub fn shannon_index(abundances: impl IntoIterator<Item = f64>) -> f64 {
+    let abundances: Vec<f64> = abundances.into_iter().filter(|&a| a > 0.0).collect();
+    let total: f64 = abundances.iter().sum();
+    abundances
+        .iter()
+        .map(|a| a / total)
+        .map(|p| -p * p.ln())
+        .sum()
+}
+
+/// Symmetric matrix of distances between samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceMatrix {
+    pub samples: Vec<String>,
+    pub distances: Array2<f64>,
+}
+
+impl DistanceMatrix {
+    /// Write as a square CSV: a `sample_id` column, then one column per sample
+    pub fn write_csv(&self, provenance: Option<&Provenance>, path: &Path) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        if let Some(provenance) = provenance {
+            provenance.write_header(&mut file)?;
+        }
+        let mut writer = csv_writer_builder().from_writer(file);
+        let mut header = vec!["sample_id".to_string()];
+        header.extend(self.samples.iter().cloned());
+        writer.write_record(&header)?;
+        for (sample, row) in self.samples.iter().zip(self.distances.rows()) {
+            let mut record = vec![sample.clone()];
+            record.extend(row.iter().map(|d| d.to_string()));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// UniFrac distances between all samples of `table`, using the lineages of
+/// its features as the tree
+pub fn unifrac_matrix(
+    table: &CountTable,
+    lineages: &HashMap<String, Vec<String>>,
+    kind: UniFrac,
+) -> DistanceMatrix {
+    let tree = LineageTree::from_lineages(table.feature_names(), lineages);
+    let counts = table.counts_matrix();
+    let shares: Vec<Vec<f64>> = counts
+        .columns()
+        .into_iter()
+        .map(|column| tree.branch_shares(column))
+        .collect();
+    let n = shares.len();
+    let mut distances = Array2::zeros((n, n));
+    for i in 0..n {
+        for j in (i + 1)..n {
+            let distance = tree.distance_between(&shares[i], &shares[j], kind);
+            distances[[i, j]] = distance;
+            distances[[j, i]] = distance;
+        }
+    }
+    DistanceMatrix {
+        samples: table.sample_names().clone(),
+        distances,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_unifrac_follows_lineages() {
+        let family = [
+            "Bacteria",
+            "Pseudomonadota",
+            "Gammaproteobacteria",
+            "Enterobacterales",
+            "Enterobacteriaceae",
+        ];
+        let lineage = |genus: &str, species: &str| {
+            family
+                .iter()
+                .chain(&[genus, species])
+                .map(|rank| rank.to_string())
+                .collect::<Vec<_>>()
+        };
+        let lineages = HashMap::from([
+            (
+                "ecoli_A".to_string(),
+                lineage("Escherichia", "Escherichia coli"),
+            ),
+            (
+                "ecoli_B".to_string(),
+                lineage("Escherichia", "Escherichia coli"),
+            ),
+            (
+                "salmonella".to_string(),
+                lineage("Salmonella", "Salmonella enterica"),
+            ),
+        ]);
+        let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+        for (sample, feature) in [("S1", "ecoli_A"), ("S2", "ecoli_B"), ("S3", "salmonella")] {
+            data.insert(
+                sample.to_string(),
+                HashMap::from([(feature.to_string(), 10.0)]),
+            );
+        }
+        data.insert(
+            "S4".to_string(),
+            HashMap::from([("ecoli_A".to_string(), 30.0)]),
+        );
+        let table = CountTable::build_from_data(&data).unwrap();
+
+        // Strains of one species differ only in their own branches
+        let weighted = unifrac_matrix(&table, &lineages, UniFrac::Weighted);
+        assert_eq!(weighted.samples, vec!["S1", "S2", "S3", "S4"]);
+        assert_relative_eq!(weighted.distances[[0, 1]], 2.0 / 16.0);
+        assert_relative_eq!(weighted.distances[[0, 2]], 6.0 / 16.0);
+        assert_relative_eq!(weighted.distances[[2, 0]], 6.0 / 16.0);
+        assert_eq!(weighted.distances[[0, 3]], 0.0);
+
+        let unweighted = unifrac_matrix(&table, &lineages, UniFrac::Unweighted);
+        assert_relative_eq!(unweighted.distances[[0, 1]], 2.0 / 9.0);
+        assert_relative_eq!(unweighted.distances[[0, 2]], 6.0 / 11.0);
+
+        assert_eq!(shannon_index([10.0]), 0.0);
+        assert_relative_eq!(shannon_index([1.0, 1.0, 0.0]).exp(), 2.0);
+        assert_eq!(shannon_index([]), 0.0);
+
+        // Without lineages every reference is equally far from every other
+        let flat = unifrac_matrix(&table, &HashMap::new(), UniFrac::Unweighted);
+        assert_eq!(flat.distances[[0, 1]], 1.0);
+        assert_eq!(flat.distances[[0, 2]], 1.0);
+    }
+}
@@ -0,0 +1,378 @@
+//! Feature matrices for strain deconvolution built from sketches.
+//!
+//! `StrainMixtureModel` works on a features x strains matrix and an observed
+//! feature vector. Here the features are sketch hashes from one resolution
+//! level: every hash that a candidate strain contributes becomes a row, a
+//! strain's column marks the hashes in its sketch, and the observed vector
+//! marks the hashes present in the sample sketch.
+//!
+//! Fixed-size (bottom-k) sketches only describe hashes below their largest
+//! retained value, so a hash missing from a full sketch is not evidence of
+//! absence above that point. The universe is therefore cut at the smallest
+//! such bound among the sample and the candidates.
+//!
+//! Several samples fitted jointly need the same rows. `shared_feature_matrix`
+//! builds each sample's matrix at the same level and keeps the rows that all
+//! of them cover: the universe of each is the sorted strain hashes up to its
+//! bound, so the shortest one is a prefix of the others.
+
+use std::collections::{BTreeSet, HashMap};
+
+use ndarray::{Array1, Array2};
+use thiserror::Error;
+
+use crate::sketch::signature::Signature;
+use crate::sketch::MultiResolutionSignature;
+
+/// Strain ID of the background column added by `SampleOnlyHashes::Background`
+pub const BACKGROUND_STRAIN_ID: &str = "unassigned";
+
+#[derive(Error, Debug)]
+pub enum FeatureMatrixError {
+    #[error("No candidate strains given")]
+    NoStrains,
+
+    #[error("No samples given")]
+    NoSamples,
+
+    #[error("No sketch level is shared by the sample and all candidate strains")]
+    NoSharedLevel,
+
+    #[error("Level {0} is not present in every sketch (shared levels: {1})")]
+    LevelOutOfRange(usize, usize),
+
+    #[error("Sketch of {0} at level {1} uses k={2}, but the sample uses k={3}")]
+    KmerSizeMismatch(String, usize, usize, usize),
+}
+
+/// Value written for a hash in a strain's column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeatureWeighting {
+    /// 1 for every hash in the strain's sketch
+    Binary,
+    /// 1 / (number of the strain's hashes in the universe), so each column sums
+    /// to one and large genomes do not dominate the mixture
+    #[default]
+    SketchFraction,
+}
+
+/// What to do with sample hashes that no candidate strain contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleOnlyHashes {
+    /// Leave them out of the universe; they are only counted
+    #[default]
+    Ignore,
+    /// Add them as features covered by an extra `unassigned` strain column, so
+    /// the share of the sample the references cannot explain gets its own
+    /// abundance instead of inflating the candidates'
+    Background,
+}
+
+/// Signature matrix and observed vector ready for deconvolution
+#[derive(Debug, Clone)]
+pub struct FeatureMatrix {
+    /// Features x strains
+    pub signatures: Array2<f64>,
+    /// 1 for features present in the sample, 0 otherwise
+    pub observed: Array1<f64>,
+    /// Column labels of `signatures`
+    pub strain_ids: Vec<String>,
+    /// Row labels of `signatures`
+    pub hashes: Vec<u64>,
+    /// Sketch level the features were taken from
+    pub level: usize,
+    /// Sample hashes within the universe bound that no candidate contains
+    pub sample_only_hashes: usize,
+}
+
+impl FeatureMatrix {
+    /// Fraction of the sample's in-universe hashes that no candidate explains
+    pub fn unexplained_fraction(&self) -> f64 {
+        let observed = match self.strain_ids.last() {
+            Some(id) if id == BACKGROUND_STRAIN_ID => self.observed.sum(),
+            _ => self.observed.sum() + self.sample_only_hashes as f64,
+        };
+        if observed > 0.0 {
+            self.sample_only_hashes as f64 / observed
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Builds a `FeatureMatrix` from a sample sketch and candidate strain sketches
+#[derive(Debug, Clone)]
+pub struct FeatureMatrixBuilder<'a> {
+    sample: &'a MultiResolutionSignature,
+    strains: Vec<&'a MultiResolutionSignature>,
+    level: Option<usize>,
+    weighting: FeatureWeighting,
+    sample_only: SampleOnlyHashes,
+}
+
+impl<'a> FeatureMatrixBuilder<'a> {
+    /// Start a builder; by default the finest level shared by all sketches is used
+    pub fn new(
+        sample: &'a MultiResolutionSignature,
+        strains: &[&'a MultiResolutionSignature],
+    ) -> Self {
+        FeatureMatrixBuilder {
+            sample,
+            strains: strains.to_vec(),
+            level: None,
+            weighting: FeatureWeighting::default(),
+            sample_only: SampleOnlyHashes::default(),
+        }
+    }
+
+    /// Take features from a specific sketch level
+    pub fn level(mut self, level: usize) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Set how strain columns are weighted
+    pub fn weighting(mut self, weighting: FeatureWeighting) -> Self {
+        self.weighting = weighting;
+        self
+    }
+
+    /// Set how hashes found only in the sample are handled
+    pub fn sample_only_hashes(mut self, handling: SampleOnlyHashes) -> Self {
+        self.sample_only = handling;
+        self
+    }
+
+    /// Build the matrix
+    pub fn build(&self) -> Result<FeatureMatrix, FeatureMatrixError> {
+        if self.strains.is_empty() {
+            return Err(FeatureMatrixError::NoStrains);
+        }
+        let shared_levels = self
+            .strains
+            .iter()
+            .map(|s| s.levels.len())
+            .min()
+            .unwrap_or(0)
+            .min(self.sample.levels.len());
+        let level = match self.level {
+            Some(level) if level >= shared_levels => {
+                return Err(FeatureMatrixError::LevelOutOfRange(level, shared_levels))
+            }
+            Some(level) => level,
+            None if shared_levels == 0 => return Err(FeatureMatrixError::NoSharedLevel),
+            None => shared_levels - 1,
+        };
+
+        let sample_level = &self.sample.levels[level];
+        for strain in &self.strains {
+            let k = strain.levels[level].kmer_size;
+            if k != sample_level.kmer_size {
+                return Err(FeatureMatrixError::KmerSizeMismatch(
+                    strain.taxon_id.clone(),
+                    level,
+                    k,
+                    sample_level.kmer_size,
+                ));
+            }
+        }
+
+        let bound = std::iter::once(&sample_level.sketch)
+            .chain(self.strains.iter().map(|s| &s.levels[level].sketch))
+            .filter_map(saturated_bound)
+            .min()
+            .unwrap_or(u64::MAX);
+        let in_universe = |hash: &&u64| **hash <= bound;
+
+        let strain_hashes: BTreeSet<u64> = self
+            .strains
+            .iter()
+            .flat_map(|s| s.levels[level].sketch.hashes.iter().filter(in_universe))
+            .copied()
+            .collect();
+        let sample_hashes: BTreeSet<u64> = sample_level
+            .sketch
+            .hashes
+            .iter()
+            .filter(in_universe)
+            .copied()
+            .collect();
+        let sample_only: Vec<u64> = sample_hashes.difference(&strain_hashes).copied().collect();
+        let background =
+            self.sample_only == SampleOnlyHashes::Background && !sample_only.is_empty();
+
+        let mut hashes: Vec<u64> = strain_hashes.into_iter().collect();
+        if background {
+            hashes.extend(&sample_only);
+        }
+        let row_of: HashMap<u64, usize> = hashes.iter().enumerate().map(|(i, &h)| (h, i)).collect();
+
+        let n_columns = self.strains.len() + usize::from(background);
+        let mut signatures = Array2::<f64>::zeros((hashes.len(), n_columns));
+        for (j, strain) in self.strains.iter().enumerate() {
+            let rows: Vec<usize> = strain.levels[level]
+                .sketch
+                .hashes
+                .iter()
+                .filter(in_universe)
+                .map(|h| row_of[h])
+                .collect();
+            let weight = match self.weighting {
+                FeatureWeighting::Binary => 1.0,
+                FeatureWeighting::SketchFraction => 1.0 / rows.len().max(1) as f64,
+            };
+            for row in rows {
+                signatures[[row, j]] = weight;
+            }
+        }
+        let mut strain_ids: Vec<String> = self.strains.iter().map(|s| s.taxon_id.clone()).collect();
+        if background {
+            let weight = match self.weighting {
+                FeatureWeighting::Binary => 1.0,
+                FeatureWeighting::SketchFraction => 1.0 / sample_only.len() as f64,
+            };
+            for hash in &sample_only {
+                signatures[[row_of[hash], n_columns - 1]] = weight;
+            }
+            strain_ids.push(BACKGROUND_STRAIN_ID.to_string());
+        }
+
+        let mut observed = Array1::<f64>::zeros(hashes.len());
+        for hash in &sample_hashes {
+            if let Some(&row) = row_of.get(hash) {
+                observed[row] = 1.0;
+            }
+        }
+
+        Ok(FeatureMatrix {
+            signatures,
+            observed,
+            strain_ids,
+            hashes,
+            level,
+            sample_only_hashes: sample_only.len(),
+        })
+    }
+}
+
+/// Feature matrix of `strains` with rows shared by all `samples`, and each
+/// sample's observed vector over those rows, in input order
+pub fn shared_feature_matrix(
+    samples: &[&MultiResolutionSignature],
+    strains: &[&MultiResolutionSignature],
+) -> Result<(FeatureMatrix, Vec<Array1<f64>>), FeatureMatrixError> {
+    if samples.is_empty() {
+        return Err(FeatureMatrixError::NoSamples);
+    }
+    if strains.is_empty() {
+        return Err(FeatureMatrixError::NoStrains);
+    }
+    let shared_levels = samples
+        .iter()
+        .chain(strains)
+        .map(|s| s.levels.len())
+        .min()
+        .unwrap_or(0);
+    if shared_levels == 0 {
+        return Err(FeatureMatrixError::NoSharedLevel);
+    }
+    let matrices = samples
+        .iter()
+        .map(|sample| {
+            FeatureMatrixBuilder::new(sample, strains)
+                .level(shared_levels - 1)
+                .build()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let shared = matrices
+        .iter()
+        .min_by_key(|matrix| matrix.hashes.len())
+        .cloned()
+        .expect("at least one sample");
+    let n_rows = shared.hashes.len();
+    let observed = matrices
+        .iter()
+        .map(|matrix| matrix.observed.iter().take(n_rows).copied().collect())
+        .collect();
+    Ok((shared, observed))
+}
+
+/// Largest hash a full fixed-size sketch can vouch for; `None` when every hash
+/// of the input is represented (scaled sketches, or bottom-k sketches that did
+/// not fill up)
+fn saturated_bound(sketch: &Signature) -> Option<u64> {
+    if sketch.scaled == 0 && sketch.num_hashes > 0 && sketch.hashes.len() >= sketch.num_hashes {
+        sketch.hashes.iter().max().copied()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+
+    #[test]
+    fn test_builds_weighted_matrix_and_observed_vector() {
+        let a = signature("A", &[], [level(21, 10, [1, 2, 3, 4])]);
+        let b = signature("B", &[], [level(21, 10, [3, 4, 5, 6])]);
+        let sample = signature("sample", &[], [level(21, 10, [1, 2, 3, 9])]);
+
+        let matrix = FeatureMatrixBuilder::new(&sample, &[&a, &b])
+            .build()
+            .unwrap();
+        assert_eq!(matrix.hashes, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(matrix.strain_ids, vec!["A", "B"]);
+        assert_eq!(matrix.signatures.column(0).sum(), 1.0);
+        assert_eq!(matrix.signatures[[0, 1]], 0.0);
+        assert_eq!(matrix.observed.to_vec(), vec![1.0, 1.0, 1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(matrix.sample_only_hashes, 1);
+        assert!((matrix.unexplained_fraction() - 0.25).abs() < 1e-12);
+
+        let binary = FeatureMatrixBuilder::new(&sample, &[&a, &b])
+            .weighting(FeatureWeighting::Binary)
+            .sample_only_hashes(SampleOnlyHashes::Background)
+            .build()
+            .unwrap();
+        assert_eq!(binary.strain_ids.last().unwrap(), BACKGROUND_STRAIN_ID);
+        assert_eq!(binary.hashes.last(), Some(&9));
+        assert_eq!(binary.signatures[[6, 2]], 1.0);
+        assert_eq!(binary.signatures[[0, 0]], 1.0);
+        assert!((binary.unexplained_fraction() - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_full_bottom_k_sketches_bound_the_universe() {
+        // The sample sketch is full at 3 hashes, so it says nothing about hashes above 5
+        let a = signature("A", &[], [level(21, 10, [1, 5, 8, 20])]);
+        let sample = signature("sample", &[], [level(21, 3, [1, 4, 5])]);
+
+        let matrix = FeatureMatrixBuilder::new(&sample, &[&a]).build().unwrap();
+        assert_eq!(matrix.hashes, vec![1, 5]);
+        assert_eq!(matrix.sample_only_hashes, 1);
+        assert!(FeatureMatrixBuilder::new(&sample, &[&a])
+            .level(1)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_shared_matrix_keeps_rows_every_sample_covers() {
+        let a = signature("A", &[], [level(21, 10, [1, 5, 8, 20])]);
+        let b = signature("B", &[], [level(21, 10, [2, 6, 9, 30])]);
+        // s1 is full at 3 hashes and bounds the universe at 6
+        let s1 = signature("s1", &[], [level(21, 3, [1, 2, 6])]);
+        let s2 = signature("s2", &[], [level(21, 10, [5, 8, 20])]);
+
+        let (matrix, observed) = shared_feature_matrix(&[&s1, &s2], &[&a, &b]).unwrap();
+        assert_eq!(matrix.hashes, vec![1, 2, 5, 6]);
+        assert_eq!(observed[0].to_vec(), vec![1.0, 1.0, 0.0, 1.0]);
+        assert_eq!(observed[1].to_vec(), vec![0.0, 0.0, 1.0, 0.0]);
+        assert!(matches!(
+            shared_feature_matrix(&[], &[&a]),
+            Err(FeatureMatrixError::NoSamples)
+        ));
+    }
+}
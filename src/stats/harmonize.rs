@@ -0,0 +1,269 @@
+//! Harmonizing count tables built against different database versions.
+//!
+//! Reference databases change between releases: assemblies get new accession
+//! versions, taxids are merged into others, and references are withdrawn.
+//! Count tables from samples classified against different releases name the
+//! same organism differently, and merging them as they are splits one taxon
+//! over several rows. An equivalence table (`from<TAB>to`, an old accession or
+//! taxid and its current one) maps every feature into one common feature
+//! space before the tables are merged. An accession missing from the table is
+//! matched to a listed accession that differs only in its version. Features
+//! that still cannot be mapped are reported, and either kept under their own
+//! ID or dropped. The input tables are held at a `CountPrecision` of the
+//! caller's choosing while they are merged, so a large study can be held as f32
+//! or u32 rather than f64.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::count_table::{CompactCountTable, CountTable};
+
+/// Where a feature goes in the common feature space
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeatureMapping {
+    /// The feature's ID in the common space (possibly unchanged)
+    To(String),
+    /// Listed without a current ID: the reference was withdrawn
+    Withdrawn,
+    /// Not in the equivalence table
+    Unknown,
+}
+
+/// Equivalences between feature IDs of older database versions and the current one
+#[derive(Debug, Clone, Default)]
+pub struct FeatureEquivalences {
+    /// Old ID to current ID, `None` for withdrawn references
+    map: HashMap<String, Option<String>>,
+    /// IDs of the common feature space
+    targets: HashSet<String>,
+    /// Old and current IDs by accession without version
+    by_base: HashMap<String, Option<String>>,
+}
+
+impl FeatureEquivalences {
+    /// Read a two-column table (tab- or comma-separated) of old and current IDs.
+    /// `#` lines and a `from`/`to` header are skipped; an empty or `-` current ID
+    /// marks a withdrawn reference.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read equivalence table {}", path.display()))?;
+        let mut equivalences = FeatureEquivalences::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(|c| c == '\t' || c == ',').map(str::trim);
+            let from = fields.next().unwrap_or("");
+            let to = fields.next().ok_or_else(|| {
+                anyhow!(
+                    "{} line {}: expected two columns (old ID, current ID)",
+                    path.display(),
+                    number + 1
+                )
+            })?;
+            if number == 0 && from.eq_ignore_ascii_case("from") {
+                continue;
+            }
+            let to = (!to.is_empty() && to != "-").then_some(to);
+            equivalences
+                .insert(from, to)
+                .with_context(|| format!("{} line {}", path.display(), number + 1))?;
+        }
+        Ok(equivalences)
+    }
+
+    /// Record that `from` is now `to` (`None` if withdrawn); a conflicting
+    /// earlier entry for `from` is an error
+    pub fn insert(&mut self, from: &str, to: Option<&str>) -> Result<()> {
+        let to = to.map(str::to_string);
+        if let Some(existing) = self.map.get(from) {
+            if *existing != to {
+                bail!(
+                    "{} is mapped to both {} and {}",
+                    from,
+                    existing.as_deref().unwrap_or("-"),
+                    to.as_deref().unwrap_or("-")
+                );
+            }
+            return Ok(());
+        }
+        if let Some(target) = &to {
+            self.targets.insert(target.clone());
+            self.by_base
+                .insert(accession_base(target).to_string(), to.clone());
+        }
+        self.by_base
+            .entry(accession_base(from).to_string())
+            .or_insert_with(|| to.clone());
+        self.map.insert(from.to_string(), to);
+        Ok(())
+    }
+
+    /// Where `feature` belongs in the common feature space
+    pub fn resolve(&self, feature: &str) -> FeatureMapping {
+        let found = match self.map.get(feature) {
+            Some(to) => Some(to),
+            None if self.targets.contains(feature) => return FeatureMapping::To(feature.into()),
+            None => self.by_base.get(accession_base(feature)),
+        };
+        match found {
+            Some(Some(to)) => FeatureMapping::To(to.clone()),
+            Some(None) => FeatureMapping::Withdrawn,
+            None => FeatureMapping::Unknown,
+        }
+    }
+}
+
+/// How the features of the merged tables were mapped
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HarmonizationReport {
+    /// Features whose ID changed, with their ID in the common space
+    pub renamed: BTreeMap<String, String>,
+    /// Features with no ID in the common space and their total count
+    pub unmapped: BTreeMap<String, f64>,
+    /// Whether unmapped features were kept under their own ID
+    pub kept_unmapped: bool,
+}
+
+/// Map the features of `tables` into the common feature space and merge them
+/// into one table. Counts of features mapped to the same ID are added.
+/// Unmapped features are kept under their own ID if `keep_unmapped`, dropped
+/// otherwise. Sample names must be unique across the tables.
+pub fn harmonize_tables(
+    tables: &[CompactCountTable],
+    equivalences: &FeatureEquivalences,
+    keep_unmapped: bool,
+) -> Result<(CountTable, HarmonizationReport)> {
+    let mut report = HarmonizationReport {
+        kept_unmapped: keep_unmapped,
+        ..HarmonizationReport::default()
+    };
+    let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+    for table in tables {
+        let n_samples = table.sample_names.len();
+        for sample in &table.sample_names {
+            if data.insert(sample.clone(), HashMap::new()).is_some() {
+                bail!("Sample {} appears in more than one table", sample);
+            }
+        }
+        for (row, feature) in table.feature_names.iter().enumerate() {
+            let target = match equivalences.resolve(feature) {
+                FeatureMapping::To(target) => {
+                    if target != *feature {
+                        report.renamed.insert(feature.clone(), target.clone());
+                    }
+                    Some(target)
+                }
+                FeatureMapping::Withdrawn | FeatureMapping::Unknown => {
+                    *report.unmapped.entry(feature.clone()).or_default() += (0..n_samples)
+                        .filter_map(|column| table.counts.get(row, column))
+                        .sum::<f64>();
+                    keep_unmapped.then(|| feature.clone())
+                }
+            };
+            let Some(target) = target else {
+                continue;
+            };
+            for (column, sample) in table.sample_names.iter().enumerate() {
+                let count = table.counts.get(row, column).unwrap_or_default();
+                *data
+                    .get_mut(sample)
+                    .expect("sample registered above")
+                    .entry(target.clone())
+                    .or_default() += count;
+            }
+        }
+    }
+    Ok((CountTable::build_from_data(&data)?, report))
+}
+
+/// An accession without its version suffix (`GCF_000005845.2` -> `GCF_000005845`);
+/// other IDs are returned unchanged
+fn accession_base(id: &str) -> &str {
+    match id.rsplit_once('.') {
+        Some((base, version))
+            if !base.is_empty()
+                && !version.is_empty()
+                && version.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::count_table::CountPrecision;
+
+    fn table(samples: &[&str], rows: &[(&str, &[f64])]) -> CompactCountTable {
+        let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+        for (c, sample) in samples.iter().enumerate() {
+            let counts = data.entry(sample.to_string()).or_default();
+            for (feature, values) in rows {
+                counts.insert(feature.to_string(), values[c]);
+            }
+        }
+        CompactCountTable::build_from_data(&data, CountPrecision::U32).unwrap()
+    }
+
+    #[test]
+    fn test_tables_mapped_to_common_features() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("equivalences.tsv");
+        fs::write(
+            &path,
+            "from\tto\n# taxid 1234 merged into 562\n1234\t562\nGCF_000099999.1\t-\nGCF_000005845.1\tGCF_000005845.2\n",
+        )
+        .unwrap();
+        let equivalences = FeatureEquivalences::from_file(&path).unwrap();
+        assert_eq!(
+            equivalences.resolve("GCF_000005845.3"),
+            FeatureMapping::To("GCF_000005845.2".to_string())
+        );
+        assert_eq!(
+            equivalences.resolve("562"),
+            FeatureMapping::To("562".into())
+        );
+
+        let old = table(
+            &["S1"],
+            &[
+                ("1234", &[10.0]),
+                ("562", &[5.0]),
+                ("GCF_000005845.1", &[7.0]),
+                ("GCF_000099999.1", &[3.0]),
+                ("28901", &[2.0]),
+            ],
+        );
+        let new = table(&["S2"], &[("562", &[4.0]), ("GCF_000005845.2", &[1.0])]);
+
+        let (merged, report) = harmonize_tables(&[old, new], &equivalences, false).unwrap();
+        assert_eq!(merged.feature_names(), &vec!["562", "GCF_000005845.2"]);
+        assert_eq!(
+            merged.get_feature_counts("562").unwrap().to_vec(),
+            vec![15.0, 4.0]
+        );
+        assert_eq!(report.renamed["1234"], "562");
+        assert_eq!(
+            report.unmapped,
+            BTreeMap::from([
+                ("28901".to_string(), 2.0),
+                ("GCF_000099999.1".to_string(), 3.0)
+            ])
+        );
+
+        let again = table(&["S2"], &[("562", &[1.0])]);
+        let new = table(&["S2"], &[("562", &[4.0])]);
+        assert!(harmonize_tables(&[again, new], &equivalences, true).is_err());
+
+        let mut conflicting = FeatureEquivalences::default();
+        conflicting.insert("1234", Some("562")).unwrap();
+        assert!(conflicting.insert("1234", Some("561")).is_err());
+    }
+}
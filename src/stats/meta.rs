@@ -0,0 +1,340 @@
+//! Meta-analysis of differential abundance results across studies.
+//!
+//! Each study contributes its log2 fold change and standard error for the
+//! features it tested. For every feature tested in enough studies the effects
+//! are pooled by inverse-variance weighting (fixed effect) and with a
+//! DerSimonian-Laird between-study variance (random effects), and Cochran's Q
+//! and I² describe how much the studies disagree beyond sampling error.
+//! Random-effects p-values are BH-adjusted across features.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use statrs::distribution::{ChiSquared, ContinuousCDF, Normal};
+
+use crate::io::escape::csv_writer_builder;
+use crate::provenance::Provenance;
+use crate::stats::{bh_adjusted, AnalysisResults};
+
+/// Number of studies a feature must be tested in to be pooled
+pub const DEFAULT_MIN_STUDIES: usize = 2;
+
+/// Two-sided 95% normal quantile, for forest-plot intervals
+const Z_95: f64 = 1.959_963_984_540_054;
+
+/// Effect of one feature in one study
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StudyEffect {
+    pub study: String,
+    pub log2_fold_change: f64,
+    pub std_error: f64,
+}
+
+/// Pooled effect under one model
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PooledEffect {
+    pub log2_fold_change: f64,
+    pub std_error: f64,
+    pub z: f64,
+    pub p_value: f64,
+}
+
+impl PooledEffect {
+    fn from_weights(effects: &[StudyEffect], weights: &[f64]) -> Self {
+        let total: f64 = weights.iter().sum();
+        let log2_fold_change = effects
+            .iter()
+            .zip(weights)
+            .map(|(effect, w)| w * effect.log2_fold_change)
+            .sum::<f64>()
+            / total;
+        let std_error = (1.0 / total).sqrt();
+        let z = log2_fold_change / std_error;
+        let normal = Normal::new(0.0, 1.0).expect("standard normal");
+        PooledEffect {
+            log2_fold_change,
+            std_error,
+            z,
+            p_value: (2.0 * (1.0 - normal.cdf(z.abs()))).min(1.0),
+        }
+    }
+}
+
+/// Meta-analysis of one feature
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetaResult {
+    pub feature_id: String,
+    /// Studies with an estimate and a positive standard error for the feature
+    pub studies: Vec<StudyEffect>,
+    pub fixed: PooledEffect,
+    pub random: PooledEffect,
+    /// Cochran's Q
+    pub q: f64,
+    /// P-value of Q on `studies.len() - 1` degrees of freedom
+    pub q_p_value: Option<f64>,
+    /// Share of the variation between studies not due to sampling error
+    pub i_squared: f64,
+    /// DerSimonian-Laird between-study variance
+    pub tau_squared: f64,
+    /// BH-adjusted random-effects p-value
+    pub p_adjusted: Option<f64>,
+}
+
+impl MetaResult {
+    /// Pool the effects of one feature; `None` with fewer than two studies
+    pub fn pool(feature_id: &str, studies: Vec<StudyEffect>) -> Option<Self> {
+        if studies.len() < 2 {
+            return None;
+        }
+        let weights: Vec<f64> = studies
+            .iter()
+            .map(|effect| 1.0 / effect.std_error.powi(2))
+            .collect();
+        let fixed = PooledEffect::from_weights(&studies, &weights);
+
+        let q: f64 = studies
+            .iter()
+            .zip(&weights)
+            .map(|(effect, w)| w * (effect.log2_fold_change - fixed.log2_fold_change).powi(2))
+            .sum();
+        let df = (studies.len() - 1) as f64;
+        let sum_w: f64 = weights.iter().sum();
+        let sum_w2: f64 = weights.iter().map(|w| w * w).sum();
+        let tau_squared = ((q - df) / (sum_w - sum_w2 / sum_w)).max(0.0);
+        let i_squared = if q > 0.0 {
+            ((q - df) / q).max(0.0)
+        } else {
+            0.0
+        };
+        let random_weights: Vec<f64> = studies
+            .iter()
+            .map(|effect| 1.0 / (effect.std_error.powi(2) + tau_squared))
+            .collect();
+        let random = PooledEffect::from_weights(&studies, &random_weights);
+        let q_p_value = ChiSquared::new(df).ok().map(|chi| 1.0 - chi.cdf(q));
+
+        Some(MetaResult {
+            feature_id: feature_id.to_string(),
+            studies,
+            fixed,
+            random,
+            q,
+            q_p_value,
+            i_squared,
+            tau_squared,
+            p_adjusted: None,
+        })
+    }
+
+    /// Relative random-effects weight of each study, summing to one
+    pub fn study_weights(&self) -> Vec<f64> {
+        let weights: Vec<f64> = self
+            .studies
+            .iter()
+            .map(|effect| 1.0 / (effect.std_error.powi(2) + self.tau_squared))
+            .collect();
+        let total: f64 = weights.iter().sum();
+        weights.iter().map(|w| w / total).collect()
+    }
+}
+
+/// Meta-analyse `(study name, results)` pairs over the features tested in at
+/// least `min_studies` of them (and never fewer than two)
+pub fn meta_analyze(studies: &[(String, AnalysisResults)], min_studies: usize) -> Vec<MetaResult> {
+    let mut effects: BTreeMap<&str, Vec<StudyEffect>> = BTreeMap::new();
+    for (study, results) in studies {
+        for result in results {
+            // A study without a usable standard error carries no weight
+            if let (Some(log2_fold_change), Some(std_error)) =
+                (result.log2_fold_change, result.std_error)
+            {
+                if log2_fold_change.is_finite() && std_error.is_finite() && std_error > 0.0 {
+                    effects
+                        .entry(result.feature_id.as_str())
+                        .or_default()
+                        .push(StudyEffect {
+                            study: study.clone(),
+                            log2_fold_change,
+                            std_error,
+                        });
+                }
+            }
+        }
+    }
+
+    let mut results: Vec<MetaResult> = effects
+        .into_iter()
+        .filter(|(_, effects)| effects.len() >= min_studies)
+        .filter_map(|(feature_id, effects)| MetaResult::pool(feature_id, effects))
+        .collect();
+    let p_values: Vec<Option<f64>> = results.iter().map(|r| Some(r.random.p_value)).collect();
+    for (result, p_adjusted) in results.iter_mut().zip(bh_adjusted(&p_values)) {
+        result.p_adjusted = p_adjusted;
+    }
+    results
+}
+
+/// Write one row per feature: studies, fixed and random effects, heterogeneity
+pub fn write_meta_results(
+    results: &[MetaResult],
+    provenance: Option<&Provenance>,
+    path: &Path,
+) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    if let Some(provenance) = provenance {
+        provenance.write_header(&mut file)?;
+    }
+    let mut writer = csv_writer_builder().from_writer(file);
+    writer.write_record([
+        "feature_id",
+        "studies",
+        "fixed_log2_fold_change",
+        "fixed_std_error",
+        "fixed_p_value",
+        "random_log2_fold_change",
+        "random_std_error",
+        "random_p_value",
+        "random_p_adjusted",
+        "q",
+        "q_p_value",
+        "i_squared",
+        "tau_squared",
+    ])?;
+    let na = |value: Option<f64>| value.map_or("NA".to_string(), |v| v.to_string());
+    for result in results {
+        writer.write_record([
+            result.feature_id.clone(),
+            result.studies.len().to_string(),
+            result.fixed.log2_fold_change.to_string(),
+            result.fixed.std_error.to_string(),
+            result.fixed.p_value.to_string(),
+            result.random.log2_fold_change.to_string(),
+            result.random.std_error.to_string(),
+            result.random.p_value.to_string(),
+            na(result.p_adjusted),
+            result.q.to_string(),
+            na(result.q_p_value),
+            result.i_squared.to_string(),
+            result.tau_squared.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write a forest-plot table: for every feature one row per study, then the
+/// fixed- and random-effects summaries, each with a 95% interval and the
+/// study's share of the random-effects weight
+pub fn write_forest_table(results: &[MetaResult], path: &Path) -> Result<()> {
+    let mut writer = csv_writer_builder().from_path(path)?;
+    writer.write_record([
+        "feature_id",
+        "row",
+        "log2_fold_change",
+        "std_error",
+        "ci_lower",
+        "ci_upper",
+        "weight",
+    ])?;
+    let mut row = |feature_id: &str, label: &str, estimate: f64, std_error: f64, weight: f64| {
+        writer.write_record([
+            feature_id.to_string(),
+            label.to_string(),
+            estimate.to_string(),
+            std_error.to_string(),
+            (estimate - Z_95 * std_error).to_string(),
+            (estimate + Z_95 * std_error).to_string(),
+            weight.to_string(),
+        ])
+    };
+    for result in results {
+        for (effect, weight) in result.studies.iter().zip(result.study_weights()) {
+            row(
+                &result.feature_id,
+                &effect.study,
+                effect.log2_fold_change,
+                effect.std_error,
+                weight,
+            )?;
+        }
+        row(
+            &result.feature_id,
+            "fixed",
+            result.fixed.log2_fold_change,
+            result.fixed.std_error,
+            1.0,
+        )?;
+        row(
+            &result.feature_id,
+            "random",
+            result.random.log2_fold_change,
+            result.random.std_error,
+            1.0,
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::DifferentialResult;
+    use approx::assert_relative_eq;
+
+    fn study(name: &str, effects: &[(&str, f64, f64)]) -> (String, AnalysisResults) {
+        let results = effects
+            .iter()
+            .map(
+                |&(feature_id, log2_fold_change, std_error)| DifferentialResult {
+                    feature_id: feature_id.to_string(),
+                    base_mean: 10.0,
+                    log2_fold_change: Some(log2_fold_change),
+                    std_error: Some(std_error),
+                    statistic: None,
+                    p_value: None,
+                    p_adjusted: None,
+                    dispersion: None,
+                    coefficients: Vec::new(),
+                    degrees_of_freedom: None,
+                },
+            )
+            .collect();
+        (name.to_string(), results)
+    }
+
+    #[test]
+    fn test_fixed_and_random_effects_pooling() {
+        let studies = vec![
+            study(
+                "s1",
+                &[("A", 1.0, 0.5), ("B", 0.2, 0.1), ("only_s1", 3.0, 1.0)],
+            ),
+            study("s2", &[("A", 1.0, 0.5), ("B", 2.0, 0.1)]),
+        ];
+        let results = meta_analyze(&studies, DEFAULT_MIN_STUDIES);
+        assert_eq!(results.len(), 2);
+
+        // Agreeing studies: no heterogeneity, both models agree
+        let a = &results[0];
+        assert_eq!(a.feature_id, "A");
+        assert_relative_eq!(a.fixed.log2_fold_change, 1.0);
+        assert_relative_eq!(a.fixed.std_error, (0.125f64).sqrt());
+        assert_eq!(a.i_squared, 0.0);
+        assert_eq!(a.tau_squared, 0.0);
+        assert_relative_eq!(a.random.std_error, a.fixed.std_error);
+
+        // Disagreeing studies: Q = 162, I² = 161/162, wider random-effects interval
+        let b = &results[1];
+        assert_relative_eq!(b.q, 162.0, epsilon = 1e-9);
+        assert_relative_eq!(b.i_squared, 161.0 / 162.0, epsilon = 1e-12);
+        assert_relative_eq!(b.tau_squared, 1.61, epsilon = 1e-12);
+        assert!(b.random.std_error > 5.0 * b.fixed.std_error);
+        assert_relative_eq!(b.study_weights().iter().sum::<f64>(), 1.0);
+        assert!(b.p_adjusted.is_some());
+    }
+}
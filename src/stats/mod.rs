@@ -6,9 +6,32 @@
 
 pub mod bayesian; // Sub-module for Bayesian statistical methods
 pub mod deconvolution;
+pub mod diversity;
+pub mod estimators;
+pub mod feature_matrix;
+pub mod harmonize;
+pub mod hierarchical_fdr;
+pub mod meta;
+pub mod outliers;
+pub mod power;
+pub mod reconciliation;
+pub mod reference_selection;
+pub mod replicates;
+pub mod strain_clusters;
+pub mod uncertainty;
 
 pub use bayesian::StrainMixtureModel;
 pub use deconvolution::StrainDeconvolution;
+pub use estimators::{AbundanceEstimator, EstimatorKind};
+pub use feature_matrix::{FeatureMatrix, FeatureMatrixBuilder};
+pub use hierarchical_fdr::{adjust_pvalues_hierarchical, HierarchicalFdrSummary, RankTest};
+pub use outliers::{assess_samples, OutlierThresholds, SampleQc};
+pub use reconciliation::AbundanceReconciliation;
+pub use reference_selection::{
+    nested_candidates, select_references, CandidateReferenceSet, ReferenceCrossValidation,
+};
+pub use strain_clusters::{AniMatrix, SpeciesStrainClusters, StrainCluster};
+pub use uncertainty::{AbundancePosterior, UncertaintyComparison};
 
 use crate::count_table::CountTable;
 use crate::metadata::load_metadata;
@@ -25,6 +48,21 @@ pub struct DifferentialResult {
     pub statistic: Option<f64>,        // Wald statistic or similar test statistic
     pub p_value: Option<f64>,          // Raw p-value from the test
     pub p_adjusted: Option<f64>,       // Adjusted p-value (e.g., Benjamini-Hochberg)
+    #[serde(default)]
+    pub dispersion: Option<f64>, // Dispersion of the feature's count model, if it has one
+    #[serde(default)]
+    pub coefficients: Vec<ModelCoefficient>, // All model terms, including the intercept
+    #[serde(default)]
+    pub degrees_of_freedom: Option<f64>, // Degrees of freedom of the test statistic
+}
+
+/// Estimate and standard error of one model term, so results can be combined
+/// across studies by inverse-variance weighting.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelCoefficient {
+    pub term: String,
+    pub estimate: f64,
+    pub std_error: Option<f64>,
 }
 
 /// Type alias for the collection of results from an analysis.
@@ -148,12 +186,19 @@ fn validate_metadata(table: &CountTable, metadata: &Metadata) -> Result<()> {
 /// # Arguments
 /// * `results` - A mutable slice of DifferentialResult structs containing raw p-values.
 pub fn adjust_pvalues_bh(results: &mut [DifferentialResult]) {
-    // Sort results by p-value, keeping track of original indices
-    let mut indexed_results: Vec<(usize, Option<f64>)> = results
-        .iter()
-        .enumerate()
-        .map(|(i, r)| (i, r.p_value))
-        .collect();
+    let p_values: Vec<Option<f64>> = results.iter().map(|r| r.p_value).collect();
+    for (result, p_adjusted) in results.iter_mut().zip(bh_adjusted(&p_values)) {
+        result.p_adjusted = p_adjusted;
+    }
+}
+
+/// Benjamini-Hochberg adjusted p-values, in the order of `p_values`; `None`
+/// (NA) values are not counted as tests and stay `None`.
+pub fn bh_adjusted(p_values: &[Option<f64>]) -> Vec<Option<f64>> {
+    let mut adjusted = vec![None; p_values.len()];
+    // Sort p-values, keeping track of original indices
+    let mut indexed_results: Vec<(usize, Option<f64>)> =
+        p_values.iter().copied().enumerate().collect();
 
     // Sort by p-value, putting None (NA) values last
     indexed_results.sort_unstable_by(|a, b| match (a.1, b.1) {
@@ -169,11 +214,6 @@ pub fn adjust_pvalues_bh(results: &mut [DifferentialResult]) {
     // Iterate downwards through sorted p-values
     for (rank, (original_index, p_value_opt)) in indexed_results.iter().enumerate().rev() {
         if let Some(p_value) = p_value_opt {
-            if m == 0 {
-                // Avoid division by zero if no tests were done
-                results[*original_index].p_adjusted = Some(1.0);
-                continue;
-            }
             // Calculate BH adjusted p-value: p * m / rank
             // Rank here is 1-based index of the sorted p-value
             let rank_1_based = rank + 1;
@@ -182,14 +222,12 @@ pub fn adjust_pvalues_bh(results: &mut [DifferentialResult]) {
             // Enforce monotonicity: adjusted p-value cannot be greater than the next highest
             let current_padj = padj.min(last_padj).min(1.0); // Ensure it doesn't exceed 1.0
 
-            results[*original_index].p_adjusted = Some(current_padj);
+            adjusted[*original_index] = Some(current_padj);
             last_padj = current_padj; // Update the last adjusted p-value seen
-        } else {
-            // If p-value was None, adjusted p-value is also None
-            results[*original_index].p_adjusted = None;
-            // Keep last_padj as is, effectively carrying forward the previous value for monotonicity check
         }
+        // If p-value was None, adjusted p-value is also None
     }
+    adjusted
 }
 
 #[cfg(test)]
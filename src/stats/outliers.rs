@@ -0,0 +1,283 @@
+//! Outlier samples ahead of differential analysis.
+//!
+//! A sample sequenced far shallower or deeper than the rest, one whose
+//! composition is unlike every other sample, or one whose sketch matched few
+//! references can dominate dispersion estimates and fold changes. Library size
+//! and distance to the composition centroid are judged with robust z-scores
+//! (median and MAD), so a single extreme sample cannot hide itself by inflating
+//! the spread. The classification rate is judged against a fixed minimum.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use statrs::statistics::{Data, OrderStatistics};
+
+use crate::count_table::CountTable;
+use crate::io::escape::csv_writer_builder;
+use crate::provenance::Provenance;
+
+/// Samples needed before library size and composition are judged
+pub const MIN_SAMPLES_FOR_ROBUST_Z: usize = 4;
+
+/// MAD scale factor making it consistent with the standard deviation of a normal distribution
+const MAD_SCALE: f64 = 1.4826;
+
+/// Limits beyond which a sample is flagged
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutlierThresholds {
+    /// Maximum absolute robust z-score of log10 library size
+    pub max_library_size_z: f64,
+    /// Maximum robust z-score of the Bray-Curtis distance to the centroid
+    pub max_distance_z: f64,
+    /// Minimum fraction of sample hashes assigned to a reference
+    pub min_assigned_fraction: f64,
+}
+
+impl Default for OutlierThresholds {
+    fn default() -> Self {
+        OutlierThresholds {
+            max_library_size_z: 3.0,
+            max_distance_z: 3.0,
+            min_assigned_fraction: 0.5,
+        }
+    }
+}
+
+/// Per-sample statistics used to flag outliers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SampleQc {
+    pub sample_id: String,
+    /// Total count of the sample
+    pub library_size: f64,
+    /// Robust z-score of log10 library size, if it could be computed
+    pub library_size_z: Option<f64>,
+    /// Bray-Curtis distance of the sample's proportions to the mean proportions
+    pub centroid_distance: f64,
+    /// Robust z-score of `centroid_distance`, if it could be computed
+    pub distance_z: Option<f64>,
+    /// Fraction of sample hashes assigned to a reference, if known
+    pub assigned_fraction: Option<f64>,
+    /// Why the sample is an outlier; empty if it is not
+    pub reasons: Vec<String>,
+}
+
+impl SampleQc {
+    pub fn is_outlier(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+}
+
+/// Compute the outlier statistics of every sample in `table`, in table order.
+///
+/// `assigned_fractions` holds each sample's classification rate; samples
+/// missing from it are not judged on it. With fewer than
+/// `MIN_SAMPLES_FOR_ROBUST_Z` samples, or when all samples have the same value
+/// (zero MAD), the corresponding z-score is `None` and nothing is flagged.
+pub fn assess_samples(
+    table: &CountTable,
+    assigned_fractions: &HashMap<String, f64>,
+    thresholds: &OutlierThresholds,
+) -> Vec<SampleQc> {
+    let counts = table.counts_matrix();
+    let library_sizes: Vec<f64> = (0..counts.ncols())
+        .map(|c| counts.column(c).sum())
+        .collect();
+
+    // Proportions of each sample; an empty sample has none and is maximally distant
+    let proportions: Vec<Vec<f64>> = library_sizes
+        .iter()
+        .enumerate()
+        .map(|(c, &total)| {
+            counts
+                .column(c)
+                .iter()
+                .map(|&count| if total > 0.0 { count / total } else { 0.0 })
+                .collect()
+        })
+        .collect();
+    let n_features = counts.nrows();
+    let centroid: Vec<f64> = (0..n_features)
+        .map(|r| proportions.iter().map(|p| p[r]).sum::<f64>() / proportions.len().max(1) as f64)
+        .collect();
+    let distances: Vec<f64> = proportions
+        .iter()
+        .map(|p| bray_curtis(p, &centroid))
+        .collect();
+
+    let log_sizes: Vec<f64> = library_sizes.iter().map(|s| (s + 1.0).log10()).collect();
+    let size_z = robust_z_scores(&log_sizes);
+    let distance_z = robust_z_scores(&distances);
+
+    table
+        .sample_names()
+        .iter()
+        .enumerate()
+        .map(|(c, sample)| {
+            let assigned_fraction = assigned_fractions.get(sample).copied();
+            let mut reasons = Vec::new();
+            if let Some(z) = size_z[c] {
+                if z.abs() > thresholds.max_library_size_z {
+                    reasons.push(format!(
+                        "library size {:.0} is {} than the others (robust z = {:.1})",
+                        library_sizes[c],
+                        if z > 0.0 { "far larger" } else { "far smaller" },
+                        z
+                    ));
+                }
+            }
+            if let Some(z) = distance_z[c] {
+                if z > thresholds.max_distance_z {
+                    reasons.push(format!(
+                        "composition is distinct (Bray-Curtis distance to centroid {:.2}, robust z = {:.1})",
+                        distances[c], z
+                    ));
+                }
+            }
+            if let Some(fraction) = assigned_fraction {
+                if fraction < thresholds.min_assigned_fraction {
+                    reasons.push(format!(
+                        "only {:.0}% of sample hashes matched a reference",
+                        fraction * 100.0
+                    ));
+                }
+            }
+            SampleQc {
+                sample_id: sample.clone(),
+                library_size: library_sizes[c],
+                library_size_z: size_z[c],
+                centroid_distance: distances[c],
+                distance_z: distance_z[c],
+                assigned_fraction,
+                reasons,
+            }
+        })
+        .collect()
+}
+
+/// Write the per-sample statistics as CSV, one row per sample
+pub fn write_sample_qc(
+    qc: &[SampleQc],
+    provenance: Option<&Provenance>,
+    output_path: &Path,
+) -> Result<()> {
+    let mut file = BufWriter::new(File::create(output_path)?);
+    if let Some(provenance) = provenance {
+        provenance.write_header(&mut file)?;
+    }
+    let mut writer = csv_writer_builder().from_writer(file);
+    writer.write_record([
+        "sample_id",
+        "library_size",
+        "library_size_z",
+        "centroid_distance",
+        "distance_z",
+        "assigned_fraction",
+        "outlier",
+        "reasons",
+    ])?;
+    let optional = |value: Option<f64>| value.map_or("NA".to_string(), |v| format!("{:.4}", v));
+    for sample in qc {
+        writer.write_record([
+            sample.sample_id.clone(),
+            sample.library_size.to_string(),
+            optional(sample.library_size_z),
+            format!("{:.4}", sample.centroid_distance),
+            optional(sample.distance_z),
+            optional(sample.assigned_fraction),
+            sample.is_outlier().to_string(),
+            sample.reasons.join("; "),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Bray-Curtis dissimilarity of two abundance vectors (1 if both are empty)
+pub(crate) fn bray_curtis(a: &[f64], b: &[f64]) -> f64 {
+    let (difference, total) = a
+        .iter()
+        .zip(b)
+        .fold((0.0, 0.0), |(d, t), (x, y)| (d + (x - y).abs(), t + x + y));
+    if total > 0.0 {
+        difference / total
+    } else {
+        1.0
+    }
+}
+
+/// `(x - median) / (1.4826 * MAD)` for each value, or all `None` if there are
+/// too few values or the MAD is zero
+fn robust_z_scores(values: &[f64]) -> Vec<Option<f64>> {
+    if values.len() < MIN_SAMPLES_FOR_ROBUST_Z {
+        return vec![None; values.len()];
+    }
+    let median = Data::new(values.to_vec()).median();
+    let mad = Data::new(
+        values
+            .iter()
+            .map(|v| (v - median).abs())
+            .collect::<Vec<_>>(),
+    )
+    .median();
+    if mad <= 0.0 || !mad.is_finite() {
+        return vec![None; values.len()];
+    }
+    values
+        .iter()
+        .map(|v| Some((v - median) / (MAD_SCALE * mad)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_library_size_composition_and_classification_rate() {
+        let sample = |counts: [f64; 3]| -> HashMap<String, f64> {
+            ["A", "B", "C"]
+                .iter()
+                .map(|f| f.to_string())
+                .zip(counts)
+                .collect()
+        };
+        let data: HashMap<String, HashMap<String, f64>> = HashMap::from([
+            ("S1".to_string(), sample([500.0, 300.0, 200.0])),
+            ("S2".to_string(), sample([780.0, 400.0, 320.0])),
+            ("S3".to_string(), sample([380.0, 270.0, 150.0])),
+            ("S4".to_string(), sample([650.0, 330.0, 220.0])),
+            // Shallow library
+            ("S5".to_string(), sample([5.0, 3.0, 2.0])),
+            // Normal depth, but dominated by another taxon
+            ("S6".to_string(), sample([10.0, 20.0, 970.0])),
+        ]);
+        let table = CountTable::build_from_data(&data).unwrap();
+        let assigned = HashMap::from([("S1".to_string(), 0.9), ("S4".to_string(), 0.2)]);
+
+        let qc = assess_samples(&table, &assigned, &OutlierThresholds::default());
+        let outliers: Vec<&str> = qc
+            .iter()
+            .filter(|s| s.is_outlier())
+            .map(|s| s.sample_id.as_str())
+            .collect();
+        assert_eq!(outliers, vec!["S4", "S5", "S6"]);
+        assert!(qc[4].reasons[0].contains("far smaller"));
+        assert!(qc[5].reasons[0].contains("composition is distinct"));
+        assert!(qc[3].reasons[0].contains("20%"));
+
+        // Too few samples to judge library size or composition
+        let small = CountTable::build_from_data(&HashMap::from([
+            ("S1".to_string(), sample([5.0, 3.0, 2.0])),
+            ("S2".to_string(), sample([500.0, 300.0, 200.0])),
+        ]))
+        .unwrap();
+        let qc = assess_samples(&small, &HashMap::new(), &OutlierThresholds::default());
+        assert!(qc
+            .iter()
+            .all(|s| !s.is_outlier() && s.library_size_z.is_none()));
+    }
+}
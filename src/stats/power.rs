@@ -0,0 +1,367 @@
+//! Power analysis for planning how many samples to sequence.
+//!
+//! A pilot count table gives, for each feature, a normalized mean and a
+//! negative binomial dispersion (method of moments across the pilot samples).
+//! For each candidate number of samples per group, features are drawn from
+//! the pilot, counts are simulated for a control group and a group with the
+//! target fold change, and the log2 abundances are compared with a Welch t
+//! test, as in the group comparison of abundance posteriors. Power is the
+//! share of simulations in which the change is detected at `alpha`; no
+//! multiple-testing correction is applied, so `alpha` should already be the
+//! per-feature level the study will use.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Gamma, Poisson};
+use serde::{Deserialize, Serialize};
+use statrs::distribution::{ContinuousCDF, StudentsT};
+
+use crate::count_table::CountTable;
+use crate::io::escape::csv_writer_builder;
+use crate::normalization::compute_size_factors;
+use crate::provenance::Provenance;
+
+/// Samples per group evaluated by default
+pub const DEFAULT_SAMPLE_SIZES: [usize; 9] = [2, 3, 4, 5, 6, 8, 10, 15, 20];
+
+/// Simulated experiments per sample size
+pub const DEFAULT_SIMULATIONS: usize = 1000;
+
+/// Power at which a sample size is recommended
+pub const DEFAULT_TARGET_POWER: f64 = 0.8;
+
+/// Pilot features with a lower mean normalized count are not simulated
+const MIN_PILOT_MEAN: f64 = 1.0;
+
+/// Floor on dispersions, so features less variable than Poisson noise still vary
+const MIN_DISPERSION: f64 = 1e-4;
+
+/// Mean and dispersion of one pilot feature
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PilotFeature {
+    pub mean: f64,
+    pub dispersion: f64,
+}
+
+/// Per-feature means and dispersions estimated from a pilot count table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PilotEstimates {
+    pub features: Vec<PilotFeature>,
+    /// Pilot features left out for a mean below one count
+    pub skipped_features: usize,
+}
+
+impl PilotEstimates {
+    /// Estimate from raw counts, normalized by median-of-ratios size factors
+    pub fn from_counts(table: &CountTable) -> Result<Self> {
+        let samples = table.sample_names().len();
+        if samples < 2 {
+            return Err(anyhow!(
+                "A pilot needs at least two samples to estimate dispersion"
+            ));
+        }
+        let size_factors = compute_size_factors(table)?;
+        let factors: Vec<f64> = table
+            .sample_names()
+            .iter()
+            .map(|sample| size_factors[sample])
+            .collect();
+        let counts = table.counts_matrix();
+        let mut features = Vec::new();
+        let mut skipped_features = 0;
+        for row in counts.rows() {
+            let normalized: Vec<f64> = row.iter().zip(&factors).map(|(c, f)| c / f).collect();
+            let mean = normalized.iter().sum::<f64>() / samples as f64;
+            if mean < MIN_PILOT_MEAN {
+                skipped_features += 1;
+                continue;
+            }
+            let variance =
+                normalized.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (samples - 1) as f64;
+            features.push(PilotFeature {
+                mean,
+                dispersion: ((variance - mean) / (mean * mean)).max(MIN_DISPERSION),
+            });
+        }
+        if features.is_empty() {
+            return Err(anyhow!(
+                "No pilot feature has a mean normalized count of at least {}",
+                MIN_PILOT_MEAN
+            ));
+        }
+        Ok(PilotEstimates {
+            features,
+            skipped_features,
+        })
+    }
+
+    /// Median dispersion of the pilot features
+    pub fn median_dispersion(&self) -> f64 {
+        let mut dispersions: Vec<f64> = self.features.iter().map(|f| f.dispersion).collect();
+        dispersions.sort_by(f64::total_cmp);
+        let mid = dispersions.len() / 2;
+        if dispersions.len() % 2 == 0 {
+            (dispersions[mid - 1] + dispersions[mid]) / 2.0
+        } else {
+            dispersions[mid]
+        }
+    }
+}
+
+/// Design being evaluated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerSettings {
+    /// Fold change (treatment over control) to detect
+    pub effect_size: f64,
+    pub alpha: f64,
+    pub sample_sizes: Vec<usize>,
+    pub simulations: usize,
+    pub target_power: f64,
+    pub seed: u64,
+}
+
+impl Default for PowerSettings {
+    fn default() -> Self {
+        PowerSettings {
+            effect_size: 2.0,
+            alpha: 0.05,
+            sample_sizes: DEFAULT_SAMPLE_SIZES.to_vec(),
+            simulations: DEFAULT_SIMULATIONS,
+            target_power: DEFAULT_TARGET_POWER,
+            seed: 42,
+        }
+    }
+}
+
+/// Simulated power at one sample size
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PowerPoint {
+    pub per_group: usize,
+    pub power: f64,
+    /// Binomial standard error of `power`
+    pub std_error: f64,
+}
+
+/// Power curve of a design
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerAnalysis {
+    pub settings: PowerSettings,
+    pub pilot_features: usize,
+    pub median_dispersion: f64,
+    pub curve: Vec<PowerPoint>,
+    /// Smallest evaluated sample size reaching the target power
+    pub recommended_per_group: Option<usize>,
+}
+
+impl PowerAnalysis {
+    /// Simulate the power of `settings` with feature parameters drawn from `pilot`
+    pub fn simulate(pilot: &PilotEstimates, settings: PowerSettings) -> Result<Self> {
+        if settings.effect_size <= 0.0 || settings.effect_size == 1.0 {
+            return Err(anyhow!(
+                "The effect size must be a fold change other than 1"
+            ));
+        }
+        if !(settings.alpha > 0.0 && settings.alpha < 1.0) {
+            return Err(anyhow!("alpha must be between 0 and 1"));
+        }
+        let valid = |value: f64| value > 0.0 && value.is_finite();
+        if pilot.features.is_empty()
+            || !pilot
+                .features
+                .iter()
+                .all(|feature| valid(feature.mean) && valid(feature.dispersion))
+        {
+            return Err(anyhow!(
+                "Pilot features need positive, finite means and dispersions"
+            ));
+        }
+        let mut rng = StdRng::seed_from_u64(settings.seed);
+        let mut sample_sizes = settings.sample_sizes.clone();
+        sample_sizes.retain(|&n| n >= 2);
+        sample_sizes.sort_unstable();
+        sample_sizes.dedup();
+
+        let curve: Vec<PowerPoint> = sample_sizes
+            .iter()
+            .map(|&per_group| {
+                let detected = (0..settings.simulations)
+                    .filter(|_| {
+                        let feature = pilot.features[rng.random_range(0..pilot.features.len())];
+                        let control = simulate_group(&mut rng, feature, 1.0, per_group);
+                        let treated =
+                            simulate_group(&mut rng, feature, settings.effect_size, per_group);
+                        welch_p_value(&control, &treated).is_some_and(|p| p < settings.alpha)
+                    })
+                    .count();
+                let power = detected as f64 / settings.simulations.max(1) as f64;
+                PowerPoint {
+                    per_group,
+                    power,
+                    std_error: (power * (1.0 - power) / settings.simulations.max(1) as f64).sqrt(),
+                }
+            })
+            .collect();
+        let recommended_per_group = curve
+            .iter()
+            .find(|point| point.power >= settings.target_power)
+            .map(|point| point.per_group);
+        Ok(PowerAnalysis {
+            settings,
+            pilot_features: pilot.features.len(),
+            median_dispersion: pilot.median_dispersion(),
+            curve,
+            recommended_per_group,
+        })
+    }
+}
+
+impl fmt::Display for PowerAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Power to detect a {}-fold change at alpha {} ({} pilot features, median dispersion {:.3}, {} simulations per size):",
+            self.settings.effect_size,
+            self.settings.alpha,
+            self.pilot_features,
+            self.median_dispersion,
+            self.settings.simulations
+        )?;
+        writeln!(f, "  per group\tpower")?;
+        for point in &self.curve {
+            writeln!(
+                f,
+                "  {}\t{:.1}% (± {:.1}%)",
+                point.per_group,
+                point.power * 100.0,
+                point.std_error * 100.0
+            )?;
+        }
+        match self.recommended_per_group {
+            Some(n) => writeln!(
+                f,
+                "{} samples per group reach {:.0}% power",
+                n,
+                self.settings.target_power * 100.0
+            ),
+            None => writeln!(
+                f,
+                "No evaluated sample size reaches {:.0}% power; try larger sizes",
+                self.settings.target_power * 100.0
+            ),
+        }
+    }
+}
+
+/// Write the power curve, one row per sample size
+pub fn write_power_curve(
+    analysis: &PowerAnalysis,
+    provenance: Option<&Provenance>,
+    path: &Path,
+) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    if let Some(provenance) = provenance {
+        provenance.write_header(&mut file)?;
+    }
+    let mut writer = csv_writer_builder().from_writer(file);
+    writer.write_record(["per_group", "power", "std_error", "reaches_target"])?;
+    for point in &analysis.curve {
+        writer.write_record([
+            point.per_group.to_string(),
+            point.power.to_string(),
+            point.std_error.to_string(),
+            (point.power >= analysis.settings.target_power).to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Log2 normalized counts of `n` samples of a feature with its mean scaled by `fold`
+fn simulate_group(rng: &mut StdRng, feature: PilotFeature, fold: f64, n: usize) -> Vec<f64> {
+    // Negative binomial as a gamma-distributed Poisson rate
+    let shape = 1.0 / feature.dispersion;
+    let rates = Gamma::new(shape, feature.mean * fold / shape)
+        .expect("pilot means and dispersions are checked to be positive");
+    (0..n)
+        .map(|_| {
+            // A rate that underflows to zero has no valid Poisson, and draws no reads
+            let count = Poisson::new(rates.sample(rng)).map_or(0.0, |counts| counts.sample(rng));
+            (count + 1.0).log2()
+        })
+        .collect()
+}
+
+/// Two-sided Welch t test p-value; `None` if both groups are constant
+fn welch_p_value(a: &[f64], b: &[f64]) -> Option<f64> {
+    let moments = |x: &[f64]| {
+        let n = x.len() as f64;
+        let mean = x.iter().sum::<f64>() / n;
+        let variance = x.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        (n, mean, variance)
+    };
+    let (n_a, mean_a, var_a) = moments(a);
+    let (n_b, mean_b, var_b) = moments(b);
+    let se2 = var_a / n_a + var_b / n_b;
+    if se2 <= 0.0 {
+        return None;
+    }
+    let t = (mean_b - mean_a) / se2.sqrt();
+    let df =
+        se2.powi(2) / ((var_a / n_a).powi(2) / (n_a - 1.0) + (var_b / n_b).powi(2) / (n_b - 1.0));
+    let t_dist = StudentsT::new(0.0, 1.0, df).ok()?;
+    Some((2.0 * (1.0 - t_dist.cdf(t.abs()))).min(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_power_grows_with_sample_size() {
+        // Two pilot features, noisy around means of 100 and 20
+        let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+        for (sample, counts) in [
+            ("p1", [80.0, 10.0]),
+            ("p2", [120.0, 30.0]),
+            ("p3", [100.0, 20.0]),
+            ("p4", [90.0, 25.0]),
+        ] {
+            data.insert(
+                sample.to_string(),
+                HashMap::from([("F1".to_string(), counts[0]), ("F2".to_string(), counts[1])]),
+            );
+        }
+        let table = CountTable::build_from_data(&data).unwrap();
+        let pilot = PilotEstimates::from_counts(&table).unwrap();
+        assert_eq!(pilot.features.len(), 2);
+        assert!(pilot.features.iter().all(|f| f.dispersion > 0.0));
+
+        let settings = PowerSettings {
+            sample_sizes: vec![2, 5, 20],
+            simulations: 400,
+            ..PowerSettings::default()
+        };
+        let analysis = PowerAnalysis::simulate(&pilot, settings).unwrap();
+        let powers: Vec<f64> = analysis.curve.iter().map(|p| p.power).collect();
+        assert!(powers[0] < powers[1] && powers[1] < powers[2]);
+        assert!(powers[2] > 0.9);
+        let recommended = analysis.recommended_per_group.unwrap();
+        assert!(analysis
+            .curve
+            .iter()
+            .all(|p| (p.per_group >= recommended) == (p.power >= DEFAULT_TARGET_POWER)));
+
+        let no_effect = PowerSettings {
+            effect_size: 1.0,
+            ..PowerSettings::default()
+        };
+        assert!(PowerAnalysis::simulate(&pilot, no_effect).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Consistency between species and strain abundances.
+//!
+//! The species abundance and the strain abundances come from different
+//! resolution levels: the share of the sample that belongs to the species is
+//! read from the coarse (meso, or macro) sketches, while the strains are
+//! resolved on the finest (micro) sketches. Sketch noise and strains missing
+//! from the database mean the strains can explain more of the sample than the
+//! species does, or less. Reconciliation expresses strain abundances as
+//! shares of their species, scales them down when they claim more mass than
+//! the species has, and keeps what no strain explains as unresolved mass.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sketch::MultiResolutionSignature;
+
+/// How far (as a share of the sample) the strains may exceed their species
+/// before the estimates are flagged as inconsistent
+pub const DEFAULT_RECONCILIATION_TOLERANCE: f64 = 0.05;
+
+/// Species and strain abundances of a sample and how they were reconciled
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AbundanceReconciliation {
+    pub species_id: String,
+    /// Share of the sample's coarse-level hashes found in the species' references
+    pub species_abundance: f64,
+    /// Share of the sample's finest-level hashes found in the candidate strains
+    pub strain_total: f64,
+    /// Factor applied to the strains' sample shares so they fit in the species
+    pub scale: f64,
+    /// Share of the species not attributed to any strain
+    pub unresolved_fraction: f64,
+    /// Whether the strains exceeded the species by more than the tolerance
+    pub inconsistent: bool,
+}
+
+impl AbundanceReconciliation {
+    /// Sample share of the species and its strains, from the sketches of the
+    /// sample, the species' references (species and strains) and the strains.
+    /// `None` if the sample has no coarse-level hashes or none in the species.
+    pub fn measure(
+        species_id: &str,
+        sample: &MultiResolutionSignature,
+        species_references: &[&MultiResolutionSignature],
+        strains: &[&MultiResolutionSignature],
+    ) -> Option<(f64, f64)> {
+        let coarse = if sample.levels.len() > 1 { 1 } else { 0 };
+        let species_abundance = contained_fraction(sample, species_references, coarse)?;
+        if species_abundance <= 0.0 {
+            log::debug!("No coarse-level hashes of the sample are in {}", species_id);
+            return None;
+        }
+        let finest = sample.levels.len() - 1;
+        let strain_total = contained_fraction(sample, strains, finest).unwrap_or(0.0);
+        Some((species_abundance, strain_total))
+    }
+
+    /// Rescale `strains` (relative abundance, uncertainty) from shares of the
+    /// strains to shares of the species. Strains claiming more of the sample
+    /// than the species are scaled down to exactly fill it.
+    pub fn reconcile(
+        species_id: &str,
+        species_abundance: f64,
+        strain_total: f64,
+        strains: &mut HashMap<String, (f64, f64)>,
+        tolerance: f64,
+    ) -> Self {
+        let relative_total: f64 = strains.values().map(|(abundance, _)| abundance).sum();
+        let inconsistent = strain_total > species_abundance + tolerance;
+        let strain_mass = strain_total.min(species_abundance);
+        let scale = if strain_total > 0.0 {
+            strain_mass / strain_total
+        } else {
+            1.0
+        };
+
+        let factor = if relative_total > 0.0 && species_abundance > 0.0 {
+            strain_mass / species_abundance / relative_total
+        } else {
+            0.0
+        };
+        for (abundance, uncertainty) in strains.values_mut() {
+            *abundance *= factor;
+            *uncertainty *= factor;
+        }
+        let assigned: f64 = strains.values().map(|(abundance, _)| abundance).sum();
+
+        AbundanceReconciliation {
+            species_id: species_id.to_string(),
+            species_abundance,
+            strain_total,
+            scale,
+            unresolved_fraction: (1.0 - assigned).max(0.0),
+            inconsistent,
+        }
+    }
+}
+
+/// Fraction of the sample's hashes at `level` found in any of `references`
+/// at the same level and k-mer size
+fn contained_fraction(
+    sample: &MultiResolutionSignature,
+    references: &[&MultiResolutionSignature],
+    level: usize,
+) -> Option<f64> {
+    let sample_level = sample.levels.get(level)?;
+    if sample_level.sketch.hashes.is_empty() {
+        return None;
+    }
+    let reference_hashes: HashSet<u64> = references
+        .iter()
+        .filter_map(|reference| reference.levels.get(level))
+        .filter(|reference_level| reference_level.kmer_size == sample_level.kmer_size)
+        .flat_map(|reference_level| reference_level.sketch.hashes.iter().copied())
+        .collect();
+    let contained = sample_level
+        .sketch
+        .hashes
+        .iter()
+        .filter(|hash| reference_hashes.contains(hash))
+        .count();
+    Some(contained as f64 / sample_level.sketch.hashes.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    fn strains() -> HashMap<String, (f64, f64)> {
+        HashMap::from([
+            ("A".to_string(), (0.75, 0.1)),
+            ("B".to_string(), (0.25, 0.1)),
+        ])
+    }
+
+    #[test]
+    fn test_strains_never_exceed_their_species() {
+        // Strains explain 60% of the sample, the species only 40%
+        let mut abundances = strains();
+        let reconciled = AbundanceReconciliation::reconcile(
+            "562",
+            0.4,
+            0.6,
+            &mut abundances,
+            DEFAULT_RECONCILIATION_TOLERANCE,
+        );
+        assert!(reconciled.inconsistent);
+        assert_relative_eq!(reconciled.scale, 0.4 / 0.6);
+        assert_relative_eq!(abundances["A"].0, 0.75);
+        assert_relative_eq!(abundances["B"].0, 0.25);
+        assert_relative_eq!(reconciled.unresolved_fraction, 0.0, epsilon = 1e-12);
+
+        // Strains explain half of the species; the rest stays unresolved
+        let mut abundances = strains();
+        let reconciled = AbundanceReconciliation::reconcile(
+            "562",
+            0.4,
+            0.2,
+            &mut abundances,
+            DEFAULT_RECONCILIATION_TOLERANCE,
+        );
+        assert!(!reconciled.inconsistent);
+        assert_eq!(reconciled.scale, 1.0);
+        assert_relative_eq!(abundances["A"].0, 0.375);
+        assert_relative_eq!(abundances["A"].1, 0.05);
+        assert_relative_eq!(reconciled.unresolved_fraction, 0.5);
+
+        // A small excess is within the tolerance but still scaled down
+        let mut abundances = strains();
+        let reconciled = AbundanceReconciliation::reconcile(
+            "562",
+            0.4,
+            0.42,
+            &mut abundances,
+            DEFAULT_RECONCILIATION_TOLERANCE,
+        );
+        assert!(!reconciled.inconsistent);
+        let total: f64 = abundances.values().map(|(a, _)| a).sum();
+        assert_relative_eq!(total, 1.0);
+    }
+}
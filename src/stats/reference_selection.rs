@@ -0,0 +1,368 @@
+//! Cross-validation of deconvolution reference panels.
+//!
+//! Adding references always improves the fit to the features they were fitted
+//! on, so in-sample error cannot tell whether a strain belongs in the panel.
+//! Here the observed features are split into folds; abundances are refitted
+//! with one fold held out and the held-out features are predicted from the
+//! fitted mixture. A panel that only absorbs noise fits the training features
+//! better but predicts the held-out ones worse.
+//!
+//! `select_references` runs this for a sample sketch, with the sketch hashes
+//! of the candidate strains as features and nested candidate sets of the
+//! most abundant strains (the `select-references` command).
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use ndarray::Array1;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::io::escape::csv_writer_builder;
+use crate::sketch::MultiResolutionSignature;
+use crate::stats::deconvolution::StrainDeconvolution;
+use crate::stats::feature_matrix::FeatureMatrixBuilder;
+
+/// Default number of cross-validation folds
+pub const DEFAULT_FOLDS: usize = 5;
+
+/// A named subset of the reference panel to evaluate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CandidateReferenceSet {
+    pub name: String,
+    pub strain_ids: Vec<String>,
+}
+
+/// Held-out prediction error of one candidate reference set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceSetScore {
+    pub name: String,
+    pub strain_ids: Vec<String>,
+    /// Root mean squared error on the held-out features of each fold
+    pub fold_errors: Vec<f64>,
+    /// Mean of `fold_errors`
+    pub mean_error: f64,
+    /// Standard error of `mean_error` across folds
+    pub std_error: f64,
+}
+
+/// K-fold cross-validation over observed features
+#[derive(Debug, Clone)]
+pub struct ReferenceCrossValidation {
+    /// Number of folds; each holds out roughly `1 / folds` of the features
+    pub folds: usize,
+    /// Seed for the assignment of features to folds
+    pub seed: u64,
+}
+
+impl Default for ReferenceCrossValidation {
+    fn default() -> Self {
+        ReferenceCrossValidation {
+            folds: DEFAULT_FOLDS,
+            seed: 42,
+        }
+    }
+}
+
+impl ReferenceCrossValidation {
+    /// Score each candidate set by held-out prediction error, best (lowest) first.
+    ///
+    /// References are taken from `panel`, whose `max_iterations` and `l1_penalty`
+    /// are used for every refit. Every candidate sees the same folds.
+    pub fn evaluate(
+        &self,
+        panel: &StrainDeconvolution,
+        profile: &Array1<f64>,
+        candidates: &[CandidateReferenceSet],
+    ) -> Result<Vec<ReferenceSetScore>> {
+        let n_features = profile.len();
+        if self.folds < 2 || self.folds > n_features {
+            return Err(anyhow!(
+                "Cross-validation needs between 2 and {} folds, got {}",
+                n_features,
+                self.folds
+            ));
+        }
+        if let Some(sig) = panel
+            .reference_signatures
+            .iter()
+            .find(|sig| sig.len() != n_features)
+        {
+            return Err(anyhow!(
+                "Reference has {} features but the profile has {}",
+                sig.len(),
+                n_features
+            ));
+        }
+
+        let folds = self.assign_folds(n_features);
+        let mut scores = candidates
+            .iter()
+            .map(|candidate| {
+                let references = candidate
+                    .strain_ids
+                    .iter()
+                    .map(|id| {
+                        panel
+                            .reference_ids
+                            .iter()
+                            .position(|r| r == id)
+                            .map(|i| &panel.reference_signatures[i])
+                            .ok_or_else(|| {
+                                anyhow!("Candidate '{}': unknown strain '{}'", candidate.name, id)
+                            })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if references.is_empty() {
+                    return Err(anyhow!("Candidate '{}' has no strains", candidate.name));
+                }
+
+                let fold_errors = folds
+                    .iter()
+                    .map(|held_out| holdout_error(panel, &references, profile, held_out))
+                    .collect::<Result<Vec<_>>>()?;
+                let k = fold_errors.len() as f64;
+                let mean_error = fold_errors.iter().sum::<f64>() / k;
+                let variance = fold_errors
+                    .iter()
+                    .map(|e| (e - mean_error).powi(2))
+                    .sum::<f64>()
+                    / (k - 1.0);
+                Ok(ReferenceSetScore {
+                    name: candidate.name.clone(),
+                    strain_ids: candidate.strain_ids.clone(),
+                    fold_errors,
+                    mean_error,
+                    std_error: (variance / k).sqrt(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        scores.sort_by(|a, b| a.mean_error.total_cmp(&b.mean_error));
+        Ok(scores)
+    }
+
+    /// Shuffle feature indices and deal them into `self.folds` folds
+    fn assign_folds(&self, n_features: usize) -> Vec<Vec<usize>> {
+        let mut indices: Vec<usize> = (0..n_features).collect();
+        indices.shuffle(&mut StdRng::seed_from_u64(self.seed));
+        let mut folds = vec![Vec::new(); self.folds];
+        for (i, feature) in indices.into_iter().enumerate() {
+            folds[i % self.folds].push(feature);
+        }
+        folds
+    }
+}
+
+/// Nested candidate sets `top_1`, `top_2`, ... made of the strains with the
+/// highest abundance when the whole panel is fitted to `profile`. Evaluating
+/// these answers "how many references should the panel keep".
+pub fn nested_candidates(
+    panel: &StrainDeconvolution,
+    profile: &Array1<f64>,
+) -> Vec<CandidateReferenceSet> {
+    let full_fit = panel.estimate_abundances(profile);
+    let mut ranked: Vec<&String> = panel.reference_ids.iter().collect();
+    ranked.sort_by(|a, b| {
+        let abundance = |id: &String| full_fit.get(id).copied().unwrap_or(0.0);
+        abundance(b).total_cmp(&abundance(a)).then_with(|| a.cmp(b))
+    });
+
+    (1..=ranked.len())
+        .map(|size| CandidateReferenceSet {
+            name: format!("top_{}", size),
+            strain_ids: ranked[..size].iter().map(|id| (*id).clone()).collect(),
+        })
+        .collect()
+}
+
+/// Score nested candidate sets (`nested_candidates`) of `strains` for a sample
+/// sketch, with the hashes of their finest shared sketch level as features
+pub fn select_references(
+    sample: &MultiResolutionSignature,
+    strains: &[&MultiResolutionSignature],
+    cv: &ReferenceCrossValidation,
+) -> Result<Vec<ReferenceSetScore>> {
+    let features = FeatureMatrixBuilder::new(sample, strains).build()?;
+    let columns = features
+        .signatures
+        .columns()
+        .into_iter()
+        .map(|column| column.to_owned())
+        .collect();
+    let panel = StrainDeconvolution::new(columns, features.strain_ids, Some(0.0), None)
+        .map_err(|e| anyhow!(e))?;
+    let candidates = nested_candidates(&panel, &features.observed);
+    cv.evaluate(&panel, &features.observed, &candidates)
+}
+
+/// Write each sample's candidate set scores as CSV, best first within a sample
+pub fn write_reference_scores(
+    scores: &[(String, Vec<ReferenceSetScore>)],
+    path: &Path,
+) -> Result<()> {
+    let mut writer = csv_writer_builder().from_path(path)?;
+    writer.write_record([
+        "sample_id",
+        "candidate",
+        "n_strains",
+        "mean_error",
+        "std_error",
+        "strain_ids",
+    ])?;
+    for (sample_id, sample_scores) in scores {
+        for score in sample_scores {
+            writer.write_record([
+                sample_id.clone(),
+                score.name.clone(),
+                score.strain_ids.len().to_string(),
+                score.mean_error.to_string(),
+                score.std_error.to_string(),
+                score.strain_ids.join(";"),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Fit `references` to the features outside `held_out` and return the RMSE of
+/// the predicted held-out features
+fn holdout_error(
+    panel: &StrainDeconvolution,
+    references: &[&Array1<f64>],
+    profile: &Array1<f64>,
+    held_out: &[usize],
+) -> Result<f64> {
+    let mut is_held_out = vec![false; profile.len()];
+    for &i in held_out {
+        is_held_out[i] = true;
+    }
+    let training: Vec<usize> = (0..profile.len()).filter(|&i| !is_held_out[i]).collect();
+    let subset = |values: &Array1<f64>, rows: &[usize]| -> Array1<f64> {
+        rows.iter().map(|&i| values[i]).collect()
+    };
+
+    let ids: Vec<String> = (0..references.len()).map(|i| i.to_string()).collect();
+    let mut fold_model = StrainDeconvolution::new(
+        references
+            .iter()
+            .map(|sig| subset(sig, &training))
+            .collect(),
+        ids.clone(),
+        Some(0.0),
+        Some(panel.max_iterations),
+    )
+    .map_err(|e| anyhow!(e))?;
+    fold_model.l1_penalty = panel.l1_penalty;
+    let fitted = fold_model.estimate_abundances(&subset(profile, &training));
+    let abundances: Vec<f64> = ids
+        .iter()
+        .map(|id| fitted.get(id).copied().unwrap_or(0.0))
+        .collect();
+
+    let mix = |rows: &[usize]| -> Array1<f64> {
+        rows.iter()
+            .map(|&row| {
+                references
+                    .iter()
+                    .zip(&abundances)
+                    .map(|(sig, a)| sig[row] * a)
+                    .sum::<f64>()
+            })
+            .collect()
+    };
+
+    // Abundances sum to one; rescale the mixture to the profile's magnitude using
+    // the training features only
+    let train_pred = mix(&training);
+    let train_obs = subset(profile, &training);
+    let denom = train_pred.dot(&train_pred);
+    let scale = if denom > 0.0 {
+        train_pred.dot(&train_obs) / denom
+    } else {
+        0.0
+    };
+
+    let residual = subset(profile, held_out) - mix(held_out) * scale;
+    Ok((residual.dot(&residual) / held_out.len() as f64).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::testing::{level, signature};
+    use ndarray::arr1;
+
+    #[test]
+    fn test_missing_reference_has_higher_holdout_error() {
+        // The sample is an even mix of A and B; C is unrelated
+        let a = arr1(&[1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        let b = arr1(&[0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0]);
+        let c = arr1(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0]);
+        let profile = &a * 0.5 + &b * 0.5;
+        let panel = StrainDeconvolution::new(
+            vec![a, b, c],
+            vec!["A".to_string(), "B".to_string(), "C".to_string()],
+            Some(0.0),
+            Some(2000),
+        )
+        .unwrap();
+
+        let candidates = nested_candidates(&panel, &profile);
+        assert_eq!(candidates.len(), 3);
+        assert_eq!(candidates[0].strain_ids.len(), 1);
+        assert_eq!(candidates[2].strain_ids.len(), 3);
+
+        let cv = ReferenceCrossValidation::default();
+        let scores = cv.evaluate(&panel, &profile, &candidates).unwrap();
+        let error_of = |name: &str| scores.iter().find(|s| s.name == name).unwrap().mean_error;
+        assert!(error_of("top_2") < error_of("top_1"));
+        assert!(scores.iter().all(|s| s.fold_errors.len() == 5));
+    }
+
+    #[test]
+    fn test_sample_sketch_selects_its_strains() {
+        let a = signature("A", &[], [level(21, 100, [1, 2, 3, 4])]);
+        let b = signature("B", &[], [level(21, 100, [5, 6, 7, 8])]);
+        let c = signature("C", &[], [level(21, 100, [9, 10, 11, 12])]);
+        let sample = signature("S1", &[], [level(21, 100, [1, 2, 3, 4, 5, 6, 7, 8])]);
+
+        let cv = ReferenceCrossValidation::default();
+        let scores = select_references(&sample, &[&a, &b, &c], &cv).unwrap();
+        assert_eq!(scores.len(), 3);
+        let error_of = |name: &str| scores.iter().find(|s| s.name == name).unwrap().mean_error;
+        assert!(error_of("top_2") < error_of("top_1"));
+        let top_2 = scores.iter().find(|s| s.name == "top_2").unwrap();
+        assert!(top_2.strain_ids.contains(&"A".to_string()));
+        assert!(top_2.strain_ids.contains(&"B".to_string()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reference_selection.csv");
+        write_reference_scores(&[("S1".to_string(), scores)], &path).unwrap();
+        let rows: Vec<csv::StringRecord> = csv::Reader::from_path(&path)
+            .unwrap()
+            .records()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(&rows[0][0], "S1");
+    }
+
+    #[test]
+    fn test_unknown_candidate_strain_errors() {
+        let panel =
+            StrainDeconvolution::new(vec![arr1(&[1.0, 0.0])], vec!["A".to_string()], None, None)
+                .unwrap();
+        let candidates = [CandidateReferenceSet {
+            name: "bad".to_string(),
+            strain_ids: vec!["Z".to_string()],
+        }];
+        let cv = ReferenceCrossValidation { folds: 2, seed: 1 };
+        assert!(cv
+            .evaluate(&panel, &arr1(&[1.0, 0.0]), &candidates)
+            .is_err());
+    }
+}
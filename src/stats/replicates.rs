@@ -0,0 +1,324 @@
+//! Collapsing technical replicates.
+//!
+//! Technical replicates (the same library sequenced twice, or one extract
+//! split across lanes) measure one biological sample. Testing them as
+//! independent samples understates the variance between biological samples
+//! and inflates significance, so they are merged into one column per
+//! biological sample before differential analysis. Samples are grouped by the
+//! `biological_sample` metadata column; samples without it stand alone, and a
+//! sample whose ID equals another sample's `biological_sample` joins that group.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::count_table::CountTable;
+use crate::metadata::{Metadata, SampleInfo};
+use crate::stats::uncertainty::AbundancePosterior;
+
+/// How the counts of technical replicates are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CollapseMethod {
+    /// Add the counts, as if the replicates were one deeper library
+    Sum,
+    /// Average the counts, keeping the depth of a single replicate
+    Mean,
+}
+
+/// Merge technical replicates into one column per biological sample.
+///
+/// Every replicate of a biological sample must share its covariates
+/// (currently the condition); a mismatch means the metadata is wrong and is an
+/// error. The collapsed metadata keeps the covariates of the group and the
+/// replicate number of its first member. Samples missing from `metadata` are
+/// kept as they are.
+///
+/// # Returns
+///
+/// * The collapsed table and metadata, and the members of each merged sample
+///   (biological samples with a single replicate are not listed).
+pub fn collapse_replicates(
+    table: &CountTable,
+    metadata: &Metadata,
+    method: CollapseMethod,
+) -> Result<(CountTable, Metadata, BTreeMap<String, Vec<String>>)> {
+    let names: Vec<&str> = table.sample_names().iter().map(String::as_str).collect();
+    let groups = group_replicates(&names, metadata);
+    let (collapsed_metadata, merged) = collapse_metadata(&names, &groups, metadata)?;
+
+    let mut data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+    for (biological, members) in &groups {
+        let counts = table.counts_matrix();
+        let scale = match method {
+            CollapseMethod::Sum => 1.0,
+            CollapseMethod::Mean => 1.0 / members.len() as f64,
+        };
+        let column = data.entry(biological.clone()).or_default();
+        for (r, feature) in table.feature_names().iter().enumerate() {
+            let total: f64 = members.iter().map(|&c| counts[[r, c]]).sum();
+            column.insert(feature.clone(), total * scale);
+        }
+    }
+
+    let collapsed = CountTable::build_from_data(&data)?;
+    Ok((collapsed, collapsed_metadata, merged))
+}
+
+/// Merge the abundance posteriors of technical replicates into one per
+/// biological sample, as [`collapse_replicates`] does for counts.
+///
+/// `depths` holds each sample's reads passing QC, in the order of
+/// `posteriors`. Draw `j` of a merged sample combines draw `j` of each member
+/// (cycling through members with fewer draws): `Sum` weights the members by
+/// depth, giving the abundances of the pooled library, and `Mean` weights them
+/// equally.
+///
+/// # Returns
+///
+/// * The collapsed posteriors and metadata, and the members of each merged
+///   sample (biological samples with a single replicate are not listed).
+pub fn collapse_posteriors(
+    posteriors: &[AbundancePosterior],
+    depths: &[f64],
+    metadata: &Metadata,
+    method: CollapseMethod,
+) -> Result<(
+    Vec<AbundancePosterior>,
+    Metadata,
+    BTreeMap<String, Vec<String>>,
+)> {
+    if depths.len() != posteriors.len() {
+        bail!(
+            "{} sequencing depths given for {} posteriors",
+            depths.len(),
+            posteriors.len()
+        );
+    }
+    let names: Vec<&str> = posteriors.iter().map(|p| p.sample_id.as_str()).collect();
+    let groups = group_replicates(&names, metadata);
+    let (collapsed_metadata, merged) = collapse_metadata(&names, &groups, metadata)?;
+
+    let mut collapsed = Vec::with_capacity(groups.len());
+    for (biological, members) in &groups {
+        if let [single] = members.as_slice() {
+            collapsed.push(AbundancePosterior {
+                sample_id: biological.clone(),
+                ..posteriors[*single].clone()
+            });
+            continue;
+        }
+        let weights: Vec<f64> = match method {
+            CollapseMethod::Sum => members.iter().map(|&c| depths[c].max(1.0)).collect(),
+            CollapseMethod::Mean => vec![1.0; members.len()],
+        };
+        let total_weight: f64 = weights.iter().sum();
+        let mut strain_ids: Vec<String> = members
+            .iter()
+            .flat_map(|&c| posteriors[c].strain_ids.iter().cloned())
+            .collect();
+        strain_ids.sort();
+        strain_ids.dedup();
+        let n_draws = members
+            .iter()
+            .map(|&c| posteriors[c].draws.len())
+            .max()
+            .unwrap_or(0);
+        let draws = (0..n_draws)
+            .map(|j| {
+                strain_ids
+                    .iter()
+                    .map(|strain| {
+                        members
+                            .iter()
+                            .zip(&weights)
+                            .map(|(&c, weight)| weight * posteriors[c].abundance(strain, j))
+                            .sum::<f64>()
+                            / total_weight
+                    })
+                    .collect()
+            })
+            .collect();
+        collapsed.push(AbundancePosterior {
+            sample_id: biological.clone(),
+            strain_ids,
+            draws,
+        });
+    }
+    Ok((collapsed, collapsed_metadata, merged))
+}
+
+/// Indices of `samples` grouped by biological sample, in input order
+fn group_replicates(samples: &[&str], metadata: &Metadata) -> BTreeMap<String, Vec<usize>> {
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (c, &sample) in samples.iter().enumerate() {
+        let biological = match metadata.sample_info.get(sample) {
+            Some(info) => info.biological_sample.as_deref().unwrap_or(sample),
+            None => {
+                warn!("Sample {} has no metadata and is not collapsed", sample);
+                sample
+            }
+        };
+        groups.entry(biological.to_string()).or_default().push(c);
+    }
+    groups
+}
+
+/// The metadata of the collapsed samples, and the members of each merged one
+fn collapse_metadata(
+    samples: &[&str],
+    groups: &BTreeMap<String, Vec<usize>>,
+    metadata: &Metadata,
+) -> Result<(Metadata, BTreeMap<String, Vec<String>>)> {
+    let mut collapsed_metadata = Metadata::new();
+    let mut merged = BTreeMap::new();
+    for (biological, members) in groups {
+        let member_names: Vec<String> = members.iter().map(|&c| samples[c].to_string()).collect();
+        if members.len() > 1 {
+            let names: Vec<&String> = member_names.iter().collect();
+            check_shared_covariates(biological, &names, metadata)?;
+            merged.insert(biological.clone(), member_names.clone());
+        }
+        if let Some(info) = metadata.sample_info.get(&member_names[0]) {
+            collapsed_metadata.add_sample(
+                biological.clone(),
+                SampleInfo {
+                    condition: info.condition.clone(),
+                    replicate: info.replicate,
+                    biological_sample: None,
+                },
+            );
+        }
+    }
+    Ok((collapsed_metadata, merged))
+}
+
+/// Fail unless every member of a biological sample has the same covariates
+fn check_shared_covariates(
+    biological: &str,
+    members: &[&String],
+    metadata: &Metadata,
+) -> Result<()> {
+    let conditions: BTreeMap<&str, Vec<&str>> =
+        members.iter().fold(BTreeMap::new(), |mut acc, sample| {
+            let condition = metadata
+                .sample_info
+                .get(sample.as_str())
+                .map_or("NA", |info| info.condition.as_str());
+            acc.entry(condition)
+                .or_insert_with(Vec::new)
+                .push(sample.as_str());
+            acc
+        });
+    if conditions.len() > 1 {
+        let detail: Vec<String> = conditions
+            .iter()
+            .map(|(condition, samples)| format!("{} ({})", condition, samples.join(", ")))
+            .collect();
+        bail!(
+            "Technical replicates of {} have different conditions: {}. Check the biological_sample column",
+            biological,
+            detail.join(" vs ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(condition: &str, biological_sample: Option<&str>) -> SampleInfo {
+        SampleInfo {
+            condition: condition.to_string(),
+            replicate: 1,
+            biological_sample: biological_sample.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_collapse_replicates() {
+        let data: HashMap<String, HashMap<String, f64>> = [
+            ("A_lane1", [("GCF_1", 10.0), ("GCF_2", 0.0)]),
+            ("A_lane2", [("GCF_1", 30.0), ("GCF_2", 4.0)]),
+            ("B", [("GCF_1", 7.0), ("GCF_2", 1.0)]),
+        ]
+        .into_iter()
+        .map(|(sample, counts)| {
+            let counts = counts.iter().map(|&(f, c)| (f.to_string(), c)).collect();
+            (sample.to_string(), counts)
+        })
+        .collect();
+        let table = CountTable::build_from_data(&data).unwrap();
+        let mut metadata = Metadata::new();
+        metadata.add_sample("A_lane1".to_string(), info("control", Some("A")));
+        metadata.add_sample("A_lane2".to_string(), info("control", Some("A")));
+        metadata.add_sample("B".to_string(), info("treated", None));
+
+        let (summed, collapsed_metadata, merged) =
+            collapse_replicates(&table, &metadata, CollapseMethod::Sum).unwrap();
+        assert_eq!(
+            summed.sample_names(),
+            &vec!["A".to_string(), "B".to_string()]
+        );
+        assert_eq!(
+            summed.get_sample_counts("A").unwrap().to_vec(),
+            vec![40.0, 4.0]
+        );
+        assert_eq!(collapsed_metadata.sample_info["A"].condition, "control");
+        assert_eq!(merged["A"], vec!["A_lane1", "A_lane2"]);
+        assert!(!merged.contains_key("B"));
+
+        let (averaged, _, _) =
+            collapse_replicates(&table, &metadata, CollapseMethod::Mean).unwrap();
+        assert_eq!(
+            averaged.get_sample_counts("A").unwrap().to_vec(),
+            vec![20.0, 2.0]
+        );
+        assert_eq!(
+            averaged.get_sample_counts("B").unwrap().to_vec(),
+            vec![7.0, 1.0]
+        );
+
+        // Replicates of one sample cannot differ in condition
+        metadata.add_sample("A_lane2".to_string(), info("treated", Some("A")));
+        let error = collapse_replicates(&table, &metadata, CollapseMethod::Sum).unwrap_err();
+        assert!(error.to_string().contains("different conditions"));
+    }
+
+    #[test]
+    fn test_collapse_posteriors() {
+        let posterior = |sample: &str, draws: Vec<Vec<f64>>| AbundancePosterior {
+            sample_id: sample.to_string(),
+            strain_ids: vec!["GCF_1".to_string(), "GCF_2".to_string()],
+            draws,
+        };
+        let posteriors = vec![
+            posterior("A_lane1", vec![vec![1.0, 0.0], vec![0.5, 0.5]]),
+            posterior("A_lane2", vec![vec![0.0, 1.0]]),
+            posterior("B", vec![vec![0.2, 0.8]]),
+        ];
+        let depths = [300.0, 100.0, 50.0];
+        let mut metadata = Metadata::new();
+        metadata.add_sample("A_lane1".to_string(), info("control", Some("A")));
+        metadata.add_sample("A_lane2".to_string(), info("control", Some("A")));
+        metadata.add_sample("B".to_string(), info("treated", None));
+
+        let (summed, collapsed_metadata, merged) =
+            collapse_posteriors(&posteriors, &depths, &metadata, CollapseMethod::Sum).unwrap();
+        assert_eq!(summed.len(), 2);
+        assert_eq!(summed[0].sample_id, "A");
+        // Weighted by depth, and the single-draw replicate is reused for draw 2
+        assert_eq!(summed[0].draws, vec![vec![0.75, 0.25], vec![0.375, 0.625]]);
+        assert_eq!(summed[1].sample_id, "B");
+        assert_eq!(summed[1].draws, vec![vec![0.2, 0.8]]);
+        assert_eq!(collapsed_metadata.sample_info["A"].condition, "control");
+        assert_eq!(merged["A"], vec!["A_lane1", "A_lane2"]);
+
+        let (averaged, _, _) =
+            collapse_posteriors(&posteriors, &depths, &metadata, CollapseMethod::Mean).unwrap();
+        assert_eq!(averaged[0].draws, vec![vec![0.5, 0.5], vec![0.25, 0.75]]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Within-species ANI and strain clustering.
+//!
+//! Reference strains of one species are often near-identical, and a strain
+//! call spread across twenty of them is hard to read. The pairwise ANI of the
+//! candidate strains is estimated from their finest-level sketches with the
+//! Mash formula, strains are grouped at ANI thresholds such as 99% and 99.9%,
+//! and strain abundances are summed per group. Pairwise ANI is looked up in
+//! a `SimilarityCache` first, since the same candidates recur in every sample
+//! of a species.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sketch::signature::KmerSignature;
+use crate::sketch::{Comparable, MultiResolutionSignature, SimilarityCache};
+
+/// Measure name of finest-level Mash ANI in a `SimilarityCache`
+const ANI_CACHE_MEASURE: &str = "finest_mash_ani";
+
+/// Default ANI thresholds for clustering
+pub const DEFAULT_ANI_THRESHOLDS: [f64; 2] = [0.99, 0.999];
+
+/// ANI estimated from the Jaccard index of two sketches with the Mash distance
+/// `d = -ln(2J / (1 + J)) / k`. `None` if the sketches are not comparable.
+pub fn mash_ani(a: &KmerSignature, b: &KmerSignature) -> Option<f64> {
+    a.ani(b)
+}
+
+/// Pairwise ANI among strains
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AniMatrix {
+    pub strain_ids: Vec<String>,
+    /// `values[i][j]` is the ANI of strains `i` and `j` (0 when not comparable)
+    pub values: Vec<Vec<f64>>,
+}
+
+impl AniMatrix {
+    /// ANI of every pair of `strains`, from the last (finest) level of each sketch
+    pub fn from_signatures(strains: &[&MultiResolutionSignature]) -> Self {
+        Self::from_signatures_cached(strains, &SimilarityCache::default())
+    }
+
+    /// Like `from_signatures`, reusing and adding to the pairs in `cache`
+    pub fn from_signatures_cached(
+        strains: &[&MultiResolutionSignature],
+        cache: &SimilarityCache,
+    ) -> Self {
+        let n = strains.len();
+        let content_hashes: Vec<String> = strains.iter().map(|s| s.content_hash()).collect();
+        let mut values = vec![vec![0.0; n]; n];
+        for i in 0..n {
+            values[i][i] = 1.0;
+            for j in (i + 1)..n {
+                let ani = cache.get_or_insert_with(
+                    ANI_CACHE_MEASURE,
+                    &content_hashes[i],
+                    &content_hashes[j],
+                    || match (strains[i].levels.last(), strains[j].levels.last()) {
+                        (Some(a), Some(b)) => mash_ani(a, b).unwrap_or(0.0),
+                        _ => 0.0,
+                    },
+                );
+                values[i][j] = ani;
+                values[j][i] = ani;
+            }
+        }
+        AniMatrix {
+            strain_ids: strains.iter().map(|s| s.taxon_id.clone()).collect(),
+            values,
+        }
+    }
+
+    /// Group strains whose ANI is at least `threshold`.
+    ///
+    /// Clusters are single-linkage: two strains share a cluster when a chain of
+    /// pairs above the threshold connects them. Cluster abundance is the sum of
+    /// member abundances; the representative is the most abundant member.
+    /// Clusters are ordered by abundance, highest first.
+    pub fn cluster(&self, threshold: f64, abundances: &HashMap<String, f64>) -> Vec<StrainCluster> {
+        let n = self.strain_ids.len();
+        let mut parent: Vec<usize> = (0..n).collect();
+        fn root(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        for i in 0..n {
+            for j in (i + 1)..n {
+                if self.values[i][j] >= threshold {
+                    let (ri, rj) = (root(&mut parent, i), root(&mut parent, j));
+                    parent[ri.max(rj)] = ri.min(rj);
+                }
+            }
+        }
+
+        let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for i in 0..n {
+            let r = root(&mut parent, i);
+            groups.entry(r).or_default().push(i);
+        }
+
+        let abundance_of = |i: usize| abundances.get(&self.strain_ids[i]).copied().unwrap_or(0.0);
+        let mut clusters: Vec<StrainCluster> = groups
+            .into_values()
+            .map(|members| {
+                let representative = members
+                    .iter()
+                    .copied()
+                    .max_by(|&a, &b| abundance_of(a).total_cmp(&abundance_of(b)).then(b.cmp(&a)))
+                    .unwrap_or(members[0]);
+                let min_ani = members
+                    .iter()
+                    .flat_map(|&a| members.iter().map(move |&b| (a, b)))
+                    .map(|(a, b)| self.values[a][b])
+                    .fold(1.0, f64::min);
+                StrainCluster {
+                    representative: self.strain_ids[representative].clone(),
+                    members: members
+                        .iter()
+                        .map(|&i| self.strain_ids[i].clone())
+                        .collect(),
+                    abundance: members.iter().map(|&i| abundance_of(i)).sum(),
+                    min_ani,
+                }
+            })
+            .collect();
+        clusters.sort_by(|a, b| {
+            b.abundance
+                .total_cmp(&a.abundance)
+                .then_with(|| a.representative.cmp(&b.representative))
+        });
+        clusters
+    }
+}
+
+/// A group of strains above an ANI threshold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StrainCluster {
+    /// Most abundant member
+    pub representative: String,
+    pub members: Vec<String>,
+    /// Summed abundance of the members
+    pub abundance: f64,
+    /// Lowest pairwise ANI within the cluster
+    pub min_ani: f64,
+}
+
+/// Strain clusters of one species at one ANI threshold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterLevel {
+    pub ani_threshold: f64,
+    pub clusters: Vec<StrainCluster>,
+}
+
+/// ANI matrix and clusterings of the candidate strains of a species
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpeciesStrainClusters {
+    pub species_id: String,
+    pub ani: AniMatrix,
+    pub levels: Vec<ClusterLevel>,
+}
+
+impl SpeciesStrainClusters {
+    /// Compute the ANI matrix of `strains`, through `cache`, and cluster it at
+    /// each threshold
+    pub fn new(
+        species_id: &str,
+        strains: &[&MultiResolutionSignature],
+        abundances: &HashMap<String, f64>,
+        thresholds: &[f64],
+        cache: &SimilarityCache,
+    ) -> Self {
+        let ani = AniMatrix::from_signatures_cached(strains, cache);
+        let levels = thresholds
+            .iter()
+            .map(|&ani_threshold| ClusterLevel {
+                ani_threshold,
+                clusters: ani.cluster(ani_threshold, abundances),
+            })
+            .collect();
+        SpeciesStrainClusters {
+            species_id: species_id.to_string(),
+            ani,
+            levels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix() -> AniMatrix {
+        AniMatrix {
+            strain_ids: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+            values: vec![
+                vec![1.0, 0.9995, 0.995],
+                vec![0.9995, 1.0, 0.992],
+                vec![0.995, 0.992, 1.0],
+            ],
+        }
+    }
+
+    #[test]
+    fn test_clusters_at_thresholds() {
+        let abundances: HashMap<String, f64> = [
+            ("A".to_string(), 0.2),
+            ("B".to_string(), 0.5),
+            ("C".to_string(), 0.3),
+        ]
+        .into_iter()
+        .collect();
+        let ani = matrix();
+
+        let fine = ani.cluster(0.999, &abundances);
+        assert_eq!(fine.len(), 2);
+        assert_eq!(fine[0].representative, "B");
+        assert_eq!(fine[0].members, vec!["A", "B"]);
+        assert!((fine[0].abundance - 0.7).abs() < 1e-12);
+        assert_eq!(fine[1].members, vec!["C"]);
+
+        let coarse = ani.cluster(0.99, &abundances);
+        assert_eq!(coarse.len(), 1);
+        assert!((coarse[0].abundance - 1.0).abs() < 1e-12);
+        assert_eq!(coarse[0].min_ani, 0.992);
+    }
+
+    #[test]
+    fn test_mash_ani_identical_and_disjoint() {
+        use crate::sketch::testing::level;
+        assert_eq!(
+            mash_ani(&level(21, 4, [1, 2, 3, 4]), &level(21, 4, [1, 2, 3, 4])),
+            Some(1.0)
+        );
+        assert_eq!(
+            mash_ani(&level(21, 4, [1, 2, 3, 4]), &level(21, 4, [5, 6, 7, 8])),
+            Some(0.0)
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Between-group comparison of strain abundances with measurement uncertainty.
+//!
+//! MCMC strain estimates are distributions, not exact values. Rather than
+//! testing posterior means, the comparison is repeated on abundance tables
+//! drawn from each sample's posterior and the results are pooled with Rubin's
+//! rules, so the standard error includes both between-sample variation and the
+//! uncertainty of the per-sample estimates.
+//!
+//! The draws come from results processed with `--posterior-draws` and an
+//! `mcmc` or `vi` estimator; the `differential` command falls back to the
+//! point estimates of samples without them.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use statrs::distribution::{ContinuousCDF, StudentsT};
+
+use crate::metadata::Metadata;
+use crate::stats::estimators::PosteriorDraws;
+use crate::stats::{adjust_pvalues_bh, AnalysisResults, DifferentialResult, ModelCoefficient};
+
+/// Posterior abundance tables analysed and pooled by default
+pub const DEFAULT_IMPUTATIONS: usize = 100;
+
+/// Posterior abundance draws for one sample
+#[derive(Debug, Clone)]
+pub struct AbundancePosterior {
+    pub sample_id: String,
+    /// Strain IDs, in the order used by `draws`
+    pub strain_ids: Vec<String>,
+    /// Abundance vectors sampled from the posterior
+    pub draws: Vec<Vec<f64>>,
+}
+
+impl AbundancePosterior {
+    /// Posterior draws kept in a sample's results
+    pub fn from_draws(sample_id: &str, draws: &PosteriorDraws) -> Self {
+        AbundancePosterior {
+            sample_id: sample_id.to_string(),
+            strain_ids: draws.strain_ids.clone(),
+            draws: draws.draws.clone(),
+        }
+    }
+
+    /// A point estimate treated as exact (a single draw)
+    pub fn point_estimate(sample_id: &str, abundances: &HashMap<String, f64>) -> Self {
+        let mut strain_ids: Vec<String> = abundances.keys().cloned().collect();
+        strain_ids.sort();
+        let draw = strain_ids.iter().map(|id| abundances[id]).collect();
+        AbundancePosterior {
+            sample_id: sample_id.to_string(),
+            strain_ids,
+            draws: vec![draw],
+        }
+    }
+
+    /// Abundance of `strain` in draw `index` (cycling through the draws); 0 if absent
+    pub(crate) fn abundance(&self, strain: &str, index: usize) -> f64 {
+        match self.strain_ids.iter().position(|id| id == strain) {
+            Some(i) if !self.draws.is_empty() => self.draws[index % self.draws.len()][i],
+            _ => 0.0,
+        }
+    }
+}
+
+/// Between-group comparison that pools results over posterior abundance draws
+#[derive(Debug, Clone)]
+pub struct UncertaintyComparison {
+    /// Number of posterior abundance tables to analyse and pool
+    pub imputations: usize,
+    /// Added to abundances before the log2 transform
+    pub pseudocount: f64,
+}
+
+impl Default for UncertaintyComparison {
+    fn default() -> Self {
+        UncertaintyComparison {
+            imputations: DEFAULT_IMPUTATIONS,
+            pseudocount: 1e-4,
+        }
+    }
+}
+
+impl UncertaintyComparison {
+    /// Compare log2 abundances of every strain between the `reference` and
+    /// `treatment` conditions in `metadata`.
+    ///
+    /// Each imputation takes one posterior draw per sample and computes a Welch
+    /// difference of group means. Estimates and variances are pooled with Rubin's
+    /// rules; p-values use a t distribution with Barnard-Rubin degrees of freedom
+    /// and are BH-adjusted. The fold change is treatment over reference. The
+    /// coefficients are the reference group's mean log2 abundance (intercept)
+    /// and the difference (`condition_<treatment>_vs_<reference>`).
+    pub fn compare_groups(
+        &self,
+        posteriors: &[AbundancePosterior],
+        metadata: &Metadata,
+        reference: &str,
+        treatment: &str,
+    ) -> Result<AnalysisResults> {
+        if self.imputations == 0 {
+            return Err(anyhow!("At least one imputation is required"));
+        }
+        let condition_of = |p: &AbundancePosterior| {
+            metadata
+                .sample_info
+                .get(&p.sample_id)
+                .map(|info| info.condition.as_str())
+        };
+        let group_a: Vec<&AbundancePosterior> = posteriors
+            .iter()
+            .filter(|p| condition_of(p) == Some(reference))
+            .collect();
+        let group_b: Vec<&AbundancePosterior> = posteriors
+            .iter()
+            .filter(|p| condition_of(p) == Some(treatment))
+            .collect();
+        if group_a.is_empty() || group_b.is_empty() {
+            return Err(anyhow!(
+                "Both conditions need samples: '{}' has {}, '{}' has {}",
+                reference,
+                group_a.len(),
+                treatment,
+                group_b.len()
+            ));
+        }
+
+        let mut strains: Vec<&String> = posteriors.iter().flat_map(|p| &p.strain_ids).collect();
+        strains.sort();
+        strains.dedup();
+
+        let m = self.imputations;
+        let mut results: AnalysisResults = strains
+            .into_iter()
+            .map(|strain| {
+                let mut estimates = Vec::with_capacity(m);
+                let mut variances = Vec::with_capacity(m);
+                let mut intercepts = Vec::with_capacity(m);
+                let mut intercept_variances = Vec::with_capacity(m);
+                let mut abundance_sum = 0.0;
+                for j in 0..m {
+                    let log_a: Vec<f64> = group_a
+                        .iter()
+                        .map(|p| (p.abundance(strain, j) + self.pseudocount).log2())
+                        .collect();
+                    let log_b: Vec<f64> = group_b
+                        .iter()
+                        .map(|p| (p.abundance(strain, j) + self.pseudocount).log2())
+                        .collect();
+                    abundance_sum += group_a
+                        .iter()
+                        .chain(&group_b)
+                        .map(|p| p.abundance(strain, j))
+                        .sum::<f64>();
+                    estimates.push(mean(&log_b) - mean(&log_a));
+                    intercepts.push(mean(&log_a));
+                    intercept_variances.push(sample_variance(&log_a) / log_a.len() as f64);
+                    variances.push(
+                        sample_variance(&log_a) / log_a.len() as f64
+                            + sample_variance(&log_b) / log_b.len() as f64,
+                    );
+                }
+
+                let base_mean = abundance_sum / (m * (group_a.len() + group_b.len())) as f64;
+                let complete_df = (group_a.len() + group_b.len()) as f64 - 2.0;
+                let pooled = pool_rubin(&estimates, &variances, complete_df);
+                let intercept = pool_rubin(&intercepts, &intercept_variances, complete_df);
+                DifferentialResult {
+                    feature_id: strain.clone(),
+                    base_mean,
+                    log2_fold_change: Some(pooled.estimate),
+                    std_error: pooled.std_error,
+                    statistic: pooled.statistic,
+                    p_value: pooled.p_value,
+                    p_adjusted: None,
+                    dispersion: None,
+                    coefficients: vec![
+                        ModelCoefficient {
+                            term: "(Intercept)".to_string(),
+                            estimate: intercept.estimate,
+                            std_error: intercept.std_error,
+                        },
+                        ModelCoefficient {
+                            term: format!("condition_{}_vs_{}", treatment, reference),
+                            estimate: pooled.estimate,
+                            std_error: pooled.std_error,
+                        },
+                    ],
+                    degrees_of_freedom: pooled.df,
+                }
+            })
+            .collect();
+
+        adjust_pvalues_bh(&mut results);
+        Ok(results)
+    }
+}
+
+/// Estimate pooled across imputations
+struct Pooled {
+    estimate: f64,
+    std_error: Option<f64>,
+    statistic: Option<f64>,
+    p_value: Option<f64>,
+    df: Option<f64>,
+}
+
+/// Rubin's rules: total variance is the mean within-imputation variance plus
+/// (1 + 1/m) times the between-imputation variance
+fn pool_rubin(estimates: &[f64], variances: &[f64], complete_df: f64) -> Pooled {
+    let m = estimates.len() as f64;
+    let estimate = mean(estimates);
+    let within = mean(variances);
+    let between = if estimates.len() > 1 {
+        sample_variance(estimates)
+    } else {
+        0.0
+    };
+    let total = within + (1.0 + 1.0 / m) * between;
+    if complete_df < 1.0 || !total.is_finite() || total <= 0.0 {
+        return Pooled {
+            estimate,
+            std_error: None,
+            statistic: None,
+            p_value: None,
+            df: None,
+        };
+    }
+
+    // Barnard-Rubin small-sample degrees of freedom
+    let gamma = (1.0 + 1.0 / m) * between / total;
+    let observed_df = complete_df * (complete_df + 1.0) / (complete_df + 3.0) * (1.0 - gamma);
+    let df = if between > 0.0 && m > 1.0 {
+        let rubin_df = (m - 1.0) / (gamma * gamma);
+        1.0 / (1.0 / rubin_df + 1.0 / observed_df)
+    } else {
+        complete_df
+    };
+
+    let std_error = total.sqrt();
+    let statistic = estimate / std_error;
+    let p_value = StudentsT::new(0.0, 1.0, df)
+        .ok()
+        .map(|t| (2.0 * (1.0 - t.cdf(statistic.abs()))).min(1.0));
+    Pooled {
+        estimate,
+        std_error: Some(std_error),
+        statistic: Some(statistic),
+        p_value,
+        df: Some(df),
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn sample_variance(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let mu = mean(values);
+    values.iter().map(|v| (v - mu).powi(2)).sum::<f64>() / (values.len() - 1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::SampleInfo;
+
+    fn metadata(samples: &[(&str, &str)]) -> Metadata {
+        let mut metadata = Metadata::new();
+        for (id, condition) in samples {
+            metadata.add_sample(
+                id.to_string(),
+                SampleInfo {
+                    condition: condition.to_string(),
+                    replicate: 1,
+                    biological_sample: None,
+                },
+            );
+        }
+        metadata
+    }
+
+    fn posterior(id: &str, draws: &[[f64; 2]]) -> AbundancePosterior {
+        AbundancePosterior {
+            sample_id: id.to_string(),
+            strain_ids: vec!["A".to_string(), "B".to_string()],
+            draws: draws.iter().map(|d| d.to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_posterior_uncertainty_widens_standard_error() {
+        let metadata = metadata(&[
+            ("c1", "ctrl"),
+            ("c2", "ctrl"),
+            ("t1", "case"),
+            ("t2", "case"),
+        ]);
+        let comparison = UncertaintyComparison {
+            imputations: 4,
+            pseudocount: 1e-4,
+        };
+
+        let exact = vec![
+            posterior("c1", &[[0.2, 0.8]]),
+            posterior("c2", &[[0.25, 0.75]]),
+            posterior("t1", &[[0.6, 0.4]]),
+            posterior("t2", &[[0.65, 0.35]]),
+        ];
+        let uncertain = vec![
+            posterior("c1", &[[0.1, 0.9], [0.3, 0.7]]),
+            posterior("c2", &[[0.15, 0.85], [0.35, 0.65]]),
+            posterior("t1", &[[0.4, 0.6], [0.8, 0.2]]),
+            posterior("t2", &[[0.45, 0.55], [0.85, 0.15]]),
+        ];
+
+        let exact = comparison
+            .compare_groups(&exact, &metadata, "ctrl", "case")
+            .unwrap();
+        let uncertain = comparison
+            .compare_groups(&uncertain, &metadata, "ctrl", "case")
+            .unwrap();
+
+        assert_eq!(exact[0].feature_id, "A");
+        assert!(exact[0].log2_fold_change.unwrap() > 1.0);
+        assert!(uncertain[0].std_error.unwrap() > exact[0].std_error.unwrap());
+        assert!(uncertain[0].p_value.unwrap() > exact[0].p_value.unwrap());
+        assert!(exact.iter().all(|r| r.p_adjusted.is_some()));
+        // Exact draws leave the complete-data degrees of freedom (4 samples - 2)
+        assert_eq!(exact[0].degrees_of_freedom, Some(2.0));
+        let contrast = &uncertain[0].coefficients[1];
+        assert_eq!(contrast.term, "condition_case_vs_ctrl");
+        assert_eq!(contrast.std_error, uncertain[0].std_error);
+    }
+
+    #[test]
+    fn test_missing_condition_errors() {
+        let metadata = metadata(&[("c1", "ctrl")]);
+        let posteriors = vec![posterior("c1", &[[0.5, 0.5]])];
+        assert!(UncertaintyComparison::default()
+            .compare_groups(&posteriors, &metadata, "ctrl", "case")
+            .is_err());
+    }
+}
@@ -0,0 +1,378 @@
+//! Golden-file regression tests for text outputs.
+//!
+//! A toy dataset of two samples is rendered through the text and HTML report,
+//! table and plot data writers and compared byte for byte with the files in
+//! `tests/golden/`, so any change in formatting or numbers shows up as a test
+//! failure with the first differing line.
+//!
+//! Golden files are only written when `UPDATE_GOLDEN=1` is set: after an
+//! intended change, or to record the file of a new test. Review the diff
+//! before committing. Without it a missing golden file fails the test, so a
+//! file that was never committed cannot pass unnoticed.
+//!
+//! The SVG plots are not covered, as plotters' SVG output depends on
+//! installed fonts; the numbers behind them are, in the HTML report's chart
+//! data and the forest-plot table.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::adaptive::classifier::{Classification, TaxonomicLevel};
+use crate::io::format::Artifact;
+use crate::pipeline::methods::AnalysisParameters;
+use crate::pipeline::qc::{ClassificationResults, ProcessingMetrics, QualityControlParams};
+use crate::pipeline::rna::SequencingMode;
+use crate::pipeline::warnings::{ReportWarning, WarningKind};
+use crate::sketch::signature::{ResolutionLevel, SignatureLayout};
+use crate::stats::estimators::EstimatorKind;
+
+/// Directory holding the golden files
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+}
+
+/// Compare `actual` with the golden file `name`, recording it instead when
+/// `UPDATE_GOLDEN` is set. Line endings are normalized.
+pub(crate) fn assert_golden(name: &str, actual: &str) {
+    let path = golden_dir().join(name);
+    let actual = actual.replace("\r\n", "\n");
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(golden_dir()).unwrap();
+        fs::write(&path, &actual).unwrap();
+        eprintln!("Recorded golden file {}", path.display());
+        return;
+    }
+
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| {
+            panic!(
+                "Cannot read golden file {}: {}\nRerun with UPDATE_GOLDEN=1 to record it.",
+                path.display(),
+                e
+            )
+        })
+        .replace("\r\n", "\n");
+    if expected == actual {
+        return;
+    }
+    let (line, want, got) = expected
+        .lines()
+        .map(Some)
+        .chain(std::iter::repeat(None))
+        .zip(actual.lines().map(Some).chain(std::iter::repeat(None)))
+        .enumerate()
+        .find(|(_, (want, got))| want != got)
+        .map(|(i, (want, got))| (i + 1, want, got))
+        .unwrap_or((0, None, None));
+    panic!(
+        "Output differs from golden file {} at line {}:\n  expected: {:?}\n  actual:   {:?}\n\
+         Rerun with UPDATE_GOLDEN=1 if the change is intended.",
+        path.display(),
+        line,
+        want.unwrap_or("<end of file>"),
+        got.unwrap_or("<end of file>")
+    );
+}
+
+fn strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|s| s.to_string()).collect()
+}
+
+/// Two toy samples: S1 resolved to strains of E. coli, S2 only to the genus
+/// and carrying a read-length warning
+pub(crate) fn toy_results() -> Vec<ClassificationResults> {
+    let genus = [
+        "Bacteria",
+        "Pseudomonadota",
+        "Gammaproteobacteria",
+        "Enterobacterales",
+        "Enterobacteriaceae",
+        "Escherichia",
+    ];
+    let species: Vec<&str> = genus.iter().copied().chain(["Escherichia coli"]).collect();
+
+    let s1 = ClassificationResults {
+        format_version: Artifact::Results.current(),
+        sample_id: "S1".to_string(),
+        metrics: ProcessingMetrics {
+            total_reads: 1200,
+            passed_reads: 1000,
+            total_bases: 180000,
+            passed_bases: 145000,
+            avg_read_length: 145.0,
+            processing_time_seconds: 1.5,
+            rrna_reads: 0,
+            contamination: Vec::new(),
+            control_reads_removed: 0,
+            pair_concordance: None,
+        },
+        classifications: vec![Classification {
+            taxon_id: "562".to_string(),
+            lineage: strings(&species),
+            level: TaxonomicLevel::Species,
+            confidence: 0.9234,
+            best_match: "GCF_000005845".to_string(),
+            similarity_scores: HashMap::from([
+                (ResolutionLevel::Macro, 0.95),
+                (ResolutionLevel::Meso, 0.8),
+            ]),
+            level_weights: HashMap::new(),
+        }],
+        strain_abundances: HashMap::from([
+            ("GCF_000005845".to_string(), (0.75, 0.05)),
+            ("GCF_000008865".to_string(), (0.25, 0.05)),
+        ]),
+        results_file: Some(PathBuf::from("results/S1_results.json")),
+        provenance: None,
+        warnings: Vec::new(),
+        insufficient_data: false,
+        strain_clusters: Vec::new(),
+        mag_hits: Vec::new(),
+        reference_suggestions: Vec::new(),
+        assigned_fraction: Some(0.85),
+        sequencing_mode: SequencingMode::Dna,
+        alerts: Vec::new(),
+        reconciliation: None,
+        estimator_comparison: BTreeMap::new(),
+        posterior_draws: None,
+        early_stop: None,
+        confirmations: Vec::new(),
+        parameters: Some(AnalysisParameters {
+            qc: QualityControlParams::default(),
+            layout: SignatureLayout::default(),
+            estimator: EstimatorKind::Em,
+        }),
+    };
+
+    let s2 = ClassificationResults {
+        format_version: Artifact::Results.current(),
+        sample_id: "S2".to_string(),
+        metrics: ProcessingMetrics {
+            total_reads: 500,
+            passed_reads: 400,
+            total_bases: 75000,
+            passed_bases: 56000,
+            avg_read_length: 140.0,
+            processing_time_seconds: 0.75,
+            rrna_reads: 0,
+            contamination: Vec::new(),
+            control_reads_removed: 0,
+            pair_concordance: None,
+        },
+        classifications: vec![Classification {
+            taxon_id: "561".to_string(),
+            lineage: strings(&genus),
+            level: TaxonomicLevel::Genus,
+            confidence: 0.6,
+            best_match: "GCF_000008865".to_string(),
+            similarity_scores: HashMap::from([(ResolutionLevel::Macro, 0.7)]),
+            level_weights: HashMap::new(),
+        }],
+        strain_abundances: HashMap::new(),
+        results_file: None,
+        provenance: None,
+        warnings: vec![ReportWarning {
+            kind: WarningKind::ReadLength,
+            level: Some(0),
+            detail: "k=51 is 68% of the median read length after QC (75 bp)".to_string(),
+            effect:
+                "each read yields only ~25 k-mers; sketches are sparse and sensitivity is reduced"
+                    .to_string(),
+        }],
+        insufficient_data: false,
+        strain_clusters: Vec::new(),
+        mag_hits: Vec::new(),
+        reference_suggestions: Vec::new(),
+        assigned_fraction: None,
+        sequencing_mode: SequencingMode::Dna,
+        alerts: Vec::new(),
+        reconciliation: None,
+        estimator_comparison: BTreeMap::new(),
+        posterior_draws: None,
+        early_stop: None,
+        confirmations: Vec::new(),
+        parameters: None,
+    };
+
+    vec![s1, s2]
+}
+
+/// Lineages of the toy features; GCF_000008865 is deliberately missing
+pub(crate) fn toy_lineages() -> HashMap<String, Vec<String>> {
+    HashMap::from([
+        (
+            "561".to_string(),
+            strings(&[
+                "Bacteria",
+                "Pseudomonadota",
+                "Gammaproteobacteria",
+                "Enterobacterales",
+                "Enterobacteriaceae",
+                "Escherichia",
+            ]),
+        ),
+        (
+            "GCF_000005845".to_string(),
+            strings(&[
+                "Bacteria",
+                "Pseudomonadota",
+                "Gammaproteobacteria",
+                "Enterobacterales",
+                "Enterobacteriaceae",
+                "Escherichia",
+                "Escherichia coli",
+                "",
+                "K-12 MG1655",
+            ]),
+        ),
+    ])
+}
+
+/// Read counts of the toy samples as `export-phyloseq` derives them: strain
+/// abundance x reads passing QC, or all reads to the top hit without strains
+pub(crate) fn toy_counts(
+    results: &[ClassificationResults],
+) -> HashMap<String, HashMap<String, f64>> {
+    results
+        .iter()
+        .map(|sample| {
+            let reads = sample.metrics.passed_reads as f64;
+            let counts: HashMap<String, f64> = if sample.strain_abundances.is_empty() {
+                sample
+                    .classifications
+                    .first()
+                    .map(|top| (top.taxon_id.clone(), reads))
+                    .into_iter()
+                    .collect()
+            } else {
+                sample
+                    .strain_abundances
+                    .iter()
+                    .map(|(id, (abundance, _))| (id.clone(), (abundance * reads).round()))
+                    .collect()
+            };
+            (sample.sample_id.clone(), counts)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReportConfig;
+    use crate::count_table::CountTable;
+    use crate::io::phyloseq::write_phyloseq_tables;
+    use crate::io::write_count_table;
+    use crate::metadata::Metadata;
+    use crate::pipeline::qc::{generate_report, generate_report_with};
+    use crate::stats::meta::{meta_analyze, write_forest_table, DEFAULT_MIN_STUDIES};
+    use crate::stats::{AnalysisResults, DifferentialResult};
+    use crate::visualization::plotter::Visualizer;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_text_reports_match_golden() {
+        for results in toy_results() {
+            let report = generate_report(&results).unwrap();
+            assert_golden(&format!("report_{}.txt", results.sample_id), &report);
+        }
+    }
+
+    #[test]
+    fn test_html_reports_match_golden() {
+        let dir = tempdir().unwrap();
+        let visualizer = Visualizer::new(dir.path()).unwrap();
+        for results in toy_results() {
+            let path = visualizer.generate_html_report(&results).unwrap();
+            assert_golden(
+                &format!("report_{}.html", results.sample_id),
+                &fs::read_to_string(path).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_report_sections_and_redaction() {
+        let results = &toy_results()[0];
+        let mut config = ReportConfig::default();
+        config.sections.strains = false;
+        config.sections.diversity = false;
+        config.redact = true;
+        let report = generate_report_with(results, &config).unwrap();
+        assert!(report.contains("Processing Metrics:"));
+        assert!(report.contains("Classification Results (Top Hit):"));
+        assert!(!report.contains("Strain Abundance Estimates"));
+        assert!(!report.contains("Strain Diversity:"));
+        assert!(report.contains("Results JSON: S1_results.json\n"));
+
+        let mut sparse = toy_results().remove(0);
+        sparse.insufficient_data = true;
+        let report = generate_report(&sparse).unwrap();
+        assert!(report.contains("Classification Results: Insufficient data"));
+        assert!(!report.contains("Classification Results (Top Hit):"));
+        assert!(!report.contains("Strain Abundance Estimates"));
+    }
+
+    #[test]
+    fn test_table_outputs_match_golden() {
+        let results = toy_results();
+        let table = CountTable::build_from_data(&toy_counts(&results)).unwrap();
+        let metadata = Metadata::from_groups("S1:control;S2:treated").unwrap();
+        let dir = tempdir().unwrap();
+
+        let files =
+            write_phyloseq_tables(&table, &toy_lineages(), Some(&metadata), None, dir.path())
+                .unwrap();
+        for path in [
+            &files.otu_table,
+            &files.tax_table,
+            &files.sample_data,
+            &files.import_script,
+        ] {
+            let name = path.file_name().unwrap().to_string_lossy();
+            assert_golden(&name, &fs::read_to_string(path).unwrap());
+        }
+
+        let count_table = dir.path().join("count_table.csv");
+        write_count_table(&table, None, &count_table.to_string_lossy()).unwrap();
+        assert_golden(
+            "count_table.csv",
+            &fs::read_to_string(&count_table).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_plot_data_matches_golden() {
+        let study = |name: &str, effects: &[(&str, f64, f64)]| {
+            let results: AnalysisResults = effects
+                .iter()
+                .map(
+                    |&(feature_id, log2_fold_change, std_error)| DifferentialResult {
+                        feature_id: feature_id.to_string(),
+                        base_mean: 10.0,
+                        log2_fold_change: Some(log2_fold_change),
+                        std_error: Some(std_error),
+                        statistic: None,
+                        p_value: None,
+                        p_adjusted: None,
+                        dispersion: None,
+                        coefficients: Vec::new(),
+                        degrees_of_freedom: None,
+                    },
+                )
+                .collect();
+            (name.to_string(), results)
+        };
+        let studies = vec![
+            study("s1", &[("A", 1.0, 0.5), ("B", 0.5, 0.25)]),
+            study("s2", &[("A", 2.0, 0.5), ("B", 0.75, 0.25)]),
+        ];
+        let dir = tempdir().unwrap();
+        let forest = dir.path().join("forest_plot.csv");
+        write_forest_table(&meta_analyze(&studies, DEFAULT_MIN_STUDIES), &forest).unwrap();
+        assert_golden("forest_plot.csv", &fs::read_to_string(&forest).unwrap());
+    }
+}
@@ -1,3 +1,8 @@
 pub mod parallel;
+pub mod paths;
+pub mod workspace;
+
+#[cfg(test)]
+pub(crate) mod golden;
 
 pub use parallel::parallel_process;
@@ -0,0 +1,234 @@
+//! Portable file names and paths.
+//!
+//! Sample IDs, reference accessions and bundle names all end up in file
+//! names, and what Linux accepts Windows often does not: `:` in a serotype
+//! (`O157:H7`), `?` or `*` in a user's label, device names such as `CON` or
+//! `aux.fastq`, and trailing dots or spaces, which Windows silently drops.
+//! Long sample IDs under a deep output directory also run past the 260
+//! characters Windows allows in a path unless the path is given in its
+//! extended (`\\?\`) form, and names differing only in case (`S1` and `s1`)
+//! are the same file on the default Windows and macOS file systems, so one
+//! sample's outputs would silently replace another's.
+//!
+//! Every file named after user input goes through `safe_file_name` (or
+//! `sample_file`), on every platform, so the same sample gets the same file
+//! names wherever the results are produced. Names that are already safe are
+//! kept as they are.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Longest name kept whole in a file name, leaving room for the suffixes
+/// appended to it (`_classification_debug.tsv`) within the 255 bytes most
+/// file systems allow per name
+const MAX_NAME_BYTES: usize = 200;
+
+/// Characters Windows does not allow in file names
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM0", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7",
+    "COM8", "COM9", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Paths at least this long need the extended form on Windows
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// `name` made valid as a file name on Windows, macOS and Linux: reserved
+/// and control characters and trailing dots and spaces become `_`, device
+/// names get a leading `_`, and names longer than 200 bytes are cut short
+/// with a digest of the whole name appended, so they stay distinct
+pub fn safe_file_name(name: &str) -> String {
+    let mut safe: String = name
+        .chars()
+        .map(|c| {
+            if RESERVED_CHARS.contains(&c) || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    let kept = safe.trim_end_matches(['.', ' ']).len();
+    let trailing = safe[kept..].chars().count();
+    safe.truncate(kept);
+    safe.extend(std::iter::repeat_n('_', trailing));
+    if safe.is_empty() {
+        safe.push('_');
+    }
+
+    let stem = safe.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.trim_end().eq_ignore_ascii_case(reserved))
+    {
+        safe.insert(0, '_');
+    }
+
+    if safe.len() > MAX_NAME_BYTES {
+        let digest = hex::encode(Sha256::digest(name.as_bytes()));
+        let mut cut = MAX_NAME_BYTES - 9;
+        while !safe.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        safe.truncate(cut);
+        safe.push('~');
+        safe.push_str(&digest[..8]);
+    }
+    safe
+}
+
+/// Path of the file `<sample_id><suffix>` in `dir`, with the sample ID made
+/// safe as a file name and the path in its `long_path` form
+pub fn sample_file(dir: &Path, sample_id: &str, suffix: &str) -> PathBuf {
+    long_path(&dir.join(format!("{}{}", safe_file_name(sample_id), suffix)))
+}
+
+/// `path` in a form that may exceed the 260-character limit of Windows
+/// paths: absolute with the `\\?\` prefix when it is that long on Windows,
+/// unchanged otherwise and on other platforms
+pub fn long_path(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    {
+        use std::ffi::OsString;
+
+        if path.as_os_str().len() < MAX_PATH {
+            return path.to_path_buf();
+        }
+        // Extended paths are not normalized by Windows, so `absolute` must do it
+        let Ok(absolute) = std::path::absolute(path) else {
+            return path.to_path_buf();
+        };
+        let text = absolute.as_os_str().to_string_lossy();
+        if text.starts_with(r"\\?\") {
+            return absolute;
+        }
+        let mut extended = OsString::from(r"\\?\");
+        match text.strip_prefix(r"\\") {
+            // A network share, `\\server\share\...`
+            Some(share) => {
+                extended.push("UNC\\");
+                extended.push(share);
+            }
+            None => extended.push(absolute.as_os_str()),
+        }
+        PathBuf::from(extended)
+    }
+    #[cfg(not(windows))]
+    {
+        path.to_path_buf()
+    }
+}
+
+/// Whether file names in `dir` are case-insensitive, as on the default
+/// Windows and macOS file systems, found by creating a probe file
+pub fn is_case_insensitive(dir: &Path) -> io::Result<bool> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".AHSP-case-probe-{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    let insensitive = dir
+        .join(format!(".ahsp-CASE-PROBE-{}", std::process::id()))
+        .exists();
+    fs::remove_file(&probe)?;
+    Ok(insensitive)
+}
+
+/// Groups of `names` that map to the same file name with `safe_file_name`,
+/// also when they differ only in case if `case_insensitive`, including
+/// names given more than once; in order of first appearance
+pub fn colliding_file_names<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    case_insensitive: bool,
+) -> Vec<Vec<&'a str>> {
+    let mut groups: BTreeMap<String, Vec<&'a str>> = BTreeMap::new();
+    let mut order = Vec::new();
+    for name in names {
+        let mut key = safe_file_name(name);
+        if case_insensitive {
+            key = key.to_lowercase();
+        }
+        let group = groups.entry(key.clone()).or_default();
+        if group.is_empty() {
+            order.push(key);
+        }
+        group.push(name);
+    }
+    order
+        .into_iter()
+        .filter_map(|key| groups.remove(&key).filter(|group| group.len() > 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_names_are_portable() {
+        assert_eq!(safe_file_name("S1"), "S1");
+        assert_eq!(safe_file_name("E. coli O157:H7"), "E. coli O157_H7");
+        assert_eq!(safe_file_name("run/1?*"), "run_1__");
+        assert_eq!(safe_file_name("sample. "), "sample__");
+        assert_eq!(safe_file_name("con"), "_con");
+        assert_eq!(safe_file_name("AUX.fastq"), "_AUX.fastq");
+        assert_eq!(safe_file_name("console"), "console");
+        assert_eq!(safe_file_name(""), "_");
+        assert_eq!(safe_file_name(".."), "__");
+        assert_eq!(safe_file_name("tab\there"), "tab_here");
+
+        let long = "大肠杆菌".repeat(40);
+        let safe = safe_file_name(&long);
+        assert!(safe.len() <= MAX_NAME_BYTES);
+        assert_ne!(safe, safe_file_name(&format!("{}x", long)));
+        assert_eq!(
+            sample_file(Path::new("out"), "a:b", "_results.json"),
+            Path::new("out").join("a_b_results.json")
+        );
+
+        assert_eq!(
+            colliding_file_names(["S1", "s1", "a:b", "a_b", "S2"], false),
+            vec![vec!["a:b", "a_b"]]
+        );
+        assert_eq!(
+            colliding_file_names(["S1", "s1", "a:b", "a_b", "S2", "S2"], true),
+            vec![vec!["S1", "s1"], vec!["a:b", "a_b"], vec!["S2", "S2"]]
+        );
+
+        // Every safe name can be created, whatever the platform
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["CON", "a<b>c", "trailing.", "nul.txt", "x|y", long.as_str()] {
+            let path = sample_file(dir.path(), name, "_results.json");
+            fs::write(&path, name).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), name);
+        }
+        let insensitive = is_case_insensitive(dir.path()).unwrap();
+        assert_eq!(insensitive, dir.path().join("_con_RESULTS.JSON").exists());
+        if cfg!(target_os = "linux") {
+            assert!(!insensitive);
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_long_paths_use_the_extended_form() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut deep = dir.path().to_path_buf();
+        for _ in 0..6 {
+            deep.push("a".repeat(50));
+        }
+        let extended = long_path(&deep);
+        assert!(extended.as_os_str().to_string_lossy().starts_with(r"\\?\"));
+        fs::create_dir_all(&extended).unwrap();
+        let file = sample_file(&deep, "S1", "_results.json");
+        fs::write(&file, "{}").unwrap();
+        assert!(file.exists());
+        assert_eq!(long_path(Path::new("short")), Path::new("short"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Scratch space for intermediate files.
+//!
+//! Partial downloads and other intermediates used to be written straight into
+//! the cache or output directories, where an interrupted run left truncated
+//! files behind. A `Workspace` is a private directory under a configurable
+//! location (ideally fast local scratch) that lives for one run: it is removed
+//! when the run finishes successfully and, by default, kept when the run fails
+//! so its contents can be inspected.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// Where workspaces are created and when they are kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorkspaceConfig {
+    /// Parent directory of workspaces (the system temporary directory if unset)
+    pub dir: Option<PathBuf>,
+
+    /// Keep the workspace of a failed run for debugging
+    pub keep_on_failure: bool,
+
+    /// Keep the workspace even after a successful run
+    pub keep: bool,
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> Self {
+        WorkspaceConfig {
+            dir: None,
+            keep_on_failure: true,
+            keep: false,
+        }
+    }
+}
+
+/// A run's private scratch directory
+#[derive(Debug)]
+pub struct Workspace {
+    path: PathBuf,
+    keep_on_failure: bool,
+    keep: bool,
+    finished: bool,
+}
+
+impl Workspace {
+    /// Create a fresh directory `ahsp-<label>-<pid>-<n>` under the configured location
+    pub fn create(config: &WorkspaceConfig, label: &str) -> io::Result<Self> {
+        let parent = config.dir.clone().unwrap_or_else(env::temp_dir);
+        fs::create_dir_all(&parent)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let mut attempt = 0u32;
+        let path = loop {
+            let path = parent.join(format!(
+                "ahsp-{}-{}-{}",
+                label,
+                process::id(),
+                nanos.wrapping_add(attempt)
+            ));
+            match fs::create_dir(&path) {
+                Ok(()) => break path,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        debug!("Created workspace {}", path.display());
+        Ok(Workspace {
+            path,
+            keep_on_failure: config.keep_on_failure,
+            keep: config.keep,
+            finished: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A subdirectory of the workspace, created if needed
+    pub fn subdir(&self, name: &str) -> io::Result<PathBuf> {
+        let dir = self.path.join(name);
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// Mark the run as successful and remove the workspace (unless configured to keep it)
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finished = true;
+        if self.keep {
+            warn!("Keeping workspace {}", self.path.display());
+            Ok(())
+        } else {
+            fs::remove_dir_all(&self.path)
+        }
+    }
+}
+
+impl Drop for Workspace {
+    /// A workspace dropped without `finish` belongs to a failed run
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if self.keep || (self.keep_on_failure && contains_files(&self.path)) {
+            warn!(
+                "Run did not finish; keeping workspace {} for debugging",
+                self.path.display()
+            );
+        } else if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("Cannot remove workspace {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Whether `dir` or any directory below it holds a file
+fn contains_files(dir: &Path) -> bool {
+    fs::read_dir(dir).map_or(false, |entries| {
+        entries.flatten().any(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                contains_files(&path)
+            } else {
+                true
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workspace_removed_on_success_and_kept_on_failure() {
+        let parent = tempfile::tempdir().unwrap();
+        let config = WorkspaceConfig {
+            dir: Some(parent.path().join("scratch")),
+            ..WorkspaceConfig::default()
+        };
+
+        let workspace = Workspace::create(&config, "test").unwrap();
+        let downloads = workspace.subdir("downloads").unwrap();
+        fs::write(downloads.join("partial.fna.gz"), b"partial").unwrap();
+        let path = workspace.path().to_path_buf();
+        assert!(path.starts_with(parent.path()));
+        workspace.finish().unwrap();
+        assert!(!path.exists());
+
+        // Dropped without finishing: kept because it holds a file
+        let workspace = Workspace::create(&config, "test").unwrap();
+        fs::write(workspace.subdir("downloads").unwrap().join("x"), b"x").unwrap();
+        let failed = workspace.path().to_path_buf();
+        drop(workspace);
+        assert!(failed.join("downloads/x").exists());
+
+        // Nothing worth keeping in an empty one
+        let workspace = Workspace::create(&config, "test").unwrap();
+        workspace.subdir("downloads").unwrap();
+        let empty = workspace.path().to_path_buf();
+        assert_ne!(empty, failed);
+        drop(workspace);
+        assert!(!empty.exists());
+    }
+}
@@ -8,7 +8,10 @@ use serde::Serialize;
 use thiserror::Error;
 
 use crate::adaptive::classifier::{Classification, TaxonomicLevel};
-use crate::pipeline::processor::ClassificationResults;
+use crate::io::escape::{html_escape, script_string};
+use crate::pipeline::locale::{ReportLocale, ReportText};
+use crate::pipeline::qc::ClassificationResults;
+use crate::utils::paths::sample_file;
 
 #[derive(Error, Debug)]
 pub enum VisualizationError {
@@ -39,11 +42,11 @@ pub enum VisualizationType {
 
 /// Template for HTML reports
 const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
-<html lang="en">
+<html lang="{{lang}}">
 <head>
     <meta charset="UTF-8">
     <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>AHSP Report: {{sample_id}}</title>
+    <title>{{t_report_title}}: {{sample_id}}</title>
     <script src="https://cdn.jsdelivr.net/npm/chart.js@3.7.1/dist/chart.min.js"></script>
     <script src="https://cdn.jsdelivr.net/npm/d3@7"></script>
     <style>
@@ -98,39 +101,39 @@ const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
     </style>
 </head>
 <body>
-    <h1>AHSP Analysis Report: {{sample_id}}</h1>
+    <h1>{{t_report_title}}: {{sample_id}}</h1>
     
     <div class="metrics">
-        <h2>Processing Metrics</h2>
-        <p><strong>Total reads:</strong> {{total_reads}}</p>
-        <p><strong>Passed QC:</strong> {{passed_reads}} ({{qc_percent}}%)</p>
-        <p><strong>Average read length:</strong> {{avg_read_length}} bp</p>
-        <p><strong>Processing time:</strong> {{processing_time}} seconds</p>
+        <h2>{{t_processing_metrics}}</h2>
+        <p><strong>{{t_total_reads}}:</strong> {{total_reads}}</p>
+        <p><strong>{{t_passed_qc}}:</strong> {{passed_reads}} ({{qc_percent}})</p>
+        <p><strong>{{t_average_read_length}}:</strong> {{avg_read_length}} bp</p>
+        <p><strong>{{t_processing_time}}:</strong> {{processing_time}} {{t_seconds}}</p>
     </div>
     
     <div class="flex-container">
         <div class="flex-item">
-            <h2>Taxonomic Classification</h2>
+            <h2>{{t_taxonomic_classification}}</h2>
             <div class="chart-container">
                 <canvas id="taxonomyChart"></canvas>
             </div>
         </div>
         
         <div class="flex-item">
-            <h2>Strain Abundances</h2>
+            <h2>{{t_strain_abundances}}</h2>
             <div class="chart-container">
                 <canvas id="strainChart"></canvas>
             </div>
         </div>
     </div>
     
-    <h2>Classification Details</h2>
+    <h2>{{t_classification_details}}</h2>
     <table>
         <tr>
-            <th>Rank</th>
-            <th>Taxon</th>
-            <th>Level</th>
-            <th>Confidence</th>
+            <th>{{t_rank}}</th>
+            <th>{{t_taxon}}</th>
+            <th>{{t_level}}</th>
+            <th>{{t_confidence}}</th>
         </tr>
         {{#classifications}}
         <tr>
@@ -142,18 +145,18 @@ const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
         {{/classifications}}
     </table>
     
-    <h2>Strain Details</h2>
+    <h2>{{t_strain_details}}</h2>
     <table>
         <tr>
-            <th>Strain</th>
-            <th>Abundance</th>
-            <th>Confidence Interval</th>
+            <th>{{t_strain}}</th>
+            <th>{{t_abundance}}</th>
+            <th>{{t_confidence_interval}}</th>
         </tr>
         {{#strains}}
         <tr>
             <td>{{id}}</td>
-            <td>{{abundance}}%</td>
-            <td>±{{confidence}}%</td>
+            <td>{{abundance}}</td>
+            <td>±{{confidence}}</td>
         </tr>
         {{/strains}}
     </table>
@@ -178,7 +181,7 @@ const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
                     },
                     title: {
                         display: true,
-                        text: 'Taxonomic Classification'
+                        text: {{script_taxonomic_classification}}
                     }
                 }
             }
@@ -191,7 +194,7 @@ const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
             data: {
                 labels: [{{strain_labels}}],
                 datasets: [{
-                    label: 'Abundance (%)',
+                    label: {{script_abundance_percent}},
                     data: [{{strain_data}}],
                     backgroundColor: [{{strain_colors}}],
                     borderColor: [{{strain_border_colors}}],
@@ -207,7 +210,7 @@ const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
                     },
                     title: {
                         display: true,
-                        text: 'Strain Abundances'
+                        text: {{script_strain_abundances}}
                     }
                 },
                 scales: {
@@ -215,7 +218,7 @@ const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
                         beginAtZero: true,
                         title: {
                             display: true,
-                            text: 'Abundance (%)'
+                            text: {{script_abundance_percent}}
                         }
                     }
                 }
@@ -229,6 +232,8 @@ const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
 pub struct Visualizer {
     /// Output directory for visualizations
     output_dir: PathBuf,
+    /// Language and number formatting of the HTML report
+    locale: ReportLocale,
 }
 
 impl Visualizer {
@@ -243,9 +248,16 @@ impl Visualizer {
 
         Ok(Visualizer {
             output_dir: output_path,
+            locale: ReportLocale::default(),
         })
     }
 
+    /// Use `locale` for the headers and numbers of HTML reports
+    pub fn with_locale(mut self, locale: ReportLocale) -> Self {
+        self.locale = locale;
+        self
+    }
+
     /// Generate a visualization from classification results
     pub fn generate_visualization(
         &self,
@@ -267,19 +279,12 @@ impl Visualizer {
         &self,
         results: &ClassificationResults,
     ) -> Result<PathBuf, VisualizationError> {
-        let output_file = self
-            .output_dir
-            .join(format!("{}_report.html", results.sample_id));
+        let output_file = sample_file(&self.output_dir, &results.sample_id, "_report.html");
 
         // Prepare template data
         let template_data = self.prepare_template_data(results)?;
 
-        // Simple template replacement (in a real implementation, use a templating library)
-        let mut html = HTML_TEMPLATE.to_string();
-
-        for (key, value) in template_data {
-            html = html.replace(&format!("{{{{{}}}}}", key), &value);
-        }
+        let html = render_template(HTML_TEMPLATE, &template_data)?;
 
         // Write HTML to file
         let mut file = File::create(&output_file)?;
@@ -294,35 +299,77 @@ impl Visualizer {
         results: &ClassificationResults,
     ) -> Result<HashMap<String, String>, VisualizationError> {
         let mut data = HashMap::new();
+        let locale = &self.locale;
+
+        // Headers in the report language
+        data.insert("lang".to_string(), locale.language.code().to_string());
+        for (key, text) in [
+            ("t_report_title", ReportText::ReportTitle),
+            ("t_processing_metrics", ReportText::ProcessingMetrics),
+            ("t_total_reads", ReportText::TotalReads),
+            ("t_passed_qc", ReportText::PassedQc),
+            ("t_average_read_length", ReportText::AverageReadLength),
+            ("t_processing_time", ReportText::ProcessingTime),
+            ("t_seconds", ReportText::Seconds),
+            (
+                "t_taxonomic_classification",
+                ReportText::TaxonomicClassification,
+            ),
+            ("t_strain_abundances", ReportText::StrainAbundances),
+            (
+                "t_classification_details",
+                ReportText::ClassificationDetails,
+            ),
+            ("t_strain_details", ReportText::StrainDetails),
+            ("t_rank", ReportText::Rank),
+            ("t_taxon", ReportText::Taxon),
+            ("t_level", ReportText::Level),
+            ("t_confidence", ReportText::Confidence),
+            ("t_strain", ReportText::Strain),
+            ("t_abundance", ReportText::Abundance),
+            ("t_confidence_interval", ReportText::ConfidenceInterval),
+        ] {
+            data.insert(key.to_string(), html_escape(locale.text(text)));
+        }
+        // The same headers as chart titles
+        data.insert(
+            "script_taxonomic_classification".to_string(),
+            script_string(locale.text(ReportText::TaxonomicClassification)),
+        );
+        data.insert(
+            "script_strain_abundances".to_string(),
+            script_string(locale.text(ReportText::StrainAbundances)),
+        );
+        data.insert(
+            "script_abundance_percent".to_string(),
+            script_string(&format!("{} (%)", locale.text(ReportText::Abundance))),
+        );
 
         // Basic information
-        data.insert("sample_id".to_string(), results.sample_id.clone());
+        data.insert("sample_id".to_string(), html_escape(&results.sample_id));
         data.insert(
             "total_reads".to_string(),
-            results.metrics.total_reads.to_string(),
+            locale.integer(results.metrics.total_reads as u64),
         );
         data.insert(
             "passed_reads".to_string(),
-            results.metrics.passed_reads.to_string(),
+            locale.integer(results.metrics.passed_reads as u64),
         );
 
-        let qc_percent = if results.metrics.total_reads > 0 {
-            format!(
-                "{:.1}",
-                100.0 * results.metrics.passed_reads as f64 / results.metrics.total_reads as f64
-            )
+        let qc_fraction = if results.metrics.total_reads > 0 {
+            results.metrics.passed_reads as f64 / results.metrics.total_reads as f64
         } else {
-            "0.0".to_string()
+            0.0
         };
-        data.insert("qc_percent".to_string(), qc_percent);
+        data.insert("qc_percent".to_string(), locale.percent(qc_fraction, 1));
 
         data.insert(
             "avg_read_length".to_string(),
-            format!("{:.1}", results.metrics.avg_read_length),
+            locale.decimal(results.metrics.avg_read_length, 1),
         );
         data.insert(
             "processing_time".to_string(),
-            format!("{:.2}", results.metrics.processing_time_seconds),
+            locale.decimal(results.metrics.processing_time_seconds, 2),
         );
 
         // Taxonomic classification data
@@ -350,7 +397,7 @@ impl Visualizer {
                     taxonomy_colors.push_str(", ");
                 }
 
-                taxonomy_labels.push_str(&format!("'{}'", taxon));
+                taxonomy_labels.push_str(&script_string(taxon));
                 taxonomy_data.push_str(&format!("{}", 100.0 / taxa.len() as f64)); // Simplified
 
                 // Generate a color based on index
@@ -369,9 +416,9 @@ impl Visualizer {
         let mut strain_colors = String::new();
         let mut strain_border_colors = String::new();
 
-        // Get top strains sorted by abundance
+        // Get top strains sorted by abundance, then ID, so reruns draw the same chart
         let mut strains: Vec<_> = results.strain_abundances.iter().collect();
-        strains.sort_by(|a, b| b.1 .0.partial_cmp(&a.1 .0).unwrap());
+        strains.sort_by(|a, b| b.1 .0.total_cmp(&a.1 .0).then_with(|| a.0.cmp(b.0)));
 
         for (i, (strain_id, (abundance, _))) in strains.iter().enumerate() {
             if i > 0 {
@@ -381,7 +428,7 @@ impl Visualizer {
                 strain_border_colors.push_str(", ");
             }
 
-            strain_labels.push_str(&format!("'{}'", strain_id));
+            strain_labels.push_str(&script_string(strain_id));
             strain_data.push_str(&format!("{:.2}", abundance * 100.0));
 
             // Generate a color based on index
@@ -399,23 +446,23 @@ impl Visualizer {
         let mut classifications = String::new();
         for (i, classification) in results.classifications.iter().enumerate() {
             classifications.push_str(&format!(
-                "<tr><td>{}</td><td>{}</td><td>{:?}</td><td>{:.2}</td></tr>\n",
+                "<tr><td>{}</td><td>{}</td><td>{:?}</td><td>{}</td></tr>\n",
                 i + 1,
-                classification.taxon_id,
+                html_escape(&classification.taxon_id),
                 classification.level,
-                classification.confidence
+                locale.decimal(classification.confidence, 2)
             ));
         }
         data.insert("classifications".to_string(), classifications);
 
         // Strain details
         let mut strain_rows = String::new();
-        for (strain_id, (abundance, confidence)) in &results.strain_abundances {
+        for (strain_id, (abundance, confidence)) in &strains {
             strain_rows.push_str(&format!(
-                "<tr><td>{}</td><td>{:.2}</td><td>±{:.2}</td></tr>\n",
-                strain_id,
-                abundance * 100.0,
-                confidence * 100.0
+                "<tr><td>{}</td><td>{}</td><td>±{}</td></tr>\n",
+                html_escape(strain_id),
+                locale.percent(*abundance, 2),
+                locale.percent(*confidence, 2)
             ));
         }
         data.insert("strains".to_string(), strain_rows);
@@ -428,9 +475,11 @@ impl Visualizer {
         &self,
         results: &ClassificationResults,
     ) -> Result<PathBuf, VisualizationError> {
-        let output_file = self
-            .output_dir
-            .join(format!("{}_taxonomy_sunburst.svg", results.sample_id));
+        let output_file = sample_file(
+            &self.output_dir,
+            &results.sample_id,
+            "_taxonomy_sunburst.svg",
+        );
 
         // Get dimensions
         let width = 800;
@@ -519,11 +568,7 @@ impl Visualizer {
                         TextAlignment::left()
                     });
 
-                let shortened_taxon = if taxon.len() > 15 {
-                    format!("{}...", &taxon[0..12])
-                } else {
-                    taxon.clone()
-                };
+                let shortened_taxon = shorten_label(taxon, 15);
 
                 root.draw_text(&shortened_taxon, &style)?;
             }
@@ -539,9 +584,7 @@ impl Visualizer {
         &self,
         results: &ClassificationResults,
     ) -> Result<PathBuf, VisualizationError> {
-        let output_file = self
-            .output_dir
-            .join(format!("{}_strain_chart.svg", results.sample_id));
+        let output_file = sample_file(&self.output_dir, &results.sample_id, "_strain_chart.svg");
 
         // Get dimensions
         let width = 800;
@@ -551,9 +594,9 @@ impl Visualizer {
         let root = SVGBackend::new(&output_file, (width, height)).into_drawing_area();
         root.fill(&WHITE)?;
 
-        // Get top strains sorted by abundance
+        // Get top strains sorted by abundance, then ID, so reruns draw the same chart
         let mut strains: Vec<_> = results.strain_abundances.iter().collect();
-        strains.sort_by(|a, b| b.1 .0.partial_cmp(&a.1 .0).unwrap());
+        strains.sort_by(|a, b| b.1 .0.total_cmp(&a.1 .0).then_with(|| a.0.cmp(b.0)));
 
         // Limit to top 10 strains for clarity
         let top_strains: Vec<_> = strains.into_iter().take(10).collect();
@@ -579,13 +622,7 @@ impl Visualizer {
             // Create strain labels and abundances
             let labels: Vec<String> = top_strains
                 .iter()
-                .map(|(id, _)| {
-                    if id.len() > 15 {
-                        format!("{}...", &id[0..12])
-                    } else {
-                        id.clone()
-                    }
-                })
+                .map(|(id, _)| shorten_label(id, 15))
                 .collect();
 
             let values: Vec<f64> = top_strains
@@ -661,9 +698,11 @@ impl Visualizer {
         &self,
         results: &ClassificationResults,
     ) -> Result<PathBuf, VisualizationError> {
-        let output_file = self
-            .output_dir
-            .join(format!("{}_confidence_heatmap.svg", results.sample_id));
+        let output_file = sample_file(
+            &self.output_dir,
+            &results.sample_id,
+            "_confidence_heatmap.svg",
+        );
 
         // Get dimensions
         let width = 800;
@@ -696,14 +735,7 @@ impl Visualizer {
         // Add strain confidence if available
         for (strain_id, (_, confidence)) in results.strain_abundances.iter().take(5) {
             confidence_data.push((
-                format!(
-                    "Strain: {}",
-                    if strain_id.len() > 12 {
-                        format!("{}...", &strain_id[0..9])
-                    } else {
-                        strain_id.clone()
-                    }
-                ),
+                format!("Strain: {}", shorten_label(strain_id, 12)),
                 *confidence,
             ));
         }
@@ -841,8 +873,57 @@ impl Visualizer {
     }
 }
 
+/// Fill the `{{key}}` placeholders of `template` from `data` in one pass,
+/// so values are never scanned for placeholders themselves. A section
+/// `{{#key}}...{{/key}}` is replaced as a whole by the value of `key`, its
+/// rows rendered beforehand. Values must already be escaped for where they go.
+fn render_template(
+    template: &str,
+    data: &HashMap<String, String>,
+) -> Result<String, VisualizationError> {
+    let value = |key: &str| {
+        data.get(key).ok_or_else(|| {
+            VisualizationError::TemplateError(format!("no value for {{{{{}}}}}", key))
+        })
+    };
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| VisualizationError::TemplateError("unclosed placeholder".to_string()))?;
+        let key = &after[..end];
+        rest = &after[end + 2..];
+        match key.strip_prefix('#') {
+            Some(section) => {
+                let close = format!("{{{{/{}}}}}", section);
+                let close_at = rest.find(&close).ok_or_else(|| {
+                    VisualizationError::TemplateError(format!("unclosed section {}", section))
+                })?;
+                rendered.push_str(value(section)?);
+                rest = &rest[close_at + close.len()..];
+            }
+            None => rendered.push_str(value(key)?),
+        }
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// `label` cut to at most `max_chars` characters, ending in `...` when cut
+fn shorten_label(label: &str, max_chars: usize) -> String {
+    if label.chars().count() > max_chars {
+        let kept: String = label.chars().take(max_chars.saturating_sub(3)).collect();
+        format!("{}...", kept)
+    } else {
+        label.to_string()
+    }
+}
+
 /// Add visualization capability to FASTQ processor
-impl crate::pipeline::processor::FastqProcessor {
+impl crate::pipeline::FastqProcessor {
     /// Generate visualizations for a processed sample
     pub fn generate_visualizations(
         &self,
@@ -883,3 +964,27 @@ impl crate::pipeline::processor::FastqProcessor {
         Ok(output_files)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template_fills_each_placeholder_once() {
+        let data = HashMap::from([
+            ("sample_id".to_string(), html_escape("{{rows}} <b>S1</b>")),
+            ("rows".to_string(), "<tr></tr>".to_string()),
+        ]);
+        let rendered = render_template(
+            "<h1>{{sample_id}}</h1>{{#rows}}<td>{{id}}</td>{{/rows}}",
+            &data,
+        )
+        .unwrap();
+        assert_eq!(rendered, "<h1>{{rows}} &lt;b&gt;S1&lt;/b&gt;</h1><tr></tr>");
+        assert!(render_template("{{missing}}", &data).is_err());
+        assert!(render_template("{{#rows}}", &data).is_err());
+
+        assert_eq!(shorten_label("Ünïcödé 大肠杆菌 🦠", 8), "Ünïcö...");
+        assert_eq!(shorten_label("E. coli", 8), "E. coli");
+    }
+}